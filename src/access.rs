@@ -10,33 +10,45 @@
 // limitations under the License.
 
 use crate::catalog;
-use crate::datums::Datums;
+use crate::datums::{Datums, TypedColumn, Value};
 use crate::executor::DestReceiver;
 use crate::parser::sem;
+use crate::protocol::{Format, Message};
+use crate::utils::adt::binary_send;
+use crate::utils::encoding;
 use crate::utils::fmgr::FmgrInfo;
 use crate::utils::{SessionState, WorkerState};
-use crate::{protocol, Oid, SockWriter};
+use crate::{kbanyhow, kbensure, protocol, Oid, SockWriter};
 use std::debug_assert;
 use std::rc::Rc;
 
+pub mod autovacuum;
 pub mod ckpt;
 pub mod clog;
 pub mod cs;
 pub mod csmvcc;
 pub mod fd;
 pub mod lmgr;
+pub mod prewarm;
 pub mod redo;
 pub mod rel;
 mod slru;
 pub mod sv;
 pub mod wal;
+pub mod walarchive;
 pub mod xact;
 
 pub struct DestRemote<'a, 'b> {
     stream: &'a mut SockWriter<'b>,
     typout: Vec<FmgrInfo>,
+    typids: Vec<Oid>,
     outstr: Vec<Rc<Datums>>,
     pub processed: u64,
+    // The result format codes of Bind, empty means all columns are in text.
+    formats: Vec<Format>,
+    // Some means the DataRows are kept for the portal instead of being sent, the
+    // RowDescription is not sent either since it is the job of Describe.
+    pub portal_rows: Option<Vec<Vec<u8>>>,
 }
 
 impl<'a, 'b> DestRemote<'a, 'b> {
@@ -44,10 +56,48 @@ impl<'a, 'b> DestRemote<'a, 'b> {
         DestRemote {
             stream,
             typout: Vec::new(),
+            typids: Vec::new(),
             processed: 0,
             outstr: Vec::new(),
+            formats: Vec::new(),
+            portal_rows: None,
         }
     }
+
+    pub fn new_portal(stream: &'a mut SockWriter<'b>, formats: Vec<Format>) -> DestRemote<'a, 'b> {
+        let mut dest = DestRemote::new(stream);
+        dest.formats = formats;
+        dest.portal_rows = Some(Vec::new());
+        return dest;
+    }
+}
+
+// SendRowDescriptionMessage, formats are the result format codes of Bind.
+pub fn row_description<'a>(
+    tlist: &'a [sem::TargetEntry],
+    formats: &[Format],
+    sess: &SessionState,
+) -> anyhow::Result<Vec<protocol::FieldDesc<'a>>> {
+    kbensure!(
+        formats.len() <= 1 || formats.len() == tlist.len(),
+        ERRCODE_PROTOCOL_VIOLATION,
+        "bind message has {} result formats but query has {} columns",
+        formats.len(),
+        tlist.len()
+    );
+    let mut fields = Vec::with_capacity(tlist.len());
+    for (idx, target) in tlist.iter().enumerate() {
+        let typoid = target.expr.val_type();
+        let (_, typlen) = catalog::get_type_output_info(sess, typoid)?;
+        let fieldname = match &target.resname {
+            None => "", // TupleDescInitEntry() set name to empty if target.resname is None.
+            Some(v) => v,
+        };
+        let mut field = protocol::FieldDesc::new(fieldname, typoid, -1, typlen);
+        field.set_format(Format::of_column(formats, idx));
+        fields.push(field);
+    }
+    return Ok(fields);
 }
 
 impl DestReceiver for DestRemote<'_, '_> {
@@ -58,23 +108,24 @@ impl DestReceiver for DestRemote<'_, '_> {
     ) -> anyhow::Result<()> {
         self.outstr.resize_with(tlist.len(), Default::default);
         self.typout.clear();
-        let mut fields = Vec::new();
+        self.typids.clear();
+        let fields = row_description(tlist, &self.formats, sess)?;
         for target in tlist {
             let typoid = target.expr.val_type();
-            let (typoutproc, typlen) = catalog::get_type_output_info(sess, typoid)?;
+            let (typoutproc, _) = catalog::get_type_output_info(sess, typoid)?;
             self.typout
                 .push(FmgrInfo::new(typoutproc, sess.fmgr_builtins)?);
-            let fieldname = match &target.resname {
-                None => "", // TupleDescInitEntry() set name to empty if target.resname is None.
-                Some(v) => v,
-            };
-            fields.push(protocol::FieldDesc::new(fieldname, typoid, -1, typlen));
+            self.typids.push(typoid);
         }
-        protocol::write_message(
+        if self.portal_rows.is_some() {
+            return Ok(());
+        }
+        protocol::write_message_to(
             self.stream,
             &protocol::RowDescription {
                 fields: fields.as_slice(),
             },
+            sess.gucstate.client_encoding,
         );
         Ok(())
     }
@@ -98,19 +149,53 @@ impl DestReceiver for DestRemote<'_, '_> {
             )?;
         }
 
-        let mut ostr = Vec::with_capacity(tuples.len());
+        let mut typed = Vec::with_capacity(tuples.len());
+        for (idx, col) in tuples.iter().enumerate() {
+            if Format::of_column(&self.formats, idx) == Format::Text {
+                typed.push(None);
+                continue;
+            }
+            let typid = self.typids[idx];
+            typed.push(Some(TypedColumn::new(typid, col).ok_or_else(|| {
+                kbanyhow!(
+                    ERRCODE_FEATURE_NOT_SUPPORTED,
+                    "no binary output function available for type {}",
+                    typid
+                )
+            })?));
+        }
+
+        let enc = worker.gucstate.client_encoding;
+        let mut encstr = Vec::with_capacity(tuples.len());
         for idx in 0..rownum {
-            ostr.clear();
-            for col in &self.outstr {
-                let colstr = col.try_get_varchar_at(idx as isize).map(|v| v.as_bytes());
-                ostr.push(colstr);
+            encstr.clear();
+            for (col, typed) in self.outstr.iter().zip(&typed) {
+                let colstr = match typed {
+                    None => match col.try_get_varchar_at(idx as isize) {
+                        None => None,
+                        Some(v) => Some(encoding::to_client(enc, v)?),
+                    },
+                    Some(typed) => match typed.get(idx as isize) {
+                        None => None,
+                        // varcharsend, the text is sent in the client encoding too.
+                        Some(Value::Varchar(v)) => Some(encoding::to_client(enc, v)?),
+                        Some(v) => {
+                            let mut buf = Vec::new();
+                            binary_send(v, &mut buf);
+                            Some(buf.into())
+                        }
+                    },
+                };
+                encstr.push(colstr);
+            }
+            let ostr: Vec<_> = encstr.iter().map(|v| v.as_deref()).collect();
+            let msg = protocol::DataRow {
+                data: ostr.as_slice(),
+            };
+            match self.portal_rows {
+                None => protocol::write_message(self.stream, &msg),
+                Some(ref mut rows) => rows.push(msg.serialize()),
             }
-            protocol::write_message(
-                self.stream,
-                &protocol::DataRow {
-                    data: ostr.as_slice(),
-                },
-            );
         }
         return Ok(());
     }
@@ -0,0 +1,292 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::access::sv::TableId;
+use crate::access::xact;
+use crate::guc::{self, GucState};
+use crate::utils::{maintenance_work_mem, Xid};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering::Relaxed};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub struct TabStat {
+    pub live_rows: u64,
+    pub dead_rows: u64,
+}
+
+// The row estimates of tables, just like the n_live_tup/n_dead_tup in PgStat_StatTabEntry.
+// The writers report the rows inserted and deleted, and autovacuum reports the rows
+// reclaimed.
+pub struct TabStats {
+    tabs: Mutex<HashMap<TableId, TabStat>>,
+}
+
+impl TabStats {
+    pub fn new() -> TabStats {
+        TabStats {
+            tabs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn count_insert(&self, table: TableId, rows: u64) {
+        let mut tabs = self.tabs.lock().unwrap();
+        tabs.entry(table).or_default().live_rows += rows;
+    }
+
+    pub fn count_delete(&self, table: TableId, rows: u64) {
+        let mut tabs = self.tabs.lock().unwrap();
+        let stat = tabs.entry(table).or_default();
+        stat.live_rows = stat.live_rows.saturating_sub(rows);
+        stat.dead_rows += rows;
+    }
+
+    // The rows inserted by the aborted transactions, they are dead without ever being live.
+    pub fn count_dead(&self, table: TableId, rows: u64) {
+        let mut tabs = self.tabs.lock().unwrap();
+        tabs.entry(table).or_default().dead_rows += rows;
+    }
+
+    pub fn count_reclaim(&self, table: TableId, rows: u64) {
+        let mut tabs = self.tabs.lock().unwrap();
+        if let Some(stat) = tabs.get_mut(&table) {
+            stat.dead_rows = stat.dead_rows.saturating_sub(rows);
+        }
+    }
+
+    // pgstat_drop_relation, the table has been dropped.
+    pub fn remove(&self, table: TableId) {
+        self.tabs.lock().unwrap().remove(&table);
+    }
+
+    pub fn get(&self, table: TableId) -> Option<TabStat> {
+        self.tabs.lock().unwrap().get(&table).copied()
+    }
+
+    fn all(&self) -> Vec<(TableId, TabStat)> {
+        let tabs = self.tabs.lock().unwrap();
+        tabs.iter().map(|(&k, &v)| (k, v)).collect()
+    }
+}
+
+// The rows inserted by the current transaction, they are reported at the end of the
+// transaction just like the pending PgStat_TableStatus.
+pub struct SessionStateExt {
+    stats: &'static TabStats,
+    inserts: Vec<(TableId, u64)>,
+}
+
+impl SessionStateExt {
+    pub fn new(stats: &'static TabStats) -> Self {
+        Self {
+            stats,
+            inserts: Vec::new(),
+        }
+    }
+
+    // pgstat_count_heap_insert
+    pub fn count_insert(&mut self, table: TableId, rows: u64) {
+        self.inserts.push((table, rows));
+    }
+
+    // The rows reclaimed by VACUUM FULL, reported at once since it never runs in a transaction
+    // block.
+    pub fn count_reclaim(&self, table: TableId, rows: u64) {
+        self.stats.count_reclaim(table, rows);
+    }
+
+    // AtEOXact_PgStat, the rows inserted by the committed transaction are live.
+    pub fn at_commit(&mut self) {
+        for (table, rows) in self.inserts.drain(..) {
+            self.stats.count_insert(table, rows);
+        }
+    }
+
+    pub fn at_abort(&mut self) {
+        self.at_subabort(0);
+    }
+
+    // The number of the pending inserts, taken at the savepoint.
+    pub fn pending_mark(&self) -> usize {
+        self.inserts.len()
+    }
+
+    // AtEOSubXact_PgStat, the rows inserted after the savepoint are dead.
+    pub fn at_subabort(&mut self, mark: usize) {
+        for (table, rows) in self.inserts.drain(mark..) {
+            self.stats.count_dead(table, rows);
+        }
+    }
+}
+
+// The freeze/compaction routine of vacuum. Only the rows deleted by the transactions
+// before horizon are invisible to all snapshots and can be reclaimed. The memory used should
+// not exceed work_mem, which is maintenance_work_mem instead of the work_mem of queries.
+// Returns the number of rows reclaimed.
+pub trait Reclaimer: Send + Sync {
+    fn reclaim(&self, table: TableId, horizon: Xid, work_mem: usize) -> anyhow::Result<u64>;
+}
+
+pub struct AutoVacOpts {
+    pub naptime: Duration,
+    pub vacuum_threshold: u64,
+    pub vacuum_scale_factor: f64,
+    // unit: bytes.
+    pub work_mem: usize,
+}
+
+impl AutoVacOpts {
+    pub fn new(gucstate: &GucState) -> AutoVacOpts {
+        let naptime = guc::get_int(gucstate, guc::AutovacuumNaptime).max(1) as u64;
+        let threshold = guc::get_int(gucstate, guc::AutovacuumVacuumThreshold).max(0) as u64;
+        AutoVacOpts {
+            naptime: Duration::from_secs(naptime),
+            vacuum_threshold: threshold,
+            vacuum_scale_factor: guc::get_real(gucstate, guc::AutovacuumVacuumScaleFactor),
+            work_mem: maintenance_work_mem(gucstate),
+        }
+    }
+
+    // relation_needs_vacanalyze
+    pub fn needs_vacuum(&self, stat: &TabStat) -> bool {
+        let vacthresh =
+            self.vacuum_threshold as f64 + self.vacuum_scale_factor * stat.live_rows as f64;
+        stat.dead_rows as f64 > vacthresh
+    }
+}
+
+// do_autovacuum, returns the number of tables vacuumed. The failure of one table does not
+// stop the others.
+pub fn do_autovacuum(
+    stats: &TabStats,
+    reclaimer: &dyn Reclaimer,
+    horizon: Xid,
+    opts: &AutoVacOpts,
+) -> usize {
+    let mut vacuumed = 0;
+    for (table, stat) in stats.all() {
+        if !opts.needs_vacuum(&stat) {
+            continue;
+        }
+        log::info!(
+            "automatic vacuum of table. table={:?} stat={:?} horizon={}",
+            table,
+            stat,
+            horizon
+        );
+        match reclaimer.reclaim(table, horizon, opts.work_mem) {
+            Ok(rows) => {
+                stats.count_reclaim(table, rows);
+                vacuumed += 1;
+            }
+            Err(e) => log::warn!("automatic vacuum failed. table={:?} err={:#}", table, e),
+        }
+    }
+    vacuumed
+}
+
+// AutoVacLauncherMain, check the tables every naptime until stop is set.
+pub fn start_autovacuum(
+    stats: &'static TabStats,
+    xact: &'static xact::GlobalStateExt,
+    reclaimer: &'static dyn Reclaimer,
+    opts: AutoVacOpts,
+    stop: &'static AtomicBool,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut nextcheck = Instant::now() + opts.naptime;
+        while !stop.load(Relaxed) {
+            let now = Instant::now();
+            if now < nextcheck {
+                thread::sleep((nextcheck - now).min(Duration::from_millis(100)));
+                continue;
+            }
+            do_autovacuum(stats, reclaimer, xact.global_xmin(), &opts);
+            nextcheck = Instant::now() + opts.naptime;
+        }
+    })
+}
+
+#[cfg(test)]
+mod autovacuum_test {
+    use super::{start_autovacuum, AutoVacOpts, Reclaimer, TabStats};
+    use crate::access::sv::TableId;
+    use crate::access::xact;
+    use crate::utils::Xid;
+    use crate::Oid;
+    use std::sync::atomic::{AtomicBool, Ordering::Relaxed};
+    use std::sync::Mutex;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    struct MockReclaimer {
+        passes: Mutex<Vec<(TableId, Xid, usize)>>,
+    }
+
+    impl Reclaimer for MockReclaimer {
+        fn reclaim(&self, table: TableId, horizon: Xid, work_mem: usize) -> anyhow::Result<u64> {
+            self.passes.lock().unwrap().push((table, horizon, work_mem));
+            Ok(300)
+        }
+    }
+
+    fn tableid(table: u32) -> TableId {
+        TableId {
+            db: Oid::new(1).unwrap(),
+            table: Oid::new(table).unwrap(),
+        }
+    }
+
+    #[test]
+    fn trigger() {
+        let stats: &'static TabStats = Box::leak(Box::new(TabStats::new()));
+        let xact: &'static xact::GlobalStateExt = Box::leak(Box::new(xact::GlobalStateExt::new(
+            Xid::new(20181218).unwrap(),
+            1000000,
+        )));
+        let reclaimer: &'static MockReclaimer = Box::leak(Box::new(MockReclaimer {
+            passes: Mutex::new(Vec::new()),
+        }));
+        let stop: &'static AtomicBool = Box::leak(Box::new(AtomicBool::new(false)));
+        let opts = AutoVacOpts {
+            naptime: Duration::from_millis(10),
+            vacuum_threshold: 50,
+            vacuum_scale_factor: 0.2,
+            work_mem: 64 << 20,
+        };
+        // vacthresh = 50 + 0.2 * 1000 = 250
+        stats.count_insert(tableid(65536), 1300);
+        stats.count_delete(tableid(65536), 300);
+        stats.count_insert(tableid(65537), 1100);
+        stats.count_delete(tableid(65537), 100);
+        let worker = start_autovacuum(stats, xact, reclaimer, opts, stop);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while reclaimer.passes.lock().unwrap().is_empty() {
+            assert!(Instant::now() < deadline, "autovacuum is not triggered");
+            thread::sleep(Duration::from_millis(10));
+        }
+        // Wait for more rounds, the table vacuumed should not be triggered again.
+        thread::sleep(Duration::from_millis(100));
+        stop.store(true, Relaxed);
+        worker.join().unwrap();
+
+        let passes = reclaimer.passes.lock().unwrap();
+        assert_eq!(
+            *passes,
+            vec![(tableid(65536), xact.global_xmin(), 64 << 20)]
+        );
+        let stat = stats.get(tableid(65536)).unwrap();
+        assert_eq!((stat.live_rows, stat.dead_rows), (1000, 0));
+        let stat = stats.get(tableid(65537)).unwrap();
+        assert_eq!((stat.live_rows, stat.dead_rows), (1000, 100));
+    }
+}
@@ -8,6 +8,17 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
+use crate::access::clog;
+use crate::access::wal::{
+    finish_record, new_ckpt_rec, Ckpt, Ctl, DbState, LocalWalStorage, Lsn, RmgrId, WalStorage,
+    XlogInfo,
+};
+use crate::access::walarchive::WalArchiver;
+use crate::guc;
+use crate::utils::crashpoint::{crash_point, CrashPoint};
+use crate::utils::KBSystemTime;
+use crate::{GlobalState, Oid};
+use std::sync::atomic::Ordering;
 use std::sync::Mutex;
 use std::vec::Vec;
 
@@ -44,3 +55,57 @@ impl PendingFileOps {
         });
     }
 }
+
+// RemoveOldXlogFiles(), the wal files before the redo of the checkpoint are removed unless the
+// replication slots still need them.
+pub fn remove_old_wal(
+    g: &GlobalState,
+    storage: &mut dyn WalStorage,
+    redo: Lsn,
+    endlsn: Lsn,
+) -> anyhow::Result<()> {
+    let max_keep_size = guc::get_int(&g.gucstate, guc::MaxSlotWalKeepSize) as i64;
+    let keeplsn = g.replslots.wal_keep_lsn(redo, endlsn, max_keep_size);
+    return storage.remove_before(keeplsn);
+}
+
+// CreateCheckPoint(CHECKPOINT_IS_SHUTDOWN), called after all sessions have exited. Nothing is
+// written after it, so the redo of the checkpoint is the checkpoint record itself and the next
+// start can skip the redo, see redo().
+pub fn shutdown_ckpt(g: &GlobalState) -> anyhow::Result<()> {
+    let wal = g.wal.unwrap();
+    g.tabsv.flushall(true)?;
+    g.tabmvcc.flushall(true)?;
+    clog::WorkerStateExt::new(g.clog).flushall()?;
+    crash_point(CrashPoint::CkptFlush);
+    let redo = wal.start_ckpt();
+    let curtli = wal.curtli();
+    let ckpt = Ckpt {
+        redo,
+        curtli,
+        prevtli: curtli,
+        nextxid: g.xact.unwrap().nextxid(),
+        nextoid: Oid::new(g.oid_creator.unwrap().load(Ordering::Relaxed)).unwrap(),
+        time: KBSystemTime::now(),
+    };
+    let mut rec = new_ckpt_rec(&ckpt);
+    finish_record(&mut rec, RmgrId::Xlog, XlogInfo::CkptShutdown as u8, None);
+    let reclen = rec.len() as u64;
+    let endlsn = wal.insert_record(rec);
+    wal.fsync(endlsn);
+    crash_point(CrashPoint::CkptRecord);
+    let ckptlsn = Lsn::new(endlsn.get() - reclen).unwrap();
+    log::info!(
+        "shutdown checkpoint. ckpt={} redo={} nextxid={} nextoid={}",
+        ckptlsn,
+        ckpt.redo,
+        ckpt.nextxid,
+        ckpt.nextoid
+    );
+    let mut ctl = Ctl::new(ckptlsn, ckpt);
+    ctl.state = DbState::Shutdowned;
+    ctl.persist(wal.sync_method())?;
+    let mut storage = LocalWalStorage::new();
+    storage.set_archiver(WalArchiver::from_guc(&g.gucstate)?);
+    return remove_old_wal(g, &mut storage, redo, endlsn);
+}
@@ -72,6 +72,10 @@ impl WorkerStateExt {
         WorkerStateExt { g }
     }
 
+    pub fn flushall(&self) -> anyhow::Result<()> {
+        self.g.d.flushall()
+    }
+
     pub fn set_xid_status(&self, xid: Xid, status: XidStatus) -> anyhow::Result<()> {
         let xid = xid.get();
         let byteno = xid_to_byte(xid);
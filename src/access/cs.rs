@@ -10,14 +10,18 @@
 // limitations under the License.
 use crate::access::csmvcc::MVCCBuf;
 use crate::access::rel;
+use crate::access::xact::WorkerExt as XACTWorkerExt;
 use crate::access::{fd, sv};
 use crate::datums::{self, Datums};
-use crate::kbensure;
+use crate::utils::sb::{LRUPolicy, SlotPinGuard};
 use crate::utils::{ser, WorkerState};
+use crate::{guc, kbanyhow, kbensure, FileId};
 use anyhow::ensure;
 use nix::libc::off_t;
-use nix::sys::uio::pwrite;
+use nix::sys::uio::{pread, pwrite};
+use std::convert::TryInto;
 use std::mem::size_of;
+use std::ops::Range;
 use std::os::unix::io::AsRawFd;
 use std::rc::Rc;
 
@@ -156,4 +160,391 @@ impl L0Writer {
         fd::use_file(&self.path, |l0file| l0file.sync_data())?;
         return Ok(());
     }
+
+    // Like sync(), but the xmins of the rows written are given instead of the xid of the worker,
+    // VACUUM FULL keeps the xmins of the rows it moves.
+    pub fn sync_xmins(
+        &mut self,
+        worker: &mut WorkerState,
+        mvccbuf: &MVCCBuf,
+        xmins: &[u64],
+    ) -> anyhow::Result<()> {
+        self.flush()?;
+        debug_assert_eq!(self.rownum, 0);
+        debug_assert_eq!(self.nextsetxminrow + xmins.len() as u32, self.meta.rownum);
+        mvccbuf.set_xmins(self.meta.fileid, self.nextsetxminrow, xmins, worker)?;
+        self.nextsetxminrow = self.meta.rownum;
+        fd::use_file(&self.path, |l0file| l0file.sync_data())?;
+        return Ok(());
+    }
+}
+
+const BLOCK_HDR_SIZE: usize = 8 /* total size */ + 4 /* rownum */ + 2 /* colnum */;
+
+// Returns the total size, the rownum and the colnum of the block.
+fn block_hdr(hdr: &[u8]) -> (u64, u32, u16) {
+    let totalsize = u64::from_ne_bytes(hdr[..8].try_into().unwrap());
+    let rownum = u32::from_ne_bytes(hdr[8..12].try_into().unwrap());
+    let colnum = u16::from_ne_bytes(hdr[12..BLOCK_HDR_SIZE].try_into().unwrap());
+    return (totalsize, rownum, colnum);
+}
+
+// Check the header of the block at off of the file, startrow is the first row of the block.
+// The block written before ALTER TABLE ADD COLUMN has fewer columns than the relation.
+fn check_block_hdr(
+    path: &str,
+    file: sv::FileMeta,
+    off: u64,
+    startrow: u32,
+    rel: &rel::Rel,
+    hdr: &[u8],
+) -> anyhow::Result<(u64, u32, usize)> {
+    let (totalsize, rownum, colnum) = block_hdr(hdr);
+    kbensure!(
+        totalsize as usize > BLOCK_HDR_SIZE + size_of::<u32>()
+            && off + totalsize <= file.len
+            && rownum > 0
+            && startrow + rownum <= file.rownum
+            && colnum > 0
+            && colnum as usize <= rel.attrs.len(),
+        ERRCODE_DATA_CORRUPTED,
+        "invalid data block header: path={} off={} totalsize={} rownum={} colnum={}",
+        path,
+        off,
+        totalsize,
+        rownum,
+        colnum
+    );
+    return Ok((totalsize, rownum, colnum as usize));
+}
+
+// The crc at the end of the block covers the rest of the block.
+fn block_crc(block: &[u8]) -> (u32, u32) {
+    let crcidx = block.len() - size_of::<u32>();
+    let crc = u32::from_ne_bytes(block[crcidx..].try_into().unwrap());
+    return (crc, crc32c::crc32c(&block[..crcidx]));
+}
+
+fn check_block_crc(path: &str, off: u64, block: &[u8]) -> anyhow::Result<()> {
+    let (crc, ecrc) = block_crc(block);
+    kbensure!(
+        crc == ecrc,
+        ERRCODE_DATA_CORRUPTED,
+        "invalid checksum in data block: path={} off={} expected={} actual={}",
+        path,
+        off,
+        ecrc,
+        crc
+    );
+    return Ok(());
+}
+
+// Check the header and the checksum of every block of the file, returns the number of blocks.
+// It is used by VERIFY TABLE, which reads the blocks as TableScan does but never decodes them.
+// The corruptions are pushed to corrupted, the blocks after an invalid header can not be
+// located, so the check of the file stops there.
+pub fn verify_datafile(
+    table: sv::TableId,
+    rel: &rel::Rel,
+    file: sv::FileMeta,
+    corrupted: &mut Vec<anyhow::Error>,
+) -> anyhow::Result<u32> {
+    let path = sv::get_datafile_path(table, file.fileid);
+    let mut data = vec![0u8; file.len as usize];
+    let rn = fd::use_file(&path, |datafile| -> anyhow::Result<usize> {
+        Ok(pread(datafile.as_raw_fd(), &mut data, 0)?)
+    })?;
+    if rn != data.len() {
+        corrupted.push(kbanyhow!(
+            ERRCODE_DATA_CORRUPTED,
+            "data file is shorter than the manifest expects: path={} len={} expected={}",
+            path,
+            rn,
+            file.len
+        ));
+        return Ok(0);
+    }
+    let (mut off, mut rownum, mut nblocks) = (0u64, 0u32, 0u32);
+    while off < file.len {
+        let hdr = &data[off as usize..];
+        if hdr.len() < BLOCK_HDR_SIZE {
+            corrupted.push(kbanyhow!(
+                ERRCODE_DATA_CORRUPTED,
+                "invalid data block header: path={} off={} len={}",
+                path,
+                off,
+                hdr.len()
+            ));
+            return Ok(nblocks);
+        }
+        let (totalsize, blkrows, _) = match check_block_hdr(&path, file, off, rownum, rel, hdr) {
+            Ok(v) => v,
+            Err(err) => {
+                corrupted.push(err);
+                return Ok(nblocks);
+            }
+        };
+        let block = &data[off as usize..(off + totalsize) as usize];
+        if let Err(err) = check_block_crc(&path, off, block) {
+            corrupted.push(err);
+        }
+        off += totalsize;
+        rownum += blkrows;
+        nblocks += 1;
+    }
+    if rownum != file.rownum {
+        corrupted.push(kbanyhow!(
+            ERRCODE_DATA_CORRUPTED,
+            "data file has {} rows, but the manifest expects {}: path={}",
+            rownum,
+            file.rownum,
+            path
+        ));
+    }
+    return Ok(nblocks);
+}
+
+// Returns the rownum and the length of the valid blocks at the beginning of the data file, the
+// blocks since the first invalid one, such as a torn block, are ignored.
+pub fn scan_datafile(path: &str) -> anyhow::Result<(u32, u64)> {
+    let data = std::fs::read(path)?;
+    let mut off = 0;
+    let mut rownum = 0u32;
+    while data.len() - off >= BLOCK_HDR_SIZE {
+        let (totalsize, blkrows, _) = block_hdr(&data[off..]);
+        if totalsize > (data.len() - off) as u64
+            || totalsize as usize <= BLOCK_HDR_SIZE + size_of::<u32>()
+            || blkrows == 0
+        {
+            break;
+        }
+        let block = &data[off..off + totalsize as usize];
+        let (crc, ecrc) = block_crc(block);
+        if crc != ecrc {
+            break;
+        }
+        rownum += blkrows;
+        off += block.len();
+    }
+    return Ok((rownum, off as u64));
+}
+
+// Read the blocks of the data files in the SupVer, only the rows visible to the snapshot of
+// the worker are returned.
+// The files of the SupVer of the table that have data.
+pub fn datafiles(
+    table: &sv::TableId,
+    rel: &rel::Rel,
+    worker: &WorkerState,
+) -> anyhow::Result<Vec<sv::FileMeta>> {
+    // svslot pin guard
+    let svslot = worker.tabsv.read(table, &rel.opt.enable_cs_wal)?;
+    let sv = svslot.v.read().unwrap();
+    return Ok(sv.as_ref().unwrap().datafiles());
+}
+
+pub struct TableScan {
+    table: sv::TableId,
+    rel: rel::Rel,
+    mvccslot: SlotPinGuard<'static, MVCCBuf, LRUPolicy>,
+    files: Vec<sv::FileMeta>,
+    fileidx: usize,
+    // The offset and the first row of the next block in files[fileidx].
+    off: u64,
+    startrow: u32,
+
+    blockbuf: Vec<u8>,
+    // enable_mmap_scan, the sealed file is mapped and the block is read from the mapping.
+    enable_mmap: bool,
+    mmap: Option<(FileId, fd::MmapFile)>,
+    mmapblock: Option<Range<usize>>,
+    xmins: Vec<u64>,
+    visible: Vec<bool>,
+}
+
+impl TableScan {
+    pub fn new(table: sv::TableId, rel: rel::Rel, worker: &WorkerState) -> anyhow::Result<Self> {
+        let files = datafiles(&table, &rel, worker)?;
+        return Self::with_files(table, rel, files, worker);
+    }
+
+    // Scan only the given files, which are a part of datafiles() in the parallel scan.
+    pub fn with_files(
+        table: sv::TableId,
+        rel: rel::Rel,
+        files: Vec<sv::FileMeta>,
+        worker: &WorkerState,
+    ) -> anyhow::Result<Self> {
+        for attr in &rel.attrs {
+            kbensure!(
+                attr.typ.len > 0,
+                ERRCODE_FEATURE_NOT_SUPPORTED,
+                "scan on variable-length column {} is not supported",
+                &attr.name
+            );
+        }
+        let mvccslot = worker.tabmvcc.read(&table, &rel.opt)?;
+        Ok(Self {
+            table,
+            rel,
+            mvccslot,
+            files,
+            fileidx: 0,
+            off: 0,
+            startrow: 0,
+            blockbuf: Vec::new(),
+            enable_mmap: guc::get_bool(&worker.gucstate, guc::EnableMmapScan),
+            mmap: None,
+            mmapblock: None,
+            xmins: Vec::new(),
+            visible: Vec::new(),
+        })
+    }
+
+    fn pread_exact(&mut self, path: &String, len: usize, off: u64) -> anyhow::Result<()> {
+        self.blockbuf.resize(len, 0);
+        let rn = fd::use_file(path, |datafile| -> anyhow::Result<usize> {
+            Ok(pread(
+                datafile.as_raw_fd(),
+                &mut self.blockbuf,
+                off as off_t,
+            )?)
+        })?;
+        ensure!(
+            rn == len,
+            "TableScan: invalid rn: path={} a={} e={} o={}",
+            path,
+            rn,
+            len,
+            off
+        );
+        return Ok(());
+    }
+
+    // The block read by read_block(), it is in the mapping of the file or in blockbuf.
+    fn block(&self) -> &[u8] {
+        match (&self.mmap, &self.mmapblock) {
+            (Some((_, mmap)), Some(range)) => &mmap.data()[range.clone()],
+            _ => &self.blockbuf,
+        }
+    }
+
+    // Read the block at self.off, returns the rownum and the colnum of the block. The sealed
+    // file is mapped instead of read into blockbuf if enable_mmap is set, the L0 file may be
+    // appended.
+    fn read_block(&mut self, file: sv::FileMeta) -> anyhow::Result<(u32, usize)> {
+        let path = sv::get_datafile_path(self.table, file.fileid);
+        let usemmap = self.enable_mmap && file.sealed;
+        if usemmap && !matches!(&self.mmap, Some((fileid, _)) if *fileid == file.fileid) {
+            self.mmap = None;
+            self.mmap = Some((file.fileid, fd::MmapFile::new(&path, file.len as usize)?));
+        }
+        self.mmapblock = None;
+        let mut hdr = [0u8; BLOCK_HDR_SIZE];
+        if usemmap {
+            let data = self.mmap.as_ref().unwrap().1.data();
+            let off = self.off as usize;
+            kbensure!(
+                off + BLOCK_HDR_SIZE <= data.len(),
+                ERRCODE_DATA_CORRUPTED,
+                "invalid data block header: path={} off={} len={}",
+                &path,
+                off,
+                data.len()
+            );
+            hdr.copy_from_slice(&data[off..off + BLOCK_HDR_SIZE]);
+        } else {
+            self.pread_exact(&path, BLOCK_HDR_SIZE, self.off)?;
+            hdr.copy_from_slice(&self.blockbuf);
+        }
+        let (totalsize, rownum, colnum) =
+            check_block_hdr(&path, file, self.off, self.startrow, &self.rel, &hdr)?;
+        if usemmap {
+            let off = self.off as usize;
+            self.mmapblock = Some(off..off + totalsize as usize);
+        } else {
+            self.pread_exact(&path, totalsize as usize, self.off)?;
+        }
+        check_block_crc(&path, self.off, self.block())?;
+        self.off += totalsize;
+        return Ok((rownum, colnum));
+    }
+
+    // Read the next block into out and its xmins into self.xmins, returns None if there are no
+    // more blocks.
+    fn read_next(&mut self, out: &mut Vec<Rc<Datums>>) -> anyhow::Result<Option<u32>> {
+        let file = loop {
+            match self.files.get(self.fileidx) {
+                None => return Ok(None),
+                Some(&file) if self.off < file.len => break file,
+                Some(_) => {
+                    self.fileidx += 1;
+                    self.off = 0;
+                    self.startrow = 0;
+                    self.mmap = None;
+                }
+            }
+        };
+        let (rownum, colnum) = self.read_block(file)?;
+        let block = self.block();
+        let crcidx = block.len() - size_of::<u32>();
+        datums::deser(
+            out,
+            &self.rel,
+            rownum,
+            colnum,
+            &block[BLOCK_HDR_SIZE..crcidx],
+        )?;
+
+        self.xmins.clear();
+        {
+            let mvcc = self.mvccslot.v.read().unwrap(); // mvcc read lock guard
+            let endrow = self.startrow + rownum;
+            let mvcc = mvcc.as_ref().unwrap();
+            mvcc.get_xmin(file.fileid, self.startrow, endrow, &mut self.xmins)?;
+        }
+        self.startrow += rownum;
+        return Ok(Some(rownum));
+    }
+
+    // Like next(), but all rows of the block are stored in out and their xmins in xmins,
+    // VACUUM decides the visibility by itself.
+    pub fn next_raw(
+        &mut self,
+        out: &mut Vec<Rc<Datums>>,
+        xmins: &mut Vec<u64>,
+    ) -> anyhow::Result<Option<u32>> {
+        let rownum = self.read_next(out)?;
+        xmins.clear();
+        xmins.extend_from_slice(&self.xmins);
+        return Ok(rownum);
+    }
+
+    // Returns None if there are no more blocks. The rows of the block visible to the snapshot
+    // are stored in out, the row number returned may be 0.
+    pub fn next(
+        &mut self,
+        worker: &WorkerState,
+        out: &mut Vec<Rc<Datums>>,
+    ) -> anyhow::Result<Option<u32>> {
+        let rownum = match self.read_next(out)? {
+            None => return Ok(None),
+            Some(rownum) => rownum,
+        };
+        self.visible.clear();
+        let mut visnum = 0;
+        for &xmin in &self.xmins {
+            let visible = worker.xmin_visible(xmin)?;
+            visnum += visible as u32;
+            self.visible.push(visible);
+        }
+        if visnum == rownum {
+            return Ok(Some(rownum));
+        }
+        for (col, attr) in out.iter_mut().zip(self.rel.attrs.iter()) {
+            let col = Rc::get_mut(col).unwrap();
+            col.retain_fixedlen(attr.typ.len as usize, &self.visible);
+        }
+        return Ok(Some(visnum));
+    }
 }
@@ -9,23 +9,29 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 use crate::access::ckpt::PendingFileOps;
+use crate::access::clog::XidStatus;
 use crate::access::fd;
+use crate::access::redo::RedoState;
 use crate::access::rel::RelOpt;
 use crate::access::sv::{get_mvccfile_path, TableId};
-use crate::access::wal::{self, Lsn, RmgrId};
+use crate::access::wal::{self, Lsn, RecordHdr, Rmgr, RmgrId};
 use crate::access::xact::WorkerExt as XACTWorkerExt;
 use crate::utils::sb::{self, FIFOPolicy, LRUPolicy, SharedBuffer, Value};
-use crate::utils::Xid;
 use crate::utils::{alloc, dealloc};
-use crate::utils::{pwritevn, WorkerState};
-use crate::FileId;
-use anyhow::ensure;
+use crate::utils::{pwritevn, ser, WorkerState, Xid, FROZEN_XID};
+use crate::{kbanyhow, kbbail, kbensure, FileId, Oid};
+use anyhow::{anyhow, ensure};
+use byteorder::{LittleEndian, ReadBytesExt};
 use nix::libc::off_t;
 use nix::sys::uio::pread;
 use nix::sys::uio::IoVec;
 use static_assertions::const_assert;
+use std::convert::TryInto;
+use std::fmt::Write;
+use std::io::Cursor;
 use std::mem::{align_of, size_of};
 use std::os::unix::io::AsRawFd;
+use std::path::Path;
 use std::ptr::NonNull;
 use std::slice;
 
@@ -125,10 +131,44 @@ impl Page {
         }
     }
 
+    fn xmin_as_slice(&self, sidx: isize, len: usize) -> &[u64] {
+        debug_assert!(sidx >= 0);
+        debug_assert!((sidx as usize + len) <= self.blk_rows() as usize);
+        debug_assert_eq!(self.0.as_ptr() as usize % align_of::<u64>(), 0);
+        unsafe {
+            let sptr = self.0.cast::<u64>().as_ptr().offset(2 + sidx);
+            slice::from_raw_parts(sptr, len)
+        }
+    }
+
     fn set_xmin(&mut self, sidx: isize, len: usize, xid: Xid) {
         self.xmin_as_mut_slice(sidx, len).fill(xid.get());
         return;
     }
+
+    // The page read from the file is valid.
+    fn check(&self, readsize: usize, blk_rows: u32) -> anyhow::Result<()> {
+        ensure!(
+            readsize == self.1,
+            "Page::load failed. unexpected readsize. r={} e={}",
+            self.1,
+            readsize
+        );
+        let ecrc = self.calc_crc32c();
+        ensure!(
+            self.crc() == ecrc,
+            "Page::load failed. invalid crc. e={} r={}",
+            self.crc(),
+            ecrc
+        );
+        ensure!(
+            self.blk_rows() == blk_rows,
+            "Page::load failed. invalid blk_rows. e={} r={}",
+            self.blk_rows(),
+            blk_rows
+        );
+        return Ok(());
+    }
 }
 
 unsafe impl Send for Page {}
@@ -162,12 +202,71 @@ fn get_blk_size(rows: u64) -> usize {
         infomasksize; /* xmax infomask */
 }
 
+// Check the checksum of every page of the mvcc file, returns the number of pages. It is used by
+// VERIFY TABLE, the corruptions are pushed to corrupted. The pages never stored are the holes
+// of zeros, see Page::load().
+pub fn verify_mvccfile(
+    table: TableId,
+    fileid: FileId,
+    blk_rows: u32,
+    corrupted: &mut Vec<anyhow::Error>,
+) -> anyhow::Result<u32> {
+    let path = get_mvccfile_path(table, fileid);
+    let data = std::fs::read(&path)?;
+    let blk_size = get_blk_size(blk_rows as u64);
+    let mut npages = 0;
+    for (blkid, page) in data.chunks(blk_size).enumerate() {
+        if page.len() != blk_size {
+            corrupted.push(kbanyhow!(
+                ERRCODE_DATA_CORRUPTED,
+                "invalid mvcc page size: path={} page={} size={} expected={}",
+                path,
+                blkid,
+                page.len(),
+                blk_size
+            ));
+            break;
+        }
+        if page.iter().all(|&b| b == 0) {
+            continue;
+        }
+        npages += 1;
+        let crc = u32::from_ne_bytes(page[..4].try_into().unwrap());
+        let ecrc = crc32c::crc32c(&page[4..]);
+        if crc != ecrc {
+            corrupted.push(kbanyhow!(
+                ERRCODE_DATA_CORRUPTED,
+                "invalid checksum in mvcc page: path={} page={} expected={} actual={}",
+                path,
+                blkid,
+                ecrc,
+                crc
+            ));
+            continue;
+        }
+        let rows = u32::from_ne_bytes(page[4..8].try_into().unwrap());
+        if rows != blk_rows {
+            corrupted.push(kbanyhow!(
+                ERRCODE_DATA_CORRUPTED,
+                "invalid blk_rows in mvcc page: path={} page={} blk_rows={} expected={}",
+                path,
+                blkid,
+                rows,
+                blk_rows
+            ));
+        }
+    }
+    return Ok(npages);
+}
+
 impl Value for Page {
     type K = PageId;
-    type LoadCtx = ();
+    // zero_on_error, just as RBM_ZERO_ON_ERROR. The redo of the record that overwrites the whole
+    // page uses it, since the page may be torn by the crash.
+    type LoadCtx = bool;
     type CommonData = PageCtx;
 
-    fn load(k: &Self::K, _lctx: &Self::LoadCtx, ctx: &Self::CommonData) -> anyhow::Result<Self> {
+    fn load(k: &Self::K, lctx: &Self::LoadCtx, ctx: &Self::CommonData) -> anyhow::Result<Self> {
         let mut page = Page::new(ctx.blk_rows as u64);
         let off = page.1 * k.blkid as usize;
         debug_assert!(off <= off_t::MAX as usize);
@@ -183,25 +282,18 @@ impl Value for Page {
             page.init(ctx.blk_rows);
             return Ok(page);
         }
-        ensure!(
-            readsize == page.1,
-            "Page::load failed. unexpected readsize. r={} e={}",
-            page.1,
-            readsize
-        );
-        let ecrc = page.calc_crc32c();
-        ensure!(
-            page.crc() == ecrc,
-            "Page::load failed. invalid crc. e={} r={}",
-            page.crc(),
-            ecrc
-        );
-        ensure!(
-            page.blk_rows() == ctx.blk_rows,
-            "Page::load failed. invalid blk_rows. e={} r={}",
-            page.blk_rows(),
-            ctx.blk_rows
-        );
+        if let Err(err) = page.check(readsize, ctx.blk_rows) {
+            if !*lctx {
+                return Err(err);
+            }
+            log::warn!(
+                "Page::load: the invalid page is zeroed. path={} page={} err={}",
+                filepath,
+                k.blkid,
+                err
+            );
+            page.init(ctx.blk_rows);
+        }
         return Ok(page);
     }
 
@@ -286,45 +378,183 @@ impl Value for MVCCBuf {
 const BUF_INIT: u8 = 0;
 const BUF_FPI: u8 = 1;
 const BUF_SET_PAGE_XMIN: u8 = 2;
-#[repr(C, packed(1))]
-struct BufInitSer {
-    eidx: u32,
-    xid: Xid,
+// Every record starts with the page it changes, in little-endian: db u32, table u32,
+// fileid u32, blkid u32, blk_rows u32. blk_rows is logged since the redo can not read the
+// reloptions of the table.
+fn ser_page_tag(out: &mut Vec<u8>, table: TableId, pageid: PageId, blk_rows: u32) {
+    ser::ser_le_u32(out, table.db.get());
+    ser::ser_le_u32(out, table.table.get());
+    ser::ser_le_u32(out, pageid.fileid.get());
+    ser::ser_le_u32(out, pageid.blkid);
+    ser::ser_le_u32(out, blk_rows);
 }
-#[repr(C, packed(1))]
-struct BufSetPageXminSer {
-    sidx: u32,
-    eidx: u32,
-    xid: Xid,
+
+fn de_page_tag(cursor: &mut Cursor<&[u8]>) -> anyhow::Result<(TableId, PageId, u32)> {
+    let db = cursor.read_u32::<LittleEndian>()?;
+    let table = cursor.read_u32::<LittleEndian>()?;
+    let fileid = cursor.read_u32::<LittleEndian>()?;
+    let blkid = cursor.read_u32::<LittleEndian>()?;
+    let blk_rows = cursor.read_u32::<LittleEndian>()?;
+    let table = TableId {
+        db: Oid::new(db).ok_or_else(|| anyhow!("de_page_tag: invalid db"))?,
+        table: Oid::new(table).ok_or_else(|| anyhow!("de_page_tag: invalid table"))?,
+    };
+    let fileid = FileId::new(fileid).ok_or_else(|| anyhow!("de_page_tag: invalid fileid"))?;
+    return Ok((table, PageId { fileid, blkid }, blk_rows));
+}
+
+// In little-endian: eidx u32, xid u64.
+fn ser_buf_init(out: &mut Vec<u8>, eidx: u32, xid: Xid) {
+    ser::ser_le_u32(out, eidx);
+    ser::ser_le_u64(out, xid.get());
+}
+
+// In little-endian: sidx u32, eidx u32, xid u64.
+fn ser_buf_set_page_xmin(out: &mut Vec<u8>, sidx: u32, eidx: u32, xid: Xid) {
+    ser::ser_le_u32(out, sidx);
+    ser::ser_le_u32(out, eidx);
+    ser::ser_le_u64(out, xid.get());
+}
+
+// Returns sidx, eidx and xid, sidx is 0 for BUF_INIT.
+fn de_buf_xmin(cursor: &mut Cursor<&[u8]>, info: u8) -> anyhow::Result<(u32, u32, Xid)> {
+    let sidx = if info == BUF_INIT {
+        0
+    } else {
+        cursor.read_u32::<LittleEndian>()?
+    };
+    let eidx = cursor.read_u32::<LittleEndian>()?;
+    let xid = cursor.read_u64::<LittleEndian>()?;
+    let xid = Xid::new(xid).ok_or_else(|| anyhow!("de_buf_xmin: invalid xid"))?;
+    return Ok((sidx, eidx, xid));
+}
+
+// Log the whole page, which is also used when the xmins of the page are changed one by one.
+fn insert_fpi_wal(page: &Page, ctx: &PageCtx, pageid: PageId, worker: &mut WorkerState) -> Lsn {
+    let mut waldat = wal::start_record_raw(&[]);
+    ser_page_tag(&mut waldat, ctx.tableid, pageid, ctx.blk_rows);
+    waldat.extend_from_slice(page.as_bytes());
+    let lsn = worker.insert_record(RmgrId::CSMvcc, BUF_FPI, waldat);
+    worker.wal.unwrap().count_fpi();
+    return lsn;
 }
 
 // insert wal record for set_page_xmin().
-fn insert_xmin_wal(page: &Page, sidx: u32, eidx: u32, xid: Xid, worker: &mut WorkerState) -> Lsn {
+fn insert_xmin_wal(
+    page: &Page,
+    ctx: &PageCtx,
+    pageid: PageId,
+    sidx: u32,
+    eidx: u32,
+    xid: Xid,
+    worker: &mut WorkerState,
+) -> Lsn {
     if let Some(pagelsn) = page.lsn() {
         if pagelsn <= worker.wal.unwrap().recently_redo_lsn() {
-            let waldat = wal::start_record_raw(page.as_bytes());
-            return worker.insert_record(RmgrId::CSMvcc, BUF_FPI, waldat);
+            return insert_fpi_wal(page, ctx, pageid, worker);
         } else {
-            let args = BufSetPageXminSer { sidx, eidx, xid };
-            let waldat = wal::start_record(&args);
+            let mut waldat = wal::start_record_raw(&[]);
+            ser_page_tag(&mut waldat, ctx.tableid, pageid, ctx.blk_rows);
+            ser_buf_set_page_xmin(&mut waldat, sidx, eidx, xid);
             let lsnret =
                 worker.try_insert_record(RmgrId::CSMvcc, BUF_SET_PAGE_XMIN, waldat, pagelsn);
             if let Some(retlsn) = lsnret {
                 return retlsn;
             }
-            let waldat = wal::start_record_raw(page.as_bytes());
-            return worker.insert_record(RmgrId::CSMvcc, BUF_FPI, waldat);
+            return insert_fpi_wal(page, ctx, pageid, worker);
         }
     } else {
         // See XLOG_HEAP_INIT_PAGE in heap_insert().
         debug_assert_eq!(sidx, 0);
-        let rec = BufInitSer { eidx, xid };
-        let waldat = wal::start_record(&rec);
+        let mut waldat = wal::start_record_raw(&[]);
+        ser_page_tag(&mut waldat, ctx.tableid, pageid, ctx.blk_rows);
+        ser_buf_init(&mut waldat, eidx, xid);
         return worker.insert_record(RmgrId::CSMvcc, BUF_INIT, waldat);
     }
 }
 
 impl MVCCBuf {
+    // Call f with the row number and the xmin of every row of [sr, er), f returns true if it
+    // changes the xmin. The xmins are written back to the page only if f succeeds for all rows
+    // of the page, and the page changed is logged as a full page image. Returns the number of
+    // the xmins changed.
+    fn update_xmin(
+        &self,
+        fileid: FileId,
+        mut sr: u32,
+        er: u32,
+        ws: &mut WorkerState,
+        mut f: impl FnMut(&WorkerState, u32, &mut u64) -> anyhow::Result<bool>,
+    ) -> anyhow::Result<u32> {
+        let blk_rows = self.pages.valctx.blk_rows;
+        let mut changed = 0;
+        let mut xmins = Vec::new();
+        while sr < er {
+            let blkid = sr / blk_rows;
+            let blksr = blkid * blk_rows;
+            let nextsr = std::cmp::min(blksr + blk_rows, er);
+            let pageid = PageId { fileid, blkid };
+            let slot = self.pages.read(&pageid, &false)?; // pin guard
+            let mut pageguard = slot.v.write().unwrap(); // page write lock guard
+            let pagedat = pageguard.as_mut().unwrap();
+            let sidx = (sr - blksr) as isize;
+            let len = (nextsr - sr) as usize;
+            xmins.clear();
+            xmins.extend_from_slice(pagedat.xmin_as_slice(sidx, len));
+            let mut pagechanged = 0;
+            for (row, xmin) in (sr..nextsr).zip(xmins.iter_mut()) {
+                pagechanged += f(ws, row, xmin)? as u32;
+            }
+            if pagechanged > 0 {
+                pagedat.xmin_as_mut_slice(sidx, len).copy_from_slice(&xmins);
+                slot.mark_dirty();
+                let lsn = insert_fpi_wal(pagedat, &self.pages.valctx, pageid, ws);
+                pagedat.set_lsn(lsn);
+                changed += pagechanged;
+            }
+            sr = nextsr;
+        }
+        return Ok(changed);
+    }
+
+    // heap_prepare_freeze_tuple, the committed xmin before horizon is replaced with FROZEN_XID,
+    // so the clog is never looked up for the row again. Returns the number of rows frozen.
+    pub fn freeze(
+        &self,
+        fileid: FileId,
+        rownum: u32,
+        horizon: Xid,
+        ws: &mut WorkerState,
+    ) -> anyhow::Result<u32> {
+        return self.update_xmin(fileid, 0, rownum, ws, |ws, _, xmin| {
+            let xid = match Xid::new(*xmin) {
+                Some(xid) if xid != FROZEN_XID && xid < horizon => xid,
+                _ => return Ok(false),
+            };
+            if ws.clog.xid_status(xid)? != XidStatus::Committed {
+                return Ok(false);
+            }
+            *xmin = FROZEN_XID.get();
+            return Ok(true);
+        });
+    }
+
+    // Set the xmins of the rows since sr, VACUUM FULL keeps the xmins of the rows it moves.
+    pub fn set_xmins(
+        &self,
+        fileid: FileId,
+        sr: u32,
+        xmins: &[u64],
+        ws: &mut WorkerState,
+    ) -> anyhow::Result<()> {
+        let er = sr + xmins.len() as u32;
+        self.update_xmin(fileid, sr, er, ws, |_, row, xmin| {
+            *xmin = xmins[(row - sr) as usize];
+            return Ok(true);
+        })?;
+        return Ok(());
+    }
+
     // Set the xmin of [sr, er) to xid.
     fn set_blk_xmin(
         &self,
@@ -343,12 +573,12 @@ impl MVCCBuf {
         let eidx = er - blksr;
         let sidx_i = sidx as isize;
         let xmin_len = (eidx - sidx) as usize;
-        let slot = self.pages.read(&pageid, &())?; // pin guard
+        let slot = self.pages.read(&pageid, &false)?; // pin guard
         let mut pageguard = slot.v.write().unwrap(); // page write lock guard
         let pagedat = pageguard.as_mut().unwrap();
         pagedat.set_xmin(sidx_i, xmin_len, xid);
         slot.mark_dirty();
-        let lsn = insert_xmin_wal(pagedat, sidx, eidx, xid, ws);
+        let lsn = insert_xmin_wal(pagedat, &self.pages.valctx, pageid, sidx, eidx, xid, ws);
         pagedat.set_lsn(lsn);
         return Ok(());
     }
@@ -372,4 +602,245 @@ impl MVCCBuf {
         }
         return Ok(());
     }
+
+    // Append the xmin of [sr, er) to out, 0 means the row has not been inserted.
+    pub fn get_xmin(
+        &self,
+        fileid: FileId,
+        mut sr: u32,
+        er: u32,
+        out: &mut Vec<u64>,
+    ) -> anyhow::Result<()> {
+        let blk_rows = self.pages.valctx.blk_rows;
+        while sr < er {
+            let blkid = sr / blk_rows;
+            let blksr = blkid * blk_rows;
+            let nextsr = std::cmp::min(blksr + blk_rows, er);
+            let slot = self.pages.read(&PageId { fileid, blkid }, &false)?; // pin guard
+            let pageguard = slot.v.read().unwrap(); // page read lock guard
+            let pagedat = pageguard.as_ref().unwrap();
+            let sidx = (sr - blksr) as isize;
+            out.extend_from_slice(pagedat.xmin_as_slice(sidx, (nextsr - sr) as usize));
+            sr = nextsr;
+        }
+        return Ok(());
+    }
+}
+
+// Call f with the page of the record in the shared buffer of the table, the page is marked
+// dirty and its lsn is set to the end of the record if f returns true. The mvcc file removed
+// later by DROP TABLE or VACUUM FULL is skipped, just as XLogReadBufferForRedo().
+fn redo_page(
+    state: &mut RedoState,
+    data: &[u8],
+    zero_on_error: bool,
+    f: impl FnOnce(&mut Page, &mut Cursor<&[u8]>, Lsn) -> anyhow::Result<bool>,
+) -> anyhow::Result<()> {
+    let mut cursor = Cursor::new(data);
+    let (table, pageid, blk_rows) = de_page_tag(&mut cursor)?;
+    if !Path::new(&get_mvccfile_path(table, pageid.fileid)).exists() {
+        log::debug!(
+            "redo_page: skip the removed mvcc file. table={:?} page={:?}",
+            table,
+            pageid
+        );
+        return Ok(());
+    }
+    let endlsn = state.endlsn();
+    let worker = &state.worker;
+    let mut relopt = RelOpt::new(&worker.gucstate);
+    relopt.mvcc_blk_rows = blk_rows;
+    let mvccslot = worker.tabmvcc.read(&table, &relopt)?; // pin guard
+    let mvcc = mvccslot.v.read().unwrap(); // lock guard
+    let mvcc = mvcc.as_ref().unwrap();
+    kbensure!(
+        mvcc.pages.valctx.blk_rows == blk_rows,
+        ERRCODE_DATA_CORRUPTED,
+        "redo_page: unexpected blk_rows. table={:?} blk_rows={} expected={}",
+        table,
+        blk_rows,
+        mvcc.pages.valctx.blk_rows
+    );
+    let slot = mvcc.pages.read(&pageid, &zero_on_error)?; // pin guard
+    let mut pageguard = slot.v.write().unwrap(); // page write lock guard
+    let page = pageguard.as_mut().unwrap();
+    if f(page, &mut cursor, endlsn)? {
+        page.set_lsn(endlsn);
+        slot.mark_dirty();
+        mvccslot.mark_dirty();
+    }
+    return Ok(());
+}
+
+// The xmins of [sidx, eidx) must be in the page.
+fn check_xmin_range(page: &Page, sidx: u32, eidx: u32) -> anyhow::Result<()> {
+    kbensure!(
+        sidx < eidx && eidx <= page.blk_rows(),
+        ERRCODE_DATA_CORRUPTED,
+        "CSMvccRmgr::redo: invalid xmin range. sidx={} eidx={} blk_rows={}",
+        sidx,
+        eidx,
+        page.blk_rows()
+    );
+    return Ok(());
+}
+
+pub struct CSMvccRmgr {}
+
+impl CSMvccRmgr {
+    pub fn new() -> CSMvccRmgr {
+        CSMvccRmgr {}
+    }
+}
+
+impl Rmgr for CSMvccRmgr {
+    fn name(&self) -> &'static str {
+        "CSMvcc"
+    }
+
+    // The infos are not in the high 4 bits, so the whole info is matched, see SVRmgr::redo().
+    fn redo(&mut self, hdr: &RecordHdr, data: &[u8], state: &mut RedoState) -> anyhow::Result<()> {
+        match hdr.info {
+            // The page is initialized even if it is newer than the record, the later records
+            // bring it up to date, just as XLOG_HEAP_INIT_PAGE.
+            BUF_INIT => redo_page(state, data, true, |page, cursor, _| {
+                let (sidx, eidx, xid) = de_buf_xmin(cursor, BUF_INIT)?;
+                check_xmin_range(page, sidx, eidx)?;
+                page.init(page.blk_rows());
+                page.set_xmin(0, eidx as usize, xid);
+                return Ok(true);
+            }),
+            BUF_SET_PAGE_XMIN => redo_page(state, data, false, |page, cursor, endlsn| {
+                if matches!(page.lsn(), Some(lsn) if lsn >= endlsn) {
+                    return Ok(false);
+                }
+                let (sidx, eidx, xid) = de_buf_xmin(cursor, BUF_SET_PAGE_XMIN)?;
+                check_xmin_range(page, sidx, eidx)?;
+                page.set_xmin(sidx as isize, (eidx - sidx) as usize, xid);
+                return Ok(true);
+            }),
+            // RestoreBlockImage
+            BUF_FPI => redo_page(state, data, true, |page, cursor, _| {
+                let image = &cursor.get_ref()[cursor.position() as usize..];
+                kbensure!(
+                    image.len() == page.1,
+                    ERRCODE_DATA_CORRUPTED,
+                    "CSMvccRmgr::redo: invalid page image size. size={} expected={}",
+                    image.len(),
+                    page.1
+                );
+                page.as_mut_bytes().copy_from_slice(image);
+                return Ok(true);
+            }),
+            info => kbbail!(
+                ERRCODE_DATA_CORRUPTED,
+                "CSMvccRmgr::redo: unknown info. info={}",
+                info
+            ),
+        }
+    }
+
+    fn desc(&self, out: &mut String, hdr: &RecordHdr, data: &[u8]) {
+        let mut cursor = Cursor::new(data);
+        let (table, pageid, blk_rows) = match de_page_tag(&mut cursor) {
+            Ok(v) => v,
+            Err(err) => {
+                write!(out, "invalid page: {}", err).unwrap();
+                return;
+            }
+        };
+        let name = match hdr.info {
+            BUF_INIT => "BUF_INIT",
+            BUF_SET_PAGE_XMIN => "BUF_SET_PAGE_XMIN",
+            BUF_FPI => "BUF_FPI",
+            info => {
+                write!(out, "UNKNOWN info={}", info).unwrap();
+                return;
+            }
+        };
+        write!(
+            out,
+            "{} db={} table={} file={} blk={} blk_rows={}",
+            name, table.db, table.table, pageid.fileid, pageid.blkid, blk_rows
+        )
+        .unwrap();
+        if hdr.info != BUF_FPI {
+            match de_buf_xmin(&mut cursor, hdr.info) {
+                Ok((sidx, eidx, xid)) => write!(out, " rows={}..{} xid={}", sidx, eidx, xid),
+                Err(err) => write!(out, " invalid: {}", err),
+            }
+            .unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod record_ser_test {
+    use super::{
+        de_buf_xmin, de_page_tag, ser_buf_init, ser_buf_set_page_xmin, ser_page_tag, PageId,
+        BUF_INIT, BUF_SET_PAGE_XMIN,
+    };
+    use crate::access::sv::TableId;
+    use crate::access::wal::{self, finish_record, for_each_misaligned, parse_record, RmgrId};
+    use crate::utils::Xid;
+    use crate::{FileId, Oid};
+    use std::io::Cursor;
+
+    const TAG_BYTES: [u8; 20] = [
+        1, 0, 0, 0, 0x10, 0x27, 0, 0, 3, 0, 0, 0, 4, 0, 0, 0, 0, 2, 0, 0,
+    ];
+
+    fn tag() -> (TableId, PageId, u32) {
+        let table = TableId {
+            db: Oid::new(1).unwrap(),
+            table: Oid::new(10000).unwrap(),
+        };
+        let pageid = PageId {
+            fileid: FileId::new(3).unwrap(),
+            blkid: 4,
+        };
+        return (table, pageid, 512);
+    }
+
+    fn check_xmin(data: &[u8], info: u8, expected: (u32, u32, Xid)) {
+        for_each_misaligned(data, |d| {
+            let mut cursor = Cursor::new(d);
+            assert_eq!(de_page_tag(&mut cursor).unwrap(), tag());
+            assert_eq!(de_buf_xmin(&mut cursor, info).unwrap(), expected);
+            assert_eq!(cursor.position() as usize, d.len());
+        });
+    }
+
+    #[test]
+    fn buf_init() {
+        let xid = Xid::new(0x0102030405060708).unwrap();
+        let (table, pageid, blk_rows) = tag();
+        let mut walrec = wal::start_record_raw(&[]);
+        ser_page_tag(&mut walrec, table, pageid, blk_rows);
+        ser_buf_init(&mut walrec, 33, xid);
+        finish_record(&mut walrec, RmgrId::CSMvcc, BUF_INIT, None);
+        let (h, data) = parse_record(&walrec);
+        assert_eq!(h.info, BUF_INIT);
+        assert_eq!(&data[..20], TAG_BYTES);
+        assert_eq!(&data[20..], [33, 0, 0, 0, 8, 7, 6, 5, 4, 3, 2, 1]);
+        check_xmin(data, BUF_INIT, (0, 33, xid));
+    }
+
+    #[test]
+    fn buf_set_page_xmin() {
+        let xid = Xid::new(0x20181218).unwrap();
+        let (table, pageid, blk_rows) = tag();
+        let mut walrec = wal::start_record_raw(&[]);
+        ser_page_tag(&mut walrec, table, pageid, blk_rows);
+        ser_buf_set_page_xmin(&mut walrec, 3, 77, xid);
+        finish_record(&mut walrec, RmgrId::CSMvcc, BUF_SET_PAGE_XMIN, None);
+        let (h, data) = parse_record(&walrec);
+        assert_eq!(h.info, BUF_SET_PAGE_XMIN);
+        assert_eq!(&data[..20], TAG_BYTES);
+        assert_eq!(
+            &data[20..],
+            [3, 0, 0, 0, 77, 0, 0, 0, 0x18, 0x12, 0x18, 0x20, 0, 0, 0, 0]
+        );
+        check_xmin(data, BUF_SET_PAGE_XMIN, (3, 77, xid));
+    }
 }
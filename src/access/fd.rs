@@ -10,11 +10,15 @@
 // limitations under the License.
 use crate::guc::{self, GucState};
 use crate::utils::{SessionState, WorkerState};
+use anyhow::ensure;
 use lru::LruCache;
+use nix::sys::mman::{mmap, munmap, MapFlags, ProtFlags};
 use std::cell::RefCell;
 use std::convert::From;
 use std::fs::{File, OpenOptions};
 use std::io;
+use std::os::unix::io::AsRawFd;
+use std::ptr::null_mut;
 
 type FDCacheT = LruCache<String, File>;
 
@@ -96,3 +100,49 @@ impl WorkerExt for WorkerState {
         do_resize_fdcache(&self.gucstate);
     }
 }
+
+// The read-only mapping of the first len bytes of the file. The file must not be truncated
+// while it is mapped, otherwise the access to the mapping raises SIGBUS.
+pub struct MmapFile {
+    addr: *mut nix::libc::c_void,
+    len: usize,
+}
+
+impl MmapFile {
+    pub fn new(path: &String, len: usize) -> anyhow::Result<Self> {
+        ensure!(len > 0, "MmapFile::new: empty mapping. path={}", path);
+        return use_file(path, |file| -> anyhow::Result<Self> {
+            let filelen = file.metadata()?.len();
+            ensure!(
+                filelen >= len as u64,
+                "MmapFile::new: the file is too short. path={} filelen={} len={}",
+                path,
+                filelen,
+                len
+            );
+            let addr = unsafe {
+                mmap(
+                    null_mut(),
+                    len,
+                    ProtFlags::PROT_READ,
+                    MapFlags::MAP_SHARED,
+                    file.as_raw_fd(),
+                    0,
+                )?
+            };
+            Ok(Self { addr, len })
+        });
+    }
+
+    pub fn data(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.addr as *const u8, self.len) }
+    }
+}
+
+impl Drop for MmapFile {
+    fn drop(&mut self) {
+        unsafe {
+            munmap(self.addr, self.len).unwrap();
+        }
+    }
+}
@@ -12,21 +12,49 @@
 // WHY lmgr is placed in src/backend/storage/? Is lmgr a storage?
 use crate::catalog::is_shared_rel;
 use crate::utils::SessionState;
-use crate::{Oid, NSRELID};
+use crate::{kbbail, Oid, NSRELID, PROCRELID, TYPERELID};
 use std::collections::HashMap;
 use std::sync::{Condvar, Mutex, RwLock};
 
-#[derive(Clone, Copy, Eq, Hash, PartialEq)]
+// The order of the tags is the declared lock ordering checked by check_order, the namespaces come
+// before the objects such as the types they contain, and the objects come before the relations
+// using them, e.g. the types of the columns. The tags of the same kind are ordered by their oids.
+#[derive(Clone, Copy, Eq, Hash, PartialEq, Debug)]
 pub enum LockTag {
-    Relation {
-        dboid: Option<Oid>,
-        reloid: Oid,
-    },
     Object {
         dboid: Oid,
         clsoid: Oid,
         objoid: Oid,
     },
+    Relation {
+        dboid: Option<Oid>,
+        reloid: Oid,
+    },
+}
+
+impl LockTag {
+    fn order_key(&self) -> (u8, Option<Oid>, Oid, Oid) {
+        match *self {
+            LockTag::Object {
+                dboid,
+                clsoid,
+                objoid,
+            } => ((clsoid != NSRELID) as u8, Some(dboid), clsoid, objoid),
+            LockTag::Relation { dboid, reloid } => (2, dboid, reloid, reloid),
+        }
+    }
+}
+
+impl Ord for LockTag {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.order_key().cmp(&other.order_key())
+    }
+}
+
+impl PartialOrd for LockTag {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
 }
 
 type LockMask = u32;
@@ -106,22 +134,31 @@ const fn conflict_modes(mode: LockMode) -> LockMask {
     LOCKCONFLICT[mode as usize]
 }
 
-// const LOCKMODENAMES: [&'static str; LOCKMODESNUM] = [
-//     "INVALID",
-//     "AccessShareLock",
-//     "RowShareLock",
-//     "RowExclusiveLock",
-//     "ShareUpdateExclusiveLock",
-//     "ShareLock",
-//     "ShareRowExclusiveLock",
-//     "ExclusiveLock",
-//     "AccessExclusiveLock"
-// ];
+const LOCKMODENAMES: [&str; LOCKMODESNUM] = [
+    "INVALID",
+    "AccessShareLock",
+    "RowShareLock",
+    "RowExclusiveLock",
+    "ShareUpdateExclusiveLock",
+    "ShareLock",
+    "ShareRowExclusiveLock",
+    "ExclusiveLock",
+    "AccessExclusiveLock",
+];
+
+struct Waiter {
+    id: u64,
+    mode: LockMode,
+    // The modes already held by the waiter, like PGPROC::heldLocks.
+    held: LockMask,
+}
 
 #[derive(Default)]
 struct Lock {
     grant: LockMask,
-    wait: LockMask,
+    // The waiting requests in the order they are granted, like the wait queue in PROC_QUEUE.
+    waiters: Vec<Waiter>,
+    nextwaiter: u64,
     req: [u32; LOCKMODESNUM],
     nreq: u32,
     granted: [u32; LOCKMODESNUM],
@@ -134,14 +171,17 @@ impl Lock {
         self.nreq += 1;
     }
 
+    fn unreq(&mut self, mode: LockMode) {
+        debug_assert!(self.req[mode as usize] > 0 && self.nreq > 0);
+        self.req[mode as usize] -= 1;
+        self.nreq -= 1;
+    }
+
     fn grant(&mut self, mode: LockMode) {
         let modeidx = mode as usize;
         self.granted[modeidx] += 1;
         self.ngranted += 1;
         self.grant |= lockbit_on(mode);
-        if self.granted[modeidx] >= self.req[modeidx] {
-            self.wait &= lockbit_off(mode);
-        }
         debug_assert!(self.ngranted > 0 && self.granted[modeidx] > 0);
         debug_assert!(self.ngranted <= self.nreq);
     }
@@ -157,7 +197,57 @@ impl Lock {
         if self.granted[modeidx] == 0 {
             self.grant &= lockbit_off(mode);
         }
-        return (conflict_modes(mode) & self.wait) != 0;
+        return (conflict_modes(mode) & self.wait_mask()) != 0;
+    }
+
+    fn wait_mask(&self) -> LockMask {
+        let mut mask = 0;
+        for waiter in &self.waiters {
+            mask |= lockbit_on(waiter.mode);
+        }
+        return mask;
+    }
+
+    // ProcSleep, a new request waits behind all waiters, except that it goes ahead of the first
+    // waiter conflicting with the locks we already held, the waiter is waiting for us anyway.
+    // If we also conflict with the locks held by the waiter, e.g. both of us upgrade the lock
+    // from AccessShare to AccessExclusive, we would wait for each other.
+    fn wait_pos(&self, held: LockMask, mode: LockMode) -> anyhow::Result<usize> {
+        let pos = self.waiters.iter().position(|waiter| {
+            return (conflict_modes(waiter.mode) & held) != 0;
+        });
+        if let Some(pos) = pos {
+            if (conflict_modes(mode) & self.waiters[pos].held) != 0 {
+                kbbail!(
+                    ERRCODE_T_R_DEADLOCK_DETECTED,
+                    "deadlock detected. upgrading to {:?} waits for {:?} which waits for us",
+                    mode,
+                    self.waiters[pos].mode
+                );
+            }
+        }
+        return Ok(pos.unwrap_or(self.waiters.len()));
+    }
+
+    // The request at pos can not be granted before the requests ahead of it.
+    fn conflict(&self, pos: usize, localcnts: &[u32; LOCKMODESNUM], mode: LockMode) -> bool {
+        let confmodes = conflict_modes(mode);
+        let mut ahead = self.waiters[..pos].iter();
+        if ahead.any(|waiter| (confmodes & lockbit_on(waiter.mode)) != 0) {
+            return true;
+        }
+        return check_conflict(self, localcnts, mode);
+    }
+
+    fn enqueue(&mut self, pos: usize, mode: LockMode, held: LockMask) -> u64 {
+        let id = self.nextwaiter;
+        self.nextwaiter += 1;
+        self.waiters.insert(pos, Waiter { id, mode, held });
+        return id;
+    }
+
+    fn waiter_pos(&self, id: u64) -> usize {
+        return self.waiters.iter().position(|w| w.id == id).unwrap();
     }
 
     fn new(mode: LockMode) -> Self {
@@ -172,6 +262,15 @@ struct LockState {
     cv: Condvar,
 }
 
+// A row of pg_locks, but per lock instead of per holder.
+pub struct LockStat {
+    pub tag: LockTag,
+    // The granted modes and the number of holders of each mode.
+    pub granted: Vec<(&'static str, u32)>,
+    // The modes of the waiters in the order they are granted.
+    pub waiters: Vec<&'static str>,
+}
+
 pub struct GlobalStateExt {
     lm: RwLock<HashMap<LockTag, Box<LockState>>>,
 }
@@ -183,6 +282,33 @@ impl GlobalStateExt {
         }
     }
 
+    // GetLockStatusData, only for diagnosing the lock table.
+    pub fn dump(&self) -> Vec<LockStat> {
+        let hm = self.lm.read().unwrap();
+        let mut stats = Vec::with_capacity(hm.len());
+        for (tag, state) in hm.iter() {
+            let lock = state.lock.lock().unwrap();
+            let granted = lock
+                .granted
+                .iter()
+                .enumerate()
+                .filter(|(_, &n)| n > 0)
+                .map(|(mode, &n)| (LOCKMODENAMES[mode], n))
+                .collect();
+            let waiters = lock
+                .waiters
+                .iter()
+                .map(|w| LOCKMODENAMES[w.mode as usize])
+                .collect();
+            stats.push(LockStat {
+                tag: *tag,
+                granted,
+                waiters,
+            });
+        }
+        return stats;
+    }
+
     fn p2r(&self, l: *const LockState) -> &LockState {
         unsafe { &*l }
     }
@@ -228,22 +354,46 @@ struct LocalLock<'a> {
 
 pub struct SessionStateExt<'a> {
     lm: HashMap<LockTag, [LocalLock<'a>; LOCKMODESNUM]>,
+    // Assert in debug builds that the new lock is after all locks held by the session in the
+    // order of LockTag, so the sessions following the same order never deadlock. It is on by
+    // default in debug builds, the tests acquiring the locks out of order on purpose turn it off.
+    pub check_order: bool,
 }
 
 impl<'a> SessionStateExt<'a> {
     pub fn new() -> Self {
-        Self { lm: HashMap::new() }
+        Self {
+            lm: HashMap::new(),
+            check_order: cfg!(debug_assertions),
+        }
+    }
+
+    // The lock already held can be acquired again in any mode.
+    fn assert_order(&self, tag: &LockTag) {
+        if self.lm.contains_key(tag) {
+            return;
+        }
+        if let Some(last) = self.lm.keys().max() {
+            debug_assert!(
+                last < tag,
+                "lock acquired out of order. tag={:?} held={:?}",
+                tag,
+                last
+            );
+        }
     }
 }
 
 pub trait SessionExt {
-    fn lock_acquire(&mut self, tag: &LockTag, mode: LockMode);
+    fn lock_acquire(&mut self, tag: &LockTag, mode: LockMode) -> anyhow::Result<()>;
     fn lock_release(&mut self, tag: &LockTag, mode: LockMode);
     fn lock_release_all(&mut self);
     // LockDatabaseObject
-    fn lock_dbobj(&mut self, cls: Oid, obj: Oid, mode: LockMode);
-    fn lock_ns(&mut self, ns: Oid, mode: LockMode);
-    fn lock_rel(&mut self, rel: Oid, mode: LockMode);
+    fn lock_dbobj(&mut self, cls: Oid, obj: Oid, mode: LockMode) -> anyhow::Result<()>;
+    fn lock_ns(&mut self, ns: Oid, mode: LockMode) -> anyhow::Result<()>;
+    fn lock_type(&mut self, typ: Oid, mode: LockMode) -> anyhow::Result<()>;
+    fn lock_proc(&mut self, proc: Oid, mode: LockMode) -> anyhow::Result<()>;
+    fn lock_rel(&mut self, rel: Oid, mode: LockMode) -> anyhow::Result<()>;
     fn unlock_rel(&mut self, rel: Oid, mode: LockMode);
 }
 
@@ -282,7 +432,7 @@ fn check_conflict(lock: &Lock, localcnts: &[u32; LOCKMODESNUM], mode: LockMode)
     let mut confnum = 0;
     macro_rules! assign {
         ($lockmode: ident) => {
-            confnum += if (confmodes & LockMode::$lockmode as u32) == 0 {
+            confnum += if (confmodes & lockbit_on(LockMode::$lockmode)) == 0 {
                 0
             } else {
                 lock.granted[LockMode::$lockmode as usize] - localcnts[LockMode::$lockmode as usize]
@@ -300,6 +450,16 @@ fn check_conflict(lock: &Lock, localcnts: &[u32; LOCKMODESNUM], mode: LockMode)
     return confnum > 0;
 }
 
+fn held_mask(localcnts: &[u32; LOCKMODESNUM]) -> LockMask {
+    let mut held = 0;
+    for (mode, &cnt) in localcnts.iter().enumerate() {
+        if cnt > 0 {
+            held |= 1 << mode;
+        }
+    }
+    return held;
+}
+
 fn global_release(lock: &LockState, mode: LockMode) -> bool /* cleanup global */ {
     let mut l = lock.lock.lock().unwrap();
     if l.ungrant(mode) {
@@ -356,12 +516,15 @@ fn get_rel_locktag(sess: &SessionState, reloid: Oid) -> LockTag {
 }
 
 impl SessionExt for SessionState {
-    fn lock_acquire(&mut self, tag: &LockTag, mode: LockMode) {
+    fn lock_acquire(&mut self, tag: &LockTag, mode: LockMode) -> anyhow::Result<()> {
+        if cfg!(debug_assertions) && self.lmgrs.check_order {
+            self.lmgrs.assert_order(tag);
+        }
         let (locallocks, localcnts) = if let Some(locallocks) = self.lmgrs.lm.get_mut(&tag) {
             if let Some(localcnts) = local_acquire(locallocks, mode) {
                 (Some(locallocks), localcnts)
             } else {
-                return;
+                return Ok(());
             }
         } else {
             (None, [0; LOCKMODESNUM])
@@ -369,15 +532,27 @@ impl SessionExt for SessionState {
         let lockstate = self.lmgrg.setup_lock(tag, mode);
         {
             let mut lock = lockstate.lock.lock().unwrap();
-            loop {
-                if !check_conflict(&lock, &localcnts, mode) {
-                    lock.grant(mode);
-                    break;
-                } else {
-                    lock.wait |= lockbit_on(mode);
+            let held = held_mask(&localcnts);
+            let pos = match lock.wait_pos(held, mode) {
+                Ok(pos) => pos,
+                Err(err) => {
+                    // We still hold some modes, so the lock is not cleaned up.
+                    lock.unreq(mode);
+                    return Err(err);
+                }
+            };
+            if lock.conflict(pos, &localcnts, mode) {
+                let id = lock.enqueue(pos, mode, held);
+                loop {
                     lock = lockstate.cv.wait(lock).unwrap();
+                    let pos = lock.waiter_pos(id);
+                    if !lock.conflict(pos, &localcnts, mode) {
+                        lock.waiters.remove(pos);
+                        break;
+                    }
                 }
             }
+            lock.grant(mode);
         }
         if let Some(locallocks) = locallocks {
             let locallock = &mut locallocks[mode as usize];
@@ -391,7 +566,7 @@ impl SessionExt for SessionState {
             locallocks[mode as usize].n = 1;
             self.lmgrs.lm.insert(*tag, locallocks);
         }
-        return;
+        return Ok(());
     }
 
     fn lock_release(&mut self, tag: &LockTag, mode: LockMode) {
@@ -432,22 +607,30 @@ impl SessionExt for SessionState {
         return;
     }
 
-    fn lock_dbobj(&mut self, cls: Oid, obj: Oid, mode: LockMode) {
+    fn lock_dbobj(&mut self, cls: Oid, obj: Oid, mode: LockMode) -> anyhow::Result<()> {
         let locktag = LockTag::Object {
             dboid: self.reqdb,
             clsoid: cls,
             objoid: obj,
         };
-        self.lock_acquire(&locktag, mode);
+        self.lock_acquire(&locktag, mode)
+    }
+
+    fn lock_ns(&mut self, ns: Oid, mode: LockMode) -> anyhow::Result<()> {
+        self.lock_dbobj(NSRELID, ns, mode)
+    }
+
+    fn lock_type(&mut self, typ: Oid, mode: LockMode) -> anyhow::Result<()> {
+        self.lock_dbobj(TYPERELID, typ, mode)
     }
 
-    fn lock_ns(&mut self, ns: Oid, mode: LockMode) {
-        self.lock_dbobj(NSRELID, ns, mode);
+    fn lock_proc(&mut self, proc: Oid, mode: LockMode) -> anyhow::Result<()> {
+        self.lock_dbobj(PROCRELID, proc, mode)
     }
 
-    fn lock_rel(&mut self, rel: Oid, mode: LockMode) {
+    fn lock_rel(&mut self, rel: Oid, mode: LockMode) -> anyhow::Result<()> {
         let locktag = get_rel_locktag(self, rel);
-        self.lock_acquire(&locktag, mode);
+        self.lock_acquire(&locktag, mode)
     }
 
     fn unlock_rel(&mut self, rel: Oid, mode: LockMode) {
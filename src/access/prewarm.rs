@@ -0,0 +1,177 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::access::rel::getrel;
+use crate::access::sv::TableId;
+use crate::utils::sb::SlotState;
+use crate::utils::{persist, SessionState};
+use crate::{catalog, GlobalState, Oid, PREWARM_SESSID};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// Like autoprewarm.blocks of pg_prewarm, one line per resident key: the pool, db and table.
+const PREWARM_FILE: &str = "global/kb_prewarm";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Pool {
+    TabSupVer,
+    TabMVCC,
+}
+
+impl Pool {
+    fn name(self) -> &'static str {
+        match self {
+            Pool::TabSupVer => "tabsv",
+            Pool::TabMVCC => "tabmvcc",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Pool> {
+        match name {
+            "tabsv" => Some(Pool::TabSupVer),
+            "tabmvcc" => Some(Pool::TabMVCC),
+            _ => None,
+        }
+    }
+}
+
+fn ser_keys(keys: &[(Pool, TableId)]) -> String {
+    let mut out = String::new();
+    for (pool, k) in keys {
+        out.push_str(&format!("{} {} {}\n", pool.name(), k.db, k.table));
+    }
+    return out;
+}
+
+// The malformed line is skipped, the file may be written by an older version.
+fn parse_keys(d: &str) -> Vec<(Pool, TableId)> {
+    let mut keys = Vec::new();
+    for line in d.lines() {
+        let mut fields = line.split(' ');
+        let pool = fields.next().and_then(Pool::from_name);
+        let db = fields.next().and_then(|v| v.parse::<Oid>().ok());
+        let table = fields.next().and_then(|v| v.parse::<Oid>().ok());
+        match (pool, db, table, fields.next()) {
+            (Some(pool), Some(db), Some(table), None) => keys.push((pool, TableId { db, table })),
+            _ => log::warn!("prewarm: skip the malformed line. line={:?}", line),
+        }
+    }
+    return keys;
+}
+
+fn resident_keys(pool: Pool, slots: Vec<SlotState<TableId>>, keys: &mut Vec<(Pool, TableId)>) {
+    for slot in slots {
+        if slot.valid {
+            keys.push((pool, slot.k));
+        }
+    }
+}
+
+// apw_dump_now, returns the number of keys dumped.
+pub fn dump(state: &GlobalState) -> anyhow::Result<usize> {
+    let mut keys = Vec::new();
+    resident_keys(Pool::TabSupVer, state.tabsv.dump(), &mut keys);
+    resident_keys(Pool::TabMVCC, state.tabmvcc.dump(), &mut keys);
+    persist(PREWARM_FILE, ser_keys(&keys).as_bytes())?;
+    return Ok(keys.len());
+}
+
+fn load_key(sess: &mut SessionState, pool: Pool, k: &TableId) -> anyhow::Result<()> {
+    let rel = getrel(sess, k.table)?;
+    match pool {
+        Pool::TabSupVer => sess.tabsv.read(k, &rel.opt.enable_cs_wal).map(|_| ()),
+        Pool::TabMVCC => sess.tabmvcc.read(k, &rel.opt).map(|_| ()),
+    }
+}
+
+// apw_load_buffers, the keys are loaded database by database since the relation options
+// are in the catalog of the database. Returns the number of keys loaded.
+pub fn load(state: &GlobalState) -> anyhow::Result<usize> {
+    let d = match fs::read_to_string(PREWARM_FILE) {
+        Ok(d) => d,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err.into()),
+    };
+    let mut bydb: BTreeMap<Oid, Vec<(Pool, TableId)>> = BTreeMap::new();
+    for (pool, k) in parse_keys(&d) {
+        bydb.entry(k.db).or_default().push((pool, k));
+    }
+    let mut loaded = 0;
+    for (db, keys) in &bydb {
+        let mut sess = match catalog::get_database_by_oid(*db).and_then(|db| {
+            state
+                .clone()
+                .new_session(&db.datname, PREWARM_SESSID, Arc::default())
+        }) {
+            Ok(sess) => sess,
+            Err(err) => {
+                log::warn!("prewarm: skip the database. db={} err={:#}", db, err);
+                continue;
+            }
+        };
+        sess.init_thread_locals();
+        for (pool, k) in keys {
+            // The table may have been dropped after the dump.
+            match load_key(&mut sess, *pool, k) {
+                Ok(()) => loaded += 1,
+                Err(err) => log::warn!(
+                    "prewarm: skip the key. pool={} key={:?} err={:#}",
+                    pool.name(),
+                    k,
+                    err
+                ),
+            }
+        }
+    }
+    return Ok(loaded);
+}
+
+// autoprewarm_main, the keys are also dumped at the smart shutdown, but the server may crash,
+// so they are dumped every interval as well.
+pub fn start_dumper(state: GlobalState, interval: Duration) {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        if let Err(err) = dump(&state) {
+            log::warn!("prewarm: dump failed. err={:#}", err);
+        }
+    });
+}
+
+#[cfg(test)]
+mod prewarm_test {
+    use super::{parse_keys, ser_keys, Pool};
+    use crate::access::sv::TableId;
+    use crate::Oid;
+
+    #[test]
+    fn keys() {
+        let key = |db: u32, table: u32| TableId {
+            db: Oid::new(db).unwrap(),
+            table: Oid::new(table).unwrap(),
+        };
+        let keys = vec![
+            (Pool::TabSupVer, key(1, 33)),
+            (Pool::TabMVCC, key(1, 33)),
+            (Pool::TabSupVer, key(2, 44)),
+        ];
+        let d = ser_keys(&keys);
+        assert_eq!(d, "tabsv 1 33\ntabmvcc 1 33\ntabsv 2 44\n");
+        assert_eq!(parse_keys(&d), keys);
+        let d = "tabsv 1 33\ntabsv 0 33\nclog 1 33\ntabsv 1\ntabsv 1 33 7\n\ntabmvcc 2 44";
+        assert_eq!(
+            parse_keys(d),
+            vec![(Pool::TabSupVer, key(1, 33)), (Pool::TabMVCC, key(2, 44))]
+        );
+    }
+}
@@ -1,13 +1,28 @@
-use crate::access::wal::{Ctl, LocalWalStorage, Rmgr, WalReader, XlogRmgr};
-use crate::access::{wal, wal::RmgrId, xact, xact::XactRmgr};
-use crate::utils::{inc_xid, WorkerState, Xid};
-use crate::{guc, make_static, GlobalState, Oid, REDO_SESSID};
+use crate::access::csmvcc::CSMvccRmgr;
+use crate::access::wal::{
+    finish_record, new_ckpt_rec, Ckpt, Ctl, DbState, LocalWalStorage, Lsn, RecordHdr, Rmgr,
+    TimeLineID, WalReader, XlogInfo, XlogRmgr,
+};
+use crate::access::{
+    ckpt::remove_old_wal, sv::SVRmgr, wal, wal::RmgrId, walarchive::WalArchiver, xact,
+    xact::XactRmgr,
+};
+use crate::guc::GucState;
+use crate::replication::parse_lsn;
+use crate::replication::walreceiver::{walreceiver_main, WalReceiver};
+use crate::utils::{inc_xid, KBSystemTime, WorkerState, Xid};
+use crate::{guc, make_static, GlobalState, Oid, Progress, REDO_SESSID, REPLAY_SESSID};
 use anyhow::anyhow;
-use std::sync::atomic::AtomicU32;
+use chrono::{DateTime, Local, TimeZone};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::thread;
+use std::time::Duration;
 
 pub struct RedoState {
     nextxid: Xid,
     nextoid: Oid,
+    // The end lsn of the record being redone, just as EndRecPtr of XLogReaderState.
+    endlsn: Option<Lsn>,
     pub worker: WorkerState,
 }
 
@@ -16,10 +31,17 @@ impl RedoState {
         RedoState {
             nextxid,
             nextoid,
+            endlsn: None,
             worker,
         }
     }
 
+    // The lsn of the page changed by the record, the record is redone only if the page is older
+    // than it.
+    pub fn endlsn(&self) -> Lsn {
+        self.endlsn.unwrap()
+    }
+
     pub fn set_nextoid(&mut self, nextoid: Oid) {
         if self.nextoid < nextoid {
             self.nextoid = nextoid;
@@ -40,20 +62,110 @@ impl RedoState {
     }
 }
 
-pub fn redo(datadir: &str) -> anyhow::Result<GlobalState> {
-    let mut g = GlobalState::init(datadir);
-    let ctl = Ctl::load()?;
-    log::info!("start redo. ctl={:?}", ctl);
+struct Rmgrs {
+    xlog: XlogRmgr,
+    xact: XactRmgr,
+    sv: SVRmgr,
+    csmvcc: CSMvccRmgr,
+}
 
-    let mut walreader = WalReader::new(Box::new(LocalWalStorage::new()), ctl.ckptcpy.redo);
-    let session = g.clone().internal_session(REDO_SESSID).unwrap();
-    session.init_thread_locals();
-    let worker = session.new_worker();
-    let mut redo_state = RedoState::new(ctl.ckptcpy.nextxid, ctl.ckptcpy.nextoid, worker);
-    let mut xlogrmgr = XlogRmgr::new();
-    let mut xactrmgr = XactRmgr::new();
+impl Rmgrs {
+    fn new() -> Rmgrs {
+        Rmgrs {
+            xlog: XlogRmgr::new(),
+            xact: XactRmgr::new(),
+            sv: SVRmgr::new(),
+            csmvcc: CSMvccRmgr::new(),
+        }
+    }
+
+    fn redo(
+        &mut self,
+        h: &RecordHdr,
+        data: &[u8],
+        endlsn: Lsn,
+        state: &mut RedoState,
+    ) -> anyhow::Result<()> {
+        if let Some(x) = h.xid {
+            state.seen_xid(x);
+        }
+        state.endlsn = Some(endlsn);
+        match h.id {
+            RmgrId::Xlog => self.xlog.redo(h, data, state),
+            RmgrId::Xact => self.xact.redo(h, data, state),
+            RmgrId::SV => self.sv.redo(h, data, state),
+            RmgrId::CSMvcc => self.csmvcc.redo(h, data, state),
+        }
+    }
+}
+
+// recovery_target_lsn and recovery_target_time, the recovery stops before the first record
+// that starts at or after the lsn, or before the first commit or abort record whose
+// xact_endts is after the time.
+#[derive(Default)]
+pub struct RecoveryTarget {
+    pub lsn: Option<Lsn>,
+    // The seconds since UNIX_EPOCH, just as xact_endts.
+    pub time: Option<u64>,
+}
+
+fn parse_target_time(v: &str) -> anyhow::Result<u64> {
+    let secs = match DateTime::parse_from_rfc3339(v) {
+        Ok(t) => t.timestamp(),
+        Err(_) => match Local.datetime_from_str(v, "%Y-%m-%d %H:%M:%S") {
+            Ok(t) => t.timestamp(),
+            Err(e) => return Err(anyhow!("invalid recovery_target_time. val={} err={}", v, e)),
+        },
+    };
+    return Ok(secs.max(0) as u64);
+}
+
+impl RecoveryTarget {
+    pub fn new(gucstate: &GucState) -> anyhow::Result<RecoveryTarget> {
+        let mut target = RecoveryTarget::default();
+        let lsn = guc::get_str(gucstate, guc::RecoveryTargetLsn);
+        if !lsn.is_empty() {
+            target.lsn = Some(parse_lsn(lsn)?);
+        }
+        let time = guc::get_str(gucstate, guc::RecoveryTargetTime);
+        if !time.is_empty() {
+            target.time = Some(parse_target_time(time)?);
+        }
+        return Ok(target);
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.lsn.is_some() || self.time.is_some()
+    }
+
+    // recoveryStopsBefore(), startlsn is the start lsn of the record.
+    fn stops_before(&self, h: &RecordHdr, startlsn: Lsn, data: &[u8]) -> bool {
+        if let Some(lsn) = self.lsn {
+            if startlsn >= lsn {
+                return true;
+            }
+        }
+        if let (Some(time), RmgrId::Xact) = (self.time, h.id) {
+            return u64::from(xact::xact_rec_endts(data)) > time;
+        }
+        return false;
+    }
+}
+
+// Read the wal records and apply them until the end of wal or the recovery target, returns
+// true if the target is reached. apply is called with the end lsn of the record. The walreader is left at the end of the last applied record
+// as if the record at the target has never been read.
+pub fn replay_until<F>(
+    walreader: &mut WalReader,
+    target: &RecoveryTarget,
+    mut apply: F,
+) -> anyhow::Result<bool>
+where
+    F: FnMut(&RecordHdr, &[u8], Lsn) -> anyhow::Result<()>,
+{
     loop {
-        match walreader.read_record() {
+        let (readlsn, startlsn) = (walreader.readlsn, walreader.endlsn);
+        let (h, data) = match walreader.read_record() {
             Err(e) => {
                 log::info!(
                     "end redo because of failed read. endlsn={} endtli={} err={}",
@@ -61,20 +173,239 @@ pub fn redo(datadir: &str) -> anyhow::Result<GlobalState> {
                     walreader.endtli(),
                     e
                 );
-                break;
+                return Ok(false);
+            }
+            Ok(v) => v,
+        };
+        if target.stops_before(&h, startlsn, &data) {
+            log::info!(
+                "recovery stopping before the record. lsn={} xid={:?}",
+                startlsn,
+                h.xid
+            );
+            walreader.readlsn = readlsn;
+            walreader.endlsn = startlsn;
+            return Ok(true);
+        }
+        apply(&h, &data, walreader.endlsn)?;
+    }
+}
+
+// After the clean shutdown, the checkpoint record is the last record and nothing needs to be
+// redone, so only the checkpoint record is read to find the end of wal.
+fn skip_redo(walreader: &mut WalReader, ctl: &Ctl) -> anyhow::Result<()> {
+    let (h, _) = walreader.read_record()?;
+    let info = h.rmgr_info();
+    if !matches!(h.id, RmgrId::Xlog) || info != XlogInfo::CkptShutdown as u8 {
+        return Err(anyhow!(
+            "redo: unexpected record at the shutdown checkpoint. ckpt={} rmgr={:?} info={}",
+            ctl.ckpt,
+            h.id,
+            info
+        ));
+    }
+    log::info!(
+        "skip redo after the clean shutdown. ckpt={} endlsn={}",
+        ctl.ckpt,
+        walreader.endlsn
+    );
+    return Ok(());
+}
+
+// The timeline of the wal written after the redo. A new timeline is created once the recovery
+// target is reached, so the records after the target in the old timeline are never replayed
+// again, see LocalWalStorage::find(). PostgreSQL also does this at the promotion of the standby,
+// which is not supported yet.
+fn select_timeline(walreader: &WalReader, reached: bool) -> anyhow::Result<TimeLineID> {
+    let endtli = walreader.endtli();
+    if !reached {
+        return Ok(endtli);
+    }
+    let newtli = TimeLineID::new(walreader.storage.last_tli()?.get() + 1).unwrap();
+    log::info!(
+        "selected new timeline. endlsn={} endtli={} newtli={}",
+        walreader.endlsn,
+        endtli,
+        newtli
+    );
+    return Ok(newtli);
+}
+
+// The checkpoint at the start of the new timeline, the next recovery starts from it so the
+// records after the recovery target in the old timeline are never replayed again.
+fn end_of_recovery_ckpt(
+    g: &GlobalState,
+    prevtli: TimeLineID,
+    state: &RedoState,
+) -> anyhow::Result<()> {
+    let wal = g.wal.unwrap();
+    let redo = wal.start_ckpt();
+    state.worker.clog.flushall()?;
+    let ckpt = Ckpt {
+        redo,
+        curtli: wal.curtli(),
+        prevtli,
+        nextxid: state.nextxid,
+        nextoid: state.nextoid,
+        time: KBSystemTime::now(),
+    };
+    let mut rec = new_ckpt_rec(&ckpt);
+    finish_record(&mut rec, RmgrId::Xlog, XlogInfo::Ckpt as u8, None);
+    let reclen = rec.len() as u64;
+    let endlsn = wal.insert_record(rec);
+    wal.fsync(endlsn);
+    let ckptlsn = Lsn::new(endlsn.get() - reclen).unwrap();
+    log::info!(
+        "end of recovery checkpoint. ckpt={} curtli={} prevtli={}",
+        ckptlsn,
+        ckpt.curtli,
+        ckpt.prevtli
+    );
+    return Ctl::new(ckptlsn, ckpt).persist(wal.sync_method());
+}
+
+// Keep reading wal records and applying them until stop is set. Unlike the crash recovery,
+// the end of wal is not the end of replay, we will wait for the new records to be shipped
+// into kb_wal. replay is advanced to the end lsn of the last applied record.
+pub fn replay_loop<F>(
+    walreader: &mut WalReader,
+    replay: &Progress,
+    stop: &AtomicBool,
+    naptime: Duration,
+    mut apply: F,
+) -> anyhow::Result<()>
+where
+    F: FnMut(&RecordHdr, &[u8], Lsn) -> anyhow::Result<()>,
+{
+    while !stop.load(Ordering::Relaxed) {
+        match walreader.read_record() {
+            Err(e) => {
+                log::debug!(
+                    "replay: wait for new wal. endlsn={} err={}",
+                    walreader.endlsn,
+                    e
+                );
+                walreader.close_file();
+                thread::sleep(naptime);
             }
             Ok((h, data)) => {
-                if let Some(x) = h.xid {
-                    redo_state.seen_xid(x);
-                }
-                match h.id {
-                    RmgrId::Xlog => xlogrmgr.redo(&h, &data, &mut redo_state)?,
-                    RmgrId::Xact => xactrmgr.redo(&h, &data, &mut redo_state)?,
-                    _ => todo!(),
-                }
+                apply(&h, &data, walreader.endlsn)?;
+                replay.set(walreader.endlsn.get());
             }
         }
     }
+    Ok(())
+}
+
+fn start_walreceiver(g: &GlobalState, startlsn: Lsn) {
+    let conninfo = guc::get_str(&g.gucstate, guc::PrimaryConninfo).to_string();
+    if conninfo.is_empty() {
+        return;
+    }
+    let slot_name = guc::get_str(&g.gucstate, guc::PrimarySlotName).to_string();
+    let replay = g.replay_lsn;
+    let naptime = guc::get_int(&g.gucstate, guc::WalRetrieveRetryInterval) as u64;
+    let wal_file_max_size = guc::get_int(&g.gucstate, guc::WalFileMaxSize) as u64;
+    thread::spawn(move || {
+        let mut rcv = WalReceiver::new("kb_wal", startlsn, wal_file_max_size).unwrap();
+        rcv.replay = replay;
+        if !slot_name.is_empty() {
+            rcv.slot_name = Some(slot_name);
+        }
+        let stop = AtomicBool::new(false);
+        loop {
+            if let Err(e) = walreceiver_main(&conninfo, &mut rcv, &stop) {
+                log::error!("walreceiver failed. endlsn={} err={:#}", rcv.endlsn, e);
+            }
+            thread::sleep(Duration::from_millis(naptime));
+        }
+    });
+}
+
+fn start_standby(g: &GlobalState, walreader: &WalReader, nextxid: Xid, nextoid: Oid) {
+    let g = g.clone();
+    let replay = g.replay_lsn.unwrap();
+    let startlsn = walreader.endlsn;
+    let readlsn = walreader.readlsn;
+    thread::spawn(move || {
+        let mut walreader = WalReader::new(Box::new(LocalWalStorage::new()), startlsn);
+        walreader.readlsn = readlsn;
+        let xact = g.xact.unwrap();
+        let session = g.internal_session(REPLAY_SESSID).unwrap();
+        session.init_thread_locals();
+        let naptime = guc::get_int(&session.gucstate, guc::WalRetrieveRetryInterval) as u64;
+        let mut redo_state = RedoState::new(nextxid, nextoid, session.new_worker());
+        let mut rmgrs = Rmgrs::new();
+        let stop = AtomicBool::new(false);
+        log::info!("start replay. startlsn={}", startlsn);
+        let ret = replay_loop(
+            &mut walreader,
+            replay,
+            &stop,
+            Duration::from_millis(naptime),
+            |h, data, endlsn| {
+                rmgrs.redo(h, data, endlsn, &mut redo_state)?;
+                if let (RmgrId::Xact, Some(xid)) = (h.id, h.xid) {
+                    xact.replay_xid(xid);
+                    for subxid in xact::xact_rec_subxids(data) {
+                        xact.replay_xid(subxid);
+                    }
+                }
+                Ok(())
+            },
+        );
+        if let Err(e) = ret {
+            log::error!("replay failed. endlsn={} err={}", walreader.endlsn, e);
+            panic!("replay failed. err={}", e);
+        }
+    });
+}
+
+pub fn redo(datadir: &str) -> anyhow::Result<GlobalState> {
+    let mut g = GlobalState::init(datadir);
+    let mut ctl = Ctl::load()?;
+    log::info!("start redo. ctl={:?}", ctl);
+
+    let mut storage = LocalWalStorage::new();
+    storage.set_archiver(WalArchiver::from_guc(&g.gucstate)?);
+    storage.set_restore_command(guc::get_str(&g.gucstate, guc::RestoreCommand));
+    let mut walreader = WalReader::new(Box::new(storage), ctl.ckptcpy.redo);
+    let session = g.clone().internal_session(REDO_SESSID).unwrap();
+    session.init_thread_locals();
+    let worker = session.new_worker();
+    let mut redo_state = RedoState::new(ctl.ckptcpy.nextxid, ctl.ckptcpy.nextoid, worker);
+    let mut rmgrs = Rmgrs::new();
+    let standby_mode = guc::get_bool(&g.gucstate, guc::StandbyMode);
+    let mut target = RecoveryTarget::new(&g.gucstate)?;
+    if target.is_set() && standby_mode {
+        return Err(anyhow!(
+            "redo: recovery target is not supported in standby mode"
+        ));
+    }
+    if target.is_set() && ctl.ckptcpy.curtli != ctl.ckptcpy.prevtli {
+        // The checkpoint is written at the end of the recovery that has reached the target.
+        log::info!("ignore the recovery target reached by the previous recovery");
+        target = RecoveryTarget::default();
+    }
+    // The archive recovery and the standby always replay, just as PostgreSQL.
+    let clean = ctl.state == DbState::Shutdowned
+        && ctl.ckptcpy.redo == ctl.ckpt
+        && !standby_mode
+        && !target.is_set();
+    let reached = if clean {
+        skip_redo(&mut walreader, &ctl)?;
+        false
+    } else {
+        replay_until(&mut walreader, &target, |h, data, endlsn| {
+            rmgrs.redo(h, data, endlsn, &mut redo_state)
+        })?
+    };
+    if target.is_set() && !reached {
+        return Err(anyhow!(
+            "redo: recovery ended before the recovery target was reached. endlsn={}",
+            walreader.endlsn
+        ));
+    }
     if walreader.endlsn <= ctl.ckpt {
         return Err(anyhow!("redo: quit early. endlsn={}", walreader.endlsn));
     }
@@ -84,7 +415,19 @@ pub fn redo(datadir: &str) -> anyhow::Result<GlobalState> {
         redo_state.nextoid
     );
 
-    walreader.storage.recycle(walreader.endlsn)?;
+    let endtli = walreader.endtli();
+    if !standby_mode {
+        // The old timeline after the target is kept as a .partial file, see switch_timeline().
+        if !reached {
+            walreader.storage.recycle(endtli, walreader.endlsn)?;
+        }
+        remove_old_wal(
+            &g,
+            walreader.storage.as_mut(),
+            ctl.ckptcpy.redo,
+            walreader.endlsn,
+        )?;
+    }
     // Use CreateCheckPoint() instead.
     g.tabsv.flushall(true)?;
     g.tabmvcc.flushall(true)?;
@@ -94,17 +437,415 @@ pub fn redo(datadir: &str) -> anyhow::Result<GlobalState> {
         None => return Err(anyhow!("walreader.readlsn is None")),
         Some(r) => r,
     };
+    g.xact = Some(make_static(xact::GlobalStateExt::new(
+        redo_state.nextxid,
+        guc::get_int(&g.gucstate, guc::XidStopLimit),
+    )));
+    if standby_mode {
+        // The standby never writes wal, kb_wal is owned by the shipper.
+        g.replay_lsn = Some(make_static(Progress::new(walreader.endlsn.get())));
+        start_standby(&g, &walreader, redo_state.nextxid, redo_state.nextoid);
+        start_walreceiver(&g, walreader.endlsn);
+        return Ok(g);
+    }
+    let tli = select_timeline(&walreader, reached)?;
+    if reached {
+        walreader
+            .storage
+            .switch_timeline(endtli, tli, walreader.endlsn)?;
+    }
     g.wal = Some(wal::init(
-        walreader.endtli(),
+        tli,
         walreader.endlsn,
         Some(readlsn),
         ctl.ckptcpy.redo,
         &g.gucstate,
     )?);
-    g.xact = Some(make_static(xact::GlobalStateExt::new(
-        redo_state.nextxid,
-        guc::get_int(&g.gucstate, guc::XidStopLimit),
-    )));
     g.renew();
+    if ctl.state != DbState::InProduction {
+        // The wal is about to be written, the next start must redo until the next clean
+        // shutdown.
+        ctl.state = DbState::InProduction;
+        ctl.time = KBSystemTime::now();
+        ctl.persist(g.wal.unwrap().sync_method())?;
+    }
+    if reached {
+        end_of_recovery_ckpt(&g, endtli, &redo_state)?;
+    }
     Ok(g)
 }
+
+#[cfg(test)]
+mod replay_test {
+    use super::{parse_target_time, replay_loop, replay_until, select_timeline, RecoveryTarget};
+    use crate::access::wal::{
+        finish_record, is_wal, parse_wal_filename, serialize_records, start_record_raw,
+        wal_filename, GlobalStateExt, LocalWalStorage, Lsn, RmgrId, TimeLineID, WalReader,
+        WalStorage,
+    };
+    use crate::access::walarchive::{Compression, WalArchiver};
+    use crate::guc::SyncMethod;
+    use crate::utils::Xid;
+    use crate::Progress;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    fn new_records(n: usize) -> Vec<Vec<u8>> {
+        let mut recs = Vec::new();
+        for i in 0..n {
+            let mut rec = start_record_raw(&[i as u8; 64]);
+            finish_record(&mut rec, RmgrId::Xlog, 0x30, None);
+            recs.push(rec);
+        }
+        recs
+    }
+
+    #[test]
+    fn catch_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let dirpath = dir.path().to_str().unwrap().to_string();
+        let tli = TimeLineID::new(1).unwrap();
+        let startlsn = Lsn::new(20181218).unwrap();
+        let seg1 = serialize_records(startlsn, None, new_records(3));
+        let seg2lsn = Lsn::new(startlsn.get() + seg1.len() as u64).unwrap();
+        let lastlsn = Lsn::new(seg2lsn.get() - seg1.len() as u64 / 3).unwrap();
+        let seg2 = serialize_records(seg2lsn, Some(lastlsn), new_records(4));
+        let endlsn = seg2lsn.get() + seg2.len() as u64;
+
+        let replay: &'static Progress = Box::leak(Box::new(Progress::new(startlsn.get())));
+        let stop = Arc::new(AtomicBool::new(false));
+        let applied = Arc::new(Mutex::new(0));
+        let replayer = {
+            let stop = stop.clone();
+            let applied = applied.clone();
+            let dirpath = dirpath.clone();
+            thread::spawn(move || {
+                let storage = Box::new(LocalWalStorage::with_dir(&dirpath));
+                let mut walreader = WalReader::new(storage, startlsn);
+                replay_loop(
+                    &mut walreader,
+                    replay,
+                    &stop,
+                    Duration::from_millis(10),
+                    |_, data, _| {
+                        assert_eq!(data.len(), 64);
+                        *applied.lock().unwrap() += 1;
+                        Ok(())
+                    },
+                )
+                .unwrap();
+            })
+        };
+
+        // Ship the first segment in two pieces, the torn record must not be applied.
+        let seg1path = format!("{}/{}", dirpath, wal_filename(tli, startlsn));
+        let mut seg1file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&seg1path)
+            .unwrap();
+        let half = seg1.len() / 2;
+        seg1file.write_all(&seg1[..half]).unwrap();
+        replay.wait(startlsn.get() + seg1.len() as u64 / 3);
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(*applied.lock().unwrap(), 1);
+        seg1file.write_all(&seg1[half..]).unwrap();
+        replay.wait(seg2lsn.get());
+        assert_eq!(*applied.lock().unwrap(), 3);
+
+        let seg2path = format!("{}/{}", dirpath, wal_filename(tli, seg2lsn));
+        std::fs::write(&seg2path, &seg2).unwrap();
+        replay.wait(endlsn);
+        assert_eq!(*applied.lock().unwrap(), 7);
+        assert_eq!(replay.get(), endlsn);
+
+        stop.store(true, Ordering::Relaxed);
+        replayer.join().unwrap();
+    }
+
+    // The commit records of xids, the xact_endts of xid is xid * 100.
+    fn commit_records(xids: &[u64]) -> Vec<Vec<u8>> {
+        let mut recs = Vec::new();
+        for &xid in xids {
+            let mut rec = start_record_raw(&(xid * 100).to_le_bytes());
+            finish_record(&mut rec, RmgrId::Xact, 0x00, Xid::new(xid));
+            recs.push(rec);
+        }
+        recs
+    }
+
+    // Returns whether the target is reached and the xids applied.
+    fn replay(walreader: &mut WalReader, target: &RecoveryTarget) -> (bool, Vec<u64>) {
+        let mut applied = Vec::new();
+        let reached = replay_until(walreader, target, |h, _, _| {
+            applied.push(h.xid.unwrap().get());
+            Ok(())
+        })
+        .unwrap();
+        (reached, applied)
+    }
+
+    #[test]
+    fn restore_command() {
+        let waldir = tempfile::tempdir().unwrap();
+        let waldir = waldir.path().to_str().unwrap().to_string();
+        let archivedir = tempfile::tempdir().unwrap();
+        let archivedir = archivedir.path().to_str().unwrap().to_string();
+        let tli = TimeLineID::new(1).unwrap();
+        let startlsn = Lsn::new(20181218).unwrap();
+        let seg1 = serialize_records(startlsn, None, commit_records(&[1, 2]));
+        let seg2lsn = Lsn::new(startlsn.get() + seg1.len() as u64).unwrap();
+        let lastlsn = Lsn::new(seg2lsn.get() - seg1.len() as u64 / 2).unwrap();
+        let seg2 = serialize_records(seg2lsn, Some(lastlsn), commit_records(&[3, 4]));
+        let seg3lsn = Lsn::new(seg2lsn.get() + seg2.len() as u64).unwrap();
+        let lastlsn = Lsn::new(seg3lsn.get() - seg2.len() as u64 / 2).unwrap();
+        let seg3 = serialize_records(seg3lsn, Some(lastlsn), commit_records(&[5]));
+        std::fs::write(format!("{}/{}", waldir, wal_filename(tli, startlsn)), &seg1).unwrap();
+        for (lsn, seg) in &[(seg2lsn, &seg2), (seg3lsn, &seg3)] {
+            std::fs::write(format!("{}/{}", archivedir, wal_filename(tli, *lsn)), seg).unwrap();
+        }
+
+        // The recovery ends at the last record of the archive.
+        let mut storage = LocalWalStorage::with_dir(&waldir);
+        storage.set_restore_command(&format!("cp {}/%f %p", archivedir));
+        let mut walreader = WalReader::new(Box::new(storage), startlsn);
+        let all = RecoveryTarget::default();
+        assert_eq!(replay(&mut walreader, &all), (false, vec![1, 2, 3, 4, 5]));
+        assert_eq!(walreader.endlsn.get(), seg3lsn.get() + seg3.len() as u64);
+        for (lsn, seg) in &[(seg2lsn, &seg2), (seg3lsn, &seg3)] {
+            let restored = std::fs::read(format!("{}/{}", waldir, wal_filename(tli, *lsn)));
+            assert_eq!(&restored.unwrap(), *seg);
+        }
+        let walfiles = std::fs::read_dir(&waldir).unwrap().count();
+        assert_eq!(walfiles, 3);
+
+        // Without the restore_command, the recovery ends at the last local record.
+        std::fs::remove_file(format!("{}/{}", waldir, wal_filename(tli, seg3lsn))).unwrap();
+        let mut walreader = WalReader::new(Box::new(LocalWalStorage::with_dir(&waldir)), startlsn);
+        assert_eq!(replay(&mut walreader, &all), (false, vec![1, 2, 3, 4]));
+
+        // The compressed archive of archive_dir is used if the restore_command is empty.
+        let archiver = WalArchiver::new(&archivedir, Compression::Gzip);
+        let seg3name = wal_filename(tli, seg3lsn);
+        archiver
+            .archive(format!("{}/{}", archivedir, seg3name).as_ref())
+            .unwrap();
+        std::fs::remove_file(format!("{}/{}", archivedir, seg3name)).unwrap();
+        let mut storage = LocalWalStorage::with_dir(&waldir);
+        storage.set_archiver(Some(archiver));
+        let mut walreader = WalReader::new(Box::new(storage), startlsn);
+        assert_eq!(replay(&mut walreader, &all), (false, vec![1, 2, 3, 4, 5]));
+    }
+
+    #[test]
+    fn recovery_target() {
+        let dir = tempfile::tempdir().unwrap();
+        let dirpath = dir.path().to_str().unwrap().to_string();
+        let tli = TimeLineID::new(1).unwrap();
+        let startlsn = Lsn::new(20181218).unwrap();
+        let seg = serialize_records(startlsn, None, commit_records(&[1, 2, 3, 4, 5]));
+        std::fs::write(format!("{}/{}", dirpath, wal_filename(tli, startlsn)), &seg).unwrap();
+        let reclen = seg.len() as u64 / 5;
+        let reclsn = |idx: u64| Lsn::new(startlsn.get() + reclen * idx).unwrap();
+        let new_reader = || WalReader::new(Box::new(LocalWalStorage::with_dir(&dirpath)), startlsn);
+
+        // The record starts at the target is not applied.
+        let mut walreader = new_reader();
+        let target = RecoveryTarget {
+            lsn: Some(reclsn(2)),
+            time: None,
+        };
+        assert_eq!(replay(&mut walreader, &target), (true, vec![1, 2]));
+        assert_eq!(walreader.endlsn, reclsn(2));
+        assert_eq!(walreader.readlsn, Some(reclsn(1)));
+        let target = RecoveryTarget {
+            lsn: Some(Lsn::new(reclsn(2).get() + 1).unwrap()),
+            time: None,
+        };
+        assert_eq!(replay(&mut new_reader(), &target), (true, vec![1, 2, 3]));
+
+        // The commit at the target time is applied.
+        let time = parse_target_time("1970-01-01T00:05:00+00:00").unwrap();
+        assert_eq!(time, 300);
+        let target = RecoveryTarget {
+            lsn: None,
+            time: Some(time),
+        };
+        assert_eq!(replay(&mut new_reader(), &target), (true, vec![1, 2, 3]));
+        let target = RecoveryTarget {
+            lsn: None,
+            time: Some(250),
+        };
+        assert_eq!(replay(&mut new_reader(), &target), (true, vec![1, 2]));
+        assert!(parse_target_time("yesterday").is_err());
+
+        // The wal ends before the target.
+        let target = RecoveryTarget {
+            lsn: None,
+            time: Some(1000),
+        };
+        let mut walreader = new_reader();
+        assert_eq!(
+            replay(&mut walreader, &target),
+            (false, vec![1, 2, 3, 4, 5])
+        );
+        assert_eq!(walreader.endlsn, reclsn(5));
+    }
+
+    #[test]
+    fn new_timeline() {
+        let dir = tempfile::tempdir().unwrap();
+        let dirpath = dir.path().to_str().unwrap().to_string();
+        let tli1 = TimeLineID::new(1).unwrap();
+        let tli2 = TimeLineID::new(2).unwrap();
+        let startlsn = Lsn::new(20181218).unwrap();
+        let seg = serialize_records(startlsn, None, commit_records(&[1, 2, 3, 4, 5]));
+        std::fs::write(
+            format!("{}/{}", dirpath, wal_filename(tli1, startlsn)),
+            &seg,
+        )
+        .unwrap();
+        let new_reader = || WalReader::new(Box::new(LocalWalStorage::with_dir(&dirpath)), startlsn);
+
+        // Stop before xid 3, and the new timeline starts there.
+        let mut walreader = new_reader();
+        let target = RecoveryTarget {
+            lsn: None,
+            time: Some(200),
+        };
+        assert_eq!(replay(&mut walreader, &target), (true, vec![1, 2]));
+        assert_eq!(walreader.endtli(), tli1);
+        let storage = LocalWalStorage::with_dir(&dirpath);
+        assert_eq!(storage.last_tli().unwrap(), tli1);
+        let switchlsn = walreader.endlsn;
+        let seg2 = serialize_records(switchlsn, walreader.readlsn, commit_records(&[6, 7]));
+        let seg2path = format!("{}/{}", dirpath, wal_filename(tli2, switchlsn));
+        std::fs::write(&seg2path, &seg2).unwrap();
+        assert_eq!(storage.last_tli().unwrap(), tli2);
+
+        // The records after the target in the old timeline are never replayed again.
+        let mut walreader = new_reader();
+        let target = RecoveryTarget::default();
+        assert_eq!(replay(&mut walreader, &target), (false, vec![1, 2, 6, 7]));
+        assert_eq!(walreader.endtli(), tli2);
+        assert_eq!(walreader.endlsn.get(), switchlsn.get() + seg2.len() as u64);
+        assert_eq!(storage.find(startlsn).unwrap(), (tli1, startlsn));
+        assert_eq!(storage.find(switchlsn).unwrap(), (tli2, switchlsn));
+
+        // The recycle of the new timeline keeps the old one.
+        let mut storage = storage;
+        storage.recycle(tli2, switchlsn).unwrap();
+        assert!(!std::path::Path::new(&seg2path).exists());
+        assert_eq!(
+            replay(&mut new_reader(), &target),
+            (false, vec![1, 2, 3, 4, 5])
+        );
+    }
+
+    #[test]
+    fn pitr_timeline() {
+        let dir = tempfile::tempdir().unwrap();
+        let dirpath = dir.path().to_str().unwrap().to_string();
+        let tli1 = TimeLineID::new(1).unwrap();
+        let startlsn = Lsn::new(20181218).unwrap();
+        let seg = serialize_records(startlsn, None, commit_records(&[1, 2, 3, 4, 5]));
+        std::fs::write(
+            format!("{}/{}", dirpath, wal_filename(tli1, startlsn)),
+            &seg,
+        )
+        .unwrap();
+        let reclen = seg.len() as u64 / 5;
+        let new_reader = || WalReader::new(Box::new(LocalWalStorage::with_dir(&dirpath)), startlsn);
+
+        let mut walreader = new_reader();
+        let target = RecoveryTarget {
+            lsn: None,
+            time: Some(300),
+        };
+        assert_eq!(replay(&mut walreader, &target), (true, vec![1, 2, 3]));
+        let tli2 = select_timeline(&walreader, true).unwrap();
+        assert_eq!(tli2.get(), 2);
+        assert_eq!(select_timeline(&walreader, false).unwrap(), tli1);
+
+        // The records after the target are only kept in the .partial file of the old timeline.
+        let switchlsn = walreader.endlsn;
+        let oldname = wal_filename(tli1, startlsn);
+        walreader
+            .storage
+            .switch_timeline(tli1, tli2, switchlsn)
+            .unwrap();
+        let partialname = format!("{}.partial", oldname);
+        assert!(!is_wal(partialname.as_bytes()));
+        assert!(!std::path::Path::new(&format!("{}/{}", dirpath, oldname)).exists());
+        let partial = std::fs::read(format!("{}/{}", dirpath, partialname)).unwrap();
+        assert_eq!(partial, seg);
+        let prefix =
+            std::fs::read(format!("{}/{}", dirpath, wal_filename(tli2, startlsn))).unwrap();
+        assert_eq!(prefix.len() as u64, switchlsn.get() - startlsn.get());
+        assert_eq!(
+            replay(&mut new_reader(), &RecoveryTarget::default()),
+            (false, vec![1, 2, 3])
+        );
+
+        // The wal after the target is written in the new timeline, a file holds 2 records.
+        let wal = GlobalStateExt::new(
+            &dirpath,
+            tli2,
+            switchlsn,
+            walreader.readlsn,
+            switchlsn,
+            0,
+            reclen * 2,
+            SyncMethod::Fdatasync,
+        )
+        .unwrap();
+        let mut endlsn = switchlsn;
+        for rec in commit_records(&[6, 7, 8, 9, 10]) {
+            endlsn = wal.insert_record(rec);
+        }
+        wal.fsync(endlsn);
+        assert_eq!(wal.curtli(), tli2);
+        let mut newfiles: Vec<_> = std::fs::read_dir(&dirpath)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .filter(|name| is_wal(name.as_bytes()))
+            .map(|name| parse_wal_filename(name.as_bytes()))
+            .collect();
+        newfiles.sort();
+        let filelsn = |idx: u64| Lsn::new(switchlsn.get() + reclen * idx).unwrap();
+        assert_eq!(
+            newfiles,
+            [
+                (tli2, startlsn),
+                (tli2, switchlsn),
+                (tli2, filelsn(2)),
+                (tli2, filelsn(4))
+            ]
+        );
+
+        // The next recovery follows the new timeline, and the next target creates timeline 3.
+        let mut walreader = new_reader();
+        let all = RecoveryTarget::default();
+        assert_eq!(
+            replay(&mut walreader, &all),
+            (false, vec![1, 2, 3, 6, 7, 8, 9, 10])
+        );
+        assert_eq!(walreader.endtli(), tli2);
+        assert_eq!(walreader.endlsn, endlsn);
+        let mut walreader = new_reader();
+        let target = RecoveryTarget {
+            lsn: Some(filelsn(3)),
+            time: None,
+        };
+        assert_eq!(
+            replay(&mut walreader, &target),
+            (true, vec![1, 2, 3, 6, 7, 8])
+        );
+        assert_eq!(walreader.endtli(), tli2);
+        assert_eq!(select_timeline(&walreader, true).unwrap().get(), 3);
+    }
+}
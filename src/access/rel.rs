@@ -10,6 +10,8 @@
 // limitations under the License.
 use crate::access::TypeDesc;
 use crate::catalog::column_val;
+use crate::commands::tablecmds::input_single;
+use crate::datums::Datums;
 use crate::guc::{self, GucState};
 use crate::utils::AttrNumber;
 use crate::utils::SessionState;
@@ -25,7 +27,7 @@ pub struct RelOpt {
 }
 
 impl RelOpt {
-    fn new(gucstate: &GucState) -> Self {
+    pub fn new(gucstate: &GucState) -> Self {
         Self {
             mvcc_blk_rows: guc::get_int(gucstate, guc::MvccBlkRows) as u32,
             mvcc_buf_cap: guc::get_int(gucstate, guc::MvccBufCap) as u32,
@@ -42,6 +44,11 @@ pub struct Attr {
     pub typ: TypeDesc,
     pub notnull: bool,
     pub dropped: bool,
+    // The text passed to the type input function for the omitted column.
+    pub default: Option<String>,
+    // attmissingval, the value of the column for the rows written before it was added by
+    // ALTER TABLE ADD COLUMN, None means NULL.
+    pub missing: Option<Datums>,
 }
 
 #[derive(Clone, Debug)]
@@ -83,6 +90,7 @@ fn getrelopt(sess: &mut SessionState, table: Oid) -> anyhow::Result<RelOpt> {
 
 fn getrelattrs(sess: &mut SessionState, table: Oid) -> anyhow::Result<Vec<Attr>> {
     let mut attrs = Vec::<Attr>::new();
+    let mut missings = Vec::new();
     let mut nextattnum = 1;
     let sql = format!(
         "select * from kb_attribute where attrelid = {} order by attnum",
@@ -103,11 +111,17 @@ fn getrelattrs(sess: &mut SessionState, table: Oid) -> anyhow::Result<Vec<Attr>>
         let notnull = notnull != 0;
         let dropped: i32 = column_val(row, "attisdropped").unwrap().parse().unwrap();
         let dropped = dropped != 0;
+        let default = column_val(row, "attdefault").map(|v| v.to_string());
+        if let Some(missing) = column_val(row, "attmissingval") {
+            missings.push((attrs.len(), missing.to_string()));
+        }
         let attr = Attr {
             num,
             name,
             notnull,
             dropped,
+            default,
+            missing: None,
             typ: TypeDesc {
                 id: atttypid,
                 len: attlen,
@@ -118,6 +132,10 @@ fn getrelattrs(sess: &mut SessionState, table: Oid) -> anyhow::Result<Vec<Attr>>
         attrs.push(attr);
         return true;
     })?;
+    for (idx, missing) in missings {
+        let missing = input_single(sess, &attrs[idx].typ, &missing)?;
+        attrs[idx].missing = Some(missing);
+    }
     return Ok(attrs);
 }
 
@@ -147,6 +147,10 @@ impl Slru {
         return Ok(());
     }
 
+    pub fn flushall(&self) -> anyhow::Result<()> {
+        self.data.flushall(true)
+    }
+
     pub fn try_readonly_load<T, F>(&self, pageno: Pageno, cb: F) -> anyhow::Result<T>
     where
         F: FnOnce(&Buff) -> T,
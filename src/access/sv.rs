@@ -9,22 +9,31 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 use crate::access::ckpt::PendingFileOps;
-use crate::access::wal::{self, Lsn, RmgrId};
+use crate::access::cs;
+use crate::access::redo::RedoState;
+use crate::access::wal::{self, LocalWalStorage, Lsn, RecordHdr, Rmgr, RmgrId, WalReader};
 use crate::access::xact::SessionExt as xactSessionExt;
+use crate::utils::crashpoint::{crash_point, CrashPoint};
 use crate::utils::marc::{Destory, Marc};
 use crate::utils::sb::{self, SharedBuffer};
-use crate::utils::{persist, ser, SessionState};
-use crate::{FileId, Oid};
+use crate::utils::{persist, ser, sync_dir, AttrNumber, SessionState};
+use crate::{kbanyhow, kbbail, kbensure, FileId, Oid};
 use anyhow::ensure;
-use byteorder::{NativeEndian, ReadBytesExt};
+use byteorder::{ByteOrder, LittleEndian, NativeEndian, ReadBytesExt};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+use std::fmt::Write;
 use std::fs::{self, OpenOptions};
-use std::io::{Cursor, Seek, SeekFrom};
+use std::io::{Cursor, ErrorKind, Seek, SeekFrom, Write as _};
 use std::mem::{self, size_of, size_of_val};
+use std::path::Path;
 use std::sync::atomic::{AtomicBool, Ordering::Relaxed};
 
 struct L0File {
     meta: FileMeta,
     inuse: AtomicBool,
+    // meta has been written to the manifest or its journal.
+    journaled: AtomicBool,
 }
 
 impl std::clone::Clone for L0File {
@@ -32,6 +41,7 @@ impl std::clone::Clone for L0File {
         Self {
             meta: self.meta,
             inuse: AtomicBool::new(self.inuse.load(Relaxed)),
+            journaled: AtomicBool::new(self.journaled.load(Relaxed)),
         }
     }
 }
@@ -41,6 +51,15 @@ impl L0File {
         Self {
             meta: FileMeta::new(fileid, 0, 0),
             inuse: AtomicBool::new(true),
+            journaled: AtomicBool::new(false),
+        }
+    }
+
+    fn persisted(meta: FileMeta) -> Self {
+        Self {
+            meta,
+            inuse: AtomicBool::new(false),
+            journaled: AtomicBool::new(true),
         }
     }
 
@@ -62,6 +81,13 @@ impl L0File {
         let inuse = self.inuse.get_mut();
         debug_assert!(*inuse);
         *inuse = false;
+        *self.journaled.get_mut() = false;
+    }
+
+    // commit_use() in redo, where the file is never in use.
+    fn redo_use(&mut self, row: u32, len: u64) {
+        *self.inuse.get_mut() = true;
+        self.commit_use(row, len);
     }
 }
 
@@ -85,20 +111,31 @@ impl SVDestoryCtx {
     }
 }
 
+// The directory of the table in the named tablespace is a symlink to the directory under the
+// spclocation, just like pg_tblspc, see create_table_storage(). So all paths are built from here.
 fn get_dir(table: TableId) -> String {
     format!("base/{}/{}", table.db, table.table)
 }
 
+// The directory of the table under the spclocation, None for the default tablespace.
+fn get_spc_dir(table: TableId, spclocation: Option<&str>) -> Option<String> {
+    spclocation.map(|loc| format!("{}/{}/{}", loc, table.db, table.table))
+}
+
 pub fn get_datafile_path(table: TableId, fileid: FileId) -> String {
-    return format!("base/{}/{}/{}.d", table.db, table.table, fileid); // .data
+    return format!("{}/{}.d", get_dir(table), fileid); // .data
 }
 
 pub fn get_mvccfile_path(table: TableId, fileid: FileId) -> String {
-    return format!("base/{}/{}/{}.M", table.db, table.table, fileid); // .mvcc
+    return format!("{}/{}.M", get_dir(table), fileid); // .mvcc
 }
 
 pub fn get_minafest_path(db: Oid, table: Oid) -> String {
-    format!("base/{}/{}/manifest", db, table)
+    format!("{}/manifest", get_dir(TableId { db, table }))
+}
+
+pub fn get_journal_path(db: Oid, table: Oid) -> String {
+    format!("{}/manifest.journal", get_dir(TableId { db, table }))
 }
 
 impl Destory for L0File {
@@ -127,7 +164,6 @@ impl Destory for ImmFile {
 }
 
 // length of l0/l1/l2 may be 0.
-#[derive(Clone)]
 pub struct SupVer {
     l0: Vec<L0File>,
     l1: Vec<Marc<ImmFile>>,
@@ -135,9 +171,46 @@ pub struct SupVer {
     nextid: u32,
     lsn: Option<Lsn>,
     enable_cs_wal: bool,
+    // The L0 files have been removed by VACUUM FULL since the manifest was written, which can
+    // not be journaled, so the manifest is rewritten by the next store_manifest().
+    l0_removed: AtomicBool,
+}
+
+impl std::clone::Clone for SupVer {
+    fn clone(&self) -> Self {
+        Self {
+            l0: self.l0.clone(),
+            l1: self.l1.clone(),
+            l2: self.l2.clone(),
+            nextid: self.nextid,
+            lsn: self.lsn,
+            enable_cs_wal: self.enable_cs_wal,
+            l0_removed: AtomicBool::new(self.l0_removed.load(Relaxed)),
+        }
+    }
 }
 
 impl SupVer {
+    // The number of L0/L1/L2 files that have data.
+    pub fn files_to_scan(&self) -> [usize; 3] {
+        [
+            self.l0.iter().filter(|f| !f.meta.is_empty()).count(),
+            self.l1.iter().filter(|f| f.rownum > 0).count(),
+            self.l2.iter().filter(|f| f.rownum > 0).count(),
+        ]
+    }
+
+    // The L0/L1/L2 files that have data.
+    pub fn datafiles(&self) -> Vec<FileMeta> {
+        let l0files = self.l0.iter().map(|f| f.meta);
+        let immfiles = self.l1.iter().chain(self.l2.iter());
+        let immfiles = immfiles.map(|f| FileMeta {
+            sealed: true,
+            ..FileMeta::new(f.fileid, f.rownum, f.len)
+        });
+        l0files.chain(immfiles).filter(|f| !f.is_empty()).collect()
+    }
+
     fn find_l0(&self, fileid: FileId) -> Option<usize> {
         debug_assert!(is_sorted_by_fileid(&self.l0, |f| f.meta.fileid));
         match self.l0.binary_search_by_key(&fileid, |f| f.meta.fileid) {
@@ -161,11 +234,13 @@ impl Destory for SupVer {
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct FileMeta {
     pub len: u64,
     pub fileid: FileId,
     pub rownum: u32,
+    // It is a L1/L2 file, which never grows.
+    pub sealed: bool,
 }
 
 impl FileMeta {
@@ -174,6 +249,7 @@ impl FileMeta {
             fileid,
             rownum,
             len,
+            sealed: false,
         }
     }
 
@@ -258,10 +334,7 @@ fn read_manifest(path: &str, enable_cs_wal: bool) -> anyhow::Result<SupVer> {
         if fileid > newestid {
             newestid = fileid;
         }
-        L0File {
-            meta: FileMeta::new(FileId::new(fileid).unwrap(), rownum, filelen),
-            inuse: AtomicBool::new(false),
-        }
+        L0File::persisted(FileMeta::new(FileId::new(fileid).unwrap(), rownum, filelen))
     })?;
     let l1files = read_level_files(&mut cursor, |fileid, filelen, rownum| {
         if fileid > newestid {
@@ -292,6 +365,7 @@ fn read_manifest(path: &str, enable_cs_wal: bool) -> anyhow::Result<SupVer> {
         nextid,
         lsn,
         enable_cs_wal,
+        l0_removed: AtomicBool::new(false),
     });
 }
 
@@ -332,33 +406,227 @@ fn write_manifest(path: &str, sv: &SupVer) -> anyhow::Result<()> {
     return persist(path, &data);
 }
 
+// The size of the manifest written by write_manifest(), every file takes 16 bytes.
+fn manifest_size(sv: &SupVer) -> u64 {
+    let nfiles = sv.l0.len() + sv.l1.len() + sv.l2.len();
+    return (size_of_val(&INIT_MANIFEST_DAT) + nfiles * 16) as u64;
+}
+
+// The journal of the manifest is a sequence of records: bodylen u32, body, crc of body u32.
+// The body is lsn u64, nextid u32 and the L0 files changed since the previous record, only
+// the changes of L0 files are journaled.
+fn ser_journal_record(sv: &SupVer, files: &Vec<FileMeta>) -> Vec<u8> {
+    let mut body = Vec::new();
+    ser::ser_u64(&mut body, sv.lsn.map_or(0, |lsn| lsn.get()));
+    ser::ser_u32(&mut body, sv.nextid);
+    write_level_files(files, &mut body, |file: &FileMeta| {
+        (file.fileid.get(), file.len, file.rownum)
+    });
+    let mut record = Vec::with_capacity(body.len() + 2 * size_of::<u32>());
+    ser::ser_u32(&mut record, body.len() as u32);
+    record.extend_from_slice(&body);
+    ser::ser_u32(&mut record, crc32c::crc32c(&body));
+    return record;
+}
+
+// Returns the body of the record at the beginning of d, None if it is torn.
+fn journal_record(d: &[u8]) -> Option<&[u8]> {
+    if d.len() < size_of::<u32>() {
+        return None;
+    }
+    let (bodylen, d) = d.split_at(size_of::<u32>());
+    let bodylen = u32::from_ne_bytes(bodylen.try_into().unwrap()) as usize;
+    if d.len() < bodylen + size_of::<u32>() {
+        return None;
+    }
+    let (body, crc) = d.split_at(bodylen);
+    let crc = u32::from_ne_bytes(crc[..size_of::<u32>()].try_into().unwrap());
+    if crc != crc32c::crc32c(body) {
+        return None;
+    }
+    return Some(body);
+}
+
+fn apply_journal_record(body: &[u8], sv: &mut SupVer) -> anyhow::Result<()> {
+    let mut cursor = Cursor::new(body);
+    let lsn = Lsn::new(cursor.read_u64::<NativeEndian>()?);
+    let nextid = cursor.read_u32::<NativeEndian>()?;
+    // The record written before the manifest was rewritten.
+    if lsn <= sv.lsn {
+        return Ok(());
+    }
+    let files = read_level_files(&mut cursor, |fileid, filelen, rownum| {
+        FileMeta::new(FileId::new(fileid).unwrap(), rownum, filelen)
+    })?;
+    for meta in files {
+        match sv.l0.binary_search_by_key(&meta.fileid, |f| f.meta.fileid) {
+            Ok(idx) => sv.l0[idx].meta = meta,
+            Err(idx) => sv.l0.insert(idx, L0File::persisted(meta)),
+        }
+    }
+    sv.lsn = lsn;
+    sv.nextid = sv.nextid.max(nextid);
+    return Ok(());
+}
+
+// Apply the records of the journal to the SupVer read from the manifest. The torn record at
+// the end is truncated, so the records appended later can be read.
+fn replay_journal(path: &str, sv: &mut SupVer) -> anyhow::Result<()> {
+    let data = match fs::read(path) {
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        ret => ret?,
+    };
+    let mut off = 0;
+    while let Some(body) = journal_record(&data[off..]) {
+        apply_journal_record(body, sv)?;
+        off += body.len() + 2 * size_of::<u32>();
+    }
+    if off < data.len() {
+        log::warn!(
+            "replay_journal: truncate the torn record. path={} len={} validlen={}",
+            path,
+            data.len(),
+            off
+        );
+        OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .set_len(off as u64)?;
+    }
+    return Ok(());
+}
+
+// Check the checksums of the manifest and the journal of the table for VERIFY TABLE. The
+// journal has been replayed when the table is loaded, so the torn record is a corruption here.
+pub fn verify_manifest(table: TableId, corrupted: &mut Vec<anyhow::Error>) -> anyhow::Result<()> {
+    if let Err(err) = read_manifest(&get_minafest_path(table.db, table.table), false) {
+        corrupted.push(err);
+    }
+    let path = get_journal_path(table.db, table.table);
+    let data = match fs::read(&path) {
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        ret => ret?,
+    };
+    let mut off = 0;
+    while let Some(body) = journal_record(&data[off..]) {
+        off += body.len() + 2 * size_of::<u32>();
+    }
+    if off < data.len() {
+        corrupted.push(kbanyhow!(
+            ERRCODE_DATA_CORRUPTED,
+            "invalid record in manifest journal: path={} off={} len={}",
+            path,
+            off,
+            data.len()
+        ));
+    }
+    return Ok(());
+}
+
+// Append the changed L0 files to the journal instead of rewriting the whole manifest. The
+// manifest is rewritten and the journal is removed once the journal would outgrow the manifest,
+// so a change is written about twice on average. If we crash before the journal is removed,
+// its records are older than the manifest and skipped by apply_journal_record().
+fn store_manifest(manifestpath: &str, journalpath: &str, sv: &SupVer) -> anyhow::Result<()> {
+    let files = sv.l0.iter().filter(|f| !f.journaled.load(Relaxed));
+    let files: Vec<FileMeta> = files.map(|f| f.meta).collect();
+    let record = ser_journal_record(sv, &files);
+    let journallen = match fs::metadata(journalpath) {
+        Err(e) if e.kind() == ErrorKind::NotFound => 0,
+        ret => ret?.len(),
+    };
+    if sv.l0_removed.load(Relaxed) || journallen + record.len() as u64 > manifest_size(sv) {
+        write_manifest(manifestpath, sv)?;
+        match fs::remove_file(journalpath) {
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            ret => ret?,
+        }
+        sv.l0_removed.store(false, Relaxed);
+    } else {
+        let mut journal = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(journalpath)?;
+        journal.write_all(&record)?;
+        journal.sync_data()?;
+        if journallen == 0 {
+            sync_dir(Path::new(journalpath).parent().unwrap())?;
+        }
+    }
+    for l0file in &sv.l0 {
+        l0file.journaled.store(true, Relaxed);
+    }
+    return Ok(());
+}
+
 pub struct SVCommonData {
     pending_ops: &'static PendingFileOps,
     walapi: Option<&'static wal::GlobalStateExt>,
+    verify_files: bool,
 }
 
 impl SVCommonData {
     pub fn new(
         pending_ops: &'static PendingFileOps,
         walapi: Option<&'static wal::GlobalStateExt>,
+        verify_files: bool,
     ) -> Self {
         Self {
             pending_ops,
             walapi,
+            verify_files,
         }
     }
 }
 
+// A partially restored backup may have a manifest referencing the missing files, report them
+// when loading the manifest instead of failing later in a confusing way. The data file may be
+// longer than the manifest says because of the aborted writes.
+fn verify_files(table: TableId, sv: &SupVer) -> anyhow::Result<()> {
+    for file in sv.datafiles() {
+        let datapath = get_datafile_path(table, file.fileid);
+        let len = match fs::metadata(&datapath) {
+            Ok(meta) => meta.len(),
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                kbbail!(
+                    ERRCODE_DATA_CORRUPTED,
+                    "the file {} referenced by the manifest does not exist",
+                    datapath
+                );
+            }
+            Err(err) => return Err(err.into()),
+        };
+        kbensure!(
+            len >= file.len,
+            ERRCODE_DATA_CORRUPTED,
+            "the file {} is {} bytes, but the manifest expects {} bytes",
+            datapath,
+            len,
+            file.len
+        );
+        let mvccpath = get_mvccfile_path(table, file.fileid);
+        kbensure!(
+            Path::new(&mvccpath).exists(),
+            ERRCODE_DATA_CORRUPTED,
+            "the file {} referenced by the manifest does not exist",
+            mvccpath
+        );
+    }
+    return Ok(());
+}
+
 impl sb::Value for Marc<SupVer> {
     type CommonData = SVCommonData;
     type LoadCtx = bool;
     type K = TableId;
 
-    fn load(k: &Self::K, ctx2: &Self::LoadCtx, _ctx: &Self::CommonData) -> anyhow::Result<Self> {
-        Ok(Marc::new(read_manifest(
-            &get_minafest_path(k.db, k.table),
-            *ctx2,
-        )?))
+    fn load(k: &Self::K, ctx2: &Self::LoadCtx, ctx: &Self::CommonData) -> anyhow::Result<Self> {
+        let mut sv = read_manifest(&get_minafest_path(k.db, k.table), *ctx2)?;
+        replay_journal(&get_journal_path(k.db, k.table), &mut sv)?;
+        if ctx.verify_files {
+            verify_files(*k, &sv)?;
+        }
+        return Ok(Marc::new(sv));
     }
 
     fn store(&self, k: &Self::K, ctx: &Self::CommonData, _force: bool) -> anyhow::Result<()> {
@@ -369,7 +637,8 @@ impl sb::Value for Marc<SupVer> {
         }
 
         let manifestpath = get_minafest_path(k.db, k.table);
-        write_manifest(&manifestpath, self)?;
+        store_manifest(&manifestpath, &get_journal_path(k.db, k.table), self)?;
+        crash_point(CrashPoint::ManifestWrite);
 
         if self.enable_cs_wal {
             for l0file in &self.l0 {
@@ -416,22 +685,30 @@ fn alloc_from(output: &mut Vec<FileMeta>, num: usize, input: &[L0File]) -> bool
 }
 
 const CREATE_L0FILE: u8 = 0;
-#[repr(C, packed(1))]
-struct CreateL0File {
-    db: u32,
-    table: u32,
-    startid: u32,
-    endid: u32,
+// In little-endian: db u32, table u32, startid u32, endid u32.
+fn ser_create_l0file(out: &mut Vec<u8>, table: TableId, startid: u32, endid: u32) {
+    ser::ser_le_u32(out, table.db.get());
+    ser::ser_le_u32(out, table.table.get());
+    ser::ser_le_u32(out, startid);
+    ser::ser_le_u32(out, endid);
+}
+
+// Returns the table and [startid, endid) of the created files.
+fn get_create_l0file(d: &[u8]) -> (TableId, u32, u32) {
+    let table = get_create_table(d);
+    let startid = LittleEndian::read_u32(&d[8..]);
+    let endid = LittleEndian::read_u32(&d[12..]);
+    (table, startid, endid)
 }
 
 const UPDATE_L0FILE: u8 = 1;
 fn do_ser_update_l0file(out: &mut Vec<u8>, table: TableId, files: &[FileMeta]) {
-    ser::ser_u32(out, table.db.get());
-    ser::ser_u32(out, table.table.get());
+    ser::ser_le_u32(out, table.db.get());
+    ser::ser_le_u32(out, table.table.get());
     for file in files {
-        ser::ser_u32(out, file.fileid.get());
-        ser::ser_u32(out, file.rownum);
-        ser::ser_u64(out, file.len);
+        ser::ser_le_u32(out, file.fileid.get());
+        ser::ser_le_u32(out, file.rownum);
+        ser::ser_le_u64(out, file.len);
     }
     return;
 }
@@ -440,19 +717,358 @@ fn ser_update_l0file(out: &mut Vec<u8>, table: TableId, files: &[FileMeta]) {
     do_ser_update_l0file(out, table, files);
 }
 
+fn de_update_l0file(d: &[u8]) -> anyhow::Result<(TableId, Vec<FileMeta>)> {
+    let mut cursor = Cursor::new(d);
+    let db = cursor.read_u32::<LittleEndian>()?;
+    let table = cursor.read_u32::<LittleEndian>()?;
+    let table = TableId {
+        db: Oid::new(db).unwrap(),
+        table: Oid::new(table).unwrap(),
+    };
+    let mut files = Vec::new();
+    while (cursor.position() as usize) < d.len() {
+        let fileid = cursor.read_u32::<LittleEndian>()?;
+        let rownum = cursor.read_u32::<LittleEndian>()?;
+        let len = cursor.read_u64::<LittleEndian>()?;
+        files.push(FileMeta::new(FileId::new(fileid).unwrap(), rownum, len));
+    }
+    return Ok((table, files));
+}
+
+// Keep the info in the high 4 bits, see RecordHdr::rmgr_info().
+const CREATE_TABLE: u8 = 0x20;
+// In little-endian: db u32, table u32. CREATE_TABLE is followed by the spclocation of the named
+// tablespace.
+fn ser_create_table(out: &mut Vec<u8>, table: TableId) {
+    ser::ser_le_u32(out, table.db.get());
+    ser::ser_le_u32(out, table.table.get());
+}
+
+fn get_create_table(d: &[u8]) -> TableId {
+    TableId {
+        db: Oid::new(LittleEndian::read_u32(&d[0..])).unwrap(),
+        table: Oid::new(LittleEndian::read_u32(&d[4..])).unwrap(),
+    }
+}
+
+fn get_create_table_spclocation(d: &[u8]) -> anyhow::Result<Option<&str>> {
+    let loc = &d[8..];
+    if loc.is_empty() {
+        return Ok(None);
+    }
+    return Ok(Some(std::str::from_utf8(loc)?));
+}
+
+// Create the base directory and the initial manifest of the table. The existing manifest is kept,
+// so it can be called again in redo. The directory of the table in the named tablespace is
+// created under the spclocation and linked from base.
+pub fn create_table_storage(table: TableId, spclocation: Option<&str>) -> anyhow::Result<()> {
+    let dir = get_dir(table);
+    let spcdir = get_spc_dir(table, spclocation);
+    if let Some(spcdir) = &spcdir {
+        fs::create_dir_all(format!("{}/{}", spclocation.unwrap(), table.db))?;
+        match fs::create_dir(spcdir) {
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+            ret => ret?,
+        }
+        sync_dir(format!("{}/{}", spclocation.unwrap(), table.db))?;
+    }
+    let ret = match &spcdir {
+        None => fs::create_dir(&dir),
+        Some(spcdir) => std::os::unix::fs::symlink(spcdir, &dir),
+    };
+    match ret {
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+        ret => ret?,
+    }
+    sync_dir(format!("base/{}", table.db))?;
+    let path = get_minafest_path(table.db, table.table);
+    if !Path::new(&path).exists() {
+        persist(path, &INIT_MANIFEST_DAT)?;
+    }
+    return Ok(());
+}
+
+// smgrdounlinkall, the files of the dropped table are unlinked by the checkpointer, just as the
+// L0 files destroyed by the compaction.
+pub fn drop_table_storage(pending_ops: &PendingFileOps, table: TableId) -> anyhow::Result<()> {
+    for entry in fs::read_dir(get_dir(table))? {
+        pending_ops.unlink(entry?.path().to_string_lossy().into_owned());
+    }
+    return Ok(());
+}
+
+// log_smgrcreate, it must be called before create_table_storage().
+pub fn insert_create_table_wal(
+    sess: &mut SessionState,
+    table: TableId,
+    spclocation: Option<&str>,
+) -> Lsn {
+    let mut waldat = wal::start_record_raw(&[]);
+    ser_create_table(&mut waldat, table);
+    waldat.extend_from_slice(spclocation.unwrap_or("").as_bytes());
+    return sess.insert_record(RmgrId::SV, CREATE_TABLE, waldat);
+}
+
+const ADD_COLUMN: u8 = 0x30;
+const DROP_COLUMN: u8 = 0x40;
+const RENAME_TABLE: u8 = 0x50;
+const RENAME_COLUMN: u8 = 0x60;
+const DROP_TABLE: u8 = 0x70;
+// In little-endian: db u32, table u32, attnum u16.
+fn ser_alter_column(out: &mut Vec<u8>, table: TableId, attnum: AttrNumber) {
+    ser_create_table(out, table);
+    ser::ser_le_u16(out, attnum.get());
+}
+
+fn get_alter_column(d: &[u8]) -> (TableId, u16) {
+    (get_create_table(d), LittleEndian::read_u16(&d[8..]))
+}
+
+// ALTER TABLE ADD COLUMN only changes the catalog, the data files written before it are read
+// with the missing value of the column, so there is nothing to redo.
+pub fn insert_add_column_wal(sess: &mut SessionState, table: TableId, attnum: AttrNumber) -> Lsn {
+    let mut waldat = wal::start_record_raw(&[]);
+    ser_alter_column(&mut waldat, table, attnum);
+    return sess.insert_record(RmgrId::SV, ADD_COLUMN, waldat);
+}
+
+// ALTER TABLE DROP COLUMN only marks the column dropped in the catalog, the data of it is
+// still in the data files.
+pub fn insert_drop_column_wal(sess: &mut SessionState, table: TableId, attnum: AttrNumber) -> Lsn {
+    let mut waldat = wal::start_record_raw(&[]);
+    ser_alter_column(&mut waldat, table, attnum);
+    return sess.insert_record(RmgrId::SV, DROP_COLUMN, waldat);
+}
+
+// ALTER TABLE RENAME only changes the name in the catalog, the storage is named by the oid.
+pub fn insert_rename_table_wal(sess: &mut SessionState, table: TableId) -> Lsn {
+    let mut waldat = wal::start_record_raw(&[]);
+    ser_create_table(&mut waldat, table);
+    return sess.insert_record(RmgrId::SV, RENAME_TABLE, waldat);
+}
+
+// The catalog rows of the dropped table are removed, the storage is unlinked by the pending file
+// ops, see drop_table_storage(), so there is nothing to redo.
+pub fn insert_drop_table_wal(sess: &mut SessionState, table: TableId) -> Lsn {
+    let mut waldat = wal::start_record_raw(&[]);
+    ser_create_table(&mut waldat, table);
+    return sess.insert_record(RmgrId::SV, DROP_TABLE, waldat);
+}
+
+pub fn insert_rename_column_wal(
+    sess: &mut SessionState,
+    table: TableId,
+    attnum: AttrNumber,
+) -> Lsn {
+    let mut waldat = wal::start_record_raw(&[]);
+    ser_alter_column(&mut waldat, table, attnum);
+    return sess.insert_record(RmgrId::SV, RENAME_COLUMN, waldat);
+}
+
+const CREATE_TYPE: u8 = 0x80;
+// In little-endian: db u32, type u32.
+fn get_create_type(d: &[u8]) -> (Oid, Oid) {
+    (
+        Oid::new(LittleEndian::read_u32(&d[0..])).unwrap(),
+        Oid::new(LittleEndian::read_u32(&d[4..])).unwrap(),
+    )
+}
+
+// CREATE TYPE only inserts the row into kb_type, there is no storage to redo.
+pub fn insert_create_type_wal(sess: &mut SessionState, db: Oid, typoid: Oid) -> Lsn {
+    let mut waldat = wal::start_record_raw(&[]);
+    ser::ser_le_u32(&mut waldat, db.get());
+    ser::ser_le_u32(&mut waldat, typoid.get());
+    return sess.insert_record(RmgrId::SV, CREATE_TYPE, waldat);
+}
+
+// Apply f to the SupVer of the table if it is older than the record, the table dropped later
+// is skipped, just as XLogReadBufferForRedo() for the dropped relation.
+fn redo_sv(
+    state: &mut RedoState,
+    table: TableId,
+    f: impl FnOnce(&mut SupVer, &'static PendingFileOps),
+) -> anyhow::Result<()> {
+    if !Path::new(&get_minafest_path(table.db, table.table)).exists() {
+        log::debug!("redo_sv: skip the dropped table. table={:?}", table);
+        return Ok(());
+    }
+    let endlsn = state.endlsn();
+    let tabsv = state.worker.tabsv;
+    let pending_ops = tabsv.valctx.pending_ops;
+    let slot = tabsv.read(&table, &false)?;
+    let mut sv = slot.v.write().unwrap(); // lock guard
+    let sv: &mut Marc<SupVer> = sv.as_mut().unwrap();
+    if matches!(sv.lsn, Some(lsn) if lsn >= endlsn) {
+        return Ok(());
+    }
+    let sv = sv.make_mut(&SVDestoryCtx::new(table, pending_ops));
+    f(sv, pending_ops);
+    sv.lsn = Some(endlsn);
+    slot.mark_dirty();
+    return Ok(());
+}
+
+// alloc_new_l0files() and start_write() in redo, the files are created even if the manifest has
+// them, since the manifest may be stored before the files are created.
+fn redo_create_l0file(state: &mut RedoState, data: &[u8]) -> anyhow::Result<()> {
+    let (table, startid, endid) = get_create_l0file(data);
+    redo_sv(state, table, |sv, _| {
+        for fileid in sv.nextid.max(startid)..endid {
+            let mut l0file = L0File::new(FileId::new(fileid).unwrap());
+            *l0file.inuse.get_mut() = false;
+            sv.l0.push(l0file);
+        }
+        sv.nextid = sv.nextid.max(endid);
+    })?;
+    if !Path::new(&get_dir(table)).exists() {
+        return Ok(());
+    }
+    for fileid in startid..endid {
+        create_l0file(table, FileId::new(fileid).unwrap())?;
+    }
+    state.worker.tabsv.valctx.pending_ops.fsync(get_dir(table));
+    return Ok(());
+}
+
+// commit_write() and commit_rewrite() in redo. The empty file of the record is the old file of
+// VACUUM FULL if it has rows in the SupVer, see commit_rewrite(), the empty file committed by
+// commit_write() never has rows.
+fn redo_update_l0file(state: &mut RedoState, data: &[u8]) -> anyhow::Result<()> {
+    let (table, files) = de_update_l0file(data)?;
+    let mut missing = None;
+    redo_sv(state, table, |sv, pending_ops| {
+        for file in &files {
+            let idx = match sv.find_l0(file.fileid) {
+                Some(idx) => idx,
+                None => {
+                    missing = Some(file.fileid);
+                    continue;
+                }
+            };
+            if file.is_empty() && !sv.l0[idx].meta.is_empty() {
+                sv.l0.remove(idx);
+                *sv.l0_removed.get_mut() = true;
+                pending_ops.unlink(get_datafile_path(table, file.fileid));
+                pending_ops.unlink(get_mvccfile_path(table, file.fileid));
+            } else {
+                sv.l0[idx].redo_use(file.rownum, file.len);
+            }
+        }
+    })?;
+    if let Some(fileid) = missing {
+        kbbail!(
+            ERRCODE_DATA_CORRUPTED,
+            "redo: the L0 file {} of the table {:?} is not in the manifest",
+            fileid,
+            table
+        );
+    }
+    return Ok(());
+}
+
+pub struct SVRmgr {}
+
+impl SVRmgr {
+    pub fn new() -> SVRmgr {
+        SVRmgr {}
+    }
+}
+
+impl Rmgr for SVRmgr {
+    fn name(&self) -> &'static str {
+        "SV"
+    }
+
+    // The L0 infos are not in the high 4 bits, so the whole info is matched. XLR_COMPRESSED has
+    // been cleared by WalReader::read_record().
+    fn redo(&mut self, hdr: &RecordHdr, data: &[u8], state: &mut RedoState) -> anyhow::Result<()> {
+        match hdr.info {
+            CREATE_L0FILE => redo_create_l0file(state, data),
+            UPDATE_L0FILE => redo_update_l0file(state, data),
+            CREATE_TABLE => {
+                let spclocation = get_create_table_spclocation(data)?;
+                create_table_storage(get_create_table(data), spclocation)
+            }
+            ADD_COLUMN | DROP_COLUMN | RENAME_TABLE | RENAME_COLUMN | DROP_TABLE | CREATE_TYPE => {
+                Ok(())
+            }
+            info => kbbail!(
+                ERRCODE_DATA_CORRUPTED,
+                "SVRmgr::redo: unknown info. info={}",
+                info
+            ),
+        }
+    }
+
+    fn desc(&self, out: &mut String, hdr: &RecordHdr, data: &[u8]) {
+        match hdr.info {
+            CREATE_L0FILE => {
+                let (table, startid, endid) = get_create_l0file(data);
+                write!(
+                    out,
+                    "CREATE_L0FILE db={} table={} files={}..{}",
+                    table.db, table.table, startid, endid
+                )
+                .unwrap();
+            }
+            UPDATE_L0FILE => match de_update_l0file(data) {
+                Ok((table, files)) => {
+                    write!(out, "UPDATE_L0FILE db={} table={}", table.db, table.table).unwrap();
+                    for file in files {
+                        write!(out, " file={}:{}:{}", file.fileid, file.rownum, file.len).unwrap();
+                    }
+                }
+                Err(err) => write!(out, "UPDATE_L0FILE invalid: {}", err).unwrap(),
+            },
+            CREATE_TABLE => {
+                let table = get_create_table(data);
+                write!(out, "CREATE_TABLE db={} table={}", table.db, table.table).unwrap();
+                if let Ok(Some(loc)) = get_create_table_spclocation(data) {
+                    write!(out, " spclocation={}", loc).unwrap();
+                }
+            }
+            RENAME_TABLE | DROP_TABLE => {
+                let table = get_create_table(data);
+                let name = if hdr.rmgr_info() == RENAME_TABLE {
+                    "RENAME_TABLE"
+                } else {
+                    "DROP_TABLE"
+                };
+                write!(out, "{} db={} table={}", name, table.db, table.table).unwrap();
+            }
+            ADD_COLUMN | DROP_COLUMN | RENAME_COLUMN => {
+                let (table, attnum) = get_alter_column(data);
+                let name = match hdr.rmgr_info() {
+                    ADD_COLUMN => "ADD_COLUMN",
+                    DROP_COLUMN => "DROP_COLUMN",
+                    _ => "RENAME_COLUMN",
+                };
+                write!(
+                    out,
+                    "{} db={} table={} attnum={}",
+                    name, table.db, table.table, attnum
+                )
+                .unwrap();
+            }
+            CREATE_TYPE => {
+                let (db, typoid) = get_create_type(data);
+                write!(out, "CREATE_TYPE db={} type={}", db, typoid).unwrap();
+            }
+            info => write!(out, "UNKNOWN info={}", info).unwrap(),
+        }
+    }
+}
+
 fn insert_create_l0file_wal(
     sess: &mut SessionState,
     table: TableId,
     startid: u32,
     endid: u32,
 ) -> Lsn {
-    let walrec = CreateL0File {
-        db: table.db.get(),
-        table: table.table.get(),
-        startid,
-        endid,
-    };
-    let waldat = wal::start_record(&walrec);
+    let mut waldat = wal::start_record_raw(&[]);
+    ser_create_l0file(&mut waldat, table, startid, endid);
     return sess.insert_record(RmgrId::SV, CREATE_L0FILE, waldat);
 }
 
@@ -553,6 +1169,65 @@ pub fn commit_write(sess: &mut SessionState, slot: &SBSlot, files: &[FileMeta])
     return;
 }
 
+// Mark the L0 files to be rewritten by VACUUM FULL in use, so that they are never allocated to
+// the writers. The files must be unchanged since they were returned by datafiles().
+pub fn start_rewrite(slot: &SBSlot, files: &[FileMeta]) -> anyhow::Result<()> {
+    let sv = slot.v.read().unwrap();
+    let sv: &Marc<SupVer> = sv.as_ref().unwrap();
+    for (idx, file) in files.iter().enumerate() {
+        let l0file = sv.find_l0(file.fileid).map(|l0idx| &sv.l0[l0idx]);
+        if matches!(l0file, Some(l0file) if l0file.meta == *file && l0file.start_use()) {
+            continue;
+        }
+        for file in &files[..idx] {
+            sv.l0[sv.find_l0(file.fileid).unwrap()].abort_use();
+        }
+        kbbail!(
+            ERRCODE_OBJECT_IN_USE,
+            "the file {} is being written",
+            get_datafile_path(slot.k, file.fileid)
+        );
+    }
+    return Ok(());
+}
+
+// Replace the L0 files rewritten by VACUUM FULL with the new files at once, so the rows are
+// neither lost nor seen twice. The old files are unlinked by the pending file ops, just as
+// drop_table_storage().
+pub fn commit_rewrite(
+    sess: &mut SessionState,
+    slot: &SBSlot,
+    oldfiles: &[FileMeta],
+    newfiles: &[FileMeta],
+) {
+    let svctx = SVDestoryCtx::new(slot.k, sess.pending_fileops);
+    let mut walfiles = newfiles.to_vec();
+    walfiles.extend(oldfiles.iter().map(|f| FileMeta::new(f.fileid, 0, 0)));
+    let mut waldat = wal::start_record_raw(&[]);
+    ser_update_l0file(&mut waldat, slot.k, &walfiles);
+
+    let mut sv = slot.v.write().unwrap(); // lock guard
+    let sv: &mut Marc<SupVer> = sv.as_mut().unwrap();
+    let sv = sv.make_mut(&svctx);
+    for file in newfiles {
+        let idx = sv.find_l0(file.fileid).unwrap();
+        sv.l0[idx].commit_use(file.rownum, file.len);
+    }
+    sv.l0
+        .retain(|l0file| oldfiles.iter().all(|f| f.fileid != l0file.meta.fileid));
+    *sv.l0_removed.get_mut() = true;
+    slot.mark_dirty();
+    let lsn = sess.insert_record(RmgrId::SV, UPDATE_L0FILE, waldat);
+    sv.lsn = Some(lsn);
+    for file in oldfiles {
+        sess.pending_fileops
+            .unlink(get_datafile_path(slot.k, file.fileid));
+        sess.pending_fileops
+            .unlink(get_mvccfile_path(slot.k, file.fileid));
+    }
+    return;
+}
+
 pub fn start_write(
     sess: &mut SessionState,
     slot: &SBSlot,
@@ -570,3 +1245,191 @@ pub fn start_write(
     mem::forget(_guard);
     return Ok(files);
 }
+
+// The latest state of the L0 files of the table recorded in all available wal. The files that
+// are created but not written yet are empty.
+pub fn l0files_from_wal(table: TableId) -> anyhow::Result<HashMap<FileId, FileMeta>> {
+    let storage = LocalWalStorage::new();
+    let mut files = HashMap::new();
+    let startlsn = match storage.first_lsn()? {
+        None => return Ok(files),
+        Some(lsn) => lsn,
+    };
+    let mut walreader = WalReader::new(Box::new(storage), startlsn);
+    while let Ok((hdr, data)) = walreader.read_record() {
+        if !matches!(hdr.id, RmgrId::SV) {
+            continue;
+        }
+        match hdr.info {
+            CREATE_L0FILE => {
+                let (tableid, startid, endid) = get_create_l0file(&data);
+                if tableid != table {
+                    continue;
+                }
+                for fileid in startid..endid {
+                    let fileid = FileId::new(fileid).unwrap();
+                    files.entry(fileid).or_insert(FileMeta::new(fileid, 0, 0));
+                }
+            }
+            UPDATE_L0FILE => {
+                let (tableid, l0files) = de_update_l0file(&data)?;
+                if tableid != table {
+                    continue;
+                }
+                for file in l0files {
+                    files.insert(file.fileid, file);
+                }
+            }
+            _ => {}
+        }
+    }
+    return Ok(files);
+}
+
+// Rebuild the manifest of the table from the data files in its directory, it is the last resort
+// for a lost or corrupted manifest. All files are placed in L0. The state of the file recorded
+// in walfiles is preferred to the one scanned from the data file, unless the data file is
+// shorter than it. The manifest is written only if force is set, returns the files of it.
+pub fn rebuild_manifest(
+    table: TableId,
+    walfiles: &HashMap<FileId, FileMeta>,
+    force: bool,
+) -> anyhow::Result<Vec<FileMeta>> {
+    let mut files = BTreeMap::new();
+    for direntry in fs::read_dir(get_dir(table))? {
+        let direntry = direntry?;
+        let name = direntry.file_name();
+        let fileid = name
+            .to_str()
+            .and_then(|name| name.strip_suffix(".d"))
+            .and_then(|fileid| fileid.parse().ok())
+            .and_then(FileId::new);
+        let fileid = match fileid {
+            None => continue,
+            Some(fileid) => fileid,
+        };
+        let path = get_datafile_path(table, fileid);
+        let (rownum, len) = cs::scan_datafile(&path)?;
+        let mut file = FileMeta::new(fileid, rownum, len);
+        if let Some(&walfile) = walfiles.get(&fileid) {
+            let filelen = direntry.metadata()?.len();
+            if filelen >= walfile.len {
+                file = walfile;
+            } else {
+                log::warn!(
+                    "rebuild_manifest: the file is shorter than wal expects. path={} len={} wallen={}",
+                    path,
+                    filelen,
+                    walfile.len
+                );
+            }
+        }
+        files.insert(fileid, file);
+    }
+    for (&fileid, &walfile) in walfiles {
+        if files.contains_key(&fileid) {
+            continue;
+        }
+        if walfile.is_empty() {
+            files.insert(fileid, walfile);
+        } else {
+            log::warn!(
+                "rebuild_manifest: the file written in wal does not exist. path={}",
+                get_datafile_path(table, fileid)
+            );
+        }
+    }
+    let files: Vec<FileMeta> = files.into_values().collect();
+    if force {
+        let sv = SupVer {
+            l0: files.iter().map(|&meta| L0File::persisted(meta)).collect(),
+            l1: Vec::new(),
+            l2: Vec::new(),
+            nextid: files.last().map_or(1, |f| f.fileid.get() + 1),
+            lsn: None,
+            enable_cs_wal: false,
+            l0_removed: AtomicBool::new(false),
+        };
+        write_manifest(&get_minafest_path(table.db, table.table), &sv)?;
+        match fs::remove_file(get_journal_path(table.db, table.table)) {
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            ret => ret?,
+        }
+    }
+    return Ok(files);
+}
+
+#[cfg(test)]
+mod record_ser_test {
+    use super::{
+        de_update_l0file, get_create_l0file, get_create_table, ser_create_l0file, ser_create_table,
+        ser_update_l0file, FileMeta, TableId, CREATE_L0FILE, CREATE_TABLE, UPDATE_L0FILE,
+    };
+    use crate::access::wal::{
+        finish_record, for_each_misaligned, parse_record, start_record_raw, RmgrId,
+    };
+    use crate::{FileId, Oid};
+
+    fn table() -> TableId {
+        TableId {
+            db: Oid::new(20181218).unwrap(),
+            table: Oid::new(0x20130203).unwrap(),
+        }
+    }
+
+    // The db and the table of table() in little-endian.
+    const TABLE_BYTES: [u8; 8] = [0xe2, 0xf0, 0x33, 0x01, 0x03, 0x02, 0x13, 0x20];
+
+    #[test]
+    fn create_table() {
+        let mut rec = start_record_raw(&[]);
+        ser_create_table(&mut rec, table());
+        finish_record(&mut rec, RmgrId::SV, CREATE_TABLE, None);
+        let (h, data) = parse_record(&rec);
+        assert_eq!(h.rmgr_info(), CREATE_TABLE);
+        assert_eq!(data, TABLE_BYTES);
+        assert_eq!(get_create_table(data), table());
+        for_each_misaligned(data, |d| assert_eq!(get_create_table(d), table()));
+    }
+
+    #[test]
+    fn create_l0file() {
+        let mut rec = start_record_raw(&[]);
+        ser_create_l0file(&mut rec, table(), 33, 77);
+        finish_record(&mut rec, RmgrId::SV, CREATE_L0FILE, None);
+        let (h, data) = parse_record(&rec);
+        assert_eq!(h.info, CREATE_L0FILE);
+        assert_eq!(&data[..8], TABLE_BYTES);
+        assert_eq!(&data[8..], [33, 0, 0, 0, 77, 0, 0, 0]);
+        assert_eq!(get_create_l0file(data), (table(), 33, 77));
+        for_each_misaligned(data, |d| {
+            assert_eq!(get_create_l0file(d), (table(), 33, 77));
+        });
+    }
+
+    #[test]
+    fn update_l0file() {
+        let files = [
+            FileMeta::new(FileId::new(1).unwrap(), 0, 0),
+            FileMeta::new(FileId::new(33).unwrap(), 20181218, 0x2013020320181218),
+        ];
+        let mut waldat = start_record_raw(&[]);
+        ser_update_l0file(&mut waldat, table(), &files);
+        finish_record(&mut waldat, RmgrId::SV, UPDATE_L0FILE, None);
+        let (h, data) = parse_record(&waldat);
+        assert_eq!(h.info, UPDATE_L0FILE);
+        assert_eq!(data.len(), 8 + 16 * files.len());
+        assert_eq!(&data[..8], TABLE_BYTES);
+        assert_eq!(&data[24..32], [33, 0, 0, 0, 0xe2, 0xf0, 0x33, 0x01]);
+        assert_eq!(
+            &data[32..40],
+            [0x18, 0x12, 0x18, 0x20, 0x03, 0x02, 0x13, 0x20]
+        );
+        assert_eq!(de_update_l0file(data).unwrap(), (table(), files.to_vec()));
+        for_each_misaligned(data, |d| {
+            assert_eq!(de_update_l0file(d).unwrap(), (table(), files.to_vec()));
+        });
+        assert_eq!(de_update_l0file(&data[..8]).unwrap(), (table(), vec![]));
+        assert!(de_update_l0file(&data[..20]).is_err());
+    }
+}
@@ -9,22 +9,27 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 use crate::access::redo::RedoState;
-use crate::guc::{self, GucState};
-use crate::utils::{persist, pwritevn, ser::as_bytes, KBSystemTime, Xid};
+use crate::access::walarchive::WalArchiver;
+use crate::guc::{self, GucState, SyncMethod};
+use crate::utils::crashpoint::{crash_point, CrashPoint};
+use crate::utils::{persist_sync, pglz, pwritevn, ser, sync_dir, KBSystemTime, Xid};
+use crate::utils::{sync_file, sync_open_flags};
 use crate::{make_static, Oid};
 use anyhow::{anyhow, ensure};
+use byteorder::{ByteOrder, LittleEndian};
 use log;
-use memoffset::offset_of;
 use nix::libc::off_t;
 use nix::sys::uio::{pread, IoVec};
 use std::convert::{From, Into};
 use std::fmt::Write;
 use std::fs::{self, read_dir, File, OpenOptions};
-use std::io::Read;
-use std::mem::size_of;
+use std::io::{Read, Write as _};
 use std::num::{NonZeroU32, NonZeroU64};
 use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::process::Command;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex, MutexGuard, Weak};
 use std::thread::panicking;
@@ -40,158 +45,136 @@ pub struct Ckpt {
     pub time: KBSystemTime,
 }
 
-#[repr(C, packed(1))]
-struct CkptSer {
-    redo: u64,
-    curtli: u32,
-    prevtli: u32,
-    nextxid: u64,
-    nextoid: u32,
-    time: u64,
-}
-
-impl From<&Ckpt> for CkptSer {
-    fn from(v: &Ckpt) -> CkptSer {
-        CkptSer {
-            redo: v.redo.get(),
-            curtli: v.curtli.get(),
-            prevtli: v.prevtli.get(),
-            nextxid: v.nextxid.get(),
-            nextoid: v.nextoid.get(),
-            time: v.time.into(),
-        }
-    }
-}
+// CheckPoint, in little-endian: redo u64, curtli u32, prevtli u32, nextxid u64, nextoid u32,
+// time u64.
+const CKPTLEN: usize = 36;
 
-impl From<&CkptSer> for Ckpt {
-    fn from(v: &CkptSer) -> Ckpt {
-        Ckpt {
-            redo: Lsn::new(v.redo).unwrap(),
-            curtli: TimeLineID::new(v.curtli).unwrap(),
-            prevtli: TimeLineID::new(v.prevtli).unwrap(),
-            nextxid: Xid::new(v.nextxid).unwrap(),
-            nextoid: Oid::new(v.nextoid).unwrap(),
-            time: v.time.into(),
-        }
-    }
+fn ser_ckpt(out: &mut Vec<u8>, ckpt: &Ckpt) {
+    ser::ser_le_u64(out, ckpt.redo.get());
+    ser::ser_le_u32(out, ckpt.curtli.get());
+    ser::ser_le_u32(out, ckpt.prevtli.get());
+    ser::ser_le_u64(out, ckpt.nextxid.get());
+    ser::ser_le_u32(out, ckpt.nextoid.get());
+    ser::ser_le_u64(out, ckpt.time.into());
 }
 
 pub fn new_ckpt_rec(ckpt: &Ckpt) -> Vec<u8> {
-    let ckptser: CkptSer = ckpt.into();
-    return start_record(&ckptser);
-}
-
-fn get_ckpt(recdata: &[u8]) -> Ckpt {
-    unsafe { (&*(recdata.as_ptr() as *const CkptSer)).into() }
+    let mut rec = start_record_raw(&[]);
+    ser_ckpt(&mut rec, ckpt);
+    return rec;
+}
+
+fn get_ckpt(d: &[u8]) -> Ckpt {
+    Ckpt {
+        redo: Lsn::new(LittleEndian::read_u64(&d[0..])).unwrap(),
+        curtli: TimeLineID::new(LittleEndian::read_u32(&d[8..])).unwrap(),
+        prevtli: TimeLineID::new(LittleEndian::read_u32(&d[12..])).unwrap(),
+        nextxid: Xid::new(LittleEndian::read_u64(&d[16..])).unwrap(),
+        nextoid: Oid::new(LittleEndian::read_u32(&d[24..])).unwrap(),
+        time: LittleEndian::read_u64(&d[28..]).into(),
+    }
 }
 
-pub const KB_CTL_VER: u32 = 20130203;
+pub const KB_CTL_VER: u32 = 20210601;
 pub const KB_CAT_VER: u32 = 20181218;
-const CONTROL_FILE: &'static str = "global/kb_control";
+pub const CONTROL_FILE: &'static str = "global/kb_control";
+
+// DBState, the redo is skipped at the start only after the clean shutdown.
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DbState {
+    Shutdowned = 1,
+    InProduction = 2,
+}
+
+impl DbState {
+    fn from_u32(v: u32) -> Option<DbState> {
+        if v == DbState::Shutdowned as u32 {
+            Some(DbState::Shutdowned)
+        } else if v == DbState::InProduction as u32 {
+            Some(DbState::InProduction)
+        } else {
+            None
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Ctl {
+    pub state: DbState,
     pub time: KBSystemTime,
     pub ckpt: Lsn,
     pub ckptcpy: Ckpt,
 }
 
-#[repr(C, packed(1))]
-struct CtlSer {
-    ctlver: u32,
-    catver: u32,
-    time: u64,
-    ckpt: u64,
-    ckptcpy: CkptSer,
-    crc32c: u32,
-}
+// ControlFileData, in little-endian: ctlver u32, catver u32, time u64, ckpt u64, ckptcpy,
+// state u32, crc32c u32.
+const CTL_CKPTCPY_OFF: usize = 24;
+const CTL_STATE_OFF: usize = CTL_CKPTCPY_OFF + CKPTLEN;
+const CTL_CRC_OFF: usize = CTL_STATE_OFF + 4;
+const CTLLEN: usize = CTL_CRC_OFF + 4;
 
-const CTLLEN: usize = size_of::<CtlSer>();
-
-impl CtlSer {
-    fn cal_crc32c(&self) -> u32 {
-        unsafe {
-            let ptr = self as *const _ as *const u8;
-            let len = offset_of!(CtlSer, crc32c);
-            let d = std::slice::from_raw_parts(ptr, len);
-            crc32c::crc32c(d)
+impl Ctl {
+    pub fn new(ckpt: Lsn, ckptcpy: Ckpt) -> Ctl {
+        Ctl {
+            state: DbState::InProduction,
+            time: KBSystemTime::now(),
+            ckpt,
+            ckptcpy,
         }
     }
 
-    fn persist(&self) -> anyhow::Result<()> {
-        unsafe {
-            let ptr = self as *const _ as *const u8;
-            let d = std::slice::from_raw_parts(ptr, CTLLEN);
-            persist(CONTROL_FILE, d)
-        }
+    pub fn persist(&self, method: SyncMethod) -> anyhow::Result<()> {
+        persist_sync(CONTROL_FILE, &self.serialize(), method)
     }
 
-    fn load() -> anyhow::Result<CtlSer> {
+    pub fn load() -> anyhow::Result<Ctl> {
         let mut d = Vec::with_capacity(CTLLEN);
         File::open(CONTROL_FILE)?.read_to_end(&mut d)?;
+        Ctl::deserialize(&d)
+    }
+
+    // The content of the control file, used by the base backup.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut d = Vec::with_capacity(CTLLEN);
+        ser::ser_le_u32(&mut d, KB_CTL_VER);
+        ser::ser_le_u32(&mut d, KB_CAT_VER);
+        ser::ser_le_u64(&mut d, self.time.into());
+        ser::ser_le_u64(&mut d, self.ckpt.get());
+        ser_ckpt(&mut d, &self.ckptcpy);
+        ser::ser_le_u32(&mut d, self.state as u32);
+        let crc = crc32c::crc32c(&d);
+        ser::ser_le_u32(&mut d, crc);
+        debug_assert_eq!(d.len(), CTLLEN);
+        return d;
+    }
+
+    pub fn deserialize(d: &[u8]) -> anyhow::Result<Ctl> {
         ensure!(
             d.len() == CTLLEN,
             "load: invalid control file. len={}",
             d.len()
         );
-        let ctl = unsafe { std::ptr::read(d.as_ptr() as *const CtlSer) };
-        let v = ctl.ctlver;
+        let v = LittleEndian::read_u32(&d[0..]);
         ensure!(v == KB_CTL_VER, "load: unexpected ctlver={}", v);
-        let v = ctl.catver;
+        let v = LittleEndian::read_u32(&d[4..]);
         ensure!(v == KB_CAT_VER, "load: unexpected catver={}", v);
-        let v1 = ctl.cal_crc32c();
-        let v = ctl.crc32c;
+        let v1 = crc32c::crc32c(&d[..CTL_CRC_OFF]);
+        let v = LittleEndian::read_u32(&d[CTL_CRC_OFF..]);
         ensure!(
             v == v1,
             "load: unexpected crc32c. actual={} expected={}",
             v,
             v1
         );
-        Ok(ctl)
-    }
-}
-impl Ctl {
-    pub fn new(ckpt: Lsn, ckptcpy: Ckpt) -> Ctl {
-        Ctl {
-            time: KBSystemTime::now(),
-            ckpt,
-            ckptcpy,
-        }
-    }
-
-    pub fn persist(&self) -> anyhow::Result<()> {
-        let v: CtlSer = self.into();
-        v.persist()
-    }
-
-    pub fn load() -> anyhow::Result<Ctl> {
-        let ctlser = CtlSer::load()?;
-        Ok((&ctlser).into())
-    }
-}
-
-impl From<&Ctl> for CtlSer {
-    fn from(v: &Ctl) -> Self {
-        let mut ctlser = CtlSer {
-            ctlver: KB_CTL_VER,
-            catver: KB_CAT_VER,
-            time: v.time.into(),
-            ckpt: v.ckpt.get(),
-            ckptcpy: (&v.ckptcpy).into(),
-            crc32c: 0,
-        };
-        ctlser.crc32c = ctlser.cal_crc32c();
-        ctlser
-    }
-}
-
-impl From<&CtlSer> for Ctl {
-    fn from(ctlser: &CtlSer) -> Ctl {
-        Ctl {
-            time: ctlser.time.into(),
-            ckpt: Lsn::new(ctlser.ckpt).unwrap(),
-            ckptcpy: (&ctlser.ckptcpy).into(),
-        }
+        let v = LittleEndian::read_u32(&d[CTL_STATE_OFF..]);
+        let state = DbState::from_u32(v).ok_or_else(|| anyhow!("load: unexpected state={}", v))?;
+        Ok(Ctl {
+            state,
+            time: LittleEndian::read_u64(&d[8..]).into(),
+            ckpt: Lsn::new(LittleEndian::read_u64(&d[16..])).unwrap(),
+            ckptcpy: get_ckpt(&d[CTL_CKPTCPY_OFF..]),
+        })
     }
 }
 
@@ -215,18 +198,28 @@ pub enum RmgrId {
     SV,
 }
 
-impl From<u8> for RmgrId {
-    fn from(v: u8) -> Self {
+impl RmgrId {
+    // None if v is not a RmgrId, for example, the garbage after the end of wal.
+    pub fn new(v: u8) -> Option<RmgrId> {
         if v == RmgrId::Xlog as u8 {
-            RmgrId::Xlog
+            Some(RmgrId::Xlog)
         } else if v == RmgrId::Xact as u8 {
-            RmgrId::Xact
+            Some(RmgrId::Xact)
         } else if v == RmgrId::CSMvcc as u8 {
-            RmgrId::CSMvcc
+            Some(RmgrId::CSMvcc)
         } else if v == RmgrId::SV as u8 {
-            RmgrId::SV
+            Some(RmgrId::SV)
         } else {
-            panic!("try from u8 to RmgrId failed. value={}", v)
+            None
+        }
+    }
+}
+
+impl From<u8> for RmgrId {
+    fn from(v: u8) -> Self {
+        match RmgrId::new(v) {
+            Some(id) => id,
+            None => panic!("try from u8 to RmgrId failed. value={}", v),
         }
     }
 }
@@ -265,13 +258,15 @@ impl WalStorageFile for LocalWalStorageFile {
 
 struct LocalWalStorageWalFile {
     file: LocalWalStorageFile,
+    tli: TimeLineID,
     lsn: Lsn,
 }
 
 impl LocalWalStorageWalFile {
-    fn new(file: File, len: u64, lsn: Lsn) -> LocalWalStorageWalFile {
+    fn new(file: File, len: u64, tli: TimeLineID, lsn: Lsn) -> LocalWalStorageWalFile {
         LocalWalStorageWalFile {
             file: LocalWalStorageFile::new(file, len),
+            tli,
             lsn,
         }
     }
@@ -291,7 +286,7 @@ impl WalStorageWalFile for LocalWalStorageWalFile {
         self.lsn
     }
     fn tli(&self) -> TimeLineID {
-        TimeLineID::new(1).unwrap()
+        self.tli
     }
 }
 
@@ -300,14 +295,148 @@ pub trait WalStorage {
     // fn open(&mut self, key: &str) -> anyhow::Result<Box<dyn WalStorageFile>>;
     fn open_wal(&mut self, tli: TimeLineID, lsn: Lsn)
         -> anyhow::Result<Box<dyn WalStorageWalFile>>;
-    fn recycle(&mut self, lsn: Lsn) -> anyhow::Result<()>;
+    // Remove the content of the timeline tli since lsn.
+    fn recycle(&mut self, tli: TimeLineID, lsn: Lsn) -> anyhow::Result<()>;
+    // Remove the wal files whose content are all before lsn.
+    fn remove_before(&mut self, lsn: Lsn) -> anyhow::Result<()>;
+    // The largest timeline of the wal files, a new timeline must be larger than it.
+    fn last_tli(&self) -> anyhow::Result<TimeLineID>;
+    // The new timeline starts at lsn. The content of the file containing lsn before lsn is
+    // copied into newtli, and the file is renamed as .partial.
+    fn switch_timeline(
+        &mut self,
+        tli: TimeLineID,
+        newtli: TimeLineID,
+        lsn: Lsn,
+    ) -> anyhow::Result<()>;
+}
+
+// %f in the restore_command is replaced by the wal file name, %p by the path to copy the wal
+// file to, and %% by %.
+fn make_restore_command(restore_command: &str, walname: &str, path: &str) -> String {
+    let mut cmd = String::new();
+    let mut chars = restore_command.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            cmd.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('f') => cmd.push_str(walname),
+            Some('p') => cmd.push_str(path),
+            Some('%') => cmd.push('%'),
+            Some(c) => {
+                cmd.push('%');
+                cmd.push(c);
+            }
+            None => cmd.push('%'),
+        }
+    }
+    return cmd;
 }
 
-pub struct LocalWalStorage {}
+pub struct LocalWalStorage {
+    dir: String,
+    archiver: Option<WalArchiver>,
+    restore_command: String,
+}
 
 impl LocalWalStorage {
     pub fn new() -> LocalWalStorage {
-        LocalWalStorage {}
+        LocalWalStorage::with_dir(WAL_DIR)
+    }
+
+    pub fn with_dir(dir: &str) -> LocalWalStorage {
+        LocalWalStorage {
+            dir: dir.to_string(),
+            archiver: None,
+            restore_command: String::new(),
+        }
+    }
+
+    // The wal files are archived by the archiver before they are removed by remove_before().
+    pub fn set_archiver(&mut self, archiver: Option<WalArchiver>) {
+        self.archiver = archiver;
+    }
+
+    // The wal file not in the dir is fetched by the restore_command, or by the archiver
+    // if the restore_command is empty.
+    pub fn set_restore_command(&mut self, restore_command: &str) {
+        self.restore_command = restore_command.to_string();
+    }
+
+    // RestoreArchivedFile(), return false if the wal file (tli, lsn) is not restored.
+    fn restore(&self, tli: TimeLineID, lsn: Lsn) -> anyhow::Result<bool> {
+        let walname = wal_filename(tli, lsn);
+        let path = self.filepath(tli, lsn);
+        if self.restore_command.is_empty() {
+            return match &self.archiver {
+                None => Ok(false),
+                Some(archiver) => archiver.restore(&walname, Path::new(&path)),
+            };
+        }
+        // The wal file is renamed after it is restored completely, so that a partly
+        // restored file can not be read.
+        let restorepath = format!("{}.restore", path);
+        let cmd = make_restore_command(&self.restore_command, &walname, &restorepath);
+        let status = Command::new("sh").arg("-c").arg(&cmd).status()?;
+        if !status.success() || !Path::new(&restorepath).exists() {
+            log::info!(
+                "could not restore the wal file from archive. cmd={} status={}",
+                cmd,
+                status
+            );
+            if Path::new(&restorepath).exists() {
+                fs::remove_file(&restorepath)?;
+            }
+            return Ok(false);
+        }
+        File::open(&restorepath)?.sync_data()?;
+        fs::rename(&restorepath, &path)?;
+        sync_dir(&self.dir)?;
+        log::info!("restored the wal file from archive. path={}", path);
+        return Ok(true);
+    }
+
+    fn filepath(&self, tli: TimeLineID, lsn: Lsn) -> String {
+        format!("{}/{}", self.dir, wal_filename(tli, lsn))
+    }
+
+    // The start lsn of the oldest wal file, None if there are no wal files.
+    pub fn first_lsn(&self) -> anyhow::Result<Option<Lsn>> {
+        let mut first = None;
+        for direntry in read_dir(&self.dir)? {
+            let direntry = direntry?;
+            let name = direntry.file_name();
+            let name = name.as_os_str().as_bytes();
+            if !is_wal(name) {
+                continue;
+            }
+            let (_, filelsn) = parse_wal_filename(name);
+            match first {
+                Some(lsn) if lsn <= filelsn => {}
+                _ => first = Some(filelsn),
+            }
+        }
+        Ok(first)
+    }
+
+    // The start of the first timeline larger than tli after lsn.
+    fn switch_lsn(&self, tli: TimeLineID, lsn: Lsn) -> anyhow::Result<Option<Lsn>> {
+        let mut switchlsn: Option<Lsn> = None;
+        for direntry in read_dir(&self.dir)? {
+            let direntry = direntry?;
+            let name = direntry.file_name();
+            let name = name.as_os_str().as_bytes();
+            if !is_wal(name) {
+                continue;
+            }
+            let (filetli, filelsn) = parse_wal_filename(name);
+            if filetli > tli && filelsn > lsn {
+                switchlsn = Some(switchlsn.map_or(filelsn, |v| v.min(filelsn)));
+            }
+        }
+        Ok(switchlsn)
     }
 }
 
@@ -319,8 +448,12 @@ fn lsn_in_file(filelsn: Lsn, len: u64, lsn: Lsn) -> bool {
 }
 
 impl WalStorage for LocalWalStorage {
+    // The new timeline starts at the lsn where the recovery stops, it replaces the old ones
+    // since then. So the wal at lsn is in the largest timeline that starts at or before lsn,
+    // the old timelines are never read after the new timeline starts.
     fn find(&self, lsn: Lsn) -> anyhow::Result<(TimeLineID, Lsn)> {
-        for direntry in read_dir("kb_wal")? {
+        let mut found: Option<(TimeLineID, Lsn, u64)> = None;
+        for direntry in read_dir(&self.dir)? {
             let direntry = direntry?;
             let name = direntry.file_name();
             let name = name.as_os_str().as_bytes();
@@ -328,15 +461,36 @@ impl WalStorage for LocalWalStorage {
                 continue;
             }
             let (tli, filelsn) = parse_wal_filename(name);
-            if tli.get() != 1 {
+            if filelsn > lsn {
                 continue;
             }
+            if let Some((foundtli, _, _)) = found {
+                if foundtli > tli {
+                    continue;
+                }
+            }
             let meta = direntry.metadata()?;
             debug_assert!(meta.is_file());
             let filelen = meta.len();
+            // Prefer the file containing lsn, then the file ending at lsn which is followed by
+            // the file to restore.
+            match found {
+                Some((foundtli, foundlsn, foundlen))
+                    if foundtli == tli
+                        && (lsn_in_file(foundlsn, foundlen, lsn)
+                            || (!lsn_in_file(filelsn, filelen, lsn)
+                                && filelsn.get() + filelen != lsn.get())) => {}
+                _ => found = Some((tli, filelsn, filelen)),
+            }
+        }
+        if let Some((tli, filelsn, filelen)) = found {
             if lsn_in_file(filelsn, filelen, lsn) {
                 return Ok((tli, filelsn));
             }
+            // The next wal file of the timeline starts at lsn.
+            if filelsn.get() + filelen == lsn.get() && self.restore(tli, lsn)? {
+                return Ok((tli, lsn));
+            }
         }
         return Err(anyhow!(
             "LocalWalStorage::find:  can not find the expected wal file. lsn={}",
@@ -349,21 +503,27 @@ impl WalStorage for LocalWalStorage {
         tli: TimeLineID,
         lsn: Lsn,
     ) -> anyhow::Result<Box<dyn WalStorageWalFile>> {
-        let file = File::open(wal_filepath(tli, lsn))?;
-        let filelen = file.metadata()?.len();
-        Ok(Box::new(LocalWalStorageWalFile::new(file, filelen, lsn)))
+        let file = File::open(self.filepath(tli, lsn))?;
+        let mut filelen = file.metadata()?.len();
+        // The content after the start of a new timeline is discarded.
+        if let Some(switchlsn) = self.switch_lsn(tli, lsn)? {
+            filelen = filelen.min(switchlsn.get() - lsn.get());
+        }
+        Ok(Box::new(LocalWalStorageWalFile::new(
+            file, filelen, tli, lsn,
+        )))
     }
 
-    fn recycle(&mut self, lsn: Lsn) -> anyhow::Result<()> {
-        for direntry in read_dir("kb_wal")? {
+    fn recycle(&mut self, tli: TimeLineID, lsn: Lsn) -> anyhow::Result<()> {
+        for direntry in read_dir(&self.dir)? {
             let direntry = direntry?;
             let name = direntry.file_name();
             let name = name.as_os_str().as_bytes();
             if !is_wal(&name) {
                 continue;
             }
-            let (tli, filelsn) = parse_wal_filename(name);
-            if tli.get() != 1 {
+            let (filetli, filelsn) = parse_wal_filename(name);
+            if filetli != tli {
                 continue;
             }
             if filelsn >= lsn {
@@ -390,6 +550,98 @@ impl WalStorage for LocalWalStorage {
         }
         Ok(())
     }
+
+    fn remove_before(&mut self, lsn: Lsn) -> anyhow::Result<()> {
+        for direntry in read_dir(&self.dir)? {
+            let direntry = direntry?;
+            let name = direntry.file_name();
+            let name = name.as_os_str().as_bytes();
+            if !is_wal(&name) {
+                continue;
+            }
+            let (_, filelsn) = parse_wal_filename(name);
+            if filelsn >= lsn {
+                continue;
+            }
+            let filelen = direntry.metadata()?.len();
+            if lsn.get() - filelsn.get() < filelen {
+                continue;
+            }
+            let path = direntry.path();
+            log::info!(
+                "LocalWalStorage::remove_before: remove wal file. path={:?} lsn={}",
+                path,
+                lsn
+            );
+            if let Some(archiver) = &self.archiver {
+                archiver.archive(&path)?;
+            }
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    // Just like PostgreSQL, the tail of the file after lsn belongs to the old timeline only,
+    // so it is kept as a .partial file which is never read by is_wal(), find() and so on.
+    fn switch_timeline(
+        &mut self,
+        tli: TimeLineID,
+        newtli: TimeLineID,
+        lsn: Lsn,
+    ) -> anyhow::Result<()> {
+        let mut found = None;
+        for direntry in read_dir(&self.dir)? {
+            let direntry = direntry?;
+            let name = direntry.file_name();
+            let name = name.as_os_str().as_bytes();
+            if !is_wal(name) {
+                continue;
+            }
+            let (filetli, filelsn) = parse_wal_filename(name);
+            if filetli == tli && lsn_in_file(filelsn, direntry.metadata()?.len(), lsn) {
+                found = Some(filelsn);
+                break;
+            }
+        }
+        let filelsn = match found {
+            None => return Ok(()),
+            Some(v) => v,
+        };
+        let path = self.filepath(tli, filelsn);
+        if filelsn < lsn {
+            let mut prefix = vec![0u8; (lsn.get() - filelsn.get()) as usize];
+            File::open(&path)?.read_exact(&mut prefix)?;
+            let mut newfile = OpenOptions::new()
+                .create_new(true)
+                .write(true)
+                .open(self.filepath(newtli, filelsn))?;
+            newfile.write_all(&prefix)?;
+            newfile.sync_data()?;
+        }
+        let partialpath = format!("{}.partial", path);
+        log::info!(
+            "LocalWalStorage::switch_timeline: rename the wal file. path={} newpath={} lsn={}",
+            path,
+            partialpath,
+            lsn
+        );
+        fs::rename(&path, &partialpath)?;
+        sync_dir(&self.dir)?;
+        Ok(())
+    }
+
+    fn last_tli(&self) -> anyhow::Result<TimeLineID> {
+        let mut last = TimeLineID::new(1).unwrap();
+        for direntry in read_dir(&self.dir)? {
+            let direntry = direntry?;
+            let name = direntry.file_name();
+            let name = name.as_os_str().as_bytes();
+            if is_wal(name) {
+                last = last.max(parse_wal_filename(name).0);
+            }
+        }
+        Ok(last)
+    }
 }
 
 pub struct WalReader {
@@ -409,36 +661,47 @@ impl WalReader {
         }
     }
 
-    pub fn rescan(&mut self, _startlsn: Lsn) {
-        unimplemented!()
+    // The file is reopened by the next read_record() since startlsn may be in another file,
+    // and the prevlsn of the first record read is not checked, just as WalReader::new().
+    pub fn rescan(&mut self, startlsn: Lsn) {
+        self.file = None;
+        self.readlsn = None;
+        self.endlsn = startlsn;
+    }
+
+    // The opened file may grow after it was opened, for example, wal is being shipped into
+    // it, close it so that the next read_record() will see the new length.
+    pub fn close_file(&mut self) {
+        self.file = None;
     }
 
+    // The next file may be in a new timeline, see LocalWalStorage::find().
     fn open_file(&mut self) -> anyhow::Result<u64> {
-        let mut endlsn_is_filelsn = false;
         if let Some(ref file) = self.file {
             let filelsn = file.lsn();
-            let filelen = file.len();
             if self.endlsn >= filelsn {
                 let endlsnlen = self.endlsn.get() - filelsn.get();
-                if endlsnlen < filelen {
+                if endlsnlen < file.len() {
                     return Ok(endlsnlen);
                 }
-                if endlsnlen == filelen {
-                    endlsn_is_filelsn = true;
-                }
             }
         }
-        let (tli, filelsn, endlsnlen) = if endlsn_is_filelsn {
-            (TimeLineID::new(1).unwrap(), self.endlsn, 0 as u64)
-        } else {
-            let (tli, filelsn) = self.storage.find(self.endlsn)?;
-            (tli, filelsn, self.endlsn.get() - filelsn.get())
-        };
+        let (tli, filelsn) = self.storage.find(self.endlsn)?;
         self.file = Some(self.storage.open_wal(tli, filelsn)?);
-        Ok(endlsnlen)
+        Ok(self.endlsn.get() - filelsn.get())
     }
 
     pub fn read_record(&mut self) -> anyhow::Result<(RecordHdr, Vec<u8>)> {
+        self.read_record_impl(false)
+    }
+
+    // Like read_record(), but the returned record includes the header, just as it was
+    // stored in the wal file.
+    pub fn read_raw_record(&mut self) -> anyhow::Result<(RecordHdr, Vec<u8>)> {
+        self.read_record_impl(true)
+    }
+
+    fn read_record_impl(&mut self, keephdr: bool) -> anyhow::Result<(RecordHdr, Vec<u8>)> {
         let recoff = self.open_file()?;
         let file = self.file.as_ref().unwrap();
         let mut hdrbytes = [0; RECHDRLEN];
@@ -467,8 +730,12 @@ impl WalReader {
             "cannot read RecordHdr. readlen={}",
             hdrlen
         );
-        let rechdrser = hdr(&hdrbytes);
-        let rechdr: RecordHdr = rechdrser.into();
+        read_ensure!(
+            RmgrId::new(hdrbytes[RECHDR_RMGR_OFF]).is_some(),
+            "invalid rmgr. rmgr={}",
+            hdrbytes[RECHDR_RMGR_OFF]
+        );
+        let mut rechdr = hdr(&hdrbytes);
         if let Some(prevlsn) = self.readlsn {
             let recprevlsn = rechdr
                 .prev
@@ -480,32 +747,171 @@ impl WalReader {
                 recprevlsn
             );
         }
+        read_ensure!(
+            rechdr.totlen as usize >= RECHDRLEN,
+            "invalid totlen. totlen={}",
+            rechdr.totlen
+        );
         let recdatlen = rechdr.totlen as usize - RECHDRLEN;
-        let mut databytes = Vec::<u8>::with_capacity(recdatlen);
-        databytes.resize(recdatlen, 0); // there is no need to do the zeroing.
-        let readlen = file.pread(&mut databytes, recoff + RECHDRLEN as u64)?;
+        let dataoff = if keephdr { RECHDRLEN } else { 0 };
+        let mut databytes = Vec::<u8>::with_capacity(dataoff + recdatlen);
+        databytes.resize(dataoff + recdatlen, 0); // there is no need to do the zeroing.
+        let readlen = file.pread(&mut databytes[dataoff..], recoff + RECHDRLEN as u64)?;
         read_ensure!(
             recdatlen == readlen,
             "cannot read data. readlen={} reclen={}",
             readlen,
             recdatlen
         );
-        let crc = crc32c::crc32c(&databytes);
+        let crc = crc32c::crc32c(&databytes[dataoff..]);
         let crc = crc32c::crc32c_append(crc, hdr_crc_area(&hdrbytes));
-        let actual_crc = rechdrser.crc32c;
+        let actual_crc = hdr_crc(&hdrbytes);
         read_ensure!(
             actual_crc == crc,
             "unexpected crc. expected={} actual={}",
             crc,
             actual_crc
         );
+        if keephdr {
+            databytes[..RECHDRLEN].copy_from_slice(&hdrbytes);
+        } else if rechdr.info & XLR_COMPRESSED != 0 {
+            read_ensure!(
+                databytes.len() >= 4,
+                "invalid compressed record. reclen={}",
+                recdatlen
+            );
+            let rawlen = LittleEndian::read_u32(&databytes) as usize;
+            databytes = pglz::decompress(&databytes[4..], rawlen).ok_or_else(|| {
+                read_err!(
+                    "cannot decompress data. reclen={} rawlen={}",
+                    recdatlen,
+                    rawlen
+                )
+            })?;
+            rechdr.info &= !XLR_COMPRESSED;
+        }
         self.readlsn = Some(self.endlsn);
         self.endlsn = Lsn::new(self.endlsn.get() + rechdr.totlen as u64).unwrap();
         Ok((rechdr, databytes))
     }
 
+    // The timeline of the file that the last record was read from.
     pub fn endtli(&self) -> TimeLineID {
-        TimeLineID::new(1).unwrap()
+        match self.file {
+            Some(ref file) => file.tli(),
+            None => TimeLineID::new(1).unwrap(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod wal_reader_test {
+    use super::{
+        compress_record, finish_record, serialize_records, start_record_raw, wal_filename,
+        LocalWalStorage, Lsn, RmgrId, TimeLineID, WalReader, RECHDRLEN, XLR_COMPRESSED,
+    };
+    use crate::guc::{self, GucState};
+
+    fn new_records(n: usize) -> Vec<Vec<u8>> {
+        let mut recs = Vec::new();
+        for i in 0..n {
+            let mut rec = start_record_raw(&[i as u8; 64]);
+            finish_record(&mut rec, RmgrId::Xlog, 0x30, None);
+            recs.push(rec);
+        }
+        recs
+    }
+
+    // The lsn, prevlsn and data of the next n records.
+    fn read(walreader: &mut WalReader, n: usize) -> Vec<(Lsn, Option<Lsn>, Vec<u8>)> {
+        let mut recs = Vec::new();
+        for _ in 0..n {
+            let lsn = walreader.endlsn;
+            let (hdr, data) = walreader.read_record().unwrap();
+            assert_eq!(walreader.readlsn, Some(lsn));
+            recs.push((lsn, hdr.prev, data));
+        }
+        recs
+    }
+
+    #[test]
+    fn rescan() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path().to_str().unwrap().to_string();
+        let tli = TimeLineID::new(1).unwrap();
+        let startlsn = Lsn::new(20181218).unwrap();
+        let seg1 = serialize_records(startlsn, None, new_records(3));
+        let reclen = seg1.len() as u64 / 3;
+        let seg2lsn = Lsn::new(startlsn.get() + seg1.len() as u64).unwrap();
+        let seg2 = serialize_records(seg2lsn, Lsn::new(seg2lsn.get() - reclen), new_records(4));
+        std::fs::write(format!("{}/{}", dir, wal_filename(tli, startlsn)), &seg1).unwrap();
+        std::fs::write(format!("{}/{}", dir, wal_filename(tli, seg2lsn)), &seg2).unwrap();
+
+        let storage = Box::new(LocalWalStorage::with_dir(&dir));
+        let mut walreader = WalReader::new(storage, startlsn);
+        let all = read(&mut walreader, 7);
+        assert!(walreader.read_record().is_err());
+        assert_eq!(all[0].1, None);
+        for i in 1..all.len() {
+            assert_eq!(all[i].1, Some(all[i - 1].0));
+        }
+        let data: Vec<_> = all.iter().map(|r| r.2[0]).collect();
+        assert_eq!(data, [0, 1, 2, 0, 1, 2, 3]);
+
+        // Back to the start from the second file.
+        walreader.rescan(startlsn);
+        assert_eq!(walreader.readlsn, None);
+        assert_eq!(read(&mut walreader, 7), all);
+        // Rescan to the record in the middle, whose prevlsn is not checked.
+        walreader.rescan(all[4].0);
+        assert_eq!(read(&mut walreader, 3), &all[4..]);
+        walreader.rescan(all[1].0);
+        assert_eq!(read(&mut walreader, 2), &all[1..3]);
+        assert!(walreader.read_record().is_ok());
+    }
+
+    #[test]
+    fn compressed() {
+        let mut page = vec![0u8; 8192];
+        for (i, v) in page.chunks_mut(8).enumerate().take(100) {
+            v[0] = i as u8;
+        }
+        let mut gucstate = GucState::default();
+        let (rec, info) = compress_record(&gucstate, start_record_raw(&page), 0x01);
+        assert_eq!((rec.len(), info), (RECHDRLEN + page.len(), 0x01));
+        guc::set_bool_guc(guc::WalCompression, true, &mut gucstate);
+        let (rec, info) = compress_record(&gucstate, start_record_raw(&page[..64]), 0x01);
+        assert_eq!((rec.len(), info), (RECHDRLEN + 64, 0x01));
+
+        let (mut fpi, info) = compress_record(&gucstate, start_record_raw(&page), 0x01);
+        assert_eq!(info, 0x01 | XLR_COMPRESSED);
+        assert!(fpi.len() < page.len() / 4);
+        finish_record(&mut fpi, RmgrId::CSMvcc, info, None);
+        let mut recs = new_records(1);
+        recs.push(fpi);
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path().to_str().unwrap().to_string();
+        let tli = TimeLineID::new(1).unwrap();
+        let startlsn = Lsn::new(20181218).unwrap();
+        let seg = serialize_records(startlsn, None, recs);
+        std::fs::write(format!("{}/{}", dir, wal_filename(tli, startlsn)), &seg).unwrap();
+
+        let storage = Box::new(LocalWalStorage::with_dir(&dir));
+        let mut walreader = WalReader::new(storage, startlsn);
+        let (_, data) = walreader.read_record().unwrap();
+        assert_eq!(data, [0u8; 64]);
+        let fpilsn = walreader.endlsn;
+        let (hdr, data) = walreader.read_record().unwrap();
+        assert!(matches!(hdr.id, RmgrId::CSMvcc));
+        assert_eq!(hdr.info, 0x01);
+        assert!((hdr.totlen as usize) < page.len() / 4);
+        assert_eq!(data, page);
+
+        // The raw record is kept as it is written.
+        walreader.rescan(fpilsn);
+        let (hdr, data) = walreader.read_raw_record().unwrap();
+        assert_eq!(hdr.info, 0x01 | XLR_COMPRESSED);
+        assert_eq!(data.len(), hdr.totlen as usize);
     }
 }
 
@@ -547,17 +953,23 @@ pub type TimeLineID = NonZeroU32;
 
 struct WritingWalFile {
     fd: File,
+    sync_method: SyncMethod,
     start_lsn: Lsn,
     write: &'static Progress,
     flush: &'static Progress,
 }
 
+pub const WAL_DIR: &str = "kb_wal";
 const WAL_FILENAME_LEN: usize = 8 + 16 + 4;
-fn wal_filepath(tli: TimeLineID, lsn: Lsn) -> String {
-    format!("kb_wal/{:0>8X}{:0>16X}.wal", tli, lsn)
+pub fn wal_filename(tli: TimeLineID, lsn: Lsn) -> String {
+    format!("{:0>8X}{:0>16X}.wal", tli, lsn)
+}
+
+fn wal_filepath(dir: &str, tli: TimeLineID, lsn: Lsn) -> String {
+    format!("{}/{}", dir, wal_filename(tli, lsn))
 }
 
-fn is_wal(filename: &[u8]) -> bool {
+pub fn is_wal(filename: &[u8]) -> bool {
     filename.len() == WAL_FILENAME_LEN && filename.ends_with(&[b'.', b'w', b'a', b'l'])
 }
 
@@ -573,25 +985,25 @@ fn parse_lsn(v: &[u8]) -> Lsn {
     Lsn::new(n).unwrap()
 }
 
-fn parse_wal_filename(filename: &[u8]) -> (TimeLineID, Lsn) {
+pub fn parse_wal_filename(filename: &[u8]) -> (TimeLineID, Lsn) {
     debug_assert!(is_wal(filename));
     (parse_tli(&filename[..8]), parse_lsn(&filename[8..24]))
 }
 
 #[cfg(test)]
 mod parse_wal_filepath_test {
-    use super::{parse_wal_filename, wal_filepath, Lsn, TimeLineID};
+    use super::{parse_wal_filename, wal_filepath, Lsn, TimeLineID, WAL_DIR};
     #[test]
     fn f() {
         let tli = TimeLineID::new(0x20181218).unwrap();
         let lsn = Lsn::new(0x2013020320181218).unwrap();
-        let fp = wal_filepath(tli, lsn);
+        let fp = wal_filepath(WAL_DIR, tli, lsn);
         assert_eq!(fp, "kb_wal/201812182013020320181218.wal");
         assert_eq!(parse_wal_filename(&fp.as_bytes()[7..]), (tli, lsn));
 
         let tli = TimeLineID::new(1).unwrap();
         let lsn = Lsn::new(20181218).unwrap();
-        let fp = wal_filepath(tli, lsn);
+        let fp = wal_filepath(WAL_DIR, tli, lsn);
         assert_eq!(fp, "kb_wal/00000001000000000133F0E2.wal");
         assert_eq!(parse_wal_filename(&fp.as_bytes()[7..]), (tli, lsn));
     }
@@ -599,29 +1011,38 @@ mod parse_wal_filepath_test {
 
 impl WritingWalFile {
     fn new(
+        dir: &str,
         tli: TimeLineID,
         lsn: Lsn,
+        sync_method: SyncMethod,
         write: &'static Progress,
         flush: &'static Progress,
     ) -> std::io::Result<WritingWalFile> {
         Ok(WritingWalFile {
-            fd: WritingWalFile::open_file(tli, lsn)?,
+            fd: WritingWalFile::open_file(dir, tli, lsn, sync_method)?,
+            sync_method,
             start_lsn: lsn,
             write,
             flush,
         })
     }
 
-    fn open_file(tli: TimeLineID, lsn: Lsn) -> std::io::Result<File> {
+    fn open_file(
+        dir: &str,
+        tli: TimeLineID,
+        lsn: Lsn,
+        sync_method: SyncMethod,
+    ) -> std::io::Result<File> {
         OpenOptions::new()
             .create(true)
             .truncate(true)
             .write(true)
-            .open(wal_filepath(tli, lsn))
+            .custom_flags(sync_open_flags(sync_method))
+            .open(wal_filepath(dir, tli, lsn))
     }
 
     fn fsync(&self, end_lsn: u64) -> std::io::Result<()> {
-        self.fd.sync_data()?;
+        sync_file(&self.fd, self.sync_method)?;
         let start_lsn = self.start_lsn.get();
         self.flush.done(start_lsn, end_lsn);
         Ok(())
@@ -672,49 +1093,43 @@ impl RecordHdr {
     }
 }
 
-#[repr(C, packed(1))]
-struct RecordHdrSer {
-    totlen: u32,
-    info: u8,
-    id: u8,
-    xid: u64,
-    prev: u64,
-    crc32c: u32,
-}
-const RECHDRLEN: usize = size_of::<RecordHdrSer>();
-
-fn mut_hdr(d: &mut [u8]) -> &mut RecordHdrSer {
-    unsafe { &mut *(d.as_mut_ptr() as *mut RecordHdrSer) }
+// The bit of RecordHdr.info set if the body of the record is compressed by wal_compression, it
+// is cleared by WalReader::read_record() after the body is decompressed, so the rmgrs never
+// see it.
+pub const XLR_COMPRESSED: u8 = 0x08;
+
+// XLogRecord, in little-endian: totlen u32, info u8, id u8, xid u64, prev u64, crc32c u32.
+const RECHDR_RMGR_OFF: usize = 5;
+const RECHDR_XID_OFF: usize = 6;
+const RECHDR_PREV_OFF: usize = 14;
+const RECHDR_CRC_OFF: usize = 22;
+const RECHDRLEN: usize = RECHDR_CRC_OFF + 4;
+
+// The record is in a Vec<u8> and its data follows the header of RECHDRLEN bytes, so the readers
+// take the fields out of the bytes by byteorder, never by casting the pointer to a struct.
+fn hdr(d: &[u8]) -> RecordHdr {
+    RecordHdr {
+        totlen: LittleEndian::read_u32(&d[0..]),
+        info: d[4],
+        id: d[RECHDR_RMGR_OFF].into(),
+        xid: Xid::new(LittleEndian::read_u64(&d[RECHDR_XID_OFF..])),
+        prev: Lsn::new(LittleEndian::read_u64(&d[RECHDR_PREV_OFF..])),
+    }
 }
 
-fn hdr(d: &[u8]) -> &RecordHdrSer {
-    unsafe { &*(d.as_ptr() as *mut RecordHdrSer) }
+fn hdr_crc(d: &[u8]) -> u32 {
+    LittleEndian::read_u32(&d[RECHDR_CRC_OFF..])
 }
 
 fn hdr_crc_area(rec: &[u8]) -> &[u8] {
-    &rec[..offset_of!(RecordHdrSer, crc32c)]
+    &rec[..RECHDR_CRC_OFF]
 }
 
 fn data_area(rec: &[u8]) -> &[u8] {
     &rec[RECHDRLEN..]
 }
 
-impl std::convert::From<&RecordHdrSer> for RecordHdr {
-    fn from(f: &RecordHdrSer) -> Self {
-        RecordHdr {
-            totlen: f.totlen,
-            info: f.info,
-            id: f.id.into(),
-            xid: Xid::new(f.xid),
-            prev: Lsn::new(f.prev),
-        }
-    }
-}
-
-pub fn start_record<T>(val: &T) -> Vec<u8> {
-    start_record_raw(as_bytes(val))
-}
-
+// The record data is serialized by ser::ser_le_xxx after the header.
 pub fn start_record_raw(val: &[u8]) -> Vec<u8> {
     let mut record = Vec::<u8>::with_capacity(RECHDRLEN + val.len());
     record.resize(RECHDRLEN, 0);
@@ -722,6 +1137,30 @@ pub fn start_record_raw(val: &[u8]) -> Vec<u8> {
     return record;
 }
 
+// XLogCompressBackupBlock, the body of the record started by start_record_raw() is compressed
+// if wal_compression is on and the body is long enough. The compressed body is the length of
+// the raw body in u32 little-endian followed by the pglz output, XLR_COMPRESSED is set in the
+// info returned.
+pub fn compress_record(gucstate: &GucState, rec: Vec<u8>, info: u8) -> (Vec<u8>, u8) {
+    let body = &rec[RECHDRLEN..];
+    if !guc::get_bool(gucstate, guc::WalCompression)
+        || body.len() < guc::get_int(gucstate, guc::WalCompressionThreshold).max(0) as usize
+        || body.len() > u32::MAX as usize
+    {
+        return (rec, info);
+    }
+    match pglz::compress(body) {
+        None => (rec, info),
+        Some(compressed) => {
+            let mut newrec = start_record_raw(&[]);
+            ser::ser_le_u32(&mut newrec, body.len() as u32);
+            newrec.extend_from_slice(&compressed);
+            (newrec, info | XLR_COMPRESSED)
+        }
+    }
+}
+
+// The crc is computed over the body as it is written, that is, after the compression.
 pub fn finish_record(d: &mut [u8], id: RmgrId, info: u8, xid: Option<Xid>) {
     let len = d.len();
     assert!(
@@ -733,17 +1172,16 @@ pub fn finish_record(d: &mut [u8], id: RmgrId, info: u8, xid: Option<Xid>) {
         xid
     );
     let crc = crc32c::crc32c(data_area(d));
-    let len = len as u32;
-    let hdr = mut_hdr(d);
-    hdr.totlen = len;
-    hdr.info = info;
-    hdr.id = id as u8;
-    hdr.xid = match xid {
+    LittleEndian::write_u32(&mut d[0..], len as u32);
+    d[4] = info;
+    d[RECHDR_RMGR_OFF] = id as u8;
+    let xid = match xid {
         None => 0,
         Some(x) => x.get(),
     };
-    hdr.prev = 0;
-    hdr.crc32c = crc;
+    LittleEndian::write_u64(&mut d[RECHDR_XID_OFF..], xid);
+    LittleEndian::write_u64(&mut d[RECHDR_PREV_OFF..], 0);
+    LittleEndian::write_u32(&mut d[RECHDR_CRC_OFF..], crc);
     return;
 }
 
@@ -817,16 +1255,18 @@ impl InsertState {
         writereq
     }
 
+    // The body crc has been computed in finish_record() without holding the insert lock,
+    // fill_record() only needs to append the header area, so its cost does not depend on
+    // the size of record.
     fn fill_record(record: &mut RecordBuff, prevlsn: Option<Lsn>) {
-        let hdr = mut_hdr(record.as_mut_slice());
-        hdr.prev = match prevlsn {
+        let prev = match prevlsn {
             None => 0,
             Some(p) => p.get(),
         };
-        let bodycrc = hdr.crc32c;
+        LittleEndian::write_u64(&mut record[RECHDR_PREV_OFF..], prev);
+        let bodycrc = hdr_crc(record);
         let crc = crc32c::crc32c_append(bodycrc, hdr_crc_area(record));
-        let hdr = mut_hdr(record.as_mut_slice());
-        hdr.crc32c = crc;
+        LittleEndian::write_u32(&mut record[RECHDR_CRC_OFF..], crc);
     }
 
     // Remeber we are locking, so be quick.
@@ -868,11 +1308,28 @@ impl InsertState {
 // Since flush will be referenced by insert.file, for convenience, we make it as a static variable,
 // otherwise, facilities like Pin + unsafe will be used.
 pub struct GlobalStateExt {
+    // The directory of the wal files, WAL_DIR except in tests.
+    dir: String,
     // redo is the value of insert.redo at a past time.
     redo: AtomicU64,
     insert: Mutex<InsertState>,
     write: &'static Progress,
     flush: &'static Progress,
+    sync_method: SyncMethod,
+    // wal_records, wal_fpi, wal_bytes and wal_sync of pg_stat_wal.
+    nrecords: AtomicU64,
+    nfpi: AtomicU64,
+    nbytes: AtomicU64,
+    nsync: AtomicU64,
+}
+
+// A snapshot of the counters of pg_stat_wal since the server started.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct WalStats {
+    pub records: u64,
+    pub fpi: u64,
+    pub bytes: u64,
+    pub sync: u64,
 }
 
 enum FlushAction {
@@ -884,21 +1341,31 @@ enum FlushAction {
 
 impl GlobalStateExt {
     // We make the type of return value as a static ref to tell the caller that
-    // you should call this method only once.
-    fn new(
+    // you should call this method only once. The wal files are written in the timeline tli,
+    // which is a new timeline after the recovery target is reached.
+    pub fn new(
+        dir: &str,
         tli: TimeLineID,
         lsn: Lsn,
         prevlsn: Option<Lsn>,
         redo: Lsn,
         wal_buff_max_size: usize,
         wal_file_max_size: u64,
+        sync_method: SyncMethod,
     ) -> std::io::Result<&'static GlobalStateExt> {
         let flush: &'static Progress = make_static(Progress::new(lsn.get()));
         let write: &'static Progress = make_static(Progress::new(lsn.get()));
+        let file = WritingWalFile::new(dir, tli, lsn, sync_method, write, flush)?;
         Ok(make_static(GlobalStateExt {
+            dir: dir.to_string(),
             redo: AtomicU64::new(redo.get()),
             write,
             flush,
+            sync_method,
+            nrecords: AtomicU64::new(0),
+            nfpi: AtomicU64::new(0),
+            nbytes: AtomicU64::new(0),
+            nsync: AtomicU64::new(0),
             insert: Mutex::new(InsertState {
                 wal_buff_max_size,
                 wal_file_max_size,
@@ -909,7 +1376,7 @@ impl GlobalStateExt {
                 buflsn: lsn,
                 bufsize: 0,
                 forcesync: false,
-                file: Some(Arc::new(WritingWalFile::new(tli, lsn, write, flush)?)),
+                file: Some(Arc::new(file)),
             }),
         }))
     }
@@ -920,8 +1387,24 @@ impl GlobalStateExt {
         insert
     }
 
+    // The record is counted before it is inserted, the length of the record is not changed
+    // by the insertion.
+    fn insert(&self, state: &mut InsertState, r: RecordBuff) -> InsertRet {
+        self.nrecords.fetch_add(1, Ordering::Relaxed);
+        self.nbytes.fetch_add(r.len() as u64, Ordering::Relaxed);
+        state.insert(r)
+    }
+
     fn do_create(&self, tli: TimeLineID, retlsn: Lsn) {
-        let file = WritingWalFile::new(tli, retlsn, self.write, self.flush).unwrap();
+        let file = WritingWalFile::new(
+            &self.dir,
+            tli,
+            retlsn,
+            self.sync_method,
+            self.write,
+            self.flush,
+        )
+        .unwrap();
         let file = Arc::new(file);
         let wreq = {
             let mut insert = self.get_insert_state();
@@ -961,18 +1444,42 @@ impl GlobalStateExt {
     pub fn insert_record(&self, r: RecordBuff) -> Lsn {
         let insert_res = {
             let mut state = self.get_insert_state();
-            state.insert(r)
+            self.insert(&mut state, r)
         };
         self.handle_insert_ret(insert_res)
     }
 
+    // Insert all records while holding the insert lock only once, so the records are
+    // continuous in wal. Each record should have been finished by finish_record(), so the crc
+    // of the record body is not computed under the lock. Returns the end lsn of the last record.
+    pub fn insert_records(&self, rs: Vec<RecordBuff>) -> Option<Lsn> {
+        let mut retlsn = None;
+        let insert_rets = {
+            let mut state = self.get_insert_state();
+            let mut insert_rets = Vec::new();
+            for r in rs {
+                match self.insert(&mut state, r) {
+                    InsertRet::NoAction(lsn) => retlsn = Some(lsn),
+                    ret => insert_rets.push(ret),
+                }
+            }
+            insert_rets
+        };
+        // At most one WriteAndCreate exists since insert.file will be None after it.
+        for ret in insert_rets {
+            let lsn = self.handle_insert_ret(ret);
+            retlsn = retlsn.max(Some(lsn));
+        }
+        return retlsn;
+    }
+
     pub fn try_insert_record(&self, r: RecordBuff, page_lsn: Lsn) -> Option<Lsn> {
         let insert_res = {
             let mut state = self.get_insert_state();
             if page_lsn <= state.redo {
                 return None;
             }
-            state.insert(r)
+            self.insert(&mut state, r)
         };
         Some(self.handle_insert_ret(insert_res))
     }
@@ -1010,7 +1517,9 @@ impl GlobalStateExt {
             // and that invocation may succeed. If we return an error here, not panic,
             // this may cause a transaction to be considered aborted, but all wal records
             // of this transaction have been flushed successfully.
+            crash_point(CrashPoint::WalWrite);
             file.fsync(lsnval).unwrap();
+            self.nsync.fetch_add(1, Ordering::Relaxed);
         }
         self.flush.wait(lsnval);
     }
@@ -1035,9 +1544,330 @@ impl GlobalStateExt {
         }
     }
 
+    // The end lsn of the last inserted record.
+    pub fn insert_lsn(&self) -> Lsn {
+        self.get_insert_state().nextlsn()
+    }
+
+    // LogwrtResult.Write
+    pub fn write_lsn(&self) -> u64 {
+        self.write.get()
+    }
+
+    // GetFlushRecPtr
+    pub fn flush_lsn(&self) -> u64 {
+        self.flush.get()
+    }
+
+    // Set the redo lsn of a new checkpoint to the current insert lsn, the page modified after
+    // it will be logged in full at the first modification.
+    pub fn start_ckpt(&self) -> Lsn {
+        let mut state = self.insert.lock().unwrap();
+        state.redo = state.nextlsn();
+        self.redo.store(state.redo.get(), Ordering::Relaxed);
+        state.redo
+    }
+
+    pub fn curtli(&self) -> TimeLineID {
+        self.get_insert_state().curtimeline
+    }
+
     pub fn recently_redo_lsn(&self) -> Lsn {
         Lsn::new(self.redo.load(Ordering::Relaxed)).unwrap()
     }
+
+    // The full page images are inserted as the usual records by the rmgrs, so they are
+    // counted by the rmgrs themselves.
+    pub fn count_fpi(&self) {
+        self.nfpi.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // The control file is synced in the same way as the wal.
+    pub fn sync_method(&self) -> SyncMethod {
+        self.sync_method
+    }
+
+    pub fn stats(&self) -> WalStats {
+        WalStats {
+            records: self.nrecords.load(Ordering::Relaxed),
+            fpi: self.nfpi.load(Ordering::Relaxed),
+            bytes: self.nbytes.load(Ordering::Relaxed),
+            sync: self.nsync.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// Serialize the records that are finished by finish_record() as they would be written
+// into the wal file starting at startlsn.
+#[cfg(test)]
+pub fn serialize_records(
+    startlsn: Lsn,
+    mut prevlsn: Option<Lsn>,
+    recs: Vec<RecordBuff>,
+) -> Vec<u8> {
+    let mut out = Vec::new();
+    for mut rec in recs {
+        InsertState::fill_record(&mut rec, prevlsn);
+        prevlsn = Lsn::new(startlsn.get() + out.len() as u64);
+        out.extend_from_slice(&rec);
+    }
+    out
+}
+
+// The header and the data of the record finished by finish_record(), just as the redo gets them.
+#[cfg(test)]
+pub fn parse_record(rec: &[u8]) -> (RecordHdr, &[u8]) {
+    let data = data_area(rec);
+    assert_eq!(hdr_crc(rec), crc32c::crc32c(data));
+    (hdr(rec), data)
+}
+
+// Call f with the copies of d at every address modulo 8, so that the readers are checked against
+// the input that is not aligned.
+#[cfg(test)]
+pub fn for_each_misaligned(d: &[u8], mut f: impl FnMut(&[u8])) {
+    for off in 0..8 {
+        let mut buf = vec![0xffu8; off];
+        buf.extend_from_slice(d);
+        f(&buf[off..]);
+    }
+}
+
+#[cfg(test)]
+mod record_crc_test {
+    use super::{
+        data_area, finish_record, hdr, hdr_crc, hdr_crc_area, start_record_raw, InsertRet,
+        InsertState, Lsn, RmgrId, TimeLineID, Xid,
+    };
+
+    fn check_crc(rec: &[u8]) {
+        let crc = crc32c::crc32c(data_area(rec));
+        let crc = crc32c::crc32c_append(crc, hdr_crc_area(rec));
+        let actual_crc = hdr_crc(rec);
+        assert_eq!(crc, actual_crc);
+    }
+
+    fn new_insert_state() -> InsertState {
+        InsertState {
+            curtimeline: TimeLineID::new(1).unwrap(),
+            wal_buff_max_size: usize::MAX,
+            wal_file_max_size: u64::MAX,
+            redo: Lsn::new(20181218).unwrap(),
+            buf: Vec::new(),
+            buflsn: Lsn::new(20181218).unwrap(),
+            prevlsn: None,
+            bufsize: 0,
+            forcesync: false,
+            file: None,
+        }
+    }
+
+    #[test]
+    fn final_crc() {
+        let mut state = new_insert_state();
+        let mut prevlsn = None;
+        for datlen in &[33usize, 8192, 1 << 20] {
+            let body: Vec<u8> = (0..*datlen).map(|v| v as u8).collect();
+            let mut rec = start_record_raw(&body);
+            finish_record(&mut rec, RmgrId::Xact, 0x10, Xid::new(20181218));
+            let reclen = rec.len() as u64;
+            let reclsn = state.nextlsn();
+            match state.insert(rec) {
+                InsertRet::NoAction(lsn) => assert_eq!(lsn.get(), reclsn.get() + reclen),
+                _ => panic!("unexpected InsertRet"),
+            }
+            let rec = state.buf.last().unwrap();
+            check_crc(rec);
+            assert_eq!(hdr(rec).prev, prevlsn);
+            prevlsn = Some(reclsn);
+        }
+    }
+
+    // fill_record() must not touch the record body, the body crc is computed before
+    // taking the insert lock.
+    #[test]
+    fn body_crc_outside_lock() {
+        let body = vec![0x33u8; 1 << 20];
+        let mut rec = start_record_raw(&body);
+        finish_record(&mut rec, RmgrId::Xact, 0x10, None);
+        let bodycrc = hdr_crc(&rec);
+        let reclen = rec.len();
+        rec[reclen - 1] = 0x77;
+        InsertState::fill_record(&mut rec, Lsn::new(20181218));
+        let crc = crc32c::crc32c_append(bodycrc, hdr_crc_area(&rec));
+        assert_eq!(crc, hdr_crc(&rec));
+    }
+}
+
+#[cfg(test)]
+mod wal_stats_test {
+    use super::{finish_record, start_record_raw, GlobalStateExt, Lsn, RmgrId, TimeLineID};
+    use super::{SyncMethod, WalStats, RECHDRLEN};
+    use crate::guc;
+    use crate::utils::{NFDATASYNC, NFSYNC};
+    use nix::fcntl::OFlag;
+    use std::os::unix::io::AsRawFd;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::atomic::Ordering::Relaxed;
+    use std::sync::Arc;
+
+    fn new_record(id: RmgrId, datlen: usize) -> Vec<u8> {
+        let mut rec = start_record_raw(&vec![0x33u8; datlen]);
+        finish_record(&mut rec, id, 0x10, None);
+        return rec;
+    }
+
+    #[test]
+    fn counters() {
+        let dir = tempfile::tempdir().unwrap();
+        let lsn = Lsn::new(20181218).unwrap();
+        let wal = GlobalStateExt::new(
+            dir.path().to_str().unwrap(),
+            TimeLineID::new(1).unwrap(),
+            lsn,
+            None,
+            lsn,
+            1 << 20,
+            1 << 30,
+            SyncMethod::Fdatasync,
+        )
+        .unwrap();
+        assert_eq!(wal.stats(), WalStats::default());
+
+        wal.insert_record(new_record(RmgrId::Xact, 10));
+        wal.insert_records(vec![new_record(RmgrId::SV, 20), new_record(RmgrId::SV, 30)]);
+        // The page is logged as a whole since it is not changed after the redo.
+        assert!(wal
+            .try_insert_record(new_record(RmgrId::CSMvcc, 40), lsn)
+            .is_none());
+        let endlsn = wal.insert_record(new_record(RmgrId::CSMvcc, 8192));
+        wal.count_fpi();
+        let bytes = (RECHDRLEN * 4 + 10 + 20 + 30 + 8192) as u64;
+        assert_eq!(endlsn.get(), lsn.get() + bytes);
+        let expected = WalStats {
+            records: 4,
+            fpi: 1,
+            bytes,
+            sync: 0,
+        };
+        assert_eq!(wal.stats(), expected);
+
+        // The flushed wal is not synced again.
+        wal.fsync(endlsn);
+        wal.fsync(endlsn);
+        assert_eq!(
+            wal.stats(),
+            WalStats {
+                sync: 1,
+                ..expected
+            }
+        );
+    }
+
+    // The insert lock is held once for all records of insert_records(), so the records
+    // inserted concurrently never get in between them.
+    #[test]
+    fn insert_records_continuous() {
+        let dir = tempfile::tempdir().unwrap();
+        let lsn = Lsn::new(20181218).unwrap();
+        let wal = GlobalStateExt::new(
+            dir.path().to_str().unwrap(),
+            TimeLineID::new(1).unwrap(),
+            lsn,
+            None,
+            lsn,
+            1 << 20,
+            1 << 30,
+            SyncMethod::Fdatasync,
+        )
+        .unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let inserter = {
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                let mut endlsns = Vec::new();
+                while !stop.load(Relaxed) {
+                    endlsns.push(wal.insert_record(new_record(RmgrId::Xact, 10)));
+                }
+                return endlsns;
+            })
+        };
+        let batchlen = (RECHDRLEN + 100) as u64 * 8;
+        let mut batches = Vec::new();
+        for _ in 0..1000 {
+            let rs = (0..8).map(|_| new_record(RmgrId::SV, 100)).collect();
+            let endlsn = wal.insert_records(rs).unwrap().get();
+            batches.push((endlsn - batchlen, endlsn));
+        }
+        stop.store(true, Relaxed);
+        let endlsns = inserter.join().unwrap();
+        for endlsn in &endlsns {
+            let endlsn = endlsn.get();
+            assert!(batches
+                .iter()
+                .all(|&(start, end)| endlsn <= start || endlsn > end));
+        }
+        assert_eq!(wal.stats().records, 8000 + endlsns.len() as u64);
+    }
+
+    // The flags of the open file from /proc/self/fdinfo.
+    fn open_flags(fd: i32) -> i32 {
+        let fdinfo = std::fs::read_to_string(format!("/proc/self/fdinfo/{}", fd)).unwrap();
+        let flags = fdinfo
+            .lines()
+            .find_map(|line| line.strip_prefix("flags:"))
+            .unwrap();
+        return i32::from_str_radix(flags.trim(), 8).unwrap();
+    }
+
+    #[test]
+    fn sync_method() {
+        let dir = tempfile::tempdir().unwrap();
+        let yaml = dir.path().join("kuiba.conf");
+        std::fs::write(&yaml, "wal_sync_method: open_dsync\n").unwrap();
+        let gucstate = guc::load(yaml.to_str().unwrap()).unwrap();
+        assert_eq!(gucstate.wal_sync_method, SyncMethod::OpenDsync);
+        std::fs::write(&yaml, "wal_sync_method: osync\n").unwrap();
+        let gucstate = guc::load(yaml.to_str().unwrap()).unwrap();
+        assert_eq!(gucstate.wal_sync_method, SyncMethod::Fdatasync);
+
+        let methods = [
+            SyncMethod::Fdatasync,
+            SyncMethod::Fsync,
+            SyncMethod::OpenDsync,
+        ];
+        for (idx, &method) in methods.iter().enumerate() {
+            let dir = tempfile::tempdir().unwrap();
+            let lsn = Lsn::new(20181218).unwrap();
+            let wal = GlobalStateExt::new(
+                dir.path().to_str().unwrap(),
+                TimeLineID::new(1).unwrap(),
+                lsn,
+                None,
+                lsn,
+                1 << 20,
+                1 << 30,
+                method,
+            )
+            .unwrap();
+            let fd = {
+                let insert = wal.insert.lock().unwrap();
+                insert.file.as_ref().unwrap().fd.as_raw_fd()
+            };
+            let dsync = open_flags(fd) & OFlag::O_DSYNC.bits() != 0;
+            assert_eq!(dsync, method == SyncMethod::OpenDsync, "idx={}", idx);
+
+            // The sessions running concurrently may also sync their files.
+            let (nfdatasync, nfsync) = (NFDATASYNC.load(Relaxed), NFSYNC.load(Relaxed));
+            wal.fsync(wal.insert_record(new_record(RmgrId::Xact, 10)));
+            assert_eq!(wal.stats().sync, 1);
+            match method {
+                SyncMethod::Fdatasync => assert!(NFDATASYNC.load(Relaxed) > nfdatasync),
+                SyncMethod::Fsync => assert!(NFSYNC.load(Relaxed) > nfsync),
+                SyncMethod::OpenDsync => (),
+            }
+        }
+    }
 }
 
 pub fn init(
@@ -1050,25 +1880,30 @@ pub fn init(
     let wal_buff_max_size = guc::get_int(gucstate, guc::WalBuffMaxSize) as usize;
     let wal_file_max_size = guc::get_int(gucstate, guc::WalFileMaxSize) as u64;
     GlobalStateExt::new(
+        WAL_DIR,
         tli,
         lsn,
         prevlsn,
         redo,
         wal_buff_max_size,
         wal_file_max_size,
+        gucstate.wal_sync_method,
     )
 }
 
 #[repr(u8)]
 #[derive(Copy, Clone, Debug)]
 pub enum XlogInfo {
+    CkptShutdown = 0x00,
     Ckpt = 0x10,
     NextOid = 0x30,
 }
 
 impl From<u8> for XlogInfo {
     fn from(value: u8) -> Self {
-        if value == XlogInfo::Ckpt as u8 {
+        if value == XlogInfo::CkptShutdown as u8 {
+            XlogInfo::CkptShutdown
+        } else if value == XlogInfo::Ckpt as u8 {
             XlogInfo::Ckpt
         } else if value == XlogInfo::NextOid as u8 {
             XlogInfo::NextOid
@@ -1087,7 +1922,7 @@ impl XlogRmgr {
 }
 
 fn get_oid(d: &[u8]) -> Oid {
-    unsafe { *(d.as_ptr() as *const Oid) }
+    Oid::new(LittleEndian::read_u32(d)).unwrap()
 }
 
 impl Rmgr for XlogRmgr {
@@ -1097,6 +1932,14 @@ impl Rmgr for XlogRmgr {
 
     fn redo(&mut self, hdr: &RecordHdr, data: &[u8], state: &mut RedoState) -> anyhow::Result<()> {
         match hdr.rmgr_info().into() {
+            // Nothing is written after the clean shutdown, so the counters are exact.
+            XlogInfo::CkptShutdown => {
+                let ckpt = get_ckpt(data);
+                log::info!("redo: clean shutdown checkpoint. redo={}", ckpt.redo);
+                state.set_nextxid(ckpt.nextxid);
+                state.set_nextoid(ckpt.nextoid);
+                Ok(())
+            }
             XlogInfo::Ckpt => {
                 let ckpt = get_ckpt(data);
                 state.set_nextxid(ckpt.nextxid);
@@ -1112,6 +1955,10 @@ impl Rmgr for XlogRmgr {
 
     fn desc(&self, out: &mut String, hdr: &RecordHdr, data: &[u8]) {
         match hdr.rmgr_info().into() {
+            XlogInfo::CkptShutdown => {
+                let ckpt = get_ckpt(data);
+                write!(out, "CHECKPOINT_SHUTDOWN {:?}", ckpt).unwrap();
+            }
             XlogInfo::Ckpt => {
                 let ckpt = get_ckpt(data);
                 write!(out, "CHECKPOINT {:?}", ckpt).unwrap();
@@ -1123,3 +1970,139 @@ impl Rmgr for XlogRmgr {
         }
     }
 }
+
+// The records and the control file are in little-endian, the expected bytes are spelled out so
+// that the layout is the same on any host.
+#[cfg(test)]
+mod record_ser_test {
+    use super::{
+        finish_record, for_each_misaligned, get_ckpt, get_oid, hdr, hdr_crc, new_ckpt_rec,
+        parse_record, start_record_raw, Ckpt, Ctl, DbState, Lsn, RmgrId, TimeLineID, XlogInfo,
+        CKPTLEN, CTLLEN, KB_CAT_VER, KB_CTL_VER, RECHDRLEN,
+    };
+    use crate::utils::{KBSystemTime, Xid};
+    use crate::Oid;
+
+    fn new_ckpt() -> Ckpt {
+        Ckpt {
+            redo: Lsn::new(0x2018121820130203).unwrap(),
+            curtli: TimeLineID::new(3).unwrap(),
+            prevtli: TimeLineID::new(2).unwrap(),
+            nextxid: Xid::new(0x1122334455667788).unwrap(),
+            nextoid: Oid::new(0x99aabbcc).unwrap(),
+            time: KBSystemTime::from(0x604b1e28),
+        }
+    }
+
+    const CKPT_BYTES: [u8; CKPTLEN] = [
+        0x03, 0x02, 0x13, 0x20, 0x18, 0x12, 0x18, 0x20, // redo
+        0x03, 0x00, 0x00, 0x00, // curtli
+        0x02, 0x00, 0x00, 0x00, // prevtli
+        0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11, // nextxid
+        0xcc, 0xbb, 0xaa, 0x99, // nextoid
+        0x28, 0x1e, 0x4b, 0x60, 0x00, 0x00, 0x00, 0x00, // time
+    ];
+
+    fn assert_ckpt_eq(l: &Ckpt, r: &Ckpt) {
+        assert_eq!(l.redo, r.redo);
+        assert_eq!(l.curtli, r.curtli);
+        assert_eq!(l.prevtli, r.prevtli);
+        assert_eq!(l.nextxid, r.nextxid);
+        assert_eq!(l.nextoid, r.nextoid);
+        assert_eq!(u64::from(l.time), u64::from(r.time));
+    }
+
+    #[test]
+    fn record_hdr() {
+        assert_eq!(RECHDRLEN, 26);
+        let mut rec = start_record_raw(&[0x33; 7]);
+        finish_record(&mut rec, RmgrId::SV, 0x20, Xid::new(0x0102030405060708));
+        assert_eq!(&rec[..4], &[33, 0, 0, 0]);
+        assert_eq!(rec[4], 0x20);
+        assert_eq!(rec[5], RmgrId::SV as u8);
+        assert_eq!(&rec[6..14], &[8, 7, 6, 5, 4, 3, 2, 1]);
+        assert_eq!(&rec[14..22], &[0; 8]);
+        let crc = crc32c::crc32c(&[0x33; 7]);
+        assert_eq!(&rec[22..26], &crc.to_le_bytes());
+        assert_eq!(hdr(&rec).prev, None);
+        let (h, data) = parse_record(&rec);
+        assert_eq!(h.totlen, 33);
+        assert_eq!(h.rmgr_info(), 0x20);
+        assert!(matches!(h.id, RmgrId::SV));
+        assert_eq!(h.xid, Xid::new(0x0102030405060708));
+        assert_eq!(h.prev, None);
+        assert_eq!(data, [0x33; 7]);
+    }
+
+    #[test]
+    fn ckpt() {
+        let ckpt = new_ckpt();
+        let mut rec = new_ckpt_rec(&ckpt);
+        finish_record(&mut rec, RmgrId::Xlog, XlogInfo::Ckpt as u8, None);
+        let (h, data) = parse_record(&rec);
+        assert_eq!(h.rmgr_info(), XlogInfo::Ckpt as u8);
+        assert_eq!(data, &CKPT_BYTES[..]);
+        assert_ckpt_eq(&get_ckpt(data), &ckpt);
+    }
+
+    #[test]
+    fn nextoid() {
+        let mut rec = start_record_raw(&[0x18, 0x12, 0x18, 0x20]);
+        finish_record(&mut rec, RmgrId::Xlog, XlogInfo::NextOid as u8, None);
+        let (_, data) = parse_record(&rec);
+        assert_eq!(get_oid(data).get(), 0x20181218);
+    }
+
+    #[test]
+    fn ctl() {
+        assert_eq!(CTLLEN, 68);
+        let mut ctl = Ctl::new(Lsn::new(0x2013020320181218).unwrap(), new_ckpt());
+        assert_eq!(ctl.state, DbState::InProduction);
+        ctl.state = DbState::Shutdowned;
+        let d = ctl.serialize();
+        assert_eq!(d.len(), CTLLEN);
+        assert_eq!(&d[0..4], &KB_CTL_VER.to_le_bytes());
+        assert_eq!(&d[4..8], &KB_CAT_VER.to_le_bytes());
+        assert_eq!(&d[8..16], &u64::from(ctl.time).to_le_bytes());
+        assert_eq!(
+            &d[16..24],
+            &[0x18, 0x12, 0x18, 0x20, 0x03, 0x02, 0x13, 0x20]
+        );
+        assert_eq!(&d[24..60], &CKPT_BYTES[..]);
+        assert_eq!(&d[60..64], &[0x01, 0x00, 0x00, 0x00]);
+        assert_eq!(&d[64..68], &crc32c::crc32c(&d[..64]).to_le_bytes());
+        let ctl2 = Ctl::deserialize(&d).unwrap();
+        assert_eq!(ctl2.state, DbState::Shutdowned);
+        assert_eq!(ctl2.ckpt, ctl.ckpt);
+        assert_eq!(u64::from(ctl2.time), u64::from(ctl.time));
+        assert_ckpt_eq(&ctl2.ckptcpy, &ctl.ckptcpy);
+
+        let mut corrupted = d.clone();
+        corrupted[30] ^= 1;
+        assert!(Ctl::deserialize(&corrupted).is_err());
+        assert!(Ctl::deserialize(&d[..CTLLEN - 1]).is_err());
+    }
+
+    #[test]
+    fn misaligned() {
+        let ckpt = new_ckpt();
+        let mut rec = new_ckpt_rec(&ckpt);
+        finish_record(&mut rec, RmgrId::Xlog, XlogInfo::Ckpt as u8, Xid::new(33));
+        for_each_misaligned(&rec, |rec| {
+            let h = hdr(rec);
+            assert_eq!(h.totlen as usize, RECHDRLEN + CKPTLEN);
+            assert_eq!(h.xid, Xid::new(33));
+            assert_eq!(hdr_crc(rec), crc32c::crc32c(&CKPT_BYTES));
+            assert_ckpt_eq(&get_ckpt(&rec[RECHDRLEN..]), &ckpt);
+        });
+        for_each_misaligned(&[0x18, 0x12, 0x18, 0x20], |d| {
+            assert_eq!(get_oid(d).get(), 0x20181218);
+        });
+        let ctl = Ctl::new(Lsn::new(0x2013020320181218).unwrap(), new_ckpt());
+        for_each_misaligned(&ctl.serialize(), |d| {
+            let ctl2 = Ctl::deserialize(d).unwrap();
+            assert_eq!(ctl2.ckpt, ctl.ckpt);
+            assert_ckpt_eq(&ctl2.ckptcpy, &ctl.ckptcpy);
+        });
+    }
+}
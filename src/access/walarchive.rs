@@ -0,0 +1,234 @@
+// Copyright 2020 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::guc::{self, GucState};
+use crate::utils::sync_dir;
+use anyhow::anyhow;
+use std::fs::{self, File};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    Off,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    pub fn parse(v: &str) -> anyhow::Result<Compression> {
+        match v {
+            "" | "off" => Ok(Compression::Off),
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(anyhow!("invalid archive_compression. val={}", v)),
+        }
+    }
+
+    // The suffix of the archived file tells how it is compressed.
+    pub fn suffix(self) -> &'static str {
+        match self {
+            Compression::Off => "",
+            Compression::Gzip => ".gz",
+            Compression::Zstd => ".zst",
+        }
+    }
+
+    // The compression command is looked up when archive_compression is loaded, otherwise the
+    // missing command is only found when the first wal file is archived at the checkpoint.
+    pub fn check_command(self) -> anyhow::Result<()> {
+        let name = match self {
+            Compression::Off => return Ok(()),
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        };
+        let found = Command::new(name)
+            .arg("--version")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .map_or(false, |status| status.success());
+        if !found {
+            return Err(anyhow!("the {} command is not found", name));
+        }
+        return Ok(());
+    }
+
+    // Most of a wal file is the sparsely-written tail, the gzip and zstd commands are
+    // good enough for it, and the archive can be read by the usual tools.
+    fn run(self, decompress: bool, src: &Path, dest: &Path) -> anyhow::Result<()> {
+        let mut cmd = match self {
+            Compression::Off => {
+                fs::copy(src, dest)?;
+                return Ok(());
+            }
+            Compression::Gzip => Command::new("gzip"),
+            Compression::Zstd => {
+                let mut cmd = Command::new("zstd");
+                cmd.arg("-q");
+                cmd
+            }
+        };
+        if decompress {
+            cmd.arg("-d");
+        }
+        let status = cmd
+            .arg("-c")
+            .stdin(File::open(src)?)
+            .stdout(File::create(dest)?)
+            .status()?;
+        if !status.success() {
+            return Err(anyhow!(
+                "compression command failed. cmd={:?} src={:?} status={}",
+                cmd,
+                src,
+                status
+            ));
+        }
+        return Ok(());
+    }
+}
+
+// The wal file named walname is archived as <dir>/<walname><suffix>.
+pub struct WalArchiver {
+    dir: String,
+    compression: Compression,
+}
+
+impl WalArchiver {
+    pub fn new(dir: &str, compression: Compression) -> WalArchiver {
+        WalArchiver {
+            dir: dir.to_string(),
+            compression,
+        }
+    }
+
+    // None if archive_dir is empty.
+    pub fn from_guc(gucstate: &GucState) -> anyhow::Result<Option<WalArchiver>> {
+        let dir = guc::get_str(gucstate, guc::ArchiveDir);
+        if dir.is_empty() {
+            return Ok(None);
+        }
+        let compression = Compression::parse(guc::get_str(gucstate, guc::ArchiveCompression))?;
+        return Ok(Some(WalArchiver::new(dir, compression)));
+    }
+
+    // XLogArchiveCommand()
+    pub fn archive(&self, path: &Path) -> anyhow::Result<String> {
+        let walname = path
+            .file_name()
+            .and_then(|v| v.to_str())
+            .ok_or_else(|| anyhow!("WalArchiver::archive: invalid wal file. path={:?}", path))?;
+        let target = format!("{}/{}{}", self.dir, walname, self.compression.suffix());
+        let tmppath = format!("{}.tmp", target);
+        self.compression.run(false, path, Path::new(&tmppath))?;
+        File::open(&tmppath)?.sync_data()?;
+        fs::rename(&tmppath, &target)?;
+        sync_dir(&self.dir)?;
+        log::info!(
+            "WalArchiver::archive: archive the wal file. path={:?} target={}",
+            path,
+            target
+        );
+        return Ok(target);
+    }
+
+    // RestoreArchivedFile(), the archived file is decompressed according to its suffix.
+    // Return false if the wal file is not archived.
+    pub fn restore(&self, walname: &str, dest: &Path) -> anyhow::Result<bool> {
+        for &compression in &[Compression::Off, Compression::Gzip, Compression::Zstd] {
+            let src = format!("{}/{}{}", self.dir, walname, compression.suffix());
+            if !Path::new(&src).exists() {
+                continue;
+            }
+            let tmppath = format!("{}.tmp", dest.display());
+            compression.run(true, Path::new(&src), Path::new(&tmppath))?;
+            File::open(&tmppath)?.sync_data()?;
+            fs::rename(&tmppath, dest)?;
+            log::info!(
+                "WalArchiver::restore: restore the wal file. src={} dest={:?}",
+                src,
+                dest
+            );
+            return Ok(true);
+        }
+        return Ok(false);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Compression, WalArchiver};
+    use crate::access::wal::{wal_filename, LocalWalStorage, Lsn, TimeLineID, WalStorage};
+    use crate::guc;
+
+    #[test]
+    fn archive_restore() {
+        let waldir = tempfile::tempdir().unwrap();
+        let waldir = waldir.path().to_str().unwrap().to_string();
+        let tli = TimeLineID::new(1).unwrap();
+        let mut seg = vec![0u8; 64 * 1024];
+        for (idx, v) in seg.iter_mut().take(1000).enumerate() {
+            *v = (idx * 7) as u8;
+        }
+        let startlsn = Lsn::new(20181218).unwrap();
+        let nextlsn = Lsn::new(startlsn.get() + seg.len() as u64).unwrap();
+        let walname = wal_filename(tli, startlsn);
+        for compression in &[Compression::Off, Compression::Gzip, Compression::Zstd] {
+            if let Err(err) = compression.check_command() {
+                eprintln!("archive_restore: skip {:?}. err={}", compression, err);
+                continue;
+            }
+            let archivedir = tempfile::tempdir().unwrap();
+            let archivedir = archivedir.path().to_str().unwrap().to_string();
+            std::fs::write(format!("{}/{}", waldir, walname), &seg).unwrap();
+            std::fs::write(format!("{}/{}", waldir, wal_filename(tli, nextlsn)), &seg).unwrap();
+            let mut storage = LocalWalStorage::with_dir(&waldir);
+            storage.set_archiver(Some(WalArchiver::new(&archivedir, *compression)));
+            storage.remove_before(nextlsn).unwrap();
+            assert!(!std::path::Path::new(&format!("{}/{}", waldir, walname)).exists());
+
+            let archived: Vec<_> = std::fs::read_dir(&archivedir)
+                .unwrap()
+                .map(|e| e.unwrap().file_name().into_string().unwrap())
+                .collect();
+            assert_eq!(archived, [format!("{}{}", walname, compression.suffix())]);
+            let archived = std::fs::read(format!("{}/{}", archivedir, archived[0])).unwrap();
+            if *compression != Compression::Off {
+                assert!(archived.len() < seg.len() / 10);
+            }
+
+            let archiver = WalArchiver::new(&archivedir, Compression::Off);
+            let dest = format!("{}/restored", waldir);
+            assert!(archiver.restore(&walname, dest.as_ref()).unwrap());
+            assert_eq!(std::fs::read(&dest).unwrap(), seg);
+            let nextname = wal_filename(tli, nextlsn);
+            assert!(!archiver.restore(&nextname, dest.as_ref()).unwrap());
+        }
+        assert!(Compression::parse("lz4").is_err());
+    }
+
+    // The invalid value and the compression whose command is not found are ignored.
+    #[test]
+    fn archive_compression_guc() {
+        let dir = tempfile::tempdir().unwrap();
+        let yaml = dir.path().join("kuiba.conf");
+        for (val, expected) in &[("lz4", "off"), ("gzip", "gzip"), ("zstd", "zstd")] {
+            std::fs::write(&yaml, format!("archive_compression: {}\n", val)).unwrap();
+            let gucstate = guc::load(yaml.to_str().unwrap()).unwrap();
+            let compression = Compression::parse(val).unwrap_or(Compression::Off);
+            let expected = match compression.check_command() {
+                Ok(()) => *expected,
+                Err(_) => "off",
+            };
+            assert_eq!(guc::get_str(&gucstate, guc::ArchiveCompression), expected);
+        }
+    }
+}
@@ -12,16 +12,23 @@ use super::clog::XidStatus;
 use super::redo::RedoState;
 use super::wal::{self, Lsn, RecordHdr, Rmgr, RmgrId, XlogInfo};
 use crate::access::lmgr::SessionExt as LMGRSessionExt;
-use crate::kbbail;
-use crate::protocol::XactStatus;
-use crate::utils::{dec_xid, inc_xid, KBSystemTime, SessionState, WorkerState, Xid};
+use crate::guc::NoticeLevel;
+use crate::{kbbail, kbensure};
+use crate::protocol::{
+    XactStatus, ERRCODE_ACTIVE_SQL_TRANSACTION, ERRCODE_NO_ACTIVE_SQL_TRANSACTION,
+};
+use crate::utils::crashpoint::{crash_point, CrashPoint};
+use crate::utils::{
+    dec_xid, inc_xid, logger, ser, KBSystemTime, SessionState, WorkerState, Xid, FROZEN_XID,
+};
 use crate::Oid;
 use anyhow::{anyhow, bail};
+use byteorder::{ByteOrder, LittleEndian};
 use log;
 use std::borrow::Borrow;
 use std::collections::{BTreeMap, BTreeSet, HashSet};
 use std::fmt::Write;
-use std::sync::{atomic::AtomicU32, atomic::Ordering::Relaxed, RwLock};
+use std::sync::{atomic::AtomicU32, atomic::AtomicU64, atomic::Ordering::Relaxed, RwLock};
 
 struct BTreeMultiSet<T: Ord> {
     d: BTreeMap<T, u32>,
@@ -71,6 +78,9 @@ pub struct GlobalStateExt {
     running: RwLock<RunningXactState>,
     xmins: RwLock<BTreeMultiSet<Xid>>,
     ckpt_delay_num: AtomicU32,
+    // xact_commit and xact_rollback of pg_stat_database.
+    ncommit: AtomicU64,
+    nabort: AtomicU64,
 }
 
 #[derive(Clone, Debug)]
@@ -113,9 +123,16 @@ impl GlobalStateExt {
             }),
             xmins: RwLock::new(BTreeMultiSet::new()),
             ckpt_delay_num: AtomicU32::new(0),
+            ncommit: AtomicU64::new(0),
+            nabort: AtomicU64::new(0),
         }
     }
 
+    // The number of the committed and the aborted transactions since the server started.
+    pub fn xact_stats(&self) -> (u64, u64) {
+        (self.ncommit.load(Relaxed), self.nabort.load(Relaxed))
+    }
+
     // GetNewTransactionId
     fn start_xid(&self) -> anyhow::Result<Xid> {
         const STOP: u64 = u64::MAX - 333;
@@ -130,14 +147,18 @@ impl GlobalStateExt {
         return Ok(xid);
     }
 
-    fn end_xid(&self, xid: Option<Xid>, xmin: Option<Xid>) {
-        if let Some(xid) = xid {
+    // The xids of the transaction and its subtransactions end at once, so no snapshot sees
+    // only a part of them completed.
+    fn end_xid(&self, xids: &[Xid], xmin: Option<Xid>) {
+        if !xids.is_empty() {
             let mut state = self.running.write().unwrap();
-            let v = state.xids.remove(&xid);
-            debug_assert!(v);
-            debug_assert_ne!(state.last_completed, xid);
-            if state.last_completed < xid {
-                state.last_completed = xid;
+            for &xid in xids {
+                let v = state.xids.remove(&xid);
+                debug_assert!(v);
+                debug_assert_ne!(state.last_completed, xid);
+                if state.last_completed < xid {
+                    state.last_completed = xid;
+                }
             }
         }
         if let Some(xmin) = xmin {
@@ -148,6 +169,22 @@ impl GlobalStateExt {
         return;
     }
 
+    pub fn nextxid(&self) -> Xid {
+        self.running.read().unwrap().nextxid
+    }
+
+    // Used by the standby, make the transaction replayed visible to the new snapshots.
+    pub fn replay_xid(&self, xid: Xid) {
+        let mut state = self.running.write().unwrap();
+        if state.nextxid <= xid {
+            state.nextxid = inc_xid(xid);
+        }
+        if state.last_completed < xid {
+            state.last_completed = xid;
+        }
+        return;
+    }
+
     fn get_snap(&self) -> Snapshot {
         let (xids, last_xid, xmin) = {
             let state = self.running.read().unwrap();
@@ -231,6 +268,19 @@ enum TBlockState {
     Abort,
     AbortEnd,
     AbortPending,
+    // TBLOCK_SUBABORT, the innermost subtransaction failed, waiting for ROLLBACK TO.
+    SubAbort,
+}
+
+// SubTransactionState, the subtransaction started by SAVEPOINT. The locks acquired by it are
+// kept until the transaction ends.
+struct SubTranCtx {
+    name: String,
+    xid: Option<Xid>,
+    // The xids of the subtransactions released into this one.
+    childxids: Vec<Xid>,
+    notify_mark: (usize, usize),
+    tabstat_mark: usize,
 }
 
 struct TranCtx {
@@ -238,6 +288,40 @@ struct TranCtx {
     state: TranState,
     block_state: TBlockState,
     startts: KBSystemTime,
+    // The xids of the subtransactions released into the transaction.
+    childxids: Vec<Xid>,
+    // The innermost subtransaction is the last one.
+    subs: Vec<SubTranCtx>,
+}
+
+// GetCurrentTransactionIdIfAny, the xid of the innermost subtransaction.
+fn cur_xid(tctx: &TranCtx) -> Option<Xid> {
+    match tctx.subs.last() {
+        Some(sub) => sub.xid,
+        None => tctx.xid,
+    }
+}
+
+// TransactionIdIsCurrentTransactionId, the xids of the transaction and the subtransactions not
+// aborted, their rows are visible as the ones of the current subtransaction.
+fn cur_xids(tctx: &TranCtx) -> Vec<Xid> {
+    let mut xids: Vec<Xid> = tctx.xid.into_iter().collect();
+    xids.extend_from_slice(&tctx.childxids);
+    for sub in &tctx.subs {
+        xids.extend(sub.xid);
+        xids.extend_from_slice(&sub.childxids);
+    }
+    return xids;
+}
+
+// The xids of all subtransactions, which end along with the transaction.
+fn take_subxids(tctx: &mut TranCtx) -> Vec<Xid> {
+    let mut xids = std::mem::take(&mut tctx.childxids);
+    for sub in tctx.subs.drain(..) {
+        xids.extend(sub.xid);
+        xids.extend(sub.childxids);
+    }
+    return xids;
 }
 
 pub struct SessionStateExt {
@@ -263,12 +347,18 @@ impl SessionStateExt {
                 xid: None,
                 state: TranState::Default,
                 block_state: TBlockState::Default,
+                childxids: Vec::new(),
+                subs: Vec::new(),
             },
             snap: None,
             last_rec_end: None,
         }
     }
 
+    pub fn global(&self) -> Option<&'static GlobalStateExt> {
+        self.xact
+    }
+
     pub fn exit_worker(&mut self, e: WorkerExitExt) {
         let sess_rec_end = lsn2u64(self.last_rec_end);
         let worker_rec_end = lsn2u64(e.last_rec_end);
@@ -281,31 +371,36 @@ impl SessionStateExt {
 #[derive(Debug)]
 struct XactRec {
     xact_endts: KBSystemTime,
+    subxids: Vec<Xid>,
 }
 
-#[repr(C, packed(1))]
-struct XactRecSer {
-    xact_endts: u64,
+// xl_xact_commit and xl_xact_abort, in little-endian: xact_endts u64, followed by the xids of
+// the subtransactions committed or aborted along with the record xid, u64 each.
+fn ser_xact_rec(out: &mut Vec<u8>, rec: &XactRec) {
+    ser::ser_le_u64(out, rec.xact_endts.into());
+    for &xid in &rec.subxids {
+        ser::ser_le_u64(out, xid.get());
+    }
 }
 
-impl std::convert::From<&XactRec> for XactRecSer {
-    fn from(v: &XactRec) -> Self {
-        Self {
-            xact_endts: v.xact_endts.into(),
-        }
+fn get_xact_rec(d: &[u8]) -> XactRec {
+    XactRec {
+        xact_endts: LittleEndian::read_u64(d).into(),
+        subxids: d[8..]
+            .chunks_exact(8)
+            .map(|v| Xid::new(LittleEndian::read_u64(v)).unwrap())
+            .collect(),
     }
 }
 
-impl std::convert::From<&XactRecSer> for XactRec {
-    fn from(v: &XactRecSer) -> Self {
-        Self {
-            xact_endts: v.xact_endts.into(),
-        }
-    }
+// The end time of the transaction of the commit or abort record, see recoveryStopsBefore().
+pub fn xact_rec_endts(d: &[u8]) -> KBSystemTime {
+    get_xact_rec(d).xact_endts
 }
 
-fn get_xact_rec(d: &[u8]) -> XactRec {
-    unsafe { (&*(d.as_ptr() as *const XactRecSer)).into() }
+// The xids of the subtransactions ended by the commit or abort record.
+pub fn xact_rec_subxids(d: &[u8]) -> Vec<Xid> {
+    get_xact_rec(d).subxids
 }
 
 #[repr(u8)]
@@ -346,53 +441,83 @@ fn itctx(sess: &SessionState) -> &TranCtx {
     &sess.xact.tranctx
 }
 
-fn log_xact_rec(sess: &mut SessionState, xact_endts: KBSystemTime, info: XactInfo) {
-    let commit_rec = XactRec { xact_endts };
-    let commit_rec_ser: XactRecSer = (&commit_rec).into();
-    let rec = wal::start_record(&commit_rec_ser);
+fn log_xact_rec(
+    sess: &mut SessionState,
+    xact_endts: KBSystemTime,
+    subxids: &[Xid],
+    info: XactInfo,
+) {
+    let commit_rec = XactRec {
+        xact_endts,
+        subxids: subxids.to_vec(),
+    };
+    let mut rec = wal::start_record_raw(&[]);
+    ser_xact_rec(&mut rec, &commit_rec);
     sess.insert_record(RmgrId::Xact, info as u8, rec);
     return;
 }
 
-fn log_commit_rec(sess: &mut SessionState, commit_time: KBSystemTime) {
-    log_xact_rec(sess, commit_time, XactInfo::Commit);
+fn log_commit_rec(sess: &mut SessionState, commit_time: KBSystemTime, subxids: &[Xid]) {
+    log_xact_rec(sess, commit_time, subxids, XactInfo::Commit);
     return;
 }
 
-fn record_tran_commit(sess: &mut SessionState) {
+// The subxids are marked committed before xid, like TransactionIdCommitTree. They are still
+// running until end_xid(), so no snapshot sees them before xid.
+fn record_tran_commit(sess: &mut SessionState, subxids: &[Xid]) {
     if tctx(sess).xid.is_some() {
         // stop_delay_ckpt() must be called!
         gctx(sess).start_delay_ckpt();
-        log_commit_rec(sess, KBSystemTime::now());
+        log_commit_rec(sess, KBSystemTime::now(), subxids);
     }
     if let Some(lsn) = sctx(sess).last_rec_end {
         sess.wal.unwrap().fsync(lsn);
         sctx(sess).last_rec_end = None;
     }
     if let Some(xid) = tctx(sess).xid {
+        crash_point(CrashPoint::CommitFsync);
+        for &subxid in subxids {
+            sess.clog
+                .set_xid_status(subxid, XidStatus::Committed)
+                .unwrap();
+        }
         sess.clog.set_xid_status(xid, XidStatus::Committed).unwrap();
         gctx(sess).stop_delay_ckpt();
     }
     return;
 }
 
-fn record_tran_abort(sess: &mut SessionState) -> anyhow::Result<()> {
+// The abort record is written with the xid of the innermost subtransaction, which is xid.
+fn log_abort(sess: &mut SessionState, xid: Xid, subxids: &[Xid]) -> anyhow::Result<()> {
+    if sess.clog.xid_status(xid)? == XidStatus::Committed {
+        panic!("cannot abort transaction {}, it was already committed", xid);
+    }
+    log_xact_rec(sess, KBSystemTime::now(), subxids, XactInfo::Abort);
+    for &subxid in subxids {
+        sess.clog
+            .set_xid_status(subxid, XidStatus::Aborted)
+            .unwrap();
+    }
+    sess.clog.set_xid_status(xid, XidStatus::Aborted).unwrap();
+    return Ok(());
+}
+
+fn record_tran_abort(sess: &mut SessionState, subxids: &[Xid]) -> anyhow::Result<()> {
     if let Some(xid) = tctx(sess).xid {
-        if sess.clog.xid_status(xid)? == XidStatus::Committed {
-            panic!("cannot abort transaction {}, it was already committed", xid);
-        }
-        log_xact_rec(sess, KBSystemTime::now(), XactInfo::Abort);
-        sess.clog.set_xid_status(xid, XidStatus::Aborted).unwrap();
+        log_abort(sess, xid, subxids)?;
     }
     sctx(sess).last_rec_end = None;
     return Ok(());
 }
 
-fn end_xid(sess: &mut SessionState) {
+fn end_xid(sess: &mut SessionState, subxids: &[Xid]) {
     let xid = tctx(sess).xid;
+    let mut xids = subxids.to_vec();
+    xids.extend(xid);
     let snapxmin = sctx(sess).snap.as_ref().map(|v| v.xmin);
-    gctx(sess).end_xid(xid, snapxmin);
+    gctx(sess).end_xid(&xids, snapxmin);
     tctx(sess).xid = None;
+    logger::set_xid(None);
     sctx(sess).snap = None;
     return;
 }
@@ -409,13 +534,24 @@ fn start_tran(sess: &mut SessionState) -> anyhow::Result<()> {
     sctx(sess).snap = Some(gctx(sess).get_snap());
     return Ok(());
 }
-// AssignTransactionId
+// AssignTransactionId, the parents are assigned before the subtransaction, so the xid of a
+// subtransaction is always larger than the ones of its parents.
 fn assign_xid(sess: &mut SessionState) -> anyhow::Result<Xid> {
     debug_assert_eq!(tctx(sess).state, TranState::Inprogress);
-    debug_assert!(tctx(sess).xid.is_none());
-    let xid = gctx(sess).start_xid()?;
-    tctx(sess).xid = Some(xid);
-    return Ok(xid);
+    debug_assert!(cur_xid(tctx(sess)).is_none());
+    check_not_in_recovery(sess, "assign TransactionIds")?;
+    if tctx(sess).xid.is_none() {
+        let xid = gctx(sess).start_xid()?;
+        tctx(sess).xid = Some(xid);
+        logger::set_xid(Some(xid));
+    }
+    for idx in 0..tctx(sess).subs.len() {
+        if tctx(sess).subs[idx].xid.is_none() {
+            let xid = gctx(sess).start_xid()?;
+            tctx(sess).subs[idx].xid = Some(xid);
+        }
+    }
+    return Ok(cur_xid(tctx(sess)).unwrap());
 }
 
 // CommitTransaction
@@ -424,8 +560,12 @@ fn commit_tran(sess: &mut SessionState) -> anyhow::Result<()> {
         log::warn!("commit_tran: unexpected state={:?}", tctx(sess).state);
     }
     tctx(sess).state = TranState::Commit;
-    record_tran_commit(sess);
-    end_xid(sess);
+    let subxids = take_subxids(tctx(sess));
+    record_tran_commit(sess, &subxids);
+    end_xid(sess, &subxids);
+    gctx(sess).ncommit.fetch_add(1, Relaxed);
+    sess.notify.at_commit();
+    sess.tabstat.at_commit();
     sess.lock_release_all();
     tctx(sess).state = TranState::Default;
     return Ok(());
@@ -436,8 +576,12 @@ fn abort_tran(sess: &mut SessionState) -> anyhow::Result<()> {
         log::warn!("abort_tran: unexpected state={:?}", tctx(sess).state);
     }
     tctx(sess).state = TranState::Abort;
-    record_tran_abort(sess)?;
-    end_xid(sess);
+    let subxids = take_subxids(tctx(sess));
+    record_tran_abort(sess, &subxids)?;
+    end_xid(sess, &subxids);
+    gctx(sess).nabort.fetch_add(1, Relaxed);
+    sess.notify.at_abort();
+    sess.tabstat.at_abort();
     sess.lock_release_all();
     return Ok(());
 }
@@ -456,8 +600,82 @@ fn cleanup_tran(sess: &mut SessionState) -> anyhow::Result<()> {
     return Ok(());
 }
 
+// DefineSavepoint and StartSubTransaction
+fn start_subtran(sess: &mut SessionState, name: &str) {
+    let notify_mark = sess.notify.pending_mark();
+    let tabstat_mark = sess.tabstat.pending_mark();
+    tctx(sess).subs.push(SubTranCtx {
+        name: name.to_string(),
+        xid: None,
+        childxids: Vec::new(),
+        notify_mark,
+        tabstat_mark,
+    });
+}
+
+// CommitSubTransaction, the xids of the innermost subtransaction are merged into its parent,
+// they are still running until the transaction ends.
+fn commit_subtran(sess: &mut SessionState) {
+    let tctx = tctx(sess);
+    let sub = tctx.subs.pop().unwrap();
+    let parent = match tctx.subs.last_mut() {
+        Some(parent) => &mut parent.childxids,
+        None => &mut tctx.childxids,
+    };
+    parent.extend(sub.xid);
+    parent.extend(sub.childxids);
+}
+
+// AbortSubTransaction and CleanupSubTransaction, the innermost subtransaction is reset to the
+// state at its savepoint. Its xids are aborted and end at once since their rows are invisible
+// to everyone.
+fn abort_subtran(sess: &mut SessionState) -> anyhow::Result<()> {
+    let sub = tctx(sess).subs.last().unwrap();
+    let (xid, mut xids, notify_mark) = (sub.xid, sub.childxids.clone(), sub.notify_mark);
+    let tabstat_mark = sub.tabstat_mark;
+    // The children are assigned after their parents.
+    debug_assert!(xid.is_some() || xids.is_empty());
+    sess.notify.at_subabort(notify_mark);
+    sess.tabstat.at_subabort(tabstat_mark);
+    if let Some(xid) = xid {
+        log_abort(sess, xid, &xids)?;
+        xids.push(xid);
+        gctx(sess).end_xid(&xids, None);
+    }
+    let sub = tctx(sess).subs.last_mut().unwrap();
+    sub.xid = None;
+    sub.childxids.clear();
+    return Ok(());
+}
+
+// The innermost subtransaction named name, a savepoint shadows the older ones with the same
+// name.
+fn find_savepoint(sess: &mut SessionState, name: &str) -> anyhow::Result<usize> {
+    match tctx(sess).subs.iter().rposition(|sub| sub.name == name) {
+        Some(idx) => Ok(idx),
+        None => kbbail!(
+            ERRCODE_S_E_INVALID_SPECIFICATION,
+            "savepoint \"{}\" does not exist",
+            name
+        ),
+    }
+}
+
+// RecoveryInProgress, the standby never writes wal, so it can not assign the xids and the oids
+// that are logged.
+fn check_not_in_recovery(sess: &SessionState, action: &str) -> anyhow::Result<()> {
+    kbensure!(
+        sess.wal.is_some(),
+        ERRCODE_READ_ONLY_SQL_TRANSACTION,
+        "cannot {} during recovery",
+        action
+    );
+    return Ok(());
+}
+
 fn log_nextoid(sess: &mut SessionState, nextoid: u32) {
-    let rec = wal::start_record_raw(&nextoid.to_ne_bytes());
+    let mut rec = wal::start_record_raw(&[]);
+    ser::ser_le_u32(&mut rec, nextoid);
     sess.insert_record(RmgrId::Xlog, XlogInfo::NextOid as u8, rec);
     return;
 }
@@ -475,12 +693,20 @@ pub trait SessionExt {
     fn commit_tran_cmd(&mut self) -> anyhow::Result<()>;
     // AbortCurrentTransaction
     fn abort_cur_tran(&mut self) -> anyhow::Result<()>;
+    // AbortOutOfAnyTransaction, the transaction block and all its savepoints are aborted.
+    fn abort_out_of_any_tran(&mut self) -> anyhow::Result<()>;
     // BeginTransactionBlock
     fn begin_tran_block(&mut self) -> anyhow::Result<()>;
     // EndTransactionBlock
     fn end_tran_block(&mut self) -> anyhow::Result<bool>;
     // UserAbortTransactionBlock
     fn user_abort_tran_block(&mut self) -> anyhow::Result<()>;
+    // DefineSavepoint
+    fn define_savepoint(&mut self, name: &str) -> anyhow::Result<()>;
+    // ReleaseSavepoint, the savepoint and the ones after it are released.
+    fn release_savepoint(&mut self, name: &str) -> anyhow::Result<()>;
+    // RollbackToSavepoint, the savepoint is kept after the rollback.
+    fn rollback_to_savepoint(&mut self, name: &str) -> anyhow::Result<()>;
     fn get_xid(&mut self) -> anyhow::Result<Xid>;
     fn is_aborted(&self) -> bool;
     fn insert_record(&mut self, id: RmgrId, info: u8, rec: Vec<u8>) -> Lsn;
@@ -492,11 +718,16 @@ pub trait SessionExt {
         page_lsn: Lsn,
     ) -> Option<Lsn>;
     fn xact_status(&self) -> XactStatus;
-    fn new_oid(&mut self) -> Oid;
+    fn new_oid(&mut self) -> anyhow::Result<Oid>;
     // PreventInTransactionBlock
     fn prevent_in_transblock(&self, stmt: &str) -> anyhow::Result<()>;
+    // PreventCommandDuringRecovery, for the commands writing wal without the xid.
+    fn prevent_during_recovery(&self, stmt: &str) -> anyhow::Result<()>;
     // RequireTransactionBlock
     fn require_transblock(&self, stmt: &str) -> anyhow::Result<()>;
+    // GetOldestXmin, the rows inserted by the transactions before it are visible to all
+    // snapshots if committed.
+    fn global_xmin(&self) -> Xid;
 }
 
 impl SessionExt for SessionState {
@@ -506,7 +737,7 @@ impl SessionExt for SessionState {
                 start_tran(self)?;
                 tctx(self).block_state = TBlockState::Started;
             }
-            TBlockState::Inprogress | TBlockState::Abort => {}
+            TBlockState::Inprogress | TBlockState::Abort | TBlockState::SubAbort => {}
             TBlockState::Begin
             | TBlockState::Started
             | TBlockState::End
@@ -537,7 +768,7 @@ impl SessionExt for SessionState {
             TBlockState::Begin => {
                 tctx(self).block_state = TBlockState::Inprogress;
             }
-            TBlockState::Inprogress | TBlockState::Abort => {}
+            TBlockState::Inprogress | TBlockState::Abort | TBlockState::SubAbort => {}
             TBlockState::AbortEnd => {
                 cleanup_tran(self)?;
                 tctx(self).block_state = TBlockState::Default;
@@ -552,7 +783,7 @@ impl SessionExt for SessionState {
     }
 
     fn abort_cur_tran(&mut self) -> anyhow::Result<()> {
-        match tctx(self).block_state {
+        match itctx(self).block_state {
             TBlockState::Default => {
                 if self.xact.tranctx.state != TranState::Default {
                     if self.xact.tranctx.state == TranState::Start {
@@ -570,11 +801,15 @@ impl SessionExt for SessionState {
                 cleanup_tran(self)?;
                 tctx(self).block_state = TBlockState::Default;
             }
+            TBlockState::Inprogress if !itctx(self).subs.is_empty() => {
+                abort_subtran(self)?;
+                tctx(self).block_state = TBlockState::SubAbort;
+            }
             TBlockState::Inprogress => {
                 abort_tran(self)?;
                 tctx(self).block_state = TBlockState::Abort;
             }
-            TBlockState::Abort => {}
+            TBlockState::Abort | TBlockState::SubAbort => {}
             TBlockState::AbortEnd => {
                 cleanup_tran(self)?;
                 tctx(self).block_state = TBlockState::Default;
@@ -583,13 +818,30 @@ impl SessionExt for SessionState {
         return Ok(());
     }
 
+    fn abort_out_of_any_tran(&mut self) -> anyhow::Result<()> {
+        match tctx(self).block_state {
+            TBlockState::Inprogress | TBlockState::SubAbort => {
+                tctx(self).block_state = TBlockState::AbortPending;
+            }
+            TBlockState::Abort => {
+                tctx(self).block_state = TBlockState::AbortEnd;
+            }
+            _ => {}
+        }
+        return self.abort_cur_tran();
+    }
+
     fn begin_tran_block(&mut self) -> anyhow::Result<()> {
         match tctx(self).block_state {
             TBlockState::Started => {
                 tctx(self).block_state = TBlockState::Begin;
             }
-            TBlockState::Inprogress | TBlockState::Abort => {
-                log::warn!("there is already a transaction in progress");
+            TBlockState::Inprogress | TBlockState::Abort | TBlockState::SubAbort => {
+                self.notice(
+                    NoticeLevel::Warning,
+                    ERRCODE_ACTIVE_SQL_TRANSACTION,
+                    "there is already a transaction in progress".to_string(),
+                );
             }
             TBlockState::Default
             | TBlockState::Begin
@@ -610,13 +862,25 @@ impl SessionExt for SessionState {
         let mut ret = false;
         match tctx(self).block_state {
             TBlockState::Inprogress => {
+                while !tctx(self).subs.is_empty() {
+                    commit_subtran(self);
+                }
                 tctx(self).block_state = TBlockState::End;
                 ret = true;
             }
             TBlockState::Abort => {
                 tctx(self).block_state = TBlockState::AbortEnd;
             }
+            // The subtransactions are aborted along with the transaction.
+            TBlockState::SubAbort => {
+                tctx(self).block_state = TBlockState::AbortPending;
+            }
             TBlockState::Started => {
+                self.notice(
+                    NoticeLevel::Warning,
+                    ERRCODE_NO_ACTIVE_SQL_TRANSACTION,
+                    "there is no transaction in progress".to_string(),
+                );
                 ret = true;
             }
             TBlockState::Default
@@ -636,12 +900,23 @@ impl SessionExt for SessionState {
 
     fn user_abort_tran_block(&mut self) -> anyhow::Result<()> {
         match tctx(self).block_state {
-            TBlockState::Inprogress | TBlockState::Started => {
+            TBlockState::Inprogress => {
+                tctx(self).block_state = TBlockState::AbortPending;
+            }
+            TBlockState::Started => {
+                self.notice(
+                    NoticeLevel::Warning,
+                    ERRCODE_NO_ACTIVE_SQL_TRANSACTION,
+                    "there is no transaction in progress".to_string(),
+                );
                 tctx(self).block_state = TBlockState::AbortPending;
             }
             TBlockState::Abort => {
                 tctx(self).block_state = TBlockState::AbortEnd;
             }
+            TBlockState::SubAbort => {
+                tctx(self).block_state = TBlockState::AbortPending;
+            }
             TBlockState::Default
             | TBlockState::Begin
             | TBlockState::End
@@ -657,12 +932,93 @@ impl SessionExt for SessionState {
         return Ok(());
     }
 
+    fn define_savepoint(&mut self, name: &str) -> anyhow::Result<()> {
+        match tctx(self).block_state {
+            TBlockState::Inprogress => start_subtran(self, name),
+            TBlockState::Abort | TBlockState::SubAbort => kbbail!(
+                ERRCODE_IN_FAILED_SQL_TRANSACTION,
+                "current transaction is aborted, commands ignored until end of transaction block"
+            ),
+            TBlockState::Default
+            | TBlockState::Started
+            | TBlockState::Begin
+            | TBlockState::End
+            | TBlockState::AbortEnd
+            | TBlockState::AbortPending => {
+                self.dead = true;
+                bail!(
+                    "define_savepoint: unexpected state={:?}",
+                    tctx(self).block_state
+                );
+            }
+        }
+        return Ok(());
+    }
+
+    fn release_savepoint(&mut self, name: &str) -> anyhow::Result<()> {
+        match tctx(self).block_state {
+            TBlockState::Inprogress => {
+                let idx = find_savepoint(self, name)?;
+                while tctx(self).subs.len() > idx {
+                    commit_subtran(self);
+                }
+            }
+            TBlockState::Abort | TBlockState::SubAbort => kbbail!(
+                ERRCODE_IN_FAILED_SQL_TRANSACTION,
+                "current transaction is aborted, commands ignored until end of transaction block"
+            ),
+            TBlockState::Default
+            | TBlockState::Started
+            | TBlockState::Begin
+            | TBlockState::End
+            | TBlockState::AbortEnd
+            | TBlockState::AbortPending => {
+                self.dead = true;
+                bail!(
+                    "release_savepoint: unexpected state={:?}",
+                    tctx(self).block_state
+                );
+            }
+        }
+        return Ok(());
+    }
+
+    // There is no savepoint in Abort since all subtransactions have been aborted.
+    fn rollback_to_savepoint(&mut self, name: &str) -> anyhow::Result<()> {
+        match tctx(self).block_state {
+            TBlockState::Inprogress | TBlockState::SubAbort | TBlockState::Abort => {
+                let idx = find_savepoint(self, name)?;
+                while tctx(self).subs.len() > idx + 1 {
+                    abort_subtran(self)?;
+                    tctx(self).subs.pop();
+                }
+                abort_subtran(self)?;
+                tctx(self).block_state = TBlockState::Inprogress;
+            }
+            TBlockState::Default
+            | TBlockState::Started
+            | TBlockState::Begin
+            | TBlockState::End
+            | TBlockState::AbortEnd
+            | TBlockState::AbortPending => {
+                self.dead = true;
+                bail!(
+                    "rollback_to_savepoint: unexpected state={:?}",
+                    tctx(self).block_state
+                );
+            }
+        }
+        return Ok(());
+    }
+
     fn is_aborted(&self) -> bool {
-        itctx(self).block_state == TBlockState::Abort
+        let bs = itctx(self).block_state;
+        bs == TBlockState::Abort || bs == TBlockState::SubAbort
     }
 
-    fn insert_record(&mut self, id: RmgrId, info: u8, mut rec: Vec<u8>) -> Lsn {
-        wal::finish_record(&mut rec, id, info, self.xact.tranctx.xid);
+    fn insert_record(&mut self, id: RmgrId, info: u8, rec: Vec<u8>) -> Lsn {
+        let (mut rec, info) = wal::compress_record(&self.gucstate, rec, info);
+        wal::finish_record(&mut rec, id, info, cur_xid(&self.xact.tranctx));
         let ret = self.wal.unwrap().insert_record(rec);
         self.xact.last_rec_end = Some(ret);
         return ret;
@@ -672,10 +1028,11 @@ impl SessionExt for SessionState {
         &mut self,
         id: RmgrId,
         info: u8,
-        mut r: Vec<u8>,
+        r: Vec<u8>,
         page_lsn: Lsn,
     ) -> Option<Lsn> {
-        wal::finish_record(&mut r, id, info, self.xact.tranctx.xid);
+        let (mut r, info) = wal::compress_record(&self.gucstate, r, info);
+        wal::finish_record(&mut r, id, info, cur_xid(&self.xact.tranctx));
         let ret = self.wal.unwrap().try_insert_record(r, page_lsn);
         if ret.is_none() {
             return None;
@@ -684,7 +1041,7 @@ impl SessionExt for SessionState {
         return ret;
     }
     fn get_xid(&mut self) -> anyhow::Result<Xid> {
-        if let Some(xid) = tctx(self).xid {
+        if let Some(xid) = cur_xid(tctx(self)) {
             return Ok(xid);
         }
         return assign_xid(self);
@@ -694,19 +1051,21 @@ impl SessionExt for SessionState {
         match itctx(self).block_state {
             TBlockState::Default | TBlockState::Started => XactStatus::NotInBlock,
             TBlockState::Begin | TBlockState::Inprogress | TBlockState::End => XactStatus::InBlock,
-            TBlockState::Abort | TBlockState::AbortEnd | TBlockState::AbortPending => {
-                XactStatus::Failed
-            }
+            TBlockState::Abort
+            | TBlockState::AbortEnd
+            | TBlockState::AbortPending
+            | TBlockState::SubAbort => XactStatus::Failed,
         }
     }
-    fn new_oid(&mut self) -> Oid {
+    fn new_oid(&mut self) -> anyhow::Result<Oid> {
+        check_not_in_recovery(self, "assign OIDs")?;
         let curoid = self.oid_creator.unwrap().fetch_add(1, Relaxed);
         let nextoid = curoid + 1;
         if nextoid == 0 {
             panic!("no more oid")
         }
         log_nextoid(self, nextoid);
-        return Oid::new(curoid).unwrap();
+        return Ok(Oid::new(curoid).unwrap());
     }
 
     fn prevent_in_transblock(&self, stmt: &str) -> anyhow::Result<()> {
@@ -720,6 +1079,10 @@ impl SessionExt for SessionState {
         return Ok(());
     }
 
+    fn prevent_during_recovery(&self, stmt: &str) -> anyhow::Result<()> {
+        return check_not_in_recovery(self, &format!("execute {}", stmt));
+    }
+
     fn require_transblock(&self, stmt: &str) -> anyhow::Result<()> {
         if !is_transblock(self) {
             kbbail!(
@@ -730,6 +1093,10 @@ impl SessionExt for SessionState {
         }
         return Ok(());
     }
+
+    fn global_xmin(&self) -> Xid {
+        self.xact.xact.unwrap().global_xmin()
+    }
 }
 
 pub struct XactRmgr {}
@@ -745,12 +1112,16 @@ impl Rmgr for XactRmgr {
         "Transaction"
     }
 
-    fn redo(&mut self, hdr: &RecordHdr, _: &[u8], state: &mut RedoState) -> anyhow::Result<()> {
+    fn redo(&mut self, hdr: &RecordHdr, data: &[u8], state: &mut RedoState) -> anyhow::Result<()> {
         let xid = hdr.xid.ok_or(anyhow!("XactRmgr::redo: invalid xid"))?;
         let xidstatus = match hdr.rmgr_info().into() {
             XactInfo::Commit => XidStatus::Committed,
             XactInfo::Abort => XidStatus::Aborted,
         };
+        for subxid in xact_rec_subxids(data) {
+            state.seen_xid(subxid);
+            state.worker.clog.set_xid_status(subxid, xidstatus)?;
+        }
         return state.worker.clog.set_xid_status(xid, xidstatus);
     }
 
@@ -771,6 +1142,9 @@ impl Rmgr for XactRmgr {
 pub struct WorkerStateExt {
     last_rec_end: Option<Lsn>,
     pub xid: Option<Xid>,
+    // See cur_xids().
+    xids: Vec<Xid>,
+    pub snap: Option<Snapshot>,
 }
 
 pub struct WorkerExitExt {
@@ -781,7 +1155,9 @@ impl WorkerStateExt {
     pub fn new(sess: &SessionState) -> Self {
         Self {
             last_rec_end: None,
-            xid: sess.xact.tranctx.xid,
+            xid: cur_xid(&sess.xact.tranctx),
+            xids: cur_xids(&sess.xact.tranctx),
+            snap: sess.xact.snap.clone(),
         }
     }
 
@@ -801,10 +1177,12 @@ pub trait WorkerExt {
         rec: Vec<u8>,
         page_lsn: Lsn,
     ) -> Option<Lsn>;
+    fn xmin_visible(&self, xmin: u64) -> anyhow::Result<bool>;
 }
 
 impl WorkerExt for WorkerState {
-    fn insert_record(&mut self, id: RmgrId, info: u8, mut rec: Vec<u8>) -> Lsn {
+    fn insert_record(&mut self, id: RmgrId, info: u8, rec: Vec<u8>) -> Lsn {
+        let (mut rec, info) = wal::compress_record(&self.gucstate, rec, info);
         wal::finish_record(&mut rec, id, info, self.xact.xid);
         let ret = self.wal.unwrap().insert_record(rec);
         self.xact.last_rec_end = Some(ret);
@@ -815,9 +1193,10 @@ impl WorkerExt for WorkerState {
         &mut self,
         id: RmgrId,
         info: u8,
-        mut r: Vec<u8>,
+        r: Vec<u8>,
         page_lsn: Lsn,
     ) -> Option<Lsn> {
+        let (mut r, info) = wal::compress_record(&self.gucstate, r, info);
         wal::finish_record(&mut r, id, info, self.xact.xid);
         let ret = self.wal.unwrap().try_insert_record(r, page_lsn);
         if ret.is_none() {
@@ -826,4 +1205,119 @@ impl WorkerExt for WorkerState {
         self.xact.last_rec_end = ret;
         return ret;
     }
+
+    fn xmin_visible(&self, xmin: u64) -> anyhow::Result<bool> {
+        xmin_satisfies_mvcc(xmin, &self.xact.xids, self.xact.snap.as_ref(), |xid| {
+            self.clog.xid_status(xid)
+        })
+    }
+}
+
+// HeapTupleSatisfiesMVCC, only the xmin is checked since there is no DELETE yet.
+// xmin is 0 if the row has not been inserted, FROZEN_XID if it has been frozen by VACUUM.
+// The rows of curxids are visible, while the committed subtransactions of the transactions
+// in progress are still running in snap.
+fn xmin_satisfies_mvcc(
+    xmin: u64,
+    curxids: &[Xid],
+    snap: Option<&Snapshot>,
+    xid_status: impl FnOnce(Xid) -> anyhow::Result<XidStatus>,
+) -> anyhow::Result<bool> {
+    let xmin = match Xid::new(xmin) {
+        None => return Ok(false),
+        Some(v) => v,
+    };
+    if curxids.contains(&xmin) || xmin == FROZEN_XID {
+        return Ok(true);
+    }
+    if let Some(snap) = snap {
+        if snap.is_running(xmin) {
+            return Ok(false);
+        }
+    }
+    return Ok(xid_status(xmin)? == XidStatus::Committed);
+}
+
+#[cfg(test)]
+mod xact_test {
+    use super::{
+        get_xact_rec, ser_xact_rec, xact_rec_endts, xact_rec_subxids, xmin_satisfies_mvcc,
+        Snapshot, XactInfo, XactRec, XidStatus,
+    };
+    use crate::access::wal::{self, finish_record, for_each_misaligned, parse_record, RmgrId};
+    use crate::utils::{KBSystemTime, Xid};
+    use std::collections::HashSet;
+
+    #[test]
+    fn xact_rec() {
+        let rec = XactRec {
+            xact_endts: KBSystemTime::from(0x604b1e28),
+            subxids: Vec::new(),
+        };
+        let mut walrec = wal::start_record_raw(&[]);
+        ser_xact_rec(&mut walrec, &rec);
+        finish_record(
+            &mut walrec,
+            RmgrId::Xact,
+            XactInfo::Abort as u8,
+            Xid::new(33),
+        );
+        let (h, data) = parse_record(&walrec);
+        assert_eq!(h.rmgr_info(), XactInfo::Abort as u8);
+        assert_eq!(h.xid, Xid::new(33));
+        assert_eq!(data, [0x28, 0x1e, 0x4b, 0x60, 0, 0, 0, 0]);
+        assert_eq!(u64::from(get_xact_rec(data).xact_endts), 0x604b1e28);
+        assert_eq!(u64::from(xact_rec_endts(data)), 0x604b1e28);
+        for_each_misaligned(data, |d| {
+            assert_eq!(u64::from(get_xact_rec(d).xact_endts), 0x604b1e28);
+        });
+        assert!(xact_rec_subxids(data).is_empty());
+
+        // The xids of the subtransactions follow xact_endts.
+        let rec = XactRec {
+            xact_endts: KBSystemTime::from(0x604b1e28),
+            subxids: vec![xid(34), xid(0x1122334455667788)],
+        };
+        let mut data = Vec::new();
+        ser_xact_rec(&mut data, &rec);
+        assert_eq!(data.len(), 24);
+        assert_eq!(&data[8..16], [34, 0, 0, 0, 0, 0, 0, 0]);
+        for_each_misaligned(&data, |d| {
+            assert_eq!(xact_rec_subxids(d), rec.subxids);
+            assert_eq!(u64::from(xact_rec_endts(d)), 0x604b1e28);
+        });
+    }
+
+    fn xid(v: u64) -> Xid {
+        Xid::new(v).unwrap()
+    }
+
+    #[test]
+    fn xmin_visible() {
+        let mut xidset = HashSet::new();
+        xidset.insert(xid(35));
+        // 33 and 35 are running, 34 has committed.
+        let snap = Snapshot {
+            xmin: xid(33),
+            xmax: xid(36),
+            xidset,
+        };
+        let visible = |xmin: u64, curxids: &[Xid], status: XidStatus| {
+            xmin_satisfies_mvcc(xmin, curxids, Some(&snap), |_| Ok(status)).unwrap()
+        };
+        assert!(!visible(0, &[], XidStatus::Committed));
+        assert!(visible(32, &[], XidStatus::Committed));
+        assert!(!visible(32, &[], XidStatus::Aborted));
+        assert!(!visible(33, &[], XidStatus::Committed));
+        assert!(visible(34, &[], XidStatus::Committed));
+        assert!(!visible(35, &[], XidStatus::Committed));
+        assert!(!visible(37, &[], XidStatus::Committed));
+        // The rows inserted by the current transaction are always visible.
+        assert!(visible(37, &[xid(37)], XidStatus::InProgress));
+        // So are the rows of its subtransactions, which are still running for others.
+        assert!(visible(38, &[xid(37), xid(38)], XidStatus::InProgress));
+        assert!(!visible(35, &[xid(33)], XidStatus::Committed));
+        // The frozen row is visible without looking up the clog.
+        assert!(visible(1, &[], XidStatus::InProgress));
+    }
 }
@@ -154,7 +154,30 @@ const KB_OPERATOR_ATTRS: [Attr; 7] = [
     },
 ];
 
-const KB_ATTRIBUTE_ATTRS: [Attr; 9] = [
+const KB_CAST_ATTRS: [Attr; 4] = [
+    Attr {
+        name: "castsource",
+        // "oid",
+        sqlite_type: "int not null ",
+    },
+    Attr {
+        name: "casttarget",
+        // "oid",
+        sqlite_type: "int not null ",
+    },
+    Attr {
+        name: "castfunc",
+        // "oid",
+        sqlite_type: "int not null ",
+    },
+    Attr {
+        name: "castcontext",
+        // "char",
+        sqlite_type: "int not null ",
+    },
+];
+
+const KB_ATTRIBUTE_ATTRS: [Attr; 11] = [
     Attr {
         name: "attrelid",
         // "oid",
@@ -200,6 +223,17 @@ const KB_ATTRIBUTE_ATTRS: [Attr; 9] = [
         // "bool", UNUSED NOW!
         sqlite_type: "int not null",
     },
+    Attr {
+        name: "attdefault",
+        // "text", the input of the type input function, null means no default.
+        sqlite_type: "text",
+    },
+    Attr {
+        name: "attmissingval",
+        // "text", the input of the type input function, the value of the column for the rows
+        // written before ALTER TABLE ADD COLUMN, null means NULL.
+        sqlite_type: "text",
+    },
 ];
 
 const KB_NAMESPACE_ATTRS: [Attr; 2] = [
@@ -321,21 +355,92 @@ const KB_TYPE_ATTRS: [Attr; 10] = [
     },
 ];
 
+const KB_AUTHID_ATTRS: [Attr; 4] = [
+    Attr {
+        name: "oid",
+        sqlite_type: "int not null unique",
+    },
+    Attr {
+        name: "rolname",
+        sqlite_type: "varchar(127) not null unique",
+    },
+    Attr {
+        name: "rolsuper",
+        sqlite_type: "int not null",
+    },
+    Attr {
+        name: "rolpassword",
+        // md5_encrypt(password, rolname) or the SCRAM secret, NULL means no password.
+        sqlite_type: "text",
+    },
+];
+
+const KB_TABLESPACE_ATTRS: [Attr; 3] = [
+    Attr {
+        name: "oid",
+        sqlite_type: "int not null unique",
+    },
+    Attr {
+        name: "spcname",
+        sqlite_type: "varchar(127) not null unique",
+    },
+    Attr {
+        name: "spclocation",
+        // The absolute path of the directory, NULL means base of the datadir.
+        sqlite_type: "text",
+    },
+];
+
+// The oid of the named tablespace, only one named tablespace is supported now.
+const NAMED_TABLESPACE_OID: u32 = 1664;
+
 // global
-fn create_global_metadata() {
+fn create_global_metadata(
+    username: &str,
+    password: Option<&str>,
+    auth: &str,
+    tablespace: Option<(&str, &str)>,
+) {
     std::fs::create_dir_all("global").unwrap();
     let conn = sqlite::open("global/meta.db").unwrap();
+    let rolpassword = match password {
+        None => "NULL".to_string(),
+        Some(v) if auth == "scram-sha-256" => format!("'{}'", utils::auth::scram_encrypt(v)),
+        Some(v) => format!(
+            "'{}'",
+            utils::auth::md5_encrypt(v.as_bytes(), username.as_bytes())
+        ),
+    };
     conn.execute(format!(
         "
     create table kb_database({});
     insert into kb_database values({}, 'template0', 1, 0, 0);
     insert into kb_database values({}, 'kuiba', 0, 1, 0);
+    create table kb_authid({});
+    insert into kb_authid values({}, '{}', 1, {});
+    create table kb_tablespace({});
+    insert into kb_tablespace values({}, 'kb_default', NULL);
     ",
         attrs_to_ddl(&KB_DATABASE_ATTRS),
         TEMPLATE0_DB,
-        KUIBADB
+        KUIBADB,
+        attrs_to_ddl(&KB_AUTHID_ATTRS),
+        BOOTSTRAP_SUPERUSERID,
+        username.replace('\'', "''"),
+        rolpassword,
+        attrs_to_ddl(&KB_TABLESPACE_ATTRS),
+        DEFAULTTABLESPACE_OID,
     ))
     .unwrap();
+    if let Some((spcname, spclocation)) = tablespace {
+        conn.execute(format!(
+            "insert into kb_tablespace values({}, '{}', '{}')",
+            NAMED_TABLESPACE_OID,
+            spcname.replace('\'', "''"),
+            spclocation.replace('\'', "''")
+        ))
+        .unwrap();
+    }
 }
 
 // base
@@ -366,6 +471,7 @@ fn create_template0_metadata() {
     insert into kb_class values({}, 'kb_namespace', {}, 0, 114, {}, 0, '');
     insert into kb_class values({}, 'kb_proc', {}, 0, 114, {}, 0, '');
     insert into kb_class values({}, 'kb_type', {}, 0, 114, {}, 0, '');
+    insert into kb_class values({}, 'kb_cast', {}, 0, 114, {}, 0, '');
     ",
         attrs_to_ddl(&KB_CLASS_ATTRS),
         RELRELID,
@@ -389,6 +495,9 @@ fn create_template0_metadata() {
         TYPERELID,
         KBCATLOGNS,
         KB_TYPE_ATTRS.len(),
+        CASTRELID,
+        KBCATLOGNS,
+        KB_CAST_ATTRS.len(),
     ))
     .unwrap();
 
@@ -895,6 +1004,69 @@ fn create_template0_metadata() {
     ",
     ))
     .unwrap();
+
+    //   SELECT '(' || oid::text, ''''||proname||'''', pronamespace, prokind::int, provolatile::int, pronargs, prorettype, ''''||proargtypes::text||'''', ''''||prosrc||'''', ''''||coalesce(probin,'')||'''),'
+    //   FROM pg_proc
+    //   WHERE oid IN (2101, 2108, 2116, 2132, 2147, 2803)
+    //   ORDER BY pg_proc.oid;
+    // numeric is not supported, so the avg(int4) returns float8 instead.
+    conn.execute(format!(
+        "insert into kb_proc values
+        (2101,'avg',11,97,105,1,701,'23','aggregate_dummy',''),
+        (2108,'sum',11,97,105,1,20,'23','aggregate_dummy',''),
+        (2116,'max',11,97,105,1,23,'23','aggregate_dummy',''),
+        (2132,'min',11,97,105,1,23,'23','aggregate_dummy',''),
+        (2147,'count',11,97,105,1,20,'2276','aggregate_dummy',''),
+        (2803,'count',11,97,105,0,20,'','aggregate_dummy','');
+    ",
+    ))
+    .unwrap();
+
+    conn.execute(format!(
+        "create table kb_cast({}, unique (castsource, casttarget));",
+        attrs_to_ddl(&KB_CAST_ATTRS)
+    ))
+    .unwrap();
+    // Only the implicit casts between the numeric types are supported.
+    //   SELECT '(' || castsource::int, casttarget::int, castfunc::int, castcontext::int || '),'
+    //   FROM pg_cast
+    //   WHERE castsource IN (20,21,23,700,701) AND casttarget IN (20,21,23,700,701) AND castcontext = 'i'
+    //   ORDER BY castsource, casttarget;
+    conn.execute(format!(
+        "insert into kb_cast values
+        (20,700,652,105),
+        (20,701,482,105),
+        (21,20,754,105),
+        (21,23,313,105),
+        (21,700,236,105),
+        (21,701,235,105),
+        (23,20,481,105),
+        (23,700,318,105),
+        (23,701,316,105),
+        (700,701,311,105);
+    ",
+    ))
+    .unwrap();
+
+    //   SELECT '(' || oid::text, ''''||proname||'''', pronamespace, prokind::int, provolatile::int, pronargs, prorettype, ''''||proargtypes::text||'''', ''''||prosrc||'''', ''''||coalesce(probin,'')||'''),'
+    //   FROM pg_proc
+    //   WHERE oid IN (235, 236, 311, 313, 316, 318, 481, 482, 652, 754)
+    //   ORDER BY pg_proc.oid;
+    conn.execute(format!(
+        "insert into kb_proc values
+        (235,'float8',11,102,105,1,701,'21','i2tod',''),
+        (236,'float4',11,102,105,1,700,'21','i2tof',''),
+        (311,'float8',11,102,105,1,701,'700','ftod',''),
+        (313,'int4',11,102,105,1,23,'21','i2toi4',''),
+        (316,'float8',11,102,105,1,701,'23','i4tod',''),
+        (318,'float4',11,102,105,1,700,'23','i4tof',''),
+        (481,'int8',11,102,105,1,20,'23','int48',''),
+        (482,'float8',11,102,105,1,701,'20','i8tod',''),
+        (652,'float4',11,102,105,1,700,'20','i8tof',''),
+        (754,'int8',11,102,105,1,20,'21','int28','');
+    ",
+    ))
+    .unwrap();
 }
 
 fn create_kuiba_metadata() {
@@ -927,7 +1099,7 @@ fn create_ctl(gucstate: &guc::GucState) -> anyhow::Result<()> {
     wals.fsync(wals.insert_record(rec));
 
     let ctl = wal::Ctl::new(lsn, ckpt);
-    ctl.persist()
+    ctl.persist(wals.sync_method())
 }
 
 fn main() {
@@ -942,17 +1114,66 @@ fn main() {
                 .index(1)
                 .required(true),
         )
+        .arg(
+            Arg::with_name("username")
+                .short("U")
+                .long("username")
+                .help("database superuser name")
+                .default_value("kuiba")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("pwfile")
+                .long("pwfile")
+                .help("read password for the new superuser from file")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("auth")
+                .short("A")
+                .long("auth")
+                .help("the encryption of the password for the new superuser")
+                .possible_values(&["md5", "scram-sha-256"])
+                .default_value("md5")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tablespace")
+                .long("tablespace")
+                .help("the named tablespace and its directory, in the form of name=directory")
+                .takes_value(true),
+        )
         .get_matches();
     let datadir = cmdline.value_of("datadir").unwrap();
+    let username = cmdline.value_of("username").unwrap();
+    // Only the first line of the file is the password, just as initdb of PostgreSQL.
+    let password = cmdline.value_of("pwfile").map(|path| {
+        let content = std::fs::read_to_string(path).unwrap();
+        content.lines().next().unwrap_or("").to_string()
+    });
+    // The directory of the tablespace is resolved before entering the datadir.
+    let tablespace = cmdline.value_of("tablespace").map(|v| {
+        let (spcname, dir) = v.split_at(v.find('=').expect("--tablespace must be name=directory"));
+        assert!(
+            spcname != "kb_default",
+            "kb_default is the name of the default tablespace"
+        );
+        std::fs::create_dir_all(&dir[1..]).unwrap();
+        let dir = std::fs::canonicalize(&dir[1..]).unwrap();
+        (spcname.to_string(), dir.to_str().unwrap().to_string())
+    });
     std::fs::create_dir_all(datadir).unwrap();
     std::env::set_current_dir(datadir).unwrap();
     std::fs::write("KB_VERSION", format!("{}\n", kuiba::KB_MAJOR)).unwrap();
     std::fs::write("kuiba.conf", format!("# define your GUC here.\n")).unwrap();
     std::fs::create_dir_all("kb_wal").unwrap();
     std::fs::create_dir_all("kb_xact").unwrap();
+    std::fs::create_dir_all("kb_replslot").unwrap();
     let gucstate = guc::load("kuiba.conf").unwrap();
     log::info!("create global metadata");
-    create_global_metadata();
+    let auth = cmdline.value_of("auth").unwrap();
+    let tablespace = tablespace.as_ref().map(|(n, l)| (n.as_str(), l.as_str()));
+    create_global_metadata(username, password.as_deref(), auth, tablespace);
     log::info!("create template0 metadata");
     create_template0_metadata();
     log::info!("create kuiba metadata");
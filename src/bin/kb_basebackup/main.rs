@@ -0,0 +1,64 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use clap::{App, Arg};
+use kuiba::init_log;
+use kuiba::replication::basebackup::base_backup;
+
+fn main() {
+    init_log();
+    let cmdline = App::new("kb_basebackup takes a base backup of a running KuiBaDB cluster.")
+        .version(kuiba::KB_VERSTR)
+        .author("盏一 <w@hidva.com>")
+        .about("KuiBaDB is another Postgresql written in Rust")
+        .arg(
+            Arg::with_name("pgdata")
+                .short("D")
+                .long("pgdata")
+                .help("receive base backup into directory")
+                .required(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("host")
+                .short("h")
+                .long("host")
+                .default_value("127.0.0.1")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("port")
+                .short("p")
+                .long("port")
+                .default_value("1218")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("username")
+                .short("U")
+                .long("username")
+                .default_value("kuiba")
+                .takes_value(true),
+        )
+        .get_matches();
+    let mut conninfo = format!(
+        "host={} port={} user={}",
+        cmdline.value_of("host").unwrap(),
+        cmdline.value_of("port").unwrap(),
+        cmdline.value_of("username").unwrap()
+    );
+    // The password is taken from PGPASSWORD, just as libpq.
+    if let Ok(password) = std::env::var("PGPASSWORD") {
+        conninfo.push_str(&format!(" password={}", password));
+    }
+    let targetdir = cmdline.value_of("pgdata").unwrap();
+    base_backup(&conninfo, targetdir).expect("base backup failed");
+    log::info!("base backup completed. targetdir={}", targetdir);
+}
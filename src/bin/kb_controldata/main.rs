@@ -14,6 +14,11 @@ fn main() {
     let ctl = wal::Ctl::load().unwrap();
     println!("kb_control version number: {}", wal::KB_CTL_VER);
     println!("Catalog version number: {}", wal::KB_CAT_VER);
+    let state = match ctl.state {
+        wal::DbState::Shutdowned => "shut down",
+        wal::DbState::InProduction => "in production",
+    };
+    println!("Database cluster state: {}", state);
     println!("kb_control last modified: {:?}", ctl.time);
     let ckpt = ctl.ckpt;
     println!("Latest checkpoint location: {}", ckpt);
@@ -0,0 +1,77 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use clap::{App, Arg};
+use kuiba::access::sv;
+use kuiba::{init_log, Oid};
+
+fn get_oid(cmdline: &clap::ArgMatches, name: &str) -> Oid {
+    let oid = cmdline.value_of(name).unwrap();
+    oid.parse()
+        .unwrap_or_else(|_| panic!("invalid {}: {}", name, oid))
+}
+
+fn main() {
+    init_log();
+    let cmdline = App::new(
+        "kb_rebuild_manifest rebuilds the manifest of a table from its data files. \
+        The server must be stopped.",
+    )
+    .version(kuiba::KB_VERSTR)
+    .author("盏一 <w@hidva.com>")
+    .about("KuiBaDB is another Postgresql written in Rust")
+    .arg(
+        Arg::with_name("datadir")
+            .short("D")
+            .long("datadir")
+            .required(true)
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("db")
+            .long("db")
+            .help("oid of the database")
+            .required(true)
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("table")
+            .long("table")
+            .help("oid of the table")
+            .required(true)
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("force")
+            .long("force")
+            .help("write the manifest, only print it if not set"),
+    )
+    .get_matches();
+    let datadir = cmdline.value_of("datadir").unwrap();
+    std::env::set_current_dir(datadir).unwrap();
+    let table = sv::TableId {
+        db: get_oid(&cmdline, "db"),
+        table: get_oid(&cmdline, "table"),
+    };
+    let walfiles = sv::l0files_from_wal(table).expect("read wal failed");
+    let force = cmdline.is_present("force");
+    let files = sv::rebuild_manifest(table, &walfiles, force).expect("rebuild manifest failed");
+    for file in &files {
+        println!(
+            "L0 file: fileid={} rownum={} len={}",
+            file.fileid, file.rownum, file.len
+        );
+    }
+    if force {
+        log::info!("manifest rebuilt. db={} table={}", table.db, table.table);
+    } else {
+        println!("The manifest is not written, use --force to write it.");
+    }
+}
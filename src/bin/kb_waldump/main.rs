@@ -0,0 +1,142 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use clap::{App, Arg};
+use kuiba::access::csmvcc::CSMvccRmgr;
+use kuiba::access::sv::SVRmgr;
+use kuiba::access::wal::{LocalWalStorage, Lsn, Rmgr, RmgrId, WalReader, XlogRmgr};
+use kuiba::access::xact::XactRmgr;
+use kuiba::replication::{format_lsn, parse_lsn};
+
+// RmgrTable, indexed by RmgrId, None if the rmgr cannot describe its records.
+fn rmgr_table() -> Vec<Option<Box<dyn Rmgr>>> {
+    let mut rmgrs: Vec<Option<Box<dyn Rmgr>>> = Vec::new();
+    let mut v = 0;
+    while let Some(id) = RmgrId::new(v) {
+        let rmgr: Option<Box<dyn Rmgr>> = match id {
+            RmgrId::Xlog => Some(Box::new(XlogRmgr::new())),
+            RmgrId::Xact => Some(Box::new(XactRmgr::new())),
+            RmgrId::SV => Some(Box::new(SVRmgr::new())),
+            RmgrId::CSMvcc => Some(Box::new(CSMvccRmgr::new())),
+        };
+        rmgrs.push(rmgr);
+        v += 1;
+    }
+    return rmgrs;
+}
+
+fn rmgr_name(rmgrs: &[Option<Box<dyn Rmgr>>], id: RmgrId) -> String {
+    match &rmgrs[id as usize] {
+        Some(rmgr) => rmgr.name().to_string(),
+        None => format!("{:?}", id),
+    }
+}
+
+// The lsn is either in the form of kb_controldata, or in the form of X/X as pg_waldump.
+fn get_lsn(cmdline: &clap::ArgMatches, name: &str) -> Option<Lsn> {
+    let v = cmdline.value_of(name)?;
+    let lsn = if v.contains('/') {
+        parse_lsn(v).ok()
+    } else {
+        v.parse().ok()
+    };
+    return Some(lsn.unwrap_or_else(|| panic!("invalid {}: {}", name, v)));
+}
+
+fn main() {
+    let cmdline = App::new("kb_waldump decodes and displays the wal records for debugging.")
+        .version(kuiba::KB_VERSTR)
+        .author("盏一 <w@hidva.com>")
+        .about("KuiBaDB is another Postgresql written in Rust")
+        .arg(
+            Arg::with_name("datadir")
+                .short("D")
+                .long("datadir")
+                .required(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("start")
+                .short("s")
+                .long("start")
+                .help("start reading at the record at this lsn")
+                .required(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("end")
+                .short("e")
+                .long("end")
+                .help("stop reading before the record at or after this lsn")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("rmgr")
+                .short("r")
+                .long("rmgr")
+                .help("only display the records generated by the rmgr, may be given many times")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .get_matches();
+    let datadir = cmdline.value_of("datadir").unwrap();
+    std::env::set_current_dir(datadir).unwrap();
+    let startlsn = get_lsn(&cmdline, "start").unwrap();
+    let endlsn = get_lsn(&cmdline, "end");
+    let rmgrs = rmgr_table();
+    let mut filter = Vec::new();
+    for name in cmdline.values_of("rmgr").into_iter().flatten() {
+        let idx = (0..rmgrs.len())
+            .find(|&idx| {
+                let id = RmgrId::new(idx as u8).unwrap();
+                rmgr_name(&rmgrs, id).eq_ignore_ascii_case(name)
+            })
+            .unwrap_or_else(|| panic!("invalid rmgr: {}", name));
+        filter.push(idx);
+    }
+
+    let mut walreader = WalReader::new(Box::new(LocalWalStorage::new()), startlsn);
+    loop {
+        let lsn = walreader.endlsn;
+        if matches!(endlsn, Some(endlsn) if lsn >= endlsn) {
+            break;
+        }
+        // The last record may be truncated by the crash, it is the end of wal.
+        let (hdr, data) = match walreader.read_record() {
+            Ok(v) => v,
+            Err(err) => {
+                eprintln!("kb_waldump: end of wal at {}: {:#}", format_lsn(lsn), err);
+                if endlsn.is_some() {
+                    std::process::exit(1);
+                }
+                break;
+            }
+        };
+        let idx = hdr.id as usize;
+        if !filter.is_empty() && !filter.contains(&idx) {
+            continue;
+        }
+        let desc = match &rmgrs[idx] {
+            Some(rmgr) => rmgr.descstr(&hdr, &data),
+            None => String::new(),
+        };
+        println!(
+            "rmgr: {} len: {} info: {:#04x} xid: {} lsn: {} prev: {} desc: {}",
+            rmgr_name(&rmgrs, hdr.id),
+            hdr.totlen,
+            hdr.info,
+            hdr.xid.map_or(0, |xid| xid.get()),
+            format_lsn(lsn),
+            hdr.prev.map_or("0/0".to_string(), format_lsn),
+            desc
+        );
+    }
+}
@@ -11,9 +11,14 @@ See the License for the specific language governing permissions and
 limitations under the License.
 */
 use clap::{App, Arg};
-use kuiba::{access::redo::redo, guc, init_log, postgres_main, LAST_INTERNAL_SESSID};
+use kuiba::access::{prewarm, redo::redo};
+use kuiba::commands::vacuum;
+use kuiba::utils::{hba, health, metrics, shutdown, statedump};
+use kuiba::{guc, init_log, postgres_main, LAST_INTERNAL_SESSID};
 use std::net::TcpListener;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
+use std::time::Duration;
 
 fn new_sessid(lastused: &mut u32) -> u32 {
     *lastused += 1;
@@ -42,17 +47,59 @@ fn main() {
     let datadir = cmdline
         .value_of("datadir")
         .expect("You must specify the -D invocation option!");
-    let global_state = redo(&datadir).expect("redo failed");
-    let port = guc::get_int(&global_state.gucstate, guc::Port) as u16;
+    // The health check answers NOT_READY and the connections are rejected during the redo, so
+    // they are started before the redo with the gucs loaded again by GlobalState::init.
+    let gucstate = guc::load(&format!("{}/kuiba.conf", datadir)).expect("load gucs failed");
+    let health_port = guc::get_int(&gucstate, guc::HealthPort) as u16;
+    if health_port != 0 {
+        health::start(health_port).expect("health::start failed");
+    }
+    let port = guc::get_int(&gucstate, guc::Port) as u16;
     let listener = TcpListener::bind(("127.0.0.1", port)).unwrap();
     log::info!("listen. port={}", port);
+    let rejecter = health::reject_until_ready(listener.try_clone().unwrap());
+    let global_state = redo(&datadir).expect("redo failed");
+    statedump::start(global_state.clone()).expect("statedump::start failed");
+    hba::start().expect("hba::start failed");
+    let metrics_port = guc::get_int(&global_state.gucstate, guc::MetricsPort) as u16;
+    if metrics_port != 0 {
+        metrics::start(metrics_port, global_state.clone()).expect("metrics::start failed");
+    }
+    let autoprewarm = guc::get_bool(&global_state.gucstate, guc::Autoprewarm);
+    if autoprewarm {
+        let loaded = prewarm::load(&global_state).expect("prewarm::load failed");
+        log::info!("prewarm: loaded. keys={}", loaded);
+        let interval = guc::get_int(&global_state.gucstate, guc::AutoprewarmInterval).max(1);
+        prewarm::start_dumper(global_state.clone(), Duration::from_secs(interval as u64));
+    }
+    // The standby writes no wal, the dead rows are reclaimed by the primary.
+    let autovac_stop: &'static AtomicBool = Box::leak(Box::new(AtomicBool::new(false)));
+    let autovac = if global_state.replay_lsn.is_none() {
+        Some(vacuum::start_autovacuum(&global_state, autovac_stop))
+    } else {
+        None
+    };
+    let shutdown_latch = shutdown::install().expect("shutdown::install failed");
+    health::set_ready();
+    rejecter.join().unwrap();
     let mut lastused_sessid = LAST_INTERNAL_SESSID;
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
+    while let Some(stream) = shutdown::accept(&listener, shutdown_latch) {
         let global_state = global_state.clone();
         let sessid = new_sessid(&mut lastused_sessid);
         thread::spawn(move || {
             postgres_main(global_state, stream, sessid);
         });
     }
+    log::info!("received smart shutdown request");
+    drop(listener);
+    autovac_stop.store(true, Ordering::Relaxed);
+    if let Some(autovac) = autovac {
+        autovac.join().unwrap();
+    }
+    shutdown::shutdown(&global_state).expect("shutdown failed");
+    if autoprewarm {
+        let dumped = prewarm::dump(&global_state).expect("prewarm::dump failed");
+        log::info!("prewarm: dumped. keys={}", dumped);
+    }
+    log::info!("database system is shut down");
 }
@@ -13,6 +13,7 @@ limitations under the License.
 use crate::utils::SessionState;
 use crate::{kbanyhow, kbensure, Oid, OptOid, DBRELID};
 
+pub mod dependency;
 pub mod namespace;
 
 #[derive(Debug)]
@@ -33,31 +34,74 @@ pub fn column_val<'a>(row: &[(&str, Option<&'a str>)], name: &str) -> Option<&'a
 }
 
 pub fn get_database(datname: &str) -> anyhow::Result<FormDataDatabase> {
+    find_database(&format!("datname = '{}'", datname))
+}
+
+pub fn get_database_by_oid(oid: Oid) -> anyhow::Result<FormDataDatabase> {
+    find_database(&format!("oid = {}", oid))
+}
+
+fn find_database(cond: &str) -> anyhow::Result<FormDataDatabase> {
     let mut retdb: anyhow::Result<FormDataDatabase> = Err(anyhow::anyhow!("not found"));
     let conn = sqlite::open("global/meta.db")?;
-    conn.iterate(
-        format!("select * from kb_database where datname = '{}'", datname),
-        |row| {
-            retdb = Ok(FormDataDatabase {
-                oid: column_val(row, "oid").unwrap().parse().unwrap(),
-                datname: column_val(row, "datname").unwrap().parse().unwrap(),
-                datistemplate: column_val(row, "datistemplate")
-                    .unwrap()
-                    .parse::<i32>()
-                    .unwrap()
-                    == 0,
-                datallowconn: column_val(row, "datallowconn")
-                    .unwrap()
-                    .parse::<i32>()
-                    .unwrap()
-                    == 0,
-            });
-            true
-        },
-    )?;
+    conn.iterate(format!("select * from kb_database where {}", cond), |row| {
+        retdb = Ok(FormDataDatabase {
+            oid: column_val(row, "oid").unwrap().parse().unwrap(),
+            datname: column_val(row, "datname").unwrap().parse().unwrap(),
+            datistemplate: column_val(row, "datistemplate")
+                .unwrap()
+                .parse::<i32>()
+                .unwrap()
+                == 0,
+            datallowconn: column_val(row, "datallowconn")
+                .unwrap()
+                .parse::<i32>()
+                .unwrap()
+                == 0,
+        });
+        true
+    })?;
     retdb
 }
 
+// The rolpassword of the role, None if the role does not exist or has no password.
+pub fn get_role_password(rolname: &str) -> anyhow::Result<Option<String>> {
+    let mut passwd = None;
+    let conn = sqlite::open("global/meta.db")?;
+    let sql = format!(
+        "select rolpassword from kb_authid where rolname = '{}'",
+        rolname.replace('\'', "''")
+    );
+    conn.iterate(sql, |row| {
+        passwd = column_val(row, "rolpassword").map(|v| v.to_string());
+        true
+    })?;
+    return Ok(passwd);
+}
+
+// The spclocation of the tablespace, None for the default tablespace whose files are in base.
+pub fn get_tablespace_location(spcname: &str) -> anyhow::Result<Option<String>> {
+    let mut found = false;
+    let mut location = None;
+    let conn = sqlite::open("global/meta.db")?;
+    let sql = format!(
+        "select spclocation from kb_tablespace where spcname = '{}'",
+        spcname.replace('\'', "''")
+    );
+    conn.iterate(sql, |row| {
+        found = true;
+        location = column_val(row, "spclocation").map(|v| v.to_string());
+        true
+    })?;
+    kbensure!(
+        found,
+        ERRCODE_UNDEFINED_OBJECT,
+        "tablespace \"{}\" does not exist",
+        spcname
+    );
+    return Ok(location);
+}
+
 #[derive(Clone, Copy)]
 pub struct FormOperator {
     pub oid: Oid,
@@ -92,26 +136,22 @@ fn get_oper(
     ))
 }
 
-fn get_opers(
-    state: &SessionState,
-    oprname: &str,
-    oprleft: OptOid,
-    oprright: Oid,
-) -> anyhow::Result<Vec<FormOperator>> {
+fn query_opers(state: &SessionState, cond: String) -> anyhow::Result<Vec<FormOperator>> {
     let mut oprs = Vec::new();
-    let oprleftval: u32 = oprleft.into();
     let sql = format!(
-        "select oid, oprnamespace, oprresult, oprcode from kb_operator where oprname='{}' and oprleft={} and oprright={}",
-        oprname,
-        oprleftval,
-        oprright
+        "select oid, oprnamespace, oprleft, oprright, oprresult, oprcode from kb_operator where {}",
+        cond
     );
     state.metaconn.iterate(sql, |row| {
         oprs.push(FormOperator {
             oid: column_val(row, "oid").unwrap().parse().unwrap(),
             oprnamespace: column_val(row, "oprnamespace").unwrap().parse().unwrap(),
-            oprleft: oprleft,
-            oprright: oprright,
+            oprleft: column_val(row, "oprleft")
+                .unwrap()
+                .parse::<u32>()
+                .unwrap()
+                .into(),
+            oprright: column_val(row, "oprright").unwrap().parse().unwrap(),
             oprresult: column_val(row, "oprresult").unwrap().parse().unwrap(),
             oprcode: column_val(row, "oprcode")
                 .unwrap()
@@ -124,6 +164,55 @@ fn get_opers(
     Ok(oprs)
 }
 
+fn get_opers(
+    state: &SessionState,
+    oprname: &str,
+    oprleft: OptOid,
+    oprright: Oid,
+) -> anyhow::Result<Vec<FormOperator>> {
+    let oprleftval: u32 = oprleft.into();
+    let cond = format!(
+        "oprname='{}' and oprleft={} and oprright={}",
+        oprname, oprleftval, oprright
+    );
+    query_opers(state, cond)
+}
+
+// The operators named oprname in all namespaces, the prefix operators if prefix is set, otherwise
+// the binary operators.
+pub fn get_opers_by_name(
+    state: &SessionState,
+    oprname: &str,
+    prefix: bool,
+) -> anyhow::Result<Vec<FormOperator>> {
+    let cond = format!(
+        "oprname='{}' and oprleft {} 0 order by oid",
+        oprname,
+        if prefix { "=" } else { "<>" }
+    );
+    query_opers(state, cond)
+}
+
+// The function of the implicit cast from source to target, see find_coercion_pathway().
+pub fn get_implicit_cast(
+    state: &SessionState,
+    source: Oid,
+    target: Oid,
+) -> anyhow::Result<Option<Oid>> {
+    let mut castfunc = None;
+    state.metaconn.iterate(
+        format!(
+            "select castfunc from kb_cast where castsource = {} and casttarget = {} and castcontext = {}",
+            source, target, b'i'
+        ),
+        |row| {
+            castfunc = Oid::new(column_val(row, "castfunc").unwrap().parse().unwrap());
+            true
+        },
+    )?;
+    return Ok(castfunc);
+}
+
 #[repr(u8)]
 #[derive(Copy, Clone)]
 pub enum ProKind {
@@ -205,6 +294,38 @@ pub fn get_proc(state: &SessionState, oid: Oid) -> anyhow::Result<FormProc> {
     ret
 }
 
+pub struct FuncCandidate {
+    pub oid: Oid,
+    pub pronamespace: Oid,
+    pub proargtypes: Vec<Oid>,
+}
+
+// The functions named proname with nargs arguments.
+pub fn get_func_candidates(
+    state: &SessionState,
+    proname: &str,
+    nargs: usize,
+) -> anyhow::Result<Vec<FuncCandidate>> {
+    let mut cands = Vec::new();
+    let sql = format!(
+        "select oid, pronamespace, proargtypes from kb_proc where proname = '{}' and pronargs = {}",
+        proname, nargs
+    );
+    state.metaconn.iterate(sql, |row| {
+        cands.push(FuncCandidate {
+            oid: column_val(row, "oid").unwrap().parse().unwrap(),
+            pronamespace: column_val(row, "pronamespace").unwrap().parse().unwrap(),
+            proargtypes: column_val(row, "proargtypes")
+                .unwrap()
+                .split_ascii_whitespace()
+                .map(|v| v.parse().unwrap())
+                .collect(),
+        });
+        true
+    })?;
+    Ok(cands)
+}
+
 pub struct FormType {
     pub id: Oid,
     pub len: i16,
@@ -277,6 +398,18 @@ pub fn get_type_output_info(state: &SessionState, oid: Oid) -> anyhow::Result<(O
     return Ok((formtype.output, formtype.len));
 }
 
+// get_typlenbyvalalign
+pub fn get_typlenalign(state: &SessionState, oid: Oid) -> anyhow::Result<(i16, u8)> {
+    let formtype = get_type(state, oid)?;
+    kbensure!(
+        formtype.isdefined,
+        ERRCODE_UNDEFINED_OBJECT,
+        "type {} is only a shell",
+        oid
+    );
+    return Ok((formtype.len, formtype.align));
+}
+
 pub fn get_type_input_info(state: &SessionState, oid: Oid) -> anyhow::Result<Oid> {
     let formtype = get_type(state, oid)?;
     kbensure!(
@@ -288,6 +421,23 @@ pub fn get_type_input_info(state: &SessionState, oid: Oid) -> anyhow::Result<Oid
     return Ok(formtype.input);
 }
 
+// get_rel_namespace
+pub fn get_rel_namespace(state: &SessionState, reloid: Oid) -> anyhow::Result<Oid> {
+    let mut nsoid: Option<Oid> = None;
+    let sql = format!("select relnamespace from kb_class where oid = {}", reloid);
+    state.metaconn.iterate(sql, |row| {
+        nsoid = Some(column_val(row, "relnamespace").unwrap().parse().unwrap());
+        true
+    })?;
+    return nsoid.ok_or_else(|| {
+        kbanyhow!(
+            ERRCODE_UNDEFINED_TABLE,
+            "relation {} does not exist",
+            reloid
+        )
+    });
+}
+
 // get_relname_relid
 pub fn relname_get_relid(
     state: &SessionState,
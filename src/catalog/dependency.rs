@@ -0,0 +1,104 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// There is no kb_depend, the dependencies are computed from the catalog. Only the column
+// depending on its type and the relation/type depending on its namespace are tracked.
+use super::column_val;
+use crate::utils::{AttrNumber, SessionState};
+use crate::{Oid, FIRST_NORMAL_OBJECT_ID, KBPUBLICNS};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DependentObject {
+    Column {
+        table: Oid,
+        relname: String,
+        attnum: AttrNumber,
+        attname: String,
+    },
+    Table {
+        oid: Oid,
+        relname: String,
+    },
+    Type {
+        oid: Oid,
+        typname: String,
+    },
+}
+
+// getObjectDescription
+impl fmt::Display for DependentObject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DependentObject::Column {
+                relname, attname, ..
+            } => write!(f, "column {} of table {}", attname, relname),
+            DependentObject::Table { relname, .. } => write!(f, "table {}", relname),
+            DependentObject::Type { typname, .. } => write!(f, "type {}", typname),
+        }
+    }
+}
+
+// IsPinnedObject, the objects created by initdb are required by the database system except the
+// public schema, just as PostgreSQL.
+pub fn is_pinned(oid: Oid) -> bool {
+    return oid < FIRST_NORMAL_OBJECT_ID && oid != KBPUBLICNS;
+}
+
+// The live columns whose type is typoid.
+pub fn type_dependents(state: &SessionState, typoid: Oid) -> anyhow::Result<Vec<DependentObject>> {
+    let mut ret = Vec::new();
+    let sql = format!(
+        "select attrelid, attnum, attname, relname from kb_attribute, kb_class \
+         where atttypid = {} and attisdropped = 0 and attrelid = kb_class.oid \
+         order by attrelid, attnum",
+        typoid
+    );
+    state.metaconn.iterate(sql, |row| {
+        ret.push(DependentObject::Column {
+            table: column_val(row, "attrelid").unwrap().parse().unwrap(),
+            relname: column_val(row, "relname").unwrap().to_string(),
+            attnum: column_val(row, "attnum").unwrap().parse().unwrap(),
+            attname: column_val(row, "attname").unwrap().to_string(),
+        });
+        true
+    })?;
+    return Ok(ret);
+}
+
+// The types and relations in the namespace, the types come first so that their columns are
+// dropped before the tables.
+pub fn ns_dependents(state: &SessionState, nsoid: Oid) -> anyhow::Result<Vec<DependentObject>> {
+    let mut ret = Vec::new();
+    let sql = format!(
+        "select oid, typname from kb_type where typnamespace = {} order by oid",
+        nsoid
+    );
+    state.metaconn.iterate(sql, |row| {
+        ret.push(DependentObject::Type {
+            oid: column_val(row, "oid").unwrap().parse().unwrap(),
+            typname: column_val(row, "typname").unwrap().to_string(),
+        });
+        true
+    })?;
+    let sql = format!(
+        "select oid, relname from kb_class where relnamespace = {} order by oid",
+        nsoid
+    );
+    state.metaconn.iterate(sql, |row| {
+        ret.push(DependentObject::Table {
+            oid: column_val(row, "oid").unwrap().parse().unwrap(),
+            relname: column_val(row, "relname").unwrap().to_string(),
+        });
+        true
+    })?;
+    return Ok(ret);
+}
@@ -12,12 +12,14 @@
 use super::column_val;
 use crate::access::lmgr::LockMode;
 use crate::access::lmgr::SessionExt as LMGRSessionExt;
-use crate::catalog::{self, get_oper, get_opers, FormOperator};
+use crate::catalog::{
+    self, get_func_candidates, get_oper, get_opers, get_opers_by_name, FormOperator,
+};
 use crate::catalog::{qualname_get_type, FormType};
 use crate::guc;
 use crate::parser::syn;
 use crate::utils::SessionState;
-use crate::{kbanyhow, kbbail, Oid, OptOid};
+use crate::{kbanyhow, kbbail, Oid, OptOid, ANYOID};
 use std::sync::Arc;
 
 #[derive(Default)]
@@ -34,7 +36,7 @@ pub trait SessionExt {
     ) -> anyhow::Result<(Option<&'a str>, &'a str)>;
     // QualifiedNameGetCreationNamespace
     fn qualname_get_create_ns<'a>(
-        &self,
+        &mut self,
         names: &'a Vec<syn::StrVal>,
     ) -> anyhow::Result<(Oid, &'a str)>;
 
@@ -54,6 +56,21 @@ pub trait SessionExt {
         oprright: Oid,
     ) -> anyhow::Result<FormOperator>;
 
+    // OpernameGetCandidates, the operators hidden by the one with the same arguments in the
+    // earlier namespace of search path are not returned.
+    fn opername_get_candidates(
+        &mut self,
+        names: &Vec<syn::StrVal>,
+        prefix: bool,
+    ) -> anyhow::Result<Vec<FormOperator>>;
+
+    // FuncnameGetCandidates + func_select_candidate, only the exact match and ANY are supported.
+    fn funcname_get_oid(
+        &mut self,
+        names: &Vec<syn::StrVal>,
+        argtypes: &[Oid],
+    ) -> anyhow::Result<Oid>;
+
     fn get_search_path(&mut self) -> &Vec<Oid>;
 
     fn lookup_explicit_namespace(&self, nspname: &str) -> anyhow::Result<Oid>;
@@ -68,7 +85,7 @@ pub trait SessionExt {
     fn relname_get_oid(&mut self, n: &str) -> anyhow::Result<Option<Oid>>;
 }
 
-fn oid_in_used(sess: &SessionState, oid: Oid, catalog: &str) -> anyhow::Result<bool> {
+pub fn oid_in_used(sess: &SessionState, oid: Oid, catalog: &str) -> anyhow::Result<bool> {
     let mut isns = false;
     sess.metaconn.iterate(
         format!("select oid from {} where oid = {}", catalog, oid),
@@ -86,17 +103,18 @@ fn oid_is_ns(sess: &SessionState, nsoid: Oid) -> anyhow::Result<bool> {
 
 impl SessionExt for SessionState {
     fn qualname_get_create_ns<'a>(
-        &self,
+        &mut self,
         names: &'a Vec<syn::StrVal>,
     ) -> anyhow::Result<(Oid, &'a str)> {
         let (nspname, name) = self.deconstruct_qualname(names)?;
         let nspoid = match nspname {
-            None => {
-                kbbail!(
+            None => match self.get_search_path().first() {
+                Some(&oid) => oid,
+                None => kbbail!(
                     ERRCODE_UNDEFINED_SCHEMA,
                     "no schema has been selected to create in"
-                );
-            }
+                ),
+            },
             Some(nspname) => self.get_namespace_oid(nspname)?,
         };
         Ok((nspoid, name))
@@ -188,6 +206,72 @@ impl SessionExt for SessionState {
         );
     }
 
+    fn opername_get_candidates(
+        &mut self,
+        names: &Vec<syn::StrVal>,
+        prefix: bool,
+    ) -> anyhow::Result<Vec<FormOperator>> {
+        let (schemaname, opername) = self.deconstruct_qualname(names)?;
+        let mut opers = get_opers_by_name(self, opername, prefix)?;
+        if let Some(schemaname) = schemaname {
+            let nspoid = self.lookup_explicit_namespace(schemaname)?;
+            opers.retain(|oper| oper.oprnamespace == nspoid);
+            return Ok(opers);
+        }
+        let mut cands: Vec<FormOperator> = Vec::new();
+        for &nspoid in self.get_search_path() {
+            for oper in &opers {
+                if oper.oprnamespace != nspoid {
+                    continue;
+                }
+                let hidden = cands
+                    .iter()
+                    .any(|cand| cand.oprleft.0 == oper.oprleft.0 && cand.oprright == oper.oprright);
+                if !hidden {
+                    cands.push(*oper);
+                }
+            }
+        }
+        return Ok(cands);
+    }
+
+    fn funcname_get_oid(
+        &mut self,
+        names: &Vec<syn::StrVal>,
+        argtypes: &[Oid],
+    ) -> anyhow::Result<Oid> {
+        let (schemaname, funcname) = self.deconstruct_qualname(names)?;
+        let mut cands = get_func_candidates(self, funcname, argtypes.len())?;
+        cands.retain(|cand| {
+            cand.proargtypes
+                .iter()
+                .zip(argtypes)
+                .all(|(&declared, &actual)| declared == actual || declared == ANYOID)
+        });
+        if let Some(schemaname) = schemaname {
+            let nspoid = self.lookup_explicit_namespace(schemaname)?;
+            cands.retain(|cand| cand.pronamespace == nspoid);
+            if let Some(cand) = cands.first() {
+                return Ok(cand.oid);
+            }
+        } else {
+            for &nspoid in self.get_search_path() {
+                for cand in &cands {
+                    if cand.pronamespace == nspoid {
+                        return Ok(cand.oid);
+                    }
+                }
+            }
+        }
+        let argtypes: Vec<String> = argtypes.iter().map(|v| v.to_string()).collect();
+        kbbail!(
+            ERRCODE_UNDEFINED_FUNCTION,
+            "function {}({}) does not exist",
+            funcname,
+            argtypes.join(", ")
+        );
+    }
+
     fn rv_get_create_ns(&mut self, rv: &syn::RangeVar<'_>) -> anyhow::Result<Oid> {
         if let Some(ref sn) = rv.schemaname {
             return self.get_namespace_oid(sn);
@@ -205,7 +289,7 @@ impl SessionExt for SessionState {
 
     fn rv_get_and_chk_create_ns(&mut self, rv: &syn::RangeVar<'_>) -> anyhow::Result<Oid> {
         let nsoid = self.rv_get_create_ns(rv)?;
-        self.lock_ns(nsoid, LockMode::AccessShare);
+        self.lock_ns(nsoid, LockMode::AccessShare)?;
         // Oid is never reused! so if the oid is still a namespace, it means that
         // nsoid got by rv_get_create_ns() is still valid.
         if !oid_is_ns(self, nsoid)? {
@@ -220,8 +304,8 @@ impl SessionExt for SessionState {
     fn typname_get_type(&mut self, typname: &str) -> anyhow::Result<Option<FormType>> {
         self.get_search_path();
         for &nsoid in &self.nsstate.search_path {
-            if let Ok(t) = qualname_get_type(self, nsoid, typname) {
-                return Ok(t);
+            if let Ok(Some(t)) = qualname_get_type(self, nsoid, typname) {
+                return Ok(Some(t));
             }
         }
         return Ok(None);
@@ -249,7 +333,7 @@ impl SessionExt for SessionState {
             if mode == LockMode::NoLock {
                 return Ok(reloid);
             }
-            self.lock_rel(reloid, mode);
+            self.lock_rel(reloid, mode)?;
             if oid_in_used(self, reloid, "kb_class")? {
                 // Oid is never reused! so if the oid is still in kb_class, it means that
                 // nsoid got by relname_get_oid() is still valid.
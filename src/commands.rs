@@ -10,6 +10,14 @@
 // limitations under the License.
 
 pub mod copy;
+pub mod discard;
+pub mod dropcmds;
+pub mod explain;
+pub mod insert;
 pub mod lockcmds;
+pub mod notify;
+pub mod schemacmds;
 pub mod tablecmds;
 pub mod typecmds;
+pub mod vacuum;
+pub mod verify;
@@ -16,26 +16,42 @@ use crate::access::sv;
 use crate::access::xact::SessionExt as XACTSessionExt;
 use crate::catalog::get_type_input_info;
 use crate::catalog::namespace::SessionExt as NSSessionExt;
-use crate::datums::Datums;
+use crate::datums::{Datums, TypedColumn};
 use crate::guc;
 use crate::parser::syn;
 use crate::parser::syn::RangeVar;
 use crate::utility::Response;
+use crate::utils::adt::{binary_recv, binary_send, parse_bool};
 use crate::utils::fmgr::{call_inproc, FmgrInfo};
-use crate::utils::{SessionState, WorkerExitGuard, WorkerState};
-use crate::{kbbail, kbensure};
-use crossbeam_channel::{bounded, Receiver};
+use crate::utils::ser::{ser_be_i16, ser_be_i32, ser_be_i32_at};
+use crate::utils::{wait_workers, SessionState, WorkerExitGuard, WorkerState};
+use crate::{kbanyhow, kbbail, kbensure};
+use byteorder::{NetworkEndian, ReadBytesExt};
+use crossbeam_channel::{bounded, Receiver, Sender};
 use std::fs::File;
-use std::io::{BufRead, BufReader};
-use std::mem::forget;
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::mem::{forget, replace};
+use std::ops::Range;
 use std::rc::Rc;
 
 // pub fn lock_stmt(sess: &mut SessionState, lock: &syn::LockStmt<'_>) -> anyhow::Result<Response> {
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum CopyFormat {
+    Text,
+    Csv,
+    Binary,
+}
+
 struct CopyOpts<'syn> {
+    format: CopyFormat,
     delim: &'syn str,
     parallel: usize,
     null: &'syn str,
+    // quote, escape and header are only used in the CSV format.
+    quote: u8,
+    escape: u8,
+    header: bool,
 }
 
 struct CopyFromArgs {
@@ -45,9 +61,11 @@ struct CopyFromArgs {
     l0file: sv::FileMeta,
     typins: Vec<FmgrInfo>,
     mvccbuf: &'static MVCCBuf,
+    // The input is already in the layout of the types, typins is empty.
+    binary: bool,
 }
 
-fn new_indatums(attcnt: usize, batch_size: u32) -> Vec<Datums> {
+pub fn new_indatums(attcnt: usize, batch_size: u32) -> Vec<Datums> {
     let mut indatums = Vec::with_capacity(attcnt);
     indatums.resize_with(attcnt, || {
         let mut datums = Datums::new();
@@ -58,7 +76,7 @@ fn new_indatums(attcnt: usize, batch_size: u32) -> Vec<Datums> {
     return indatums;
 }
 
-fn indatums2data(
+pub fn indatums2data(
     indatums: Vec<Datums>,
     typmods: &[Rc<Datums>],
     typins: &[FmgrInfo],
@@ -87,17 +105,390 @@ fn copyfrommain(args: CopyFromArgs, worker: &mut WorkerState) -> anyhow::Result<
     for attr in &args.rel.attrs {
         typmods.push(Rc::new(Datums::new_single_fixedlen(attr.typ.mode)));
     }
-    debug_assert_eq!(attcnt, args.typins.len());
+    debug_assert!(args.binary || attcnt == args.typins.len());
     let mut l0writer = cs::L0Writer::new(args.tabid, args.rel, args.l0file);
     for (indatums, inrownum) in args.inrec.iter() {
         debug_assert_eq!(indatums.len(), attcnt);
-        let outs = indatums2data(indatums, &typmods, &args.typins, worker)?;
+        let outs = if args.binary {
+            indatums.into_iter().map(Rc::new).collect()
+        } else {
+            indatums2data(indatums, &typmods, &args.typins, worker)?
+        };
         l0writer.write(outs, inrownum)?;
     }
     l0writer.sync(worker, args.mvccbuf)?;
     return Ok(l0writer.meta);
 }
 
+// Set the field of the input row, None means NULL.
+fn set_infield(
+    indatums: &mut [Datums],
+    colidx: usize,
+    rowidx: isize,
+    field: Option<&[u8]>,
+) -> anyhow::Result<()> {
+    kbensure!(
+        colidx < indatums.len(),
+        ERRCODE_BAD_COPY_FILE_FORMAT,
+        "extra data after last expected column",
+    );
+    match field {
+        None => {
+            indatums[colidx].set_null_at(rowidx);
+            indatums[colidx].set_empty_at(rowidx);
+        }
+        Some(v) => indatums[colidx].set_varchar_at(rowidx, v),
+    }
+    return Ok(());
+}
+
+// Read a line without the trailing newline, returns false at EOF.
+fn read_text_line(input: &mut impl BufRead, line: &mut String) -> anyhow::Result<bool> {
+    line.clear();
+    if input.read_line(line)? == 0 {
+        return Ok(false);
+    }
+    if line.ends_with('\n') {
+        line.pop();
+        if line.ends_with('\r') {
+            line.pop();
+        }
+    }
+    return Ok(true);
+}
+
+// A record of the CSV input, the fields are the ranges of buf, None means NULL.
+struct CsvRecord {
+    line: Vec<u8>,
+    buf: Vec<u8>,
+    fields: Vec<Option<Range<usize>>>,
+}
+
+impl CsvRecord {
+    fn new() -> Self {
+        Self {
+            line: Vec::new(),
+            buf: Vec::new(),
+            fields: Vec::new(),
+        }
+    }
+
+    // CopyReadLine in the CSV mode, the newline in the quoted field does not end the record.
+    fn read_line(&mut self, input: &mut impl BufRead, opts: &CopyOpts) -> anyhow::Result<bool> {
+        self.line.clear();
+        let mut inquote = false;
+        let mut idx = 0;
+        loop {
+            if input.read_until(b'\n', &mut self.line)? == 0 {
+                if self.line.is_empty() {
+                    return Ok(false);
+                }
+                kbensure!(
+                    !inquote,
+                    ERRCODE_BAD_COPY_FILE_FORMAT,
+                    "unterminated CSV quoted field"
+                );
+                break;
+            }
+            while idx < self.line.len() {
+                let c = self.line[idx];
+                idx += 1;
+                if inquote && c == opts.escape && opts.escape != opts.quote {
+                    // The escaped char is skipped, which is never the newline ending the line.
+                    if let Some(&n) = self.line.get(idx) {
+                        if n == opts.quote || n == opts.escape {
+                            idx += 1;
+                        }
+                    }
+                } else if c == opts.quote {
+                    inquote = !inquote;
+                }
+            }
+            if !inquote {
+                break;
+            }
+        }
+        if self.line.ends_with(b"\n") {
+            self.line.pop();
+            if self.line.ends_with(b"\r") {
+                self.line.pop();
+            }
+        }
+        kbensure!(
+            std::str::from_utf8(&self.line).is_ok(),
+            ERRCODE_BAD_COPY_FILE_FORMAT,
+            "invalid byte sequence for encoding \"UTF8\""
+        );
+        return Ok(true);
+    }
+
+    // CopyReadAttributesCSV, only the unquoted field matching the null string is NULL, so that
+    // "" is the empty string even if the null string is empty.
+    fn split(&mut self, opts: &CopyOpts) -> anyhow::Result<()> {
+        let delim = opts.delim.as_bytes()[0];
+        let line = &self.line;
+        self.buf.clear();
+        self.fields.clear();
+        let mut idx = 0;
+        loop {
+            let start = self.buf.len();
+            let rawstart = idx;
+            let mut saw_quote = false;
+            while idx < line.len() && line[idx] != delim {
+                let c = line[idx];
+                idx += 1;
+                if c != opts.quote {
+                    self.buf.push(c);
+                    continue;
+                }
+                saw_quote = true;
+                loop {
+                    kbensure!(
+                        idx < line.len(),
+                        ERRCODE_BAD_COPY_FILE_FORMAT,
+                        "unterminated CSV quoted field"
+                    );
+                    let c = line[idx];
+                    idx += 1;
+                    if c == opts.escape {
+                        if let Some(&n) = line.get(idx) {
+                            if n == opts.quote || n == opts.escape {
+                                self.buf.push(n);
+                                idx += 1;
+                                continue;
+                            }
+                        }
+                    }
+                    if c == opts.quote {
+                        break;
+                    }
+                    self.buf.push(c);
+                }
+            }
+            if !saw_quote && &line[rawstart..idx] == opts.null.as_bytes() {
+                self.buf.truncate(start);
+                self.fields.push(None);
+            } else {
+                self.fields.push(Some(start..self.buf.len()));
+            }
+            if idx >= line.len() {
+                break;
+            }
+            idx += 1;
+        }
+        return Ok(());
+    }
+
+    // Read the next record, returns false at EOF.
+    fn read(&mut self, input: &mut impl BufRead, opts: &CopyOpts) -> anyhow::Result<bool> {
+        if !self.read_line(input, opts)? {
+            return Ok(false);
+        }
+        self.split(opts)?;
+        return Ok(true);
+    }
+}
+
+// Send the input rows to the workers, returns the number of rows sent. Stops early if all
+// workers have exited, the error of workers is returned by wait_workers().
+fn send_input(
+    mut input: impl BufRead,
+    opts: &CopyOpts,
+    attcnt: usize,
+    batch_size: u32,
+    datas: Sender<(Vec<Datums>, u32)>,
+) -> anyhow::Result<u64> {
+    let mut totalrows = 0u64;
+    let mut inrownum = 0isize;
+    let mut indatums = new_indatums(attcnt, batch_size);
+    let mut line = String::new();
+    let mut csv = CsvRecord::new();
+    if opts.header && !csv.read(&mut input, opts)? {
+        return Ok(totalrows);
+    }
+    loop {
+        let colcnt = if opts.format == CopyFormat::Csv {
+            if !csv.read(&mut input, opts)? {
+                break;
+            }
+            for (colidx, field) in csv.fields.iter().enumerate() {
+                let field = field.clone().map(|r| &csv.buf[r]);
+                set_infield(&mut indatums, colidx, inrownum, field)?;
+            }
+            csv.fields.len()
+        } else {
+            if !read_text_line(&mut input, &mut line)? {
+                break;
+            }
+            let mut colidx = 0usize;
+            for colstr in line.split(opts.delim) {
+                let field = if colstr == opts.null {
+                    None
+                } else {
+                    Some(colstr.as_bytes())
+                };
+                set_infield(&mut indatums, colidx, inrownum, field)?;
+                colidx += 1;
+            }
+            colidx
+        };
+        kbensure!(
+            colcnt == attcnt,
+            ERRCODE_BAD_COPY_FILE_FORMAT,
+            "missing data for column",
+        );
+        inrownum += 1;
+        if inrownum >= batch_size as isize {
+            let batch = replace(&mut indatums, new_indatums(attcnt, batch_size));
+            if datas.send((batch, inrownum as u32)).is_err() {
+                return Ok(totalrows);
+            }
+            totalrows += inrownum as u64;
+            inrownum = 0;
+        }
+    }
+    if inrownum > 0 {
+        for indatum in &mut indatums {
+            indatum.truncate(inrownum as u32);
+        }
+        if datas.send((indatums, inrownum as u32)).is_ok() {
+            totalrows += inrownum as u64;
+        }
+    }
+    return Ok(totalrows);
+}
+
+// The signature of the binary COPY file, see BinarySignature in copyfromparse.c.
+const BINARY_SIGNATURE: &[u8; 11] = b"PGCOPY\n\xff\r\n\0";
+// The flag bit of the binary COPY file header indicating the OIDs are included.
+const BINARY_FLAG_OIDS: i32 = 1 << 16;
+
+fn copy_eof(err: std::io::Error) -> anyhow::Error {
+    if err.kind() == ErrorKind::UnexpectedEof {
+        return kbanyhow!(ERRCODE_BAD_COPY_FILE_FORMAT, "unexpected EOF in COPY data");
+    }
+    return err.into();
+}
+
+// ReceiveCopyBinaryHeader
+fn read_binary_header(input: &mut impl Read) -> anyhow::Result<()> {
+    let mut sig = [0u8; BINARY_SIGNATURE.len()];
+    input.read_exact(&mut sig).map_err(copy_eof)?;
+    kbensure!(
+        &sig == BINARY_SIGNATURE,
+        ERRCODE_BAD_COPY_FILE_FORMAT,
+        "COPY file signature not recognized"
+    );
+    let flags = input.read_i32::<NetworkEndian>().map_err(copy_eof)?;
+    kbensure!(
+        flags & BINARY_FLAG_OIDS == 0,
+        ERRCODE_BAD_COPY_FILE_FORMAT,
+        "invalid COPY file header (WITH OIDS)"
+    );
+    kbensure!(
+        (flags as u32) & 0xffff0000 == 0,
+        ERRCODE_BAD_COPY_FILE_FORMAT,
+        "unrecognized critical flags in COPY file header"
+    );
+    let extlen = input.read_i32::<NetworkEndian>().map_err(copy_eof)?;
+    kbensure!(
+        extlen >= 0,
+        ERRCODE_BAD_COPY_FILE_FORMAT,
+        "invalid COPY file header (missing length)"
+    );
+    let skipped = std::io::copy(&mut input.take(extlen as u64), &mut std::io::sink())?;
+    kbensure!(
+        skipped == extlen as u64,
+        ERRCODE_BAD_COPY_FILE_FORMAT,
+        "invalid COPY file header (wrong length)"
+    );
+    return Ok(());
+}
+
+fn new_binary_indatums(rel: &rel::Rel, batch_size: u32) -> Vec<Datums> {
+    let mut indatums = Vec::with_capacity(rel.attrs.len());
+    for attr in &rel.attrs {
+        let mut datums = Datums::new();
+        datums.resize_fixedlen(batch_size, attr.typ.len as usize, attr.typ.align as usize);
+        datums.set_notnull_all();
+        indatums.push(datums);
+    }
+    return indatums;
+}
+
+// CopyReadBinaryTuple, send_input() for the binary format.
+fn send_binary_input(
+    mut input: impl Read,
+    rel: &rel::Rel,
+    batch_size: u32,
+    datas: Sender<(Vec<Datums>, u32)>,
+) -> anyhow::Result<u64> {
+    read_binary_header(&mut input)?;
+    let attcnt = rel.attrs.len();
+    let mut totalrows = 0u64;
+    let mut inrownum = 0isize;
+    let mut indatums = new_binary_indatums(rel, batch_size);
+    let mut fld = Vec::new();
+    loop {
+        let fldcnt = input.read_i16::<NetworkEndian>().map_err(copy_eof)?;
+        if fldcnt == -1 {
+            let mut extra = [0u8; 1];
+            kbensure!(
+                input.read(&mut extra)? == 0,
+                ERRCODE_BAD_COPY_FILE_FORMAT,
+                "received copy data after EOF marker"
+            );
+            break;
+        }
+        kbensure!(
+            fldcnt as isize == attcnt as isize,
+            ERRCODE_BAD_COPY_FILE_FORMAT,
+            "row field count is {}, expected {}",
+            fldcnt,
+            attcnt
+        );
+        for (colidx, attr) in rel.attrs.iter().enumerate() {
+            let fldlen = input.read_i32::<NetworkEndian>().map_err(copy_eof)?;
+            if fldlen == -1 {
+                indatums[colidx].set_null_at(inrownum);
+                continue;
+            }
+            kbensure!(
+                fldlen >= 0,
+                ERRCODE_BAD_COPY_FILE_FORMAT,
+                "invalid field size"
+            );
+            fld.clear();
+            // take() keeps a bogus length from allocating the memory.
+            input.by_ref().take(fldlen as u64).read_to_end(&mut fld)?;
+            kbensure!(
+                fld.len() == fldlen as usize,
+                ERRCODE_BAD_COPY_FILE_FORMAT,
+                "unexpected EOF in COPY data"
+            );
+            binary_recv(attr.typ.id, &fld, &mut indatums[colidx], inrownum)?;
+        }
+        inrownum += 1;
+        if inrownum >= batch_size as isize {
+            let batch = replace(&mut indatums, new_binary_indatums(rel, batch_size));
+            if datas.send((batch, inrownum as u32)).is_err() {
+                return Ok(totalrows);
+            }
+            totalrows += inrownum as u64;
+            inrownum = 0;
+        }
+    }
+    if inrownum > 0 {
+        for indatum in &mut indatums {
+            indatum.truncate(inrownum as u32);
+        }
+        if datas.send((indatums, inrownum as u32)).is_ok() {
+            totalrows += inrownum as u64;
+        }
+    }
+    return Ok(totalrows);
+}
+
 fn copyfrom(
     dest: &RangeVar<'_>,
     input: impl BufRead,
@@ -111,9 +502,23 @@ fn copyfrom(
         table: tableoid,
     };
     let destrel = rel::getrel(sess, tableoid)?;
+    kbensure!(
+        destrel.attrs.iter().all(|attr| !attr.dropped),
+        ERRCODE_FEATURE_NOT_SUPPORTED,
+        "COPY FROM a table with dropped columns is not supported"
+    );
     let attcnt = destrel.attrs.len();
     let mut typins = Vec::with_capacity(destrel.attrs.len());
     for attr in &destrel.attrs {
+        if opts.format == CopyFormat::Binary {
+            kbensure!(
+                attr.typ.len > 0,
+                ERRCODE_FEATURE_NOT_SUPPORTED,
+                "binary COPY of the variable-length column {} is not supported",
+                attr.name
+            );
+            continue;
+        }
         let typinoid = get_type_input_info(sess, attr.typ.id)?;
         let typin = FmgrInfo::new(typinoid, sess.fmgr_builtins)?;
         typins.push(typin);
@@ -141,69 +546,102 @@ fn copyfrom(
         l0file: l0files[idx],
         typins: typins.clone(),
         mvccbuf: mvcc,
+        binary: opts.format == CopyFormat::Binary,
     };
     let workerrec = sess.exec(opts.parallel, arggen, copyfrommain);
+    // Only the workers receive the input, so that sending fails once all workers exit.
+    drop(datar);
     // worker_exit_guard
     let worker_exit_guard = WorkerExitGuard::new(&workerrec);
-
-    let mut totalrows = 0u64;
-    let mut inrownum = 0isize;
-    let mut indatums = new_indatums(attcnt, batch_size);
-    for line in input.lines() {
-        let line = line?;
-        let mut colidx = 0usize;
-        for colstr in line.split(opts.delim) {
-            kbensure!(
-                colidx < attcnt,
-                ERRCODE_BAD_COPY_FILE_FORMAT,
-                "extra data after last expected column",
-            );
-            if colstr == opts.null {
-                indatums[colidx].set_null_at(inrownum);
-                indatums[colidx].set_empty_at(inrownum);
-            } else {
-                indatums[colidx].set_varchar_at(inrownum, colstr.as_bytes());
-            }
-            colidx += 1;
-        }
-        kbensure!(
-            colidx == attcnt,
-            ERRCODE_BAD_COPY_FILE_FORMAT,
-            "missing data for column",
-        );
-        inrownum += 1;
-        if inrownum >= batch_size as isize {
-            datas.send((indatums, inrownum as u32))?;
-            indatums = new_indatums(attcnt, batch_size);
-            totalrows += inrownum as u64;
-            inrownum = 0;
-        }
-    }
-    if inrownum > 0 {
-        for indatum in &mut indatums {
-            indatum.set_len(inrownum as u32);
-        }
-        datas.send((indatums, inrownum as u32))?;
-        totalrows += inrownum as u64;
-    }
-    drop(datas);
+    // The workers exit after datas is dropped by send_input().
+    let sent = if opts.format == CopyFormat::Binary {
+        send_binary_input(input, &destrel, batch_size, datas)
+    } else {
+        send_input(input, opts, attcnt, batch_size, datas)
+    };
+    let workerrets = wait_workers(&workerrec)?;
+    let totalrows = sent?;
 
     let mut l0newmeta = Vec::with_capacity(opts.parallel);
-    for (workexit, filemeta) in workerrec.iter() {
-        let filemeta = filemeta?;
+    for (workexit, filemeta) in workerrets {
         l0newmeta.push(filemeta);
         sess.exit_worker(workexit);
     }
     forget(worker_exit_guard);
     sv::commit_write(sess, &svslot, &l0newmeta);
     forget(abort_guard);
+    sess.tabstat.count_insert(tableid, totalrows);
+    return Ok(totalrows);
+}
+
+// CopyTo in the binary format, returns the number of rows written.
+fn copyto(src: &RangeVar<'_>, output: impl Write, sess: &mut SessionState) -> anyhow::Result<u64> {
+    let tableoid = sess.rv_get_oid(src, LockMode::AccessShare)?;
+    let tableid = sv::TableId {
+        db: sess.reqdb,
+        table: tableoid,
+    };
+    let srcrel = rel::getrel(sess, tableoid)?;
+    // The dropped columns are still in the data files, they are skipped when writing.
+    let attcnt = srcrel.attrs.iter().filter(|attr| !attr.dropped).count();
+    let worker = sess.new_worker();
+    let mut scan = cs::TableScan::new(tableid, srcrel.clone(), &worker)?;
+    let mut output = BufWriter::new(output);
+    let mut buf = Vec::new();
+    buf.extend_from_slice(BINARY_SIGNATURE);
+    ser_be_i32(&mut buf, 0); // flags
+    ser_be_i32(&mut buf, 0); // header extension length
+    let mut totalrows = 0u64;
+    let mut cols = Vec::with_capacity(srcrel.attrs.len());
+    while let Some(rownum) = scan.next(&worker, &mut cols)? {
+        worker.check_termreq()?;
+        let mut typed = Vec::with_capacity(attcnt);
+        let live = cols
+            .iter()
+            .zip(&srcrel.attrs)
+            .filter(|(_, attr)| !attr.dropped);
+        for (col, attr) in live {
+            typed.push(TypedColumn::new(attr.typ.id, col).ok_or_else(|| {
+                kbanyhow!(
+                    ERRCODE_FEATURE_NOT_SUPPORTED,
+                    "no binary output function available for type {}",
+                    attr.typ.id
+                )
+            })?);
+        }
+        for idx in 0..rownum as isize {
+            ser_be_i16(&mut buf, attcnt as i16);
+            for col in &typed {
+                match col.get(idx) {
+                    None => ser_be_i32(&mut buf, -1),
+                    Some(val) => {
+                        let lenoff = buf.len();
+                        ser_be_i32(&mut buf, 0);
+                        binary_send(val, &mut buf);
+                        let fldlen = buf.len() - lenoff - 4;
+                        ser_be_i32_at(&mut buf, lenoff, fldlen as i32);
+                    }
+                }
+            }
+        }
+        output.write_all(&buf)?;
+        buf.clear();
+        totalrows += rownum as u64;
+    }
+    ser_be_i16(&mut buf, -1); // trailer
+    output.write_all(&buf)?;
+    output.flush()?;
     return Ok(totalrows);
 }
 
 fn parse_copyopts<'syn>(copy: &'syn syn::CopyStmt<'_>) -> anyhow::Result<CopyOpts<'syn>> {
-    let mut delim = "";
+    let mut format = CopyFormat::Text;
+    let mut delim = None;
     let mut parallel = 1usize;
     let mut null = "";
+    let mut quote = None;
+    let mut escape = None;
+    let mut header = None;
     for defelem in &copy.opts {
         let (name, val) = match defelem {
             syn::DefElem::Unspec(v) | syn::DefElem::Add(v) => (&v.defname, &v.arg),
@@ -212,7 +650,15 @@ fn parse_copyopts<'syn>(copy: &'syn syn::CopyStmt<'_>) -> anyhow::Result<CopyOpt
         let name: &str = name;
         match name {
             "format" => match val {
-                syn::Value::Str(s) if s.as_str() == "csv" => continue,
+                syn::Value::Str(s) if s.as_str() == "text" => {
+                    format = CopyFormat::Text;
+                }
+                syn::Value::Str(s) if s.as_str() == "csv" => {
+                    format = CopyFormat::Csv;
+                }
+                syn::Value::Str(s) if s.as_str() == "binary" => {
+                    format = CopyFormat::Binary;
+                }
                 _ => {
                     kbbail!(
                         ERRCODE_INVALID_PARAMETER_VALUE,
@@ -223,7 +669,7 @@ fn parse_copyopts<'syn>(copy: &'syn syn::CopyStmt<'_>) -> anyhow::Result<CopyOpt
             },
             "delimiter" => match val {
                 syn::Value::Str(val) => {
-                    delim = val;
+                    delim = Some(val.as_str());
                 }
                 _ => {
                     kbbail!(
@@ -257,26 +703,189 @@ fn parse_copyopts<'syn>(copy: &'syn syn::CopyStmt<'_>) -> anyhow::Result<CopyOpt
                     );
                 }
             },
+            "quote" => match val {
+                syn::Value::Str(val) => {
+                    quote = Some(val.as_str());
+                }
+                _ => {
+                    kbbail!(
+                        ERRCODE_INVALID_PARAMETER_VALUE,
+                        "COPY quote {} not recognized",
+                        val
+                    );
+                }
+            },
+            "escape" => match val {
+                syn::Value::Str(val) => {
+                    escape = Some(val.as_str());
+                }
+                _ => {
+                    kbbail!(
+                        ERRCODE_INVALID_PARAMETER_VALUE,
+                        "COPY escape {} not recognized",
+                        val
+                    );
+                }
+            },
+            "header" => match parse_bool(&val.to_string()) {
+                Some(v) => {
+                    header = Some(v);
+                }
+                None => {
+                    kbbail!(
+                        ERRCODE_INVALID_PARAMETER_VALUE,
+                        "COPY header {} not recognized",
+                        val
+                    );
+                }
+            },
             &_ => {
                 kbbail!(ERRCODE_SYNTAX_ERROR, "option {} not recognized", name);
             }
         }
     }
+    // ProcessCopyOptions
+    let csv = format == CopyFormat::Csv;
+    for (optname, specified) in [
+        ("quote", quote.is_some()),
+        ("escape", escape.is_some()),
+        ("HEADER", header.is_some()),
+    ] {
+        kbensure!(
+            csv || !specified,
+            ERRCODE_FEATURE_NOT_SUPPORTED,
+            "COPY {} available only in CSV mode",
+            optname
+        );
+    }
+    let delim = delim.unwrap_or(if csv { "," } else { "" });
+    let quote = quote.unwrap_or("\"");
+    let escape = escape.unwrap_or(quote);
+    if csv {
+        for (optname, val) in [("delimiter", delim), ("quote", quote), ("escape", escape)] {
+            kbensure!(
+                val.len() == 1,
+                ERRCODE_FEATURE_NOT_SUPPORTED,
+                "COPY {} must be a single one-byte character",
+                optname
+            );
+        }
+        kbensure!(
+            delim != quote,
+            ERRCODE_INVALID_PARAMETER_VALUE,
+            "COPY delimiter and quote must be different"
+        );
+        kbensure!(
+            !null.contains(delim),
+            ERRCODE_INVALID_PARAMETER_VALUE,
+            "COPY delimiter must not appear in the NULL specification"
+        );
+    }
     return Ok(CopyOpts {
+        format,
         delim,
         parallel,
         null,
+        quote: quote.as_bytes()[0],
+        escape: escape.as_bytes()[0],
+        header: header.unwrap_or(false),
     });
 }
 
 pub fn copy_stmt(sess: &mut SessionState, copy: &syn::CopyStmt<'_>) -> anyhow::Result<Response> {
-    kbensure!(
-        copy.from,
-        ERRCODE_FEATURE_NOT_SUPPORTED,
-        "COPY TO is not supported"
-    );
     let copyopts = parse_copyopts(copy)?;
-    let input = File::open(copy.filename.as_str())?;
-    let processed = copyfrom(&copy.rel, BufReader::new(input), &copyopts, sess)?;
+    let processed = if copy.from {
+        let input = File::open(copy.filename.as_str())?;
+        copyfrom(&copy.rel, BufReader::new(input), &copyopts, sess)?
+    } else {
+        kbensure!(
+            copyopts.format == CopyFormat::Binary,
+            ERRCODE_FEATURE_NOT_SUPPORTED,
+            "COPY TO is only supported in the binary format"
+        );
+        let output = File::create(copy.filename.as_str())?;
+        copyto(&copy.rel, output, sess)?
+    };
     return Ok(Response::new_str(format!("COPY {}", processed)));
 }
+
+#[cfg(test)]
+mod copy_test {
+    use super::{CopyFormat, CopyOpts, CsvRecord};
+    use crate::protocol::ERRCODE_BAD_COPY_FILE_FORMAT;
+    use crate::utils::err::errcode;
+
+    fn csv_opts<'a>(null: &'a str, escape: u8) -> CopyOpts<'a> {
+        CopyOpts {
+            format: CopyFormat::Csv,
+            delim: ",",
+            parallel: 1,
+            null,
+            quote: b'"',
+            escape,
+            header: false,
+        }
+    }
+
+    // Returns the fields of all records, None means NULL.
+    fn read_all(input: &[u8], opts: &CopyOpts) -> anyhow::Result<Vec<Vec<Option<String>>>> {
+        let mut input = input;
+        let mut csv = CsvRecord::new();
+        let mut records = Vec::new();
+        while csv.read(&mut input, opts)? {
+            let fields = csv.fields.iter().map(|field| {
+                let v = field.clone().map(|r| &csv.buf[r]);
+                v.map(|v| String::from_utf8(v.to_vec()).unwrap())
+            });
+            records.push(fields.collect());
+        }
+        return Ok(records);
+    }
+
+    fn s(v: &str) -> Option<String> {
+        Some(v.to_string())
+    }
+
+    #[test]
+    fn csv_quoted() {
+        let input = b"1,\"a,b\",\"line1\nline2\",\"say \"\"hi\"\"\",,\"\"\r\n2,x\"y\"z,\"\"\"\"\n";
+        let records = read_all(input, &csv_opts("", b'"')).unwrap();
+        assert_eq!(
+            records,
+            vec![
+                vec![
+                    s("1"),
+                    s("a,b"),
+                    s("line1\nline2"),
+                    s("say \"hi\""),
+                    None,
+                    s("")
+                ],
+                vec![s("2"), s("xyz"), s("\"")],
+            ]
+        );
+    }
+
+    #[test]
+    fn csv_null_escape() {
+        let input = b"\\N,\"\\N\",,\"a\\\"b\\\\\"\n\"\\\"\nc\",\\N\n";
+        let records = read_all(input, &csv_opts("\\N", b'\\')).unwrap();
+        assert_eq!(
+            records,
+            vec![
+                vec![None, s("\\N"), s(""), s("a\"b\\")],
+                vec![s("\"\nc"), None],
+            ]
+        );
+    }
+
+    #[test]
+    fn csv_malformed() {
+        let opts = csv_opts("", b'"');
+        let inputs: [&[u8]; 3] = [b"1,\"abc\n2,3\n", b"1,\"abc", b"\xff,1\n"];
+        for input in inputs {
+            let err = read_all(input, &opts).unwrap_err();
+            assert_eq!(errcode(&err), ERRCODE_BAD_COPY_FILE_FORMAT);
+        }
+    }
+}
@@ -0,0 +1,147 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::access::xact::SessionExt;
+use crate::kbbail;
+use crate::parser::syn;
+use crate::utility::Response;
+use crate::utils::SessionState;
+
+// The statements sent by the connection poolers to reset the session between clients, such
+// as server_reset_query of pgbouncer.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SessionReset {
+    DiscardAll,
+    ResetAll,
+    SessionAuthorizationDefault,
+    CloseAll,
+    UnlistenAll,
+}
+
+const SESSION_RESETS: [(&[&str], SessionReset); 5] = [
+    (&["discard", "all"], SessionReset::DiscardAll),
+    (&["reset", "all"], SessionReset::ResetAll),
+    (
+        &["set", "session", "authorization", "default"],
+        SessionReset::SessionAuthorizationDefault,
+    ),
+    (&["close", "all"], SessionReset::CloseAll),
+    (&["unlisten", "*"], SessionReset::UnlistenAll),
+];
+
+impl SessionReset {
+    pub fn tag(self) -> &'static str {
+        match self {
+            SessionReset::DiscardAll => "DISCARD ALL",
+            SessionReset::ResetAll => "RESET",
+            SessionReset::SessionAuthorizationDefault => "SET",
+            SessionReset::CloseAll => "CLOSE CURSOR ALL",
+            SessionReset::UnlistenAll => "UNLISTEN",
+        }
+    }
+}
+
+// Recognizes the query consisting only of the reset statements without the parser, so the
+// reset batch of the pooler is handled by one reset_session(). The words are case-insensitive
+// and separated by whitespaces, the statements are separated by semicolons.
+pub fn parse_resets(query: &str) -> Option<Vec<SessionReset>> {
+    let mut resets = Vec::new();
+    for stmt in query.split(';') {
+        let words: Vec<_> = stmt.split_ascii_whitespace().collect();
+        if words.is_empty() {
+            continue;
+        }
+        let (_, reset) = SESSION_RESETS.iter().find(|(expected, _)| {
+            expected.len() == words.len()
+                && expected
+                    .iter()
+                    .zip(&words)
+                    .all(|(e, w)| e.eq_ignore_ascii_case(w))
+        })?;
+        resets.push(*reset);
+    }
+    if resets.is_empty() {
+        return None;
+    }
+    return Some(resets);
+}
+
+// DiscardAll, every kind of the session state is reset at most once no matter how many
+// statements reset it. There are no cursors and only one user, so CLOSE ALL and SET SESSION
+// AUTHORIZATION DEFAULT have nothing to reset.
+pub fn reset_session(sess: &mut SessionState, resets: &[SessionReset]) -> anyhow::Result<()> {
+    let has = |kinds: &[SessionReset]| resets.iter().any(|r| kinds.contains(r));
+    if has(&[SessionReset::DiscardAll]) {
+        sess.prevent_in_transblock("DISCARD ALL")?;
+    }
+    if has(&[SessionReset::DiscardAll, SessionReset::ResetAll]) {
+        sess.reset_all_gucs();
+    }
+    if has(&[SessionReset::DiscardAll, SessionReset::UnlistenAll]) {
+        sess.notify.unlisten(None);
+    }
+    return Ok(());
+}
+
+pub fn discard_stmt(sess: &mut SessionState, stmt: &syn::DiscardStmt) -> anyhow::Result<Response> {
+    let reset = match stmt {
+        syn::DiscardStmt::All => SessionReset::DiscardAll,
+    };
+    reset_session(sess, &[reset])?;
+    return Ok(Response::new(reset.tag()));
+}
+
+// PerformPortalClose
+pub fn close_portal_stmt(stmt: &syn::ClosePortalStmt<'_>) -> anyhow::Result<Response> {
+    if let Some(ref name) = stmt.portalname {
+        kbbail!(
+            ERRCODE_UNDEFINED_CURSOR,
+            "cursor \"{}\" does not exist",
+            name.as_str()
+        );
+    }
+    return Ok(Response::new(SessionReset::CloseAll.tag()));
+}
+
+#[cfg(test)]
+mod discard_test {
+    use super::{parse_resets, SessionReset};
+
+    #[test]
+    fn recognize() {
+        assert_eq!(
+            parse_resets("DISCARD ALL"),
+            Some(vec![SessionReset::DiscardAll])
+        );
+        assert_eq!(
+            parse_resets(
+                " reset\n  All ;; Set session AUTHORIZATION default;CLOSE ALL;unlisten *;"
+            ),
+            Some(vec![
+                SessionReset::ResetAll,
+                SessionReset::SessionAuthorizationDefault,
+                SessionReset::CloseAll,
+                SessionReset::UnlistenAll
+            ])
+        );
+        for query in &[
+            "",
+            ";",
+            "discard",
+            "discard all all",
+            "discard temp",
+            "reset all; select 1",
+            "unlisten hidva",
+            "discard_all",
+        ] {
+            assert_eq!(parse_resets(query), None, "{}", query);
+        }
+    }
+}
@@ -0,0 +1,234 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::access::lmgr::{LockMode, SessionExt as LMGRSessionExt};
+use crate::catalog::dependency::{self, DependentObject};
+use crate::catalog::namespace::{oid_in_used, SessionExt};
+use crate::catalog::qualname_get_type;
+use crate::commands::tablecmds::{drop_table, remove_attribute};
+use crate::guc::NoticeLevel;
+use crate::parser::syn;
+use crate::protocol::{ERRCODE_SUCCESSFUL_COMPLETION, ERRCODE_UNDEFINED_TABLE};
+use crate::utility::Response;
+use crate::utils::err::errcode;
+use crate::utils::SessionState;
+use crate::xact::SessionExt as XACTSessionExt;
+use crate::{kbbail, kbensure, Oid};
+use std::sync::Arc;
+
+fn skip_missing(state: &mut SessionState, kind: &str, name: &str) {
+    state.notice(
+        NoticeLevel::Notice,
+        ERRCODE_SUCCESSFUL_COMPLETION,
+        format!("{} \"{}\" does not exist, skipping", kind, name),
+    );
+}
+
+fn check_not_pinned(oid: Oid, kind: &str, name: &str) -> anyhow::Result<()> {
+    kbensure!(
+        !dependency::is_pinned(oid),
+        ERRCODE_DEPENDENT_OBJECTS_STILL_EXIST,
+        "cannot drop {} {} because it is required by the database system",
+        kind,
+        name
+    );
+    return Ok(());
+}
+
+// reportDependentObjects, the dependent objects are dropped only if CASCADE is specified.
+fn report_dependents(
+    state: &mut SessionState,
+    behavior: syn::DropBehavior,
+    kind: &str,
+    name: &str,
+    dependents: &[DependentObject],
+) -> anyhow::Result<()> {
+    if dependents.is_empty() {
+        return Ok(());
+    }
+    if behavior == syn::DropBehavior::Restrict {
+        let detail: Vec<_> = dependents
+            .iter()
+            .map(|v| format!("{} depends on {} {}", v, kind, name))
+            .collect();
+        kbbail!(
+            ERRCODE_DEPENDENT_OBJECTS_STILL_EXIST,
+            "cannot drop {} {} because other objects depend on it. detail={} \
+             hint=Use DROP ... CASCADE to drop the dependent objects too.",
+            kind,
+            name,
+            detail.join("; ")
+        );
+    }
+    for dependent in dependents {
+        state.notice(
+            NoticeLevel::Notice,
+            ERRCODE_SUCCESSFUL_COMPLETION,
+            format!("drop cascades to {}", dependent),
+        );
+    }
+    return Ok(());
+}
+
+// deleteOneObject
+fn drop_dependent(state: &mut SessionState, dependent: &DependentObject) -> anyhow::Result<()> {
+    match dependent {
+        DependentObject::Column { table, attnum, .. } => {
+            state.lock_rel(*table, LockMode::AccessExclusive)?;
+            remove_attribute(state, *table, *attnum)
+        }
+        DependentObject::Table { oid, .. } => {
+            state.lock_rel(*oid, LockMode::AccessExclusive)?;
+            drop_table(state, *oid)
+        }
+        DependentObject::Type { oid, typname } => {
+            drop_type(state, *oid, typname, syn::DropBehavior::Cascade)
+        }
+    }
+}
+
+fn remove_relation(
+    state: &mut SessionState,
+    names: &Vec<syn::StrVal<'_>>,
+    missing_ok: bool,
+) -> anyhow::Result<()> {
+    let (schemaname, relname) = state.deconstruct_qualname(names)?;
+    let rv = syn::RangeVar {
+        schemaname: schemaname.map(syn::StrVal::InPlace),
+        relname: syn::StrVal::InPlace(relname),
+        alias: None,
+    };
+    let tableoid = match state.rv_get_oid(&rv, LockMode::AccessExclusive) {
+        Ok(v) => v,
+        Err(e) if missing_ok && errcode(&e) == ERRCODE_UNDEFINED_TABLE => {
+            skip_missing(state, "table", relname);
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+    check_not_pinned(tableoid, "table", relname)?;
+    // Nothing depends on a table now.
+    return drop_table(state, tableoid);
+}
+
+// RemoveTypeById, the columns of the type are dropped if behavior is CASCADE.
+fn drop_type(
+    state: &mut SessionState,
+    typoid: Oid,
+    typname: &str,
+    behavior: syn::DropBehavior,
+) -> anyhow::Result<()> {
+    check_not_pinned(typoid, "type", typname)?;
+    // The columns of the type are created or dropped under the lock, see build_desc().
+    state.lock_type(typoid, LockMode::AccessExclusive)?;
+    kbensure!(
+        oid_in_used(state, typoid, "kb_type")?,
+        ERRCODE_UNDEFINED_OBJECT,
+        "type \"{}\" does not exist",
+        typname
+    );
+    let dependents = dependency::type_dependents(state, typoid)?;
+    report_dependents(state, behavior, "type", typname, &dependents)?;
+    for dependent in &dependents {
+        drop_dependent(state, dependent)?;
+    }
+    state
+        .metaconn
+        .execute(format!("delete from kb_type where oid = {}", typoid))?;
+    return Ok(());
+}
+
+fn remove_type(
+    state: &mut SessionState,
+    names: &Vec<syn::StrVal<'_>>,
+    missing_ok: bool,
+    behavior: syn::DropBehavior,
+) -> anyhow::Result<()> {
+    let (schemaname, typname) = state.deconstruct_qualname(names)?;
+    let formtype = if let Some(schemaname) = schemaname {
+        let nsoid = state.get_namespace_oid(schemaname)?;
+        qualname_get_type(state, nsoid, typname)?
+    } else {
+        state.typname_get_type(typname)?
+    };
+    let typoid = match formtype {
+        Some(v) => v.id,
+        None if missing_ok => {
+            skip_missing(state, "type", typname);
+            return Ok(());
+        }
+        None => kbbail!(
+            ERRCODE_UNDEFINED_OBJECT,
+            "type \"{}\" does not exist",
+            typname
+        ),
+    };
+    return drop_type(state, typoid, typname, behavior);
+}
+
+// RemoveSchemaById, the types and relations in the schema are dropped if behavior is CASCADE.
+fn remove_schema(
+    state: &mut SessionState,
+    nspname: &str,
+    missing_ok: bool,
+    behavior: syn::DropBehavior,
+) -> anyhow::Result<()> {
+    let nsoid = match state.get_namespace_oid(nspname) {
+        Ok(v) => v,
+        Err(_) if missing_ok => {
+            skip_missing(state, "schema", nspname);
+            return Ok(());
+        }
+        Err(_) => kbbail!(
+            ERRCODE_UNDEFINED_SCHEMA,
+            "schema \"{}\" does not exist",
+            nspname
+        ),
+    };
+    check_not_pinned(nsoid, "schema", nspname)?;
+    // Blocks the creation of the relations in the schema, see rv_get_and_chk_create_ns().
+    state.lock_ns(nsoid, LockMode::AccessExclusive)?;
+    let dependents = dependency::ns_dependents(state, nsoid)?;
+    report_dependents(state, behavior, "schema", nspname, &dependents)?;
+    // The types are locked before the tables using them are dropped, see LockTag.
+    for dependent in &dependents {
+        if let DependentObject::Type { oid, .. } = dependent {
+            state.lock_type(*oid, LockMode::AccessExclusive)?;
+        }
+    }
+    for dependent in &dependents {
+        drop_dependent(state, dependent)?;
+    }
+    state
+        .metaconn
+        .execute(format!("delete from kb_namespace where oid = {}", nsoid))?;
+    Arc::make_mut(&mut state.gucstate).base_search_path_valid = false;
+    return Ok(());
+}
+
+// RemoveObjects and RemoveRelations
+pub fn remove_objects(stmt: &syn::DropStmt, state: &mut SessionState) -> anyhow::Result<Response> {
+    let tag = match stmt.remove_type {
+        syn::ObjectType::Table => "DROP TABLE",
+        syn::ObjectType::Type => "DROP TYPE",
+        syn::ObjectType::Schema => "DROP SCHEMA",
+    };
+    state.prevent_in_transblock(tag)?;
+    for names in &stmt.objects {
+        match stmt.remove_type {
+            syn::ObjectType::Table => remove_relation(state, names, stmt.missing_ok)?,
+            syn::ObjectType::Type => remove_type(state, names, stmt.missing_ok, stmt.behavior)?,
+            syn::ObjectType::Schema => {
+                remove_schema(state, &names[0], stmt.missing_ok, stmt.behavior)?
+            }
+        }
+    }
+    return Ok(Response::new(tag));
+}
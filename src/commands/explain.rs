@@ -0,0 +1,163 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::access::rel;
+use crate::optimizer::{self, AggStrategy, Plan};
+use crate::parser::sem;
+use crate::utility::Response;
+use crate::utils::SessionState;
+
+struct ExplainState {
+    lines: Vec<String>,
+    indent: usize,
+}
+
+impl ExplainState {
+    fn new() -> Self {
+        Self {
+            lines: Vec::new(),
+            indent: 0,
+        }
+    }
+
+    fn push(&mut self, line: String) {
+        if self.indent == 0 {
+            self.lines.push(line);
+        } else {
+            let width = 2 + (self.indent - 1) * 6;
+            self.lines
+                .push(format!("{:width$}->  {}", "", line, width = width));
+        }
+    }
+}
+
+// ExplainNode
+fn explain_node(
+    plan: &Plan,
+    es: &mut ExplainState,
+    files_to_scan: &mut impl FnMut(&optimizer::SeqScan) -> anyhow::Result<[usize; 3]>,
+) -> anyhow::Result<()> {
+    match plan {
+        Plan::Result(r) => {
+            es.push("Result".to_string());
+            if let Some(lefttree) = &r.lefttree {
+                es.indent += 1;
+                explain_node(lefttree, es, files_to_scan)?;
+                es.indent -= 1;
+            }
+        }
+        Plan::Agg(a) => {
+            es.push(match a.aggstrategy {
+                AggStrategy::Plain => "Aggregate".to_string(),
+                AggStrategy::Hashed => "HashAggregate".to_string(),
+            });
+            es.indent += 1;
+            explain_node(&a.lefttree, es, files_to_scan)?;
+            es.indent -= 1;
+        }
+        Plan::Sort(s) => {
+            es.push(match s.bound {
+                None => "Sort".to_string(),
+                Some(bound) => format!("Sort  (top-N bound={})", bound),
+            });
+            es.indent += 1;
+            explain_node(&s.lefttree, es, files_to_scan)?;
+            es.indent -= 1;
+        }
+        Plan::Limit(l) => {
+            es.push(format!("Limit  (count={})", l.count));
+            es.indent += 1;
+            explain_node(&l.lefttree, es, files_to_scan)?;
+            es.indent -= 1;
+        }
+        Plan::SeqScan(s) => {
+            let [l0, l1, l2] = files_to_scan(s)?;
+            es.push(format!(
+                "Seq Scan on {}  (parallel={} l0={} l1={} l2={})",
+                s.relname, s.parallel, l0, l1, l2
+            ));
+        }
+    }
+    return Ok(());
+}
+
+fn files_to_scan(sess: &mut SessionState, scan: &optimizer::SeqScan) -> anyhow::Result<[usize; 3]> {
+    let rel = rel::getrel(sess, scan.table.table)?;
+    // svslot pin guard
+    let svslot = sess.tabsv.read(&scan.table, &rel.opt.enable_cs_wal)?;
+    let sv = svslot.v.read().unwrap();
+    return Ok(sv.as_ref().unwrap().files_to_scan());
+}
+
+// ExplainQuery
+pub fn explain_stmt(sess: &mut SessionState, query: &sem::Query) -> anyhow::Result<Response> {
+    let plannedstmt = optimizer::planner(sess, query)?;
+    let mut es = ExplainState::new();
+    explain_node(&plannedstmt.plan_tree, &mut es, &mut |scan| {
+        files_to_scan(sess, scan)
+    })?;
+    return Ok(Response::new_rows(
+        "EXPLAIN",
+        "QUERY PLAN".to_string(),
+        es.lines,
+    ));
+}
+
+#[cfg(test)]
+mod explain_test {
+    use super::{explain_node, ExplainState};
+    use crate::access::rel::{Rel, RelOpt};
+    use crate::access::sv::TableId;
+    use crate::optimizer::{self, Plan, PlanCommon};
+    use crate::Oid;
+
+    #[test]
+    fn seqscan() {
+        let table = TableId {
+            db: Oid::new(1).unwrap(),
+            table: Oid::new(65536).unwrap(),
+        };
+        let scan = Plan::SeqScan(optimizer::SeqScan {
+            plan: PlanCommon { tlist: Vec::new() },
+            table,
+            relname: "hidva".to_string(),
+            rel: Rel {
+                attrs: Vec::new(),
+                opt: RelOpt {
+                    mvcc_blk_rows: 1,
+                    mvcc_buf_cap: 1,
+                    data_blk_rows: 1,
+                    enable_cs_wal: false,
+                },
+            },
+            parallel: 1,
+            qual: Vec::new(),
+        });
+        let plan = Plan::Result(optimizer::Result {
+            plan: PlanCommon { tlist: Vec::new() },
+            resconstantqual: None,
+            lefttree: Some(Box::new(scan)),
+            qual: Vec::new(),
+        });
+        let mut es = ExplainState::new();
+        explain_node(&plan, &mut es, &mut |scan| {
+            assert_eq!(scan.table, table);
+            Ok([2, 1, 0])
+        })
+        .unwrap();
+        assert_eq!(
+            es.lines,
+            vec![
+                "Result".to_string(),
+                "  ->  Seq Scan on hidva  (parallel=1 l0=2 l1=1 l2=0)".to_string()
+            ]
+        );
+    }
+}
@@ -0,0 +1,203 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use super::copy::{indatums2data, new_indatums};
+use crate::access::cs;
+use crate::access::lmgr::LockMode;
+use crate::access::rel;
+use crate::access::sv;
+use crate::access::xact::SessionExt as XACTSessionExt;
+use crate::catalog::get_type_input_info;
+use crate::catalog::namespace::SessionExt as NSSessionExt;
+use crate::datums::Datums;
+use crate::executor::{exec_returning, DestReceiver};
+use crate::parser::{sem, syn};
+use crate::utility::Response;
+use crate::utils::fmgr::FmgrInfo;
+use crate::utils::{SessionState, WorkerState};
+use crate::{kbanyhow, kbbail, kbensure};
+use std::mem::forget;
+use std::rc::Rc;
+
+// The text of the literal in VALUES or DEFAULT, which will be passed to the type input function.
+// None means NULL.
+pub fn literal_text(expr: &syn::Expr<'_>, what: &str) -> anyhow::Result<Option<String>> {
+    match expr {
+        syn::Expr::AConst(c) => {
+            if let syn::Value::Null = c.val {
+                return Ok(None);
+            }
+            return Ok(Some(c.val.to_string()));
+        }
+        syn::Expr::AExpr(e) if e.name.len() == 1 && &*e.name[0] == "-" => {
+            if let syn::AExprOprands::One(syn::Expr::AConst(c)) = &*e.oprands {
+                if let syn::Value::Num(n) = &c.val {
+                    return Ok(Some(format!("-{}", n)));
+                }
+            }
+        }
+        _ => {}
+    }
+    kbbail!(
+        ERRCODE_FEATURE_NOT_SUPPORTED,
+        "only literals are supported in {}",
+        what
+    );
+}
+
+fn set_literal(indatum: &mut Datums, rowidx: usize, val: Option<&str>) {
+    match val {
+        None => {
+            indatum.set_null_at(rowidx as isize);
+            indatum.set_empty_at(rowidx as isize);
+        }
+        Some(val) => indatum.set_varchar_at(rowidx as isize, val.as_bytes()),
+    }
+}
+
+fn dropped_datums(attr: &rel::Attr, rownum: u32) -> Datums {
+    let mut datums = Datums::new();
+    if attr.typ.len > 0 {
+        datums.resize_fixedlen(rownum, attr.typ.len as usize, attr.typ.align as usize);
+    } else {
+        datums.resize_varlen(rownum);
+    }
+    datums.set_null_all();
+    return datums;
+}
+
+// checkInsertTargets, returns the indexes of the target columns.
+fn insert_targets(stmt: &syn::InsertStmt<'_>, rel: &rel::Rel) -> anyhow::Result<Vec<usize>> {
+    if stmt.cols.is_empty() {
+        let attidxs = rel.attrs.iter().enumerate().filter(|(_, v)| !v.dropped);
+        return Ok(attidxs.map(|(attidx, _)| attidx).collect());
+    }
+    let mut targets = Vec::with_capacity(stmt.cols.len());
+    for col in &stmt.cols {
+        let colname: &str = col;
+        let attidx = rel
+            .attrs
+            .iter()
+            .position(|v| !v.dropped && v.name == colname);
+        let attidx = attidx.ok_or_else(|| {
+            kbanyhow!(
+                ERRCODE_UNDEFINED_COLUMN,
+                "column \"{}\" of relation \"{}\" does not exist",
+                colname,
+                &*stmt.relation.relname
+            )
+        })?;
+        kbensure!(
+            !targets.contains(&attidx),
+            ERRCODE_DUPLICATE_COLUMN,
+            "column \"{}\" specified more than once",
+            colname
+        );
+        targets.push(attidx);
+    }
+    return Ok(targets);
+}
+
+// ExecInsert, the rows of RETURNING are sent to dest.
+pub fn insert_stmt(
+    sess: &mut SessionState,
+    stmt: &syn::InsertStmt<'_>,
+    returning: &Vec<sem::TargetEntry>,
+    dest: &mut dyn DestReceiver,
+) -> anyhow::Result<Response> {
+    let tableoid = sess.rv_get_oid(&stmt.relation, LockMode::RowExclusive)?;
+    let tableid = sv::TableId {
+        db: sess.reqdb,
+        table: tableoid,
+    };
+    let destrel = rel::getrel(sess, tableoid)?;
+    let attcnt = destrel.attrs.len();
+    let targets = insert_targets(stmt, &destrel)?;
+    let rownum = stmt.values.len() as u32;
+    let mut indatums = new_indatums(attcnt, rownum);
+    for (rowidx, row) in stmt.values.iter().enumerate() {
+        kbensure!(
+            row.len() <= targets.len(),
+            ERRCODE_SYNTAX_ERROR,
+            "INSERT has more expressions than target columns"
+        );
+        kbensure!(
+            row.len() == targets.len(),
+            ERRCODE_SYNTAX_ERROR,
+            "INSERT has more target columns than expressions"
+        );
+        for (expr, &attidx) in row.iter().zip(&targets) {
+            let val = literal_text(expr, "VALUES")?;
+            set_literal(&mut indatums[attidx], rowidx, val.as_deref());
+        }
+    }
+    // The omitted columns are filled with their defaults, the NOT NULL constraint is checked by
+    // L0Writer.
+    for (attidx, attr) in destrel.attrs.iter().enumerate() {
+        if targets.contains(&attidx) {
+            continue;
+        }
+        for rowidx in 0..rownum as usize {
+            set_literal(&mut indatums[attidx], rowidx, attr.default.as_deref());
+        }
+    }
+    // The dropped columns are filled with NULL directly, their types may have been dropped.
+    let indatums: Vec<_> = indatums
+        .into_iter()
+        .zip(&destrel.attrs)
+        .filter(|(_, attr)| !attr.dropped)
+        .map(|(indatum, _)| indatum)
+        .collect();
+    let mut typins = Vec::with_capacity(attcnt);
+    let mut typmods = Vec::with_capacity(attcnt);
+    for attr in destrel.attrs.iter().filter(|attr| !attr.dropped) {
+        let typinoid = get_type_input_info(sess, attr.typ.id)?;
+        typins.push(FmgrInfo::new(typinoid, sess.fmgr_builtins)?);
+        typmods.push(Rc::new(Datums::new_single_fixedlen(attr.typ.mode)));
+    }
+
+    // mvccslot pin guard
+    let mvccslot = sess.tabmvcc.read(&tableid, &destrel.opt)?;
+    let mvcc = mvccslot.v.read().unwrap();
+    let mvcc = mvcc.as_ref().unwrap();
+    mvccslot.mark_dirty();
+
+    // svslot guard
+    let svslot = sess.tabsv.read(&tableid, &destrel.opt.enable_cs_wal)?;
+    let l0files = sv::start_write(sess, &svslot, 1)?;
+    // AbortWriteGuard
+    let abort_guard = sv::AbortWriteGuard::new(&svslot, &l0files);
+
+    sess.get_xid()?;
+    let mut worker = WorkerState::new(sess);
+    let mut livedata = indatums2data(indatums, &typmods, &typins, &worker)?.into_iter();
+    let data: Vec<_> = destrel
+        .attrs
+        .iter()
+        .map(|attr| {
+            if !attr.dropped {
+                return livedata.next().unwrap();
+            }
+            return Rc::new(dropped_datums(attr, rownum));
+        })
+        .collect();
+    let mut l0writer = cs::L0Writer::new(tableid, destrel, l0files[0]);
+    l0writer.write(data.clone(), rownum)?;
+    l0writer.sync(&mut worker, mvcc)?;
+    if !returning.is_empty() {
+        exec_returning(returning, &data, rownum, sess, &worker, dest)?;
+    }
+    sess.exit_worker(worker.exit());
+
+    sv::commit_write(sess, &svslot, &[l0writer.meta]);
+    forget(abort_guard);
+    sess.tabstat.count_insert(tableid, rownum as u64);
+    return Ok(Response::new_str(format!("INSERT 0 {}", rownum)));
+}
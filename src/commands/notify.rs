@@ -0,0 +1,320 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// LISTEN/NOTIFY, just like async.c. The notifications are queued in the transaction, and
+// are delivered to the listening sessions when the transaction commits. Unlike PostgreSQL,
+// there is no global queue, each listener owns a queue of the notifications delivered to it.
+use crate::parser::syn;
+use crate::utility::Response;
+use crate::utils::latch::Latch;
+use crate::utils::SessionState;
+use crate::{kbensure, protocol};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+const NAMEDATALEN: usize = 64;
+const NOTIFY_PAYLOAD_MAX_LENGTH: usize = 8000;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Notification {
+    pub sessid: u32, // the session that sent the notification.
+    pub channel: String,
+    pub payload: String,
+}
+
+impl Notification {
+    pub fn to_msg(&self) -> protocol::NotificationResponse<'_> {
+        protocol::NotificationResponse {
+            sessid: self.sessid,
+            channel: &self.channel,
+            payload: &self.payload,
+        }
+    }
+}
+
+struct Listener {
+    latch: Arc<Latch>,
+    queue: Vec<Notification>,
+}
+
+#[derive(Default)]
+struct Registry {
+    channels: HashMap<String, HashSet<u32>>,
+    listeners: HashMap<u32, Listener>,
+}
+
+impl Registry {
+    fn unlisten(&mut self, sessid: u32, channel: &str) {
+        if let Some(sessids) = self.channels.get_mut(channel) {
+            sessids.remove(&sessid);
+            if sessids.is_empty() {
+                self.channels.remove(channel);
+            }
+        }
+    }
+
+    fn unlisten_all(&mut self, sessid: u32) {
+        self.channels.retain(|_, sessids| {
+            sessids.remove(&sessid);
+            !sessids.is_empty()
+        });
+    }
+}
+
+pub struct GlobalStateExt {
+    reg: Mutex<Registry>,
+}
+
+impl GlobalStateExt {
+    pub fn new() -> GlobalStateExt {
+        GlobalStateExt {
+            reg: Mutex::new(Registry::default()),
+        }
+    }
+}
+
+enum ListenAction {
+    Listen(String),
+    Unlisten(String),
+    UnlistenAll,
+}
+
+pub struct SessionStateExt {
+    global: &'static GlobalStateExt,
+    sessid: u32,
+    latch: Arc<Latch>,
+    // pendingActions
+    actions: Vec<ListenAction>,
+    // pendingNotifies
+    notifies: Vec<Notification>,
+}
+
+impl SessionStateExt {
+    pub fn new(global: &'static GlobalStateExt, sessid: u32, latch: Arc<Latch>) -> Self {
+        global.reg.lock().unwrap().listeners.insert(
+            sessid,
+            Listener {
+                latch: latch.clone(),
+                queue: Vec::new(),
+            },
+        );
+        Self {
+            global,
+            sessid,
+            latch,
+            actions: Vec::new(),
+            notifies: Vec::new(),
+        }
+    }
+
+    pub fn latch(&self) -> &Arc<Latch> {
+        &self.latch
+    }
+
+    // Async_Listen
+    pub fn listen(&mut self, channel: &str) {
+        self.actions.push(ListenAction::Listen(channel.to_string()));
+    }
+
+    // Async_Unlisten, Async_UnlistenAll
+    pub fn unlisten(&mut self, channel: Option<&str>) {
+        self.actions.push(match channel {
+            Some(channel) => ListenAction::Unlisten(channel.to_string()),
+            None => ListenAction::UnlistenAll,
+        });
+    }
+
+    // Async_Notify
+    pub fn notify(&mut self, channel: &str, payload: &str) -> anyhow::Result<()> {
+        kbensure!(
+            !channel.is_empty(),
+            ERRCODE_INVALID_PARAMETER_VALUE,
+            "channel name cannot be empty"
+        );
+        kbensure!(
+            channel.len() < NAMEDATALEN,
+            ERRCODE_INVALID_PARAMETER_VALUE,
+            "channel name too long"
+        );
+        kbensure!(
+            payload.len() < NOTIFY_PAYLOAD_MAX_LENGTH,
+            ERRCODE_INVALID_PARAMETER_VALUE,
+            "payload string too long"
+        );
+        // AsyncExistsPendingNotify, fold the duplicate notifications.
+        if self
+            .notifies
+            .iter()
+            .any(|n| n.channel == channel && n.payload == payload)
+        {
+            return Ok(());
+        }
+        self.notifies.push(Notification {
+            sessid: self.sessid,
+            channel: channel.to_string(),
+            payload: payload.to_string(),
+        });
+        Ok(())
+    }
+
+    // AtCommit_Notify, apply the pending actions and deliver the pending notifications.
+    pub fn at_commit(&mut self) {
+        if self.actions.is_empty() && self.notifies.is_empty() {
+            return;
+        }
+        let mut reg = self.global.reg.lock().unwrap();
+        for action in self.actions.drain(..) {
+            match action {
+                ListenAction::Listen(channel) => {
+                    reg.channels.entry(channel).or_default().insert(self.sessid);
+                }
+                ListenAction::Unlisten(channel) => reg.unlisten(self.sessid, &channel),
+                ListenAction::UnlistenAll => reg.unlisten_all(self.sessid),
+            }
+        }
+        let Registry {
+            channels,
+            listeners,
+        } = &mut *reg;
+        for notify in self.notifies.drain(..) {
+            let sessids = match channels.get(&notify.channel) {
+                Some(v) => v,
+                None => continue,
+            };
+            for sessid in sessids {
+                if let Some(listener) = listeners.get_mut(sessid) {
+                    listener.queue.push(notify.clone());
+                    listener.latch.set();
+                }
+            }
+        }
+    }
+
+    // AtAbort_Notify
+    pub fn at_abort(&mut self) {
+        self.actions.clear();
+        self.notifies.clear();
+    }
+
+    // The number of the pending actions and notifications, taken at the savepoint.
+    pub fn pending_mark(&self) -> (usize, usize) {
+        (self.actions.len(), self.notifies.len())
+    }
+
+    // AtSubAbort_Notify, the actions and notifications after the savepoint are discarded.
+    pub fn at_subabort(&mut self, mark: (usize, usize)) {
+        self.actions.truncate(mark.0);
+        self.notifies.truncate(mark.1);
+    }
+
+    // The notifications delivered to this session, the caller should send them to the client.
+    pub fn take_notifications(&self) -> Vec<Notification> {
+        let mut reg = self.global.reg.lock().unwrap();
+        match reg.listeners.get_mut(&self.sessid) {
+            Some(listener) => std::mem::take(&mut listener.queue),
+            None => Vec::new(),
+        }
+    }
+}
+
+impl Drop for SessionStateExt {
+    // Async_UnlistenOnExit
+    fn drop(&mut self) {
+        let mut reg = self.global.reg.lock().unwrap();
+        reg.unlisten_all(self.sessid);
+        reg.listeners.remove(&self.sessid);
+    }
+}
+
+pub fn notify_stmt(
+    sess: &mut SessionState,
+    stmt: &syn::NotifyStmt<'_>,
+) -> anyhow::Result<Response> {
+    let payload = stmt.payload.as_ref().map_or("", |v| v.as_str());
+    sess.notify.notify(&stmt.conditionname, payload)?;
+    return Ok(Response::new("NOTIFY"));
+}
+
+pub fn listen_stmt(
+    sess: &mut SessionState,
+    stmt: &syn::ListenStmt<'_>,
+) -> anyhow::Result<Response> {
+    sess.notify.listen(&stmt.conditionname);
+    return Ok(Response::new("LISTEN"));
+}
+
+pub fn unlisten_stmt(
+    sess: &mut SessionState,
+    stmt: &syn::UnlistenStmt<'_>,
+) -> anyhow::Result<Response> {
+    sess.notify
+        .unlisten(stmt.conditionname.as_ref().map(|v| v.as_str()));
+    return Ok(Response::new("UNLISTEN"));
+}
+
+#[cfg(test)]
+mod notify_test {
+    use super::{GlobalStateExt, Notification, SessionStateExt};
+    use crate::utils::latch::Latch;
+    use nix::poll::{poll, PollFd, PollFlags};
+    use std::sync::Arc;
+
+    fn is_set(latch: &Latch) -> bool {
+        let mut fds = [PollFd::new(latch.fd(), PollFlags::POLLIN)];
+        poll(&mut fds, 0).unwrap() > 0
+    }
+
+    fn new_sess(global: &'static GlobalStateExt, sessid: u32) -> SessionStateExt {
+        SessionStateExt::new(global, sessid, Arc::new(Latch::new().unwrap()))
+    }
+
+    #[test]
+    fn deliver_on_commit() {
+        let global: &'static GlobalStateExt = Box::leak(Box::new(GlobalStateExt::new()));
+        let mut listener = new_sess(global, 33);
+        let mut notifier = new_sess(global, 44);
+        listener.listen("hidva");
+        listener.at_commit();
+
+        notifier.notify("hidva", "hello").unwrap();
+        notifier.notify("hidva", "hello").unwrap();
+        notifier.notify("other", "world").unwrap();
+        assert!(!is_set(listener.latch()));
+        assert!(listener.take_notifications().is_empty());
+        notifier.at_commit();
+        assert!(is_set(listener.latch()));
+        listener.latch().reset();
+        assert_eq!(
+            listener.take_notifications(),
+            vec![Notification {
+                sessid: 44,
+                channel: "hidva".to_string(),
+                payload: "hello".to_string(),
+            }]
+        );
+        assert!(notifier.take_notifications().is_empty());
+
+        // The aborted notifications are discarded.
+        notifier.notify("hidva", "aborted").unwrap();
+        notifier.at_abort();
+        notifier.at_commit();
+        assert!(!is_set(listener.latch()));
+
+        listener.unlisten(None);
+        listener.at_commit();
+        notifier.notify("hidva", "hello").unwrap();
+        notifier.at_commit();
+        assert!(listener.take_notifications().is_empty());
+
+        assert!(notifier.notify("hidva", &"x".repeat(8000)).is_err());
+        assert!(notifier.notify("", "").is_err());
+    }
+}
@@ -0,0 +1,40 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::catalog::namespace::SessionExt;
+use crate::kbensure;
+use crate::parser::syn;
+use crate::utility::Response;
+use crate::utils::SessionState;
+use crate::xact::SessionExt as XACTSessionExt;
+use std::sync::Arc;
+
+// CreateSchemaCommand
+pub fn create_schema(
+    stmt: &syn::CreateSchemaStmt,
+    state: &mut SessionState,
+) -> anyhow::Result<Response> {
+    state.prevent_in_transblock("CREATE SCHEMA")?;
+    let nspname: &str = &stmt.schemaname;
+    kbensure!(
+        state.get_namespace_oid(nspname).is_err(),
+        ERRCODE_DUPLICATE_SCHEMA,
+        "schema \"{}\" already exists",
+        nspname
+    );
+    let nsoid = state.new_oid()?;
+    state.metaconn.execute(format!(
+        "insert into kb_namespace values({}, '{}')",
+        nsoid, nspname
+    ))?;
+    // The schema may be in search_path.
+    Arc::make_mut(&mut state.gucstate).base_search_path_valid = false;
+    return Ok(Response::new("CREATE SCHEMA"));
+}
@@ -8,19 +8,25 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
-use crate::access::sv;
+use crate::access::lmgr::{LockMode, SessionExt as LMGRSessionExt};
 use crate::access::TypeDesc;
-use crate::catalog::namespace::SessionExt;
-use crate::catalog::{qualname_get_type, FormType};
+use crate::access::{rel, sv};
+use crate::catalog::namespace::{oid_in_used, SessionExt};
+use crate::catalog::{get_rel_namespace, get_type_input_info, relname_get_relid};
+use crate::catalog::{get_tablespace_location, qualname_get_type, FormType};
+use crate::commands::copy::{indatums2data, new_indatums};
+use crate::commands::insert::literal_text;
+use crate::datums::Datums;
 use crate::guc;
-use crate::kbbail;
 use crate::parser::syn;
 use crate::utility::Response;
-use crate::utils::{persist, sync_dir};
+use crate::utils::fmgr::FmgrInfo;
+use crate::utils::{AttrNumber, WorkerState};
 use crate::utils::{ExecSQLOnDrop, SessionState};
 use crate::xact::SessionExt as XACTSessionExt;
+use crate::{kbbail, kbensure, Oid};
 use anyhow::ensure;
-use std::fs;
+use std::rc::Rc;
 
 struct TupleDesc {
     pub desc: Vec<TypeDesc>,
@@ -63,10 +69,11 @@ fn typname_type(state: &mut SessionState, typnam: &syn::TypeName<'_>) -> anyhow:
     kbbail!(ERRCODE_UNDEFINED_OBJECT, "type {:?} does not exist", typnam);
 }
 
-// BuildDescForRelation
+// BuildDescForRelation, the types are locked in the order of their oids so that DROP TYPE waits
+// for the new columns, see LockTag. The type dropped before it is locked does not exist.
 fn build_desc(
     state: &mut SessionState,
-    table_elts: &Vec<syn::ColumnDef<'_>>,
+    table_elts: &[syn::ColumnDef<'_>],
 ) -> anyhow::Result<TupleDesc> {
     let mut ts = TupleDesc {
         desc: Vec::with_capacity(table_elts.len()),
@@ -74,19 +81,114 @@ fn build_desc(
     for cf in table_elts {
         ts.desc.push(typname_type(state, &cf.typename)?);
     }
+    let mut types: Vec<_> = ts.desc.iter().map(|v| v.id).zip(table_elts).collect();
+    types.sort_unstable_by_key(|v| v.0);
+    types.dedup_by_key(|v| v.0);
+    for (typoid, cf) in types {
+        state.lock_type(typoid, LockMode::AccessShare)?;
+        kbensure!(
+            oid_in_used(state, typoid, "kb_type")?,
+            ERRCODE_UNDEFINED_OBJECT,
+            "type {:?} does not exist",
+            cf.typename
+        );
+    }
     return Ok(ts);
 }
 
-fn get_relopt(stmt: &syn::CreateTableStmt, state: &mut SessionState) -> String {
+// transformColumnDefinition, returns attnotnull and the default.
+fn column_constraints(cf: &syn::ColumnDef<'_>) -> anyhow::Result<(bool, Option<String>)> {
+    let mut notnull = None;
+    let mut default = None;
+    for constraint in &cf.constraints {
+        match constraint {
+            syn::ColConstraint::Null | syn::ColConstraint::NotNull => {
+                let isnotnull = matches!(constraint, syn::ColConstraint::NotNull);
+                kbensure!(
+                    notnull.is_none() || notnull == Some(isnotnull),
+                    ERRCODE_SYNTAX_ERROR,
+                    "conflicting NULL/NOT NULL declarations for column \"{}\"",
+                    &*cf.colname
+                );
+                notnull = Some(isnotnull);
+            }
+            syn::ColConstraint::Default(expr) => {
+                kbensure!(
+                    default.is_none(),
+                    ERRCODE_SYNTAX_ERROR,
+                    "multiple default values specified for column \"{}\"",
+                    &*cf.colname
+                );
+                default = Some(literal_text(expr, "DEFAULT")?);
+            }
+        }
+    }
+    return Ok((notnull.unwrap_or(false), default.flatten()));
+}
+
+// The default is passed to the type input function here, so the invalid default is reported by
+// CREATE TABLE instead of INSERT.
+fn check_default(state: &mut SessionState, typ: &TypeDesc, default: &str) -> anyhow::Result<()> {
+    input_single(state, typ, default)?;
+    return Ok(());
+}
+
+// Passes the text to the type input function of typ, returns the datum as a single.
+pub fn input_single(
+    state: &mut SessionState,
+    typ: &TypeDesc,
+    text: &str,
+) -> anyhow::Result<Datums> {
+    let typin = FmgrInfo::new(get_type_input_info(state, typ.id)?, state.fmgr_builtins)?;
+    let typmod = Rc::new(Datums::new_single_fixedlen(typ.mode));
+    let mut indatums = new_indatums(1, 1);
+    indatums[0].set_varchar_at(0, text.as_bytes());
+    let worker = WorkerState::new(state);
+    let ret = indatums2data(indatums, &[typmod], &[typin], &worker);
+    state.exit_worker(worker.exit());
+    let mut single = Datums::new();
+    single.set_single_from(&ret?[0], 0, typ.len);
+    return Ok(single);
+}
+
+// Quote the text as a sqlite string literal.
+fn sqlite_text(val: &Option<String>) -> String {
+    match val {
+        None => "NULL".to_string(),
+        Some(v) => format!("'{}'", v.replace('\'', "''")),
+    }
+}
+
+// The mvcc_blk_rows used at creation is saved, so each table keeps its own page size even if
+// the GUC is changed later.
+// Returns the reloptions and the spclocation of the tablespace option.
+fn get_relopt(
+    stmt: &syn::CreateTableStmt,
+    state: &mut SessionState,
+) -> anyhow::Result<(String, Option<String>)> {
     let mut ret: Vec<String> = vec![];
+    let mut spclocation = None;
     let mut meet_mvcc_blk_rows = false;
     for defelem in &stmt.opts {
         let (name, val) = match defelem {
             syn::DefElem::Unspec(v) | syn::DefElem::Add(v) => (&v.defname, &v.arg),
             _ => continue,
         };
-        ret.push(format!("{}={}", name, val));
+        let val = val.to_string();
         let name: &str = name;
+        if let "mvcc_blk_rows" | "data_blk_rows" | "mvcc_buf_cap" = name {
+            kbensure!(
+                matches!(val.parse::<u32>(), Ok(v) if v > 0),
+                ERRCODE_INVALID_PARAMETER_VALUE,
+                "invalid value for integer option \"{}\": {}",
+                name,
+                val
+            );
+        }
+        if name == "tablespace" {
+            spclocation = get_tablespace_location(&val)?;
+        }
+        ret.push(format!("{}={}", name, val));
         if name == "mvcc_blk_rows" {
             meet_mvcc_blk_rows = true;
         }
@@ -97,7 +199,7 @@ fn get_relopt(stmt: &syn::CreateTableStmt, state: &mut SessionState) -> String {
             guc::get_int(&state.gucstate, guc::MvccBlkRows)
         ));
     }
-    return ret.join(",");
+    return Ok((ret.join(","), spclocation));
 }
 
 pub fn create_table(
@@ -106,13 +208,29 @@ pub fn create_table(
 ) -> anyhow::Result<Response> {
     state.prevent_in_transblock("CREATE TABLE")?;
 
-    let nsoid = state.rv_get_create_ns(&stmt.relation)?;
-    let tableoid = state.new_oid();
+    let nsoid = state.rv_get_and_chk_create_ns(&stmt.relation)?;
+    let tableoid = state.new_oid()?;
     let tupdesc = build_desc(state, &stmt.table_elts)?;
+    // Nobody can see the new table before commit, the lock is for the future lookups by oid.
+    state.lock_rel(tableoid, LockMode::AccessExclusive)?;
+    let mut constraints = Vec::with_capacity(stmt.table_elts.len());
+    for (cf, typ) in stmt.table_elts.iter().zip(&tupdesc.desc) {
+        let (notnull, default) = column_constraints(cf)?;
+        if let Some(default) = &default {
+            check_default(state, typ, default)?;
+        }
+        constraints.push((notnull, default));
+    }
+    let (relopt, spclocation) = get_relopt(stmt, state)?;
     let xid = state.get_xid()?;
 
+    let tableid = sv::TableId {
+        db: state.reqdb,
+        table: tableoid,
+    };
+    sv::insert_create_table_wal(state, tableid, spclocation.as_deref());
+
     state.metaconn.execute("begin")?;
-    let relopt = get_relopt(stmt, state);
     let _rollback = ExecSQLOnDrop::new(&state.metaconn, "rollback");
     let relname: &str = &stmt.relation.relname;
     state.metaconn.execute(format!(
@@ -124,25 +242,256 @@ pub fn create_table(
         xid,
         relopt,
     ))?;
-    for attidx in 0..tupdesc.desc.len() {
+    let columns = stmt.table_elts.iter().zip(&tupdesc.desc).zip(&constraints);
+    for (attidx, ((cf, typdesc), (notnull, default))) in columns.enumerate() {
         let attnum = attidx + 1;
-        let typdesc = &tupdesc.desc[attidx];
-        let attname: &str = &stmt.table_elts[attidx].colname;
+        let attname: &str = &cf.colname;
         let sql = format!(
-            "insert into kb_attribute values({}, '{}', {}, {}, {}, {}, {}, 0, 0)",
-            tableoid, attname, typdesc.id, typdesc.len, typdesc.align, attnum, typdesc.mode
+            "insert into kb_attribute values({}, '{}', {}, {}, {}, {}, {}, {}, 0, {}, NULL)",
+            tableoid,
+            attname,
+            typdesc.id,
+            typdesc.len,
+            typdesc.align,
+            attnum,
+            typdesc.mode,
+            *notnull as i32,
+            sqlite_text(default)
         );
         state.metaconn.execute(sql)?;
     }
 
-    fs::create_dir(format!("base/{}/{}", state.reqdb, tableoid))?;
-    sync_dir(format!("base/{}", state.reqdb))?;
-    persist(
-        sv::get_minafest_path(state.reqdb, tableoid),
-        &sv::INIT_MANIFEST_DAT,
-    )?;
+    sv::create_table_storage(tableid, spclocation.as_deref())?;
     state.metaconn.execute("commit")?;
     std::mem::forget(_rollback);
 
     return Ok(Response::new("CREATE TABLE"));
 }
+
+// ATExecAddColumn, only the catalog is changed, the data files written before are not rewritten
+// and the scan reads the new column of them as attmissingval, which is the default.
+fn add_column(
+    state: &mut SessionState,
+    relation: &syn::RangeVar<'_>,
+    cf: &syn::ColumnDef<'_>,
+) -> anyhow::Result<()> {
+    // The type is locked before the table, see LockTag.
+    let typdesc = build_desc(state, std::slice::from_ref(cf))?
+        .desc
+        .pop()
+        .unwrap();
+    let tableoid = state.rv_get_oid(relation, LockMode::AccessExclusive)?;
+    let rel = rel::getrel(state, tableoid)?;
+    let attname: &str = &cf.colname;
+    kbensure!(
+        rel.attrs.iter().all(|attr| attr.name != attname),
+        ERRCODE_DUPLICATE_COLUMN,
+        "column \"{}\" of relation \"{}\" already exists",
+        attname,
+        &*relation.relname
+    );
+    let (notnull, default) = column_constraints(cf)?;
+    if let Some(default) = &default {
+        check_default(state, &typdesc, default)?;
+    }
+    let tableid = sv::TableId {
+        db: state.reqdb,
+        table: tableoid,
+    };
+    if notnull && default.is_none() {
+        let svslot = state.tabsv.read(&tableid, &rel.opt.enable_cs_wal)?;
+        let sv = svslot.v.read().unwrap();
+        kbensure!(
+            sv.as_ref().unwrap().datafiles().is_empty(),
+            ERRCODE_NOT_NULL_VIOLATION,
+            "column \"{}\" of relation \"{}\" contains null values",
+            attname,
+            &*relation.relname
+        );
+    }
+    let attnum = rel.attrs.len() + 1;
+    state.get_xid()?;
+    sv::insert_add_column_wal(state, tableid, AttrNumber::new(attnum as u16).unwrap());
+
+    state.metaconn.execute("begin")?;
+    let _rollback = ExecSQLOnDrop::new(&state.metaconn, "rollback");
+    let sql = format!(
+        "insert into kb_attribute values({}, '{}', {}, {}, {}, {}, {}, {}, 0, {}, {})",
+        tableoid,
+        attname,
+        typdesc.id,
+        typdesc.len,
+        typdesc.align,
+        attnum,
+        typdesc.mode,
+        notnull as i32,
+        sqlite_text(&default),
+        sqlite_text(&default)
+    );
+    state.metaconn.execute(sql)?;
+    state.metaconn.execute(format!(
+        "update kb_class set relnattrs = {} where oid = {}",
+        attnum, tableoid
+    ))?;
+    state.metaconn.execute("commit")?;
+    std::mem::forget(_rollback);
+    return Ok(());
+}
+
+// ATExecDropColumn, the column is only marked dropped, its data is still in the data files
+// and is skipped by the queries. The name is changed so that a new column can take it.
+fn drop_column(
+    state: &mut SessionState,
+    relation: &syn::RangeVar<'_>,
+    colname: &str,
+) -> anyhow::Result<()> {
+    let tableoid = state.rv_get_oid(relation, LockMode::AccessExclusive)?;
+    let rel = rel::getrel(state, tableoid)?;
+    let attr = rel
+        .attrs
+        .iter()
+        .find(|attr| !attr.dropped && attr.name == colname);
+    let attnum = match attr {
+        Some(attr) => attr.num,
+        None => kbbail!(
+            ERRCODE_UNDEFINED_COLUMN,
+            "column \"{}\" of relation \"{}\" does not exist",
+            colname,
+            &*relation.relname
+        ),
+    };
+    return remove_attribute(state, tableoid, attnum);
+}
+
+// RemoveAttributeById, the caller must hold the AccessExclusive lock of the table.
+pub fn remove_attribute(
+    state: &mut SessionState,
+    tableoid: Oid,
+    attnum: AttrNumber,
+) -> anyhow::Result<()> {
+    let tableid = sv::TableId {
+        db: state.reqdb,
+        table: tableoid,
+    };
+    state.get_xid()?;
+    sv::insert_drop_column_wal(state, tableid, attnum);
+
+    state.metaconn.execute(format!(
+        "update kb_attribute set attname = '........kb.dropped.{}........', attisdropped = 1, \
+         attnotnull = 0, attdefault = NULL, attmissingval = NULL \
+         where attrelid = {} and attnum = {}",
+        attnum, tableoid, attnum
+    ))?;
+    return Ok(());
+}
+
+// heap_drop_with_catalog, the caller must hold the AccessExclusive lock of the table.
+pub fn drop_table(state: &mut SessionState, tableoid: Oid) -> anyhow::Result<()> {
+    let tableid = sv::TableId {
+        db: state.reqdb,
+        table: tableoid,
+    };
+    state.get_xid()?;
+    sv::insert_drop_table_wal(state, tableid);
+
+    state.metaconn.execute("begin")?;
+    let _rollback = ExecSQLOnDrop::new(&state.metaconn, "rollback");
+    state.metaconn.execute(format!(
+        "delete from kb_attribute where attrelid = {}",
+        tableoid
+    ))?;
+    state
+        .metaconn
+        .execute(format!("delete from kb_class where oid = {}", tableoid))?;
+    state.metaconn.execute("commit")?;
+    std::mem::forget(_rollback);
+    sv::drop_table_storage(state.pending_fileops, tableid)?;
+    return Ok(());
+}
+
+// RenameRelation, the storage is named by the oid so only kb_class is changed. There is no
+// relation cache, the new name is seen by the next lookup from the catalog.
+fn rename_table(
+    state: &mut SessionState,
+    relation: &syn::RangeVar<'_>,
+    newname: &str,
+) -> anyhow::Result<()> {
+    let tableoid = state.rv_get_oid(relation, LockMode::AccessExclusive)?;
+    let nsoid = get_rel_namespace(state, tableoid)?;
+    kbensure!(
+        relname_get_relid(state, newname, nsoid)?.is_none(),
+        ERRCODE_DUPLICATE_TABLE,
+        "relation \"{}\" already exists",
+        newname
+    );
+    let tableid = sv::TableId {
+        db: state.reqdb,
+        table: tableoid,
+    };
+    state.get_xid()?;
+    sv::insert_rename_table_wal(state, tableid);
+
+    state.metaconn.execute(format!(
+        "update kb_class set relname = '{}' where oid = {}",
+        newname, tableoid
+    ))?;
+    return Ok(());
+}
+
+// renameatt
+fn rename_column(
+    state: &mut SessionState,
+    relation: &syn::RangeVar<'_>,
+    oldname: &str,
+    newname: &str,
+) -> anyhow::Result<()> {
+    let tableoid = state.rv_get_oid(relation, LockMode::AccessExclusive)?;
+    let rel = rel::getrel(state, tableoid)?;
+    let attr = rel
+        .attrs
+        .iter()
+        .find(|attr| !attr.dropped && attr.name == oldname);
+    let attnum = match attr {
+        Some(attr) => attr.num,
+        None => kbbail!(
+            ERRCODE_UNDEFINED_COLUMN,
+            "column \"{}\" does not exist",
+            oldname
+        ),
+    };
+    kbensure!(
+        rel.attrs.iter().all(|attr| attr.name != newname),
+        ERRCODE_DUPLICATE_COLUMN,
+        "column \"{}\" of relation \"{}\" already exists",
+        newname,
+        &*relation.relname
+    );
+    let tableid = sv::TableId {
+        db: state.reqdb,
+        table: tableoid,
+    };
+    state.get_xid()?;
+    sv::insert_rename_column_wal(state, tableid, attnum);
+
+    state.metaconn.execute(format!(
+        "update kb_attribute set attname = '{}' where attrelid = {} and attnum = {}",
+        newname, tableoid, attnum
+    ))?;
+    return Ok(());
+}
+
+pub fn alter_table(
+    stmt: &syn::AlterTableStmt,
+    state: &mut SessionState,
+) -> anyhow::Result<Response> {
+    state.prevent_in_transblock("ALTER TABLE")?;
+    match &stmt.cmd {
+        syn::AlterTableCmd::AddColumn(cf) => add_column(state, &stmt.relation, cf)?,
+        syn::AlterTableCmd::DropColumn(colname) => drop_column(state, &stmt.relation, colname)?,
+        syn::AlterTableCmd::RenameTable(newname) => rename_table(state, &stmt.relation, newname)?,
+        syn::AlterTableCmd::RenameColumn(oldname, newname) => {
+            rename_column(state, &stmt.relation, oldname, newname)?
+        }
+    }
+    return Ok(Response::new("ALTER TABLE"));
+}
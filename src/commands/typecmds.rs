@@ -9,20 +9,221 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::access::lmgr::{LockMode, SessionExt as LMGRSessionExt};
+use crate::access::sv;
 use crate::catalog::namespace::SessionExt;
+use crate::catalog::{get_func_candidates, get_proc, get_typlenalign, qualname_get_type, FormProc};
+use crate::guc::NoticeLevel;
 use crate::parser::syn;
+use crate::protocol::ERRCODE_SYNTAX_ERROR;
 use crate::utility::Response;
 use crate::utils::SessionState;
+use crate::xact::SessionExt as XACTSessionExt;
+use crate::{kbbail, kbensure, Oid, VARCHAROID};
 
-pub fn define_type(stmt: &syn::DefineTypeStmt, state: &SessionState) -> anyhow::Result<Response> {
-    let (typnsoid, typname) = state.qualname_get_create_ns(&stmt.defnames)?;
+// The type is defined by the builtin input and output functions, so its values are laid out as
+// the result type of the input function, INTERNALLENGTH and ALIGNMENT must agree with it.
+struct TypeDef<'a> {
+    input: Option<&'a str>,
+    output: Option<&'a str>,
+    len: Option<i16>,
+    align: Option<u8>,
+}
+
+fn def_get_string<'a>(name: &str, val: &'a syn::Value<'_>) -> anyhow::Result<&'a str> {
+    match val {
+        syn::Value::Str(s) => Ok(s.as_str()),
+        _ => kbbail!(
+            ERRCODE_SYNTAX_ERROR,
+            "{} requires a name, got {}",
+            name,
+            val
+        ),
+    }
+}
+
+// defGetTypeLength
+fn def_get_type_length(val: &syn::Value<'_>) -> anyhow::Result<i16> {
+    match val {
+        syn::Value::Num(syn::NumVal::Int(n)) if *n > 0 && *n <= i16::MAX as i32 => Ok(*n as i16),
+        syn::Value::Str(s) if s.as_str() == "variable" => Ok(-1),
+        _ => kbbail!(
+            ERRCODE_SYNTAX_ERROR,
+            "invalid argument for internallength: \"{}\"",
+            val
+        ),
+    }
+}
+
+fn def_get_alignment(val: &syn::Value<'_>) -> anyhow::Result<u8> {
+    let align = def_get_string("alignment", val)?;
+    return match align {
+        "char" => Ok(1),
+        "int2" => Ok(2),
+        "int4" => Ok(4),
+        "double" => Ok(8),
+        _ => kbbail!(
+            ERRCODE_INVALID_PARAMETER_VALUE,
+            "alignment \"{}\" not recognized",
+            align
+        ),
+    };
+}
 
-    return Ok(Response::new_ex(
-        "CREATE TYPE",
-        "CREATE TYPE".to_string(),
-        format!(
-            "DefineType. typensoid={} typname={} stmt={:?}",
-            typnsoid, typname, stmt
+fn parse_typedef<'a>(
+    stmt: &'a syn::DefineTypeStmt<'_>,
+    state: &mut SessionState,
+) -> anyhow::Result<TypeDef<'a>> {
+    let mut typedef = TypeDef {
+        input: None,
+        output: None,
+        len: None,
+        align: None,
+    };
+    for defelem in &stmt.definition {
+        let (name, val) = match defelem {
+            syn::DefElem::Unspec(v) | syn::DefElem::Add(v) => (&v.defname, &v.arg),
+            _ => continue,
+        };
+        let name: &str = name;
+        let redundant = match name {
+            "input" => typedef.input.replace(def_get_string(name, val)?).is_some(),
+            "output" => typedef.output.replace(def_get_string(name, val)?).is_some(),
+            "internallength" => typedef.len.replace(def_get_type_length(val)?).is_some(),
+            "alignment" => typedef.align.replace(def_get_alignment(val)?).is_some(),
+            _ => {
+                state.notice(
+                    NoticeLevel::Warning,
+                    ERRCODE_SYNTAX_ERROR,
+                    format!("type attribute \"{}\" not recognized", name),
+                );
+                false
+            }
+        };
+        kbensure!(
+            !redundant,
+            ERRCODE_SYNTAX_ERROR,
+            "conflicting or redundant options. option={}",
+            name
+        );
+    }
+    return Ok(typedef);
+}
+
+fn check_builtin(state: &SessionState, proc: FormProc, procname: &str) -> anyhow::Result<FormProc> {
+    kbensure!(
+        state.fmgr_builtins.contains_key(&proc.oid),
+        ERRCODE_INVALID_OBJECT_DEFINITION,
+        "function {} is not a builtin function",
+        procname
+    );
+    return Ok(proc);
+}
+
+// findTypeInputFunction, the input function takes the text of the value.
+fn find_input_func(state: &SessionState, procname: &str) -> anyhow::Result<FormProc> {
+    for cand in get_func_candidates(state, procname, 1)? {
+        if cand.proargtypes == [VARCHAROID] {
+            return check_builtin(state, get_proc(state, cand.oid)?, procname);
+        }
+    }
+    kbbail!(
+        ERRCODE_UNDEFINED_FUNCTION,
+        "function {}(varchar) does not exist",
+        procname
+    );
+}
+
+// findTypeOutputFunction, the output function takes the result of the input function.
+fn find_output_func(
+    state: &SessionState,
+    procname: &str,
+    argtype: Oid,
+) -> anyhow::Result<FormProc> {
+    for cand in get_func_candidates(state, procname, 1)? {
+        if cand.proargtypes == [argtype] {
+            let proc = check_builtin(state, get_proc(state, cand.oid)?, procname)?;
+            kbensure!(
+                proc.prorettype == VARCHAROID,
+                ERRCODE_INVALID_OBJECT_DEFINITION,
+                "type output function {} must return type varchar",
+                procname
+            );
+            return Ok(proc);
+        }
+    }
+    kbbail!(
+        ERRCODE_UNDEFINED_FUNCTION,
+        "function {}({}) does not exist",
+        procname,
+        argtype
+    );
+}
+
+// DefineType, only the base type is supported, the shell type is not created.
+pub fn define_type(
+    stmt: &syn::DefineTypeStmt,
+    state: &mut SessionState,
+) -> anyhow::Result<Response> {
+    let (typnsoid, typname) = state.qualname_get_create_ns(&stmt.defnames)?;
+    if stmt.definition.is_empty() {
+        return Ok(Response::new_ex(
+            "CREATE TYPE",
+            "CREATE TYPE".to_string(),
+            format!(
+                "DefineType. typensoid={} typname={} stmt={:?}",
+                typnsoid, typname, stmt
+            ),
+        ));
+    }
+    state.prevent_in_transblock("CREATE TYPE")?;
+    let typedef = parse_typedef(stmt, state)?;
+    let input = match typedef.input {
+        Some(v) => v,
+        None => kbbail!(
+            ERRCODE_INVALID_OBJECT_DEFINITION,
+            "type input function must be specified"
+        ),
+    };
+    let output = match typedef.output {
+        Some(v) => v,
+        None => kbbail!(
+            ERRCODE_INVALID_OBJECT_DEFINITION,
+            "type output function must be specified"
         ),
-    ));
+    };
+    let inproc = find_input_func(state, input)?;
+    let outproc = find_output_func(state, output, inproc.prorettype)?;
+    // The functions of the type are locked in the order of their oids just like the column types
+    // in build_desc(), so DROP FUNCTION would wait for the new type.
+    let mut procs = [inproc.oid, outproc.oid];
+    procs.sort_unstable();
+    for &proc in &procs {
+        state.lock_proc(proc, LockMode::AccessShare)?;
+    }
+    let (replen, repalign) = get_typlenalign(state, inproc.prorettype)?;
+    let typlen = typedef.len.unwrap_or(replen);
+    let typalign = typedef.align.unwrap_or(repalign);
+    kbensure!(
+        typlen == replen && typalign == repalign,
+        ERRCODE_INVALID_OBJECT_DEFINITION,
+        "internallength {} and alignment {} do not match the result of type input function {}",
+        typlen,
+        typalign,
+        input
+    );
+    kbensure!(
+        qualname_get_type(state, typnsoid, typname)?.is_none(),
+        ERRCODE_DUPLICATE_OBJECT,
+        "type \"{}\" already exists",
+        typname
+    );
+    let typoid = state.new_oid()?;
+    sv::insert_create_type_wal(state, state.reqdb, typoid);
+    // There is no typmod function, 1 is used just as the builtin types, see initdb.
+    state.metaconn.execute(format!(
+        "insert into kb_type values({}, '{}', {}, {}, {}, 1, {}, {}, 1, 1)",
+        typoid, typname, typnsoid, typlen, typalign, inproc.oid, outproc.oid
+    ))?;
+    return Ok(Response::new("CREATE TYPE"));
 }
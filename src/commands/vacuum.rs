@@ -0,0 +1,279 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::access::autovacuum::{self, AutoVacOpts, Reclaimer};
+use crate::access::clog::XidStatus;
+use crate::access::cs::{self, TableScan};
+use crate::access::lmgr::{LockMode, SessionExt as LMGRSessionExt};
+use crate::access::xact::SessionExt as XACTSessionExt;
+use crate::access::{rel, sv};
+use crate::catalog::namespace::{oid_in_used, SessionExt as NSSessionExt};
+use crate::catalog::{self, column_val};
+use crate::parser::syn;
+use crate::utility::Response;
+use crate::utils::{SessionState, WorkerState, Xid, FROZEN_XID};
+use crate::{make_static, GlobalState, Oid, AUTOVAC_SESSID, FIRST_NORMAL_OBJECT_ID};
+use std::mem::forget;
+use std::rc::Rc;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+// get_all_vacuum_rels, the tables dropped before they are locked are skipped.
+fn all_tables(sess: &mut SessionState, mode: LockMode) -> anyhow::Result<Vec<Oid>> {
+    let mut oids: Vec<Oid> = Vec::new();
+    let sql = format!(
+        "select oid from kb_class where oid >= {} order by oid",
+        FIRST_NORMAL_OBJECT_ID
+    );
+    sess.metaconn.iterate(sql, |row| {
+        oids.push(column_val(row, "oid").unwrap().parse().unwrap());
+        true
+    })?;
+    let mut tables = Vec::with_capacity(oids.len());
+    for oid in oids {
+        sess.lock_rel(oid, mode)?;
+        if oid_in_used(sess, oid, "kb_class")? {
+            tables.push(oid);
+        } else {
+            sess.unlock_rel(oid, mode);
+        }
+    }
+    return Ok(tables);
+}
+
+// lazy_vacuum_rel, the committed xmins before horizon are frozen in place, the files are never
+// rewritten. Returns the number of rows frozen.
+fn lazy_vacuum_rel(sess: &mut SessionState, tableoid: Oid) -> anyhow::Result<u32> {
+    let rel = rel::getrel(sess, tableoid)?;
+    let table = sv::TableId {
+        db: sess.reqdb,
+        table: tableoid,
+    };
+    let files = {
+        // svslot pin guard
+        let svslot = sess.tabsv.read(&table, &rel.opt.enable_cs_wal)?;
+        let sv = svslot.v.read().unwrap();
+        sv.as_ref().unwrap().datafiles()
+    };
+    let horizon = sess.global_xmin();
+    // mvccslot pin guard
+    let mvccslot = sess.tabmvcc.read(&table, &rel.opt)?;
+    let mvcc = mvccslot.v.read().unwrap();
+    let mvcc = mvcc.as_ref().unwrap();
+    let mut worker = WorkerState::new(sess);
+    let mut frozen = 0;
+    for file in &files {
+        frozen += mvcc.freeze(file.fileid, file.rownum, horizon, &mut worker)?;
+    }
+    if frozen > 0 {
+        mvccslot.mark_dirty();
+    }
+    sess.exit_worker(worker.exit());
+    log::info!(
+        "vacuum: db={} table={} files={} horizon={} frozen={}",
+        table.db,
+        table.table,
+        files.len(),
+        horizon,
+        frozen
+    );
+    return Ok(frozen);
+}
+
+// The xmin of the row moved by VACUUM FULL, None means the row is dead. The rows inserted before
+// horizon are invisible to all snapshots if not committed, the committed ones are frozen, and the
+// others are kept as they are since some snapshots may still not see them.
+fn full_xmin(worker: &WorkerState, xmin: u64, horizon: Xid) -> anyhow::Result<Option<u64>> {
+    let xid = match Xid::new(xmin) {
+        None => return Ok(None),
+        Some(xid) if xid == FROZEN_XID || xid >= horizon => return Ok(Some(xmin)),
+        Some(xid) => xid,
+    };
+    // The transaction still in progress before horizon has crashed.
+    match worker.clog.xid_status(xid)? {
+        XidStatus::Committed => Ok(Some(FROZEN_XID.get())),
+        XidStatus::Aborted | XidStatus::InProgress => Ok(None),
+    }
+}
+
+// cluster_rel, the live rows are moved into a new L0 file which replaces all files of the table,
+// so the space of the dead rows is reclaimed. Returns the number of rows removed.
+fn full_vacuum_rel(sess: &mut SessionState, tableoid: Oid, horizon: Xid) -> anyhow::Result<u32> {
+    let rel = rel::getrel(sess, tableoid)?;
+    let table = sv::TableId {
+        db: sess.reqdb,
+        table: tableoid,
+    };
+    // mvccslot pin guard
+    let mvccslot = sess.tabmvcc.read(&table, &rel.opt)?;
+    let mvcc = mvccslot.v.read().unwrap();
+    let mvcc = mvcc.as_ref().unwrap();
+    mvccslot.mark_dirty();
+
+    // svslot guard
+    let svslot = sess.tabsv.read(&table, &rel.opt.enable_cs_wal)?;
+    let oldfiles = svslot.v.read().unwrap().as_ref().unwrap().datafiles();
+    if oldfiles.is_empty() {
+        return Ok(0);
+    }
+    sv::start_rewrite(&svslot, &oldfiles)?;
+    let old_guard = sv::AbortWriteGuard::new(&svslot, &oldfiles);
+    let newfiles = sv::start_write(sess, &svslot, 1)?;
+    let new_guard = sv::AbortWriteGuard::new(&svslot, &newfiles);
+
+    let mut worker = WorkerState::new(sess);
+    let mut scan = TableScan::with_files(table, rel.clone(), oldfiles.clone(), &worker)?;
+    let mut l0writer = cs::L0Writer::new(table, rel.clone(), newfiles[0]);
+    let mut out = Vec::new();
+    let mut xmins = Vec::new();
+    let mut keep = Vec::new();
+    let mut newxmins = Vec::new();
+    let mut removed = 0;
+    while let Some(rownum) = scan.next_raw(&mut out, &mut xmins)? {
+        keep.clear();
+        for &xmin in &xmins {
+            let newxmin = full_xmin(&worker, xmin, horizon)?;
+            keep.push(newxmin.is_some());
+            newxmins.extend(newxmin);
+        }
+        let kept = keep.iter().filter(|&&v| v).count() as u32;
+        removed += rownum - kept;
+        if kept == 0 {
+            continue;
+        }
+        if kept < rownum {
+            for (col, attr) in out.iter_mut().zip(rel.attrs.iter()) {
+                let col = Rc::get_mut(col).unwrap();
+                col.retain_fixedlen(attr.typ.len as usize, &keep);
+            }
+        }
+        l0writer.write(out.clone(), kept)?;
+    }
+    drop(scan);
+    l0writer.sync_xmins(&mut worker, mvcc, &newxmins)?;
+    sess.exit_worker(worker.exit());
+
+    sv::commit_rewrite(sess, &svslot, &oldfiles, &[l0writer.meta]);
+    forget(new_guard);
+    forget(old_guard);
+    log::info!(
+        "vacuum full: db={} table={} files={} horizon={} rows={} removed={}",
+        table.db,
+        table.table,
+        oldfiles.len(),
+        horizon,
+        newxmins.len(),
+        removed
+    );
+    return Ok(removed);
+}
+
+// ExecVacuum, VACUUM takes ShareUpdateExclusive so the table can still be read and written during
+// it, while VACUUM FULL takes AccessExclusive since it replaces the files of the table.
+pub fn vacuum_stmt(
+    sess: &mut SessionState,
+    stmt: &syn::VacuumStmt<'_>,
+) -> anyhow::Result<Response> {
+    sess.prevent_in_transblock("VACUUM")?;
+    sess.prevent_during_recovery("VACUUM")?;
+    let mode = if stmt.full {
+        LockMode::AccessExclusive
+    } else {
+        LockMode::ShareUpdateExclusive
+    };
+    let tables = match &stmt.relation {
+        Some(relation) => vec![sess.rv_get_oid(relation, mode)?],
+        None => all_tables(sess, mode)?,
+    };
+    for tableoid in tables {
+        if stmt.full {
+            let horizon = sess.global_xmin();
+            let removed = full_vacuum_rel(sess, tableoid, horizon)?;
+            let table = sv::TableId {
+                db: sess.reqdb,
+                table: tableoid,
+            };
+            sess.tabstat.count_reclaim(table, removed as u64);
+        } else {
+            lazy_vacuum_rel(sess, tableoid)?;
+        }
+    }
+    return Ok(Response::new("VACUUM"));
+}
+
+// The table dropped before it is locked is skipped, None is returned.
+fn autovac_rel(
+    sess: &mut SessionState,
+    tableoid: Oid,
+    horizon: Xid,
+) -> anyhow::Result<Option<u32>> {
+    sess.lock_rel(tableoid, LockMode::AccessExclusive)?;
+    if !oid_in_used(sess, tableoid, "kb_class")? {
+        return Ok(None);
+    }
+    return full_vacuum_rel(sess, tableoid, horizon).map(Some);
+}
+
+// The reclaim pass of autovacuum is VACUUM FULL since only it removes the dead rows, it runs in
+// an internal session of the database of the table, just like autovacuum_do_vac_analyze.
+pub struct VacuumReclaimer {
+    state: GlobalState,
+}
+
+impl VacuumReclaimer {
+    pub fn new(state: GlobalState) -> Self {
+        Self { state }
+    }
+}
+
+impl Reclaimer for VacuumReclaimer {
+    // The rows are rewritten by L0Writer in batches, so work_mem is not used.
+    fn reclaim(&self, table: sv::TableId, horizon: Xid, _work_mem: usize) -> anyhow::Result<u64> {
+        let db = match catalog::get_database_by_oid(table.db) {
+            Ok(db) => db,
+            Err(err) => {
+                self.state.tabstats.remove(table);
+                return Err(err);
+            }
+        };
+        let mut sess =
+            self.state
+                .clone()
+                .new_session(&db.datname, AUTOVAC_SESSID, Arc::default())?;
+        sess.init_thread_locals();
+        sess.start_tran_cmd()?;
+        let ret = autovac_rel(&mut sess, table.table, horizon);
+        match ret {
+            Ok(_) => sess.commit_tran_cmd()?,
+            Err(_) => sess.abort_cur_tran()?,
+        }
+        match ret? {
+            Some(removed) => Ok(removed as u64),
+            None => {
+                self.state.tabstats.remove(table);
+                Ok(0)
+            }
+        }
+    }
+}
+
+// Starts the autovacuum launcher of the primary, it exits once stop is set.
+pub fn start_autovacuum(state: &GlobalState, stop: &'static AtomicBool) -> JoinHandle<()> {
+    let reclaimer = make_static(VacuumReclaimer::new(state.clone()));
+    return autovacuum::start_autovacuum(
+        state.tabstats,
+        state.xact.unwrap(),
+        reclaimer,
+        AutoVacOpts::new(&state.gucstate),
+        stop,
+    );
+}
@@ -0,0 +1,95 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::access::lmgr::LockMode;
+use crate::access::{cs, csmvcc, rel, sv};
+use crate::catalog::namespace::SessionExt as NSSessionExt;
+use crate::guc;
+use crate::parser::syn;
+use crate::utility::Response;
+use crate::utils::{wait_workers, SessionState};
+use crate::Oid;
+
+// Check the data files and the mvcc files assigned to the worker, returns the number of blocks
+// and pages checked and the corruptions found.
+fn verify_files(
+    table: sv::TableId,
+    rel: &rel::Rel,
+    files: Vec<sv::FileMeta>,
+) -> anyhow::Result<(u32, u32, Vec<anyhow::Error>)> {
+    let (mut nblocks, mut npages) = (0, 0);
+    let mut corrupted = Vec::new();
+    for file in files {
+        nblocks += cs::verify_datafile(table, rel, file, &mut corrupted)?;
+        let blk_rows = rel.opt.mvcc_blk_rows;
+        npages += csmvcc::verify_mvccfile(table, file.fileid, blk_rows, &mut corrupted)?;
+    }
+    return Ok((nblocks, npages, corrupted));
+}
+
+// Check the checksums of the manifest, the data blocks and the mvcc pages of the table without
+// modifying anything, returns all corruptions found in the order of files. The files are
+// split among at most max_parallel_workers_per_gather workers.
+pub fn verify_table(sess: &mut SessionState, tableoid: Oid) -> anyhow::Result<Vec<anyhow::Error>> {
+    let rel = rel::getrel(sess, tableoid)?;
+    let table = sv::TableId {
+        db: sess.reqdb,
+        table: tableoid,
+    };
+    let files = {
+        // svslot pin guard
+        let svslot = sess.tabsv.read(&table, &rel.opt.enable_cs_wal)?;
+        let sv = svslot.v.read().unwrap();
+        sv.as_ref().unwrap().datafiles()
+    };
+    let mut corrupted = Vec::new();
+    sv::verify_manifest(table, &mut corrupted)?;
+    if files.is_empty() {
+        return Ok(corrupted);
+    }
+    let maxworkers = guc::get_int(&sess.gucstate, guc::MaxParallelWorkersPerGather).max(1);
+    let chunksize = (files.len() + maxworkers as usize - 1) / maxworkers as usize;
+    let chunks: Vec<_> = files.chunks(chunksize).map(|c| c.to_vec()).collect();
+    let workers = sess.exec_maintenance(
+        chunks.len(),
+        |idx| chunks[idx].clone(),
+        move |files, _| verify_files(table, &rel, files),
+    );
+    let (mut nblocks, mut npages) = (0, 0);
+    for (exit, (blocks, pages, errs)) in wait_workers(&workers)? {
+        sess.exit_worker(exit);
+        nblocks += blocks;
+        npages += pages;
+        corrupted.extend(errs);
+    }
+    log::info!(
+        "verify table: db={} table={} files={} workers={} blocks={} pages={} corrupted={}",
+        table.db,
+        table.table,
+        files.len(),
+        chunks.len(),
+        nblocks,
+        npages,
+        corrupted.len()
+    );
+    return Ok(corrupted);
+}
+
+// VERIFY TABLE, returns a row for every corruption found, no rows means the table is intact.
+pub fn verify_stmt(
+    sess: &mut SessionState,
+    stmt: &syn::VerifyStmt<'_>,
+) -> anyhow::Result<Response> {
+    let tableoid = sess.rv_get_oid(&stmt.relation, LockMode::AccessShare)?;
+    let corrupted = verify_table(sess, tableoid)?;
+    let vals = corrupted.iter().map(|err| err.to_string()).collect();
+    return Ok(Response::new_rows("VERIFY", "corruption".to_string(), vals));
+}
@@ -10,10 +10,14 @@
 // limitations under the License.
 use crate::access::rel;
 use crate::utils::{alloc, dealloc, doalloc, realloc, ser};
+use crate::{Oid, BOOLOID, BYTEAOID, FLOAT4OID, FLOAT8OID, INT2OID, INT4OID, INT8OID, VARCHAROID};
+use anyhow::ensure;
 use static_assertions::const_assert;
-use std::mem::{align_of, size_of, transmute_copy};
+use std::convert::TryInto;
+use std::hash::{Hash, Hasher};
+use std::mem::{align_of, size_of};
 use std::ptr::copy_nonoverlapping as memcpy;
-use std::ptr::NonNull;
+use std::ptr::{self, NonNull};
 use std::rc::Rc;
 use std::slice;
 use std::str::{self, from_utf8};
@@ -66,6 +70,20 @@ fn as_varchar(v: &[u8]) -> &str {
     return unsafe { str::from_utf8_unchecked(v) };
 }
 
+// The first typlen bytes are the datum of the single fixed-length value v, see
+// set_single_fixedlen().
+fn single_fixedlen_bytes(v: usize, typlen: usize) -> [u8; 8] {
+    let mut bytes = [0u8; 8];
+    match typlen {
+        1 => bytes[..1].copy_from_slice(&(v as u8).to_ne_bytes()),
+        2 => bytes[..2].copy_from_slice(&(v as u16).to_ne_bytes()),
+        4 => bytes[..4].copy_from_slice(&(v as u32).to_ne_bytes()),
+        8 => bytes.copy_from_slice(&(v as u64).to_ne_bytes()),
+        _ => unreachable!("single_fixedlen_bytes: invalid typlen: {}", typlen),
+    }
+    return bytes;
+}
+
 unsafe impl Send for Datums {}
 
 unsafe impl Sync for Datums {}
@@ -110,19 +128,23 @@ impl Datums {
         self.ndatum = SINGLE_NULL_MASK | SINGLE;
     }
 
+    // The value is kept in blob_cap, so the blob is freed first. The bytes of v are copied
+    // instead of transmute_copy() to an integer, whose alignment may be larger than T's.
     pub fn set_single_fixedlen<T: Copy>(&mut self, v: T) {
         let size_t = size_of::<T>();
         debug_assert!(size_t == 1 || size_t == 2 || size_t == 4 || size_t == 8);
-        debug_assert!(self.blob.is_none());
+        self.free_blob();
+        let mut bytes = [0u8; 8];
+        unsafe {
+            memcpy(&v as *const T as *const u8, bytes.as_mut_ptr(), size_t);
+        }
         self.ndatum = SINGLE;
-        self.blob_cap = unsafe {
-            match size_t {
-                1 => transmute_copy::<T, u8>(&v) as usize,
-                2 => transmute_copy::<T, u16>(&v) as usize,
-                4 => transmute_copy::<T, u32>(&v) as usize,
-                8 => transmute_copy::<T, u64>(&v) as usize,
-                _ => unreachable!("set_single_fixedlen: invalid size_of<T>: {}", size_t),
-            }
+        self.blob_cap = match size_t {
+            1 => bytes[0] as usize,
+            2 => u16::from_ne_bytes([bytes[0], bytes[1]]) as usize,
+            4 => u32::from_ne_bytes(bytes[..4].try_into().unwrap()) as usize,
+            8 => u64::from_ne_bytes(bytes) as usize,
+            _ => unreachable!("set_single_fixedlen: invalid size_of<T>: {}", size_t),
         };
         return;
     }
@@ -133,19 +155,18 @@ impl Datums {
         debug_assert!(self.is_single());
         debug_assert!(!self.is_single_null());
         debug_assert!(self.blob.is_none());
-        unsafe {
-            match size_t {
-                1 => transmute_copy(&(self.blob_cap as u8)),
-                2 => transmute_copy(&(self.blob_cap as u16)),
-                4 => transmute_copy(&(self.blob_cap as u32)),
-                8 => transmute_copy(&(self.blob_cap as u64)),
-                _ => unreachable!("get_single_fixedlen: invalid size_of<T>: {}", size_t),
-            }
-        }
+        let bytes = single_fixedlen_bytes(self.blob_cap, size_t);
+        // bytes is only aligned to u8.
+        return unsafe { ptr::read_unaligned(bytes.as_ptr() as *const T) };
     }
 
     pub fn set_single_varchar(&mut self, v: &[u8]) {
         debug_assert!(valid_varchar(v));
+        self.set_single_bytea(v);
+    }
+
+    pub fn set_single_bytea(&mut self, v: &[u8]) {
+        debug_assert!(v.len() <= VARLENA_MAX_SIZE);
         self.reserve_blob(v.len());
         self.set_blob_at(0, v);
         self.ndatum = SINGLE | (v.len() as u32);
@@ -165,18 +186,26 @@ impl Datums {
         return as_varchar(rawdata);
     }
 
+    // The new area is zeroed, so that the datums of nulls, which are never set, can still be
+    // read as bytes, see ser_fixed().
     fn reserve_datums(&mut self, ndatum: usize, typlen: usize, typalign: usize) {
         let datum_cap = typlen * ndatum;
-        if let Some(datums) = self.datums {
-            if self.datums_cap < datum_cap {
-                self.datums = Some(realloc(datums, typalign, self.datums_cap, datum_cap));
-                self.datums_cap = datum_cap;
-                debug_assert!(self.datums_align == typalign);
+        let oldcap = if let Some(datums) = self.datums {
+            if self.datums_cap >= datum_cap {
+                return;
             }
+            debug_assert!(self.datums_align == typalign);
+            self.datums = Some(realloc(datums, typalign, self.datums_cap, datum_cap));
+            self.datums_cap
         } else {
             self.datums = Some(alloc(datum_cap, typalign));
-            self.datums_cap = datum_cap;
             self.datums_align = typalign;
+            0
+        };
+        self.datums_cap = datum_cap;
+        unsafe {
+            let ptr = self.datums.unwrap().as_ptr().add(oldcap);
+            ptr::write_bytes(ptr, 0, datum_cap - oldcap);
         }
         return;
     }
@@ -187,6 +216,8 @@ impl Datums {
         debug_assert!(self.blob.is_none());
         self.ndatum = ndatum;
         self.reserve_datums(ndatum as usize, typlen, typalign);
+        // The nulls of the old datums are meaningless.
+        self.null.truncate(0);
         return;
     }
 
@@ -212,7 +243,15 @@ impl Datums {
         }
     }
 
-    fn set_datums_at<T: Copy>(&self, idx: isize, val: T) {
+    fn set_datums_bytes(&mut self, val: &[u8]) {
+        debug_assert!(self.datums.is_some());
+        debug_assert!(val.len() <= self.datums_cap);
+        unsafe {
+            memcpy(val.as_ptr(), self.datums.unwrap().as_ptr(), val.len());
+        }
+    }
+
+    fn set_datums_at<T: Copy>(&mut self, idx: isize, val: T) {
         unsafe {
             *self.datums_at(idx) = val;
         }
@@ -227,6 +266,7 @@ impl Datums {
         self.reserve_datums(ndatum as usize + 1, size_of::<usize>(), align_of::<usize>());
         self.ndatum = ndatum;
         self.set_datums_at(0, 0usize);
+        self.null.truncate(0);
         return;
     }
 
@@ -245,6 +285,39 @@ impl Datums {
         return self.get_datums_at(idx);
     }
 
+    // Only keep the datum at idx if keep[idx] is true.
+    pub fn retain_fixedlen(&mut self, typlen: usize, keep: &[bool]) {
+        debug_assert!(!self.is_single());
+        debug_assert!(self.blob.is_none());
+        debug_assert!(self.null_is_valid());
+        debug_assert_eq!(keep.len(), self.len() as usize);
+        let mut newlen = 0usize;
+        for (idx, &keepit) in keep.iter().enumerate() {
+            if !keepit {
+                continue;
+            }
+            if newlen != idx {
+                unsafe {
+                    let ptr = self.datums.unwrap().as_ptr();
+                    memcpy(ptr.add(idx * typlen), ptr.add(newlen * typlen), typlen);
+                }
+                if !self.null.is_empty() {
+                    let isnull = self.null[idx];
+                    self.null.set(newlen, isnull);
+                }
+            }
+            newlen += 1;
+        }
+        self.truncate(newlen as u32);
+        return;
+    }
+
+    fn free_blob(&mut self) {
+        if let Some(blobp) = self.blob.take() {
+            dealloc(blobp, self.blob_cap, align_of::<u8>());
+        }
+    }
+
     fn reserve_blob(&mut self, ncap: usize) {
         if let Some(blobp) = self.blob {
             if self.blob_cap < ncap {
@@ -258,8 +331,9 @@ impl Datums {
         return;
     }
 
+    // idx may be blob_cap, such as the empty varchar at the end.
     fn blob_at(&self, idx: isize) -> *mut u8 {
-        debug_assert!((idx as usize) < self.blob_cap);
+        debug_assert!((idx as usize) <= self.blob_cap);
         return unsafe {
             // Use datums.unwrap_unchecked() instead
             self.blob.unwrap().as_ptr().offset(idx)
@@ -304,6 +378,12 @@ impl Datums {
         return as_varchar(rawdata);
     }
 
+    // try_get_varchar_at() for bytea, whose datum may be not UTF-8. Unlike get_varchar_at(),
+    // the layout is checked.
+    pub fn try_get_bytea_at(&self, idx: isize) -> Option<&[u8]> {
+        return self.checked_varlen_at(idx);
+    }
+
     pub fn try_get_varchar_at(&self, idx: isize) -> Option<&str> {
         if self.is_single() {
             if self.is_single_null() {
@@ -334,6 +414,19 @@ impl Datums {
         return;
     }
 
+    // Only keep the first newlen datums.
+    pub fn truncate(&mut self, newlen: u32) {
+        debug_assert!(newlen <= self.len());
+        self.set_len(newlen);
+        if !self.null.is_empty() {
+            self.null.truncate(newlen as usize);
+            if !self.null.any() {
+                self.null.truncate(0);
+            }
+        }
+        debug_assert!(self.null_is_valid());
+    }
+
     // #[cfg(debug_assertions)]
     fn null_is_valid(&self) -> bool {
         if self.null.is_empty() {
@@ -355,11 +448,8 @@ impl Datums {
         debug_assert!(self.null_is_valid());
         debug_assert!(!self.is_single());
         let rownum = self.len() as usize;
-        self.null.reserve(rownum);
-        unsafe {
-            self.null.set_len(rownum);
-        }
-        self.null.set_all();
+        self.null.truncate(0);
+        self.null.grow(rownum, true);
         debug_assert!(self.null_is_valid());
         return;
     }
@@ -425,6 +515,79 @@ impl Datums {
         return;
     }
 
+    // Call f with the bytes of the datum at idx, None means null. typlen is -1 for varchar.
+    // idx is ignored if self is single.
+    fn with_bytes_at<R>(&self, idx: isize, typlen: i16, f: impl FnOnce(Option<&[u8]>) -> R) -> R {
+        if self.is_single() {
+            if self.is_single_null() {
+                return f(None);
+            }
+            if typlen < 0 {
+                return f(Some(self.get_single_varchar().as_bytes()));
+            }
+            let typlen = typlen as usize;
+            return f(Some(
+                &single_fixedlen_bytes(self.blob_cap, typlen)[..typlen],
+            ));
+        }
+        if self.is_null_at(idx) {
+            return f(None);
+        }
+        if typlen < 0 {
+            return f(Some(self.get_varchar_at(idx).as_bytes()));
+        }
+        let typlen = typlen as usize;
+        return f(Some(self.datums_as_bytes(idx * typlen as isize, typlen)));
+    }
+
+    // The datums are hashed and compared bytewise, so it is only suitable for the types
+    // whose equality is bitwise, such as int and varchar. All nulls are hashed to the same value.
+    pub fn hash_at<H: Hasher>(&self, idx: isize, typlen: i16, state: &mut H) {
+        self.with_bytes_at(idx, typlen, |v| v.hash(state));
+    }
+
+    // Unlike the = operator, null is equal to null here, so that nulls form one group.
+    pub fn eq_at(&self, idx: isize, other: &Datums, otheridx: isize, typlen: i16) -> bool {
+        self.with_bytes_at(idx, typlen, |l| {
+            other.with_bytes_at(otheridx, typlen, |r| l == r)
+        })
+    }
+
+    // Make self a single holding a copy of the datum at idx of src.
+    pub fn set_single_from(&mut self, src: &Datums, idx: isize, typlen: i16) {
+        src.with_bytes_at(idx, typlen, |v| match v {
+            None => self.set_single_null(),
+            Some(v) if typlen < 0 => self.set_single_varchar(v),
+            Some(v) => match typlen {
+                1 => self.set_single_fixedlen(u8::from_ne_bytes(v.try_into().unwrap())),
+                2 => self.set_single_fixedlen(u16::from_ne_bytes(v.try_into().unwrap())),
+                4 => self.set_single_fixedlen(u32::from_ne_bytes(v.try_into().unwrap())),
+                8 => self.set_single_fixedlen(u64::from_ne_bytes(v.try_into().unwrap())),
+                _ => unreachable!("set_single_from: invalid typlen: {}", typlen),
+            },
+        })
+    }
+
+    // Copy the datum at srcidx of src to idx of self, self should have been resized.
+    // The varchar datums must be set in order.
+    pub fn set_at_from(&mut self, idx: isize, src: &Datums, srcidx: isize, typlen: i16) {
+        src.with_bytes_at(srcidx, typlen, |v| match v {
+            None => {
+                if typlen < 0 {
+                    self.set_empty_at(idx);
+                }
+                self.set_null_at(idx);
+            }
+            Some(v) if typlen < 0 => self.set_varchar_at(idx, v),
+            Some(v) => unsafe {
+                let typlen = typlen as usize;
+                let ptr = self.datums.unwrap().as_ptr();
+                debug_assert!((idx as usize + 1) * typlen <= self.datums_cap);
+                memcpy(v.as_ptr(), ptr.add(idx as usize * typlen), typlen);
+            },
+        })
+    }
+
     pub fn clonerc(v: &Rc<Datums>) -> Rc<Datums> {
         v.clone()
     }
@@ -472,13 +635,97 @@ impl Datums {
         let bval: u8 = self.get_datums_at(byte_idx);
         return (bval >> bits_shift) & (((1 << BLEN) - 1) as u8);
     }
+
+    fn check_idx(&self, idx: isize) {
+        assert!(
+            idx >= 0 && idx < self.len() as isize,
+            "Datums: index out of range. idx={} len={}",
+            idx,
+            self.len()
+        );
+    }
+
+    // The checked version of get_single_fixedlen() and get_fixedlen_at(), used by TypedColumn.
+    // It panics if self is not a fixed-length column of T. T must be valid for any bits, such as
+    // the integer and the float.
+    fn checked_fixedlen_at<T: Copy>(&self, idx: isize) -> Option<T> {
+        if self.is_single() {
+            if self.is_single_null() {
+                return None;
+            }
+            assert!(
+                self.blob.is_none(),
+                "Datums: not a fixed-length single. size={}",
+                size_of::<T>()
+            );
+            return Some(self.get_single_fixedlen());
+        }
+        self.check_idx(idx);
+        let datums = match self.datums {
+            Some(datums) if self.blob.is_none() => datums,
+            _ => panic!("Datums: not a fixed-length column. size={}", size_of::<T>()),
+        };
+        assert!(
+            self.datums_align.is_multiple_of(align_of::<T>())
+                && (idx as usize + 1) * size_of::<T>() <= self.datums_cap,
+            "Datums: not a fixed-length column of the size. size={} align={} cap={}",
+            size_of::<T>(),
+            self.datums_align,
+            self.datums_cap
+        );
+        if self.is_null_at(idx) {
+            return None;
+        }
+        return Some(unsafe { ptr::read(datums.cast::<T>().as_ptr().offset(idx)) });
+    }
+
+    // The checked version of get_single_varchar() and get_varchar_at() for the variable-length
+    // types, it returns the bytes without checking the encoding.
+    fn checked_varlen_at(&self, idx: isize) -> Option<&[u8]> {
+        if self.is_single() {
+            if self.is_single_null() {
+                return None;
+            }
+            let len = (self.ndatum & LEN_MASK) as usize;
+            assert!(
+                self.blob.is_some() && len <= self.blob_cap,
+                "Datums: not a variable-length single. len={}",
+                len
+            );
+            return Some(self.get_blob_at(0, len));
+        }
+        self.check_idx(idx);
+        assert!(
+            self.datums.is_some()
+                && self.datums_align == align_of::<usize>()
+                && (idx as usize + 2) * size_of::<usize>() <= self.datums_cap,
+            "Datums: not a variable-length column. align={} cap={}",
+            self.datums_align,
+            self.datums_cap
+        );
+        if self.is_null_at(idx) {
+            return None;
+        }
+        let start: usize = self.get_datums_at(idx);
+        let end: usize = self.get_datums_at(idx + 1);
+        if start == end {
+            // The blob is not allocated if all datums are empty.
+            return Some(&[]);
+        }
+        assert!(
+            self.blob.is_some() && start < end && end <= self.blob_cap,
+            "Datums: invalid variable-length datum. start={} end={} cap={}",
+            start,
+            end,
+            self.blob_cap
+        );
+        return Some(self.get_blob_at(start, end));
+    }
 }
 
 impl Drop for Datums {
     fn drop(&mut self) {
-        if let Some(blobp) = self.blob {
-            dealloc(blobp, self.blob_cap, align_of::<u8>());
-        }
+        self.free_blob();
         if let Some(datumsp) = self.datums {
             dealloc(datumsp, self.datums_cap, self.datums_align);
         }
@@ -496,41 +743,141 @@ impl Clone for Datums {
             blob_cap: self.blob_cap,
             null: self.null.clone(),
         };
-        if self.blob.is_some() {
+        if let Some(blob) = self.blob {
             d.blob = Some(doalloc(self.blob_cap, align_of::<u8>()));
+            unsafe {
+                memcpy(blob.as_ptr(), d.blob.unwrap().as_ptr(), self.blob_cap);
+            }
         }
-        if self.datums.is_some() {
+        if let Some(datums) = self.datums {
             d.datums = Some(doalloc(self.datums_cap, self.datums_align));
+            unsafe {
+                memcpy(datums.as_ptr(), d.datums.unwrap().as_ptr(), self.datums_cap);
+            }
         }
         return d;
     }
 }
 
-fn ser_fixed_nonull(
+// The datum of the bootstrapped types, see TypedColumn::get().
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value<'a> {
+    Bool(bool),
+    Int2(i16),
+    Int4(i32),
+    Int8(i64),
+    Float4(f32),
+    Float8(f64),
+    Bytea(&'a [u8]),
+    Varchar(&'a str),
+}
+
+// The column whose type is resolved once from the type oid. Unlike the getters of Datums, get()
+// checks the single state, the index, the null and the layout of the datums, so misusing it
+// panics instead of UB.
+#[derive(Debug, Clone, Copy)]
+pub enum TypedColumn<'a> {
+    Bool(&'a Datums),
+    Int2(&'a Datums),
+    Int4(&'a Datums),
+    Int8(&'a Datums),
+    Float4(&'a Datums),
+    Float8(&'a Datums),
+    Bytea(&'a Datums),
+    Varchar(&'a Datums),
+}
+
+impl<'a> TypedColumn<'a> {
+    // None if typid is not a bootstrapped type.
+    pub fn new(typid: Oid, datums: &'a Datums) -> Option<TypedColumn<'a>> {
+        let col = match typid {
+            BOOLOID => TypedColumn::Bool(datums),
+            INT2OID => TypedColumn::Int2(datums),
+            INT4OID => TypedColumn::Int4(datums),
+            INT8OID => TypedColumn::Int8(datums),
+            FLOAT4OID => TypedColumn::Float4(datums),
+            FLOAT8OID => TypedColumn::Float8(datums),
+            BYTEAOID => TypedColumn::Bytea(datums),
+            VARCHAROID => TypedColumn::Varchar(datums),
+            _ => return None,
+        };
+        return Some(col);
+    }
+
+    // The datum at idx, None means null. idx is ignored if the column is single.
+    pub fn get(&self, idx: isize) -> Option<Value<'a>> {
+        match *self {
+            // Any non-zero byte is true, so that a corrupted bool is not UB.
+            TypedColumn::Bool(d) => d
+                .checked_fixedlen_at::<u8>(idx)
+                .map(|v| Value::Bool(v != 0)),
+            TypedColumn::Int2(d) => d.checked_fixedlen_at(idx).map(Value::Int2),
+            TypedColumn::Int4(d) => d.checked_fixedlen_at(idx).map(Value::Int4),
+            TypedColumn::Int8(d) => d.checked_fixedlen_at(idx).map(Value::Int8),
+            TypedColumn::Float4(d) => d.checked_fixedlen_at(idx).map(Value::Float4),
+            TypedColumn::Float8(d) => d.checked_fixedlen_at(idx).map(Value::Float8),
+            TypedColumn::Bytea(d) => d.checked_varlen_at(idx).map(Value::Bytea),
+            TypedColumn::Varchar(d) => d
+                .checked_varlen_at(idx)
+                .map(|v| Value::Varchar(from_utf8(v).expect("Datums: invalid varchar"))),
+        }
+    }
+}
+
+fn nullbitmap_len(rownum: u32) -> usize {
+    (rownum as usize).div_ceil(8)
+}
+
+// The column is serialized as: ndatum, nullbitmap_len, the null bitmap, the datums. The datum
+// of null is undefined, and the null bitmap is empty if there is no null.
+fn ser_fixed(
     out: &mut Vec<u8>,
     typlen: i16,
     rownum: u32,
     colidx: usize,
+    hasnull: bool,
     input: &[(Vec<Rc<Datums>>, u32)],
 ) {
     debug_assert!(typlen > 0);
     debug_assert!(rownum <= NDATUM_MAX);
+    let bitmaplen = if hasnull { nullbitmap_len(rownum) } else { 0 };
     let datumlen = typlen as usize * rownum as usize;
     let cap = size_of::<u32>()  /* ndatum */
         + size_of::<u32>() /* nullbitmap_len */
+        + bitmaplen
         + datumlen;
     out.reserve(cap);
     let outlen = out.len();
 
     ser::ser_u32(out, rownum);
-    ser::ser_u32(out, 0);
+    ser::ser_u32(out, bitmaplen as u32);
+    if hasnull {
+        let bitmapoff = out.len();
+        out.resize(bitmapoff + bitmaplen, 0);
+        let mut rowidx = 0;
+        for (cols, colrownum) in input {
+            let col = &cols[colidx];
+            for idx in 0..*colrownum as isize {
+                let isnull = if col.is_single() {
+                    col.is_single_null()
+                } else {
+                    col.is_null_at(idx)
+                };
+                if isnull {
+                    out[bitmapoff + rowidx / 8] |= 1 << (rowidx % 8);
+                }
+                rowidx += 1;
+            }
+        }
+    }
     for (cols, colrownum) in input {
         let colrownum = *colrownum;
         let col = &cols[colidx];
-        debug_assert!(!col.has_null());
+        debug_assert!(hasnull || !col.has_null());
         if col.is_single() {
-            let blobdat = col.blob_cap.to_ne_bytes();
+            let blobdat;
             let item = if typlen <= 8 {
+                blobdat = single_fixedlen_bytes(col.blob_cap, typlen as usize);
                 &blobdat[..typlen as usize]
             } else {
                 col.datums_as_bytes(0, typlen as usize)
@@ -558,19 +905,299 @@ pub fn ser(
     for colidx in 0..rel.attrs.len() {
         let typlen = rel.attrs[colidx].typ.len;
         if typlen > 0 {
-            if hasnull[colidx] {
-                unimplemented!();
-            } else {
-                ser_fixed_nonull(out, typlen, rownum, colidx, input);
-            }
+            ser_fixed(out, typlen, rownum, colidx, hasnull[colidx], input);
         } else {
             unimplemented!();
         }
     }
 }
 
+fn deser_u32(input: &[u8], off: usize) -> u32 {
+    u32::from_ne_bytes(input[off..off + size_of::<u32>()].try_into().unwrap())
+}
+
+fn deser_fixed(
+    out: &mut Datums,
+    typlen: i16,
+    typalign: u8,
+    rownum: u32,
+    input: &mut &[u8],
+) -> anyhow::Result<()> {
+    debug_assert!(typlen > 0);
+    let hdrlen = size_of::<u32>() /* ndatum */ + size_of::<u32>() /* nullbitmap_len */;
+    ensure!(
+        input.len() >= hdrlen,
+        "deser_fixed: truncated column. len={}",
+        input.len()
+    );
+    let ndatum = deser_u32(input, 0);
+    let bitmaplen = deser_u32(input, size_of::<u32>()) as usize;
+    ensure!(
+        ndatum == rownum && (bitmaplen == 0 || bitmaplen == nullbitmap_len(rownum)),
+        "deser_fixed: invalid column. ndatum={} rownum={} nullbitmap_len={}",
+        ndatum,
+        rownum,
+        bitmaplen
+    );
+    let datumlen = typlen as usize * rownum as usize;
+    ensure!(
+        input.len() >= hdrlen + bitmaplen + datumlen,
+        "deser_fixed: truncated column. len={} expect={}",
+        input.len(),
+        hdrlen + bitmaplen + datumlen
+    );
+    out.resize_fixedlen(rownum, typlen as usize, typalign as usize);
+    let datumoff = hdrlen + bitmaplen;
+    out.set_datums_bytes(&input[datumoff..datumoff + datumlen]);
+    out.set_notnull_all();
+    let bitmap = &input[hdrlen..datumoff];
+    if !bitmap.is_empty() {
+        for idx in 0..rownum as usize {
+            if bitmap[idx / 8] & (1 << (idx % 8)) != 0 {
+                out.set_null_at(idx as isize);
+            }
+        }
+    }
+    *input = &input[datumoff + datumlen..];
+    return Ok(());
+}
+
+// The inverse of ser(), input is the first colnum columns of a block whose row number is
+// rownum. The columns added by ALTER TABLE ADD COLUMN after the block was written are filled
+// with their missing values.
+pub fn deser(
+    out: &mut Vec<Rc<Datums>>,
+    rel: &rel::Rel,
+    rownum: u32,
+    colnum: usize,
+    mut input: &[u8],
+) -> anyhow::Result<()> {
+    debug_assert!(rownum > 0);
+    debug_assert!(colnum <= rel.attrs.len());
+    out.clear();
+    for attr in &rel.attrs[..colnum] {
+        let typlen = attr.typ.len;
+        if typlen <= 0 {
+            unimplemented!();
+        }
+        let mut datums = Datums::new();
+        deser_fixed(&mut datums, typlen, attr.typ.align, rownum, &mut input)?;
+        out.push(Rc::new(datums));
+    }
+    ensure!(
+        input.is_empty(),
+        "deser: unexpected trailing data. len={}",
+        input.len()
+    );
+    for attr in &rel.attrs[colnum..] {
+        let typlen = attr.typ.len;
+        if typlen <= 0 {
+            unimplemented!();
+        }
+        let mut datums = Datums::new();
+        datums.resize_fixedlen(rownum, typlen as usize, attr.typ.align as usize);
+        match &attr.missing {
+            None => datums.set_null_all(),
+            Some(missing) => {
+                for idx in 0..rownum as isize {
+                    datums.set_at_from(idx, missing, 0, typlen);
+                }
+            }
+        }
+        out.push(Rc::new(datums));
+    }
+    return Ok(());
+}
+
+// The tests only go through the allocator and bit_vec, there is no FFI, so the unsafe code of
+// Datums can be checked by Miri: cargo +nightly miri test --lib datums::
 #[cfg(test)]
 mod test {
+    use super::{Datums, TypedColumn, Value};
+    use crate::{BOOLOID, BYTEAOID, FLOAT4OID, FLOAT8OID, INT2OID, INT4OID, INT8OID, VARCHAROID};
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::Hasher;
+
+    fn hash_at(d: &Datums, idx: isize, typlen: i16) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        d.hash_at(idx, typlen, &mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn single_fixedlen() {
+        let mut d = Datums::new_single_fixedlen(true);
+        assert!(d.get_single_fixedlen::<bool>());
+        d.set_single_fixedlen(-3i16);
+        assert_eq!(d.get_single_fixedlen::<i16>(), -3);
+        d.set_single_fixedlen(-20181218i32);
+        assert_eq!(d.get_single_fixedlen::<i32>(), -20181218);
+        d.set_single_fixedlen(0.5f32);
+        assert_eq!(d.get_single_fixedlen::<f32>(), 0.5);
+        d.set_single_fixedlen(i64::MIN);
+        assert_eq!(d.get_single_fixedlen::<i64>(), i64::MIN);
+        d.set_single_fixedlen(-1.25f64);
+        assert_eq!(d.get_single_fixedlen::<f64>(), -1.25);
+        // The datum is hashed and compared by the bytes in the native byte order.
+        let mut col = Datums::new();
+        col.resize_fixedlen(1, 8, 8);
+        col.set_fixedlen_at(0, -1.25f64);
+        assert!(d.eq_at(0, &col, 0, 8));
+        assert_eq!(hash_at(&d, 0, 8), hash_at(&col, 0, 8));
+
+        // The blob of the single varchar is freed when it becomes a single fixedlen.
+        d.set_single_varchar(b"KuiBaDB");
+        d.set_single_fixedlen(33i32);
+        assert_eq!(d.clone().get_single_fixedlen::<i32>(), 33);
+    }
+
+    #[test]
+    fn varchar() {
+        let mut d = Datums::new_single_varchar(b"");
+        assert_eq!(d.get_single_varchar(), "");
+        d.set_single_varchar("盏一".as_bytes());
+        assert_eq!(d.clone().get_single_varchar(), "盏一");
+
+        let mut col = Datums::new();
+        col.resize_varlen(4);
+        col.set_varchar_at(0, b"hello");
+        col.set_empty_at(1);
+        col.set_null_at(1);
+        col.set_varchar_at(2, b"");
+        col.set_varchar_at(3, b"world");
+        let col = col.clone();
+        assert_eq!(col.try_get_varchar_at(0), Some("hello"));
+        assert_eq!(col.try_get_varchar_at(1), None);
+        assert_eq!(col.try_get_varchar_at(2), Some(""));
+        assert_eq!(col.try_get_varchar_at(3), Some("world"));
+
+        let mut out = Datums::new();
+        out.resize_varlen(4);
+        for idx in (0..4).rev() {
+            let mut single = Datums::new();
+            single.set_single_from(&col, idx, -1);
+            out.set_at_from(3 - idx, &single, 0, -1);
+            assert!(single.eq_at(0, &col, idx, -1));
+            assert_eq!(hash_at(&single, 0, -1), hash_at(&col, idx, -1));
+        }
+        assert_eq!(out.try_get_varchar_at(0), Some("world"));
+        assert_eq!(out.try_get_varchar_at(1), Some(""));
+        assert_eq!(out.try_get_varchar_at(2), None);
+        assert_eq!(out.try_get_varchar_at(3), Some("hello"));
+    }
+
+    // The datums of nulls are never set, but they are read as bytes by ser().
+    #[test]
+    fn unset_null_datums() {
+        let mut col = Datums::new();
+        col.resize_fixedlen(2, 8, 8);
+        col.set_fixedlen_at(0, 33i64);
+        col.set_null_at(1);
+        col.resize_fixedlen(4, 8, 8);
+        col.set_null_all();
+        let mut single = Datums::new();
+        single.set_single_from(&col, 3, 8);
+        assert!(single.is_single_null());
+        let mut bytes = Vec::new();
+        super::ser_fixed(&mut bytes, 8, 4, 0, true, &[(vec![col.into()], 4)]);
+        assert_eq!(&bytes[8..9], &[0b1111]);
+        assert_eq!(&bytes[17..], &[0u8; 24][..]);
+    }
+
+    // The column of 3 datums whose second datum is null.
+    fn fixedlen_col<T: Copy>(v0: T, v2: T) -> Datums {
+        let mut d = Datums::new();
+        d.resize_fixedlen(3, std::mem::size_of::<T>(), std::mem::align_of::<T>());
+        d.set_fixedlen_at(0, v0);
+        d.set_null_at(1);
+        d.set_fixedlen_at(2, v2);
+        return d;
+    }
+
+    fn varlen_col(v0: &[u8], v2: &[u8]) -> Datums {
+        let mut d = Datums::new();
+        d.resize_varlen(3);
+        d.set_varchar_at(0, v0);
+        d.set_empty_at(1);
+        d.set_null_at(1);
+        d.set_varchar_at(2, v2);
+        return d;
+    }
+
+    fn check_col(typid: crate::Oid, d: &Datums, v0: Value, v2: Value) {
+        let col = TypedColumn::new(typid, d).unwrap();
+        assert_eq!(col.get(0), Some(v0));
+        assert_eq!(col.get(1), None);
+        assert_eq!(col.get(2), Some(v2));
+    }
+
+    #[test]
+    fn typed_column() {
+        let d = fixedlen_col(true, false);
+        check_col(BOOLOID, &d, Value::Bool(true), Value::Bool(false));
+        let d = fixedlen_col(i16::MIN, 33i16);
+        check_col(INT2OID, &d, Value::Int2(i16::MIN), Value::Int2(33));
+        let d = fixedlen_col(-20181218i32, i32::MAX);
+        check_col(INT4OID, &d, Value::Int4(-20181218), Value::Int4(i32::MAX));
+        let d = fixedlen_col(i64::MIN, 0x2013020320181218i64);
+        check_col(
+            INT8OID,
+            &d,
+            Value::Int8(i64::MIN),
+            Value::Int8(0x2013020320181218),
+        );
+        let d = fixedlen_col(0.5f32, -2.25f32);
+        check_col(FLOAT4OID, &d, Value::Float4(0.5), Value::Float4(-2.25));
+        let d = fixedlen_col(1e300f64, -0.125f64);
+        check_col(FLOAT8OID, &d, Value::Float8(1e300), Value::Float8(-0.125));
+        let d = varlen_col(&[0xff, 0x00], b"");
+        check_col(BYTEAOID, &d, Value::Bytea(&[0xff, 0x00]), Value::Bytea(b""));
+        let d = varlen_col("盏一".as_bytes(), b"KuiBaDB");
+        check_col(
+            VARCHAROID,
+            &d,
+            Value::Varchar("盏一"),
+            Value::Varchar("KuiBaDB"),
+        );
+        let d = varlen_col(b"", b"");
+        check_col(VARCHAROID, &d, Value::Varchar(""), Value::Varchar(""));
+
+        // The single ignores idx.
+        let d = Datums::new_single_fixedlen(7i64);
+        let col = TypedColumn::new(INT8OID, &d).unwrap();
+        assert_eq!(
+            (col.get(0), col.get(100)),
+            (Some(Value::Int8(7)), Some(Value::Int8(7)))
+        );
+        let d = Datums::new_single_varchar(b"hello");
+        let col = TypedColumn::new(VARCHAROID, &d).unwrap();
+        assert_eq!(col.get(3), Some(Value::Varchar("hello")));
+        let d = Datums::new_single_null();
+        assert_eq!(TypedColumn::new(FLOAT8OID, &d).unwrap().get(0), None);
+        assert!(TypedColumn::new(crate::ANYOID, &d).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "index out of range")]
+    fn typed_column_out_of_range() {
+        let d = fixedlen_col(1i32, 2i32);
+        TypedColumn::new(INT4OID, &d).unwrap().get(3);
+    }
+
+    #[test]
+    #[should_panic(expected = "not a fixed-length column of the size")]
+    fn typed_column_wrong_type() {
+        let d = fixedlen_col(1i32, 2i32);
+        TypedColumn::new(INT8OID, &d).unwrap().get(2);
+    }
+
+    #[test]
+    #[should_panic(expected = "not a variable-length column")]
+    fn typed_column_not_varlen() {
+        let d = fixedlen_col(1i32, 2i32);
+        TypedColumn::new(VARCHAROID, &d).unwrap().get(0);
+    }
+
     #[test]
     fn f() {
         const BLEN: u8 = 2;
@@ -621,4 +1248,127 @@ mod test {
         assert_eq!(d.get_bits_at::<BLEN>(6), 3);
         assert_eq!(d.get_bits_at::<BLEN>(7), 0);
     }
+
+    #[test]
+    fn ser_deser() {
+        use crate::access::rel::{Attr, Rel, RelOpt};
+        use crate::access::TypeDesc;
+        use crate::{Oid, INT4OID, INT8OID};
+        use std::rc::Rc;
+
+        let attr = |num: u16, id: Oid, len: i16| Attr {
+            num: std::num::NonZeroU16::new(num).unwrap(),
+            name: format!("c{}", num),
+            typ: TypeDesc {
+                id,
+                len,
+                align: len as u8,
+                mode: -1,
+            },
+            notnull: false,
+            dropped: false,
+            default: None,
+            missing: None,
+        };
+        let mut rel = Rel {
+            attrs: vec![attr(1, INT4OID, 4), attr(2, INT8OID, 8)],
+            opt: RelOpt {
+                mvcc_blk_rows: 1,
+                mvcc_buf_cap: 1,
+                data_blk_rows: 1,
+                enable_cs_wal: false,
+            },
+        };
+        let mut c1 = super::Datums::new();
+        c1.resize_fixedlen(3, 4, 4);
+        for idx in 0..3 {
+            c1.set_fixedlen_at(idx, idx as i32 + 33);
+        }
+        let c2 = super::Datums::new_single_fixedlen(20181218i64);
+        let input = vec![(vec![Rc::new(c1), Rc::new(c2)], 3)];
+        let mut out = Vec::new();
+        super::ser(&mut out, &rel, 3, &[false, false], &input);
+
+        let mut cols = Vec::new();
+        super::deser(&mut cols, &rel, 3, 2, &out).unwrap();
+        assert_eq!(cols.len(), 2);
+        for idx in 0..3 {
+            assert_eq!(cols[0].get_fixedlen_at::<i32>(idx), idx as i32 + 33);
+            assert_eq!(cols[1].get_fixedlen_at::<i64>(idx), 20181218);
+        }
+        assert!(super::deser(&mut cols, &rel, 2, 2, &out).is_err());
+        assert!(super::deser(&mut cols, &rel, 3, 2, &out[..out.len() - 1]).is_err());
+        assert!(super::deser(&mut cols, &rel, 3, 1, &out).is_err());
+
+        // The nulls of two batches, the second batch is a single null.
+        let mut c1 = super::Datums::new();
+        c1.resize_fixedlen(2, 4, 4);
+        c1.set_fixedlen_at(0, 7i32);
+        c1.set_null_at(1);
+        let c2 = super::Datums::new_single_fixedlen(1i64);
+        let mut d1 = super::Datums::new();
+        d1.resize_fixedlen(9, 4, 4);
+        for idx in 0..9 {
+            d1.set_fixedlen_at(idx, idx as i32);
+        }
+        let input_null = vec![
+            (vec![Rc::new(c1), Rc::new(c2)], 2),
+            (
+                vec![Rc::new(d1), Rc::new(super::Datums::new_single_null())],
+                9,
+            ),
+        ];
+        let mut out = Vec::new();
+        super::ser(&mut out, &rel, 11, &[true, true], &input_null);
+        let mut nullcols = Vec::new();
+        super::deser(&mut nullcols, &rel, 11, 2, &out).unwrap();
+        assert_eq!(nullcols[0].get_fixedlen_at::<i32>(0), 7);
+        assert!(nullcols[0].is_null_at(1));
+        for idx in 2..11 {
+            assert!(!nullcols[0].is_null_at(idx));
+            assert_eq!(nullcols[0].get_fixedlen_at::<i32>(idx), idx as i32 - 2);
+            assert!(nullcols[1].is_null_at(idx));
+        }
+        assert!(!nullcols[1].is_null_at(0) && !nullcols[1].is_null_at(1));
+
+        let c1 = Rc::get_mut(&mut cols[0]).unwrap();
+        c1.retain_fixedlen(4, &[true, false, true]);
+        assert_eq!(c1.len(), 2);
+        assert_eq!(c1.get_fixedlen_at::<i32>(0), 33);
+        assert_eq!(c1.get_fixedlen_at::<i32>(1), 35);
+
+        let c1 = Rc::get_mut(&mut nullcols[0]).unwrap();
+        let mut keep = vec![true; 11];
+        keep[0] = false;
+        c1.retain_fixedlen(4, &keep);
+        assert_eq!(c1.len(), 10);
+        assert!(c1.is_null_at(0) && !c1.is_null_at(1));
+        keep.truncate(10);
+        keep[0] = false;
+        c1.retain_fixedlen(4, &keep);
+        assert!(!c1.has_null());
+        assert_eq!(c1.get_fixedlen_at::<i32>(8), 8);
+
+        // The block written before the columns were added.
+        let mut c1 = super::Datums::new();
+        c1.resize_fixedlen(2, 4, 4);
+        c1.set_fixedlen_at(0, 1i32);
+        c1.set_fixedlen_at(1, 2i32);
+        let mut out = Vec::new();
+        rel.attrs.truncate(1);
+        super::ser(&mut out, &rel, 2, &[false], &[(vec![Rc::new(c1)], 2)]);
+        let mut added = attr(3, INT4OID, 4);
+        added.missing = Some(super::Datums::new_single_fixedlen(-7i32));
+        rel.attrs.push(attr(2, INT8OID, 8));
+        rel.attrs.push(added);
+        let mut cols = Vec::new();
+        super::deser(&mut cols, &rel, 2, 1, &out).unwrap();
+        assert_eq!(cols.len(), 3);
+        for idx in 0..2 {
+            assert_eq!(cols[0].get_fixedlen_at::<i32>(idx), idx as i32 + 1);
+            assert!(cols[1].is_null_at(idx));
+            assert!(!cols[2].is_null_at(idx));
+            assert_eq!(cols[2].get_fixedlen_at::<i32>(idx), -7);
+        }
+    }
 }
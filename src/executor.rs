@@ -9,13 +9,21 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::access::cs::{datafiles, TableScan};
+use crate::access::sv::FileMeta;
+use crate::access::TypeDesc;
 use crate::datums::Datums;
+use crate::guc;
+use crate::kbensure;
 use crate::optimizer;
-use crate::optimizer::PlannedStmt;
+use crate::optimizer::{AggStrategy, PlannedStmt};
 use crate::parser::sem::{self, ExprHash};
+use crate::parser::syn::BoolExprType;
 use crate::utils::fmgr::{get_fn_addr, FmgrInfo};
-use crate::utils::{SessionState, WorkerState};
+use crate::utils::{wait_workers, SessionState, WorkerExit, WorkerRet, WorkerState};
+use crossbeam_channel::{bounded, Receiver, Sender};
 use std::collections::HashMap;
+use std::mem::{align_of, size_of};
 use std::rc::Rc;
 
 pub trait DestReceiver {
@@ -29,9 +37,14 @@ pub trait DestReceiver {
     ) -> anyhow::Result<()>;
 }
 
+mod agg;
+mod sort;
+
 struct ExprInitCtx {
     nextid: usize,
     exprid: HashMap<ExprHash, usize>,
+    // The aggregates found in the expressions, AggrefState::aggno is the index.
+    aggs: Vec<sem::Aggref>,
 }
 
 impl ExprInitCtx {
@@ -39,6 +52,7 @@ impl ExprInitCtx {
         Self {
             nextid: 0,
             exprid: HashMap::new(),
+            aggs: Vec::new(),
         }
     }
 
@@ -56,11 +70,19 @@ impl ExprInitCtx {
 // NO! f(x: Option<&i32>) will also use the stack to pass x!
 struct ExprContext<'exe> {
     results: &'exe mut [Rc<Datums>],
+    // The columns of the rows returned by the scan.
+    scantuple: &'exe [Rc<Datums>],
+    // The values of the aggregates, only used by Agg.
+    aggvalues: &'exe [Rc<Datums>],
 }
 
 impl<'exe> ExprContext<'exe> {
-    fn new(results: &'exe mut [Rc<Datums>]) -> ExprContext<'exe> {
-        Self { results }
+    fn new(results: &'exe mut [Rc<Datums>], scantuple: &'exe [Rc<Datums>]) -> ExprContext<'exe> {
+        Self {
+            results,
+            scantuple,
+            aggvalues: &[],
+        }
     }
 }
 
@@ -93,6 +115,30 @@ impl ConstState {
     }
 }
 
+struct VarState {
+    es: CommonExprState,
+    attidx: usize,
+}
+
+impl VarState {
+    fn eval(&mut self, ctx: &mut ExprContext) -> anyhow::Result<()> {
+        ctx.results[self.es.residx] = Datums::clonerc(&ctx.scantuple[self.attidx]);
+        return Ok(());
+    }
+}
+
+struct AggrefState {
+    es: CommonExprState,
+    aggno: usize,
+}
+
+impl AggrefState {
+    fn eval(&mut self, ctx: &mut ExprContext) -> anyhow::Result<()> {
+        ctx.results[self.es.residx] = Datums::clonerc(&ctx.aggvalues[self.aggno]);
+        return Ok(());
+    }
+}
+
 struct FuncExprState {
     es: CommonExprState,
     args: Vec<ExprState>,
@@ -126,9 +172,115 @@ impl FuncExprState {
     }
 }
 
+// The value of the bool datum at idx, None means null.
+fn bool_at(d: &Datums, idx: isize) -> Option<bool> {
+    if d.is_single() {
+        if d.is_single_null() {
+            return None;
+        }
+        return Some(d.get_single_fixedlen());
+    }
+    if d.is_null_at(idx) {
+        return None;
+    }
+    return Some(d.get_fixedlen_at(idx));
+}
+
+// The three-valued logic of SQL.
+fn bool_expr_at(boolop: BoolExprType, args: &[Rc<Datums>], idx: isize) -> Option<bool> {
+    match boolop {
+        BoolExprType::Not => bool_at(&args[0], idx).map(|v| !v),
+        BoolExprType::And => {
+            let mut ret = Some(true);
+            for arg in args {
+                match bool_at(arg, idx) {
+                    Some(false) => return Some(false),
+                    None => ret = None,
+                    Some(true) => {}
+                }
+            }
+            ret
+        }
+        BoolExprType::Or => {
+            let mut ret = Some(false);
+            for arg in args {
+                match bool_at(arg, idx) {
+                    Some(true) => return Some(true),
+                    None => ret = None,
+                    Some(false) => {}
+                }
+            }
+            ret
+        }
+    }
+}
+
+// ExecEvalAnd, ExecEvalOr, ExecEvalNot
+fn eval_bool_expr(boolop: BoolExprType, args: &[Rc<Datums>], ret: &mut Datums) {
+    let rownum = match args.iter().find(|v| !v.is_single()) {
+        None => {
+            match bool_expr_at(boolop, args, 0) {
+                None => ret.set_single_null(),
+                Some(v) => ret.set_single_fixedlen(v),
+            }
+            return;
+        }
+        Some(v) => v.len(),
+    };
+    ret.set_notnull_all();
+    ret.resize_fixedlen(rownum, size_of::<bool>(), align_of::<bool>());
+    for idx in 0..rownum as isize {
+        match bool_expr_at(boolop, args, idx) {
+            None => ret.set_null_at(idx),
+            Some(v) => ret.set_fixedlen_at(idx, v),
+        }
+    }
+    return;
+}
+
+// sel[idx] is true if the row at idx satisfies all quals evaluated so far,
+// a null qual result means the row is not selected.
+fn and_selection(sel: &mut [bool], qual: &Datums) {
+    if qual.is_single() {
+        if bool_at(qual, 0) != Some(true) {
+            sel.iter_mut().for_each(|v| *v = false);
+        }
+        return;
+    }
+    debug_assert_eq!(sel.len(), qual.len() as usize);
+    for (idx, v) in sel.iter_mut().enumerate() {
+        *v = *v && bool_at(qual, idx as isize) == Some(true);
+    }
+    return;
+}
+
+struct BoolExprState {
+    es: CommonExprState,
+    boolop: BoolExprType,
+    args: Vec<ExprState>,
+    argsval: Vec<Rc<Datums>>,
+}
+
+impl BoolExprState {
+    fn eval(&mut self, ctx: &mut ExprContext, worker: &WorkerState) -> anyhow::Result<()> {
+        let _clear = Clear(&mut self.argsval as *mut _);
+        for argexpr in &mut self.args {
+            argexpr.eval(ctx, worker)?;
+            let rescln = Datums::clonerc(&ctx.results[argexpr.es().residx]);
+            self.argsval.push(rescln);
+        }
+        let ret = Rc::make_mut(&mut ctx.results[self.es.residx]);
+        eval_bool_expr(self.boolop, &self.argsval, ret);
+        return Ok(());
+    }
+}
+
 enum ExprState {
     Const(ConstState),
     Func(FuncExprState),
+    Var(VarState),
+    Bool(BoolExprState),
+    Aggref(AggrefState),
     RefRes(RefRes),
 }
 
@@ -137,6 +289,9 @@ impl ExprState {
         match self {
             ExprState::Const(c) => c.eval(ctx),
             ExprState::Func(f) => f.eval(ctx, worker),
+            ExprState::Var(v) => v.eval(ctx),
+            ExprState::Bool(b) => b.eval(ctx, worker),
+            ExprState::Aggref(a) => a.eval(ctx),
             ExprState::RefRes(_) => {
                 return Ok(());
             }
@@ -151,6 +306,9 @@ impl ExprState {
         match self {
             ExprState::Const(c) => &c.es,
             ExprState::Func(f) => &f.es,
+            ExprState::Var(v) => &v.es,
+            ExprState::Bool(b) => &b.es,
+            ExprState::Aggref(a) => &a.es,
             ExprState::RefRes(r) => &r.es,
         }
     }
@@ -169,6 +327,18 @@ fn exec_init_const(
     return Ok(ret);
 }
 
+fn exec_init_var(
+    node: &sem::Var,
+    _: &WorkerState,
+    initctx: &mut ExprInitCtx,
+) -> anyhow::Result<ExprState> {
+    let residx = initctx.advance();
+    return Ok(ExprState::Var(VarState {
+        es: CommonExprState { residx },
+        attidx: node.varattno.get() as usize - 1,
+    }));
+}
+
 fn exec_init_func(
     node: &sem::FuncExpr,
     state: &WorkerState,
@@ -191,6 +361,38 @@ fn exec_init_func(
     }));
 }
 
+fn exec_init_bool(
+    node: &sem::BoolExpr,
+    state: &WorkerState,
+    initctx: &mut ExprInitCtx,
+) -> anyhow::Result<ExprState> {
+    let mut args = Vec::with_capacity(node.args.len());
+    for e in &node.args {
+        args.push(exec_init_expr(e, state, initctx)?);
+    }
+    let residx = initctx.advance();
+    return Ok(ExprState::Bool(BoolExprState {
+        es: CommonExprState { residx },
+        boolop: node.boolop,
+        argsval: Vec::with_capacity(args.len()),
+        args,
+    }));
+}
+
+fn exec_init_aggref(
+    node: &sem::Aggref,
+    _: &WorkerState,
+    initctx: &mut ExprInitCtx,
+) -> anyhow::Result<ExprState> {
+    let residx = initctx.advance();
+    let aggno = initctx.aggs.len();
+    initctx.aggs.push(node.clone());
+    return Ok(ExprState::Aggref(AggrefState {
+        es: CommonExprState { residx },
+        aggno,
+    }));
+}
+
 fn exec_init_expr(
     node: &sem::Expr,
     state: &WorkerState,
@@ -204,6 +406,9 @@ fn exec_init_expr(
     let exprstate = match node {
         sem::Expr::Const(c) => exec_init_const(c, state, initctx)?,
         sem::Expr::Func(f) => exec_init_func(f, state, initctx)?,
+        sem::Expr::Var(v) => exec_init_var(v, state, initctx)?,
+        sem::Expr::Bool(b) => exec_init_bool(b, state, initctx)?,
+        sem::Expr::Aggref(a) => exec_init_aggref(a, state, initctx)?,
     };
 
     initctx.exprid.insert(exprhash, exprstate.es().residx);
@@ -236,6 +441,16 @@ impl ProjectionInfo {
     }
 }
 
+// Make sure the results are not shared with others before the evaluation.
+fn reset_results(results: &mut [Rc<Datums>]) {
+    for res in results.iter_mut().rev() {
+        if Rc::strong_count(res) > 1 {
+            // Delay this allocation?
+            *res = Rc::new(Datums::new());
+        }
+    }
+}
+
 // // Put only fields that will be needed by all operators in PlanStateBase.
 // struct PlanStateBase<'exe> {
 //     // plan: &'opt optimizer::Plan<'syn>, // It is not always necessary.
@@ -246,6 +461,7 @@ impl ProjectionInfo {
 
 struct ResultState {
     proj_info: ProjectionInfo,
+    resconstantqual: Option<ExprState>,
     done: bool,
     results: Vec<Rc<Datums>>,
     ret: Vec<Rc<Datums>>,
@@ -264,14 +480,15 @@ impl ResultState {
         }
         self.done = true;
         self.ret.clear();
-        for res in (&mut self.results).iter_mut().rev() {
-            if Rc::strong_count(res) > 1 {
-                // Delay this allocation?
-                *res = Rc::new(Datums::new());
+        reset_results(&mut self.results);
+        let mut ectx = ExprContext::new(&mut self.results, &[]);
+
+        if let Some(qual) = &mut self.resconstantqual {
+            qual.eval(&mut ectx, worker)?;
+            if bool_at(&ectx.results[qual.es().residx], 0) != Some(true) {
+                return Ok((None, 0));
             }
         }
-        let mut ectx = ExprContext::new(&mut self.results);
-
         self.proj_info.eval(&mut ectx, worker)?;
         for expr in &self.proj_info.pi_state {
             let rescln = Datums::clonerc(&self.results[expr.es().residx]);
@@ -281,8 +498,485 @@ impl ResultState {
     }
 }
 
+struct SeqScanState {
+    proj_info: ProjectionInfo,
+    // The qual is evaluated before the rows are filtered, so it uses its own results.
+    qual: Vec<ExprState>,
+    qual_results: Vec<Rc<Datums>>,
+    sel: Vec<bool>,
+    typlens: Vec<usize>,
+    scan: TableScan,
+    scantuple: Vec<Rc<Datums>>,
+    results: Vec<Rc<Datums>>,
+    ret: Vec<Rc<Datums>>,
+}
+
+impl SeqScanState {
+    // ExecQual, only keep the rows that satisfy the qual in scantuple.
+    fn filter(&mut self, worker: &WorkerState, rownum: u32) -> anyhow::Result<u32> {
+        reset_results(&mut self.qual_results);
+        self.sel.clear();
+        self.sel.resize(rownum as usize, true);
+        let mut ectx = ExprContext::new(&mut self.qual_results, &self.scantuple);
+        for qual in &mut self.qual {
+            qual.eval(&mut ectx, worker)?;
+            and_selection(&mut self.sel, &ectx.results[qual.es().residx]);
+        }
+        let selnum = self.sel.iter().filter(|v| **v).count() as u32;
+        if selnum == 0 || selnum == rownum {
+            return Ok(selnum);
+        }
+        // Release the references to scantuple, so that make_mut() will not clone.
+        reset_results(&mut self.qual_results);
+        for (col, &typlen) in self.scantuple.iter_mut().zip(&self.typlens) {
+            Rc::make_mut(col).retain_fixedlen(typlen, &self.sel);
+        }
+        return Ok(selnum);
+    }
+
+    fn exec(
+        &mut self,
+        worker: &WorkerState,
+    ) -> anyhow::Result<(
+        /* rows */ Option<&[Rc<Datums>]>,
+        /* rownumber */ u32,
+    )> {
+        let rownum = loop {
+            // CHECK_FOR_INTERRUPTS, every plan reads the rows from the seqscan batch by batch.
+            worker.check_termreq()?;
+            let rownum = match self.scan.next(worker, &mut self.scantuple)? {
+                None => return Ok((None, 0)),
+                Some(0) => continue,
+                Some(rownum) => rownum,
+            };
+            if self.qual.is_empty() {
+                break rownum;
+            }
+            let rownum = self.filter(worker, rownum)?;
+            if rownum > 0 {
+                break rownum;
+            }
+        };
+        self.ret.clear();
+        reset_results(&mut self.results);
+        let mut ectx = ExprContext::new(&mut self.results, &self.scantuple);
+
+        self.proj_info.eval(&mut ectx, worker)?;
+        for expr in &self.proj_info.pi_state {
+            let rescln = Datums::clonerc(&self.results[expr.es().residx]);
+            self.ret.push(rescln);
+        }
+        return Ok((Some(&self.ret), rownum));
+    }
+}
+
+struct AggStatePerAgg {
+    // None for count(*).
+    arg: Option<ExprState>,
+    // The transition state of AggStrategy::Plain, and the initial state of AggStrategy::Hashed.
+    trans: agg::AggTrans,
+}
+
+// The rows aggregated by AggState.
+enum AggInput {
+    Plan(Box<PlanState>),
+    // The partial aggregations done by the parallel workers are combined by the leader.
+    Partial(ParallelWorkers<PartialAgg>),
+}
+
+struct AggState {
+    input: AggInput,
+    aggstrategy: AggStrategy,
+    aggs: Vec<AggStatePerAgg>,
+    // The grouping expressions are evaluated together with the arguments of aggregates.
+    group_exprs: Vec<ExprState>,
+    group_typs: Vec<TypeDesc>,
+    group_keys: Vec<Rc<Datums>>,
+    hashtable: agg::AggHashTable,
+    // The group of each row of the current batch.
+    groups: Vec<usize>,
+    // The next group to be returned.
+    nextgroup: usize,
+    batch_size: usize,
+    // The results of the arguments of aggregates.
+    arg_results: Vec<Rc<Datums>>,
+    aggvalues: Vec<Rc<Datums>>,
+    proj_info: ProjectionInfo,
+    done: bool,
+    // results[..group_exprs.len()] are the grouping values, see exec_init_agg().
+    results: Vec<Rc<Datums>>,
+    ret: Vec<Rc<Datums>>,
+}
+
+impl AggState {
+    fn advance(&mut self, worker: &WorkerState) -> anyhow::Result<()> {
+        let lefttree = match &mut self.input {
+            AggInput::Plan(lefttree) => lefttree,
+            AggInput::Partial(workers) => {
+                for partial in workers.wait()? {
+                    self.combine(partial)?;
+                }
+                return Ok(());
+            }
+        };
+        loop {
+            let (rows, rownum) = lefttree.exec(worker)?;
+            let rows = match rows {
+                None => return Ok(()),
+                Some(rows) => rows,
+            };
+            reset_results(&mut self.arg_results);
+            let mut ectx = ExprContext::new(&mut self.arg_results, rows);
+            if self.aggstrategy == AggStrategy::Hashed {
+                for expr in &mut self.group_exprs {
+                    expr.eval(&mut ectx, worker)?;
+                    let rescln = Datums::clonerc(&ectx.results[expr.es().residx]);
+                    self.group_keys.push(rescln);
+                }
+                let ret = self
+                    .hashtable
+                    .lookup(&self.group_keys, rownum, &mut self.groups);
+                self.group_keys.clear();
+                ret?;
+            }
+            for (aggno, peragg) in self.aggs.iter_mut().enumerate() {
+                let argval = match &mut peragg.arg {
+                    None => None,
+                    Some(arg) => {
+                        arg.eval(&mut ectx, worker)?;
+                        Some(&*ectx.results[arg.es().residx])
+                    }
+                };
+                match self.aggstrategy {
+                    AggStrategy::Plain => peragg.trans.advance(argval, rownum)?,
+                    AggStrategy::Hashed => {
+                        for (idx, &group) in self.groups.iter().enumerate() {
+                            let trans = self.hashtable.trans_mut(group, aggno);
+                            trans.advance_at(argval, idx as isize)?;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // combine_aggregates
+    fn combine(&mut self, partial: PartialAgg) -> anyhow::Result<()> {
+        match self.aggstrategy {
+            AggStrategy::Plain => {
+                for (peragg, trans) in self.aggs.iter_mut().zip(&partial.trans) {
+                    peragg.trans.combine(trans)?;
+                }
+            }
+            AggStrategy::Hashed => self.hashtable.combine(&partial.hashtable)?,
+        }
+        return Ok(());
+    }
+
+    // Store the aggregate values and the grouping values of the groups in [start, end) to
+    // aggvalues and results.
+    fn finalize_groups(&mut self, start: usize, end: usize) {
+        let rownum = (end - start) as u32;
+        for (aggno, aggvalue) in self.aggvalues.iter_mut().enumerate() {
+            let out = Rc::make_mut(aggvalue);
+            self.aggs[aggno].trans.init_output(out, rownum);
+            for (idx, group) in (start..end).enumerate() {
+                self.hashtable
+                    .trans(group, aggno)
+                    .finalize_at(out, idx as isize);
+            }
+        }
+        for (keyno, typ) in self.group_typs.iter().enumerate() {
+            let out = Rc::make_mut(&mut self.results[keyno]);
+            if typ.len < 0 {
+                out.resize_varlen(rownum);
+            } else {
+                out.resize_fixedlen(rownum, typ.len as usize, typ.align as usize);
+            }
+            out.set_notnull_all();
+            for (idx, group) in (start..end).enumerate() {
+                let key = self.hashtable.key(group, keyno);
+                out.set_at_from(idx as isize, key, 0, typ.len);
+            }
+        }
+    }
+
+    // ExecAgg
+    fn exec(
+        &mut self,
+        worker: &WorkerState,
+    ) -> anyhow::Result<(
+        /* rows */ Option<&[Rc<Datums>]>,
+        /* rownumber */ u32,
+    )> {
+        let rownum = match self.aggstrategy {
+            AggStrategy::Plain => {
+                if self.done {
+                    return Ok((None, 0));
+                }
+                self.done = true;
+                self.advance(worker)?;
+                reset_results(&mut self.aggvalues);
+                for (peragg, aggvalue) in self.aggs.iter().zip(&mut self.aggvalues) {
+                    peragg.trans.finalize(Rc::make_mut(aggvalue));
+                }
+                reset_results(&mut self.results);
+                1
+            }
+            AggStrategy::Hashed => {
+                if !self.done {
+                    self.done = true;
+                    self.advance(worker)?;
+                }
+                // agg_retrieve_hash_table
+                let start = self.nextgroup;
+                if start >= self.hashtable.len() {
+                    return Ok((None, 0));
+                }
+                let end = self.hashtable.len().min(start + self.batch_size);
+                self.nextgroup = end;
+                reset_results(&mut self.aggvalues);
+                reset_results(&mut self.results);
+                self.finalize_groups(start, end);
+                (end - start) as u32
+            }
+        };
+
+        self.ret.clear();
+        let mut ectx = ExprContext::new(&mut self.results, &[]);
+        ectx.aggvalues = &self.aggvalues;
+        self.proj_info.eval(&mut ectx, worker)?;
+        for expr in &self.proj_info.pi_state {
+            let rescln = Datums::clonerc(&self.results[expr.es().residx]);
+            self.ret.push(rescln);
+        }
+        return Ok((Some(&self.ret), rownum));
+    }
+}
+
+struct SortState {
+    lefttree: Box<PlanState>,
+    tuplesort: sort::Tuplesort,
+    done: bool,
+    // The next sorted row to be returned.
+    next: usize,
+    batch_size: usize,
+    ret: Vec<Rc<Datums>>,
+}
+
+impl SortState {
+    // ExecSort
+    fn exec(
+        &mut self,
+        worker: &WorkerState,
+    ) -> anyhow::Result<(
+        /* rows */ Option<&[Rc<Datums>]>,
+        /* rownumber */ u32,
+    )> {
+        if !self.done {
+            self.done = true;
+            loop {
+                let (rows, rownum) = self.lefttree.exec(worker)?;
+                match rows {
+                    None => break,
+                    Some(rows) => self.tuplesort.put_batch(rows, rownum),
+                }
+            }
+            self.tuplesort.perform_sort();
+        }
+        let start = self.next;
+        if start >= self.tuplesort.len() {
+            return Ok((None, 0));
+        }
+        let end = self.tuplesort.len().min(start + self.batch_size);
+        self.next = end;
+        reset_results(&mut self.ret);
+        self.tuplesort.get_batch(start, end, &mut self.ret);
+        return Ok((Some(&self.ret), (end - start) as u32));
+    }
+}
+
+struct LimitState {
+    lefttree: Box<PlanState>,
+    count: u64,
+    // The number of rows returned so far.
+    returned: u64,
+    ret: Vec<Rc<Datums>>,
+}
+
+impl LimitState {
+    // ExecLimit
+    fn exec(
+        &mut self,
+        worker: &WorkerState,
+    ) -> anyhow::Result<(
+        /* rows */ Option<&[Rc<Datums>]>,
+        /* rownumber */ u32,
+    )> {
+        if self.returned >= self.count {
+            return Ok((None, 0));
+        }
+        let (rows, rownum) = self.lefttree.exec(worker)?;
+        let rows = match rows {
+            None => return Ok((None, 0)),
+            Some(rows) => rows,
+        };
+        let left = self.count - self.returned;
+        self.ret.clear();
+        for col in rows {
+            self.ret.push(Datums::clonerc(col));
+        }
+        if (rownum as u64) <= left {
+            self.returned += rownum as u64;
+            return Ok((Some(&self.ret), rownum));
+        }
+        for col in &mut self.ret {
+            if !col.is_single() {
+                Rc::make_mut(col).truncate(left as u32);
+            }
+        }
+        self.returned = self.count;
+        return Ok((Some(&self.ret), left as u32));
+    }
+}
+
+// The rows returned by the parallel workers.
+type WorkerRows = (Vec<Datums>, u32);
+
+// Scan the files assigned to the worker and send the rows to the leader.
+fn parallel_seqscan_main(
+    (node, files, send): (optimizer::SeqScan, Vec<FileMeta>, Sender<WorkerRows>),
+    worker: &mut WorkerState,
+) -> anyhow::Result<()> {
+    let mut scanstate = exec_init_seqscan_files(&node, files, worker)?;
+    loop {
+        let (rows, rownum) = scanstate.exec(worker)?;
+        let rows = match rows {
+            None => return Ok(()),
+            Some(rows) => rows,
+        };
+        let rows = rows.iter().map(|v| Datums::clone(v)).collect();
+        if send.send((rows, rownum)).is_err() {
+            // The leader does not need more rows, such as LIMIT.
+            return Ok(());
+        }
+    }
+}
+
+// The workers started by SessionState::exec() for the plan node, they are waited on drop.
+struct ParallelWorkers<Ret> {
+    rec: Receiver<WorkerRet<Ret>>,
+    exits: Vec<WorkerExit>,
+}
+
+impl<Ret> ParallelWorkers<Ret> {
+    fn new(rec: Receiver<WorkerRet<Ret>>) -> Self {
+        Self {
+            rec,
+            exits: Vec::new(),
+        }
+    }
+
+    // Wait for all workers to exit, see wait_workers().
+    fn wait(&mut self) -> anyhow::Result<Vec<Ret>> {
+        let mut rets = Vec::new();
+        for (exit, ret) in wait_workers(&self.rec)? {
+            self.exits.push(exit);
+            rets.push(ret);
+        }
+        return Ok(rets);
+    }
+
+    // Returns the number of workers.
+    fn shutdown(&mut self, sess: &mut SessionState) -> anyhow::Result<usize> {
+        self.wait()?;
+        let nworkers = self.exits.len();
+        for exit in self.exits.drain(..) {
+            sess.exit_worker(exit);
+        }
+        return Ok(nworkers);
+    }
+}
+
+impl<Ret> Drop for ParallelWorkers<Ret> {
+    fn drop(&mut self) {
+        for _item in self.rec.iter() {}
+    }
+}
+
+// The aggregation done by the parallel worker, see parallel_agg_main().
+struct PartialAgg {
+    // The transition states of AggStrategy::Plain.
+    trans: Vec<agg::AggTrans>,
+    hashtable: agg::AggHashTable,
+}
+
+// Aggregate the rows of the files assigned to the worker, the transition states are returned
+// to the leader without finalization.
+fn parallel_agg_main(
+    (node, files): (optimizer::Agg, Vec<FileMeta>),
+    worker: &mut WorkerState,
+) -> anyhow::Result<PartialAgg> {
+    let scan = match &*node.lefttree {
+        optimizer::Plan::SeqScan(scan) => scan,
+        _ => unreachable!("parallel_agg_main: the lefttree is not SeqScan"),
+    };
+    let scanstate = exec_init_seqscan_files(scan, files, worker)?;
+    let input = AggInput::Plan(Box::new(PlanState::SeqScan(scanstate)));
+    let mut aggstate = exec_init_agg_input(&node, worker, input)?;
+    aggstate.advance(worker)?;
+    return Ok(PartialAgg {
+        trans: aggstate.aggs.into_iter().map(|v| v.trans).collect(),
+        hashtable: aggstate.hashtable,
+    });
+}
+
+// ExecGather, the leader of the parallel SeqScan.
+struct GatherState {
+    // None after the workers are shut down. It is dropped before workers so that the workers
+    // that are still sending rows exit.
+    rows: Option<Receiver<WorkerRows>>,
+    workers: ParallelWorkers<()>,
+    ret: Vec<Rc<Datums>>,
+}
+
+impl GatherState {
+    fn exec(
+        &mut self,
+        _worker: &WorkerState,
+    ) -> anyhow::Result<(
+        /* rows */ Option<&[Rc<Datums>]>,
+        /* rownumber */ u32,
+    )> {
+        let (rows, rownum) = match self.rows.as_ref().map(|v| v.recv()) {
+            Some(Ok(rows)) => rows,
+            Some(Err(_)) => {
+                // All workers have exited.
+                self.rows = None;
+                self.workers.wait()?;
+                return Ok((None, 0));
+            }
+            None => return Ok((None, 0)),
+        };
+        self.ret.clear();
+        self.ret.extend(rows.into_iter().map(Rc::new));
+        return Ok((Some(&self.ret), rownum));
+    }
+
+    // ExecShutdownGather
+    fn shutdown(&mut self, sess: &mut SessionState) -> anyhow::Result<usize> {
+        self.rows = None;
+        return self.workers.shutdown(sess);
+    }
+}
+
 enum PlanState {
     Result(ResultState),
+    SeqScan(SeqScanState),
+    Gather(GatherState),
+    Agg(AggState),
+    Sort(SortState),
+    Limit(LimitState),
 }
 
 impl PlanState {
@@ -295,6 +989,25 @@ impl PlanState {
     )> {
         match self {
             PlanState::Result(s) => s.exec(worker),
+            PlanState::SeqScan(s) => s.exec(worker),
+            PlanState::Gather(g) => g.exec(worker),
+            PlanState::Agg(a) => a.exec(worker),
+            PlanState::Sort(s) => s.exec(worker),
+            PlanState::Limit(l) => l.exec(worker),
+        }
+    }
+
+    // ExecShutdownNode, returns the number of parallel workers that have exited.
+    fn shutdown(&mut self, sess: &mut SessionState) -> anyhow::Result<usize> {
+        match self {
+            PlanState::Result(_) | PlanState::SeqScan(_) => Ok(0),
+            PlanState::Gather(g) => g.shutdown(sess),
+            PlanState::Agg(a) => match &mut a.input {
+                AggInput::Plan(lefttree) => lefttree.shutdown(sess),
+                AggInput::Partial(workers) => workers.shutdown(sess),
+            },
+            PlanState::Sort(s) => s.lefttree.shutdown(sess),
+            PlanState::Limit(l) => l.lefttree.shutdown(sess),
         }
     }
 }
@@ -304,33 +1017,266 @@ fn exec_init_result<'opt, 'exe>(
     state: &'exe WorkerState,
 ) -> anyhow::Result<ResultState> {
     let mut initctx = ExprInitCtx::new();
+    let resconstantqual = match &node.resconstantqual {
+        None => None,
+        Some(qual) => Some(exec_init_expr(qual, state, &mut initctx)?),
+    };
     let proj_info = ProjectionInfo::try_new(&node.plan.tlist, state, &mut initctx)?;
     let mut results = Vec::with_capacity(initctx.nextid);
     results.resize_with(initctx.nextid, Default::default);
     Ok(ResultState {
         proj_info,
+        resconstantqual,
         results,
         done: false,
         ret: Vec::with_capacity(node.plan.tlist.len()),
     })
 }
 
+fn exec_init_seqscan<'opt, 'exe>(
+    node: &'opt optimizer::SeqScan,
+    state: &'exe WorkerState,
+    sess: &mut SessionState,
+) -> anyhow::Result<PlanState> {
+    let mut workerfiles = partition_files(node, state)?;
+    let parallel = workerfiles.len();
+    if parallel <= 1 {
+        let files = workerfiles.pop().unwrap();
+        let scanstate = exec_init_seqscan_files(node, files, state)?;
+        return Ok(PlanState::SeqScan(scanstate));
+    }
+    let (send, rows) = bounded::<WorkerRows>(parallel);
+    let arggen = |idx: usize| {
+        let mut node = node.clone();
+        node.parallel = 1;
+        (node, workerfiles[idx].clone(), send.clone())
+    };
+    let workers = sess.exec(parallel, arggen, parallel_seqscan_main);
+    return Ok(PlanState::Gather(GatherState {
+        rows: Some(rows),
+        workers: ParallelWorkers::new(workers),
+        ret: Vec::with_capacity(node.plan.tlist.len()),
+    }));
+}
+
+// Distribute the files to at most node.parallel workers in turn.
+fn partition_files(
+    node: &optimizer::SeqScan,
+    state: &WorkerState,
+) -> anyhow::Result<Vec<Vec<FileMeta>>> {
+    let files = datafiles(&node.table, &node.rel, state)?;
+    let parallel = node.parallel.min(files.len()).max(1);
+    let mut workerfiles = vec![Vec::new(); parallel];
+    for (idx, file) in files.into_iter().enumerate() {
+        workerfiles[idx % parallel].push(file);
+    }
+    return Ok(workerfiles);
+}
+
+fn exec_init_seqscan_files<'opt, 'exe>(
+    node: &'opt optimizer::SeqScan,
+    files: Vec<FileMeta>,
+    state: &'exe WorkerState,
+) -> anyhow::Result<SeqScanState> {
+    let mut qualctx = ExprInitCtx::new();
+    let mut qual = Vec::with_capacity(node.qual.len());
+    for e in &node.qual {
+        qual.push(exec_init_expr(e, state, &mut qualctx)?);
+    }
+    let mut qual_results = Vec::with_capacity(qualctx.nextid);
+    qual_results.resize_with(qualctx.nextid, Default::default);
+    let mut initctx = ExprInitCtx::new();
+    let proj_info = ProjectionInfo::try_new(&node.plan.tlist, state, &mut initctx)?;
+    let mut results = Vec::with_capacity(initctx.nextid);
+    results.resize_with(initctx.nextid, Default::default);
+    Ok(SeqScanState {
+        proj_info,
+        qual,
+        qual_results,
+        sel: Vec::new(),
+        typlens: node.rel.attrs.iter().map(|v| v.typ.len as usize).collect(),
+        scan: TableScan::with_files(node.table, node.rel.clone(), files, state)?,
+        scantuple: Vec::with_capacity(node.rel.attrs.len()),
+        results,
+        ret: Vec::with_capacity(node.plan.tlist.len()),
+    })
+}
+
+// ExecInitAgg, the aggregation over the parallel SeqScan is done by the workers.
+fn exec_init_agg<'opt, 'exe>(
+    node: &'opt optimizer::Agg,
+    state: &'exe WorkerState,
+    sess: &mut SessionState,
+) -> anyhow::Result<AggState> {
+    let scan = match &*node.lefttree {
+        optimizer::Plan::SeqScan(scan) if scan.parallel > 1 => scan,
+        lefttree => {
+            let input = AggInput::Plan(Box::new(exec_init_plan(lefttree, state, sess)?));
+            return exec_init_agg_input(node, state, input);
+        }
+    };
+    let mut workerfiles = partition_files(scan, state)?;
+    let parallel = workerfiles.len();
+    if parallel <= 1 {
+        let scanstate = exec_init_seqscan_files(scan, workerfiles.pop().unwrap(), state)?;
+        let input = AggInput::Plan(Box::new(PlanState::SeqScan(scanstate)));
+        return exec_init_agg_input(node, state, input);
+    }
+    let arggen = |idx: usize| (node.clone(), workerfiles[idx].clone());
+    let workers = sess.exec(parallel, arggen, parallel_agg_main);
+    let input = AggInput::Partial(ParallelWorkers::new(workers));
+    return exec_init_agg_input(node, state, input);
+}
+
+fn exec_init_agg_input<'opt, 'exe>(
+    node: &'opt optimizer::Agg,
+    state: &'exe WorkerState,
+    input: AggInput,
+) -> anyhow::Result<AggState> {
+    let mut initctx = ExprInitCtx::new();
+    // The expressions matching the grouping expressions refer to the grouping values directly.
+    for group in &node.group_clause {
+        let residx = initctx.advance();
+        initctx.exprid.insert(group.expr.hash(), residx);
+    }
+    let proj_info = ProjectionInfo::try_new(&node.plan.tlist, state, &mut initctx)?;
+    let mut results = Vec::with_capacity(initctx.nextid);
+    results.resize_with(initctx.nextid, Default::default);
+
+    // The arguments are evaluated over the rows returned by lefttree.
+    let mut argctx = ExprInitCtx::new();
+    let mut group_exprs = Vec::with_capacity(node.group_clause.len());
+    let mut group_typs = Vec::with_capacity(node.group_clause.len());
+    for group in &node.group_clause {
+        let typ = group.typ;
+        kbensure!(
+            matches!(typ.len, -1 | 1 | 2 | 4 | 8),
+            ERRCODE_UNDEFINED_FUNCTION,
+            "could not identify an equality operator for type {}",
+            typ.id
+        );
+        group_exprs.push(exec_init_expr(&group.expr, state, &mut argctx)?);
+        group_typs.push(typ);
+    }
+    let mut aggs = Vec::with_capacity(initctx.aggs.len());
+    for aggref in &initctx.aggs {
+        let arg = match aggref.args.first() {
+            None => None,
+            Some(arg) => Some(exec_init_expr(arg, state, &mut argctx)?),
+        };
+        aggs.push(AggStatePerAgg {
+            arg,
+            trans: agg::AggTrans::new(aggref.aggfnoid)?,
+        });
+    }
+    let mut arg_results = Vec::with_capacity(argctx.nextid);
+    arg_results.resize_with(argctx.nextid, Default::default);
+    let mut aggvalues = Vec::with_capacity(aggs.len());
+    aggvalues.resize_with(aggs.len(), Default::default);
+    let hashtable = agg::AggHashTable::new(
+        group_typs.iter().map(|v| v.len).collect(),
+        aggs.iter().map(|v| v.trans.clone()).collect(),
+        state.work_mem,
+    );
+    Ok(AggState {
+        input,
+        aggstrategy: node.aggstrategy,
+        aggs,
+        group_keys: Vec::with_capacity(group_exprs.len()),
+        group_exprs,
+        group_typs,
+        hashtable,
+        groups: Vec::new(),
+        nextgroup: 0,
+        batch_size: guc::get_int(&state.gucstate, guc::BatchSize).max(1) as usize,
+        arg_results,
+        aggvalues,
+        proj_info,
+        done: false,
+        results,
+        ret: Vec::with_capacity(node.plan.tlist.len()),
+    })
+}
+
+// ExecInitSort
+fn exec_init_sort<'opt, 'exe>(
+    node: &'opt optimizer::Sort,
+    state: &'exe WorkerState,
+    sess: &mut SessionState,
+) -> anyhow::Result<SortState> {
+    let mut ret = Vec::with_capacity(node.plan.tlist.len());
+    ret.resize_with(node.plan.tlist.len(), Default::default);
+    Ok(SortState {
+        lefttree: Box::new(exec_init_plan(&node.lefttree, state, sess)?),
+        tuplesort: sort::Tuplesort::new(&node.sort_clause, node.coltyps.clone(), node.bound)?,
+        done: false,
+        next: 0,
+        batch_size: guc::get_int(&state.gucstate, guc::BatchSize).max(1) as usize,
+        ret,
+    })
+}
+
+// ExecInitLimit
+fn exec_init_limit<'opt, 'exe>(
+    node: &'opt optimizer::Limit,
+    state: &'exe WorkerState,
+    sess: &mut SessionState,
+) -> anyhow::Result<LimitState> {
+    Ok(LimitState {
+        lefttree: Box::new(exec_init_plan(&node.lefttree, state, sess)?),
+        count: node.count,
+        returned: 0,
+        ret: Vec::with_capacity(node.plan.tlist.len()),
+    })
+}
+
 fn exec_init_plan<'opt, 'exe>(
     node: &'opt optimizer::Plan,
     state: &'exe WorkerState,
+    sess: &mut SessionState,
 ) -> anyhow::Result<PlanState> {
     match node {
         optimizer::Plan::Result(r) => exec_init_result(r, state).map(|v| PlanState::Result(v)),
+        optimizer::Plan::SeqScan(s) => exec_init_seqscan(s, state, sess),
+        optimizer::Plan::Agg(a) => exec_init_agg(a, state, sess).map(|v| PlanState::Agg(v)),
+        optimizer::Plan::Sort(s) => exec_init_sort(s, state, sess).map(|v| PlanState::Sort(v)),
+        optimizer::Plan::Limit(l) => exec_init_limit(l, state, sess).map(|v| PlanState::Limit(v)),
     }
 }
 
+// ExecProcessReturning, the returning list is evaluated over the columns of the rows inserted,
+// which are placed in the order of the attributes of the relation.
+pub fn exec_returning(
+    tlist: &Vec<sem::TargetEntry>,
+    tuples: &[Rc<Datums>],
+    rownum: u32,
+    session: &SessionState,
+    worker: &WorkerState,
+    dest: &mut dyn DestReceiver,
+) -> anyhow::Result<()> {
+    let mut initctx = ExprInitCtx::new();
+    let mut proj_info = ProjectionInfo::try_new(tlist, worker, &mut initctx)?;
+    let mut results = Vec::with_capacity(initctx.nextid);
+    results.resize_with(initctx.nextid, Default::default);
+    let mut ectx = ExprContext::new(&mut results, tuples);
+    proj_info.eval(&mut ectx, worker)?;
+    let ret: Vec<_> = proj_info
+        .pi_state
+        .iter()
+        .map(|expr| Datums::clonerc(&results[expr.es().residx]))
+        .collect();
+    dest.startup(tlist, session)?;
+    return dest.receive(&ret, rownum, worker);
+}
+
+// Returns the number of parallel workers used by the query.
 pub fn exec_select(
     stmt: &PlannedStmt,
-    session: &SessionState,
+    session: &mut SessionState,
     dest: &mut impl DestReceiver,
-) -> anyhow::Result<()> {
+) -> anyhow::Result<usize> {
     let state = WorkerState::new(session);
-    let mut planstate = exec_init_plan(&stmt.plan_tree, &state)?;
+    let mut planstate = exec_init_plan(&stmt.plan_tree, &state, session)?;
     dest.startup(stmt.plan_tree.tlist(), session)?;
     loop {
         let (rows, rownumber) = planstate.exec(&state)?;
@@ -339,5 +1285,77 @@ pub fn exec_select(
             Some(tuples) => dest.receive(tuples, rownumber, &state)?,
         }
     }
-    Ok(())
+    return planstate.shutdown(session);
+}
+
+#[cfg(test)]
+mod executor_test {
+    use super::{and_selection, bool_at, eval_bool_expr};
+    use crate::datums::Datums;
+    use crate::parser::syn::BoolExprType;
+    use crate::utils::adt::typcmp;
+    use std::mem::{align_of, size_of};
+    use std::rc::Rc;
+
+    fn int4col(vals: &[Option<i32>]) -> Datums {
+        let mut col = Datums::new();
+        col.resize_fixedlen(vals.len() as u32, size_of::<i32>(), align_of::<i32>());
+        for (idx, v) in vals.iter().enumerate() {
+            match v {
+                None => col.set_null_at(idx as isize),
+                &Some(v) => col.set_fixedlen_at(idx as isize, v),
+            }
+        }
+        col
+    }
+
+    #[test]
+    fn qual() {
+        // col > 5 AND col < 10
+        let col = int4col(&[Some(1), Some(6), None, Some(9), Some(10), Some(7), None]);
+        let mut gt = Datums::new();
+        typcmp::<i32>(&mut gt, &col, &Datums::new_single_fixedlen(5), i32::gt);
+        let mut lt = Datums::new();
+        typcmp::<i32>(&mut lt, &col, &Datums::new_single_fixedlen(10), i32::lt);
+        let mut and = Datums::new();
+        eval_bool_expr(BoolExprType::And, &[Rc::new(gt), Rc::new(lt)], &mut and);
+        let mut sel = vec![true; col.len() as usize];
+        and_selection(&mut sel, &and);
+        assert_eq!(sel, [false, true, false, true, false, true, false]);
+
+        // NOT (col > 5) OR col = null, null is not selected.
+        let mut gt = Datums::new();
+        typcmp::<i32>(&mut gt, &col, &Datums::new_single_fixedlen(5), i32::gt);
+        let mut not = Datums::new();
+        eval_bool_expr(BoolExprType::Not, &[Rc::new(gt)], &mut not);
+        let mut eq = Datums::new();
+        typcmp::<i32>(&mut eq, &col, &Datums::new_single_null(), i32::eq);
+        assert!(eq.is_null_at(0) && eq.is_null_at(1));
+        let mut or = Datums::new();
+        eval_bool_expr(BoolExprType::Or, &[Rc::new(not), Rc::new(eq)], &mut or);
+        assert_eq!(bool_at(&or, 0), Some(true));
+        assert_eq!(bool_at(&or, 1), None);
+        assert_eq!(bool_at(&or, 2), None);
+        let mut sel = vec![true; col.len() as usize];
+        and_selection(&mut sel, &or);
+        assert_eq!(sel, [true, false, false, false, false, false, false]);
+
+        // false AND null is false, true AND null is null.
+        let args = [
+            Rc::new(Datums::new_single_fixedlen(false)),
+            Rc::new(Datums::new_single_null()),
+        ];
+        let mut ret = Datums::new();
+        eval_bool_expr(BoolExprType::And, &args, &mut ret);
+        assert_eq!(bool_at(&ret, 0), Some(false));
+        let args = [
+            Rc::new(Datums::new_single_fixedlen(true)),
+            Rc::new(Datums::new_single_null()),
+        ];
+        eval_bool_expr(BoolExprType::And, &args, &mut ret);
+        assert_eq!(bool_at(&ret, 0), None);
+        let mut sel = vec![true; 3];
+        and_selection(&mut sel, &ret);
+        assert_eq!(sel, [false, false, false]);
+    }
 }
@@ -0,0 +1,547 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::datums::Datums;
+use crate::{kbanyhow, kbbail, Oid};
+use std::borrow::Borrow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::Hasher;
+use std::mem::{align_of, size_of};
+use std::rc::Rc;
+
+// The transition state of the aggregate, the input is reduced batch by batch.
+#[derive(Debug, Clone)]
+pub enum AggTrans {
+    // count(*)
+    CountStar(i64),
+    // count(any), the nulls are skipped.
+    Count(i64),
+    // sum(int4), count is used to tell whether all inputs are null.
+    SumInt4 { sum: i64, count: i64 },
+    // avg(int4)
+    AvgInt4 { sum: i64, count: i64 },
+    MaxInt4(Option<i32>),
+    MinInt4(Option<i32>),
+}
+
+// Call f for each non-null int4 value in arg, and the number of times it appears.
+fn foreach_int4(
+    arg: &Datums,
+    rownum: u32,
+    mut f: impl FnMut(i32, i64) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    if arg.is_single() {
+        if !arg.is_single_null() {
+            f(arg.get_single_fixedlen(), rownum as i64)?;
+        }
+        return Ok(());
+    }
+    debug_assert_eq!(arg.len(), rownum);
+    for idx in 0..rownum as isize {
+        if !arg.is_null_at(idx) {
+            f(arg.get_fixedlen_at(idx), 1)?;
+        }
+    }
+    return Ok(());
+}
+
+fn int4_at(arg: &Datums, idx: isize) -> Option<i32> {
+    if arg.is_single() {
+        if arg.is_single_null() {
+            None
+        } else {
+            Some(arg.get_single_fixedlen())
+        }
+    } else if arg.is_null_at(idx) {
+        None
+    } else {
+        Some(arg.get_fixedlen_at(idx))
+    }
+}
+
+// int4_sum, int4_avg_accum, v appears n times.
+fn accum_int4(sum: &mut i64, count: &mut i64, v: i32, n: i64) -> anyhow::Result<()> {
+    *sum = (v as i64)
+        .checked_mul(n)
+        .and_then(|v| sum.checked_add(v))
+        .ok_or_else(|| kbanyhow!(ERRCODE_NUMERIC_VALUE_OUT_OF_RANGE, "bigint out of range"))?;
+    *count += n;
+    return Ok(());
+}
+
+impl AggTrans {
+    pub fn new(aggfnoid: Oid) -> anyhow::Result<AggTrans> {
+        let trans = match aggfnoid.get() {
+            2803 => AggTrans::CountStar(0),
+            2147 => AggTrans::Count(0),
+            2108 => AggTrans::SumInt4 { sum: 0, count: 0 },
+            2101 => AggTrans::AvgInt4 { sum: 0, count: 0 },
+            2116 => AggTrans::MaxInt4(None),
+            2132 => AggTrans::MinInt4(None),
+            _ => kbbail!(
+                ERRCODE_UNDEFINED_FUNCTION,
+                "aggregate {} is not in internal lookup table",
+                aggfnoid
+            ),
+        };
+        return Ok(trans);
+    }
+
+    // advance_aggregates, arg is None for count(*).
+    pub fn advance(&mut self, arg: Option<&Datums>, rownum: u32) -> anyhow::Result<()> {
+        match self {
+            AggTrans::CountStar(count) => {
+                *count += rownum as i64;
+            }
+            AggTrans::Count(count) => {
+                let arg = arg.unwrap();
+                let nonnull = if arg.is_single() {
+                    if arg.is_single_null() {
+                        0
+                    } else {
+                        rownum
+                    }
+                } else {
+                    (0..rownum as isize)
+                        .filter(|&idx| !arg.is_null_at(idx))
+                        .count() as u32
+                };
+                *count += nonnull as i64;
+            }
+            AggTrans::SumInt4 { sum, count } | AggTrans::AvgInt4 { sum, count } => {
+                foreach_int4(arg.unwrap(), rownum, |v, n| accum_int4(sum, count, v, n))?;
+            }
+            AggTrans::MaxInt4(max) => {
+                foreach_int4(arg.unwrap(), rownum, |v, _| {
+                    *max = Some(max.map_or(v, |m| m.max(v)));
+                    Ok(())
+                })?;
+            }
+            AggTrans::MinInt4(min) => {
+                foreach_int4(arg.unwrap(), rownum, |v, _| {
+                    *min = Some(min.map_or(v, |m| m.min(v)));
+                    Ok(())
+                })?;
+            }
+        }
+        return Ok(());
+    }
+
+    // Same as advance(), but only the row at idx is accumulated.
+    pub fn advance_at(&mut self, arg: Option<&Datums>, idx: isize) -> anyhow::Result<()> {
+        match self {
+            AggTrans::CountStar(count) => {
+                *count += 1;
+            }
+            AggTrans::Count(count) => {
+                let arg = arg.unwrap();
+                let isnull = if arg.is_single() {
+                    arg.is_single_null()
+                } else {
+                    arg.is_null_at(idx)
+                };
+                if !isnull {
+                    *count += 1;
+                }
+            }
+            AggTrans::SumInt4 { sum, count } | AggTrans::AvgInt4 { sum, count } => {
+                if let Some(v) = int4_at(arg.unwrap(), idx) {
+                    accum_int4(sum, count, v, 1)?;
+                }
+            }
+            AggTrans::MaxInt4(max) => {
+                if let Some(v) = int4_at(arg.unwrap(), idx) {
+                    *max = Some(max.map_or(v, |m| m.max(v)));
+                }
+            }
+            AggTrans::MinInt4(min) => {
+                if let Some(v) = int4_at(arg.unwrap(), idx) {
+                    *min = Some(min.map_or(v, |m| m.min(v)));
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    // combine_aggregates, merge the transition state computed by another parallel worker.
+    pub fn combine(&mut self, other: &AggTrans) -> anyhow::Result<()> {
+        match (self, other) {
+            (AggTrans::CountStar(count), AggTrans::CountStar(other))
+            | (AggTrans::Count(count), AggTrans::Count(other)) => {
+                *count += other;
+            }
+            (
+                AggTrans::SumInt4 { sum, count },
+                &AggTrans::SumInt4 {
+                    sum: othersum,
+                    count: othercount,
+                },
+            )
+            | (
+                AggTrans::AvgInt4 { sum, count },
+                &AggTrans::AvgInt4 {
+                    sum: othersum,
+                    count: othercount,
+                },
+            ) => {
+                *sum = sum.checked_add(othersum).ok_or_else(|| {
+                    kbanyhow!(ERRCODE_NUMERIC_VALUE_OUT_OF_RANGE, "bigint out of range")
+                })?;
+                *count += othercount;
+            }
+            (AggTrans::MaxInt4(max), &AggTrans::MaxInt4(Some(v))) => {
+                *max = Some(max.map_or(v, |m| m.max(v)));
+            }
+            (AggTrans::MinInt4(min), &AggTrans::MinInt4(Some(v))) => {
+                *min = Some(min.map_or(v, |m| m.min(v)));
+            }
+            (AggTrans::MaxInt4(_), AggTrans::MaxInt4(None))
+            | (AggTrans::MinInt4(_), AggTrans::MinInt4(None)) => {}
+            (trans, other) => unreachable!("combine: {:?} with {:?}", trans, other),
+        }
+        return Ok(());
+    }
+
+    // finalize_aggregate, the aggregates except count return null if there is no non-null input.
+    pub fn finalize(&self, out: &mut Datums) {
+        match self {
+            &AggTrans::CountStar(count) | &AggTrans::Count(count) => {
+                out.set_single_fixedlen(count);
+            }
+            &AggTrans::SumInt4 { sum, count } => {
+                if count == 0 {
+                    out.set_single_null();
+                } else {
+                    out.set_single_fixedlen(sum);
+                }
+            }
+            &AggTrans::AvgInt4 { sum, count } => {
+                if count == 0 {
+                    out.set_single_null();
+                } else {
+                    out.set_single_fixedlen(sum as f64 / count as f64);
+                }
+            }
+            &AggTrans::MaxInt4(v) | &AggTrans::MinInt4(v) => match v {
+                None => out.set_single_null(),
+                Some(v) => out.set_single_fixedlen(v),
+            },
+        }
+    }
+
+    // Resize out to hold rownum results of this aggregate, see finalize_at().
+    pub fn init_output(&self, out: &mut Datums, rownum: u32) {
+        match self {
+            AggTrans::CountStar(_) | AggTrans::Count(_) | AggTrans::SumInt4 { .. } => {
+                out.resize_fixedlen(rownum, size_of::<i64>(), align_of::<i64>())
+            }
+            AggTrans::AvgInt4 { .. } => {
+                out.resize_fixedlen(rownum, size_of::<f64>(), align_of::<f64>())
+            }
+            AggTrans::MaxInt4(_) | AggTrans::MinInt4(_) => {
+                out.resize_fixedlen(rownum, size_of::<i32>(), align_of::<i32>())
+            }
+        }
+        out.set_notnull_all();
+    }
+
+    // Same as finalize(), but the result is stored at idx of out.
+    pub fn finalize_at(&self, out: &mut Datums, idx: isize) {
+        match self {
+            &AggTrans::CountStar(count) | &AggTrans::Count(count) => {
+                out.set_fixedlen_at(idx, count);
+            }
+            &AggTrans::SumInt4 { sum, count } => {
+                if count == 0 {
+                    out.set_null_at(idx);
+                } else {
+                    out.set_fixedlen_at(idx, sum);
+                }
+            }
+            &AggTrans::AvgInt4 { sum, count } => {
+                if count == 0 {
+                    out.set_null_at(idx);
+                } else {
+                    out.set_fixedlen_at(idx, sum as f64 / count as f64);
+                }
+            }
+            &AggTrans::MaxInt4(v) | &AggTrans::MinInt4(v) => match v {
+                None => out.set_null_at(idx),
+                Some(v) => out.set_fixedlen_at(idx, v),
+            },
+        }
+    }
+}
+
+// The hash table of HashAggregate, keyed by the grouping values.
+pub struct AggHashTable {
+    keytyplens: Vec<i16>,
+    ngroups: usize,
+    // hash of the grouping values -> the groups with that hash.
+    buckets: HashMap<u64, Vec<usize>>,
+    // The grouping values of the group i are keys[i * keytyplens.len()..], all are single.
+    keys: Vec<Datums>,
+    // The transition states of the group i are trans[i * inittrans.len()..].
+    trans: Vec<AggTrans>,
+    inittrans: Vec<AggTrans>,
+    // The memory used by the hash table, it should not exceed work_mem.
+    memused: usize,
+    work_mem: usize,
+}
+
+impl AggHashTable {
+    pub fn new(keytyplens: Vec<i16>, inittrans: Vec<AggTrans>, work_mem: usize) -> Self {
+        Self {
+            keytyplens,
+            ngroups: 0,
+            buckets: HashMap::new(),
+            keys: Vec::new(),
+            trans: Vec::new(),
+            inittrans,
+            memused: 0,
+            work_mem,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.ngroups
+    }
+
+    fn key_eq<D: Borrow<Datums>>(&self, group: usize, keycols: &[D], idx: isize) -> bool {
+        let keys = &self.keys[group * self.keytyplens.len()..];
+        keycols
+            .iter()
+            .zip(keys)
+            .zip(&self.keytyplens)
+            .all(|((col, key), &typlen)| col.borrow().eq_at(idx, key, 0, typlen))
+    }
+
+    fn new_group<D: Borrow<Datums>>(&mut self, keycols: &[D], idx: isize) -> anyhow::Result<usize> {
+        let group = self.ngroups;
+        self.ngroups += 1;
+        let mut memused = size_of::<usize>() * 2;
+        for (col, &typlen) in keycols.iter().zip(&self.keytyplens) {
+            let mut key = Datums::new();
+            key.set_single_from(col.borrow(), idx, typlen);
+            memused += size_of::<Datums>();
+            if typlen < 0 && !key.is_single_null() {
+                memused += key.get_single_varchar().len();
+            }
+            self.keys.push(key);
+        }
+        self.trans.extend_from_slice(&self.inittrans);
+        memused += size_of::<AggTrans>() * self.inittrans.len();
+        self.memused += memused;
+        if self.memused > self.work_mem {
+            kbbail!(
+                ERRCODE_OUT_OF_MEMORY,
+                "hash table of HashAggregate exceeds work_mem. groups={} work_mem={}kB",
+                group + 1,
+                self.work_mem / 1024
+            );
+        }
+        return Ok(group);
+    }
+
+    // lookup_hash_entries, find or create the group of each row, keycols are the grouping values.
+    pub fn lookup(
+        &mut self,
+        keycols: &[Rc<Datums>],
+        rownum: u32,
+        groups: &mut Vec<usize>,
+    ) -> anyhow::Result<()> {
+        groups.clear();
+        for idx in 0..rownum as isize {
+            let group = self.lookup_at(keycols, idx)?;
+            groups.push(group);
+        }
+        return Ok(());
+    }
+
+    // Find or create the group of the row at idx.
+    fn lookup_at<D: Borrow<Datums>>(&mut self, keycols: &[D], idx: isize) -> anyhow::Result<usize> {
+        let mut hasher = DefaultHasher::new();
+        for (col, &typlen) in keycols.iter().zip(&self.keytyplens) {
+            col.borrow().hash_at(idx, typlen, &mut hasher);
+        }
+        let hash = hasher.finish();
+        let found = match self.buckets.get(&hash) {
+            None => None,
+            Some(bucket) => bucket
+                .iter()
+                .copied()
+                .find(|&group| self.key_eq(group, keycols, idx)),
+        };
+        if let Some(group) = found {
+            return Ok(group);
+        }
+        let group = self.new_group(keycols, idx)?;
+        self.buckets.entry(hash).or_default().push(group);
+        return Ok(group);
+    }
+
+    // Merge the groups of the hash table built by another parallel worker.
+    pub fn combine(&mut self, other: &AggHashTable) -> anyhow::Result<()> {
+        let keynum = self.keytyplens.len();
+        let aggnum = self.inittrans.len();
+        for othergroup in 0..other.ngroups {
+            let keys = &other.keys[othergroup * keynum..(othergroup + 1) * keynum];
+            let group = self.lookup_at(keys, 0)?;
+            for aggno in 0..aggnum {
+                let othertrans = other.trans(othergroup, aggno);
+                self.trans_mut(group, aggno).combine(othertrans)?;
+            }
+        }
+        return Ok(());
+    }
+
+    pub fn trans_mut(&mut self, group: usize, aggno: usize) -> &mut AggTrans {
+        &mut self.trans[group * self.inittrans.len() + aggno]
+    }
+
+    pub fn trans(&self, group: usize, aggno: usize) -> &AggTrans {
+        &self.trans[group * self.inittrans.len() + aggno]
+    }
+
+    // The grouping value of the keyno-th grouping column of the group, it is single.
+    pub fn key(&self, group: usize, keyno: usize) -> &Datums {
+        &self.keys[group * self.keytyplens.len() + keyno]
+    }
+}
+
+#[cfg(test)]
+mod agg_test {
+    use super::{AggHashTable, AggTrans};
+    use crate::datums::Datums;
+    use crate::Oid;
+    use std::mem::{align_of, size_of};
+    use std::rc::Rc;
+
+    fn int4col(vals: &[Option<i32>]) -> Datums {
+        let mut col = Datums::new();
+        col.resize_fixedlen(vals.len() as u32, size_of::<i32>(), align_of::<i32>());
+        for (idx, v) in vals.iter().enumerate() {
+            match v {
+                None => col.set_null_at(idx as isize),
+                &Some(v) => col.set_fixedlen_at(idx as isize, v),
+            }
+        }
+        col
+    }
+
+    fn agg(aggfnoid: u32, batches: &[Datums]) -> Datums {
+        let mut trans = AggTrans::new(Oid::new(aggfnoid).unwrap()).unwrap();
+        for batch in batches {
+            let rownum = if batch.is_single() { 3 } else { batch.len() };
+            trans.advance(Some(batch), rownum).unwrap();
+        }
+        let mut out = Datums::new();
+        trans.finalize(&mut out);
+        out
+    }
+
+    #[test]
+    fn aggs() {
+        let batches = [
+            int4col(&[Some(3), None, Some(-7)]),
+            int4col(&[None, None]),
+            int4col(&[Some(10)]),
+            Datums::new_single_fixedlen(2i32),
+            Datums::new_single_null(),
+        ];
+        // count(*) is advanced without argument.
+        let mut trans = AggTrans::new(Oid::new(2803).unwrap()).unwrap();
+        trans.advance(None, 3).unwrap();
+        trans.advance(None, 2).unwrap();
+        let mut out = Datums::new();
+        trans.finalize(&mut out);
+        assert_eq!(out.get_single_fixedlen::<i64>(), 5);
+
+        assert_eq!(agg(2147, &batches).get_single_fixedlen::<i64>(), 6);
+        assert_eq!(agg(2108, &batches).get_single_fixedlen::<i64>(), 12);
+        assert_eq!(agg(2101, &batches).get_single_fixedlen::<f64>(), 2.0);
+        assert_eq!(agg(2116, &batches).get_single_fixedlen::<i32>(), 10);
+        assert_eq!(agg(2132, &batches).get_single_fixedlen::<i32>(), -7);
+    }
+
+    #[test]
+    fn empty() {
+        let mut out = Datums::new();
+        AggTrans::new(Oid::new(2803).unwrap())
+            .unwrap()
+            .finalize(&mut out);
+        assert_eq!(out.get_single_fixedlen::<i64>(), 0);
+        assert_eq!(agg(2147, &[]).get_single_fixedlen::<i64>(), 0);
+        for aggfnoid in [2108, 2101, 2116, 2132] {
+            assert!(agg(aggfnoid, &[]).is_single_null());
+        }
+        // All inputs are null.
+        let batches = [int4col(&[None, None]), Datums::new_single_null()];
+        assert_eq!(agg(2147, &batches).get_single_fixedlen::<i64>(), 0);
+        for aggfnoid in [2108, 2101, 2116, 2132] {
+            assert!(agg(aggfnoid, &batches).is_single_null());
+        }
+    }
+
+    #[test]
+    fn hash_group() {
+        let sum = AggTrans::new(Oid::new(2108).unwrap()).unwrap();
+        let mut table = AggHashTable::new(vec![4], vec![sum], 1024 * 1024);
+        let mut groups = Vec::new();
+        let batches = [
+            (
+                int4col(&[Some(1), None, Some(2), Some(1), None]),
+                int4col(&[Some(10), Some(20), Some(30), Some(40), None]),
+            ),
+            (
+                int4col(&[None, Some(2), Some(3)]),
+                int4col(&[Some(5), Some(6), Some(7)]),
+            ),
+        ];
+        for (keycol, valcol) in batches.iter() {
+            let keycols = [Rc::new(keycol.clone())];
+            table.lookup(&keycols, keycol.len(), &mut groups).unwrap();
+            for (idx, &group) in groups.iter().enumerate() {
+                let trans = table.trans_mut(group, 0);
+                trans.advance_at(Some(valcol), idx as isize).unwrap();
+            }
+        }
+        // The nulls are in the same group.
+        assert_eq!(groups, [1, 2, 3]);
+        assert_eq!(table.len(), 4);
+        let mut sums = Datums::new();
+        let mut keys = Datums::new();
+        table.trans(0, 0).init_output(&mut sums, 4);
+        keys.resize_fixedlen(4, size_of::<i32>(), align_of::<i32>());
+        for group in 0..4 {
+            table.trans(group, 0).finalize_at(&mut sums, group as isize);
+            keys.set_at_from(group as isize, table.key(group, 0), 0, 4);
+        }
+        assert_eq!(keys.get_fixedlen_at::<i32>(0), 1);
+        assert!(keys.is_null_at(1));
+        assert_eq!(keys.get_fixedlen_at::<i32>(2), 2);
+        assert_eq!(keys.get_fixedlen_at::<i32>(3), 3);
+        assert_eq!(sums.get_fixedlen_at::<i64>(0), 50);
+        assert_eq!(sums.get_fixedlen_at::<i64>(1), 25);
+        assert_eq!(sums.get_fixedlen_at::<i64>(2), 36);
+        assert_eq!(sums.get_fixedlen_at::<i64>(3), 7);
+    }
+
+    #[test]
+    fn exceed_work_mem() {
+        let count = AggTrans::new(Oid::new(2803).unwrap()).unwrap();
+        let mut table = AggHashTable::new(vec![4], vec![count], 1024);
+        let keys: Vec<Option<i32>> = (0..1024).map(Some).collect();
+        let keycols = [Rc::new(int4col(&keys))];
+        let mut groups = Vec::new();
+        assert!(table.lookup(&keycols, 1024, &mut groups).is_err());
+    }
+}
@@ -0,0 +1,347 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::access::TypeDesc;
+use crate::datums::Datums;
+use crate::parser::sem::SortClause;
+use crate::utils::adt::{get_sort_cmp, SortCmp};
+use crate::{kbanyhow, kbensure};
+use std::cmp::Ordering;
+use std::rc::Rc;
+
+// SortSupportData
+struct SortKey {
+    colidx: usize,
+    cmp: SortCmp,
+    descending: bool,
+    nulls_first: bool,
+}
+
+// A row copied from the input, all columns are single.
+// seq is the position of the row in the input, it is used to break the ties,
+// so the output is always the same as a stable sort.
+struct SortTuple {
+    cols: Vec<Datums>,
+    seq: u64,
+}
+
+fn is_null_at(d: &Datums, idx: isize) -> bool {
+    if d.is_single() {
+        d.is_single_null()
+    } else {
+        d.is_null_at(idx)
+    }
+}
+
+// ApplySortComparator
+fn compare_key(key: &SortKey, a: &Datums, aidx: isize, b: &Datums, bidx: isize) -> Ordering {
+    let ret = match (is_null_at(a, aidx), is_null_at(b, bidx)) {
+        (true, true) => return Ordering::Equal,
+        (true, false) if key.nulls_first => return Ordering::Less,
+        (true, false) => return Ordering::Greater,
+        (false, true) if key.nulls_first => return Ordering::Greater,
+        (false, true) => return Ordering::Less,
+        (false, false) => (key.cmp)(a, aidx, b, bidx),
+    };
+    if key.descending {
+        ret.reverse()
+    } else {
+        ret
+    }
+}
+
+// comparetup_heap
+fn compare_tuple(keys: &[SortKey], a: &SortTuple, b: &SortTuple) -> Ordering {
+    for key in keys {
+        let ret = compare_key(key, &a.cols[key.colidx], 0, &b.cols[key.colidx], 0);
+        if ret != Ordering::Equal {
+            return ret;
+        }
+    }
+    a.seq.cmp(&b.seq)
+}
+
+// Compare the row at idx of cols with the tuple b.
+fn compare_row(
+    keys: &[SortKey],
+    cols: &[Rc<Datums>],
+    idx: isize,
+    seq: u64,
+    b: &SortTuple,
+) -> Ordering {
+    for key in keys {
+        let ret = compare_key(key, &cols[key.colidx], idx, &b.cols[key.colidx], 0);
+        if ret != Ordering::Equal {
+            return ret;
+        }
+    }
+    seq.cmp(&b.seq)
+}
+
+// Tuplesortstate, the rows are kept in memory.
+pub struct Tuplesort {
+    keys: Vec<SortKey>,
+    coltyps: Vec<TypeDesc>,
+    // Only the first bound rows are needed, see tuplesort_set_bound().
+    bound: Option<usize>,
+    // If bound is set and tuples is full, tuples is a max-heap whose root is the greatest tuple.
+    tuples: Vec<SortTuple>,
+    nextseq: u64,
+}
+
+impl Tuplesort {
+    // tuplesort_begin_heap, coltyps are the types of the input columns.
+    pub fn new(
+        sort_clause: &[SortClause],
+        coltyps: Vec<TypeDesc>,
+        bound: Option<u64>,
+    ) -> anyhow::Result<Tuplesort> {
+        for typ in &coltyps {
+            kbensure!(
+                matches!(typ.len, -1 | 1 | 2 | 4 | 8),
+                ERRCODE_FEATURE_NOT_SUPPORTED,
+                "sort on type {} is not supported",
+                typ.id
+            );
+        }
+        let mut keys = Vec::with_capacity(sort_clause.len());
+        for sortcl in sort_clause {
+            let cmp = get_sort_cmp(sortcl.sortcoltype).ok_or_else(|| {
+                kbanyhow!(
+                    ERRCODE_UNDEFINED_FUNCTION,
+                    "could not identify an ordering operator for type {}",
+                    sortcl.sortcoltype
+                )
+            })?;
+            keys.push(SortKey {
+                colidx: sortcl.tleidx,
+                cmp,
+                descending: sortcl.descending,
+                nulls_first: sortcl.nulls_first,
+            });
+        }
+        Ok(Tuplesort {
+            keys,
+            coltyps,
+            bound: bound.map(|v| v as usize),
+            tuples: Vec::new(),
+            nextseq: 0,
+        })
+    }
+
+    fn copy_tuple(&self, cols: &[Rc<Datums>], idx: isize, seq: u64) -> SortTuple {
+        let cols = cols
+            .iter()
+            .zip(&self.coltyps)
+            .map(|(col, typ)| {
+                let mut d = Datums::new();
+                d.set_single_from(col, idx, typ.len);
+                d
+            })
+            .collect();
+        SortTuple { cols, seq }
+    }
+
+    fn sift_down(&mut self, mut parent: usize) {
+        let n = self.tuples.len();
+        loop {
+            let left = parent * 2 + 1;
+            if left >= n {
+                return;
+            }
+            let mut child = left;
+            let right = left + 1;
+            if right < n
+                && compare_tuple(&self.keys, &self.tuples[right], &self.tuples[left])
+                    == Ordering::Greater
+            {
+                child = right;
+            }
+            if compare_tuple(&self.keys, &self.tuples[child], &self.tuples[parent])
+                != Ordering::Greater
+            {
+                return;
+            }
+            self.tuples.swap(parent, child);
+            parent = child;
+        }
+    }
+
+    // make_bounded_heap
+    fn make_bounded_heap(&mut self) {
+        for idx in (0..self.tuples.len() / 2).rev() {
+            self.sift_down(idx);
+        }
+    }
+
+    // tuplesort_puttupleslot
+    pub fn put_batch(&mut self, cols: &[Rc<Datums>], rownum: u32) {
+        for idx in 0..rownum as isize {
+            let seq = self.nextseq;
+            self.nextseq += 1;
+            match self.bound {
+                Some(bound) if self.tuples.len() >= bound => {
+                    // The row is discarded if it is not less than the greatest tuple in the heap,
+                    // so the rows arriving earlier win the ties at the boundary.
+                    if bound == 0
+                        || compare_row(&self.keys, cols, idx, seq, &self.tuples[0])
+                            != Ordering::Less
+                    {
+                        continue;
+                    }
+                    self.tuples[0] = self.copy_tuple(cols, idx, seq);
+                    self.sift_down(0);
+                }
+                bound => {
+                    let tuple = self.copy_tuple(cols, idx, seq);
+                    self.tuples.push(tuple);
+                    if bound == Some(self.tuples.len()) {
+                        self.make_bounded_heap();
+                    }
+                }
+            }
+        }
+    }
+
+    // tuplesort_performsort
+    pub fn perform_sort(&mut self) {
+        let keys = &self.keys;
+        self.tuples
+            .sort_unstable_by(|a, b| compare_tuple(keys, a, b));
+    }
+
+    pub fn len(&self) -> usize {
+        self.tuples.len()
+    }
+
+    // Store the columns of the sorted tuples in [start, end) to out, only the first
+    // out.len() columns are stored.
+    pub fn get_batch(&self, start: usize, end: usize, out: &mut [Rc<Datums>]) {
+        let rownum = (end - start) as u32;
+        for (colidx, (col, typ)) in out.iter_mut().zip(&self.coltyps).enumerate() {
+            let col = Rc::make_mut(col);
+            if typ.len < 0 {
+                col.resize_varlen(rownum);
+            } else {
+                col.resize_fixedlen(rownum, typ.len as usize, typ.align as usize);
+            }
+            col.set_notnull_all();
+            for (idx, tuple) in self.tuples[start..end].iter().enumerate() {
+                col.set_at_from(idx as isize, &tuple.cols[colidx], 0, typ.len);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod sort_test {
+    use super::Tuplesort;
+    use crate::access::TypeDesc;
+    use crate::datums::Datums;
+    use crate::parser::sem::SortClause;
+    use crate::INT4OID;
+    use rand::Rng;
+    use std::cmp::Ordering;
+    use std::mem::{align_of, size_of};
+    use std::rc::Rc;
+
+    type Row = (Option<i32>, i32);
+
+    fn int4col(vals: impl Iterator<Item = Option<i32>>) -> Datums {
+        let vals: Vec<_> = vals.collect();
+        let mut col = Datums::new();
+        col.resize_fixedlen(vals.len() as u32, size_of::<i32>(), align_of::<i32>());
+        for (idx, v) in vals.iter().enumerate() {
+            match v {
+                None => col.set_null_at(idx as isize),
+                &Some(v) => col.set_fixedlen_at(idx as isize, v),
+            }
+        }
+        col
+    }
+
+    fn int4typ() -> TypeDesc {
+        TypeDesc {
+            id: INT4OID,
+            len: size_of::<i32>() as i16,
+            align: align_of::<i32>() as u8,
+            mode: -1,
+        }
+    }
+
+    // Sort rows by the first column, the second column is the position of the row.
+    fn sort(rows: &[Row], sortcl: &SortClause, bound: Option<u64>, batch: usize) -> Vec<Row> {
+        let mut sort =
+            Tuplesort::new(&[sortcl.clone()], vec![int4typ(), int4typ()], bound).unwrap();
+        for chunk in rows.chunks(batch) {
+            let cols = [
+                Rc::new(int4col(chunk.iter().map(|v| v.0))),
+                Rc::new(int4col(chunk.iter().map(|v| Some(v.1)))),
+            ];
+            sort.put_batch(&cols, chunk.len() as u32);
+        }
+        sort.perform_sort();
+        let mut out = [Rc::new(Datums::new()), Rc::new(Datums::new())];
+        sort.get_batch(0, sort.len(), &mut out);
+        (0..sort.len() as isize)
+            .map(|idx| {
+                let key = if out[0].is_null_at(idx) {
+                    None
+                } else {
+                    Some(out[0].get_fixedlen_at(idx))
+                };
+                (key, out[1].get_fixedlen_at(idx))
+            })
+            .collect()
+    }
+
+    fn expected(rows: &[Row], sortcl: &SortClause) -> Vec<Row> {
+        let mut rows = rows.to_vec();
+        rows.sort_by(|a, b| match (a.0, b.0) {
+            (None, None) => Ordering::Equal,
+            (None, Some(_)) if sortcl.nulls_first => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(_), None) if sortcl.nulls_first => Ordering::Greater,
+            (Some(_), None) => Ordering::Less,
+            (Some(a), Some(b)) if sortcl.descending => b.cmp(&a),
+            (Some(a), Some(b)) => a.cmp(&b),
+        });
+        rows
+    }
+
+    #[test]
+    fn top_n() {
+        let mut rng = rand::thread_rng();
+        for &(descending, nulls_first) in &[(false, false), (true, true), (false, true)] {
+            let sortcl = SortClause {
+                tleidx: 0,
+                sortcoltype: INT4OID,
+                descending,
+                nulls_first,
+            };
+            let rows: Vec<Row> = (0..1000)
+                .map(|idx| {
+                    let key = rng.gen_range(0, 50);
+                    (if key == 0 { None } else { Some(key) }, idx)
+                })
+                .collect();
+            let full = expected(&rows, &sortcl);
+            assert_eq!(sort(&rows, &sortcl, None, 64), full);
+            for &n in &[0usize, 1, 7, 100, 999, 1000, 5000] {
+                let topn = sort(&rows, &sortcl, Some(n as u64), 64);
+                assert_eq!(topn, &full[..n.min(full.len())], "n={}", n);
+            }
+            // The input is smaller than n.
+            let small = &rows[..10];
+            let topn = sort(small, &sortcl, Some(20), 3);
+            assert_eq!(topn, expected(small, &sortcl));
+        }
+    }
+}
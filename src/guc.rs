@@ -11,7 +11,11 @@ See the License for the specific language governing permissions and
 limitations under the License.
 */
 mod gucdef;
+use crate::access::walarchive::Compression;
 use crate::common;
+use crate::utils::crashpoint;
+use crate::utils::encoding::Encoding;
+use crate::utils::logger;
 pub use gucdef::B::*;
 pub use gucdef::I::*;
 pub use gucdef::R::*;
@@ -41,6 +45,55 @@ pub struct GucState {
     pub loglvl: log::LevelFilter,
 
     pub base_search_path_valid: bool,
+
+    // bytea_output
+    pub bytea_output: ByteaOutput,
+
+    // client_encoding
+    pub client_encoding: Encoding,
+
+    // client_min_messages
+    pub client_minlvl: NoticeLevel,
+
+    // wal_sync_method
+    pub wal_sync_method: SyncMethod,
+}
+
+// The levels of the message sent to the client by NoticeResponse, ERROR is sent by
+// ErrorResponse and is always sent.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum NoticeLevel {
+    Debug,
+    Log,
+    Notice,
+    Warning,
+    Error,
+}
+
+impl NoticeLevel {
+    pub fn severity(self) -> &'static str {
+        match self {
+            NoticeLevel::Debug => "DEBUG",
+            NoticeLevel::Log => "LOG",
+            NoticeLevel::Notice => "NOTICE",
+            NoticeLevel::Warning => "WARNING",
+            NoticeLevel::Error => "ERROR",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ByteaOutput {
+    Hex,
+    Escape,
+}
+
+// The file written through O_DSYNC is never synced explicitly, see utils::sync_file().
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SyncMethod {
+    Fdatasync,
+    Fsync,
+    OpenDsync,
 }
 
 impl Default for GucState {
@@ -49,6 +102,10 @@ impl Default for GucState {
             vals: GucVals::default(),
             loglvl: log::LevelFilter::Trace,
             base_search_path_valid: false,
+            bytea_output: ByteaOutput::Hex,
+            client_encoding: Encoding::Utf8,
+            client_minlvl: NoticeLevel::Notice,
+            wal_sync_method: SyncMethod::Fdatasync,
         }
     }
 }
@@ -193,6 +250,17 @@ pub fn set_bool_guc(idx: gucdef::B, val: bool, gucstate: &mut GucState) {
     apply_bool_guc(idx as usize, val, gucstate, Source::SET);
 }
 
+// RESET, the value is reset to the one in reset through SET, so that the preassign hook runs
+// just as SET.
+pub fn reset_guc(idx: GucIdx, reset: &GucState, gucstate: &mut GucState) {
+    match idx {
+        GucIdx::I(idx) => set_int_guc(idx, get_int(reset, idx), gucstate),
+        GucIdx::R(idx) => set_real_guc(idx, get_real(reset, idx), gucstate),
+        GucIdx::B(idx) => set_bool_guc(idx, get_bool(reset, idx), gucstate),
+        GucIdx::S(idx) => set_str_guc(idx, get_str(reset, idx).to_string(), gucstate),
+    }
+}
+
 fn load_guc(gucstate: &mut GucState, guckey: &str, gucval: &Yaml) {
     macro_rules! apply_guc {
         ($yamlto: ident, $apply: ident, $idx: expr) => {
@@ -243,6 +311,10 @@ pub fn get_int(gucvals: &GucState, guckey: gucdef::I) -> i32 {
     gucvals.vals.int_vals[guckey as usize]
 }
 
+pub fn get_real(gucvals: &GucState, guckey: gucdef::R) -> f64 {
+    gucvals.vals.real_vals[guckey as usize]
+}
+
 pub fn get_bool(gucvals: &GucState, guckey: gucdef::B) -> bool {
     gucvals.vals.bool_vals[guckey as usize]
 }
@@ -263,12 +335,14 @@ fn log_min_messages_preassign(val: &mut String, gucstate: &mut GucState) -> bool
         "DEBUG2" => log::LevelFilter::Trace,
         _ => return false,
     };
-    log::set_max_level(gucstate.loglvl);
+    // Only affects the current thread, the level of kuiba.conf is applied to all threads by
+    // logger::start().
+    logger::set_level(gucstate.loglvl);
     true
 }
 
-fn log_min_messages_show(_: &GucState) -> String {
-    match log::max_level() {
+fn log_min_messages_show(gucstate: &GucState) -> String {
+    match gucstate.loglvl {
         log::LevelFilter::Off => "OFF",
         log::LevelFilter::Error => "ERROR",
         log::LevelFilter::Warn => "WARNING",
@@ -279,6 +353,87 @@ fn log_min_messages_show(_: &GucState) -> String {
     .to_string()
 }
 
+fn log_destination_preassign(val: &mut String, _gucstate: &mut GucState) -> bool {
+    *val == "stderr" || *val == "file"
+}
+
+fn auth_method_preassign(val: &mut String, _gucstate: &mut GucState) -> bool {
+    *val == "trust" || *val == "md5" || *val == "scram-sha-256"
+}
+
+fn log_format_preassign(val: &mut String, _gucstate: &mut GucState) -> bool {
+    *val == "text" || *val == "json"
+}
+
+fn log_filename_preassign(val: &mut String, _gucstate: &mut GucState) -> bool {
+    logger::is_valid_filename(val)
+}
+
+fn application_name_preassign(val: &mut String, _gucstate: &mut GucState) -> bool {
+    logger::set_appname(val);
+    true
+}
+
+fn client_min_messages_preassign(val: &mut String, gucstate: &mut GucState) -> bool {
+    let lowercase = val.to_ascii_lowercase();
+    gucstate.client_minlvl = match lowercase.as_str() {
+        "debug5" | "debug4" | "debug3" | "debug2" | "debug1" => NoticeLevel::Debug,
+        "log" => NoticeLevel::Log,
+        "notice" => NoticeLevel::Notice,
+        "warning" => NoticeLevel::Warning,
+        "error" => NoticeLevel::Error,
+        _ => return false,
+    };
+    *val = lowercase;
+    true
+}
+
+fn bytea_output_preassign(val: &mut String, gucstate: &mut GucState) -> bool {
+    gucstate.bytea_output = match val.as_str() {
+        "hex" => ByteaOutput::Hex,
+        "escape" => ByteaOutput::Escape,
+        _ => return false,
+    };
+    true
+}
+
+fn wal_sync_method_preassign(val: &mut String, gucstate: &mut GucState) -> bool {
+    gucstate.wal_sync_method = match val.as_str() {
+        "fdatasync" => SyncMethod::Fdatasync,
+        "fsync" => SyncMethod::Fsync,
+        "open_dsync" => SyncMethod::OpenDsync,
+        _ => return false,
+    };
+    true
+}
+
+fn archive_compression_preassign(val: &mut String, _gucstate: &mut GucState) -> bool {
+    match Compression::parse(val).and_then(|compression| compression.check_command()) {
+        Ok(()) => true,
+        Err(err) => {
+            log::warn!("archive_compression_preassign: {}. val={}", err, val);
+            false
+        }
+    }
+}
+
+// check_client_encoding, the value is canonicalized so that SHOW and ParameterStatus report
+// the same name for all the aliases.
+fn client_encoding_preassign(val: &mut String, gucstate: &mut GucState) -> bool {
+    match Encoding::from_name(val) {
+        None => false,
+        Some(enc) => {
+            gucstate.client_encoding = enc;
+            *val = enc.name().to_string();
+            true
+        }
+    }
+}
+
+fn crash_point_preassign(val: &mut String, _gucstate: &mut GucState) -> bool {
+    crashpoint::arm(val)
+}
+
 fn search_path_preassign(_val: &mut String, gucstate: &mut GucState) -> bool {
     gucstate.base_search_path_valid = false;
     true
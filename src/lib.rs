@@ -13,11 +13,15 @@ limitations under the License.
 use access::csmvcc::{MVCCBufCtx, TabMVCC};
 use access::lmgr;
 use access::sv;
-use access::{ckpt, clog, wal, xact, xact::SessionExt as xact_sess_ext};
+use access::{autovacuum, ckpt, clog, wal, xact, xact::SessionExt as xact_sess_ext};
 use anyhow::Context;
 use log;
-use rand;
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags};
+use protocol::Message;
+use rand::{rngs::OsRng, RngCore};
 use static_assertions::const_assert;
+use std::borrow::Cow;
 use std::cmp::Ordering as cmpord;
 use std::cmp::{max, min};
 use std::collections::HashMap;
@@ -25,11 +29,19 @@ use std::debug_assert;
 use std::io::{BufReader, BufWriter, Write};
 use std::iter::Iterator;
 use std::net::TcpStream;
+use std::os::unix::io::AsRawFd;
 use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering, Ordering::Relaxed};
 use std::sync::{Arc, Condvar, Mutex};
-use stderrlog::{ColorChoice, Timestamp};
+use std::time::{Duration, Instant};
+use utils::auth;
+use utils::latch::Latch;
 use utils::sb;
-use utils::{err::errcode, AttrNumber, SessionState};
+use utils::ssl::KBStream;
+use utils::{
+    encoding::{self, Encoding},
+    err::{errcode, errposition},
+    AttrNumber, SessionState,
+};
 
 pub mod access;
 pub mod catalog;
@@ -41,6 +53,7 @@ pub mod guc;
 pub mod optimizer;
 pub mod parser;
 pub mod protocol;
+pub mod replication;
 pub mod utility;
 pub mod utils;
 
@@ -57,12 +70,7 @@ pub const KB_BLCKSZ: usize = 8192;
 const_assert!((KB_BLCKSZ & (KB_BLCKSZ - 1)) == 0); // KB_BLCKSZ should be 2^n!
 
 pub fn init_log() {
-    stderrlog::new()
-        .verbosity(33)
-        .timestamp(Timestamp::Microsecond)
-        .color(ColorChoice::Never)
-        .init()
-        .unwrap();
+    utils::logger::init();
 }
 
 mod oids;
@@ -217,21 +225,37 @@ impl Progress {
 pub struct CancelState {
     pub key: u32,
     pub termreq: Arc<AtomicBool>,
+    // Used to wake up the idle session.
+    pub latch: Option<Arc<Latch>>,
+    // The smart shutdown does not wait for the walsenders, see shutdown().
+    pub walsender: bool,
 }
 
 pub type CancelMap = HashMap<u32, CancelState>;
 
-fn insert_cancel_map(cancelmap: &Mutex<CancelMap>, sessid: u32, key: u32) -> Arc<AtomicBool> {
+fn insert_cancel_map(
+    cancelmap: &Mutex<CancelMap>,
+    sessid: u32,
+    key: u32,
+    walsender: bool,
+) -> Arc<AtomicBool> {
     let termreq: Arc<AtomicBool> = Arc::default();
     let cancel_state = CancelState {
         key,
         termreq: termreq.clone(),
+        latch: None,
+        walsender,
     };
     let mut map = cancelmap.lock().unwrap();
     map.insert(sessid, cancel_state);
     termreq
 }
 
+fn set_cancel_latch(cancelmap: &Mutex<CancelMap>, sessid: u32, latch: Arc<Latch>) {
+    let mut map = cancelmap.lock().unwrap();
+    map.get_mut(&sessid).unwrap().latch = Some(latch);
+}
+
 struct SessionDroper<'a> {
     map: &'a Mutex<CancelMap>,
     id: u32,
@@ -259,9 +283,17 @@ fn handle_cancel_request(cancelmap: &Mutex<CancelMap>, cancel_req: protocol::Can
             None => {
                 done = "cannot find the backend";
             }
-            Some(CancelState { key, termreq }) => {
+            Some(CancelState {
+                key,
+                termreq,
+                latch,
+                ..
+            }) => {
                 if *key == cancel_req.key {
                     termreq.store(true, Ordering::Relaxed);
+                    if let Some(latch) = latch {
+                        latch.set();
+                    }
                 } else {
                     done = "unexpected key";
                 }
@@ -271,20 +303,124 @@ fn handle_cancel_request(cancelmap: &Mutex<CancelMap>, cancel_req: protocol::Can
     log::info!("execute cancel request. done={}", done);
 }
 
-type SockReader<'a> = BufReader<&'a TcpStream>;
-type SockWriter<'a> = BufWriter<&'a TcpStream>;
+type SockReader<'a> = BufReader<&'a KBStream>;
+type SockWriter<'a> = BufWriter<&'a KBStream>;
 
-fn on_error(level: &str, err: &anyhow::Error, writer: &mut SockWriter) {
+fn on_error(level: &str, err: &anyhow::Error, writer: &mut SockWriter, enc: Encoding) {
     let ec = errcode(err);
     let msg = format!("{:#}", err);
     log::error!("msglvl={} code={} {}", level, ec, &msg);
+    let pos = errposition(err).map(|v| v.to_string());
+    let mut errmsg = protocol::ErrorResponse::new(level, ec, &msg);
+    errmsg.fields.position = pos.as_deref();
     // ignore error, just as send_message_to_frontend().
-    protocol::write_message(writer, &protocol::ErrorResponse::new(level, ec, &msg));
+    protocol::write_message_to(writer, &errmsg, enc);
     let _ = writer.flush();
     return;
 }
 
 const NOSSL: [u8; 1] = ['N' as u8];
+const SSLOK: [u8; 1] = ['S' as u8];
+
+// ProcessNotifyInterrupt
+fn send_notifications(state: &SessionState, sockwriter: &mut SockWriter<'_>) {
+    for notification in state.notify.take_notifications() {
+        protocol::write_message_to(
+            sockwriter,
+            &notification.to_msg(),
+            state.gucstate.client_encoding,
+        );
+    }
+}
+
+// Wait until the client message arrives. The notifications delivered during the wait are
+// sent to the client immediately, just like the ProcessClientReadInterrupt.
+fn wait_client_read(
+    state: &SessionState,
+    sockreader: &SockReader<'_>,
+    sockwriter: &mut SockWriter<'_>,
+    idle_timeout: Option<Duration>,
+) -> anyhow::Result<()> {
+    if !sockreader.buffer().is_empty() || sockreader.get_ref().has_pending() {
+        return Ok(());
+    }
+    let latch = state.notify.latch();
+    let deadline = idle_timeout.map(|v| Instant::now() + v);
+    loop {
+        let mut fds = [
+            PollFd::new(sockreader.get_ref().as_raw_fd(), PollFlags::POLLIN),
+            PollFd::new(latch.fd(), PollFlags::POLLIN),
+        ];
+        let timeout = match deadline {
+            None => -1,
+            Some(deadline) => {
+                let left = deadline.saturating_duration_since(Instant::now());
+                kbensure!(
+                    !left.is_zero(),
+                    ERRCODE_IDLE_IN_TRANSACTION_SESSION_TIMEOUT,
+                    "terminating connection due to idle-in-transaction timeout"
+                );
+                // Rounds up so that the poll never returns before the deadline.
+                left.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32
+            }
+        };
+        match poll(&mut fds, timeout) {
+            Err(nix::Error::Sys(Errno::EINTR)) => continue,
+            v => {
+                v.with_context(|| errctx!(ERRCODE_CONNECTION_FAILURE, "poll failed"))?;
+            }
+        }
+        if fds[1].revents().map_or(false, |v| !v.is_empty()) {
+            latch.reset();
+            send_notifications(state, sockwriter);
+            sockwriter.flush()?;
+        }
+        if fds[0].revents().map_or(false, |v| !v.is_empty()) {
+            return Ok(());
+        }
+    }
+}
+
+// ProcessStartupPacket, the SSLRequest is accepted if ssl is set, otherwise it is declined.
+// The startup packet after it is read instead, through the TLS session if accepted.
+fn read_startup_packet(
+    sockreader: &mut SockReader<'_>,
+    sockwriter: &mut SockWriter<'_>,
+    msg: &mut Vec<u8>,
+    ssl: Option<&Arc<rustls::ServerConfig>>,
+) -> anyhow::Result<()> {
+    protocol::read_startup_message(sockreader, msg)?;
+    if protocol::SSLRequest::deserialize(msg).is_some() {
+        let ssl = match ssl {
+            None => {
+                sockwriter.write_all(&NOSSL)?;
+                sockwriter.flush()?;
+                protocol::read_startup_message(sockreader, msg)?;
+                return Ok(());
+            }
+            Some(v) => v,
+        };
+        // The client must wait for the answer before starting the handshake.
+        kbensure!(
+            sockreader.buffer().is_empty(),
+            ERRCODE_PROTOCOL_VIOLATION,
+            "received unencrypted data after SSL request"
+        );
+        sockwriter.write_all(&SSLOK)?;
+        sockwriter.flush()?;
+        sockreader
+            .get_ref()
+            .start_tls(ssl.clone())
+            .with_context(|| {
+                errctx!(
+                    ERRCODE_PROTOCOL_VIOLATION,
+                    "could not accept SSL connection"
+                )
+            })?;
+        protocol::read_startup_message(sockreader, msg)?;
+    }
+    return Ok(());
+}
 
 fn do_postgres_main(
     global_state: GlobalState,
@@ -301,16 +437,11 @@ fn do_postgres_main(
             .map_or("UNKNOWN ADDR".to_string(), |v| v.to_string())
     );
     let mut msg = Vec::new();
-    protocol::read_startup_message(sockreader, &mut msg)?;
+    read_startup_packet(sockreader, sockwriter, &mut msg, global_state.ssl.as_ref())?;
     if let Some(req) = protocol::CancelRequest::deserialize(&msg) {
         handle_cancel_request(&global_state.cancelmap, req);
         return Ok(());
     }
-    if let Some(_) = protocol::SSLRequest::deserialize(&msg) {
-        sockwriter.write_all(&NOSSL)?;
-        sockwriter.flush()?;
-        protocol::read_startup_message(sockreader, &mut msg)?;
-    }
     let startup = protocol::StartupMessage::deserialize(&msg).with_context(|| {
         errctx!(
             ERRCODE_PROTOCOL_VIOLATION,
@@ -319,47 +450,136 @@ fn do_postgres_main(
         )
     })?;
     log::info!("receive startup message. msg={:?}", &startup);
-    let expected_client_encoding = guc::get_str(&global_state.gucstate, guc::ClientEncoding);
     // validate
-    kbensure!(
-        startup.check_client_encoding(expected_client_encoding),
-        ERRCODE_PROTOCOL_VIOLATION,
-        "Unsupported client encoding. expected={}",
-        expected_client_encoding
-    );
+    let server_encoding = guc::get_str(&global_state.gucstate, guc::ServerEncoding);
+    let client_encoding = match startup.client_encoding() {
+        // The client is assumed to use the server encoding, just as pgbench does.
+        None => None,
+        Some(name) => Some(encoding::check_client_encoding(name, server_encoding)?),
+    };
+    auth::client_authentication(
+        &global_state.gucstate,
+        startup.database(),
+        startup.user(),
+        sockreader,
+        sockwriter,
+    )?;
     // post-validate
-    let sesskey = rand::random();
-    let termreq = insert_cancel_map(&global_state.cancelmap, sessid, sesskey);
-    let _droper = SessionDroper::new(&global_state.cancelmap, sessid);
+    // The secret key is all that authenticates the CancelRequest, so it is drawn from the OS.
+    let sesskey = OsRng.next_u32();
+    let termreq = insert_cancel_map(
+        &global_state.cancelmap,
+        sessid,
+        sesskey,
+        startup.replication(),
+    );
+    let cancelmap = global_state.cancelmap;
+    let _droper = SessionDroper::new(cancelmap, sessid);
     let mut state = global_state.new_session(&startup.database(), sessid, termreq)?;
+    set_cancel_latch(cancelmap, sessid, state.notify.latch().clone());
     log::info!("connect database. dboid={}", state.reqdb);
+    state.init_thread_locals();
+    let gucstate = Arc::make_mut(&mut state.gucstate);
+    if let Some(enc) = client_encoding {
+        guc::set_str_guc(guc::ClientEncoding, enc.name().to_string(), gucstate);
+    }
+    if let Some(appname) = startup.application_name() {
+        guc::set_str_guc(guc::ApplicationName, appname.to_string(), gucstate);
+    }
+    // The startup parameters are the session defaults restored by RESET.
+    state.reset_gucstate = state.gucstate.clone();
     // post-validate for client-side
     protocol::write_message(sockwriter, &protocol::AuthenticationOk {});
-    protocol::report_all_gucs(&state.gucstate, sockwriter);
+    let mut reported_gucs = protocol::ReportedGucs::new();
+    protocol::report_changed_gucs(&state.gucstate, &mut reported_gucs, sockwriter);
     protocol::write_message(sockwriter, &protocol::BackendKeyData::new(sessid, sesskey));
-    state.init_thread_locals();
+    if startup.replication() {
+        return replication::walsender::walsender_main(&mut state, sockreader, sockwriter);
+    }
+    let mut extstate = ExtendedState::default();
+    let mut send_ready_for_query = true;
+    // The idle_in_transaction_session_timeout only applies to the wait right after ReadyForQuery.
+    let mut idle_timeout = None;
+    // After an error in the extended query message, the messages are skipped until Sync.
+    let mut ignore_till_sync = false;
     loop {
-        state.check_termreq()?;
-        protocol::write_message(
-            sockwriter,
-            &protocol::ReadyForQuery::new(state.xact_status()),
-        );
-        sockwriter.flush()?;
-        let (msgtype, msgdata) = protocol::read_message(sockreader)
-            .with_context(|| errctx!(ERRCODE_CONNECTION_FAILURE, "read_message failed"))?;
-        state.check_termreq()?;
+        if send_ready_for_query {
+            send_notifications(&state, sockwriter);
+            protocol::report_changed_gucs(&state.gucstate, &mut reported_gucs, sockwriter);
+            let xact_status = state.xact_status();
+            // AtCommit_Portals and AtAbort_Portals.
+            if !matches!(xact_status, protocol::XactStatus::InBlock) {
+                extstate.portals.clear();
+            }
+            let timeout = guc::get_int(&state.gucstate, guc::IdleInTransactionSessionTimeout);
+            if !matches!(xact_status, protocol::XactStatus::NotInBlock) && timeout > 0 {
+                idle_timeout = Some(Duration::from_millis(timeout as u64));
+            }
+            protocol::write_message(sockwriter, &protocol::ReadyForQuery::new(xact_status));
+            sockwriter.flush()?;
+            send_ready_for_query = false;
+        }
+        let ret = wait_client_read(&state, sockreader, sockwriter, idle_timeout.take());
+        if ret.is_err() {
+            // The session is terminated, its transaction must not be left behind.
+            state.abort_out_of_any_tran()?;
+            return ret;
+        }
+        let (msgtype, msgdata) = protocol::read_message(sockreader)?;
+        // The cancel request received while idle is ignored, just as DoingCommandRead.
+        state.termreq.store(false, Relaxed);
         if msgtype == protocol::MsgType::EOF as i8 || msgtype == protocol::MsgType::Terminate as i8
         {
             log::info!("end connection");
             return Ok(());
         }
+        if msgtype == protocol::MsgType::Sync as i8 {
+            // Every extended query message commits its own command, so there is no implicit
+            // transaction left to finish here.
+            ignore_till_sync = false;
+            send_ready_for_query = true;
+            continue;
+        }
+        if ignore_till_sync {
+            continue;
+        }
+        if msgtype == protocol::MsgType::Flush as i8 {
+            sockwriter.flush()?;
+            continue;
+        }
         kbensure!(
-            msgtype == protocol::MsgType::Query as i8,
+            msgtype == protocol::MsgType::Query as i8 || is_extended_message(msgtype),
             ERRCODE_PROTOCOL_VIOLATION,
-            "unexpected msg. expected=Q actual={}",
+            "unexpected msg. actual={}",
             msgtype
         );
+        if msgtype != protocol::MsgType::Query as i8 {
+            state.update_stmt_startts();
+            let ret =
+                exec_extended_message(msgtype, &msgdata, &mut extstate, &mut state, sockwriter);
+            send_notices(&mut state, sockwriter);
+            if let Err(ref err) = ret {
+                state.on_error(err, sockwriter);
+                state.abort_cur_tran().unwrap();
+                ignore_till_sync = true;
+            }
+            if state.dead {
+                return Ok(());
+            }
+            continue;
+        }
+        send_ready_for_query = true;
         state.update_stmt_startts();
+        // pg_client_to_server, the invalid byte sequence only fails the current query.
+        let converted = match encoding::to_server(state.gucstate.client_encoding, &msgdata) {
+            Ok(Cow::Borrowed(_)) => None,
+            Ok(Cow::Owned(v)) => Some(v.into_bytes()),
+            Err(err) => {
+                state.on_error(&err, sockwriter);
+                continue;
+            }
+        };
+        let msgdata = converted.unwrap_or(msgdata);
         let query = protocol::Query::deserialize(&msgdata).with_context(|| {
             errctx!(
                 ERRCODE_PROTOCOL_VIOLATION,
@@ -378,34 +598,95 @@ const SOCK_SEND_BUF_SIZE: usize = 8192;
 const SOCK_RECV_BUF_SIZE: usize = 8192;
 
 pub fn postgres_main(global_state: GlobalState, streamv: TcpStream, sessid: u32) {
+    let streamv = KBStream::new(streamv);
     let mut sockreader = BufReader::with_capacity(SOCK_RECV_BUF_SIZE, &streamv);
     let mut sockwriter = BufWriter::with_capacity(SOCK_SEND_BUF_SIZE, &streamv);
     let res = do_postgres_main(global_state, &mut sockreader, &mut sockwriter, sessid);
     if let Err(err) = res {
-        on_error(protocol::SEVERITY_FATAL, &err, &mut sockwriter);
+        // The session may not be established, so the client_encoding is unknown.
+        on_error(
+            protocol::SEVERITY_FATAL,
+            &err,
+            &mut sockwriter,
+            Encoding::Utf8,
+        );
     }
     let _ = sockwriter.flush(); // ignore error, just as ReadyForQuery
+    streamv.close();
     return;
 }
 
-fn write_str_response(resp: &utility::StrResp, stream: &mut SockWriter) {
-    protocol::write_message(
-        stream,
-        &protocol::RowDescription {
-            fields: &[protocol::FieldDesc::new(
-                &resp.name,
-                VARCHAROID.into(),
-                -1,
-                -1,
-            )],
-        },
+fn do_reject_startup(
+    sockreader: &mut SockReader<'_>,
+    sockwriter: &mut SockWriter<'_>,
+) -> anyhow::Result<()> {
+    let mut msg = Vec::new();
+    read_startup_packet(sockreader, sockwriter, &mut msg, None)?;
+    // There is no session to cancel yet.
+    if protocol::CancelRequest::deserialize(&msg).is_some() {
+        return Ok(());
+    }
+    kbbail!(
+        ERRCODE_CANNOT_CONNECT_NOW,
+        "the database system is starting up"
     );
-    protocol::write_message(
+}
+
+// ProcessStartupPacket with CAC_STARTUP, the connection arriving before the recovery is done
+// gets the FATAL once its startup packet is read.
+pub fn reject_startup(streamv: TcpStream) {
+    let streamv = KBStream::new(streamv);
+    let mut sockreader = BufReader::with_capacity(SOCK_RECV_BUF_SIZE, &streamv);
+    let mut sockwriter = BufWriter::with_capacity(SOCK_SEND_BUF_SIZE, &streamv);
+    if let Err(err) = do_reject_startup(&mut sockreader, &mut sockwriter) {
+        on_error(
+            protocol::SEVERITY_FATAL,
+            &err,
+            &mut sockwriter,
+            Encoding::Utf8,
+        );
+    }
+    let _ = sockwriter.flush();
+    return;
+}
+
+fn str_field_desc(name: &str) -> protocol::FieldDesc<'_> {
+    protocol::FieldDesc::new(name, VARCHAROID.into(), -1, -1)
+}
+
+// The DataRows of resp, the text of varchar is the same in both the text and binary format.
+fn str_data_rows(resp: &utility::StrResp, enc: Encoding) -> anyhow::Result<Vec<Vec<u8>>> {
+    let mut rows = Vec::with_capacity(resp.vals.len());
+    for val in &resp.vals {
+        let val = encoding::to_client(enc, val)?;
+        rows.push(
+            protocol::DataRow {
+                data: &[Some(&val)],
+            }
+            .serialize(),
+        );
+    }
+    return Ok(rows);
+}
+
+fn write_str_response(
+    resp: &utility::StrResp,
+    stream: &mut SockWriter,
+    enc: Encoding,
+) -> anyhow::Result<()> {
+    let rows = str_data_rows(resp, enc)?;
+    protocol::write_message_to(
         stream,
-        &protocol::DataRow {
-            data: &[Some(resp.val.as_bytes())],
+        &protocol::RowDescription {
+            fields: &[str_field_desc(&resp.name)],
         },
+        enc,
     );
+    for row in rows {
+        // ignore error, just as write_message().
+        let _ = stream.write_all(&row);
+    }
+    return Ok(());
 }
 
 fn write_cmd_complete(tag: &str, stream: &mut SockWriter) {
@@ -417,9 +698,10 @@ fn exec_utility(
     session: &mut SessionState,
     stream: &mut SockWriter,
 ) -> anyhow::Result<String> {
-    let resp = utility::process_utility(stmt, session)?;
+    let mut dest_remote = access::DestRemote::new(stream);
+    let resp = utility::process_utility(stmt, session, &mut dest_remote)?;
     if let Some(ref strresp) = resp.resp {
-        write_str_response(strresp, stream);
+        write_str_response(strresp, stream, session.gucstate.client_encoding)?;
     }
     return Ok(resp.tag.to_string());
 }
@@ -443,8 +725,21 @@ fn do_exec_simple_query(
     // We dont want a multi-line log.
     log::info!("receive query. {}", query /* .replace("\n", " ") */);
     session.start_tran_cmd()?;
-    let ast = parser::parse(query)
-        .with_context(|| errctx!(ERRCODE_SYNTAX_ERROR, "parse query failed"))?;
+    if let Some(resets) = commands::discard::parse_resets(query) {
+        kbensure!(
+            !session.is_aborted(),
+            ERRCODE_IN_FAILED_SQL_TRANSACTION,
+            "current transaction is aborted, commands ignored until end of transaction block"
+        );
+        commands::discard::reset_session(session, &resets)?;
+        session.commit_tran_cmd()?;
+        send_notices(session, stream);
+        for reset in resets {
+            write_cmd_complete(reset.tag(), stream);
+        }
+        return Ok(());
+    }
+    let ast = parser::parse(query)?;
     kbensure!(
         !session.is_aborted() || ast.is_tran_exit(),
         ERRCODE_IN_FAILED_SQL_TRANSACTION,
@@ -461,17 +756,381 @@ fn do_exec_simple_query(
         parser::sem::Stmt::Optimizable(ref stmt) => exec_optimizable(stmt, session, stream),
     }?;
     session.commit_tran_cmd()?;
+    send_notices(session, stream);
     write_cmd_complete(&cmdtag, stream);
     return Ok(());
 }
 
+// Send the notices of SessionState::notice().
+fn send_notices(session: &mut SessionState, stream: &mut SockWriter) {
+    for notice in session.notices.drain(..) {
+        let msg = protocol::NoticeResponse {
+            fields: protocol::ErrFields {
+                severity: Some(notice.level.severity()),
+                code: Some(notice.code),
+                msg: Some(&notice.msg),
+                ..protocol::ErrFields::default()
+            },
+        };
+        protocol::write_message_to(stream, &msg, session.gucstate.client_encoding);
+    }
+}
+
 fn exec_simple_query(query: &str, session: &mut SessionState, stream: &mut SockWriter) {
-    if let Err(ref err) = do_exec_simple_query(query, session, stream) {
+    let ret = do_exec_simple_query(query, session, stream);
+    send_notices(session, stream);
+    if let Err(ref err) = ret {
         session.on_error(err, stream);
         session.abort_cur_tran().unwrap();
     }
 }
 
+// CachedPlanSource. Only the query is kept since the parse tree borrows the query string, the
+// query is parsed again by Bind, Describe and Execute.
+struct PreparedStmt {
+    query: String,
+}
+
+// The result of the portal. The portal is run to completion by its first Execute, and the rows
+// are kept here so that they can be fetched max_rows at a time.
+struct PortalResult {
+    rows: Vec<Vec<u8>>,
+    // The command tag, None for the empty query. The tag of SELECT is built on completion
+    // since it counts the rows sent by the last Execute, just as PortalRunSelect().
+    tag: Option<String>,
+    is_select: bool,
+}
+
+struct Portal {
+    query: String,
+    // The result format codes of Bind.
+    formats: Vec<protocol::Format>,
+    // None means the portal has not been run.
+    result: Option<PortalResult>,
+}
+
+// The prepared statements and portals of the extended query protocol, the empty name is the
+// unnamed statement or portal that is replaced on reuse.
+#[derive(Default)]
+struct ExtendedState {
+    stmts: HashMap<String, PreparedStmt>,
+    portals: HashMap<String, Portal>,
+}
+
+fn is_extended_message(msgtype: i8) -> bool {
+    [
+        protocol::MsgType::Parse,
+        protocol::MsgType::Bind,
+        protocol::MsgType::Describe,
+        protocol::MsgType::Execute,
+        protocol::MsgType::Close,
+    ]
+    .iter()
+    .any(|&v| v as i8 == msgtype)
+}
+
+fn check_aborted(session: &SessionState, ast: &parser::syn::Stmt) -> anyhow::Result<()> {
+    kbensure!(
+        !session.is_aborted() || ast.is_tran_exit(),
+        ERRCODE_IN_FAILED_SQL_TRANSACTION,
+        "current transaction is aborted, commands ignored until end of transaction block"
+    );
+    return Ok(());
+}
+
+fn get_stmt<'a>(extstate: &'a ExtendedState, name: &str) -> anyhow::Result<&'a PreparedStmt> {
+    extstate.stmts.get(name).ok_or_else(|| {
+        kbanyhow!(
+            ERRCODE_INVALID_SQL_STATEMENT_NAME,
+            "prepared statement \"{}\" does not exist",
+            name
+        )
+    })
+}
+
+fn get_portal<'a>(extstate: &'a mut ExtendedState, name: &str) -> anyhow::Result<&'a mut Portal> {
+    extstate.portals.get_mut(name).ok_or_else(|| {
+        kbanyhow!(
+            ERRCODE_UNDEFINED_CURSOR,
+            "portal \"{}\" does not exist",
+            name
+        )
+    })
+}
+
+// exec_parse_message, the query is only checked by the parser here. The parameters are not
+// supported since there is no Param in the parse tree.
+fn exec_parse(
+    msg: &protocol::Parse,
+    extstate: &mut ExtendedState,
+    session: &SessionState,
+    stream: &mut SockWriter,
+) -> anyhow::Result<()> {
+    let query = encoding::to_server(session.gucstate.client_encoding, msg.query)?;
+    log::info!("receive parse. name={} query={}", msg.name, query);
+    kbensure!(
+        msg.paramtypes.is_empty(),
+        ERRCODE_FEATURE_NOT_SUPPORTED,
+        "parameters of the prepared statement are not supported"
+    );
+    kbensure!(
+        msg.name.is_empty() || !extstate.stmts.contains_key(msg.name),
+        ERRCODE_DUPLICATE_PSTATEMENT,
+        "prepared statement \"{}\" already exists",
+        msg.name
+    );
+    let ast = parser::parse(&query)?;
+    check_aborted(session, &ast)?;
+    extstate.stmts.insert(
+        msg.name.to_string(),
+        PreparedStmt {
+            query: query.into_owned(),
+        },
+    );
+    protocol::write_message(stream, &protocol::ParseComplete {});
+    return Ok(());
+}
+
+// exec_bind_message
+fn exec_bind(
+    msg: protocol::Bind,
+    extstate: &mut ExtendedState,
+    session: &SessionState,
+    stream: &mut SockWriter,
+) -> anyhow::Result<()> {
+    let stmt = get_stmt(extstate, msg.stmt)?;
+    kbensure!(
+        msg.params.is_empty(),
+        ERRCODE_PROTOCOL_VIOLATION,
+        "bind message supplies {} parameters, but prepared statement \"{}\" requires 0",
+        msg.params.len(),
+        msg.stmt
+    );
+    kbensure!(
+        msg.portal.is_empty() || !extstate.portals.contains_key(msg.portal),
+        ERRCODE_DUPLICATE_CURSOR,
+        "portal \"{}\" already exists",
+        msg.portal
+    );
+    if session.is_aborted() {
+        check_aborted(session, &parser::parse(&stmt.query)?)?;
+    }
+    let portal = Portal {
+        query: stmt.query.clone(),
+        formats: msg.resultformats,
+        result: None,
+    };
+    extstate.portals.insert(msg.portal.to_string(), portal);
+    protocol::write_message(stream, &protocol::BindComplete {});
+    return Ok(());
+}
+
+// exec_describe_statement_message and exec_describe_portal_message, the query is analyzed to
+// get its result columns.
+fn describe_query(
+    query: &str,
+    formats: &[protocol::Format],
+    session: &mut SessionState,
+    stream: &mut SockWriter,
+) -> anyhow::Result<()> {
+    session.start_tran_cmd()?;
+    let ast = parser::parse(query)?;
+    check_aborted(session, &ast)?;
+    if let parser::syn::Stmt::Empty = ast {
+        session.commit_tran_cmd()?;
+        protocol::write_message(stream, &protocol::NoData {});
+        return Ok(());
+    }
+    let enc = session.gucstate.client_encoding;
+    match parser::sem::kb_analyze(session, &ast)? {
+        parser::sem::Stmt::Utility(parser::sem::UtilityStmt::Insert(_, ref returning))
+            if !returning.is_empty() =>
+        {
+            let fields = access::row_description(returning, formats, session)?;
+            protocol::write_message_to(stream, &protocol::RowDescription { fields: &fields }, enc);
+        }
+        parser::sem::Stmt::Utility(ref stmt) => match utility::utility_result_name(stmt) {
+            None => protocol::write_message(stream, &protocol::NoData {}),
+            Some(name) => {
+                let mut field = str_field_desc(&name);
+                field.set_format(protocol::Format::of_column(formats, 0));
+                protocol::write_message_to(
+                    stream,
+                    &protocol::RowDescription { fields: &[field] },
+                    enc,
+                );
+            }
+        },
+        parser::sem::Stmt::Optimizable(ref stmt) => {
+            // The resjunk entries are always placed after all other entries.
+            let cnt = stmt.tlist.iter().take_while(|v| !v.resjunk).count();
+            let fields = access::row_description(&stmt.tlist[..cnt], formats, session)?;
+            protocol::write_message_to(stream, &protocol::RowDescription { fields: &fields }, enc);
+        }
+    }
+    session.commit_tran_cmd()?;
+    return Ok(());
+}
+
+fn exec_describe(
+    msg: &protocol::Describe,
+    extstate: &mut ExtendedState,
+    session: &mut SessionState,
+    stream: &mut SockWriter,
+) -> anyhow::Result<()> {
+    match msg.target {
+        protocol::Target::Statement => {
+            let query = &get_stmt(extstate, msg.name)?.query;
+            protocol::write_message(stream, &protocol::ParameterDescription { types: &[] });
+            describe_query(query, &[], session, stream)
+        }
+        protocol::Target::Portal => {
+            let portal = get_portal(extstate, msg.name)?;
+            describe_query(&portal.query, &portal.formats, session, stream)
+        }
+    }
+}
+
+// PortalRun, the RowDescription is not sent since it is the job of Describe.
+fn run_portal(
+    portal: &Portal,
+    session: &mut SessionState,
+    stream: &mut SockWriter,
+) -> anyhow::Result<PortalResult> {
+    log::info!("run portal. {}", portal.query);
+    session.start_tran_cmd()?;
+    let ast = parser::parse(&portal.query)?;
+    check_aborted(session, &ast)?;
+    if let parser::syn::Stmt::Empty = ast {
+        session.commit_tran_cmd()?;
+        return Ok(PortalResult {
+            rows: Vec::new(),
+            tag: None,
+            is_select: false,
+        });
+    }
+    let result = match parser::sem::kb_analyze(session, &ast)? {
+        parser::sem::Stmt::Utility(ref stmt) => {
+            let mut dest = access::DestRemote::new_portal(stream, portal.formats.clone());
+            let resp = utility::process_utility(stmt, session, &mut dest)?;
+            let rows = match resp.resp {
+                None => dest.portal_rows.unwrap_or_default(),
+                Some(ref strresp) => str_data_rows(strresp, session.gucstate.client_encoding)?,
+            };
+            PortalResult {
+                rows,
+                tag: Some(resp.tag),
+                is_select: false,
+            }
+        }
+        parser::sem::Stmt::Optimizable(ref stmt) => {
+            let plannedstmt = optimizer::planner(session, stmt)?;
+            let mut dest = access::DestRemote::new_portal(stream, portal.formats.clone());
+            executor::exec_select(&plannedstmt, session, &mut dest)?;
+            PortalResult {
+                rows: dest.portal_rows.unwrap_or_default(),
+                tag: Some(String::new()),
+                is_select: true,
+            }
+        }
+    };
+    session.commit_tran_cmd()?;
+    return Ok(result);
+}
+
+// exec_execute_message, max_rows <= 0 means fetching all rows.
+fn exec_execute(
+    msg: &protocol::Execute,
+    extstate: &mut ExtendedState,
+    session: &mut SessionState,
+    stream: &mut SockWriter,
+) -> anyhow::Result<()> {
+    let portal = get_portal(extstate, msg.portal)?;
+    if portal.result.is_none() {
+        portal.result = Some(run_portal(portal, session, stream)?);
+    }
+    let result = portal.result.as_mut().unwrap();
+    let tag = match result.tag {
+        None => {
+            protocol::write_message(stream, &protocol::EmptyQueryResponse {});
+            return Ok(());
+        }
+        Some(ref tag) => tag,
+    };
+    let cnt = if msg.max_rows > 0 {
+        min(msg.max_rows as usize, result.rows.len())
+    } else {
+        result.rows.len()
+    };
+    for row in result.rows.drain(..cnt) {
+        // ignore error, just as write_message().
+        let _ = stream.write_all(&row);
+    }
+    if !result.rows.is_empty() {
+        protocol::write_message(stream, &protocol::PortalSuspended {});
+        return Ok(());
+    }
+    if result.is_select {
+        write_cmd_complete(&format!("SELECT {}", cnt), stream);
+    } else {
+        write_cmd_complete(tag, stream);
+    }
+    return Ok(());
+}
+
+// exec_close_message, closing a nonexistent statement or portal is not an error.
+fn exec_close(msg: &protocol::Close, extstate: &mut ExtendedState, stream: &mut SockWriter) {
+    match msg.target {
+        protocol::Target::Statement => extstate.stmts.remove(msg.name).map(|_| ()),
+        protocol::Target::Portal => extstate.portals.remove(msg.name).map(|_| ()),
+    };
+    protocol::write_message(stream, &protocol::CloseComplete {});
+}
+
+fn exec_extended_message(
+    msgtype: i8,
+    msgdata: &[u8],
+    extstate: &mut ExtendedState,
+    session: &mut SessionState,
+    stream: &mut SockWriter,
+) -> anyhow::Result<()> {
+    match msgtype as u8 {
+        b'P' => exec_parse(
+            &protocol::Parse::deserialize(msgdata)?,
+            extstate,
+            session,
+            stream,
+        ),
+        b'B' => exec_bind(
+            protocol::Bind::deserialize(msgdata)?,
+            extstate,
+            session,
+            stream,
+        ),
+        b'D' => exec_describe(
+            &protocol::Describe::deserialize(msgdata)?,
+            extstate,
+            session,
+            stream,
+        ),
+        b'E' => exec_execute(
+            &protocol::Execute::deserialize(msgdata)?,
+            extstate,
+            session,
+            stream,
+        ),
+        b'C' => {
+            exec_close(&protocol::Close::deserialize(msgdata)?, extstate, stream);
+            Ok(())
+        }
+        _ => Err(kbanyhow!(
+            ERRCODE_PROTOCOL_VIOLATION,
+            "unexpected msg. actual={}",
+            msgtype
+        )),
+    }
+}
+
 fn make_static<T>(v: T) -> &'static T {
     Box::leak(Box::new(v))
 }
@@ -495,23 +1154,36 @@ pub struct GlobalState {
     pub pending_fileops: &'static ckpt::PendingFileOps,
     pub tabsv: &'static sv::TabSupVer,
     pub tabmvcc: &'static TabMVCC,
+    // Only set in standby mode, the end lsn of the last replayed record.
+    pub replay_lsn: Option<&'static Progress>,
+    pub replslots: &'static replication::slot::ReplSlots,
+    pub tabstats: &'static autovacuum::TabStats,
+    pub notify: &'static commands::notify::GlobalStateExt,
+    // The TLS configuration loaded from ssl_cert_file and ssl_key_file, None if SSL is disabled.
+    pub ssl: Option<Arc<rustls::ServerConfig>>,
 }
 
 #[cfg(test)]
 const TEST_SESSID: u32 = 0;
 const REDO_SESSID: u32 = 1;
+const REPLAY_SESSID: u32 = 2;
+const PREWARM_SESSID: u32 = 3;
+const AUTOVAC_SESSID: u32 = 4;
 pub const LAST_INTERNAL_SESSID: u32 = 20181218;
 
 impl GlobalState {
     fn new(gucstate: Arc<guc::GucState>) -> GlobalState {
         let pending_fileops = make_static(ckpt::PendingFileOps::new());
         let table_sv_cap = guc::get_int(&gucstate, guc::TableSvCap) as usize;
-        let tabsv = sb::new_lru_sb(table_sv_cap, sv::SVCommonData::new(pending_fileops, None));
+        let verify_files = guc::get_bool(&gucstate, guc::VerifyManifestFiles);
+        let svdata = sv::SVCommonData::new(pending_fileops, None, verify_files);
+        let tabsv = sb::new_lru_sb(table_sv_cap, svdata);
         let tabsv = make_static(tabsv);
         let table_mvcc_cap = guc::get_int(&gucstate, guc::TableMvccCap) as usize;
         let tabmvccctx = MVCCBufCtx::new(pending_fileops, None);
         let tabmvcc = sb::new_lru_sb(table_mvcc_cap, tabmvccctx);
         let tabmvcc = make_static(tabmvcc);
+        let ssl = utils::ssl::tls_init(&gucstate).unwrap();
         GlobalState {
             fmgr_builtins: make_static(utils::fmgr::get_fmgr_builtins()),
             cancelmap: make_static(Mutex::<CancelMap>::default()),
@@ -524,6 +1196,13 @@ impl GlobalState {
             pending_fileops,
             tabsv,
             tabmvcc,
+            replay_lsn: None,
+            replslots: make_static(
+                replication::slot::ReplSlots::load(replication::slot::REPLSLOT_DIR).unwrap(),
+            ),
+            tabstats: make_static(autovacuum::TabStats::new()),
+            notify: make_static(commands::notify::GlobalStateExt::new()),
+            ssl,
         }
     }
 
@@ -531,7 +1210,8 @@ impl GlobalState {
         debug_assert!(self.wal.is_some());
         free_static(self.tabsv);
         let table_sv_cap = guc::get_int(&self.gucstate, guc::TableSvCap) as usize;
-        let svdata = sv::SVCommonData::new(self.pending_fileops, self.wal);
+        let verify_files = guc::get_bool(&self.gucstate, guc::VerifyManifestFiles);
+        let svdata = sv::SVCommonData::new(self.pending_fileops, self.wal, verify_files);
         let tabsv = sb::new_lru_sb(table_sv_cap, svdata);
         self.tabsv = make_static(tabsv);
 
@@ -546,6 +1226,7 @@ impl GlobalState {
     fn init(datadir: &str) -> GlobalState {
         std::env::set_current_dir(datadir).unwrap();
         let gucstate = guc::load("kuiba.conf").unwrap();
+        utils::logger::start(&gucstate).unwrap();
         GlobalState::new(Arc::new(gucstate))
     }
 
@@ -564,12 +1245,15 @@ impl GlobalState {
         })?;
         let metaconn = sqlite::open(format!("base/{}/meta.db", reqdb.oid))
             .with_context(|| errctx!(ERRCODE_INTERNAL_ERROR, "connt open metaconn."))?;
+        let latch = Latch::new()
+            .with_context(|| errctx!(ERRCODE_INTERNAL_ERROR, "cannot create latch."))?;
         Ok(SessionState::new(
             sessid,
             reqdb.oid,
             reqdb.datname,
             termreq,
             metaconn,
+            Arc::new(latch),
             self,
         ))
     }
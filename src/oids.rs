@@ -32,6 +32,7 @@ impl std::convert::From<OptOid> for u32 {
 
 pub const TEMPLATE0_DB: Oid = unsafe { Oid::new_unchecked(1) };
 pub const KUIBADB: Oid = unsafe { Oid::new_unchecked(2) };
+pub const BOOTSTRAP_SUPERUSERID: Oid = unsafe { Oid::new_unchecked(10) };
 pub const KBCATLOGNS: Oid = unsafe { Oid::new_unchecked(11) };
 pub const BOOLOID: Oid = unsafe { Oid::new_unchecked(16) };
 pub const BOOLINPROC: Oid = unsafe { Oid::new_unchecked(1242) };
@@ -57,12 +58,17 @@ pub const FLOAT8OUTPROC: Oid = unsafe { Oid::new_unchecked(215) };
 pub const VARCHAROID: Oid = unsafe { Oid::new_unchecked(1043) };
 pub const VARCHARINPROC: Oid = unsafe { Oid::new_unchecked(1046) };
 pub const VARCHAROUTPROC: Oid = unsafe { Oid::new_unchecked(1047) };
+pub const ANYOID: Oid = unsafe { Oid::new_unchecked(2276) };
 pub const TYPERELID: Oid = unsafe { Oid::new_unchecked(1247) };
 pub const ATTRRELID: Oid = unsafe { Oid::new_unchecked(1249) };
 pub const PROCRELID: Oid = unsafe { Oid::new_unchecked(1255) };
 pub const RELRELID: Oid = unsafe { Oid::new_unchecked(1259) };
 pub const DBRELID: Oid = unsafe { Oid::new_unchecked(1262) };
+pub const DEFAULTTABLESPACE_OID: Oid = unsafe { Oid::new_unchecked(1663) };
 pub const KBPUBLICNS: Oid = unsafe { Oid::new_unchecked(2200) };
+pub const CASTRELID: Oid = unsafe { Oid::new_unchecked(2605) };
 pub const NSRELID: Oid = unsafe { Oid::new_unchecked(2615) };
 pub const OPRELID: Oid = unsafe { Oid::new_unchecked(2617) };
 // pub const MaxOid: Oid = unsafe {Oid::new_unchecked(16384)};  // The oid of system catalogs should be less than MaxOid.
+// FirstNormalObjectId, the objects created by initdb have the smaller oids.
+pub const FIRST_NORMAL_OBJECT_ID: Oid = unsafe { Oid::new_unchecked(16384) };
@@ -9,17 +9,27 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::access::rel;
+use crate::access::sv::TableId;
+use crate::access::TypeDesc;
+use crate::catalog::get_typlenalign;
+use crate::guc;
 use crate::parser::sem;
+use crate::parser::syn::BoolExprType;
 use crate::utils::SessionState;
 use anyhow;
 
+mod clauses;
+
 // 'sem is the lifetime of stuff returned by kb_analyze().
 
 // Common should always be placed first so that Plan::common can erase the match expr.
+#[derive(Clone)]
 pub struct PlanCommon {
     pub tlist: Vec<sem::TargetEntry>,
 }
 
+#[derive(Clone)]
 pub struct Result {
     pub plan: PlanCommon,
     pub resconstantqual: Option<sem::Expr>,
@@ -27,14 +37,74 @@ pub struct Result {
     pub qual: Vec<sem::Expr>,
 }
 
+// Scan all files of the SupVer of the table.
+#[derive(Clone)]
+pub struct SeqScan {
+    pub plan: PlanCommon,
+    pub table: TableId,
+    pub relname: String,
+    pub rel: rel::Rel,
+    // The number of workers scanning the files, 1 means the scan is not parallel.
+    pub parallel: usize,
+    // implicitly-ANDed qual conditions
+    pub qual: Vec<sem::Expr>,
+}
+
+// AggStrategy
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AggStrategy {
+    // Plain aggregation without GROUP BY, the output is always one row.
+    Plain,
+    // Grouped aggregation via a hash table, one row per group.
+    Hashed,
+}
+
+#[derive(Clone)]
+pub struct Agg {
+    pub plan: PlanCommon,
+    pub aggstrategy: AggStrategy,
+    // The grouping expressions evaluated over the rows returned by lefttree.
+    pub group_clause: Vec<sem::SortGroupClause>,
+    pub lefttree: Box<Plan>,
+}
+
+// Sort the rows returned by lefttree, only the first plan.tlist.len() columns are returned,
+// the remaining columns are the resjunk columns used by ORDER BY.
+#[derive(Clone)]
+pub struct Sort {
+    pub plan: PlanCommon,
+    pub sort_clause: Vec<sem::SortClause>,
+    // The types of the columns returned by lefttree.
+    pub coltyps: Vec<TypeDesc>,
+    // Only the first bound rows are needed, it is pushed down from Limit.
+    pub bound: Option<u64>,
+    pub lefttree: Box<Plan>,
+}
+
+#[derive(Clone)]
+pub struct Limit {
+    pub plan: PlanCommon,
+    pub count: u64,
+    pub lefttree: Box<Plan>,
+}
+
+#[derive(Clone)]
 pub enum Plan {
     Result(Result),
+    SeqScan(SeqScan),
+    Agg(Agg),
+    Sort(Sort),
+    Limit(Limit),
 }
 
 impl Plan {
     pub fn common(&self) -> &PlanCommon {
         match self {
             Plan::Result(r) => &r.plan,
+            Plan::SeqScan(s) => &s.plan,
+            Plan::Agg(a) => &a.plan,
+            Plan::Sort(s) => &s.plan,
+            Plan::Limit(l) => &l.plan,
         }
     }
 
@@ -48,15 +118,155 @@ pub struct PlannedStmt {
     pub plan_tree: Plan,
 }
 
-pub fn planner(_state: &mut SessionState, parse: &sem::Query) -> anyhow::Result<PlannedStmt> {
-    Ok(PlannedStmt {
-        plan_tree: Plan::Result(Result {
+// make_ands_implicit
+fn make_ands_implicit(clause: Option<&sem::Expr>) -> Vec<sem::Expr> {
+    match clause {
+        None => Vec::new(),
+        Some(sem::Expr::Bool(v)) if v.boolop == BoolExprType::And => v.args.clone(),
+        Some(v) => vec![v.clone()],
+    }
+}
+
+// The target list of the scan which returns all columns of the relation.
+fn build_physical_tlist(rel: &rel::Rel) -> Vec<sem::TargetEntry> {
+    rel.attrs
+        .iter()
+        .map(|attr| sem::TargetEntry {
+            expr: sem::Expr::Var(sem::Var {
+                varattno: attr.num,
+                vartype: attr.typ,
+                loc: Default::default(),
+            }),
+            resno: attr.num,
+            resname: Some(attr.name.clone()),
+            resjunk: false,
+        })
+        .collect()
+}
+
+// One worker per file, but no more than max_parallel_workers_per_gather, so the small
+// tables are scanned without the parallel overhead.
+fn scan_parallel(state: &SessionState, table: &TableId, rel: &rel::Rel) -> anyhow::Result<usize> {
+    let maxworkers = guc::get_int(&state.gucstate, guc::MaxParallelWorkersPerGather).max(1);
+    // svslot pin guard
+    let svslot = state.tabsv.read(table, &rel.opt.enable_cs_wal)?;
+    let sv = svslot.v.read().unwrap();
+    let nfiles: usize = sv.as_ref().unwrap().files_to_scan().iter().sum();
+    return Ok(nfiles.clamp(1, maxworkers as usize));
+}
+
+fn scan_plan(
+    state: &mut SessionState,
+    parse: &sem::Query,
+    tlist: Vec<sem::TargetEntry>,
+) -> anyhow::Result<Plan> {
+    // The qual folded to true is dropped. The qual folded to false or NULL is checked once by
+    // Result instead of scanning the table, just as the gating Result of create_scan_plan().
+    let qual = match &parse.qual {
+        Some(sem::Expr::Const(c)) if clauses::const_bool(c) == Some(true) => None,
+        qual => qual.as_ref(),
+    };
+    match parse.rtable.first() {
+        Some(rte) if !matches!(qual, Some(sem::Expr::Const(_))) => {
+            let table = TableId {
+                db: state.reqdb,
+                table: rte.relid,
+            };
+            return Ok(Plan::SeqScan(SeqScan {
+                plan: PlanCommon { tlist },
+                table,
+                relname: rte.relname.clone(),
+                rel: rte.rel.clone(),
+                parallel: scan_parallel(state, &table, &rte.rel)?,
+                qual: make_ands_implicit(qual),
+            }));
+        }
+        _ => {}
+    }
+    Ok(Plan::Result(Result {
+        plan: PlanCommon { tlist },
+        qual: Vec::new(),
+        lefttree: None,
+        // Without FROM, the WHERE clause can only reference constants.
+        resconstantqual: qual.cloned(),
+    }))
+}
+
+fn agg_plan(state: &mut SessionState, parse: &sem::Query) -> anyhow::Result<Plan> {
+    if !parse.has_aggs && parse.group_clause.is_empty() {
+        return scan_plan(state, parse, parse.tlist.clone());
+    }
+    // The aggregates and the grouping expressions are evaluated over the columns returned by the scan.
+    let subtlist = match parse.rtable.first() {
+        None => Vec::new(),
+        Some(rte) => build_physical_tlist(&rte.rel),
+    };
+    Ok(Plan::Agg(Agg {
+        plan: PlanCommon {
+            tlist: parse.tlist.clone(),
+        },
+        aggstrategy: if parse.group_clause.is_empty() {
+            AggStrategy::Plain
+        } else {
+            AggStrategy::Hashed
+        },
+        group_clause: parse.group_clause.clone(),
+        lefttree: Box::new(scan_plan(state, parse, subtlist)?),
+    }))
+}
+
+// preprocess_expression, the constant subexpressions are evaluated once here instead of for
+// every row.
+fn preprocess_expressions(state: &SessionState, parse: &sem::Query) -> anyhow::Result<sem::Query> {
+    let mut parse = parse.clone();
+    for tle in &mut parse.tlist {
+        tle.expr = clauses::eval_const_expressions(state, &tle.expr)?;
+    }
+    // The grouping expressions are matched with the target list by hash, so they must be folded
+    // in the same way.
+    for group in &mut parse.group_clause {
+        group.expr = clauses::eval_const_expressions(state, &group.expr)?;
+    }
+    if let Some(qual) = &parse.qual {
+        parse.qual = Some(clauses::eval_const_expressions(state, qual)?);
+    }
+    return Ok(parse);
+}
+
+pub fn planner(state: &mut SessionState, parse: &sem::Query) -> anyhow::Result<PlannedStmt> {
+    let parse = &preprocess_expressions(state, parse)?;
+    let mut plan = agg_plan(state, parse)?;
+    // The resjunk entries are removed by Sort.
+    let tlist: Vec<sem::TargetEntry> = parse.tlist.iter().filter(|v| !v.resjunk).cloned().collect();
+    if !parse.sort_clause.is_empty() {
+        let mut coltyps = Vec::with_capacity(parse.tlist.len());
+        for tle in &parse.tlist {
+            let typid = tle.expr.val_type();
+            let (len, align) = get_typlenalign(state, typid)?;
+            coltyps.push(TypeDesc {
+                id: typid,
+                len,
+                align,
+                mode: -1,
+            });
+        }
+        plan = Plan::Sort(Sort {
             plan: PlanCommon {
-                tlist: parse.tlist.clone(),
+                tlist: tlist.clone(),
             },
-            qual: Vec::new(),
-            lefttree: None,
-            resconstantqual: None,
-        }),
-    })
+            sort_clause: parse.sort_clause.clone(),
+            coltyps,
+            // ExecSetTupleBound
+            bound: parse.limit_count,
+            lefttree: Box::new(plan),
+        });
+    }
+    if let Some(count) = parse.limit_count {
+        plan = Plan::Limit(Limit {
+            plan: PlanCommon { tlist },
+            count,
+            lefttree: Box::new(plan),
+        });
+    }
+    Ok(PlannedStmt { plan_tree: plan })
 }
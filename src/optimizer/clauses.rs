@@ -0,0 +1,164 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// clauses.c, only the constant folding of eval_const_expressions is done.
+use crate::access::TypeDesc;
+use crate::catalog::{get_proc, get_typlenalign, ProVolatile};
+use crate::datums::Datums;
+use crate::parser::sem::{self, Expr};
+use crate::parser::syn::{self, BoolExprType};
+use crate::utils::fmgr::{get_fn_addr, FmgrInfo};
+use crate::utils::{SessionState, WorkerState};
+use crate::BOOLOID;
+use std::mem::{align_of, size_of};
+use std::rc::Rc;
+
+// The value of the bool constant, None means NULL.
+pub fn const_bool(c: &sem::Const) -> Option<bool> {
+    if c.v.is_single_null() {
+        return None;
+    }
+    return Some(c.v.get_single_fixedlen());
+}
+
+// makeBoolConst
+fn make_bool_const(v: Option<bool>, loc: syn::Location) -> Expr {
+    let v = match v {
+        None => Datums::new_single_null(),
+        Some(v) => Datums::new_single_fixedlen(v),
+    };
+    Expr::Const(sem::Const {
+        typ: TypeDesc {
+            id: BOOLOID,
+            len: size_of::<bool>() as i16,
+            align: align_of::<bool>() as u8,
+            mode: -1,
+        },
+        v,
+        loc,
+    })
+}
+
+// evaluate_function, only the immutable function whose arguments are all constants is
+// evaluated, so that the volatile function is still called for every row.
+fn evaluate_function(sess: &SessionState, func: &sem::FuncExpr) -> anyhow::Result<Option<Expr>> {
+    let mut args = Vec::with_capacity(func.args.len());
+    for arg in &func.args {
+        match arg {
+            Expr::Const(c) => args.push(Rc::new(c.v.clone())),
+            _ => return Ok(None),
+        }
+    }
+    if !matches!(get_proc(sess, func.funcid)?.provolatile, ProVolatile::Immu) {
+        return Ok(None);
+    }
+    let flinfo = FmgrInfo {
+        fn_oid: func.funcid,
+        fn_addr: get_fn_addr(func.funcid, sess.fmgr_builtins)?,
+    };
+    let worker = WorkerState::new(sess);
+    let mut ret = Rc::new(Datums::new());
+    (flinfo.fn_addr)(&flinfo, &mut ret, &args, &worker)?;
+    let (len, align) = get_typlenalign(sess, func.funcresulttype)?;
+    return Ok(Some(Expr::Const(sem::Const {
+        typ: TypeDesc {
+            id: func.funcresulttype,
+            len,
+            align,
+            mode: -1,
+        },
+        v: Rc::try_unwrap(ret).unwrap_or_else(|v| (*v).clone()),
+        loc: func.loc,
+    })));
+}
+
+// simplify_and_arguments and simplify_or_arguments. The nested AND/OR is flattened, the
+// constant deciding the result replaces the whole expression, and the other constants are
+// dropped except NULL.
+fn simplify_bool_args(boolop: BoolExprType, args: Vec<Expr>, loc: syn::Location) -> Expr {
+    let decisive = boolop == BoolExprType::Or;
+    let mut newargs = Vec::with_capacity(args.len());
+    let mut has_null = false;
+    let mut pending = args;
+    pending.reverse();
+    while let Some(arg) = pending.pop() {
+        match arg {
+            Expr::Bool(v) if v.boolop == boolop => pending.extend(v.args.into_iter().rev()),
+            Expr::Const(c) => match const_bool(&c) {
+                None => has_null = true,
+                Some(v) if v == decisive => return make_bool_const(Some(decisive), loc),
+                Some(_) => {}
+            },
+            arg => newargs.push(arg),
+        }
+    }
+    if has_null {
+        newargs.push(make_bool_const(None, loc));
+    }
+    match newargs.len() {
+        0 => make_bool_const(Some(!decisive), loc),
+        1 => newargs.pop().unwrap(),
+        _ => Expr::Bool(sem::BoolExpr {
+            boolop,
+            args: newargs,
+            loc,
+        }),
+    }
+}
+
+fn eval_const_args(sess: &SessionState, args: &[Expr]) -> anyhow::Result<Vec<Expr>> {
+    let mut ret = Vec::with_capacity(args.len());
+    for arg in args {
+        ret.push(eval_const_expressions(sess, arg)?);
+    }
+    return Ok(ret);
+}
+
+// eval_const_expressions, the error raised by the function is reported at plan time, just as
+// PostgreSQL.
+pub fn eval_const_expressions(sess: &SessionState, node: &Expr) -> anyhow::Result<Expr> {
+    match node {
+        Expr::Const(_) | Expr::Var(_) => Ok(node.clone()),
+        Expr::Func(func) => {
+            let func = sem::FuncExpr {
+                funcresulttype: func.funcresulttype,
+                funcid: func.funcid,
+                args: eval_const_args(sess, &func.args)?,
+                loc: func.loc,
+            };
+            match evaluate_function(sess, &func)? {
+                Some(c) => Ok(c),
+                None => Ok(Expr::Func(func)),
+            }
+        }
+        Expr::Aggref(agg) => Ok(Expr::Aggref(sem::Aggref {
+            aggfnoid: agg.aggfnoid,
+            aggtype: agg.aggtype,
+            args: eval_const_args(sess, &agg.args)?,
+            aggstar: agg.aggstar,
+            loc: agg.loc,
+        })),
+        Expr::Bool(b) => {
+            let mut args = eval_const_args(sess, &b.args)?;
+            match b.boolop {
+                BoolExprType::Not => match args.pop().unwrap() {
+                    Expr::Const(c) => Ok(make_bool_const(const_bool(&c).map(|v| !v), b.loc)),
+                    arg => Ok(Expr::Bool(sem::BoolExpr {
+                        boolop: b.boolop,
+                        args: vec![arg],
+                        loc: b.loc,
+                    })),
+                },
+                boolop => Ok(simplify_bool_args(boolop, args, b.loc)),
+            }
+        }
+    }
+}
@@ -10,21 +10,358 @@ WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 See the License for the specific language governing permissions and
 limitations under the License.
 */
-use crate::kbbail;
-use lalrpop_util::lalrpop_mod;
+use crate::protocol::ERRCODE_SYNTAX_ERROR;
+use crate::utils::err::ErrCtx;
+use lalrpop_util::{lalrpop_mod, ParseError};
 
+pub mod oper;
 pub mod sem;
 pub mod syn;
 lalrpop_mod!(sql, "/parser/sql.rs");
 
+// base_yyerror, scanner_errposition
+fn syntax_error(query: &str, loc: usize, msg: String) -> anyhow::Error {
+    let cursorpos = query[..loc].chars().count() + 1;
+    anyhow::anyhow!("").context(ErrCtx {
+        code: ERRCODE_SYNTAX_ERROR,
+        msg,
+        cursorpos: Some(cursorpos),
+    })
+}
+
 pub fn parse(query: &str) -> anyhow::Result<syn::Stmt> {
-    match sql::StmtParser::new().parse(query) {
-        Ok(v) => Ok(v),
-        Err(e) => kbbail!(
-            ERRCODE_SYNTAX_ERROR,
-            "parse error: query={} e={:#}",
-            query,
-            e
-        ),
+    let (loc, msg) = match sql::StmtParser::new().parse(query) {
+        Ok(v) => return Ok(v),
+        Err(ParseError::InvalidToken { location }) => {
+            let tok = query[location..].chars().next().unwrap_or_default();
+            (location, format!("syntax error at or near \"{}\"", tok))
+        }
+        Err(ParseError::UnrecognizedEOF { location, .. }) => {
+            (location, "syntax error at end of input".to_string())
+        }
+        Err(ParseError::UnrecognizedToken {
+            token: (s, _, e), ..
+        })
+        | Err(ParseError::ExtraToken { token: (s, _, e) }) => {
+            (s, format!("syntax error at or near \"{}\"", &query[s..e]))
+        }
+        Err(ParseError::User { error }) => (0, format!("syntax error: {}", error)),
+    };
+    return Err(syntax_error(query, loc, msg));
+}
+
+#[cfg(test)]
+mod parser_test {
+    use super::parse;
+    use super::syn::{
+        AConst, AExprOprands, AlterTableCmd, BoolExprType, ColConstraint, DefElem, DropBehavior,
+        Expr, ObjectType, SortByDir, SortByNulls, Stmt, TranStmt, Value,
+    };
+    use crate::protocol::ERRCODE_SYNTAX_ERROR;
+    use crate::utils::err::{errcode, errposition};
+
+    fn tran(query: &str) -> TranStmt<'_> {
+        match parse(query).unwrap() {
+            Stmt::Tran(v) => v,
+            v => panic!("unexpected stmt. query={} stmt={:?}", query, v),
+        }
+    }
+
+    #[test]
+    fn tran_stmt() {
+        assert!(matches!(tran("begin"), TranStmt::Begin));
+        assert!(matches!(tran("COMMIT;"), TranStmt::Commit));
+        assert!(matches!(tran("abort"), TranStmt::Abort));
+        assert!(matches!(tran("Rollback"), TranStmt::Abort));
+        assert!(matches!(tran("savepoint sp"), TranStmt::Savepoint(n) if &*n == "sp"));
+        assert!(matches!(tran("release savepoint sp"), TranStmt::Release(n) if &*n == "sp"));
+        assert!(matches!(tran("RELEASE sp"), TranStmt::Release(n) if &*n == "sp"));
+        assert!(matches!(tran("rollback to savepoint sp"), TranStmt::RollbackTo(n) if &*n == "sp"));
+        assert!(matches!(tran("ROLLBACK TO sp"), TranStmt::RollbackTo(n) if &*n == "sp"));
+        assert!(parse("savepoint").is_err());
+        assert!(parse("rollback to").is_err());
+    }
+
+    #[test]
+    fn select_stmt() {
+        let stmt = parse("select i, j from t where i > 5 and not j = 1 or i <> 2").unwrap();
+        let stmt = match stmt {
+            Stmt::Select(v) => v,
+            v => panic!("unexpected stmt. stmt={:?}", v),
+        };
+        assert_eq!(stmt.tlist.len(), 2);
+        assert_eq!(stmt.from_clause.len(), 1);
+        let or = match stmt.where_clause {
+            Some(Expr::BoolExpr(v)) => v,
+            v => panic!("unexpected where. where={:?}", v),
+        };
+        assert_eq!(or.boolop, BoolExprType::Or);
+        assert_eq!(or.args.len(), 2);
+        let and = match &or.args[0] {
+            Expr::BoolExpr(v) => v,
+            v => panic!("unexpected expr. expr={:?}", v),
+        };
+        assert_eq!(and.boolop, BoolExprType::And);
+        match &and.args[1] {
+            Expr::BoolExpr(v) => assert_eq!(v.boolop, BoolExprType::Not),
+            v => panic!("unexpected expr. expr={:?}", v),
+        }
+        match &or.args[1] {
+            Expr::AExpr(v) => {
+                assert_eq!(&*v.name[0], "<>");
+                assert!(matches!(&*v.oprands, AExprOprands::Two(..)));
+            }
+            v => panic!("unexpected expr. expr={:?}", v),
+        }
+
+        match parse("select 1 from t where i = 1 and j = 2 and k != 3").unwrap() {
+            Stmt::Select(v) => match v.where_clause {
+                Some(Expr::BoolExpr(v)) => assert_eq!(v.args.len(), 3),
+                v => panic!("unexpected where. where={:?}", v),
+            },
+            v => panic!("unexpected stmt. stmt={:?}", v),
+        }
+
+        match parse("select i, sum(j) from t where j > 1 group by i, i + 1").unwrap() {
+            Stmt::Select(v) => {
+                assert!(v.where_clause.is_some());
+                assert_eq!(v.group_clause.len(), 2);
+            }
+            v => panic!("unexpected stmt. stmt={:?}", v),
+        }
+
+        match parse("select i from t order by i desc nulls last, j, 1 asc limit 10").unwrap() {
+            Stmt::Select(v) => {
+                let dirs: Vec<_> = v.sort_clause.iter().map(|v| v.sortby_dir).collect();
+                assert_eq!(dirs, [SortByDir::Desc, SortByDir::Default, SortByDir::Asc]);
+                assert_eq!(v.sort_clause[0].sortby_nulls, SortByNulls::Last);
+                assert_eq!(v.sort_clause[1].sortby_nulls, SortByNulls::Default);
+                assert!(matches!(v.limit_count, Some(Expr::AConst(_))));
+            }
+            v => panic!("unexpected stmt. stmt={:?}", v),
+        }
+    }
+
+    #[test]
+    fn insert_stmt() {
+        match parse("insert into t values (1, -2), (3, 'x')").unwrap() {
+            Stmt::Insert(v) => {
+                assert_eq!(&*v.relation.relname, "t");
+                assert_eq!(v.values.len(), 2);
+                assert_eq!(v.values[0].len(), 2);
+                assert!(v.returning.is_empty());
+            }
+            v => panic!("unexpected stmt. stmt={:?}", v),
+        }
+        match parse("insert into t (j, i) values (1, null) returning i + 1 as k, *").unwrap() {
+            Stmt::Insert(v) => {
+                assert_eq!(v.cols.len(), 2);
+                assert_eq!(v.returning.len(), 2);
+                assert_eq!(v.returning[0].name.as_deref(), Some("k"));
+                assert!(matches!(
+                    &v.values[0][1],
+                    Expr::AConst(AConst {
+                        val: Value::Null,
+                        ..
+                    })
+                ));
+            }
+            v => panic!("unexpected stmt. stmt={:?}", v),
+        }
+    }
+
+    #[test]
+    fn create_table_stmt() {
+        match parse("create table t (i int not null default -1, j int null)").unwrap() {
+            Stmt::CreateTable(v) => {
+                let i = &v.table_elts[0].constraints;
+                assert!(matches!(
+                    i[..],
+                    [ColConstraint::NotNull, ColConstraint::Default(_)]
+                ));
+                let j = &v.table_elts[1].constraints;
+                assert!(matches!(j[..], [ColConstraint::Null]));
+            }
+            v => panic!("unexpected stmt. stmt={:?}", v),
+        }
+    }
+
+    #[test]
+    fn alter_table_stmt() {
+        for query in &[
+            "alter table t add column k int default 3",
+            "ALTER TABLE t ADD k int default 3",
+        ] {
+            match parse(query).unwrap() {
+                Stmt::AlterTable(v) => {
+                    assert_eq!(&*v.relation.relname, "t");
+                    match &v.cmd {
+                        AlterTableCmd::AddColumn(cf) => {
+                            assert_eq!(&*cf.colname, "k");
+                            assert!(matches!(cf.constraints[..], [ColConstraint::Default(_)]));
+                        }
+                        cmd => panic!("unexpected cmd. cmd={:?}", cmd),
+                    }
+                }
+                v => panic!("unexpected stmt. stmt={:?}", v),
+            }
+        }
+        for query in &["alter table t drop column k", "ALTER TABLE t DROP k"] {
+            match parse(query).unwrap() {
+                Stmt::AlterTable(v) => match &v.cmd {
+                    AlterTableCmd::DropColumn(colname) => assert_eq!(&**colname, "k"),
+                    cmd => panic!("unexpected cmd. cmd={:?}", cmd),
+                },
+                v => panic!("unexpected stmt. stmt={:?}", v),
+            }
+        }
+        match parse("alter table t rename to t2").unwrap() {
+            Stmt::AlterTable(v) => match &v.cmd {
+                AlterTableCmd::RenameTable(newname) => assert_eq!(&**newname, "t2"),
+                cmd => panic!("unexpected cmd. cmd={:?}", cmd),
+            },
+            v => panic!("unexpected stmt. stmt={:?}", v),
+        }
+        for query in &[
+            "alter table t rename column a to b",
+            "ALTER TABLE t RENAME a TO b",
+        ] {
+            match parse(query).unwrap() {
+                Stmt::AlterTable(v) => match &v.cmd {
+                    AlterTableCmd::RenameColumn(old, new) => {
+                        assert_eq!(&**old, "a");
+                        assert_eq!(&**new, "b");
+                    }
+                    cmd => panic!("unexpected cmd. cmd={:?}", cmd),
+                },
+                v => panic!("unexpected stmt. stmt={:?}", v),
+            }
+        }
+    }
+
+    #[test]
+    fn drop_stmt() {
+        match parse("drop table if exists t1, s.t2 cascade").unwrap() {
+            Stmt::Drop(v) => {
+                assert_eq!(v.remove_type, ObjectType::Table);
+                assert_eq!(v.behavior, DropBehavior::Cascade);
+                assert!(v.missing_ok);
+                let names: Vec<Vec<&str>> = v
+                    .objects
+                    .iter()
+                    .map(|v| v.iter().map(|v| &**v).collect())
+                    .collect();
+                assert_eq!(names, [vec!["t1"], vec!["s", "t2"]]);
+            }
+            v => panic!("unexpected stmt. stmt={:?}", v),
+        }
+        match parse("DROP SCHEMA s RESTRICT").unwrap() {
+            Stmt::Drop(v) => {
+                assert_eq!(v.remove_type, ObjectType::Schema);
+                assert_eq!(v.behavior, DropBehavior::Restrict);
+                assert!(!v.missing_ok);
+            }
+            v => panic!("unexpected stmt. stmt={:?}", v),
+        }
+        match parse("drop type t").unwrap() {
+            Stmt::Drop(v) => {
+                assert_eq!(v.remove_type, ObjectType::Type);
+                assert_eq!(v.behavior, DropBehavior::Restrict);
+            }
+            v => panic!("unexpected stmt. stmt={:?}", v),
+        }
+        match parse("create schema s").unwrap() {
+            Stmt::CreateSchema(v) => assert_eq!(&*v.schemaname, "s"),
+            v => panic!("unexpected stmt. stmt={:?}", v),
+        }
+        assert!(parse("drop schema s.t").is_err());
+    }
+
+    #[test]
+    fn define_type() {
+        let query = "CREATE TYPE s.myint (INPUT = int4in, output = int4out, \
+                     internallength = 4, alignment = 'int4')";
+        match parse(query).unwrap() {
+            Stmt::DefineType(v) => {
+                let names: Vec<&str> = v.defnames.iter().map(|v| &**v).collect();
+                assert_eq!(names, ["s", "myint"]);
+                let defs: Vec<_> = v
+                    .definition
+                    .iter()
+                    .map(|v| match v {
+                        DefElem::Unspec(v) => format!("{}={}", &*v.defname, v.arg),
+                        v => panic!("unexpected def elem. elem={:?}", v),
+                    })
+                    .collect();
+                assert_eq!(
+                    defs,
+                    [
+                        "input=int4in",
+                        "output=int4out",
+                        "internallength=4",
+                        "alignment=int4"
+                    ]
+                );
+            }
+            v => panic!("unexpected stmt. stmt={:?}", v),
+        }
+        match parse("create type t").unwrap() {
+            Stmt::DefineType(v) => assert!(v.definition.is_empty()),
+            v => panic!("unexpected stmt. stmt={:?}", v),
+        }
+        assert!(parse("create type t ()").is_err());
+    }
+
+    #[test]
+    fn vacuum_stmt() {
+        match parse("vacuum").unwrap() {
+            Stmt::Vacuum(v) => {
+                assert!(!v.full);
+                assert!(v.relation.is_none());
+            }
+            v => panic!("unexpected stmt. stmt={:?}", v),
+        }
+        match parse("VACUUM FULL s.t").unwrap() {
+            Stmt::Vacuum(v) => {
+                assert!(v.full);
+                let relation = v.relation.as_ref().unwrap();
+                assert_eq!(relation.schemaname.as_deref(), Some("s"));
+                assert_eq!(&*relation.relname, "t");
+            }
+            v => panic!("unexpected stmt. stmt={:?}", v),
+        }
+        match parse("vacuum t").unwrap() {
+            Stmt::Vacuum(v) => {
+                assert!(!v.full);
+                assert_eq!(&*v.relation.as_ref().unwrap().relname, "t");
+            }
+            v => panic!("unexpected stmt. stmt={:?}", v),
+        }
+        assert!(parse("vacuum full t, t2").is_err());
+    }
+
+    fn syntax_error(query: &str, msg: &str, pos: usize) {
+        let err = parse(query).unwrap_err();
+        assert_eq!(errcode(&err), ERRCODE_SYNTAX_ERROR);
+        assert_eq!(errposition(&err), Some(pos), "query={}", query);
+        assert_eq!(format!("{:#}", err), format!("{}: ", msg));
+    }
+
+    #[test]
+    fn malformed() {
+        syntax_error("selec 1", "syntax error at or near \"selec\"", 1);
+        syntax_error("select 1 +", "syntax error at end of input", 11);
+        syntax_error(
+            "insert into t values 1",
+            "syntax error at or near \"1\"",
+            22,
+        );
+        syntax_error(
+            "select * from t where i < 1 < 2",
+            "syntax error at or near \"<\"",
+            29,
+        );
+        syntax_error("select 'é' from t t", "syntax error at or near \"t\"", 19);
+        syntax_error("begin; commit", "syntax error at or near \"commit\"", 8);
     }
 }
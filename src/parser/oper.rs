@@ -0,0 +1,134 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// parse_oper.c, and the part of parse_func.c/parse_coerce.c used to select the operator.
+use super::syn;
+use crate::catalog::get_implicit_cast;
+use crate::catalog::namespace::SessionExt;
+use crate::catalog::FormOperator;
+use crate::utils::SessionState;
+use crate::{kbbail, Oid, OptOid, FLOAT8OID};
+
+// can_coerce_type, only the implicit casts in kb_cast are supported.
+pub fn can_coerce_type(session: &SessionState, input: Oid, target: Oid) -> anyhow::Result<bool> {
+    if input == target {
+        return Ok(true);
+    }
+    return Ok(get_implicit_cast(session, input, target)?.is_some());
+}
+
+// IsPreferredType, float8 is the preferred type of the numeric category, the other categories
+// have no preferred type yet.
+fn is_preferred_type(typ: Oid) -> bool {
+    typ == FLOAT8OID
+}
+
+fn oper_args(oper: &FormOperator) -> Vec<Oid> {
+    match oper.oprleft.0 {
+        None => vec![oper.oprright],
+        Some(left) => vec![left, oper.oprright],
+    }
+}
+
+// Keep the candidates with the most matches counted by ismatch, see func_select_candidate.
+fn keep_most_matches(
+    cands: Vec<FormOperator>,
+    inputs: &[Oid],
+    ismatch: impl Fn(Oid, Oid) -> bool,
+) -> Vec<FormOperator> {
+    let nmatches = |cand: &FormOperator| {
+        oper_args(cand)
+            .iter()
+            .zip(inputs)
+            .filter(|(&declared, &input)| ismatch(declared, input))
+            .count()
+    };
+    let most = cands.iter().map(&nmatches).max().unwrap_or(0);
+    cands
+        .into_iter()
+        .filter(|cand| nmatches(cand) == most)
+        .collect()
+}
+
+// oper_select_candidate, None if there is no candidate that the inputs can be coerced to.
+fn oper_select_candidate(
+    session: &SessionState,
+    opname: &str,
+    cands: Vec<FormOperator>,
+    inputs: &[Oid],
+) -> anyhow::Result<Option<FormOperator>> {
+    // func_match_argtypes
+    let mut matched = Vec::new();
+    for cand in cands {
+        let mut coercible = true;
+        for (&declared, &input) in oper_args(&cand).iter().zip(inputs) {
+            if !can_coerce_type(session, input, declared)? {
+                coercible = false;
+                break;
+            }
+        }
+        if coercible {
+            matched.push(cand);
+        }
+    }
+    if matched.len() <= 1 {
+        return Ok(matched.pop());
+    }
+    // func_select_candidate, the exact matches first, then the preferred types.
+    let matched = keep_most_matches(matched, inputs, |declared, input| declared == input);
+    if matched.len() == 1 {
+        return Ok(matched.first().copied());
+    }
+    let matched = keep_most_matches(matched, inputs, |declared, input| {
+        declared == input || is_preferred_type(declared)
+    });
+    if matched.len() == 1 {
+        return Ok(matched.first().copied());
+    }
+    kbbail!(
+        ERRCODE_AMBIGUOUS_FUNCTION,
+        "operator is not unique. name={} inputs={:?} candidates={:?}",
+        opname,
+        inputs,
+        matched.iter().map(|v| v.oid).collect::<Vec<_>>()
+    );
+}
+
+// oper and left_oper, the prefix operator is looked up if oprleft is None. The exact match is
+// preferred, otherwise the operator that the arguments can be implicitly coerced to is selected,
+// the caller should coerce the arguments to the argument types of the operator.
+pub fn oper(
+    session: &mut SessionState,
+    opname: &Vec<syn::StrVal>,
+    oprleft: OptOid,
+    oprright: Oid,
+) -> anyhow::Result<FormOperator> {
+    if let Ok(op) = session.opername_get_oprid(opname, oprleft, oprright) {
+        return Ok(op);
+    }
+    let cands = session.opername_get_candidates(opname, oprleft.0.is_none())?;
+    let inputs = match oprleft.0 {
+        None => vec![oprright],
+        Some(left) => vec![left, oprright],
+    };
+    let name = opname.last().map_or("", |v| v.as_str());
+    if let Some(op) = oper_select_candidate(session, name, cands, &inputs)? {
+        return Ok(op);
+    }
+    let oprleft: u32 = oprleft.into();
+    kbbail!(
+        ERRCODE_UNDEFINED_FUNCTION,
+        "operator does not exist. name={} left={} right={}",
+        name,
+        oprleft,
+        oprright
+    );
+}
@@ -9,13 +9,17 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use super::oper;
 use super::syn;
+use crate::access::lmgr::LockMode;
+use crate::access::rel;
 use crate::access::TypeDesc;
 use crate::catalog::namespace::SessionExt as NamespaceSessionExt;
-use crate::catalog::{get_proc, FormOperator};
+use crate::catalog::{get_implicit_cast, get_proc, get_typlenalign, ProKind};
 use crate::datums::Datums;
+use crate::utils::adt::get_sort_cmp;
 use crate::utils::{AttrNumber, SessionState};
-use crate::{kbbail, Oid, OptOid, FLOAT8OID, INT4OID, INT8OID, VARCHAROID};
+use crate::{kbbail, kbensure, Oid, OptOid, BOOLOID, FLOAT8OID, INT4OID, INT8OID, VARCHAROID};
 use std::convert::TryInto;
 use std::debug_assert;
 use std::mem::{align_of, size_of};
@@ -27,9 +31,23 @@ pub enum UtilityStmt<'syn, 'input> {
     VariableShow(&'syn syn::VariableShowStmt<'input>),
     DefineType(&'syn syn::DefineTypeStmt<'input>),
     CreateTable(&'syn syn::CreateTableStmt<'input>),
-    Tran(&'syn syn::TranStmt),
+    AlterTable(&'syn syn::AlterTableStmt<'input>),
+    CreateSchema(&'syn syn::CreateSchemaStmt<'input>),
+    Drop(&'syn syn::DropStmt<'input>),
+    Tran(&'syn syn::TranStmt<'input>),
     Lock(&'syn syn::LockStmt<'input>),
     Copy(&'syn syn::CopyStmt<'input>),
+    // The RETURNING list is analyzed, its Vars refer to the columns of the inserted rows.
+    Insert(&'syn syn::InsertStmt<'input>, Vec<TargetEntry>),
+    Notify(&'syn syn::NotifyStmt<'input>),
+    Listen(&'syn syn::ListenStmt<'input>),
+    Unlisten(&'syn syn::UnlistenStmt<'input>),
+    VariableReset(&'syn syn::VariableResetStmt<'input>),
+    Discard(&'syn syn::DiscardStmt),
+    ClosePortal(&'syn syn::ClosePortalStmt<'input>),
+    Explain(Query),
+    Verify(&'syn syn::VerifyStmt<'input>),
+    Vacuum(&'syn syn::VacuumStmt<'input>),
 }
 
 pub type ExprHash = md5::Digest;
@@ -76,6 +94,11 @@ impl Const {
                 -1,
                 align_of::<usize>(), /* unused */
             ),
+            // There is no unknown type to resolve the type of NULL.
+            syn::Value::Null => kbbail!(
+                ERRCODE_FEATURE_NOT_SUPPORTED,
+                "NULL constant is not supported in expressions"
+            ),
         };
         Ok(Const {
             typ: TypeDesc {
@@ -93,22 +116,19 @@ impl Const {
         let mut md5h = md5::Context::new();
         md5h.consume((9188113448065398074u64).to_ne_bytes());
         self.typ.hash(&mut md5h);
-        match self.typ.id {
-            INT4OID => {
-                md5h.consume(self.v.get_single_fixedlen::<i32>().to_ne_bytes());
-            }
-            INT8OID => {
-                md5h.consume(self.v.get_single_fixedlen::<i64>().to_ne_bytes());
-            }
-            FLOAT8OID => {
-                md5h.consume(self.v.get_single_fixedlen::<f64>().to_ne_bytes());
-            }
-            VARCHAROID => {
-                md5h.consume(self.v.get_single_varchar().as_bytes());
-            }
-            _ => {
-                unreachable!("unknown typ: {:?}", self.typ);
-            }
+        // The constant folded by the planner may be NULL or of any type.
+        if self.v.is_single_null() {
+            md5h.consume([0u8]);
+            return md5h.compute();
+        }
+        md5h.consume([1u8]);
+        match self.typ.len {
+            1 => md5h.consume(self.v.get_single_fixedlen::<u8>().to_ne_bytes()),
+            2 => md5h.consume(self.v.get_single_fixedlen::<u16>().to_ne_bytes()),
+            4 => md5h.consume(self.v.get_single_fixedlen::<u32>().to_ne_bytes()),
+            8 => md5h.consume(self.v.get_single_fixedlen::<u64>().to_ne_bytes()),
+            -1 => md5h.consume(self.v.try_get_bytea_at(0).unwrap()),
+            _ => unreachable!("unknown typ: {:?}", self.typ),
         }
         return md5h.compute();
     }
@@ -135,10 +155,73 @@ impl FuncExpr {
     }
 }
 
+// The column of the only relation in the range table.
+#[derive(Debug, Clone)]
+pub struct Var {
+    pub varattno: AttrNumber,
+    pub vartype: TypeDesc,
+    pub loc: syn::Location,
+}
+
+impl Var {
+    pub fn hash(&self) -> ExprHash {
+        let mut md5h = md5::Context::new();
+        md5h.consume((3907216352386862657u64).to_ne_bytes());
+        md5h.consume(self.varattno.get().to_ne_bytes());
+        self.vartype.hash(&mut md5h);
+        return md5h.compute();
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BoolExpr {
+    pub boolop: syn::BoolExprType,
+    pub args: Vec<Expr>,
+    pub loc: syn::Location,
+}
+
+impl BoolExpr {
+    pub fn hash(&self) -> ExprHash {
+        let mut md5h = md5::Context::new();
+        md5h.consume((6019212541870917923u64).to_ne_bytes());
+        md5h.consume((self.boolop as u8).to_ne_bytes());
+        for arg in &self.args {
+            md5h.consume(arg.hash().0);
+        }
+        return md5h.compute();
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Aggref {
+    pub aggfnoid: Oid,
+    pub aggtype: Oid,
+    pub args: Vec<Expr>,
+    pub aggstar: bool,
+    pub loc: syn::Location,
+}
+
+impl Aggref {
+    pub fn hash(&self) -> ExprHash {
+        let mut md5h = md5::Context::new();
+        md5h.consume((1563281957733254867u64).to_ne_bytes());
+        md5h.consume(self.aggfnoid.get().to_ne_bytes());
+        md5h.consume(self.aggtype.get().to_ne_bytes());
+        md5h.consume([self.aggstar as u8]);
+        for arg in &self.args {
+            md5h.consume(arg.hash().0);
+        }
+        return md5h.compute();
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum Expr {
     Const(Const),
     Func(FuncExpr),
+    Var(Var),
+    Bool(BoolExpr),
+    Aggref(Aggref),
 }
 
 impl Expr {
@@ -146,6 +229,9 @@ impl Expr {
         match self {
             Expr::Const(v) => v.typ.id,
             Expr::Func(v) => v.funcresulttype,
+            Expr::Var(v) => v.vartype.id,
+            Expr::Bool(_) => BOOLOID,
+            Expr::Aggref(v) => v.aggtype,
         }
     }
 
@@ -153,6 +239,9 @@ impl Expr {
         match self {
             Expr::Const(v) => v.hash(),
             Expr::Func(v) => v.hash(),
+            Expr::Var(v) => v.hash(),
+            Expr::Bool(v) => v.hash(),
+            Expr::Aggref(v) => v.hash(),
         }
     }
 }
@@ -162,17 +251,51 @@ pub struct TargetEntry {
     pub expr: Expr,
     pub resno: AttrNumber,
     pub resname: Option<String>,
+    // The entry is only used by ORDER BY and should not be returned to the client.
+    // The resjunk entries are always placed after all other entries.
+    pub resjunk: bool,
+}
+
+// An item of GROUP BY, typ is used to hash and compare the grouping values.
+#[derive(Debug, Clone)]
+pub struct SortGroupClause {
+    pub expr: Expr,
+    pub typ: TypeDesc,
+}
+
+// An item of ORDER BY, tleidx is the index of the sort column in the target list.
+#[derive(Debug, Clone)]
+pub struct SortClause {
+    pub tleidx: usize,
+    pub sortcoltype: Oid,
+    pub descending: bool,
+    pub nulls_first: bool,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum CmdType {
     Select,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+pub struct RangeTblEntry {
+    pub relid: Oid,
+    pub relname: String,
+    pub rel: rel::Rel,
+}
+
+#[derive(Debug, Clone)]
 pub struct Query {
     pub cmdtype: CmdType,
     pub tlist: Vec<TargetEntry>,
+    pub rtable: Vec<RangeTblEntry>,
+    // The WHERE clause.
+    pub qual: Option<Expr>,
+    pub group_clause: Vec<SortGroupClause>,
+    pub sort_clause: Vec<SortClause>,
+    // None means no LIMIT.
+    pub limit_count: Option<u64>,
+    pub has_aggs: bool,
 }
 
 pub enum Stmt<'syn, 'input> {
@@ -184,38 +307,62 @@ struct ParseState<'a> {
     sess_state: &'a mut SessionState,
     p_expr_kind: ParseExprKind,
     p_next_resno: AttrNumber,
+    p_rtable: Vec<RangeTblEntry>,
+    p_has_aggs: bool,
+    // We are transforming the arguments of an aggregate.
+    p_in_agg: bool,
+}
+
+impl ParseState<'_> {
+    fn new(sess_state: &mut SessionState) -> ParseState<'_> {
+        ParseState {
+            sess_state,
+            p_expr_kind: ParseExprKind::None,
+            p_next_resno: 1.try_into().unwrap(),
+            p_rtable: Vec::new(),
+            p_has_aggs: false,
+            p_in_agg: false,
+        }
+    }
 }
 
 #[derive(PartialEq, Clone, Copy)]
 enum ParseExprKind {
     None = 0,
     SelectTarget,
+    Where,
+    GroupBy,
+    OrderBy,
+    Limit,
+    Returning,
 }
 
-fn binary_oper_exact(
-    session: &mut SessionState,
-    opname: &Vec<syn::StrVal>,
-    oprleft: Oid,
-    oprright: Oid,
-) -> anyhow::Result<FormOperator> {
-    session.opername_get_oprid(opname, OptOid(Some(oprleft)), oprright)
-}
-
-fn oper(
-    session: &mut SessionState,
-    opname: &Vec<syn::StrVal>,
-    oprleft: Oid,
-    oprright: Oid,
-) -> anyhow::Result<FormOperator> {
-    binary_oper_exact(session, opname, oprleft, oprright)
-}
-
-fn left_oper(
-    session: &mut SessionState,
-    opname: &Vec<syn::StrVal>,
-    oprright: Oid,
-) -> anyhow::Result<FormOperator> {
-    session.opername_get_oprid(opname, OptOid(None), oprright)
+// coerce_type, the argument is wrapped in the cast function of the implicit cast.
+fn coerce_type(
+    session: &SessionState,
+    node: Expr,
+    target: Oid,
+    loc: syn::Location,
+) -> anyhow::Result<Expr> {
+    let input = node.val_type();
+    if input == target {
+        return Ok(node);
+    }
+    let castfunc = match get_implicit_cast(session, input, target)? {
+        Some(v) => v,
+        None => kbbail!(
+            ERRCODE_CANNOT_COERCE,
+            "cannot cast type {} to {}",
+            input,
+            target
+        ),
+    };
+    return Ok(Expr::Func(FuncExpr {
+        funcresulttype: target,
+        funcid: castfunc,
+        args: vec![node],
+        loc,
+    }));
 }
 
 fn make_op(
@@ -225,20 +372,20 @@ fn make_op(
     rtree: Expr,
     loc: syn::Location,
 ) -> anyhow::Result<FuncExpr> {
+    let session = &mut pstate.sess_state;
+    let rtype = rtree.val_type();
     let (op, args) = match ltree {
         None => {
-            let rtype = rtree.val_type();
-            (
-                left_oper(&mut pstate.sess_state, opname, rtype)?,
-                vec![rtree],
-            )
+            let op = oper::oper(session, opname, OptOid(None), rtype)?;
+            (op, vec![coerce_type(session, rtree, op.oprright, loc)?])
         }
         Some(ltree) => {
             let ltype = ltree.val_type();
-            let rtype = rtree.val_type();
+            let op = oper::oper(session, opname, OptOid(Some(ltype)), rtype)?;
+            let ltree = coerce_type(session, ltree, op.oprleft.0.unwrap(), loc)?;
             (
-                oper(&mut pstate.sess_state, opname, ltype, rtype)?,
-                vec![ltree, rtree],
+                op,
+                vec![ltree, coerce_type(session, rtree, op.oprright, loc)?],
             )
         }
     };
@@ -275,11 +422,192 @@ fn transform_a_expr_op(pstate: &mut ParseState, expr: &syn::AExpr) -> anyhow::Re
     }
 }
 
+fn make_var(attr: &rel::Attr, loc: syn::Location) -> Var {
+    Var {
+        varattno: attr.num,
+        vartype: attr.typ,
+        loc,
+    }
+}
+
+// transformColumnRef
+fn transform_column_ref(pstate: &mut ParseState, cref: &syn::ColumnRef) -> anyhow::Result<Var> {
+    let (relname, colname) = match cref.fields.as_slice() {
+        [syn::ColumnField::Str(colname)] => (None, colname),
+        [syn::ColumnField::Str(relname), syn::ColumnField::Str(colname)] => {
+            (Some(relname), colname)
+        }
+        _ => {
+            kbbail!(ERRCODE_SYNTAX_ERROR, "improper use of \"*\"");
+        }
+    };
+    let rte = match pstate.p_rtable.first() {
+        None => {
+            kbbail!(
+                ERRCODE_UNDEFINED_COLUMN,
+                "column \"{}\" does not exist",
+                colname
+            );
+        }
+        Some(v) => v,
+    };
+    if let Some(relname) = relname {
+        if relname.as_str() != rte.relname {
+            kbbail!(
+                ERRCODE_UNDEFINED_TABLE,
+                "missing FROM-clause entry for table \"{}\"",
+                relname
+            );
+        }
+    }
+    for attr in &rte.rel.attrs {
+        if !attr.dropped && attr.name == colname.as_str() {
+            return Ok(make_var(attr, cref.loc));
+        }
+    }
+    kbbail!(
+        ERRCODE_UNDEFINED_COLUMN,
+        "column \"{}\" does not exist",
+        colname
+    );
+}
+
 fn transform_expr_recurse(pstate: &mut ParseState, expr: &syn::Expr) -> anyhow::Result<Expr> {
     match expr {
         syn::Expr::AConst(v) => Const::try_new(v).map(|v| Expr::Const(v)),
         syn::Expr::AExpr(v) => transform_a_expr_op(pstate, v).map(|v| Expr::Func(v)),
+        syn::Expr::ColumnRef(v) => transform_column_ref(pstate, v).map(|v| Expr::Var(v)),
+        syn::Expr::BoolExpr(v) => transform_bool_expr(pstate, v).map(|v| Expr::Bool(v)),
+        syn::Expr::FuncCall(v) => transform_func_call(pstate, v).map(|v| Expr::Aggref(v)),
+    }
+}
+
+// transformFuncCall, only aggregate functions are supported.
+fn transform_func_call(pstate: &mut ParseState, fc: &syn::FuncCall) -> anyhow::Result<Aggref> {
+    // check_agglevels_and_constraints
+    match pstate.p_expr_kind {
+        ParseExprKind::Where => kbbail!(
+            ERRCODE_GROUPING_ERROR,
+            "aggregate functions are not allowed in WHERE"
+        ),
+        ParseExprKind::GroupBy => kbbail!(
+            ERRCODE_GROUPING_ERROR,
+            "aggregate functions are not allowed in GROUP BY"
+        ),
+        ParseExprKind::Limit => kbbail!(
+            ERRCODE_GROUPING_ERROR,
+            "aggregate functions are not allowed in LIMIT"
+        ),
+        ParseExprKind::Returning => kbbail!(
+            ERRCODE_GROUPING_ERROR,
+            "aggregate functions are not allowed in RETURNING"
+        ),
+        ParseExprKind::None | ParseExprKind::SelectTarget | ParseExprKind::OrderBy => {}
+    }
+    kbensure!(
+        !pstate.p_in_agg,
+        ERRCODE_GROUPING_ERROR,
+        "aggregate function calls cannot be nested"
+    );
+    pstate.p_in_agg = true;
+    let args: anyhow::Result<Vec<Expr>> = fc
+        .args
+        .iter()
+        .map(|arg| transform_expr_recurse(pstate, arg))
+        .collect();
+    pstate.p_in_agg = false;
+    let args = args?;
+    let argtypes: Vec<Oid> = args.iter().map(|v| v.val_type()).collect();
+    let funcid = pstate
+        .sess_state
+        .funcname_get_oid(&fc.funcname, &argtypes)?;
+    let proc = get_proc(pstate.sess_state, funcid)?;
+    kbensure!(
+        matches!(proc.prokind, ProKind::Agg),
+        ERRCODE_FEATURE_NOT_SUPPORTED,
+        "only aggregate functions are supported"
+    );
+    // ParseFuncOrColumn
+    kbensure!(
+        fc.agg_star || !args.is_empty(),
+        ERRCODE_WRONG_OBJECT_TYPE,
+        "{}(*) must be used to call a parameterless aggregate function",
+        fc.funcname.last().unwrap()
+    );
+    pstate.p_has_aggs = true;
+    return Ok(Aggref {
+        aggfnoid: funcid,
+        aggtype: proc.prorettype,
+        args,
+        aggstar: fc.agg_star,
+        loc: fc.loc,
+    });
+}
+
+// check_ungrouped_columns, the expressions matching one of the GROUP BY items are allowed.
+fn check_ungrouped_columns(
+    pstate: &ParseState,
+    expr: &Expr,
+    groups: &[ExprHash],
+) -> anyhow::Result<()> {
+    if groups.contains(&expr.hash()) {
+        return Ok(());
+    }
+    match expr {
+        Expr::Const(_) | Expr::Aggref(_) => {}
+        Expr::Func(v) => {
+            for arg in &v.args {
+                check_ungrouped_columns(pstate, arg, groups)?;
+            }
+        }
+        Expr::Bool(v) => {
+            for arg in &v.args {
+                check_ungrouped_columns(pstate, arg, groups)?;
+            }
+        }
+        Expr::Var(v) => {
+            let rte = &pstate.p_rtable[0];
+            let attr = &rte.rel.attrs[v.varattno.get() as usize - 1];
+            kbbail!(
+                ERRCODE_GROUPING_ERROR,
+                "column \"{}.{}\" must appear in the GROUP BY clause or be used in an aggregate function",
+                rte.relname,
+                attr.name
+            );
+        }
+    }
+    return Ok(());
+}
+
+// coerce_to_boolean
+fn coerce_to_boolean(node: Expr, constructname: &str) -> anyhow::Result<Expr> {
+    kbensure!(
+        node.val_type() == BOOLOID,
+        ERRCODE_DATATYPE_MISMATCH,
+        "argument of {} must be type boolean, not type {}",
+        constructname,
+        node.val_type()
+    );
+    return Ok(node);
+}
+
+// transformBoolExpr
+fn transform_bool_expr(pstate: &mut ParseState, expr: &syn::BoolExpr) -> anyhow::Result<BoolExpr> {
+    let opname = match expr.boolop {
+        syn::BoolExprType::And => "AND",
+        syn::BoolExprType::Or => "OR",
+        syn::BoolExprType::Not => "NOT",
+    };
+    let mut args = Vec::with_capacity(expr.args.len());
+    for arg in &expr.args {
+        let arg = transform_expr_recurse(pstate, arg)?;
+        args.push(coerce_to_boolean(arg, opname)?);
     }
+    return Ok(BoolExpr {
+        boolop: expr.boolop,
+        args,
+        loc: expr.loc,
+    });
 }
 
 fn transform_expr(
@@ -296,7 +624,15 @@ fn transform_expr(
 }
 
 // FigureColname
-fn figure_colname<'syn>(_node: &'syn syn::Expr) -> String {
+fn figure_colname<'syn>(node: &'syn syn::Expr) -> String {
+    if let syn::Expr::ColumnRef(cref) = node {
+        if let Some(syn::ColumnField::Str(colname)) = cref.fields.last() {
+            return colname.to_string();
+        }
+    }
+    if let syn::Expr::FuncCall(fc) = node {
+        return fc.funcname.last().unwrap().to_string();
+    }
     "?column?".to_string()
 }
 
@@ -312,15 +648,50 @@ fn transform_target_entry<'syn>(
         None => figure_colname(node),
         Some(v) => v,
     };
+    return make_target_entry(pstate, expr, resname);
+}
+
+fn make_target_entry(
+    pstate: &mut ParseState,
+    expr: Expr,
+    resname: String,
+) -> anyhow::Result<TargetEntry> {
     let resno = pstate.p_next_resno;
     pstate.p_next_resno = (pstate.p_next_resno.get() + 1).try_into()?;
     Ok(TargetEntry {
         resno,
         expr,
         resname: Some(resname),
+        resjunk: false,
     })
 }
 
+// ExpandColumnRefStar
+fn expand_column_ref_star(
+    pstate: &mut ParseState,
+    cref: &syn::ColumnRef,
+    out: &mut Vec<TargetEntry>,
+) -> anyhow::Result<()> {
+    let attrs = match pstate.p_rtable.first() {
+        None => {
+            kbbail!(
+                ERRCODE_SYNTAX_ERROR,
+                "SELECT * with no tables specified is not valid"
+            );
+        }
+        Some(rte) => rte.rel.attrs.clone(),
+    };
+    for attr in attrs.iter().filter(|v| !v.dropped) {
+        let var = make_var(attr, cref.loc);
+        out.push(make_target_entry(
+            pstate,
+            Expr::Var(var),
+            attr.name.clone(),
+        )?);
+    }
+    return Ok(());
+}
+
 // transformTargetList
 fn transform_target_list<'syn>(
     pstate: &mut ParseState,
@@ -329,6 +700,12 @@ fn transform_target_list<'syn>(
 ) -> anyhow::Result<Vec<TargetEntry>> {
     let mut v = Vec::<TargetEntry>::with_capacity(tlist.len());
     for target in tlist {
+        if let syn::Expr::ColumnRef(cref) = &target.val {
+            if let Some(syn::ColumnField::Star) = cref.fields.last() {
+                expand_column_ref_star(pstate, cref, &mut v)?;
+                continue;
+            }
+        }
         v.push(transform_target_entry(
             pstate,
             &target.val,
@@ -339,18 +716,236 @@ fn transform_target_list<'syn>(
     Ok(v)
 }
 
+// transformFromClause
+fn transform_from_clause<'syn>(
+    pstate: &mut ParseState,
+    from_clause: &'syn Vec<syn::RangeVar>,
+) -> anyhow::Result<Vec<RangeTblEntry>> {
+    if from_clause.len() > 1 {
+        kbbail!(ERRCODE_FEATURE_NOT_SUPPORTED, "JOIN is not supported");
+    }
+    let mut rtable = Vec::with_capacity(from_clause.len());
+    for rv in from_clause {
+        let relid = pstate.sess_state.rv_get_oid(rv, LockMode::AccessShare)?;
+        let rel = rel::getrel(pstate.sess_state, relid)?;
+        rtable.push(RangeTblEntry {
+            relid,
+            relname: rv.relname.to_string(),
+            rel,
+        });
+    }
+    Ok(rtable)
+}
+
+// transformGroupClause, the duplicate items are removed.
+fn transform_group_clause<'syn>(
+    pstate: &mut ParseState,
+    group_clause: &'syn Vec<syn::Expr>,
+) -> anyhow::Result<Vec<SortGroupClause>> {
+    let mut groups: Vec<SortGroupClause> = Vec::with_capacity(group_clause.len());
+    for item in group_clause {
+        let expr = transform_expr(pstate, item, ParseExprKind::GroupBy)?;
+        let exprhash = expr.hash();
+        if groups.iter().any(|v| v.expr.hash() == exprhash) {
+            continue;
+        }
+        let typid = expr.val_type();
+        let (len, align) = get_typlenalign(pstate.sess_state, typid)?;
+        groups.push(SortGroupClause {
+            expr,
+            typ: TypeDesc {
+                id: typid,
+                len,
+                align,
+                mode: -1,
+            },
+        });
+    }
+    return Ok(groups);
+}
+
+// findTargetlistEntrySQL92 + findTargetlistEntrySQL99, a resjunk entry is added
+// if the ORDER BY item is not in the target list.
+fn find_target_entry<'syn>(
+    pstate: &mut ParseState,
+    node: &'syn syn::Expr,
+    tlist: &mut Vec<TargetEntry>,
+) -> anyhow::Result<usize> {
+    if let syn::Expr::ColumnRef(cref) = node {
+        if let [syn::ColumnField::Str(name)] = cref.fields.as_slice() {
+            let mut found: Option<usize> = None;
+            for (idx, tle) in tlist.iter().enumerate() {
+                if tle.resjunk || tle.resname.as_deref() != Some(name.as_str()) {
+                    continue;
+                }
+                if let Some(previdx) = found {
+                    kbensure!(
+                        tlist[previdx].expr.hash() == tle.expr.hash(),
+                        ERRCODE_AMBIGUOUS_COLUMN,
+                        "ORDER BY \"{}\" is ambiguous",
+                        name
+                    );
+                } else {
+                    found = Some(idx);
+                }
+            }
+            if let Some(idx) = found {
+                return Ok(idx);
+            }
+        }
+    }
+    if let syn::Expr::AConst(syn::AConst {
+        val: syn::Value::Num(syn::NumVal::Int(pos)),
+        ..
+    }) = node
+    {
+        let pos = *pos;
+        let visible = tlist.iter().filter(|v| !v.resjunk).count();
+        kbensure!(
+            pos >= 1 && pos as usize <= visible,
+            ERRCODE_INVALID_COLUMN_REFERENCE,
+            "ORDER BY position {} is not in select list",
+            pos
+        );
+        return Ok(pos as usize - 1);
+    }
+    let expr = transform_expr(pstate, node, ParseExprKind::OrderBy)?;
+    let exprhash = expr.hash();
+    if let Some(idx) = tlist.iter().position(|v| v.expr.hash() == exprhash) {
+        return Ok(idx);
+    }
+    let mut tle = make_target_entry(pstate, expr, figure_colname(node))?;
+    tle.resjunk = true;
+    tlist.push(tle);
+    return Ok(tlist.len() - 1);
+}
+
+// transformSortClause
+fn transform_sort_clause<'syn>(
+    pstate: &mut ParseState,
+    sort_clause: &'syn Vec<syn::SortBy>,
+    tlist: &mut Vec<TargetEntry>,
+) -> anyhow::Result<Vec<SortClause>> {
+    let mut sorts = Vec::with_capacity(sort_clause.len());
+    for sortby in sort_clause {
+        let tleidx = find_target_entry(pstate, &sortby.node, tlist)?;
+        let sortcoltype = tlist[tleidx].expr.val_type();
+        kbensure!(
+            get_sort_cmp(sortcoltype).is_some(),
+            ERRCODE_UNDEFINED_FUNCTION,
+            "could not identify an ordering operator for type {}",
+            sortcoltype
+        );
+        let descending = sortby.sortby_dir == syn::SortByDir::Desc;
+        // NULLS LAST is the default for ASC order, and NULLS FIRST is the default for DESC order.
+        let nulls_first = match sortby.sortby_nulls {
+            syn::SortByNulls::Default => descending,
+            syn::SortByNulls::First => true,
+            syn::SortByNulls::Last => false,
+        };
+        sorts.push(SortClause {
+            tleidx,
+            sortcoltype,
+            descending,
+            nulls_first,
+        });
+    }
+    return Ok(sorts);
+}
+
+// transformLimitClause, only the integer constant is supported.
+fn transform_limit_clause(
+    pstate: &mut ParseState,
+    limit: &Option<syn::Expr>,
+) -> anyhow::Result<Option<u64>> {
+    let limit = match limit {
+        None => return Ok(None),
+        Some(v) => v,
+    };
+    // The unary minus is not folded, so check the negative constant here.
+    if let syn::Expr::AExpr(e) = limit {
+        if e.name.len() == 1 && &*e.name[0] == "-" {
+            if let syn::AExprOprands::One(syn::Expr::AConst(c)) = &*e.oprands {
+                kbensure!(
+                    !matches!(c.val, syn::Value::Num(_)),
+                    ERRCODE_INVALID_ROW_COUNT_IN_LIMIT_CLAUSE,
+                    "LIMIT must not be negative"
+                );
+            }
+        }
+    }
+    let limit = transform_expr(pstate, limit, ParseExprKind::Limit)?;
+    let count = match &limit {
+        Expr::Const(c) if c.typ.id == INT4OID => c.v.get_single_fixedlen::<i32>() as i64,
+        Expr::Const(c) if c.typ.id == INT8OID => c.v.get_single_fixedlen::<i64>(),
+        _ => kbbail!(
+            ERRCODE_FEATURE_NOT_SUPPORTED,
+            "argument of LIMIT must be an integer constant"
+        ),
+    };
+    kbensure!(
+        count >= 0,
+        ERRCODE_INVALID_ROW_COUNT_IN_LIMIT_CLAUSE,
+        "LIMIT must not be negative"
+    );
+    return Ok(Some(count as u64));
+}
+
 // transformSelectStmt
 fn transform_select_stmt<'syn, 'input>(
     pstate: &mut ParseState,
     stmt: &'syn syn::SelectStmt<'input>,
 ) -> anyhow::Result<Query> {
-    let tlist = transform_target_list(pstate, &stmt.tlist, ParseExprKind::SelectTarget)?;
+    pstate.p_rtable = transform_from_clause(pstate, &stmt.from_clause)?;
+    let mut tlist = transform_target_list(pstate, &stmt.tlist, ParseExprKind::SelectTarget)?;
+    let qual = match &stmt.where_clause {
+        None => None,
+        Some(v) => {
+            let qual = transform_expr(pstate, v, ParseExprKind::Where)?;
+            Some(coerce_to_boolean(qual, "WHERE")?)
+        }
+    };
+    let group_clause = transform_group_clause(pstate, &stmt.group_clause)?;
+    let sort_clause = transform_sort_clause(pstate, &stmt.sort_clause, &mut tlist)?;
+    let limit_count = transform_limit_clause(pstate, &stmt.limit_count)?;
+    if pstate.p_has_aggs || !group_clause.is_empty() {
+        let groups: Vec<ExprHash> = group_clause.iter().map(|v| v.expr.hash()).collect();
+        for target in &tlist {
+            check_ungrouped_columns(pstate, &target.expr, &groups)?;
+        }
+    }
     Ok(Query {
         cmdtype: CmdType::Select,
         tlist,
+        qual,
+        group_clause,
+        sort_clause,
+        limit_count,
+        has_aggs: pstate.p_has_aggs,
+        rtable: std::mem::take(&mut pstate.p_rtable),
     })
 }
 
+// transformReturningList
+fn transform_returning_list<'syn>(
+    pstate: &mut ParseState,
+    stmt: &'syn syn::InsertStmt,
+) -> anyhow::Result<Vec<TargetEntry>> {
+    if stmt.returning.is_empty() {
+        return Ok(Vec::new());
+    }
+    let relid = pstate
+        .sess_state
+        .rv_get_oid(&stmt.relation, LockMode::RowExclusive)?;
+    let rel = rel::getrel(pstate.sess_state, relid)?;
+    pstate.p_rtable = vec![RangeTblEntry {
+        relid,
+        relname: stmt.relation.relname.to_string(),
+        rel,
+    }];
+    return transform_target_list(pstate, &stmt.returning, ParseExprKind::Returning);
+}
+
 // parse_analyze
 pub fn kb_analyze<'syn, 'input>(
     state: &mut SessionState,
@@ -362,16 +957,33 @@ pub fn kb_analyze<'syn, 'input>(
         syn::Stmt::DefineType(v) => Ok(Stmt::Utility(UtilityStmt::DefineType(v))),
         syn::Stmt::Tran(v) => Ok(Stmt::Utility(UtilityStmt::Tran(v))),
         syn::Stmt::Select(v) => {
-            let mut pstate = ParseState {
-                sess_state: state,
-                p_expr_kind: ParseExprKind::None,
-                p_next_resno: 1.try_into().unwrap(),
-            };
+            let mut pstate = ParseState::new(state);
             transform_select_stmt(&mut pstate, v).map(|v| Stmt::Optimizable(v))
         }
+        syn::Stmt::Explain(v) => {
+            let mut pstate = ParseState::new(state);
+            transform_select_stmt(&mut pstate, &v.query)
+                .map(|v| Stmt::Utility(UtilityStmt::Explain(v)))
+        }
         syn::Stmt::CreateTable(v) => Ok(Stmt::Utility(UtilityStmt::CreateTable(v))),
+        syn::Stmt::AlterTable(v) => Ok(Stmt::Utility(UtilityStmt::AlterTable(v))),
+        syn::Stmt::CreateSchema(v) => Ok(Stmt::Utility(UtilityStmt::CreateSchema(v))),
+        syn::Stmt::Drop(v) => Ok(Stmt::Utility(UtilityStmt::Drop(v))),
         syn::Stmt::Lock(v) => Ok(Stmt::Utility(UtilityStmt::Lock(v))),
         syn::Stmt::Copy(v) => Ok(Stmt::Utility(UtilityStmt::Copy(v))),
+        syn::Stmt::Insert(v) => {
+            let mut pstate = ParseState::new(state);
+            let returning = transform_returning_list(&mut pstate, v)?;
+            Ok(Stmt::Utility(UtilityStmt::Insert(v, returning)))
+        }
+        syn::Stmt::Notify(v) => Ok(Stmt::Utility(UtilityStmt::Notify(v))),
+        syn::Stmt::Listen(v) => Ok(Stmt::Utility(UtilityStmt::Listen(v))),
+        syn::Stmt::Unlisten(v) => Ok(Stmt::Utility(UtilityStmt::Unlisten(v))),
+        syn::Stmt::VariableReset(v) => Ok(Stmt::Utility(UtilityStmt::VariableReset(v))),
+        syn::Stmt::Discard(v) => Ok(Stmt::Utility(UtilityStmt::Discard(v))),
+        syn::Stmt::ClosePortal(v) => Ok(Stmt::Utility(UtilityStmt::ClosePortal(v))),
+        syn::Stmt::Verify(v) => Ok(Stmt::Utility(UtilityStmt::Verify(v))),
+        syn::Stmt::Vacuum(v) => Ok(Stmt::Utility(UtilityStmt::Vacuum(v))),
         syn::Stmt::Empty => unreachable!(),
     }
 }
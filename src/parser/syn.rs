@@ -87,6 +87,7 @@ impl Display for NumVal<'_> {
 pub enum Value<'input> {
     Num(NumVal<'input>),
     Str(StrVal<'input>),
+    Null,
 }
 
 // Display is used in get_relopt().
@@ -95,11 +96,12 @@ impl Display for Value<'_> {
         match self {
             Value::Str(s) => write!(f, "{}", s),
             Value::Num(s) => write!(f, "{}", s),
+            Value::Null => write!(f, "NULL"),
         }
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Default)]
 pub struct Location {
     pub s: usize,
     pub e: usize,
@@ -123,10 +125,13 @@ pub struct VariableShowStmt<'input> {
 }
 
 #[derive(Debug)]
-pub enum TranStmt {
+pub enum TranStmt<'input> {
     Begin,
     Abort,
     Commit,
+    Savepoint(StrVal<'input>),
+    Release(StrVal<'input>),
+    RollbackTo(StrVal<'input>),
 }
 
 #[derive(Debug)]
@@ -135,17 +140,32 @@ pub enum Stmt<'input> {
     VariableShow(VariableShowStmt<'input>),
     DefineType(DefineTypeStmt<'input>),
     Select(SelectStmt<'input>),
-    Tran(TranStmt),
+    Tran(TranStmt<'input>),
     CreateTable(CreateTableStmt<'input>),
+    AlterTable(AlterTableStmt<'input>),
+    CreateSchema(CreateSchemaStmt<'input>),
+    Drop(DropStmt<'input>),
     Lock(LockStmt<'input>),
     Copy(CopyStmt<'input>),
+    Insert(InsertStmt<'input>),
+    Notify(NotifyStmt<'input>),
+    Listen(ListenStmt<'input>),
+    Unlisten(UnlistenStmt<'input>),
+    VariableReset(VariableResetStmt<'input>),
+    Discard(DiscardStmt),
+    ClosePortal(ClosePortalStmt<'input>),
+    Explain(ExplainStmt<'input>),
+    Verify(VerifyStmt<'input>),
+    Vacuum(VacuumStmt<'input>),
     Empty,
 }
 
 impl Stmt<'_> {
     pub fn is_tran_exit(&self) -> bool {
         match self {
-            Stmt::Tran(TranStmt::Commit) | Stmt::Tran(TranStmt::Abort) => true,
+            Stmt::Tran(TranStmt::Commit)
+            | Stmt::Tran(TranStmt::Abort)
+            | Stmt::Tran(TranStmt::RollbackTo(_)) => true,
             _ => false,
         }
     }
@@ -153,7 +173,7 @@ impl Stmt<'_> {
 
 #[cfg(test)]
 mod syn_test {
-    use super::{Stmt, TranStmt};
+    use super::{Stmt, StrVal, TranStmt};
 
     #[test]
     fn f() {
@@ -163,6 +183,10 @@ mod syn_test {
         assert!(s.is_tran_exit());
         let s = Stmt::Tran(TranStmt::Begin);
         assert!(!s.is_tran_exit());
+        let s = Stmt::Tran(TranStmt::RollbackTo(StrVal::InPlace("sp")));
+        assert!(s.is_tran_exit());
+        let s = Stmt::Tran(TranStmt::Release(StrVal::InPlace("sp")));
+        assert!(!s.is_tran_exit());
     }
 }
 
@@ -195,6 +219,30 @@ pub struct DefineTypeStmt<'input> {
 pub enum Expr<'input> {
     AConst(AConst<'input>),
     AExpr(AExpr<'input>),
+    ColumnRef(ColumnRef<'input>),
+    BoolExpr(BoolExpr<'input>),
+    FuncCall(FuncCall<'input>),
+}
+
+#[derive(Debug)]
+pub struct FuncCall<'input> {
+    pub funcname: Vec<StrVal<'input>>,
+    pub args: Vec<Expr<'input>>,
+    // The argument was really '*'.
+    pub agg_star: bool,
+    pub loc: Location,
+}
+
+#[derive(Debug)]
+pub enum ColumnField<'input> {
+    Str(StrVal<'input>),
+    Star,
+}
+
+#[derive(Debug)]
+pub struct ColumnRef<'input> {
+    pub fields: Vec<ColumnField<'input>>,
+    pub loc: Location,
 }
 
 #[derive(Debug)]
@@ -216,6 +264,46 @@ pub struct AExpr<'input> {
     pub loc: Location,
 }
 
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum BoolExprType {
+    And,
+    Or,
+    Not,
+}
+
+#[derive(Debug)]
+pub struct BoolExpr<'input> {
+    pub boolop: BoolExprType,
+    pub args: Vec<Expr<'input>>,
+    pub loc: Location,
+}
+
+// makeAndExpr, makeOrExpr. `a AND b AND c` is flattened into one BoolExpr with three args.
+pub fn make_bool_expr<'input>(
+    boolop: BoolExprType,
+    l: Expr<'input>,
+    r: Expr<'input>,
+    loc: Location,
+) -> Expr<'input> {
+    if let Expr::BoolExpr(mut l) = l {
+        if l.boolop == boolop {
+            l.args.push(r);
+            l.loc = loc;
+            return Expr::BoolExpr(l);
+        }
+        return Expr::BoolExpr(BoolExpr {
+            boolop,
+            args: vec![Expr::BoolExpr(l), r],
+            loc,
+        });
+    }
+    return Expr::BoolExpr(BoolExpr {
+        boolop,
+        args: vec![l, r],
+        loc,
+    });
+}
+
 #[derive(Debug)]
 pub struct InsertResTarget<'input> {
     pub name: Option<StrVal<'input>>,
@@ -233,6 +321,33 @@ pub struct ResTarget<'input> {
 pub struct SelectStmt<'input> {
     // tlist may be empty. `select from table` is valid.
     pub tlist: Vec<ResTarget<'input>>,
+    pub from_clause: Vec<RangeVar<'input>>,
+    pub where_clause: Option<Expr<'input>>,
+    pub group_clause: Vec<Expr<'input>>,
+    pub sort_clause: Vec<SortBy<'input>>,
+    pub limit_count: Option<Expr<'input>>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SortByDir {
+    Default,
+    Asc,
+    Desc,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SortByNulls {
+    Default,
+    First,
+    Last,
+}
+
+#[derive(Debug)]
+pub struct SortBy<'input> {
+    pub node: Expr<'input>,
+    pub sortby_dir: SortByDir,
+    pub sortby_nulls: SortByNulls,
+    pub loc: Location,
 }
 
 #[derive(Debug)]
@@ -261,10 +376,19 @@ pub fn system_type_name(name: &str) -> TypeName<'_> {
     }
 }
 
+// PG Constraint, only the column constraints are supported.
+#[derive(Debug)]
+pub enum ColConstraint<'input> {
+    Null,
+    NotNull,
+    Default(Expr<'input>),
+}
+
 #[derive(Debug)]
 pub struct ColumnDef<'input> {
     pub colname: StrVal<'input>,
     pub typename: TypeName<'input>,
+    pub constraints: Vec<ColConstraint<'input>>,
 }
 
 // PG CreateStmt
@@ -275,6 +399,52 @@ pub struct CreateTableStmt<'input> {
     pub opts: Vec<DefElem<'input>>,
 }
 
+// PG AlterTableCmd and RenameStmt, only ADD COLUMN, DROP COLUMN and RENAME are supported.
+#[derive(Debug)]
+pub enum AlterTableCmd<'input> {
+    AddColumn(ColumnDef<'input>),
+    DropColumn(StrVal<'input>),
+    // The new name of the table.
+    RenameTable(StrVal<'input>),
+    // The old and new name of the column.
+    RenameColumn(StrVal<'input>, StrVal<'input>),
+}
+
+// PG AlterTableStmt, only one command is supported.
+#[derive(Debug)]
+pub struct AlterTableStmt<'input> {
+    pub relation: RangeVar<'input>,
+    pub cmd: AlterTableCmd<'input>,
+}
+
+#[derive(Debug)]
+pub struct CreateSchemaStmt<'input> {
+    pub schemaname: StrVal<'input>,
+}
+
+// PG ObjectType, only the objects can be dropped are here.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ObjectType {
+    Table,
+    Type,
+    Schema,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DropBehavior {
+    Restrict,
+    Cascade,
+}
+
+#[derive(Debug)]
+pub struct DropStmt<'input> {
+    // The qualified names of the objects.
+    pub objects: Vec<Vec<StrVal<'input>>>,
+    pub remove_type: ObjectType,
+    pub behavior: DropBehavior,
+    pub missing_ok: bool,
+}
+
 #[derive(Debug)]
 pub struct LockStmt<'input> {
     pub rels: Vec<RangeVar<'input>>,
@@ -289,3 +459,69 @@ pub struct CopyStmt<'input> {
     // delimiters, parallel, null, format=csv
     pub opts: Vec<DefElem<'input>>,
 }
+
+#[derive(Debug)]
+pub struct NotifyStmt<'input> {
+    pub conditionname: StrVal<'input>,
+    pub payload: Option<StrVal<'input>>,
+}
+
+#[derive(Debug)]
+pub struct ListenStmt<'input> {
+    pub conditionname: StrVal<'input>,
+}
+
+#[derive(Debug)]
+pub struct UnlistenStmt<'input> {
+    // None means UNLISTEN *
+    pub conditionname: Option<StrVal<'input>>,
+}
+
+// The VAR_RESET and VAR_RESET_ALL kinds of VariableSetStmt.
+#[derive(Debug)]
+pub enum VariableResetStmt<'input> {
+    Var(StrVal<'input>),
+    All,
+    // SET SESSION AUTHORIZATION DEFAULT
+    SessionAuthorization,
+}
+
+// DiscardMode, only DISCARD ALL is supported.
+#[derive(Debug)]
+pub enum DiscardStmt {
+    All,
+}
+
+#[derive(Debug)]
+pub struct ClosePortalStmt<'input> {
+    // None means CLOSE ALL
+    pub portalname: Option<StrVal<'input>>,
+}
+
+#[derive(Debug)]
+pub struct InsertStmt<'input> {
+    pub relation: RangeVar<'input>,
+    // The target columns, empty means all columns in order.
+    pub cols: Vec<StrVal<'input>>,
+    // Each element is a row of VALUES.
+    pub values: Vec<Vec<Expr<'input>>>,
+    // Empty means no RETURNING.
+    pub returning: Vec<ResTarget<'input>>,
+}
+
+#[derive(Debug)]
+pub struct ExplainStmt<'input> {
+    pub query: SelectStmt<'input>,
+}
+
+#[derive(Debug)]
+pub struct VerifyStmt<'input> {
+    pub relation: RangeVar<'input>,
+}
+
+// VACUUM [FULL] [table], all tables of the database are vacuumed if no table is given.
+#[derive(Debug)]
+pub struct VacuumStmt<'input> {
+    pub full: bool,
+    pub relation: Option<RangeVar<'input>>,
+}
@@ -10,6 +10,7 @@ WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 See the License for the specific language governing permissions and
 limitations under the License.
 */
+use crate::utils::encoding::{self, Encoding};
 use crate::utils::ser;
 use crate::{errctx, kbanyhow, kbensure, Oid, OptOid, SockReader, SockWriter};
 use crate::{guc, AttrNumber};
@@ -24,22 +25,51 @@ use std::str::from_utf8;
 mod errcodes;
 pub use errcodes::*;
 
-fn read_body(stream: &mut SockReader, content: &mut Vec<u8>) -> std::io::Result<()> {
-    let len = stream.read_u32::<NetworkEndian>()?;
-    content.resize(len as usize - size_of::<u32>(), 0);
-    stream.read_exact(content.as_mut_slice())?;
+// PQ_LARGE_MESSAGE_LIMIT and MAX_STARTUP_PACKET_LENGTH.
+pub const MAX_MESSAGE_LEN: u32 = 0x3fffffff;
+pub const MAX_STARTUP_MESSAGE_LEN: u32 = 10000;
+
+// pq_getmessage(), the length is checked before any allocation since it comes from the client.
+fn read_body<R: Read>(stream: &mut R, content: &mut Vec<u8>, maxlen: u32) -> anyhow::Result<()> {
+    let len = stream
+        .read_u32::<NetworkEndian>()
+        .with_context(|| errctx!(ERRCODE_CONNECTION_FAILURE, "read message length failed"))?;
+    // The length includes itself.
+    kbensure!(
+        len as usize >= size_of::<u32>() && len <= maxlen,
+        ERRCODE_PROTOCOL_VIOLATION,
+        "invalid message length. len={} maxlen={}",
+        len,
+        maxlen
+    );
+    // The body is read as it arrives instead of allocating the whole len in advance.
+    let bodylen = (len as usize - size_of::<u32>()) as u64;
+    content.clear();
+    stream
+        .take(bodylen)
+        .read_to_end(content)
+        .with_context(|| errctx!(ERRCODE_CONNECTION_FAILURE, "read message body failed"))?;
+    kbensure!(
+        content.len() as u64 == bodylen,
+        ERRCODE_CONNECTION_FAILURE,
+        "incomplete message. len={} read={}",
+        len,
+        content.len()
+    );
     return Ok(());
 }
 
-pub fn read_message(stream: &mut SockReader) -> std::io::Result<(i8, Vec<u8>)> {
+pub fn read_message(stream: &mut SockReader) -> anyhow::Result<(i8, Vec<u8>)> {
     let mut content = Vec::new();
-    let msgtype = stream.read_i8()?;
-    read_body(stream, &mut content)?;
+    let msgtype = stream
+        .read_i8()
+        .with_context(|| errctx!(ERRCODE_CONNECTION_FAILURE, "read message type failed"))?;
+    read_body(stream, &mut content, MAX_MESSAGE_LEN)?;
     Ok((msgtype, content))
 }
 
-pub fn read_startup_message(stream: &mut SockReader, content: &mut Vec<u8>) -> std::io::Result<()> {
-    read_body(stream, content)
+pub fn read_startup_message(stream: &mut SockReader, content: &mut Vec<u8>) -> anyhow::Result<()> {
+    read_body(stream, content, MAX_STARTUP_MESSAGE_LEN)
 }
 
 #[derive(Debug)]
@@ -74,8 +104,19 @@ impl SSLRequest {
 }
 
 #[repr(i8)]
+#[derive(Clone, Copy)]
 pub enum MsgType {
     Query = 'Q' as i8,
+    Parse = 'P' as i8,
+    Bind = 'B' as i8,
+    Describe = 'D' as i8,
+    Execute = 'E' as i8,
+    Close = 'C' as i8,
+    Sync = 'S' as i8,
+    Flush = 'H' as i8,
+    PasswordMessage = 'p' as i8,
+    CopyData = 'd' as i8,
+    CopyDone = 'c' as i8,
     Terminate = 'X' as i8,
     EOF = -1,
 }
@@ -85,13 +126,32 @@ pub fn write_message<T: Message>(stream: &mut SockWriter, msg: &T) {
     let _ = stream.write_all(&msg.serialize());
 }
 
+pub fn write_message_to<T: Message>(stream: &mut SockWriter, msg: &T, enc: Encoding) {
+    // ignore error, just as PostgreSQL.
+    let _ = stream.write_all(&msg.serialize_to(enc));
+}
+
 pub trait Message {
     fn serialize(&self) -> Vec<u8>;
+
+    // Serializes the message for the client whose client_encoding is enc, the text in the
+    // message is converted to enc. Only the message carrying the text need to override it.
+    fn serialize_to(&self, _enc: Encoding) -> Vec<u8> {
+        self.serialize()
+    }
+}
+
+// pq_sendstring, the untranslatable char is replaced since these messages can not fail.
+fn ser_cstr_to(out: &mut Vec<u8>, buf: &str, enc: Encoding) {
+    out.extend_from_slice(&encoding::to_client_lossy(enc, buf));
+    out.push(0);
 }
 
 const STARTUP_USER_PARAM: &str = "user";
 const STARTUP_DATABASE_PARAM: &str = "database";
 const STARTUP_CLIENT_ENCODING: &str = "client_encoding";
+const STARTUP_REPLICATION: &str = "replication";
+const STARTUP_APPLICATION_NAME: &str = "application_name";
 
 #[derive(Debug)]
 pub struct StartupMessage<'a> {
@@ -110,7 +170,8 @@ fn find(d: &[u8], start: usize, val: u8) -> i64 {
     -1
 }
 
-fn read_cstr<'a>(cursor: &mut Cursor<&'a [u8]>) -> anyhow::Result<&'a str> {
+// The string is left in the client encoding, the cursor is untouched on error.
+fn read_cstr_bytes<'a>(cursor: &mut Cursor<&'a [u8]>) -> anyhow::Result<&'a [u8]> {
     let data = cursor.get_ref();
     let idx = find(data, cursor.position() as usize, 0);
     kbensure!(
@@ -119,19 +180,88 @@ fn read_cstr<'a>(cursor: &mut Cursor<&'a [u8]>) -> anyhow::Result<&'a str> {
         "invalid string in message"
     );
     let cstrdata = &data[cursor.position() as usize..idx as usize];
+    cursor.set_position(idx as u64 + 1);
+    Ok(cstrdata)
+}
+
+fn read_cstr<'a>(cursor: &mut Cursor<&'a [u8]>) -> anyhow::Result<&'a str> {
+    let pos = cursor.position();
+    let cstrdata = read_cstr_bytes(cursor)?;
     let retstr = from_utf8(cstrdata).with_context(|| {
         errctx!(
             ERRCODE_PROTOCOL_VIOLATION,
             "invalid UTF-8 string in message"
         )
-    })?;
-    cursor.set_position(idx as u64 + 1);
-    Ok(retstr)
+    });
+    if retstr.is_err() {
+        cursor.set_position(pos);
+    }
+    retstr
+}
+
+fn insufficient_data() -> anyhow::Error {
+    kbanyhow!(
+        ERRCODE_PROTOCOL_VIOLATION,
+        "insufficient data left in message"
+    )
+}
+
+// pq_getmsgint(cursor, 2)
+fn read_i16(cursor: &mut Cursor<&[u8]>) -> anyhow::Result<i16> {
+    cursor
+        .read_i16::<NetworkEndian>()
+        .map_err(|_| insufficient_data())
+}
+
+// pq_getmsgint(cursor, 4)
+fn read_i32(cursor: &mut Cursor<&[u8]>) -> anyhow::Result<i32> {
+    cursor
+        .read_i32::<NetworkEndian>()
+        .map_err(|_| insufficient_data())
+}
+
+// The count of the array in message, such as the number of the parameters in Bind.
+fn read_count(cursor: &mut Cursor<&[u8]>) -> anyhow::Result<usize> {
+    let cnt = read_i16(cursor)?;
+    kbensure!(
+        cnt >= 0,
+        ERRCODE_PROTOCOL_VIOLATION,
+        "invalid array length in message. len={}",
+        cnt
+    );
+    return Ok(cnt as usize);
+}
+
+// pq_getmsgbytes
+fn read_bytes<'a>(cursor: &mut Cursor<&'a [u8]>, len: usize) -> anyhow::Result<&'a [u8]> {
+    let data: &'a [u8] = cursor.get_ref();
+    let start = cursor.position() as usize;
+    if data.len() - start < len {
+        return Err(insufficient_data());
+    }
+    cursor.set_position((start + len) as u64);
+    return Ok(&data[start..start + len]);
+}
+
+// pq_getmsgend
+fn read_end(cursor: &Cursor<&[u8]>) -> anyhow::Result<()> {
+    kbensure!(
+        cursor.position() as usize == cursor.get_ref().len(),
+        ERRCODE_PROTOCOL_VIOLATION,
+        "invalid message format"
+    );
+    return Ok(());
 }
 
 impl StartupMessage<'_> {
     pub fn deserialize(d: &[u8]) -> anyhow::Result<StartupMessage<'_>> {
         //log::trace!("StartupMessage deserialize. d={:?}", d);
+        kbensure!(
+            d.len() >= 4,
+            ERRCODE_PROTOCOL_VIOLATION,
+            "StartupMessage: invalid length. len={}",
+            d.len()
+        );
         let mut cursor = Cursor::new(d);
         let major_ver = cursor.read_u16::<NetworkEndian>()?;
         let minor_ver = cursor.read_u16::<NetworkEndian>()?;
@@ -165,11 +295,54 @@ impl StartupMessage<'_> {
             .map_or_else(|| self.user(), |v| *v)
     }
 
-    pub fn check_client_encoding(&self, expected: &str) -> bool {
-        self.params.get(&STARTUP_CLIENT_ENCODING).map_or(
-            true, /* pgbench don't send STARTUP_CLIENT_ENCODING */
-            |v| v.eq_ignore_ascii_case(expected),
-        )
+    // Only the physical replication is supported, so replication=database is not allowed.
+    pub fn replication(&self) -> bool {
+        self.params.get(&STARTUP_REPLICATION).map_or(false, |v| {
+            v.eq_ignore_ascii_case("true")
+                || v.eq_ignore_ascii_case("on")
+                || v.eq_ignore_ascii_case("yes")
+                || *v == "1"
+        })
+    }
+
+    pub fn application_name(&self) -> Option<&str> {
+        self.params.get(&STARTUP_APPLICATION_NAME).copied()
+    }
+
+    // pgbench don't send STARTUP_CLIENT_ENCODING.
+    pub fn client_encoding(&self) -> Option<&str> {
+        self.params.get(&STARTUP_CLIENT_ENCODING).copied()
+    }
+}
+
+impl Message for StartupMessage<'_> {
+    fn serialize(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(64);
+        ser::ser_be_u32(&mut out, 0);
+        ser::ser_be_u16(&mut out, self.major_ver);
+        ser::ser_be_u16(&mut out, self.minor_ver);
+        for (name, val) in &self.params {
+            ser::ser_cstr(&mut out, name);
+            ser::ser_cstr(&mut out, val);
+        }
+        out.push(0);
+        let msglen = out.len();
+        ser::ser_be_u32_at(&mut out, 0, msglen as u32);
+        return out;
+    }
+}
+
+impl<'a> StartupMessage<'a> {
+    pub fn new(params: HashMap<&'a str, &'a str>) -> anyhow::Result<StartupMessage<'a>> {
+        let user = params
+            .get(&STARTUP_USER_PARAM)
+            .ok_or_else(|| kbanyhow!(ERRCODE_PROTOCOL_VIOLATION, "StartupMessage: no user key"))?;
+        return Ok(StartupMessage {
+            major_ver: 3,
+            minor_ver: 0,
+            username: user,
+            params,
+        });
     }
 }
 
@@ -182,7 +355,7 @@ pub struct ErrFields<'a> {
     // pub V: Option<&'a str>,
     // pub D: Option<&'a str>,
     // pub H: Option<&'a str>,
-    // pub P: Option<&'a str>,
+    pub position: Option<&'a str>,
     // pub p: Option<&'a str>,
     // pub q: Option<&'a str>,
     // pub W: Option<&'a str>,
@@ -196,14 +369,14 @@ pub struct ErrFields<'a> {
     // pub R: Option<&'a str>,
 }
 
-fn serialize_errmsg(typ: u8, fields: &ErrFields) -> Vec<u8> {
+fn serialize_errmsg(typ: u8, fields: &ErrFields, enc: Encoding) -> Vec<u8> {
     let mut out = Vec::<u8>::with_capacity(32);
     out.resize(5, typ);
     macro_rules! write_field {
         ($field: ident, $fieldtype: literal) => {
             if let Some(v) = fields.$field {
                 out.push($fieldtype as u8);
-                ser::ser_cstr(&mut out, v);
+                ser_cstr_to(&mut out, v, enc);
             }
         };
     }
@@ -213,7 +386,7 @@ fn serialize_errmsg(typ: u8, fields: &ErrFields) -> Vec<u8> {
     // write_field!(V, 'V');
     // write_field!(D, 'D');
     // write_field!(H, 'H');
-    // write_field!(P, 'P');
+    write_field!(position, 'P');
     // write_field!(p, 'p');
     // write_field!(q, 'q');
     // write_field!(W, 'W');
@@ -257,7 +430,25 @@ impl<'a> ErrorResponse<'a> {
 
 impl<'a> Message for ErrorResponse<'a> {
     fn serialize(&self) -> Vec<u8> {
-        serialize_errmsg('E' as u8, &self.fields)
+        self.serialize_to(Encoding::Utf8)
+    }
+
+    fn serialize_to(&self, enc: Encoding) -> Vec<u8> {
+        serialize_errmsg('E' as u8, &self.fields, enc)
+    }
+}
+
+pub struct NoticeResponse<'a> {
+    pub fields: ErrFields<'a>,
+}
+
+impl Message for NoticeResponse<'_> {
+    fn serialize(&self) -> Vec<u8> {
+        self.serialize_to(Encoding::Utf8)
+    }
+
+    fn serialize_to(&self, enc: Encoding) -> Vec<u8> {
+        serialize_errmsg('N' as u8, &self.fields, enc)
     }
 }
 
@@ -273,6 +464,159 @@ impl Message for AuthenticationOk {
     }
 }
 
+// The salt is random so that the response of the client can not be replayed.
+pub struct AuthenticationMD5Password {
+    pub salt: [u8; 4],
+}
+
+impl Message for AuthenticationMD5Password {
+    fn serialize(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(13);
+        out.push('R' as u8);
+        ser::ser_be_u32(&mut out, 12);
+        ser::ser_be_u32(&mut out, 5);
+        out.extend_from_slice(&self.salt);
+        return out;
+    }
+}
+
+#[derive(Debug)]
+pub struct PasswordMessage<'a> {
+    pub password: &'a str,
+}
+
+impl PasswordMessage<'_> {
+    pub fn deserialize(d: &[u8]) -> anyhow::Result<PasswordMessage<'_>> {
+        let mut cursor = Cursor::new(d);
+        let password = read_cstr(&mut cursor)?;
+        read_end(&cursor)?;
+        return Ok(PasswordMessage { password });
+    }
+}
+
+impl Message for PasswordMessage<'_> {
+    fn serialize(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(5 + self.password.len() + 1);
+        out.resize(5, 'p' as u8);
+        ser::ser_cstr(&mut out, self.password);
+        let msglen = out.len() - 1;
+        ser::ser_be_u32_at(&mut out, 1, msglen as u32);
+        return out;
+    }
+}
+
+// The SASL mechanisms supported by the server, the client picks one of them.
+pub struct AuthenticationSASL<'a> {
+    pub mechanisms: &'a [&'a str],
+}
+
+impl Message for AuthenticationSASL<'_> {
+    fn serialize(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(64);
+        out.resize(5, 'R' as u8);
+        ser::ser_be_u32(&mut out, 10);
+        for mechanism in self.mechanisms {
+            ser::ser_cstr(&mut out, mechanism);
+        }
+        out.push(0);
+        let msglen = out.len() - 1;
+        ser::ser_be_u32_at(&mut out, 1, msglen as u32);
+        return out;
+    }
+}
+
+fn ser_sasl_data(code: u32, data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(9 + data.len());
+    out.push('R' as u8);
+    ser::ser_be_u32(&mut out, 8 + data.len() as u32);
+    ser::ser_be_u32(&mut out, code);
+    out.extend_from_slice(data);
+    return out;
+}
+
+pub struct AuthenticationSASLContinue<'a> {
+    pub data: &'a [u8],
+}
+
+impl Message for AuthenticationSASLContinue<'_> {
+    fn serialize(&self) -> Vec<u8> {
+        return ser_sasl_data(11, self.data);
+    }
+}
+
+pub struct AuthenticationSASLFinal<'a> {
+    pub data: &'a [u8],
+}
+
+impl Message for AuthenticationSASLFinal<'_> {
+    fn serialize(&self) -> Vec<u8> {
+        return ser_sasl_data(12, self.data);
+    }
+}
+
+// The first 'p' message of the SASL exchange, data is None if the length is -1.
+#[derive(Debug)]
+pub struct SASLInitialResponse<'a> {
+    pub mechanism: &'a str,
+    pub data: Option<&'a [u8]>,
+}
+
+impl SASLInitialResponse<'_> {
+    pub fn deserialize(d: &[u8]) -> anyhow::Result<SASLInitialResponse<'_>> {
+        let mut cursor = Cursor::new(d);
+        let mechanism = read_cstr(&mut cursor)?;
+        let len = read_i32(&mut cursor)?;
+        let data = if len < 0 {
+            None
+        } else {
+            Some(read_bytes(&mut cursor, len as usize)?)
+        };
+        read_end(&cursor)?;
+        return Ok(SASLInitialResponse { mechanism, data });
+    }
+}
+
+impl Message for SASLInitialResponse<'_> {
+    fn serialize(&self) -> Vec<u8> {
+        let datalen = self.data.map_or(0, |v| v.len());
+        let mut out = Vec::with_capacity(5 + self.mechanism.len() + 5 + datalen);
+        out.resize(5, 'p' as u8);
+        ser::ser_cstr(&mut out, self.mechanism);
+        match self.data {
+            None => ser::ser_be_i32(&mut out, -1),
+            Some(data) => {
+                ser::ser_be_i32(&mut out, data.len() as i32);
+                out.extend_from_slice(data);
+            }
+        }
+        let msglen = out.len() - 1;
+        ser::ser_be_u32_at(&mut out, 1, msglen as u32);
+        return out;
+    }
+}
+
+// The following 'p' messages of the SASL exchange, the whole message is the data.
+#[derive(Debug)]
+pub struct SASLResponse<'a> {
+    pub data: &'a [u8],
+}
+
+impl SASLResponse<'_> {
+    pub fn deserialize(d: &[u8]) -> anyhow::Result<SASLResponse<'_>> {
+        return Ok(SASLResponse { data: d });
+    }
+}
+
+impl Message for SASLResponse<'_> {
+    fn serialize(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(5 + self.data.len());
+        out.push('p' as u8);
+        ser::ser_be_u32(&mut out, 4 + self.data.len() as u32);
+        out.extend_from_slice(self.data);
+        return out;
+    }
+}
+
 pub struct BackendKeyData {
     backendid: u32,
     key: u32,
@@ -331,9 +675,9 @@ pub struct Query<'a> {
 impl Query<'_> {
     pub fn deserialize(d: &[u8]) -> anyhow::Result<Query<'_>> {
         kbensure!(
-            !d.is_empty(),
+            d.last() == Some(&0),
             ERRCODE_PROTOCOL_VIOLATION,
-            "Query string is empty"
+            "Query string is not null-terminated"
         );
         let qstr = from_utf8(&d[..d.len() - 1])
             .with_context(|| errctx!(ERRCODE_PROTOCOL_VIOLATION, "Query string is not UTF-8"))?;
@@ -341,6 +685,17 @@ impl Query<'_> {
     }
 }
 
+impl Message for Query<'_> {
+    fn serialize(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(5 + self.query.len() + 1);
+        out.resize(5, 'Q' as u8);
+        ser::ser_cstr(&mut out, self.query);
+        let msglen = out.len() - 1;
+        ser::ser_be_u32_at(&mut out, 1, msglen as u32);
+        return out;
+    }
+}
+
 pub struct CommandComplete<'a> {
     pub tag: &'a str,
 }
@@ -369,20 +724,29 @@ impl<'a> ParameterStatus<'a> {
 
 impl Message for ParameterStatus<'_> {
     fn serialize(&self) -> Vec<u8> {
+        self.serialize_to(Encoding::Utf8)
+    }
+
+    fn serialize_to(&self, enc: Encoding) -> Vec<u8> {
         let mut out = Vec::with_capacity(64);
         out.resize(5, 'S' as u8);
-        ser::ser_cstr(&mut out, self.name);
-        ser::ser_cstr(&mut out, self.value);
+        ser_cstr_to(&mut out, self.name, enc);
+        ser_cstr_to(&mut out, self.value, enc);
         let msglen = out.len() - 1;
         ser::ser_be_u32_at(&mut out, 1, msglen as u32);
         return out;
     }
 }
 
+// The values of the GUC_REPORT GUCs last sent to the client, just as last_reported.
+pub type ReportedGucs = HashMap<&'static str, String>;
+
+// ReportGUCOption, the value is sent only if it differs from the last reported one.
 pub fn report_guc(
-    name: &str,
+    name: &'static str,
     gucvals: &guc::GucState,
     gucidx: guc::GucIdx,
+    reported: &mut ReportedGucs,
     stream: &mut SockWriter,
 ) {
     let gen = guc::get_guc_generic(gucidx);
@@ -390,14 +754,47 @@ pub fn report_guc(
         return;
     }
     let value = guc::show(gen, gucvals, gucidx);
+    if reported.get(name) == Some(&value) {
+        return;
+    }
     log::trace!("report guc. name={} value={}", name, value);
     let msg = ParameterStatus::new(name, &value);
-    write_message(stream, &msg);
+    write_message_to(stream, &msg, gucvals.client_encoding);
+    reported.insert(name, value);
 }
 
-pub fn report_all_gucs(gucvals: &guc::GucState, stream: &mut SockWriter) {
+// BeginReportingGUCOptions and ReportChangedGUCOptions, all GUC_REPORT GUCs are sent if
+// nothing has been reported yet.
+pub fn report_changed_gucs(
+    gucvals: &guc::GucState,
+    reported: &mut ReportedGucs,
+    stream: &mut SockWriter,
+) {
     for (&name, &gucidx) in guc::GUC_NAMEINFO_MAP.iter() {
-        report_guc(name, gucvals, gucidx, stream)
+        report_guc(name, gucvals, gucidx, reported, stream)
+    }
+}
+
+pub struct NotificationResponse<'a> {
+    pub sessid: u32,
+    pub channel: &'a str,
+    pub payload: &'a str,
+}
+
+impl Message for NotificationResponse<'_> {
+    fn serialize(&self) -> Vec<u8> {
+        self.serialize_to(Encoding::Utf8)
+    }
+
+    fn serialize_to(&self, enc: Encoding) -> Vec<u8> {
+        let mut out = Vec::with_capacity(64);
+        out.resize(5, 'A' as u8);
+        ser::ser_be_u32(&mut out, self.sessid);
+        ser_cstr_to(&mut out, self.channel, enc);
+        ser_cstr_to(&mut out, self.payload, enc);
+        let msglen = out.len() - 1;
+        ser::ser_be_u32_at(&mut out, 1, msglen as u32);
+        return out;
     }
 }
 
@@ -412,9 +809,42 @@ impl Message for EmptyQueryResponse {
     }
 }
 
-#[derive(Clone, Copy)]
-enum Format {
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
     Text = 0,
+    Binary = 1,
+}
+
+impl Format {
+    fn from_code(code: i16) -> anyhow::Result<Format> {
+        match code {
+            0 => Ok(Format::Text),
+            1 => Ok(Format::Binary),
+            _ => Err(kbanyhow!(
+                ERRCODE_INVALID_PARAMETER_VALUE,
+                "unsupported format code: {}",
+                code
+            )),
+        }
+    }
+
+    // The format of the column idx, formats are the result format codes of Bind.
+    pub fn of_column(formats: &[Format], idx: usize) -> Format {
+        match formats.len() {
+            0 => Format::Text,
+            1 => formats[0],
+            _ => formats[idx],
+        }
+    }
+}
+
+fn read_formats(cursor: &mut Cursor<&[u8]>) -> anyhow::Result<Vec<Format>> {
+    let cnt = read_count(cursor)?;
+    let mut formats = Vec::with_capacity(cnt);
+    for _ in 0..cnt {
+        formats.push(Format::from_code(read_i16(cursor)?)?);
+    }
+    return Ok(formats);
 }
 
 pub struct FieldDesc<'a> {
@@ -439,6 +869,10 @@ impl FieldDesc<'_> {
             format: Format::Text,
         }
     }
+
+    pub fn set_format(&mut self, format: Format) {
+        self.format = format;
+    }
 }
 
 pub struct RowDescription<'a, 'b> {
@@ -447,6 +881,10 @@ pub struct RowDescription<'a, 'b> {
 
 impl Message for RowDescription<'_, '_> {
     fn serialize(&self) -> Vec<u8> {
+        self.serialize_to(Encoding::Utf8)
+    }
+
+    fn serialize_to(&self, enc: Encoding) -> Vec<u8> {
         let mut out = Vec::with_capacity(64);
         out.resize(5, 'T' as u8);
         ser::ser_be_u16(&mut out, self.fields.len() as u16);
@@ -455,7 +893,7 @@ impl Message for RowDescription<'_, '_> {
                 None => 0,
                 Some(v) => v.get(),
             };
-            ser::ser_cstr(&mut out, field.name);
+            ser_cstr_to(&mut out, field.name, enc);
             ser::ser_be_u32(&mut out, field.reloid.into());
             ser::ser_be_u16(&mut out, attnum);
             ser::ser_be_u32(&mut out, field.typoid.get());
@@ -495,3 +933,493 @@ impl Message for DataRow<'_, '_> {
         return out;
     }
 }
+
+pub struct CopyBothResponse {}
+
+impl Message for CopyBothResponse {
+    fn serialize(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(1 + 4 + 1 + 2);
+        out.push('W' as u8);
+        ser::ser_be_u32(&mut out, 7);
+        out.push(Format::Text as u8);
+        ser::ser_be_u16(&mut out, 0);
+        return out;
+    }
+}
+
+pub struct CopyOutResponse {}
+
+impl Message for CopyOutResponse {
+    fn serialize(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(1 + 4 + 1 + 2);
+        out.push('H' as u8);
+        ser::ser_be_u32(&mut out, 7);
+        out.push(Format::Binary as u8);
+        ser::ser_be_u16(&mut out, 0);
+        return out;
+    }
+}
+
+pub struct CopyData<'a> {
+    pub data: &'a [u8],
+}
+
+impl Message for CopyData<'_> {
+    fn serialize(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(1 + 4 + self.data.len());
+        out.push('d' as u8);
+        ser::ser_be_u32(&mut out, (4 + self.data.len()) as u32);
+        out.extend_from_slice(self.data);
+        return out;
+    }
+}
+
+pub struct CopyDone {}
+
+impl Message for CopyDone {
+    fn serialize(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(1 + 4);
+        out.push('c' as u8);
+        ser::ser_be_u32(&mut out, 4);
+        return out;
+    }
+}
+
+// Parse, the query is left in the client encoding.
+#[derive(Debug)]
+pub struct Parse<'a> {
+    pub name: &'a str,
+    pub query: &'a [u8],
+    pub paramtypes: Vec<u32>,
+}
+
+impl Parse<'_> {
+    pub fn deserialize(d: &[u8]) -> anyhow::Result<Parse<'_>> {
+        let mut cursor = Cursor::new(d);
+        let name = read_cstr(&mut cursor)?;
+        let query = read_cstr_bytes(&mut cursor)?;
+        let cnt = read_count(&mut cursor)?;
+        let mut paramtypes = Vec::with_capacity(cnt);
+        for _ in 0..cnt {
+            paramtypes.push(read_i32(&mut cursor)? as u32);
+        }
+        read_end(&cursor)?;
+        return Ok(Parse {
+            name,
+            query,
+            paramtypes,
+        });
+    }
+}
+
+#[derive(Debug)]
+pub struct Bind<'a> {
+    pub portal: &'a str,
+    pub stmt: &'a str,
+    pub paramformats: Vec<Format>,
+    // None means NULL.
+    pub params: Vec<Option<&'a [u8]>>,
+    pub resultformats: Vec<Format>,
+}
+
+impl Bind<'_> {
+    pub fn deserialize(d: &[u8]) -> anyhow::Result<Bind<'_>> {
+        let mut cursor = Cursor::new(d);
+        let portal = read_cstr(&mut cursor)?;
+        let stmt = read_cstr(&mut cursor)?;
+        let paramformats = read_formats(&mut cursor)?;
+        let cnt = read_count(&mut cursor)?;
+        let mut params = Vec::with_capacity(cnt);
+        for _ in 0..cnt {
+            let len = read_i32(&mut cursor)?;
+            if len == -1 {
+                params.push(None);
+                continue;
+            }
+            kbensure!(
+                len >= 0,
+                ERRCODE_PROTOCOL_VIOLATION,
+                "invalid parameter length. len={}",
+                len
+            );
+            params.push(Some(read_bytes(&mut cursor, len as usize)?));
+        }
+        let resultformats = read_formats(&mut cursor)?;
+        read_end(&cursor)?;
+        return Ok(Bind {
+            portal,
+            stmt,
+            paramformats,
+            params,
+            resultformats,
+        });
+    }
+}
+
+// The object that Describe and Close refer to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Target {
+    Statement,
+    Portal,
+}
+
+fn read_target(cursor: &mut Cursor<&[u8]>, msg: &str) -> anyhow::Result<Target> {
+    let kind = read_bytes(cursor, 1).map(|v| v[0])?;
+    match kind {
+        b'S' => Ok(Target::Statement),
+        b'P' => Ok(Target::Portal),
+        _ => Err(kbanyhow!(
+            ERRCODE_PROTOCOL_VIOLATION,
+            "invalid {} message subtype {}",
+            msg,
+            kind
+        )),
+    }
+}
+
+#[derive(Debug)]
+pub struct Describe<'a> {
+    pub target: Target,
+    pub name: &'a str,
+}
+
+impl Describe<'_> {
+    pub fn deserialize(d: &[u8]) -> anyhow::Result<Describe<'_>> {
+        let mut cursor = Cursor::new(d);
+        let target = read_target(&mut cursor, "DESCRIBE")?;
+        let name = read_cstr(&mut cursor)?;
+        read_end(&cursor)?;
+        return Ok(Describe { target, name });
+    }
+}
+
+#[derive(Debug)]
+pub struct Execute<'a> {
+    pub portal: &'a str,
+    // Zero or negative means no limit.
+    pub max_rows: i32,
+}
+
+impl Execute<'_> {
+    pub fn deserialize(d: &[u8]) -> anyhow::Result<Execute<'_>> {
+        let mut cursor = Cursor::new(d);
+        let portal = read_cstr(&mut cursor)?;
+        let max_rows = read_i32(&mut cursor)?;
+        read_end(&cursor)?;
+        return Ok(Execute { portal, max_rows });
+    }
+}
+
+#[derive(Debug)]
+pub struct Close<'a> {
+    pub target: Target,
+    pub name: &'a str,
+}
+
+impl Close<'_> {
+    pub fn deserialize(d: &[u8]) -> anyhow::Result<Close<'_>> {
+        let mut cursor = Cursor::new(d);
+        let target = read_target(&mut cursor, "CLOSE")?;
+        let name = read_cstr(&mut cursor)?;
+        read_end(&cursor)?;
+        return Ok(Close { target, name });
+    }
+}
+
+fn ser_empty_msg(typ: u8) -> Vec<u8> {
+    let mut out = Vec::with_capacity(1 + 4);
+    out.push(typ);
+    ser::ser_be_u32(&mut out, 4);
+    return out;
+}
+
+pub struct ParseComplete {}
+
+impl Message for ParseComplete {
+    fn serialize(&self) -> Vec<u8> {
+        ser_empty_msg(b'1')
+    }
+}
+
+pub struct BindComplete {}
+
+impl Message for BindComplete {
+    fn serialize(&self) -> Vec<u8> {
+        ser_empty_msg(b'2')
+    }
+}
+
+pub struct CloseComplete {}
+
+impl Message for CloseComplete {
+    fn serialize(&self) -> Vec<u8> {
+        ser_empty_msg(b'3')
+    }
+}
+
+pub struct NoData {}
+
+impl Message for NoData {
+    fn serialize(&self) -> Vec<u8> {
+        ser_empty_msg(b'n')
+    }
+}
+
+pub struct PortalSuspended {}
+
+impl Message for PortalSuspended {
+    fn serialize(&self) -> Vec<u8> {
+        ser_empty_msg(b's')
+    }
+}
+
+pub struct ParameterDescription<'a> {
+    pub types: &'a [Oid],
+}
+
+impl Message for ParameterDescription<'_> {
+    fn serialize(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(1 + 4 + 2 + 4 * self.types.len());
+        out.resize(5, 't' as u8);
+        ser::ser_be_u16(&mut out, self.types.len() as u16);
+        for typ in self.types {
+            ser::ser_be_u32(&mut out, typ.get());
+        }
+        let msglen = out.len() - 1;
+        ser::ser_be_u32_at(&mut out, 1, msglen as u32);
+        return out;
+    }
+}
+
+pub struct Terminate {}
+
+impl Message for Terminate {
+    fn serialize(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(1 + 4);
+        out.push('X' as u8);
+        ser::ser_be_u32(&mut out, 4);
+        return out;
+    }
+}
+
+#[cfg(test)]
+mod fuzz_test {
+    use super::{
+        read_body, read_cstr, Bind, CancelRequest, Close, Describe, Execute, Format, Parse,
+        PasswordMessage, Query, SASLInitialResponse, SSLRequest, StartupMessage, Target,
+        ERRCODE_INVALID_PARAMETER_VALUE, ERRCODE_PROTOCOL_VIOLATION, MAX_MESSAGE_LEN,
+        MAX_STARTUP_MESSAGE_LEN,
+    };
+    use crate::protocol::Message;
+    use crate::utils::err::errcode;
+    use crate::utils::ser;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::collections::HashMap;
+    use std::io::Cursor;
+
+    // Zero is more likely to be generated, so that the strings are terminated sometimes.
+    fn random_bytes(rng: &mut StdRng, maxlen: usize) -> Vec<u8> {
+        let len = rng.gen_range(0, maxlen + 1);
+        (0..len)
+            .map(|_| if rng.gen_bool(0.2) { 0 } else { rng.gen() })
+            .collect()
+    }
+
+    fn random_str(rng: &mut StdRng, maxlen: usize) -> String {
+        let len = rng.gen_range(0, maxlen + 1);
+        (0..len).map(|_| rng.gen_range(1u8, 128) as char).collect()
+    }
+
+    // The parser must return Ok or the error of ERRCODE_PROTOCOL_VIOLATION, and never panic.
+    fn parse_all(d: &[u8]) {
+        let _ = CancelRequest::deserialize(d);
+        let _ = SSLRequest::deserialize(d);
+        if let Err(e) = StartupMessage::deserialize(d) {
+            assert_eq!(errcode(&e), ERRCODE_PROTOCOL_VIOLATION, "d={:?}", d);
+        }
+        if let Err(e) = Query::deserialize(d) {
+            assert_eq!(errcode(&e), ERRCODE_PROTOCOL_VIOLATION, "d={:?}", d);
+        }
+        if let Err(e) = Parse::deserialize(d) {
+            assert_eq!(errcode(&e), ERRCODE_PROTOCOL_VIOLATION, "d={:?}", d);
+        }
+        if let Err(e) = Bind::deserialize(d) {
+            let code = errcode(&e);
+            assert!(
+                code == ERRCODE_PROTOCOL_VIOLATION || code == ERRCODE_INVALID_PARAMETER_VALUE,
+                "d={:?}",
+                d
+            );
+        }
+        if let Err(e) = Describe::deserialize(d) {
+            assert_eq!(errcode(&e), ERRCODE_PROTOCOL_VIOLATION, "d={:?}", d);
+        }
+        if let Err(e) = Execute::deserialize(d) {
+            assert_eq!(errcode(&e), ERRCODE_PROTOCOL_VIOLATION, "d={:?}", d);
+        }
+        if let Err(e) = Close::deserialize(d) {
+            assert_eq!(errcode(&e), ERRCODE_PROTOCOL_VIOLATION, "d={:?}", d);
+        }
+        if let Err(e) = PasswordMessage::deserialize(d) {
+            assert_eq!(errcode(&e), ERRCODE_PROTOCOL_VIOLATION, "d={:?}", d);
+        }
+        if let Err(e) = SASLInitialResponse::deserialize(d) {
+            assert_eq!(errcode(&e), ERRCODE_PROTOCOL_VIOLATION, "d={:?}", d);
+        }
+        let mut cursor = Cursor::new(d);
+        while (cursor.position() as usize) < d.len() {
+            let pos = cursor.position();
+            if read_cstr(&mut cursor).is_err() {
+                assert_eq!(cursor.position(), pos);
+                break;
+            }
+            assert!(cursor.position() > pos);
+        }
+        let mut content = Vec::new();
+        if read_body(&mut Cursor::new(d), &mut content, MAX_MESSAGE_LEN).is_ok() {
+            assert!(content.len() + 4 <= d.len());
+            assert_eq!(&content[..], &d[4..4 + content.len()]);
+        }
+    }
+
+    #[test]
+    fn random_bytes_parse() {
+        let mut rng = StdRng::seed_from_u64(20181218);
+        for _ in 0..100000 {
+            let d = random_bytes(&mut rng, 48);
+            parse_all(&d);
+        }
+    }
+
+    #[test]
+    fn mutated_messages_parse() {
+        let mut rng = StdRng::seed_from_u64(20130203);
+        for _ in 0..20000 {
+            let mut params = HashMap::new();
+            let user = random_str(&mut rng, 8);
+            let database = random_str(&mut rng, 8);
+            params.insert("user", user.as_str());
+            if rng.gen() {
+                params.insert("database", database.as_str());
+            }
+            let startup = StartupMessage::new(params).unwrap().serialize();
+            let parsed = StartupMessage::deserialize(&startup[4..]).unwrap();
+            assert_eq!(parsed.user(), user);
+            let query = random_str(&mut rng, 32);
+            let qmsg = Query { query: &query }.serialize();
+            assert_eq!(Query::deserialize(&qmsg[5..]).unwrap().query, query);
+
+            for msg in &[&startup[..], &qmsg[1..]] {
+                let mut d = msg.to_vec();
+                for _ in 0..rng.gen_range(1, 4) {
+                    let idx = rng.gen_range(0, d.len());
+                    match rng.gen_range(0, 3) {
+                        0 => d[idx] = rng.gen(),
+                        1 => d.truncate(idx),
+                        _ => d.insert(idx, 0),
+                    }
+                    if d.is_empty() {
+                        break;
+                    }
+                }
+                parse_all(&d);
+                parse_all(&d[4.min(d.len())..]);
+            }
+        }
+    }
+
+    // The regression cases found by the tests above.
+    #[test]
+    fn regression() {
+        // The huge length is not allocated before the body arrives.
+        let mut content = Vec::new();
+        let d = [0xff, 0xff, 0xff, 0xf0, b'Q'];
+        assert!(read_body(&mut Cursor::new(&d), &mut content, MAX_MESSAGE_LEN).is_err());
+        assert!(content.capacity() < 1024);
+        assert_eq!(
+            errcode(&StartupMessage::deserialize(&[0, 3, 0]).unwrap_err()),
+            ERRCODE_PROTOCOL_VIOLATION
+        );
+        // Query without the null terminator.
+        assert!(Query::deserialize(b"select 1").is_err());
+        assert!(Query::deserialize(b"").is_err());
+    }
+
+    #[test]
+    fn sasl_messages() {
+        let msg = SASLInitialResponse {
+            mechanism: "SCRAM-SHA-256",
+            data: Some(b"n,,n=,r=abc"),
+        }
+        .serialize();
+        let resp = SASLInitialResponse::deserialize(&msg[5..]).unwrap();
+        assert_eq!(resp.mechanism, "SCRAM-SHA-256");
+        assert_eq!(resp.data, Some(&b"n,,n=,r=abc"[..]));
+        let resp = SASLInitialResponse::deserialize(b"SCRAM-SHA-256\0\xff\xff\xff\xff").unwrap();
+        assert_eq!(resp.data, None);
+        // The length is larger than the data.
+        let err = SASLInitialResponse::deserialize(b"SCRAM-SHA-256\0\0\0\0\x04abc").unwrap_err();
+        assert_eq!(errcode(&err), ERRCODE_PROTOCOL_VIOLATION);
+    }
+
+    #[test]
+    fn extended_messages() {
+        let parse = Parse::deserialize(b"s1\0select 1\0\0\0").unwrap();
+        assert_eq!((parse.name, parse.query), ("s1", &b"select 1"[..]));
+        assert!(parse.paramtypes.is_empty());
+        // The trailing garbage.
+        assert!(Parse::deserialize(b"\0select 1\0\0\0\0").is_err());
+
+        let d = b"p1\0s1\0\0\0\0\x02\xff\xff\xff\xff\0\0\0\x01x\0\x01\0\x01";
+        let bind = Bind::deserialize(d).unwrap();
+        assert_eq!((bind.portal, bind.stmt), ("p1", "s1"));
+        assert_eq!(bind.params, [None, Some(&b"x"[..])]);
+        assert_eq!(bind.resultformats, [Format::Binary]);
+        // The parameter longer than the message.
+        let d = b"\0\0\0\0\0\x01\0\0\0\x09x\0\0";
+        assert_eq!(
+            errcode(&Bind::deserialize(d).unwrap_err()),
+            ERRCODE_PROTOCOL_VIOLATION
+        );
+        let d = b"\0\0\0\0\0\0\0\x01\0\x02";
+        assert_eq!(
+            errcode(&Bind::deserialize(d).unwrap_err()),
+            ERRCODE_INVALID_PARAMETER_VALUE
+        );
+
+        let describe = Describe::deserialize(b"P\0").unwrap();
+        assert_eq!((describe.target, describe.name), (Target::Portal, ""));
+        assert!(Describe::deserialize(b"X\0").is_err());
+        let close = Close::deserialize(b"Ss1\0").unwrap();
+        assert_eq!((close.target, close.name), (Target::Statement, "s1"));
+        let execute = Execute::deserialize(b"\0\0\0\0\x0a").unwrap();
+        assert_eq!((execute.portal, execute.max_rows), ("", 10));
+        assert!(Execute::deserialize(b"\0\0\0").is_err());
+    }
+
+    #[test]
+    fn message_len() {
+        for len in 0..=4u32 {
+            let mut d = Vec::new();
+            ser::ser_be_u32(&mut d, len);
+            let mut content = vec![1, 2, 3];
+            let ret = read_body(&mut Cursor::new(&d), &mut content, MAX_MESSAGE_LEN);
+            if len < 4 {
+                assert_eq!(errcode(&ret.unwrap_err()), ERRCODE_PROTOCOL_VIOLATION);
+            } else {
+                ret.unwrap();
+                assert!(content.is_empty());
+            }
+        }
+        let mut d = Vec::new();
+        ser::ser_be_u32(&mut d, MAX_STARTUP_MESSAGE_LEN + 1);
+        d.resize(MAX_STARTUP_MESSAGE_LEN as usize + 1, 0);
+        let mut content = Vec::new();
+        let ret = read_body(&mut Cursor::new(&d), &mut content, MAX_STARTUP_MESSAGE_LEN);
+        assert_eq!(errcode(&ret.unwrap_err()), ERRCODE_PROTOCOL_VIOLATION);
+        assert!(content.is_empty());
+        read_body(&mut Cursor::new(&d), &mut content, MAX_MESSAGE_LEN).unwrap();
+        assert_eq!(content.len(), MAX_STARTUP_MESSAGE_LEN as usize - 3);
+    }
+}
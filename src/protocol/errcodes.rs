@@ -14,6 +14,7 @@ pub const ERRCODE_UNDEFINED_DATABASE: &str = "3D000";
 pub const ERRCODE_CONNECTION_FAILURE: &str = "08006";
 pub const ERRCODE_PROTOCOL_VIOLATION: &str = "08P01";
 pub const ERRCODE_ADMIN_SHUTDOWN: &str = "57P01";
+pub const ERRCODE_CANNOT_CONNECT_NOW: &str = "57P03";
 pub const ERRCODE_SYNTAX_ERROR: &str = "42601";
 pub const ERRCODE_INTERNAL_ERROR: &str = "XX000";
 pub const ERRCODE_FEATURE_NOT_SUPPORTED: &str = "0A000";
@@ -29,3 +30,38 @@ pub const ERRCODE_NO_ACTIVE_SQL_TRANSACTION: &str = "25P01";
 pub const ERRCODE_UNDEFINED_TABLE: &str = "42P01";
 pub const ERRCODE_BAD_COPY_FILE_FORMAT: &str = "22P04";
 pub const ERRCODE_NOT_NULL_VIOLATION: &str = "23502";
+pub const ERRCODE_INVALID_NAME: &str = "42602";
+pub const ERRCODE_DUPLICATE_OBJECT: &str = "42710";
+pub const ERRCODE_OBJECT_NOT_IN_PREREQUISITE_STATE: &str = "55000";
+pub const ERRCODE_UNDEFINED_COLUMN: &str = "42703";
+pub const ERRCODE_INVALID_TEXT_REPRESENTATION: &str = "22P02";
+pub const ERRCODE_DATATYPE_MISMATCH: &str = "42804";
+pub const ERRCODE_GROUPING_ERROR: &str = "42803";
+pub const ERRCODE_WRONG_OBJECT_TYPE: &str = "42809";
+pub const ERRCODE_OUT_OF_MEMORY: &str = "53200";
+pub const ERRCODE_AMBIGUOUS_COLUMN: &str = "42702";
+pub const ERRCODE_INVALID_COLUMN_REFERENCE: &str = "42P10";
+pub const ERRCODE_INVALID_ROW_COUNT_IN_LIMIT_CLAUSE: &str = "2201W";
+pub const ERRCODE_DUPLICATE_COLUMN: &str = "42701";
+pub const ERRCODE_T_R_DEADLOCK_DETECTED: &str = "40P01";
+pub const ERRCODE_DATA_CORRUPTED: &str = "XX001";
+pub const ERRCODE_CHARACTER_NOT_IN_REPERTOIRE: &str = "22021";
+pub const ERRCODE_UNTRANSLATABLE_CHARACTER: &str = "22P05";
+pub const ERRCODE_UNDEFINED_CURSOR: &str = "34000";
+pub const ERRCODE_INVALID_SQL_STATEMENT_NAME: &str = "26000";
+pub const ERRCODE_DUPLICATE_PSTATEMENT: &str = "42P05";
+pub const ERRCODE_DUPLICATE_CURSOR: &str = "42P03";
+pub const ERRCODE_INVALID_PASSWORD: &str = "28P01";
+pub const ERRCODE_DUPLICATE_TABLE: &str = "42P07";
+pub const ERRCODE_DEPENDENT_OBJECTS_STILL_EXIST: &str = "2BP01";
+pub const ERRCODE_DUPLICATE_SCHEMA: &str = "42P06";
+pub const ERRCODE_SUCCESSFUL_COMPLETION: &str = "00000";
+pub const ERRCODE_INVALID_OBJECT_DEFINITION: &str = "42P17";
+pub const ERRCODE_QUERY_CANCELED: &str = "57014";
+pub const ERRCODE_AMBIGUOUS_FUNCTION: &str = "42725";
+pub const ERRCODE_CANNOT_COERCE: &str = "42846";
+pub const ERRCODE_OBJECT_IN_USE: &str = "55006";
+pub const ERRCODE_S_E_INVALID_SPECIFICATION: &str = "3B001";
+pub const ERRCODE_IDLE_IN_TRANSACTION_SESSION_TIMEOUT: &str = "25P03";
+pub const ERRCODE_INVALID_AUTHORIZATION_SPECIFICATION: &str = "28000";
+pub const ERRCODE_READ_ONLY_SQL_TRANSACTION: &str = "25006";
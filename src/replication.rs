@@ -0,0 +1,575 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::access::wal::Lsn;
+use crate::utils::ser;
+use crate::{kbanyhow, kbbail, kbensure};
+use std::convert::TryInto;
+
+pub mod basebackup;
+pub mod slot;
+pub mod walreceiver;
+pub mod walsender;
+
+// The messages carried by CopyData in the replication mode, just as PostgreSQL.
+const XLOGDATA_MSG: u8 = b'w';
+const XLOGDATA_HDRLEN: usize = 1 + 8 * 3;
+
+// XLogData is a batch of continuous and complete wal records starting at start.
+// walend is the end lsn of wal on the sender side.
+pub struct XLogData<'a> {
+    pub start: Lsn,
+    pub walend: Lsn,
+    pub sendtime: u64,
+    pub data: &'a [u8],
+}
+
+impl XLogData<'_> {
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(XLOGDATA_HDRLEN + self.data.len());
+        out.push(XLOGDATA_MSG);
+        ser::ser_be_u64(&mut out, self.start.get());
+        ser::ser_be_u64(&mut out, self.walend.get());
+        ser::ser_be_u64(&mut out, self.sendtime);
+        out.extend_from_slice(self.data);
+        return out;
+    }
+
+    pub fn deserialize(d: &[u8]) -> anyhow::Result<XLogData<'_>> {
+        kbensure!(
+            d.len() >= XLOGDATA_HDRLEN && d[0] == XLOGDATA_MSG,
+            ERRCODE_PROTOCOL_VIOLATION,
+            "invalid XLogData message. len={}",
+            d.len()
+        );
+        let getlsn = |off: usize| {
+            let v = u64::from_be_bytes(d[off..off + 8].try_into().unwrap());
+            Lsn::new(v).ok_or_else(|| {
+                kbanyhow!(
+                    ERRCODE_PROTOCOL_VIOLATION,
+                    "invalid lsn in XLogData message"
+                )
+            })
+        };
+        return Ok(XLogData {
+            start: getlsn(1)?,
+            walend: getlsn(9)?,
+            sendtime: u64::from_be_bytes(d[17..25].try_into().unwrap()),
+            data: &d[XLOGDATA_HDRLEN..],
+        });
+    }
+}
+
+const KEEPALIVE_MSG: u8 = b'k';
+const KEEPALIVE_LEN: usize = 1 + 8 * 2 + 1;
+
+// PrimaryKeepalive is sent by the walsender when the standby has not replied for a while.
+pub struct PrimaryKeepalive {
+    pub walend: Lsn,
+    pub sendtime: u64,
+    pub reply_requested: bool,
+}
+
+impl PrimaryKeepalive {
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(KEEPALIVE_LEN);
+        out.push(KEEPALIVE_MSG);
+        ser::ser_be_u64(&mut out, self.walend.get());
+        ser::ser_be_u64(&mut out, self.sendtime);
+        out.push(self.reply_requested as u8);
+        return out;
+    }
+
+    pub fn deserialize(d: &[u8]) -> Option<PrimaryKeepalive> {
+        if d.len() != KEEPALIVE_LEN || d[0] != KEEPALIVE_MSG {
+            return None;
+        }
+        return Some(PrimaryKeepalive {
+            walend: Lsn::new(u64::from_be_bytes(d[1..9].try_into().unwrap()))?,
+            sendtime: u64::from_be_bytes(d[9..17].try_into().unwrap()),
+            reply_requested: d[17] != 0,
+        });
+    }
+}
+
+const STATUS_UPDATE_MSG: u8 = b'r';
+const STATUS_UPDATE_LEN: usize = 1 + 8 * 4 + 1;
+
+// StandbyStatusUpdate reports the positions of the standby, 0 means unknown.
+#[derive(Debug, PartialEq)]
+pub struct StandbyStatusUpdate {
+    pub write: u64,
+    pub flush: u64,
+    pub apply: u64,
+    pub sendtime: u64,
+    pub reply_requested: bool,
+}
+
+impl StandbyStatusUpdate {
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(STATUS_UPDATE_LEN);
+        out.push(STATUS_UPDATE_MSG);
+        ser::ser_be_u64(&mut out, self.write);
+        ser::ser_be_u64(&mut out, self.flush);
+        ser::ser_be_u64(&mut out, self.apply);
+        ser::ser_be_u64(&mut out, self.sendtime);
+        out.push(self.reply_requested as u8);
+        return out;
+    }
+
+    pub fn deserialize(d: &[u8]) -> anyhow::Result<StandbyStatusUpdate> {
+        kbensure!(
+            d.len() == STATUS_UPDATE_LEN && d[0] == STATUS_UPDATE_MSG,
+            ERRCODE_PROTOCOL_VIOLATION,
+            "invalid standby status update message. len={}",
+            d.len()
+        );
+        let getu64 = |off: usize| u64::from_be_bytes(d[off..off + 8].try_into().unwrap());
+        return Ok(StandbyStatusUpdate {
+            write: getu64(1),
+            flush: getu64(9),
+            apply: getu64(17),
+            sendtime: getu64(25),
+            reply_requested: d[33] != 0,
+        });
+    }
+}
+
+// The format of lsn in the replication commands is %X/%X, just as PostgreSQL.
+pub fn parse_lsn(v: &str) -> anyhow::Result<Lsn> {
+    let mut parts = v.splitn(2, '/');
+    let hi = parts.next().unwrap();
+    let lo = match parts.next() {
+        None => kbbail!(ERRCODE_SYNTAX_ERROR, "invalid lsn. lsn={}", v),
+        Some(lo) => lo,
+    };
+    let hi = u32::from_str_radix(hi, 16);
+    let lo = u32::from_str_radix(lo, 16);
+    if let (Ok(hi), Ok(lo)) = (hi, lo) {
+        if let Some(lsn) = Lsn::new(((hi as u64) << 32) | lo as u64) {
+            return Ok(lsn);
+        }
+    }
+    kbbail!(ERRCODE_SYNTAX_ERROR, "invalid lsn. lsn={}", v);
+}
+
+pub fn format_lsn(lsn: Lsn) -> String {
+    let lsn = lsn.get();
+    format!("{:X}/{:X}", lsn >> 32, lsn as u32)
+}
+
+#[cfg(test)]
+mod replication_test {
+    use super::slot::ReplSlots;
+    use super::walreceiver::{walreceiver_main, WalReceiver};
+    use super::walsender::{
+        parse_replication_cmd, send_identify_system, start_replication, ReplicationCmd, WalSndCtx,
+    };
+    use super::{format_lsn, parse_lsn, PrimaryKeepalive, XLogData};
+    use crate::access::wal::{
+        finish_record, serialize_records, start_record_raw, wal_filename, LocalWalStorage, Lsn,
+        RmgrId, TimeLineID, WalReader, WalStorage,
+    };
+    use crate::protocol::{
+        self, MsgType, StartupMessage, ERRCODE_ADMIN_SHUTDOWN, ERRCODE_CONNECTION_FAILURE,
+        ERRCODE_FEATURE_NOT_SUPPORTED,
+    };
+    use crate::utils::err::errcode;
+    use crate::utils::ssl::KBStream;
+    use crate::{SockReader, SockWriter};
+    use std::io::{BufReader, BufWriter, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn lsn() {
+        let lsn = Lsn::new(0x2018121820130203).unwrap();
+        assert_eq!(format_lsn(lsn), "20181218/20130203");
+        assert_eq!(parse_lsn("20181218/20130203").unwrap(), lsn);
+        assert!(parse_lsn("20181218").is_err());
+        assert!(parse_lsn("0/0").is_err());
+        assert_eq!(
+            parse_replication_cmd("start_replication physical 0/133F0E2;").unwrap(),
+            ReplicationCmd::StartReplication {
+                slot: None,
+                startlsn: Lsn::new(20181218).unwrap()
+            }
+        );
+        assert_eq!(
+            parse_replication_cmd("START_REPLICATION SLOT s1 0/133F0E2").unwrap(),
+            ReplicationCmd::StartReplication {
+                slot: Some("s1".to_string()),
+                startlsn: Lsn::new(20181218).unwrap()
+            }
+        );
+        assert_eq!(
+            parse_replication_cmd("CREATE_REPLICATION_SLOT s1 PHYSICAL").unwrap(),
+            ReplicationCmd::CreateReplicationSlot("s1".to_string())
+        );
+        assert_eq!(
+            parse_replication_cmd("DROP_REPLICATION_SLOT s1").unwrap(),
+            ReplicationCmd::DropReplicationSlot("s1".to_string())
+        );
+        assert_eq!(
+            parse_replication_cmd("base_backup;").unwrap(),
+            ReplicationCmd::BaseBackup
+        );
+        assert_eq!(
+            parse_replication_cmd("identify_system").unwrap(),
+            ReplicationCmd::IdentifySystem
+        );
+        assert!(parse_replication_cmd("TIMELINE_HISTORY 1").is_err());
+        assert!(parse_replication_cmd("START_REPLICATION SLOT 0/133F0E2").is_err());
+    }
+
+    fn new_records(n: usize) -> Vec<Vec<u8>> {
+        let mut recs = Vec::new();
+        for i in 0..n {
+            let mut rec = start_record_raw(&[i as u8; 128]);
+            finish_record(&mut rec, RmgrId::Xlog, 0x30, None);
+            recs.push(rec);
+        }
+        recs
+    }
+
+    // The standby may be in a timeline other than 1, for example, it is backed up from a
+    // primary which has been recovered to a point in time.
+    #[test]
+    fn receive_timeline() {
+        let standby = tempfile::tempdir().unwrap();
+        let standby = standby.path().to_str().unwrap().to_string();
+        let tli1 = TimeLineID::new(1).unwrap();
+        let tli2 = TimeLineID::new(2).unwrap();
+        let startlsn = Lsn::new(20181218).unwrap();
+        let seg1 = serialize_records(startlsn, None, new_records(5));
+        let reclen = seg1.len() as u64 / 5;
+        let lsn = |n: u64| Lsn::new(startlsn.get() + reclen * n).unwrap();
+        // The new timeline starts after the third record, the files overlap since then.
+        let seg2 = serialize_records(lsn(3), Some(lsn(2)), new_records(1));
+        let seg1path = format!("{}/{}", standby, wal_filename(tli1, startlsn));
+        let seg2path = format!("{}/{}", standby, wal_filename(tli2, lsn(3)));
+        std::fs::write(&seg1path, &seg1).unwrap();
+        std::fs::write(&seg2path, &seg2).unwrap();
+
+        let mut rcv = WalReceiver::new(&standby, lsn(4), 1 << 30).unwrap();
+        let data = serialize_records(lsn(4), Some(lsn(3)), new_records(2));
+        rcv.write(lsn(4), &data).unwrap();
+        assert_eq!(std::fs::read(&seg1path).unwrap(), seg1);
+        let mut expected = seg2.clone();
+        expected.extend_from_slice(&data);
+        assert_eq!(std::fs::read(&seg2path).unwrap(), expected);
+
+        let storage = LocalWalStorage::with_dir(&standby);
+        assert_eq!(storage.find(lsn(2)).unwrap(), (tli1, startlsn));
+        assert_eq!(storage.find(lsn(3)).unwrap(), (tli2, lsn(3)));
+        assert_eq!(storage.find(lsn(4)).unwrap(), (tli2, lsn(3)));
+        let mut walreader = WalReader::new(Box::new(storage), startlsn);
+        for _ in 0..6 {
+            walreader.read_record().unwrap();
+        }
+        assert_eq!(walreader.endtli(), tli2);
+        assert_eq!(walreader.endlsn, lsn(6));
+        assert!(walreader.read_record().is_err());
+
+        // The new file is created in the timeline as well.
+        let mut rcv = WalReceiver::new(&standby, lsn(6), 1).unwrap();
+        let data = serialize_records(lsn(6), Some(lsn(5)), new_records(1));
+        rcv.write(lsn(6), &data).unwrap();
+        let seg3path = format!("{}/{}", standby, wal_filename(tli2, lsn(6)));
+        assert_eq!(std::fs::read(&seg3path).unwrap(), data);
+    }
+
+    // Answer IDENTIFY_SYSTEM of the walreceiver as the walsender.
+    fn identify_system(
+        sockreader: &mut SockReader,
+        sockwriter: &mut SockWriter,
+        tli: TimeLineID,
+        xlogpos: Lsn,
+    ) {
+        let (_, msgdata) = protocol::read_message(sockreader).unwrap();
+        let query = protocol::Query::deserialize(&msgdata).unwrap();
+        assert_eq!(
+            parse_replication_cmd(query.query).unwrap(),
+            ReplicationCmd::IdentifySystem
+        );
+        send_identify_system(sockwriter, tli, xlogpos);
+        protocol::write_message(
+            sockwriter,
+            &protocol::CommandComplete {
+                tag: "IDENTIFY_SYSTEM",
+            },
+        );
+        protocol::write_message(
+            sockwriter,
+            &protocol::ReadyForQuery::new(protocol::XactStatus::NotInBlock),
+        );
+        sockwriter.flush().unwrap();
+    }
+
+    // The primary serves one replication connection, f is called after the startup.
+    fn fake_primary(
+        authcode: u32,
+        f: impl FnOnce(&mut SockReader, &mut SockWriter) + Send + 'static,
+    ) -> (u16, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let primary = thread::spawn(move || {
+            let stream = KBStream::new(listener.accept().unwrap().0);
+            let mut sockreader = BufReader::new(&stream);
+            let mut sockwriter = BufWriter::new(&stream);
+            let mut msg = Vec::new();
+            protocol::read_startup_message(&mut sockreader, &mut msg).unwrap();
+            sockwriter.write_all(&[b'R', 0, 0, 0, 8]).unwrap();
+            sockwriter.write_all(&authcode.to_be_bytes()).unwrap();
+            protocol::write_message(
+                &mut sockwriter,
+                &protocol::ReadyForQuery::new(protocol::XactStatus::NotInBlock),
+            );
+            sockwriter.flush().unwrap();
+            f(&mut sockreader, &mut sockwriter);
+        });
+        return (port, primary);
+    }
+
+    // The walreceiver fails at once rather than waiting for the primary forever.
+    #[test]
+    fn unsupported_auth() {
+        // AuthenticationGSS
+        let (port, primary) = fake_primary(7, |_, _| {});
+        let standby = tempfile::tempdir().unwrap();
+        let standby = standby.path().to_str().unwrap();
+        let startlsn = Lsn::new(20181218).unwrap();
+        let mut rcv = WalReceiver::new(standby, startlsn, 1 << 30).unwrap();
+        let conninfo = format!("host=127.0.0.1 port={}", port);
+        let err = walreceiver_main(&conninfo, &mut rcv, &AtomicBool::new(false)).unwrap_err();
+        assert_eq!(errcode(&err), ERRCODE_FEATURE_NOT_SUPPORTED);
+        assert!(
+            err.to_string().contains("unsupported authentication"),
+            "{}",
+            err
+        );
+        primary.join().unwrap();
+    }
+
+    // The received wal is written in the timeline reported by IDENTIFY_SYSTEM, and the primary
+    // behind the standby is rejected.
+    #[test]
+    fn identify_timeline() {
+        let standby = tempfile::tempdir().unwrap();
+        let standby = standby.path().to_str().unwrap().to_string();
+        let tli1 = TimeLineID::new(1).unwrap();
+        let tli2 = TimeLineID::new(2).unwrap();
+        let startlsn = Lsn::new(20181218).unwrap();
+        let seg1 = serialize_records(startlsn, None, new_records(2));
+        let reclen = seg1.len() as u64 / 2;
+        let lsn = move |n: u64| Lsn::new(startlsn.get() + reclen * n).unwrap();
+        let seg1path = format!("{}/{}", standby, wal_filename(tli1, startlsn));
+        std::fs::write(&seg1path, &seg1).unwrap();
+        let data = serialize_records(lsn(2), Some(lsn(1)), new_records(1));
+
+        let (port, primary) = {
+            let data = data.clone();
+            fake_primary(0, move |sockreader, sockwriter| {
+                identify_system(sockreader, sockwriter, tli2, lsn(3));
+                let (_, msgdata) = protocol::read_message(sockreader).unwrap();
+                let query = protocol::Query::deserialize(&msgdata).unwrap();
+                assert!(matches!(
+                    parse_replication_cmd(query.query).unwrap(),
+                    ReplicationCmd::StartReplication { .. }
+                ));
+                protocol::write_message(sockwriter, &protocol::CopyBothResponse {});
+                let xlogdata = XLogData {
+                    start: lsn(2),
+                    walend: lsn(3),
+                    sendtime: 0,
+                    data: &data,
+                };
+                let msg = xlogdata.serialize();
+                protocol::write_message(sockwriter, &protocol::CopyData { data: &msg });
+                protocol::write_message(sockwriter, &protocol::CopyDone {});
+                sockwriter.flush().unwrap();
+                let _ = protocol::read_message(sockreader);
+            })
+        };
+        let mut rcv = WalReceiver::new(&standby, lsn(2), 1 << 30).unwrap();
+        let conninfo = format!("host=127.0.0.1 port={}", port);
+        walreceiver_main(&conninfo, &mut rcv, &AtomicBool::new(false)).unwrap();
+        primary.join().unwrap();
+        assert_eq!(rcv.endlsn, lsn(3));
+        assert_eq!(std::fs::read(&seg1path).unwrap(), seg1);
+        let seg2path = format!("{}/{}", standby, wal_filename(tli2, lsn(2)));
+        assert_eq!(std::fs::read(&seg2path).unwrap(), data);
+        let storage = Box::new(LocalWalStorage::with_dir(&standby));
+        let mut walreader = WalReader::new(storage, startlsn);
+        for _ in 0..3 {
+            walreader.read_record().unwrap();
+        }
+        assert_eq!(walreader.endtli(), tli2);
+
+        let (port, primary) = fake_primary(0, move |sockreader, sockwriter| {
+            identify_system(sockreader, sockwriter, tli1, lsn(3));
+        });
+        let conninfo = format!("host=127.0.0.1 port={}", port);
+        let err = walreceiver_main(&conninfo, &mut rcv, &AtomicBool::new(false)).unwrap_err();
+        assert!(
+            err.to_string().contains("is behind recovery timeline 2"),
+            "{}",
+            err
+        );
+        primary.join().unwrap();
+    }
+
+    #[test]
+    fn stream() {
+        let primary = tempfile::tempdir().unwrap();
+        let primary = primary.path().to_str().unwrap().to_string();
+        let standby = tempfile::tempdir().unwrap();
+        let standby = standby.path().to_str().unwrap().to_string();
+        let tli = TimeLineID::new(1).unwrap();
+        let startlsn = Lsn::new(20181218).unwrap();
+        let seg1 = serialize_records(startlsn, None, new_records(3));
+        let reclen = seg1.len() as u64 / 3;
+        let seg2lsn = Lsn::new(startlsn.get() + seg1.len() as u64).unwrap();
+        let seg2 = serialize_records(seg2lsn, Lsn::new(seg2lsn.get() - reclen), new_records(5));
+        std::fs::write(
+            format!("{}/{}", primary, wal_filename(tli, startlsn)),
+            &seg1,
+        )
+        .unwrap();
+        std::fs::write(format!("{}/{}", primary, wal_filename(tli, seg2lsn)), &seg2).unwrap();
+
+        let slotdir = tempfile::tempdir().unwrap();
+        let slots: &'static ReplSlots = Box::leak(Box::new(
+            ReplSlots::load(slotdir.path().to_str().unwrap()).unwrap(),
+        ));
+        slots.create("standby1", startlsn).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let termreq = Arc::new(AtomicBool::new(false));
+        let sender = {
+            let termreq = termreq.clone();
+            let primary = primary.clone();
+            thread::spawn(move || {
+                let stream = KBStream::new(listener.accept().unwrap().0);
+                let mut sockreader = BufReader::new(&stream);
+                let mut sockwriter = BufWriter::new(&stream);
+                let mut msg = Vec::new();
+                protocol::read_startup_message(&mut sockreader, &mut msg).unwrap();
+                assert!(StartupMessage::deserialize(&msg).unwrap().replication());
+                protocol::write_message(&mut sockwriter, &protocol::AuthenticationOk {});
+                protocol::write_message(
+                    &mut sockwriter,
+                    &protocol::ReadyForQuery::new(protocol::XactStatus::NotInBlock),
+                );
+                sockwriter.flush().unwrap();
+                identify_system(&mut sockreader, &mut sockwriter, tli, startlsn);
+                let (_, msgdata) = protocol::read_message(&mut sockreader).unwrap();
+                let query = protocol::Query::deserialize(&msgdata).unwrap();
+                let (slot, lsn) = match parse_replication_cmd(query.query).unwrap() {
+                    ReplicationCmd::StartReplication { slot, startlsn } => (slot, startlsn),
+                    _ => panic!("unexpected replication command"),
+                };
+                assert_eq!(lsn, startlsn);
+                assert_eq!(slot.as_deref(), Some("standby1"));
+                let ctx = WalSndCtx {
+                    wal: None,
+                    termreq: &termreq,
+                    slot: slot.map(|v| (slots, v)),
+                    timeout: Duration::from_millis(200),
+                };
+                let storage = Box::new(LocalWalStorage::with_dir(&primary));
+                let ret = start_replication(&mut sockreader, &mut sockwriter, storage, lsn, &ctx);
+                // The standby keeps replying, so the walsender is terminated by termreq.
+                assert_eq!(errcode(&ret.unwrap_err()), ERRCODE_ADMIN_SHUTDOWN);
+            })
+        };
+        let receiver = {
+            let standby = standby.clone();
+            thread::spawn(move || {
+                let mut rcv = WalReceiver::new(&standby, startlsn, 1 << 30).unwrap();
+                rcv.slot_name = Some("standby1".to_string());
+                let conninfo = format!("host=127.0.0.1 port={}", port);
+                let stop = AtomicBool::new(false);
+                // The walsender is terminated by the test, so the connection is closed.
+                assert!(walreceiver_main(&conninfo, &mut rcv, &stop).is_err());
+                rcv.endlsn
+            })
+        };
+
+        let standbyfile = format!("{}/{}", standby, wal_filename(tli, startlsn));
+        let mut expected = seg1.clone();
+        expected.extend_from_slice(&seg2);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let endlsn = Lsn::new(startlsn.get() + expected.len() as u64).unwrap();
+        while std::fs::read(&standbyfile).map_or(true, |d| d != expected)
+            || slots.get("standby1") != Some(endlsn)
+        {
+            assert!(Instant::now() < deadline, "the standby does not catch up");
+            thread::sleep(Duration::from_millis(10));
+        }
+        // Longer than wal_sender_timeout, the keepalive keeps the connection alive.
+        thread::sleep(Duration::from_millis(500));
+        assert!(!sender.is_finished());
+        termreq.store(true, Ordering::Relaxed);
+        sender.join().unwrap();
+        assert_eq!(receiver.join().unwrap(), endlsn);
+
+        let storage = Box::new(LocalWalStorage::with_dir(&standby));
+        let mut walreader = WalReader::new(storage, startlsn);
+        for _ in 0..8 {
+            let (_, data) = walreader.read_record().unwrap();
+            assert_eq!(data.len(), 128);
+        }
+        assert!(walreader.read_record().is_err());
+    }
+
+    #[test]
+    fn timeout() {
+        let primary = tempfile::tempdir().unwrap();
+        let primary = primary.path().to_str().unwrap().to_string();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let sender = thread::spawn(move || {
+            let stream = KBStream::new(listener.accept().unwrap().0);
+            let mut sockreader = BufReader::new(&stream);
+            let mut sockwriter = BufWriter::new(&stream);
+            let termreq = AtomicBool::new(false);
+            let ctx = WalSndCtx {
+                wal: None,
+                termreq: &termreq,
+                slot: None,
+                timeout: Duration::from_millis(300),
+            };
+            let storage = Box::new(LocalWalStorage::with_dir(&primary));
+            let startlsn = Lsn::new(20181218).unwrap();
+            let starttime = Instant::now();
+            let ret = start_replication(&mut sockreader, &mut sockwriter, storage, startlsn, &ctx);
+            let err = ret.unwrap_err();
+            assert_eq!(errcode(&err), ERRCODE_CONNECTION_FAILURE);
+            assert!(format!("{:#}", err).contains("replication timeout"));
+            assert!(starttime.elapsed() >= Duration::from_millis(300));
+        });
+        // The standby never replies.
+        let stream = KBStream::new(TcpStream::connect(("127.0.0.1", port)).unwrap());
+        sender.join().unwrap();
+        let mut sockreader = BufReader::new(&stream);
+        let (msgtype, _) = protocol::read_message(&mut sockreader).unwrap();
+        assert_eq!(msgtype, 'W' as i8);
+        let (msgtype, msgdata) = protocol::read_message(&mut sockreader).unwrap();
+        assert_eq!(msgtype, MsgType::CopyData as i8);
+        let keepalive = PrimaryKeepalive::deserialize(&msgdata).unwrap();
+        assert!(keepalive.reply_requested);
+        assert_eq!(keepalive.walend.get(), 20181218);
+    }
+}
@@ -0,0 +1,467 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use super::slot::REPLSLOT_DIR;
+use super::walreceiver::{connect_primary, read_message, send_message, wait_ready};
+use crate::access::wal::{
+    self, finish_record, is_wal, new_ckpt_rec, parse_wal_filename, Ckpt, Ctl, Lsn, RmgrId,
+    XlogInfo, CONTROL_FILE, WAL_DIR,
+};
+use crate::protocol::{self, Message, MsgType};
+use crate::utils::{sync_dir, KBSystemTime, SessionState};
+use crate::{errctx, kbanyhow, kbbail, kbensure, Oid, SockReader, SockWriter};
+use anyhow::Context;
+use std::fs::{self, read_dir, File, OpenOptions};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::atomic::Ordering::Relaxed;
+use std::thread;
+use std::time::Duration;
+
+// The backup is a sequence of CopyData messages, a file starts with 'f' + path + '\0',
+// and its content is carried by the following 'd' messages.
+const BACKUP_FILE_MSG: u8 = b'f';
+const BACKUP_DATA_MSG: u8 = b'd';
+const BACKUP_CHUNK_SIZE: usize = 128 * 1024;
+
+const BACKUP_FILES: [&str; 2] = ["KB_VERSION", "kuiba.conf"];
+// The wal is not in the list, only the wal needed by the backup is sent.
+const BACKUP_DIRS: [&str; 3] = ["global", "base", "kb_xact"];
+
+// do_pg_start_backup, the backup starts at a new checkpoint, all changes before its redo
+// lsn have been written to the data files before they are copied. The changes after
+// redo will be replayed from the wal sent with the backup.
+pub fn start_backup(state: &SessionState) -> anyhow::Result<Ctl> {
+    let wal = state.wal.ok_or_else(|| {
+        kbanyhow!(
+            ERRCODE_OBJECT_NOT_IN_PREREQUISITE_STATE,
+            "recovery is in progress"
+        )
+    })?;
+    let xact = state.xact.global().unwrap();
+    let redo = wal.start_ckpt();
+    // The transaction that has inserted its commit record before redo may not have updated
+    // the clog yet, wait for it, otherwise its status will be lost in the backup.
+    while xact.ckpt_is_delayed() {
+        thread::sleep(Duration::from_millis(10));
+    }
+    state.clog.flushall()?;
+    state.tabsv.flushall(true)?;
+    state.tabmvcc.flushall(true)?;
+    let curtli = wal.curtli();
+    let ckpt = Ckpt {
+        redo,
+        curtli,
+        prevtli: curtli,
+        nextxid: xact.nextxid(),
+        nextoid: Oid::new(state.oid_creator.unwrap().load(Relaxed)).unwrap(),
+        time: KBSystemTime::now(),
+    };
+    let mut rec = new_ckpt_rec(&ckpt);
+    finish_record(&mut rec, RmgrId::Xlog, XlogInfo::Ckpt as u8, None);
+    let reclen = rec.len() as u64;
+    let endlsn = wal.insert_record(rec);
+    wal.fsync(endlsn);
+    let ckptlsn = Lsn::new(endlsn.get() - reclen).unwrap();
+    log::info!("start backup. ckpt={} redo={}", ckptlsn, redo);
+    Ok(Ctl::new(ckptlsn, ckpt))
+}
+
+fn send_copydata(sockwriter: &mut SockWriter, msg: &[u8]) -> anyhow::Result<()> {
+    sockwriter
+        .write_all(&protocol::CopyData { data: msg }.serialize())
+        .with_context(|| errctx!(ERRCODE_CONNECTION_FAILURE, "could not send data to client"))
+}
+
+fn send_file_hdr(sockwriter: &mut SockWriter, path: &str) -> anyhow::Result<()> {
+    let mut msg = Vec::with_capacity(1 + path.len() + 1);
+    msg.push(BACKUP_FILE_MSG);
+    msg.extend_from_slice(path.as_bytes());
+    msg.push(0);
+    send_copydata(sockwriter, &msg)
+}
+
+fn send_file_data(sockwriter: &mut SockWriter, data: &[u8]) -> anyhow::Result<()> {
+    for chunk in data.chunks(BACKUP_CHUNK_SIZE) {
+        let mut msg = Vec::with_capacity(1 + chunk.len());
+        msg.push(BACKUP_DATA_MSG);
+        msg.extend_from_slice(chunk);
+        send_copydata(sockwriter, &msg)?;
+    }
+    Ok(())
+}
+
+// Send at most limit bytes of the file, the file removed concurrently is skipped.
+fn send_file(
+    sockwriter: &mut SockWriter,
+    datadir: &str,
+    path: &str,
+    limit: Option<u64>,
+) -> anyhow::Result<()> {
+    let file = match File::open(format!("{}/{}", datadir, path)) {
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        v => v?,
+    };
+    send_file_hdr(sockwriter, path)?;
+    let mut file = file.take(limit.unwrap_or(u64::MAX));
+    let mut buf = vec![0u8; BACKUP_CHUNK_SIZE];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            return Ok(());
+        }
+        send_file_data(sockwriter, &buf[..n])?;
+    }
+}
+
+// The manifest of table and its journal are sent before its data files. The L0 file is only
+// appended after it has been recorded in manifest, so the data file copied later is never
+// shorter than what the manifest copied says, and the wal will fix the rest.
+fn send_dir(sockwriter: &mut SockWriter, datadir: &str, dir: &str) -> anyhow::Result<()> {
+    let mut entries = Vec::new();
+    for direntry in read_dir(format!("{}/{}", datadir, dir))? {
+        let direntry = direntry?;
+        let name = direntry.file_name().to_string_lossy().to_string();
+        // The directory of the table in the named tablespace is a symlink, it is sent as a
+        // regular directory.
+        let isdir = match fs::metadata(direntry.path()) {
+            Err(ref e) if e.kind() == ErrorKind::NotFound => continue,
+            v => v?.is_dir(),
+        };
+        entries.push((!name.starts_with("manifest"), name, isdir));
+    }
+    entries.sort();
+    for (_, name, isdir) in entries {
+        let path = format!("{}/{}", dir, name);
+        if path == CONTROL_FILE {
+            continue;
+        }
+        if isdir {
+            send_file_hdr(sockwriter, &format!("{}/", path))?;
+            send_dir(sockwriter, datadir, &path)?;
+        } else {
+            send_file(sockwriter, datadir, &path, None)?;
+        }
+    }
+    Ok(())
+}
+
+// Send the wal files containing [redo, endlsn).
+fn send_wal(
+    sockwriter: &mut SockWriter,
+    datadir: &str,
+    redo: Lsn,
+    endlsn: Lsn,
+) -> anyhow::Result<()> {
+    let mut files = Vec::new();
+    for direntry in read_dir(format!("{}/{}", datadir, WAL_DIR))? {
+        let direntry = direntry?;
+        let name = direntry.file_name();
+        let name = name.as_os_str().as_bytes();
+        if !is_wal(name) {
+            continue;
+        }
+        let (tli, filelsn) = parse_wal_filename(name);
+        files.push((filelsn, tli));
+    }
+    files.sort();
+    send_file_hdr(sockwriter, &format!("{}/", WAL_DIR))?;
+    for (idx, &(filelsn, tli)) in files.iter().enumerate() {
+        if filelsn >= endlsn {
+            break;
+        }
+        if let Some(&(nextlsn, _)) = files.get(idx + 1) {
+            if nextlsn <= redo {
+                continue;
+            }
+        }
+        let path = format!("{}/{}", WAL_DIR, wal::wal_filename(tli, filelsn));
+        send_file(
+            sockwriter,
+            datadir,
+            &path,
+            Some(endlsn.get() - filelsn.get()),
+        )?;
+    }
+    Ok(())
+}
+
+// perform_base_backup, stop_backup is called after all data files have been sent, it
+// returns the end lsn of the wal that the backup needs to be consistent.
+pub fn send_base_backup<F: FnOnce() -> Lsn>(
+    sockwriter: &mut SockWriter,
+    datadir: &str,
+    ctl: &Ctl,
+    stop_backup: F,
+) -> anyhow::Result<()> {
+    protocol::write_message(sockwriter, &protocol::CopyOutResponse {});
+    for file in &BACKUP_FILES {
+        send_file(sockwriter, datadir, file, None)?;
+    }
+    for dir in &BACKUP_DIRS {
+        send_file_hdr(sockwriter, &format!("{}/", dir))?;
+        send_dir(sockwriter, datadir, dir)?;
+    }
+    let endlsn = stop_backup();
+    log::info!("stop backup. endlsn={}", endlsn);
+    send_wal(sockwriter, datadir, ctl.ckptcpy.redo, endlsn)?;
+    send_file_hdr(sockwriter, CONTROL_FILE)?;
+    send_file_data(sockwriter, &ctl.serialize())?;
+    protocol::write_message(sockwriter, &protocol::CopyDone {});
+    sockwriter
+        .flush()
+        .with_context(|| errctx!(ERRCODE_CONNECTION_FAILURE, "could not send data to client"))
+}
+
+fn check_backup_path(path: &str) -> anyhow::Result<()> {
+    let p = Path::new(path);
+    kbensure!(
+        !path.is_empty()
+            && p.is_relative()
+            && p.components()
+                .all(|c| c.as_os_str() != ".." && c.as_os_str() != "."),
+        ERRCODE_PROTOCOL_VIOLATION,
+        "invalid file path in backup. path={}",
+        path
+    );
+    Ok(())
+}
+
+fn finish_file(file: Option<File>) -> anyhow::Result<()> {
+    if let Some(file) = file {
+        file.sync_all()?;
+    }
+    Ok(())
+}
+
+// Receive the backup sent by send_base_backup() into targetdir.
+pub fn receive_base_backup(sockreader: &mut SockReader, targetdir: &str) -> anyhow::Result<()> {
+    let (msgtype, _) = read_message(sockreader)?;
+    kbensure!(
+        msgtype == 'H' as i8,
+        ERRCODE_PROTOCOL_VIOLATION,
+        "unexpected msg. expected=H actual={}",
+        msgtype
+    );
+    let mut dirs = vec![targetdir.to_string()];
+    let mut file: Option<File> = None;
+    loop {
+        let (msgtype, msgdata) = read_message(sockreader)?;
+        if msgtype == MsgType::CopyDone as i8 {
+            break;
+        }
+        kbensure!(
+            msgtype == MsgType::CopyData as i8,
+            ERRCODE_PROTOCOL_VIOLATION,
+            "unexpected msg. expected=d actual={}",
+            msgtype
+        );
+        match msgdata.split_first() {
+            Some((&BACKUP_FILE_MSG, path)) => {
+                finish_file(file.take())?;
+                let path = String::from_utf8_lossy(path.strip_suffix(&[0]).unwrap_or(path));
+                check_backup_path(&path)?;
+                let fullpath = format!("{}/{}", targetdir, path);
+                if let Some(dir) = fullpath.strip_suffix('/') {
+                    fs::create_dir_all(dir)?;
+                    dirs.push(dir.to_string());
+                } else {
+                    file = Some(
+                        OpenOptions::new()
+                            .write(true)
+                            .create(true)
+                            .truncate(true)
+                            .open(&fullpath)?,
+                    );
+                }
+            }
+            Some((&BACKUP_DATA_MSG, data)) => match file {
+                Some(ref mut file) => file.write_all(data)?,
+                None => kbbail!(
+                    ERRCODE_PROTOCOL_VIOLATION,
+                    "receive file data before the file header"
+                ),
+            },
+            _ => kbbail!(ERRCODE_PROTOCOL_VIOLATION, "unexpected backup message"),
+        }
+    }
+    finish_file(file)?;
+    // The replication slots are not copied, just as PostgreSQL.
+    let slotdir = format!("{}/{}", targetdir, REPLSLOT_DIR);
+    fs::create_dir_all(&slotdir)?;
+    dirs.push(slotdir);
+    for dir in &dirs {
+        sync_dir(dir)?;
+    }
+    Ok(())
+}
+
+// pg_basebackup, take a base backup of the primary specified by conninfo into targetdir.
+pub fn base_backup(conninfo: &str, targetdir: &str) -> anyhow::Result<()> {
+    let stream = connect_primary(conninfo)?;
+    let mut sockreader = BufReader::new(&stream);
+    let mut sockwriter = BufWriter::new(&stream);
+    fs::create_dir_all(targetdir)?;
+    send_message(
+        &mut sockwriter,
+        &protocol::Query {
+            query: "BASE_BACKUP",
+        },
+    )?;
+    receive_base_backup(&mut sockreader, targetdir)?;
+    wait_ready(&mut sockreader)?;
+    send_message(&mut sockwriter, &protocol::Terminate {})
+}
+
+#[cfg(test)]
+mod basebackup_test {
+    use super::{receive_base_backup, send_base_backup};
+    use crate::access::wal::{
+        finish_record, new_ckpt_rec, serialize_records, start_record_raw, wal_filename, Ckpt, Ctl,
+        LocalWalStorage, Lsn, RmgrId, TimeLineID, WalReader, XlogInfo, CONTROL_FILE, WAL_DIR,
+    };
+    use crate::replication::slot::{ReplSlots, REPLSLOT_DIR};
+    use crate::utils::ssl::KBStream;
+    use crate::utils::{KBSystemTime, Xid};
+    use crate::Oid;
+    use std::fs;
+    use std::io::{BufReader, BufWriter};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    fn write_file(dir: &str, path: &str, data: &[u8]) {
+        let path = format!("{}/{}", dir, path);
+        fs::create_dir_all(std::path::Path::new(&path).parent().unwrap()).unwrap();
+        fs::write(path, data).unwrap();
+    }
+
+    fn new_record(i: u8) -> Vec<u8> {
+        let mut rec = start_record_raw(&[i; 64]);
+        finish_record(&mut rec, RmgrId::Xlog, 0x30, None);
+        rec
+    }
+
+    #[test]
+    fn backup_and_start() {
+        let primary = tempfile::tempdir().unwrap();
+        let primary = primary.path().to_str().unwrap().to_string();
+        let target = tempfile::tempdir().unwrap();
+        let target = target.path().to_str().unwrap().to_string();
+        let datafiles = [
+            ("KB_VERSION", vec![b'0', b'\n']),
+            ("global/meta.db", vec![1u8; 100]),
+            ("base/1/meta.db", vec![2u8; 300 * 1024]),
+            ("base/1/65536/manifest", vec![3u8; 28]),
+            ("base/1/65536/1.d", vec![4u8; 1024]),
+            ("kb_xact/0", vec![5u8; 8192]),
+        ];
+        for (path, data) in &datafiles {
+            write_file(&primary, path, data);
+        }
+        fs::create_dir_all(format!("{}/base/2", primary)).unwrap();
+        let spcdir = tempfile::tempdir().unwrap();
+        let spcdir = spcdir.path().to_str().unwrap().to_string();
+        write_file(&spcdir, "2/65537/manifest", &[9u8; 28]);
+        std::os::unix::fs::symlink(
+            format!("{}/2/65537", spcdir),
+            format!("{}/base/2/65537", primary),
+        )
+        .unwrap();
+        write_file(&primary, CONTROL_FILE, &[6u8; 16]);
+
+        // wal: [r0 r1] [ckpt r2 partial], redo is the end of r0.
+        let tli = TimeLineID::new(1).unwrap();
+        let startlsn = Lsn::new(20181218).unwrap();
+        let r0 = new_record(0);
+        let r1 = new_record(1);
+        let r2 = new_record(2);
+        let redo = Lsn::new(startlsn.get() + r0.len() as u64).unwrap();
+        let ckpt = Ckpt {
+            redo,
+            curtli: tli,
+            prevtli: tli,
+            nextxid: Xid::new(2).unwrap(),
+            nextoid: Oid::new(65536).unwrap(),
+            time: KBSystemTime::now(),
+        };
+        let mut ckptrec = new_ckpt_rec(&ckpt);
+        finish_record(&mut ckptrec, RmgrId::Xlog, XlogInfo::Ckpt as u8, None);
+        let ckptlsn = Lsn::new(redo.get() + r1.len() as u64).unwrap();
+        let endlsn = Lsn::new(ckptlsn.get() + (ckptrec.len() + r2.len()) as u64).unwrap();
+        let wal0 = serialize_records(startlsn, None, vec![r0, r1]);
+        let mut wal1 = serialize_records(ckptlsn, Some(redo), vec![ckptrec, r2]);
+        // The record being written when the backup stops.
+        wal1.extend_from_slice(&[7u8; 20]);
+        let oldlsn = Lsn::new(1218).unwrap();
+        write_file(
+            &primary,
+            &format!("{}/{}", WAL_DIR, wal_filename(tli, oldlsn)),
+            &[8u8; 10],
+        );
+        write_file(
+            &primary,
+            &format!("{}/{}", WAL_DIR, wal_filename(tli, startlsn)),
+            &wal0,
+        );
+        write_file(
+            &primary,
+            &format!("{}/{}", WAL_DIR, wal_filename(tli, ckptlsn)),
+            &wal1,
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let sender = {
+            let primary = primary.clone();
+            thread::spawn(move || {
+                let stream = KBStream::new(listener.accept().unwrap().0);
+                let mut sockwriter = BufWriter::new(&stream);
+                let ctl = Ctl::new(ckptlsn, ckpt);
+                send_base_backup(&mut sockwriter, &primary, &ctl, || endlsn).unwrap();
+            })
+        };
+        let stream = KBStream::new(TcpStream::connect(("127.0.0.1", port)).unwrap());
+        let mut sockreader = BufReader::new(&stream);
+        receive_base_backup(&mut sockreader, &target).unwrap();
+        sender.join().unwrap();
+
+        for (path, data) in &datafiles {
+            assert_eq!(&fs::read(format!("{}/{}", target, path)).unwrap(), data);
+        }
+        assert!(fs::metadata(format!("{}/base/2", target)).unwrap().is_dir());
+        let spctable = format!("{}/base/2/65537", target);
+        assert!(fs::symlink_metadata(&spctable).unwrap().is_dir());
+        assert_eq!(
+            fs::read(format!("{}/manifest", spctable)).unwrap(),
+            [9u8; 28]
+        );
+        assert!(ReplSlots::load(&format!("{}/{}", target, REPLSLOT_DIR)).is_ok());
+        let walfiles = fs::read_dir(format!("{}/{}", target, WAL_DIR))
+            .unwrap()
+            .count();
+        assert_eq!(walfiles, 2);
+
+        // Start from the backup just as redo() does.
+        let ctl =
+            Ctl::deserialize(&fs::read(format!("{}/{}", target, CONTROL_FILE)).unwrap()).unwrap();
+        assert_eq!(ctl.ckpt, ckptlsn);
+        assert_eq!(ctl.ckptcpy.redo, redo);
+        let storage = LocalWalStorage::with_dir(&format!("{}/{}", target, WAL_DIR));
+        let mut walreader = WalReader::new(Box::new(storage), ctl.ckptcpy.redo);
+        let mut infos = Vec::new();
+        while let Ok((hdr, _)) = walreader.read_record() {
+            infos.push(hdr.rmgr_info());
+        }
+        assert_eq!(infos, vec![0x30, XlogInfo::Ckpt as u8, 0x30]);
+        assert_eq!(walreader.endlsn, endlsn);
+        assert!(walreader.endlsn > ctl.ckpt);
+    }
+}
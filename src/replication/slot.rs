@@ -0,0 +1,271 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::access::wal::Lsn;
+use crate::utils::sync_dir;
+use crate::{kbbail, kbensure};
+use anyhow::anyhow;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::{self, read_dir, File};
+use std::io::Write;
+use std::sync::Mutex;
+
+pub const REPLSLOT_DIR: &str = "kb_replslot";
+const NAMEDATALEN: usize = 64;
+const SLOT_FILE_LEN: usize = 8 + 4;
+
+// A replication slot records the restart_lsn of one standby, the wal at or after the
+// minimum restart_lsn of all slots will be retained. Each slot is persisted in
+// kb_replslot/<name> as restart_lsn + crc32c.
+pub struct ReplSlots {
+    dir: String,
+    slots: Mutex<HashMap<String, Lsn>>,
+}
+
+fn check_slot_name(name: &str) -> anyhow::Result<()> {
+    kbensure!(
+        !name.is_empty() && name.len() < NAMEDATALEN,
+        ERRCODE_INVALID_NAME,
+        "replication slot name \"{}\" is too short or too long",
+        name
+    );
+    kbensure!(
+        name.bytes()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == b'_'),
+        ERRCODE_INVALID_NAME,
+        "replication slot name \"{}\" contains invalid character",
+        name
+    );
+    return Ok(());
+}
+
+fn ser_slot(restart_lsn: Lsn) -> [u8; SLOT_FILE_LEN] {
+    let mut d = [0; SLOT_FILE_LEN];
+    d[..8].copy_from_slice(&restart_lsn.get().to_le_bytes());
+    let crc = crc32c::crc32c(&d[..8]);
+    d[8..].copy_from_slice(&crc.to_le_bytes());
+    return d;
+}
+
+fn de_slot(d: &[u8]) -> anyhow::Result<Lsn> {
+    anyhow::ensure!(
+        d.len() == SLOT_FILE_LEN,
+        "invalid slot file. len={}",
+        d.len()
+    );
+    let crc = u32::from_le_bytes(d[8..].try_into().unwrap());
+    let actual_crc = crc32c::crc32c(&d[..8]);
+    anyhow::ensure!(
+        crc == actual_crc,
+        "unexpected crc32c. expected={} actual={}",
+        crc,
+        actual_crc
+    );
+    let lsn = u64::from_le_bytes(d[..8].try_into().unwrap());
+    Lsn::new(lsn).ok_or_else(|| anyhow!("invalid restart_lsn"))
+}
+
+impl ReplSlots {
+    pub fn load(dir: &str) -> anyhow::Result<ReplSlots> {
+        fs::create_dir_all(dir)?;
+        let mut slots = HashMap::new();
+        for direntry in read_dir(dir)? {
+            let direntry = direntry?;
+            let name = direntry.file_name();
+            let name = match name.to_str() {
+                Some(n) if check_slot_name(n).is_ok() => n.to_string(),
+                _ => continue, // such as the temporary file.
+            };
+            let restart_lsn = de_slot(&fs::read(direntry.path())?)
+                .map_err(|e| anyhow!("ReplSlots::load: slot={} err={}", name, e))?;
+            log::info!(
+                "load replication slot. name={} restart_lsn={}",
+                name,
+                restart_lsn
+            );
+            slots.insert(name, restart_lsn);
+        }
+        Ok(ReplSlots {
+            dir: dir.to_string(),
+            slots: Mutex::new(slots),
+        })
+    }
+
+    fn persist(&self, name: &str, restart_lsn: Lsn) -> anyhow::Result<()> {
+        let tmppath = format!("{}/{}.tmp", self.dir, name);
+        let mut file = File::create(&tmppath)?;
+        file.write_all(&ser_slot(restart_lsn))?;
+        file.sync_data()?;
+        fs::rename(&tmppath, format!("{}/{}", self.dir, name))?;
+        sync_dir(&self.dir)?;
+        Ok(())
+    }
+
+    // ReplicationSlotCreate
+    pub fn create(&self, name: &str, restart_lsn: Lsn) -> anyhow::Result<()> {
+        check_slot_name(name)?;
+        let mut slots = self.slots.lock().unwrap();
+        if slots.contains_key(name) {
+            kbbail!(
+                ERRCODE_DUPLICATE_OBJECT,
+                "replication slot \"{}\" already exists",
+                name
+            );
+        }
+        self.persist(name, restart_lsn)?;
+        slots.insert(name.to_string(), restart_lsn);
+        return Ok(());
+    }
+
+    // ReplicationSlotDrop
+    pub fn drop(&self, name: &str) -> anyhow::Result<()> {
+        let mut slots = self.slots.lock().unwrap();
+        if !slots.contains_key(name) {
+            kbbail!(
+                ERRCODE_UNDEFINED_OBJECT,
+                "replication slot \"{}\" does not exist",
+                name
+            );
+        }
+        fs::remove_file(format!("{}/{}", self.dir, name))?;
+        sync_dir(&self.dir)?;
+        slots.remove(name);
+        return Ok(());
+    }
+
+    // restart_lsn never goes backwards.
+    pub fn advance(&self, name: &str, restart_lsn: Lsn) -> anyhow::Result<Lsn> {
+        let mut slots = self.slots.lock().unwrap();
+        let cur = match slots.get_mut(name) {
+            None => kbbail!(
+                ERRCODE_UNDEFINED_OBJECT,
+                "replication slot \"{}\" does not exist",
+                name
+            ),
+            Some(cur) => cur,
+        };
+        if restart_lsn > *cur {
+            self.persist(name, restart_lsn)?;
+            *cur = restart_lsn;
+        }
+        return Ok(*cur);
+    }
+
+    pub fn get(&self, name: &str) -> Option<Lsn> {
+        self.slots.lock().unwrap().get(name).copied()
+    }
+
+    pub fn min_restart_lsn(&self) -> Option<Lsn> {
+        self.slots.lock().unwrap().values().min().copied()
+    }
+
+    // KeepLogSeg, the wal before the returned lsn can be removed. redo is the redo lsn of
+    // the last checkpoint, endlsn is the current end of wal. An inactive slot can not pin
+    // more than max_keep_size bytes of wal if max_keep_size is not -1.
+    pub fn wal_keep_lsn(&self, redo: Lsn, endlsn: Lsn, max_keep_size: i64) -> Lsn {
+        let mut keep = redo;
+        if let Some(slotlsn) = self.min_restart_lsn() {
+            if slotlsn < keep {
+                keep = slotlsn;
+            }
+        }
+        if max_keep_size >= 0 {
+            let limit = endlsn.get().saturating_sub(max_keep_size as u64);
+            if keep.get() < limit {
+                log::warn!(
+                    "replication slots exceed max_slot_wal_keep_size. restart_lsn={} endlsn={}",
+                    keep,
+                    endlsn
+                );
+                keep = std::cmp::min(redo, Lsn::new(limit).unwrap());
+            }
+        }
+        return keep;
+    }
+}
+
+#[cfg(test)]
+mod slot_test {
+    use super::ReplSlots;
+    use crate::access::wal::{wal_filename, LocalWalStorage, Lsn, TimeLineID, WalStorage};
+    use std::path::Path;
+
+    const SEGSIZE: u64 = 1024;
+
+    fn lsn(v: u64) -> Lsn {
+        Lsn::new(v).unwrap()
+    }
+
+    fn segpath(dir: &str, seg: u64) -> String {
+        let tli = TimeLineID::new(1).unwrap();
+        format!("{}/{}", dir, wal_filename(tli, lsn(SEGSIZE * seg)))
+    }
+
+    #[test]
+    fn retain_wal() {
+        let datadir = tempfile::tempdir().unwrap();
+        let waldir = datadir.path().join("kb_wal");
+        let waldir = waldir.to_str().unwrap();
+        let slotdir = datadir.path().join("kb_replslot");
+        let slotdir = slotdir.to_str().unwrap();
+        std::fs::create_dir_all(waldir).unwrap();
+        for seg in 1..=4 {
+            std::fs::write(segpath(waldir, seg), vec![0; SEGSIZE as usize]).unwrap();
+        }
+        let mut storage = LocalWalStorage::with_dir(waldir);
+        let redo = lsn(SEGSIZE * 4 + 33);
+        let endlsn = lsn(SEGSIZE * 5);
+
+        let slots = ReplSlots::load(slotdir).unwrap();
+        slots.create("standby1", lsn(SEGSIZE * 2 + 1)).unwrap();
+        assert!(slots.create("standby1", lsn(SEGSIZE)).is_err());
+        assert!(slots.create("Standby", lsn(SEGSIZE)).is_err());
+        let keep = slots.wal_keep_lsn(redo, endlsn, -1);
+        assert_eq!(keep, lsn(SEGSIZE * 2 + 1));
+        storage.remove_before(keep).unwrap();
+        assert!(!Path::new(&segpath(waldir, 1)).exists());
+        assert!(Path::new(&segpath(waldir, 2)).exists());
+
+        // The slot is persisted.
+        let slots = ReplSlots::load(slotdir).unwrap();
+        assert_eq!(slots.get("standby1"), Some(lsn(SEGSIZE * 2 + 1)));
+        assert_eq!(
+            slots.advance("standby1", lsn(SEGSIZE)).unwrap(),
+            lsn(SEGSIZE * 2 + 1)
+        );
+        slots.advance("standby1", lsn(SEGSIZE * 3)).unwrap();
+        storage
+            .remove_before(slots.wal_keep_lsn(redo, endlsn, -1))
+            .unwrap();
+        assert!(!Path::new(&segpath(waldir, 2)).exists());
+        assert!(Path::new(&segpath(waldir, 3)).exists());
+
+        // An inactive slot can not pin wal beyond max_slot_wal_keep_size.
+        let keep = slots.wal_keep_lsn(redo, endlsn, SEGSIZE as i64);
+        assert_eq!(keep, lsn(SEGSIZE * 4));
+        assert_eq!(slots.wal_keep_lsn(redo, endlsn, 0), redo);
+        assert_eq!(
+            slots.wal_keep_lsn(redo, endlsn, 3 * SEGSIZE as i64),
+            lsn(SEGSIZE * 3)
+        );
+
+        slots.drop("standby1").unwrap();
+        assert!(slots.drop("standby1").is_err());
+        assert_eq!(slots.min_restart_lsn(), None);
+        storage
+            .remove_before(slots.wal_keep_lsn(redo, endlsn, -1))
+            .unwrap();
+        assert!(!Path::new(&segpath(waldir, 3)).exists());
+        assert!(Path::new(&segpath(waldir, 4)).exists());
+        let slots = ReplSlots::load(slotdir).unwrap();
+        assert_eq!(slots.min_restart_lsn(), None);
+    }
+}
@@ -0,0 +1,477 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use super::{format_lsn, PrimaryKeepalive, StandbyStatusUpdate, XLogData};
+use crate::access::wal::{
+    is_wal, parse_wal_filename, wal_filename, LocalWalStorage, Lsn, TimeLineID, WalStorage,
+};
+use crate::protocol::{self, Message, MsgType};
+use crate::utils::auth::{md5_client_response, ScramClient, SCRAM_SHA_256_NAME};
+use crate::utils::ssl::KBStream;
+use crate::utils::KBSystemTime;
+use crate::{errctx, kbanyhow, kbbail, kbensure, Progress, SockReader, SockWriter};
+use anyhow::Context;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::{read_dir, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::net::TcpStream;
+use std::os::unix::ffi::OsStrExt;
+use std::sync::atomic::{AtomicBool, Ordering::Relaxed};
+
+// WalReceiver writes the wal received from the primary into dir. The record is never
+// split across files since WalReader can not read such records, so we only switch to
+// a new file at the boundary of XLogData.
+pub struct WalReceiver {
+    dir: String,
+    wal_file_max_size: u64,
+    // The received wal continues the local timeline, the primary is expected to be in it.
+    tli: TimeLineID,
+    file: Option<(Lsn, File)>,
+    pub endlsn: Lsn,
+    // The replay progress reported to the primary.
+    pub replay: Option<&'static Progress>,
+    pub slot_name: Option<String>,
+}
+
+impl WalReceiver {
+    // Continue writing the file containing startlsn if any, the content after startlsn
+    // will be discarded, it may be a torn record. The file is chosen as LocalWalStorage::find(),
+    // the last file of the largest timeline that starts at or before startlsn.
+    pub fn new(dir: &str, startlsn: Lsn, wal_file_max_size: u64) -> anyhow::Result<WalReceiver> {
+        let mut found: Option<(TimeLineID, Lsn, u64)> = None;
+        for direntry in read_dir(dir)? {
+            let direntry = direntry?;
+            let name = direntry.file_name();
+            let name = name.as_os_str().as_bytes();
+            if !is_wal(name) {
+                continue;
+            }
+            let (tli, filelsn) = parse_wal_filename(name);
+            if filelsn > startlsn {
+                continue;
+            }
+            if let Some((foundtli, foundlsn, _)) = found {
+                if (foundtli, foundlsn) > (tli, filelsn) {
+                    continue;
+                }
+            }
+            found = Some((tli, filelsn, direntry.metadata()?.len()));
+        }
+        let tli = match found {
+            Some((tli, _, _)) => tli,
+            None => LocalWalStorage::with_dir(dir).last_tli()?,
+        };
+        let file = match found {
+            Some((tli, filelsn, filelen)) if startlsn.get() - filelsn.get() <= filelen => {
+                let off = startlsn.get() - filelsn.get();
+                let path = format!("{}/{}", dir, wal_filename(tli, filelsn));
+                let mut file = OpenOptions::new().write(true).open(path)?;
+                file.set_len(off)?;
+                file.seek(SeekFrom::Start(off))?;
+                Some((filelsn, file))
+            }
+            _ => None,
+        };
+        Ok(WalReceiver {
+            dir: dir.to_string(),
+            wal_file_max_size,
+            tli,
+            file,
+            endlsn: startlsn,
+            replay: None,
+            slot_name: None,
+        })
+    }
+
+    fn create_file(&mut self, lsn: Lsn) -> anyhow::Result<()> {
+        let path = format!("{}/{}", self.dir, wal_filename(self.tli, lsn));
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(path)?;
+        self.file = Some((lsn, file));
+        Ok(())
+    }
+
+    // The received wal is written in the timeline of the primary. The file of the old timeline
+    // is closed, so the next XLogData starts a file of the new timeline that LocalWalStorage::find()
+    // prefers. The timeline history is not fetched, the primary is expected to switch to the
+    // new timeline after the wal received.
+    pub fn follow_timeline(&mut self, tli: TimeLineID) -> anyhow::Result<()> {
+        kbensure!(
+            tli >= self.tli,
+            ERRCODE_CONNECTION_FAILURE,
+            "highest timeline {} of the primary is behind recovery timeline {}",
+            tli,
+            self.tli
+        );
+        if tli > self.tli {
+            log::info!(
+                "follow the timeline of the primary. endlsn={} tli={} newtli={}",
+                self.endlsn,
+                self.tli,
+                tli
+            );
+            self.tli = tli;
+            self.file = None;
+        }
+        return Ok(());
+    }
+
+    // XLogWalRcvSendReply, the received wal has always been flushed.
+    fn status(&self) -> StandbyStatusUpdate {
+        StandbyStatusUpdate {
+            write: self.endlsn.get(),
+            flush: self.endlsn.get(),
+            apply: self.replay.map_or(0, |v| v.get()),
+            sendtime: KBSystemTime::now().into(),
+            reply_requested: false,
+        }
+    }
+
+    pub fn write(&mut self, start: Lsn, data: &[u8]) -> anyhow::Result<()> {
+        kbensure!(
+            start == self.endlsn,
+            ERRCODE_PROTOCOL_VIOLATION,
+            "unexpected XLogData. expected={} actual={}",
+            self.endlsn,
+            start
+        );
+        let switch = match self.file {
+            None => true,
+            Some((filelsn, _)) => start.get() - filelsn.get() >= self.wal_file_max_size,
+        };
+        if switch {
+            self.create_file(start)?;
+        }
+        let file = &mut self.file.as_mut().unwrap().1;
+        file.write_all(data)?;
+        file.sync_data()?;
+        self.endlsn = Lsn::new(start.get() + data.len() as u64).unwrap();
+        Ok(())
+    }
+}
+
+// The primary_conninfo is a space-separated list of keyword=value, such as
+// "host=127.0.0.1 port=1218 user=kuiba". Only host, port, user, password and dbname are used.
+fn parse_conninfo(conninfo: &str) -> anyhow::Result<HashMap<&str, &str>> {
+    let mut kvs = HashMap::new();
+    for kv in conninfo.split_whitespace() {
+        let mut kviter = kv.splitn(2, '=');
+        let k = kviter.next().unwrap();
+        let v = match kviter.next() {
+            None => kbbail!(
+                ERRCODE_INVALID_PARAMETER_VALUE,
+                "invalid conninfo. conninfo={}",
+                conninfo
+            ),
+            Some(v) => v,
+        };
+        kvs.insert(k, v);
+    }
+    Ok(kvs)
+}
+
+fn errmsg(d: &[u8]) -> String {
+    // Fields are ended with '\0', and 'M' is the message field.
+    for field in d.split(|&v| v == 0) {
+        if let Some((&b'M', msg)) = field.split_first() {
+            return String::from_utf8_lossy(msg).to_string();
+        }
+    }
+    return String::from_utf8_lossy(d).to_string();
+}
+
+pub(super) fn read_message(sockreader: &mut SockReader) -> anyhow::Result<(i8, Vec<u8>)> {
+    let (msgtype, msgdata) = protocol::read_message(sockreader).with_context(|| {
+        errctx!(
+            ERRCODE_CONNECTION_FAILURE,
+            "could not receive data from the primary"
+        )
+    })?;
+    kbensure!(
+        msgtype != 'E' as i8,
+        ERRCODE_CONNECTION_FAILURE,
+        "error from the primary: {}",
+        errmsg(&msgdata)
+    );
+    Ok((msgtype, msgdata))
+}
+
+pub(super) fn send_message<T: Message>(sockwriter: &mut SockWriter, msg: &T) -> anyhow::Result<()> {
+    sockwriter
+        .write_all(&msg.serialize())
+        .and_then(|_| sockwriter.flush())
+        .with_context(|| {
+            errctx!(
+                ERRCODE_CONNECTION_FAILURE,
+                "could not send data to the primary"
+            )
+        })
+}
+
+// Wait for ReadyForQuery, other messages such as ParameterStatus are ignored.
+pub(super) fn wait_ready(sockreader: &mut SockReader) -> anyhow::Result<()> {
+    loop {
+        let (msgtype, _) = read_message(sockreader)?;
+        if msgtype == 'Z' as i8 {
+            return Ok(());
+        }
+    }
+}
+
+fn required_password(password: Option<&str>) -> anyhow::Result<&str> {
+    match password {
+        None => kbbail!(
+            ERRCODE_INVALID_PASSWORD,
+            "the primary requires a password but conninfo has none"
+        ),
+        Some(v) => Ok(v),
+    }
+}
+
+// Reads the SASL message of the primary whose authentication code is code, returns the data
+// following the code.
+fn read_sasl_message(sockreader: &mut SockReader, code: u32) -> anyhow::Result<String> {
+    let (msgtype, msgdata) = read_message(sockreader)?;
+    kbensure!(
+        msgtype == 'R' as i8 && msgdata.get(..4) == Some(&code.to_be_bytes()),
+        ERRCODE_PROTOCOL_VIOLATION,
+        "unexpected SASL message. expected code={} msgtype={} msg={:?}",
+        code,
+        msgtype,
+        msgdata
+    );
+    String::from_utf8(msgdata[4..].to_vec())
+        .map_err(|_| kbanyhow!(ERRCODE_PROTOCOL_VIOLATION, "invalid UTF-8 in SASL message"))
+}
+
+// The SCRAM-SHA-256 exchange, mechanisms is the data of AuthenticationSASL.
+fn scram_authenticate(
+    sockreader: &mut SockReader,
+    sockwriter: &mut SockWriter,
+    mechanisms: &[u8],
+    password: &str,
+) -> anyhow::Result<()> {
+    kbensure!(
+        mechanisms
+            .split(|&c| c == 0)
+            .any(|v| v == SCRAM_SHA_256_NAME.as_bytes()),
+        ERRCODE_PROTOCOL_VIOLATION,
+        "none of the SASL authentication mechanisms is supported. mechanisms={:?}",
+        mechanisms
+    );
+    let mut client = ScramClient::new(password);
+    let client_first = client.client_first();
+    let msg = protocol::SASLInitialResponse {
+        mechanism: SCRAM_SHA_256_NAME,
+        data: Some(client_first.as_bytes()),
+    };
+    send_message(sockwriter, &msg)?;
+    let server_first = read_sasl_message(sockreader, 11)?;
+    let client_final = client.client_final(&server_first)?;
+    let msg = protocol::SASLResponse {
+        data: client_final.as_bytes(),
+    };
+    send_message(sockwriter, &msg)?;
+    let server_final = read_sasl_message(sockreader, 12)?;
+    client.verify_server_final(&server_final)
+}
+
+// Answer the password, md5 or SCRAM authentication request of the primary with the password of
+// conninfo, AuthenticationOk is left to wait_ready(). The other requests fail at once rather than
+// waiting for the primary forever.
+fn authenticate(
+    sockreader: &mut SockReader,
+    sockwriter: &mut SockWriter,
+    user: &str,
+    password: Option<&str>,
+) -> anyhow::Result<()> {
+    let (msgtype, msgdata) = read_message(sockreader)?;
+    if msgtype != 'R' as i8 {
+        return Ok(());
+    }
+    match msgdata.get(..4) {
+        Some([0, 0, 0, 0]) => Ok(()),
+        Some([0, 0, 0, 3]) => {
+            let password = required_password(password)?;
+            send_message(sockwriter, &protocol::PasswordMessage { password })
+        }
+        Some([0, 0, 0, 5]) => {
+            let salt = msgdata.get(4..8).ok_or_else(|| {
+                kbanyhow!(
+                    ERRCODE_PROTOCOL_VIOLATION,
+                    "invalid AuthenticationMD5Password. msg={:?}",
+                    msgdata
+                )
+            })?;
+            let resp = md5_client_response(user, required_password(password)?, salt);
+            send_message(sockwriter, &protocol::PasswordMessage { password: &resp })
+        }
+        Some([0, 0, 0, 10]) => scram_authenticate(
+            sockreader,
+            sockwriter,
+            &msgdata[4..],
+            required_password(password)?,
+        ),
+        _ => kbbail!(
+            ERRCODE_FEATURE_NOT_SUPPORTED,
+            "unsupported authentication request from the primary. msg={:?}",
+            msgdata
+        ),
+    }
+}
+
+// Connect to the primary and finish the startup of the replication connection, the
+// primary is waiting for the replication command when it returns.
+pub(super) fn connect_primary(conninfo: &str) -> anyhow::Result<KBStream> {
+    let kvs = parse_conninfo(conninfo)?;
+    let host = kvs.get("host").map_or("127.0.0.1", |v| *v);
+    let port = kvs.get("port").map_or("1218", |v| *v);
+    let user = kvs.get("user").map_or("kuiba", |v| *v);
+    let dbname = kvs.get("dbname").map_or("kuiba", |v| *v);
+    let stream = TcpStream::connect(format!("{}:{}", host, port)).with_context(|| {
+        errctx!(
+            ERRCODE_CONNECTION_FAILURE,
+            "could not connect to the primary. conninfo={}",
+            conninfo
+        )
+    })?;
+    let stream = KBStream::new(stream);
+    {
+        let mut sockreader = BufReader::new(&stream);
+        let mut sockwriter = BufWriter::new(&stream);
+        let mut params = HashMap::new();
+        params.insert("user", user);
+        params.insert("database", dbname);
+        params.insert("replication", "true");
+        send_message(&mut sockwriter, &protocol::StartupMessage::new(params)?)?;
+        let password = kvs.get("password").copied();
+        authenticate(&mut sockreader, &mut sockwriter, user, password)?;
+        wait_ready(&mut sockreader)?;
+    }
+    Ok(stream)
+}
+
+// The column idx of the DataRow, None if it is NULL.
+fn datarow_column(d: &[u8], idx: usize) -> anyhow::Result<Option<&[u8]>> {
+    let invalid = || kbanyhow!(ERRCODE_PROTOCOL_VIOLATION, "invalid DataRow. msg={:?}", d);
+    let ncols = u16::from_be_bytes(d.get(..2).ok_or_else(invalid)?.try_into().unwrap());
+    kbensure!(
+        idx < ncols as usize,
+        ERRCODE_PROTOCOL_VIOLATION,
+        "invalid DataRow. msg={:?}",
+        d
+    );
+    let mut off = 2;
+    for colidx in 0..=idx {
+        let len = d.get(off..off + 4).ok_or_else(invalid)?;
+        let len = i32::from_be_bytes(len.try_into().unwrap());
+        off += 4;
+        if len < 0 {
+            if colidx == idx {
+                return Ok(None);
+            }
+            continue;
+        }
+        let col = d.get(off..off + len as usize).ok_or_else(invalid)?;
+        if colidx == idx {
+            return Ok(Some(col));
+        }
+        off += len as usize;
+    }
+    unreachable!()
+}
+
+// IDENTIFY_SYSTEM, returns the timeline of the primary.
+fn identify_system(
+    sockreader: &mut SockReader,
+    sockwriter: &mut SockWriter,
+) -> anyhow::Result<TimeLineID> {
+    let query = protocol::Query {
+        query: "IDENTIFY_SYSTEM",
+    };
+    send_message(sockwriter, &query)?;
+    let mut tli = None;
+    loop {
+        let (msgtype, msgdata) = read_message(sockreader)?;
+        if msgtype == 'D' as i8 {
+            tli = datarow_column(&msgdata, 1)?
+                .and_then(|v| std::str::from_utf8(v).ok())
+                .and_then(|v| v.parse().ok());
+        } else if msgtype == 'Z' as i8 {
+            break;
+        }
+    }
+    return tli.ok_or_else(|| {
+        kbanyhow!(
+            ERRCODE_PROTOCOL_VIOLATION,
+            "invalid response of IDENTIFY_SYSTEM from the primary"
+        )
+    });
+}
+
+// WalReceiverMain, connect to the primary and write the received wal until the connection
+// is closed or stop is set.
+pub fn walreceiver_main(
+    conninfo: &str,
+    rcv: &mut WalReceiver,
+    stop: &AtomicBool,
+) -> anyhow::Result<()> {
+    let stream = connect_primary(conninfo)?;
+    let mut sockreader = BufReader::new(&stream);
+    let mut sockwriter = BufWriter::new(&stream);
+    let tli = identify_system(&mut sockreader, &mut sockwriter)?;
+    rcv.follow_timeline(tli)?;
+    let slot = match rcv.slot_name {
+        None => String::new(),
+        Some(ref name) => format!("SLOT {} ", name),
+    };
+    let cmd = format!(
+        "START_REPLICATION {}PHYSICAL {}",
+        slot,
+        format_lsn(rcv.endlsn)
+    );
+    send_message(&mut sockwriter, &protocol::Query { query: &cmd })?;
+    let (msgtype, _) = read_message(&mut sockreader)?;
+    kbensure!(
+        msgtype == 'W' as i8,
+        ERRCODE_PROTOCOL_VIOLATION,
+        "unexpected msg. expected=W actual={}",
+        msgtype
+    );
+    log::info!("start streaming. startlsn={}", rcv.endlsn);
+    while !stop.load(Relaxed) {
+        let (msgtype, msgdata) = read_message(&mut sockreader)?;
+        if msgtype == MsgType::CopyDone as i8 {
+            break;
+        }
+        kbensure!(
+            msgtype == MsgType::CopyData as i8,
+            ERRCODE_PROTOCOL_VIOLATION,
+            "unexpected msg. expected=d actual={}",
+            msgtype
+        );
+        if let Some(keepalive) = PrimaryKeepalive::deserialize(&msgdata) {
+            if !keepalive.reply_requested {
+                continue;
+            }
+        } else {
+            let xlogdata = XLogData::deserialize(&msgdata)?;
+            rcv.write(xlogdata.start, xlogdata.data)?;
+        }
+        let status = rcv.status().serialize();
+        send_message(&mut sockwriter, &protocol::CopyData { data: &status })?;
+    }
+    let _ = send_message(&mut sockwriter, &protocol::CopyDone {});
+    Ok(())
+}
@@ -0,0 +1,379 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use super::basebackup::{send_base_backup, start_backup};
+use super::slot::ReplSlots;
+use super::{format_lsn, parse_lsn, PrimaryKeepalive, StandbyStatusUpdate, XLogData};
+use crate::access::wal::{self, LocalWalStorage, Lsn, TimeLineID, WalReader, WalStorage};
+use crate::protocol::{self, FieldDesc, Message, MsgType, XactStatus};
+use crate::utils::{KBSystemTime, SessionState};
+use crate::{errctx, guc, kbanyhow, kbbail, kbensure, SockReader, SockWriter};
+use crate::{INT4OID, VARCHAROID};
+use anyhow::Context;
+use nix::poll::{poll, PollFd, PollFlags};
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering::Relaxed};
+use std::time::{Duration, Instant};
+
+// The max size of wal carried by one XLogData message.
+const XLOGDATA_MAX_SIZE: usize = 128 * 1024;
+const WALSND_NAPTIME: Duration = Duration::from_millis(100);
+
+#[derive(Debug, PartialEq)]
+pub enum ReplicationCmd {
+    StartReplication { slot: Option<String>, startlsn: Lsn },
+    CreateReplicationSlot(String),
+    DropReplicationSlot(String),
+    BaseBackup,
+    IdentifySystem,
+}
+
+fn is_kw(token: &str, kw: &str) -> bool {
+    token.eq_ignore_ascii_case(kw)
+}
+
+// START_REPLICATION [SLOT slot_name] [PHYSICAL] XXX/XXX
+// CREATE_REPLICATION_SLOT slot_name PHYSICAL
+// DROP_REPLICATION_SLOT slot_name
+// BASE_BACKUP
+// IDENTIFY_SYSTEM
+pub fn parse_replication_cmd(query: &str) -> anyhow::Result<ReplicationCmd> {
+    let query = query.trim().trim_end_matches(';');
+    let mut tokens: Vec<&str> = query.split_whitespace().collect();
+    match tokens.as_slice() {
+        [cmd, ..] if is_kw(cmd, "START_REPLICATION") => {
+            let mut slot = None;
+            if tokens.len() >= 3 && is_kw(tokens[1], "SLOT") {
+                slot = Some(tokens[2].to_string());
+                tokens.drain(1..3);
+            }
+            if tokens.len() == 3 && is_kw(tokens[1], "PHYSICAL") {
+                tokens.remove(1);
+            }
+            if tokens.len() == 2 {
+                let startlsn = parse_lsn(tokens[1])?;
+                return Ok(ReplicationCmd::StartReplication { slot, startlsn });
+            }
+        }
+        [cmd, name, kind] if is_kw(cmd, "CREATE_REPLICATION_SLOT") && is_kw(kind, "PHYSICAL") => {
+            return Ok(ReplicationCmd::CreateReplicationSlot(name.to_string()));
+        }
+        [cmd, name] if is_kw(cmd, "DROP_REPLICATION_SLOT") => {
+            return Ok(ReplicationCmd::DropReplicationSlot(name.to_string()));
+        }
+        [cmd] if is_kw(cmd, "BASE_BACKUP") => {
+            return Ok(ReplicationCmd::BaseBackup);
+        }
+        [cmd] if is_kw(cmd, "IDENTIFY_SYSTEM") => {
+            return Ok(ReplicationCmd::IdentifySystem);
+        }
+        _ => (),
+    }
+    kbbail!(
+        ERRCODE_SYNTAX_ERROR,
+        "unsupported replication command. cmd={}",
+        query
+    );
+}
+
+fn send_copydata(sockwriter: &mut SockWriter, msg: &[u8]) -> anyhow::Result<()> {
+    sockwriter
+        .write_all(&protocol::CopyData { data: msg }.serialize())
+        .and_then(|_| sockwriter.flush())
+        .with_context(|| errctx!(ERRCODE_CONNECTION_FAILURE, "could not send data to client"))
+}
+
+// Wait at most timeout for a message from the client, return true if there is one.
+fn wait_message(sockreader: &SockReader, timeout: Duration) -> anyhow::Result<bool> {
+    if !sockreader.buffer().is_empty() || sockreader.get_ref().has_pending() {
+        return Ok(true);
+    }
+    let fd = sockreader.get_ref().as_raw_fd();
+    let mut fds = [PollFd::new(fd, PollFlags::POLLIN)];
+    let n = poll(&mut fds, timeout.as_millis() as i32)
+        .with_context(|| errctx!(ERRCODE_CONNECTION_FAILURE, "poll failed"))?;
+    return Ok(n > 0);
+}
+
+pub struct WalSndCtx<'a> {
+    pub wal: Option<&'static wal::GlobalStateExt>,
+    pub termreq: &'a AtomicBool,
+    // The restart_lsn of slot will be advanced to the flush position of the standby.
+    pub slot: Option<(&'a ReplSlots, String)>,
+    // wal_sender_timeout, zero means the timeout is disabled.
+    pub timeout: Duration,
+}
+
+struct WalSnd<'a, 'b> {
+    ctx: &'b WalSndCtx<'a>,
+    last_reply: Instant,
+    ping_sent: bool,
+}
+
+impl WalSnd<'_, '_> {
+    // ProcessRepliesIfAny, return false if the client has ended the streaming.
+    fn process_replies(&mut self, sockreader: &mut SockReader) -> anyhow::Result<bool> {
+        while wait_message(sockreader, Duration::from_millis(0))? {
+            let (msgtype, msgdata) = protocol::read_message(sockreader)?;
+            self.last_reply = Instant::now();
+            self.ping_sent = false;
+            if msgtype == MsgType::CopyDone as i8 {
+                return Ok(false);
+            }
+            kbensure!(
+                msgtype == MsgType::CopyData as i8,
+                ERRCODE_PROTOCOL_VIOLATION,
+                "unexpected msg. expected=d actual={}",
+                msgtype
+            );
+            let status = StandbyStatusUpdate::deserialize(&msgdata)?;
+            log::trace!("receive standby status update. {:?}", status);
+            if let (Some((slots, name)), Some(flush)) = (&self.ctx.slot, Lsn::new(status.flush)) {
+                slots.advance(name, flush)?;
+            }
+        }
+        return Ok(true);
+    }
+
+    // WalSndKeepaliveIfNecessary and WalSndCheckTimeOut
+    fn check_timeout(&mut self, sockwriter: &mut SockWriter, walend: Lsn) -> anyhow::Result<()> {
+        let timeout = self.ctx.timeout;
+        if timeout.as_millis() == 0 {
+            return Ok(());
+        }
+        let elapsed = self.last_reply.elapsed();
+        kbensure!(
+            elapsed < timeout,
+            ERRCODE_CONNECTION_FAILURE,
+            "terminating walsender process due to replication timeout"
+        );
+        if !self.ping_sent && elapsed >= timeout / 2 {
+            let keepalive = PrimaryKeepalive {
+                walend,
+                sendtime: KBSystemTime::now().into(),
+                reply_requested: true,
+            };
+            send_copydata(sockwriter, &keepalive.serialize())?;
+            self.ping_sent = true;
+        }
+        return Ok(());
+    }
+}
+
+// Stream the wal starting at startlsn to the client until an error occurs or the client
+// sends CopyDone. Only the wal that has been flushed is sent, so the standby never gets
+// ahead of the primary.
+pub fn start_replication(
+    sockreader: &mut SockReader,
+    sockwriter: &mut SockWriter,
+    storage: Box<dyn WalStorage>,
+    startlsn: Lsn,
+    ctx: &WalSndCtx,
+) -> anyhow::Result<()> {
+    log::info!("start replication. startlsn={}", startlsn);
+    protocol::write_message(sockwriter, &protocol::CopyBothResponse {});
+    sockwriter
+        .flush()
+        .with_context(|| errctx!(ERRCODE_CONNECTION_FAILURE, "could not send data to client"))?;
+    let mut walsnd = WalSnd {
+        ctx,
+        last_reply: Instant::now(),
+        ping_sent: false,
+    };
+    let mut walreader = WalReader::new(storage, startlsn);
+    let mut buf = Vec::new();
+    let mut bufstart = startlsn;
+    // WalSndDone, the wal flushed before termreq, such as the shutdown checkpoint, is sent
+    // before the walsender is terminated.
+    let mut stoplsn = None;
+    loop {
+        if stoplsn.is_none() && ctx.termreq.load(Relaxed) {
+            stoplsn = Some(ctx.wal.map_or(0, |v| v.flush_lsn()));
+        }
+        kbensure!(
+            stoplsn.map_or(true, |v| bufstart.get() < v),
+            ERRCODE_ADMIN_SHUTDOWN,
+            "terminating walsender process due to administrator command"
+        );
+        if !walsnd.process_replies(sockreader)? {
+            log::info!("end replication. endlsn={}", bufstart);
+            protocol::write_message(sockwriter, &protocol::CopyDone {});
+            return Ok(());
+        }
+        walsnd.check_timeout(sockwriter, bufstart)?;
+        match walreader.read_raw_record() {
+            Ok((_, rec)) => {
+                buf.extend_from_slice(&rec);
+                if buf.len() < XLOGDATA_MAX_SIZE {
+                    continue;
+                }
+            }
+            Err(e) => {
+                log::trace!(
+                    "walsender: wait for new wal. endlsn={} err={}",
+                    walreader.endlsn,
+                    e
+                );
+                walreader.close_file();
+                if buf.is_empty() {
+                    kbensure!(
+                        stoplsn.is_none(),
+                        ERRCODE_ADMIN_SHUTDOWN,
+                        "terminating walsender process due to administrator command"
+                    );
+                    wait_message(sockreader, WALSND_NAPTIME)?;
+                    continue;
+                }
+            }
+        }
+        let walend = walreader.endlsn;
+        if let Some(wal) = ctx.wal {
+            wal.fsync(walend);
+        }
+        let xlogdata = XLogData {
+            start: bufstart,
+            walend,
+            sendtime: KBSystemTime::now().into(),
+            data: &buf,
+        };
+        send_copydata(sockwriter, &xlogdata.serialize())?;
+        bufstart = walend;
+        buf.clear();
+    }
+}
+
+// IdentifySystem, the columns are systemid, timeline, xlogpos and dbname just as PostgreSQL.
+// KuiBaDB has no system identifier yet, and dbname is always NULL in the physical replication.
+pub fn send_identify_system(sockwriter: &mut SockWriter, tli: TimeLineID, xlogpos: Lsn) {
+    let fields = [
+        FieldDesc::new("systemid", VARCHAROID, -1, -1),
+        FieldDesc::new("timeline", INT4OID, -1, 4),
+        FieldDesc::new("xlogpos", VARCHAROID, -1, -1),
+        FieldDesc::new("dbname", VARCHAROID, -1, -1),
+    ];
+    protocol::write_message(sockwriter, &protocol::RowDescription { fields: &fields });
+    let tli = tli.to_string();
+    let xlogpos = format_lsn(xlogpos);
+    let row = protocol::DataRow {
+        data: &[None, Some(tli.as_bytes()), Some(xlogpos.as_bytes()), None],
+    };
+    protocol::write_message(sockwriter, &row);
+}
+
+fn exec_replication_cmd(
+    state: &SessionState,
+    sockreader: &mut SockReader,
+    sockwriter: &mut SockWriter,
+    query: &str,
+) -> anyhow::Result<()> {
+    let tag = match parse_replication_cmd(query)? {
+        ReplicationCmd::StartReplication { slot, startlsn } => {
+            if let Some(ref slot) = slot {
+                kbensure!(
+                    state.replslots.get(slot).is_some(),
+                    ERRCODE_UNDEFINED_OBJECT,
+                    "replication slot \"{}\" does not exist",
+                    slot
+                );
+            }
+            let timeout = guc::get_int(&state.gucstate, guc::WalSenderTimeout) as u64;
+            let ctx = WalSndCtx {
+                wal: state.wal,
+                termreq: &state.termreq,
+                slot: slot.map(|v| (state.replslots, v)),
+                timeout: Duration::from_millis(timeout),
+            };
+            let storage = Box::new(LocalWalStorage::new());
+            return start_replication(sockreader, sockwriter, storage, startlsn, &ctx);
+        }
+        ReplicationCmd::CreateReplicationSlot(name) => {
+            // The wal after the current insert lsn is reserved immediately.
+            let wal = state.wal.ok_or_else(|| {
+                kbanyhow!(
+                    ERRCODE_FEATURE_NOT_SUPPORTED,
+                    "cannot create replication slot during recovery"
+                )
+            })?;
+            state.replslots.create(&name, wal.insert_lsn())?;
+            "CREATE_REPLICATION_SLOT"
+        }
+        ReplicationCmd::DropReplicationSlot(name) => {
+            state.replslots.drop(&name)?;
+            "DROP_REPLICATION_SLOT"
+        }
+        ReplicationCmd::BaseBackup => {
+            let ctl = start_backup(state)?;
+            let wal = state.wal.unwrap();
+            send_base_backup(sockwriter, ".", &ctl, || {
+                let endlsn = wal.insert_lsn();
+                wal.fsync(endlsn);
+                endlsn
+            })?;
+            "BASE_BACKUP"
+        }
+        ReplicationCmd::IdentifySystem => {
+            let wal = state.wal.ok_or_else(|| {
+                kbanyhow!(
+                    ERRCODE_FEATURE_NOT_SUPPORTED,
+                    "cannot identify system during recovery"
+                )
+            })?;
+            send_identify_system(sockwriter, wal.curtli(), wal.insert_lsn());
+            "IDENTIFY_SYSTEM"
+        }
+    };
+    protocol::write_message(sockwriter, &protocol::CommandComplete { tag });
+    return Ok(());
+}
+
+// WalSndLoop, the replication connection only accepts the replication commands.
+pub fn walsender_main(
+    state: &mut SessionState,
+    sockreader: &mut SockReader,
+    sockwriter: &mut SockWriter,
+) -> anyhow::Result<()> {
+    loop {
+        state.check_termreq()?;
+        protocol::write_message(
+            sockwriter,
+            &protocol::ReadyForQuery::new(XactStatus::NotInBlock),
+        );
+        sockwriter.flush()?;
+        // The idle walsender is terminated by the smart shutdown too.
+        while !wait_message(sockreader, WALSND_NAPTIME)? {
+            kbensure!(
+                !state.termreq.load(Relaxed),
+                ERRCODE_ADMIN_SHUTDOWN,
+                "terminating walsender process due to administrator command"
+            );
+        }
+        let (msgtype, msgdata) = protocol::read_message(sockreader)?;
+        if msgtype == MsgType::EOF as i8 || msgtype == MsgType::Terminate as i8 {
+            log::info!("end replication connection");
+            return Ok(());
+        }
+        kbensure!(
+            msgtype == MsgType::Query as i8,
+            ERRCODE_PROTOCOL_VIOLATION,
+            "unexpected msg. expected=Q actual={}",
+            msgtype
+        );
+        let query = protocol::Query::deserialize(&msgdata)?;
+        log::info!("receive replication command. {}", query.query);
+        if let Err(ref err) = exec_replication_cmd(state, sockreader, sockwriter, query.query) {
+            state.on_error(err, sockwriter);
+            // The connection is unusable once the streaming has started.
+            if crate::utils::err::errcode(err) == protocol::ERRCODE_CONNECTION_FAILURE {
+                return Ok(());
+            }
+        }
+    }
+}
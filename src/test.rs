@@ -9,15 +9,44 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::utils::SessionState;
-use crate::{GlobalState, TEST_SESSID};
+use crate::access::lmgr::LockTag;
+use crate::access::redo::redo;
+use crate::access::xact::SessionExt as XACTSessionExt;
+use crate::catalog;
+use crate::datums::Datums;
+use crate::executor::{self, DestReceiver};
+use crate::parser::{self, sem};
+use crate::utility::process_utility;
+use crate::utils::fmgr::FmgrInfo;
+use crate::utils::{SessionState, WorkerState};
+use crate::{optimizer, GlobalState, TEST_SESSID};
 use std::env;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 
+mod agg;
+mod bytea;
+mod cancel;
+mod clauses;
 mod clog;
+mod copy;
+mod cs;
+mod dropcmds;
+mod insert;
+mod lmgr;
+mod notice;
+mod oper;
+mod parallel;
+mod savepoint;
+mod sort;
+mod tablecmds;
+mod typecmds;
+mod vacuum;
 
+// The sessions need wal and xact to run the transactions, so we recover the datadir first.
 fn init_global_state() -> GlobalState {
     let datadir = env::var("KUIBADB_DATADIR").expect("KUIBADB_DATADIR env");
-    GlobalState::init(&datadir)
+    redo(&datadir).unwrap()
 }
 
 lazy_static::lazy_static! {
@@ -33,3 +62,107 @@ fn new_session() -> SessionState {
     sess.init_thread_locals();
     return sess;
 }
+
+// Collect the rows returned by SELECT in text format, None means null.
+struct Rows {
+    typout: Vec<FmgrInfo>,
+    outstr: Vec<Rc<Datums>>,
+    rows: Vec<Vec<Option<String>>>,
+}
+
+impl DestReceiver for Rows {
+    fn startup(
+        &mut self,
+        tlist: &Vec<sem::TargetEntry>,
+        sess: &SessionState,
+    ) -> anyhow::Result<()> {
+        self.outstr.resize_with(tlist.len(), Default::default);
+        for target in tlist {
+            let (typoutproc, _) = catalog::get_type_output_info(sess, target.expr.val_type())?;
+            self.typout
+                .push(FmgrInfo::new(typoutproc, sess.fmgr_builtins)?);
+        }
+        Ok(())
+    }
+
+    fn receive(
+        &mut self,
+        tuples: &[Rc<Datums>],
+        rownum: u32,
+        worker: &WorkerState,
+    ) -> anyhow::Result<()> {
+        for (idx, typout) in self.typout.iter().enumerate() {
+            (typout.fn_addr)(typout, &mut self.outstr[idx], &tuples[idx..idx + 1], worker)?;
+        }
+        for idx in 0..rownum as isize {
+            let row = self
+                .outstr
+                .iter()
+                .map(|col| col.try_get_varchar_at(idx).map(|v| v.to_string()));
+            self.rows.push(row.collect());
+        }
+        Ok(())
+    }
+}
+
+type TextRows = Vec<Vec<Option<String>>>;
+
+fn do_exec(sess: &mut SessionState, query: &str) -> anyhow::Result<TextRows> {
+    let ast = parser::parse(query)?;
+    let mut rows = Rows {
+        typout: Vec::new(),
+        outstr: Vec::new(),
+        rows: Vec::new(),
+    };
+    match parser::sem::kb_analyze(sess, &ast)? {
+        sem::Stmt::Utility(ref stmt) => {
+            process_utility(stmt, sess, &mut rows)?;
+        }
+        sem::Stmt::Optimizable(ref stmt) => {
+            let plannedstmt = optimizer::planner(sess, stmt)?;
+            executor::exec_select(&plannedstmt, sess, &mut rows)?;
+        }
+    }
+    return Ok(rows.rows);
+}
+
+// Just like exec_simple_query(), returns the rows of SELECT.
+fn exec(sess: &mut SessionState, query: &str) -> anyhow::Result<TextRows> {
+    sess.start_tran_cmd()?;
+    let ret = do_exec(sess, query);
+    match ret {
+        Ok(_) => sess.commit_tran_cmd()?,
+        Err(_) => sess.abort_cur_tran()?,
+    }
+    return ret;
+}
+
+// Build the expected rows, "NULL" means null.
+fn text_rows(rows: &[&[&str]]) -> TextRows {
+    rows.iter()
+        .map(|row| {
+            row.iter()
+                .map(|&v| {
+                    if v == "NULL" {
+                        None
+                    } else {
+                        Some(v.to_string())
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
+
+// Wait until someone waits for the lock, returns false if nobody waits for it in time.
+fn wait_for_waiter(sess: &SessionState, tag: LockTag, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        let locks = sess.lmgrg.dump();
+        if locks.iter().any(|l| l.tag == tag && !l.waiters.is_empty()) {
+            return true;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    return false;
+}
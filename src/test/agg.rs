@@ -0,0 +1,88 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{exec, text_rows};
+
+#[test]
+fn aggregates() {
+    let mut sess = super::new_session();
+    exec(&mut sess, "create table agg_t(i int, j int)").unwrap();
+    let query = "select count(*), count(i), sum(i), avg(i), max(j), min(j) from agg_t";
+    let rows = exec(&mut sess, query).unwrap();
+    assert_eq!(
+        rows,
+        text_rows(&[&["0", "0", "NULL", "NULL", "NULL", "NULL"]])
+    );
+
+    exec(
+        &mut sess,
+        "insert into agg_t values (1, 10), (6, -60), (9, 90)",
+    )
+    .unwrap();
+    exec(&mut sess, "insert into agg_t values (10, 100)").unwrap();
+    let rows = exec(&mut sess, query).unwrap();
+    assert_eq!(rows, text_rows(&[&["4", "4", "26", "6.5", "100", "-60"]]));
+    let rows = exec(
+        &mut sess,
+        "select count(*), sum(i + j) from agg_t where i > 5",
+    )
+    .unwrap();
+    assert_eq!(rows, text_rows(&[&["3", "155"]]));
+    let rows = exec(&mut sess, "select count(*) from agg_t where i > 100").unwrap();
+    assert_eq!(rows, text_rows(&[&["0"]]));
+
+    assert!(exec(&mut sess, "select i, count(*) from agg_t").is_err());
+    assert!(exec(&mut sess, "select i from agg_t where count(*) > 1").is_err());
+}
+
+#[test]
+fn group_by() {
+    let mut sess = super::new_session();
+    exec(&mut sess, "create table agg_g(i int, j int)").unwrap();
+    let query = "select i, sum(j), count(*) from agg_g group by i";
+    assert_eq!(exec(&mut sess, query).unwrap(), text_rows(&[]));
+
+    exec(
+        &mut sess,
+        "insert into agg_g values (1, 10), (2, 20), (1, 11)",
+    )
+    .unwrap();
+    exec(
+        &mut sess,
+        "insert into agg_g values (3, 30), (2, 21), (1, 12)",
+    )
+    .unwrap();
+    let mut rows = exec(&mut sess, query).unwrap();
+    rows.sort();
+    assert_eq!(
+        rows,
+        text_rows(&[&["1", "33", "3"], &["2", "41", "2"], &["3", "30", "1"]])
+    );
+
+    let query = "select i * 10 + 1, max(j) from agg_g where j > 10 group by i";
+    let mut rows = exec(&mut sess, query).unwrap();
+    rows.sort();
+    assert_eq!(
+        rows,
+        text_rows(&[&["11", "12"], &["21", "21"], &["31", "30"]])
+    );
+    let query = "select i - 1, count(j) from agg_g group by i - 1";
+    let mut rows = exec(&mut sess, query).unwrap();
+    rows.sort();
+    assert_eq!(rows, text_rows(&[&["0", "3"], &["1", "2"], &["2", "1"]]));
+    let mut rows = exec(&mut sess, "select i from agg_g group by i, i").unwrap();
+    rows.sort();
+    assert_eq!(rows, text_rows(&[&["1"], &["2"], &["3"]]));
+
+    assert!(exec(&mut sess, "select j from agg_g group by i").is_err());
+    assert!(exec(&mut sess, "select i - 1 from agg_g group by i - 2").is_err());
+    assert!(exec(&mut sess, "select i from agg_g group by count(*)").is_err());
+}
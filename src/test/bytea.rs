@@ -0,0 +1,86 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::exec;
+use crate::datums::Datums;
+use crate::protocol::{ERRCODE_INVALID_PARAMETER_VALUE, ERRCODE_INVALID_TEXT_REPRESENTATION};
+use crate::utils::err::errcode;
+use crate::utils::fmgr::{call_inproc, FmgrInfo};
+use crate::utils::SessionState;
+use crate::{BYTEAINPROC, BYTEAOUTPROC};
+use std::rc::Rc;
+
+fn byteain(sess: &SessionState, input: Datums) -> anyhow::Result<Rc<Datums>> {
+    let worker = sess.new_worker();
+    let byteain = FmgrInfo::new(BYTEAINPROC, sess.fmgr_builtins).unwrap();
+    let mut bytea = Rc::new(Datums::new());
+    let typmod = Rc::new(Datums::new_single_fixedlen(-1i32));
+    call_inproc(&byteain, &mut bytea, Rc::new(input), typmod, &worker)?;
+    return Ok(bytea);
+}
+
+// Renders the bytea like the output of SELECT, under the bytea_output of the session.
+fn byteaout(sess: &SessionState, bytea: Rc<Datums>) -> Rc<Datums> {
+    let worker = sess.new_worker();
+    let byteaout = FmgrInfo::new(BYTEAOUTPROC, sess.fmgr_builtins).unwrap();
+    let mut text = Rc::new(Datums::new());
+    (byteaout.fn_addr)(&byteaout, &mut text, &[bytea], &worker).unwrap();
+    return text;
+}
+
+fn roundtrip(sess: &SessionState, input: &str) -> String {
+    let bytea = byteain(sess, Datums::new_single_varchar(input.as_bytes())).unwrap();
+    return byteaout(sess, bytea).get_single_varchar().to_string();
+}
+
+#[test]
+fn bytea_output() {
+    let mut sess = super::new_session();
+    let input = "\\x00 415c7f80FF";
+    assert_eq!(roundtrip(&sess, input), "\\x00415c7f80ff");
+    assert_eq!(roundtrip(&sess, "a\\\\b\\001"), "\\x615c6201");
+    assert_eq!(roundtrip(&sess, ""), "\\x");
+
+    exec(&mut sess, "set bytea_output to 'escape'").unwrap();
+    assert_eq!(roundtrip(&sess, input), "\\000A\\\\\\177\\200\\377");
+    assert_eq!(roundtrip(&sess, "a\\\\b\\001"), "a\\\\b\\001");
+    // The invalid value is ignored.
+    exec(&mut sess, "set bytea_output to 'base64'").unwrap();
+    assert_eq!(roundtrip(&sess, "\\x7e"), "~");
+    // The setting is local to the session.
+    let other = super::new_session();
+    assert_eq!(roundtrip(&other, "\\x7e"), "\\x7e");
+
+    let mut input = Datums::new();
+    input.resize_varlen(3);
+    input.set_notnull_all();
+    input.set_varchar_at(0, b"\\x0a");
+    input.set_null_at(1);
+    input.set_empty_at(1);
+    input.set_varchar_at(2, b"\\\\");
+    let text = byteaout(&sess, byteain(&sess, input).unwrap());
+    assert_eq!(text.try_get_varchar_at(0), Some("\\012"));
+    assert_eq!(text.try_get_varchar_at(1), None);
+    assert_eq!(text.try_get_varchar_at(2), Some("\\\\"));
+    exec(&mut sess, "set bytea_output to 'hex'").unwrap();
+    assert_eq!(roundtrip(&sess, "\\x7e"), "\\x7e");
+
+    for (input, code) in [
+        ("\\x0", ERRCODE_INVALID_PARAMETER_VALUE),
+        ("\\x0g", ERRCODE_INVALID_PARAMETER_VALUE),
+        ("\\x0 1", ERRCODE_INVALID_PARAMETER_VALUE),
+        ("\\9", ERRCODE_INVALID_TEXT_REPRESENTATION),
+        ("\\40", ERRCODE_INVALID_TEXT_REPRESENTATION),
+    ] {
+        let err = byteain(&sess, Datums::new_single_varchar(input.as_bytes())).unwrap_err();
+        assert_eq!(errcode(&err), code, "{}", input);
+    }
+}
@@ -0,0 +1,87 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{exec, text_rows};
+use crate::protocol::ERRCODE_QUERY_CANCELED;
+use crate::utils::err::errcode;
+use std::sync::atomic::Ordering::Relaxed;
+use std::thread;
+use std::time::Duration;
+use tempfile::NamedTempFile;
+
+#[test]
+fn cancel() {
+    let mut sess = super::new_session();
+    exec(&mut sess, "create table cancel_t(i int, j int)").unwrap();
+    exec(&mut sess, "insert into cancel_t values (1, 10), (2, 20)").unwrap();
+
+    // The termreq is set by the CancelRequest, it is checked by the scan of every plan.
+    let output = NamedTempFile::new().unwrap();
+    let copy = format!(
+        "copy cancel_t to '{}' with (format 'binary')",
+        output.path().display()
+    );
+    sess.termreq.store(true, Relaxed);
+    for query in &[
+        "select i from cancel_t",
+        "select count(*) from cancel_t",
+        "select i from cancel_t order by j",
+        &copy,
+    ] {
+        let err = exec(&mut sess, query).unwrap_err();
+        assert_eq!(errcode(&err), ERRCODE_QUERY_CANCELED, "query={}", query);
+    }
+    // The statement is canceled, the session and the table are intact.
+    sess.termreq.store(false, Relaxed);
+    let rows = exec(&mut sess, "select i from cancel_t order by i").unwrap();
+    assert_eq!(rows, text_rows(&[&["1"], &["2"]]));
+    exec(&mut sess, "drop table cancel_t").unwrap();
+}
+
+#[test]
+fn statement_timeout() {
+    let mut sess = super::new_session();
+    exec(&mut sess, "create table stmt_timeout_t(i int)").unwrap();
+    exec(&mut sess, "insert into stmt_timeout_t values (1), (2)").unwrap();
+    exec(&mut sess, "set statement_timeout to 50").unwrap();
+
+    // The timer starts with the statement, see do_postgres_main().
+    exec(&mut sess, "begin").unwrap();
+    sess.update_stmt_startts();
+    thread::sleep(Duration::from_millis(100));
+    for query in &[
+        "select i from stmt_timeout_t",
+        "select count(*) from stmt_timeout_t",
+    ] {
+        let err = exec(&mut sess, query).unwrap_err();
+        assert_eq!(errcode(&err), ERRCODE_QUERY_CANCELED, "query={}", query);
+        let msg = err.to_string();
+        assert!(msg.contains("statement timeout"), "msg={}", msg);
+    }
+    exec(&mut sess, "rollback").unwrap();
+
+    // The timer is reset by the next statement even in the same transaction.
+    exec(&mut sess, "begin").unwrap();
+    sess.update_stmt_startts();
+    thread::sleep(Duration::from_millis(100));
+    sess.update_stmt_startts();
+    let rows = exec(&mut sess, "select i from stmt_timeout_t order by i").unwrap();
+    assert_eq!(rows, text_rows(&[&["1"], &["2"]]));
+    exec(&mut sess, "commit").unwrap();
+
+    // 0 disables the timeout.
+    exec(&mut sess, "set statement_timeout to 0").unwrap();
+    sess.update_stmt_startts();
+    thread::sleep(Duration::from_millis(100));
+    let rows = exec(&mut sess, "select count(*) from stmt_timeout_t").unwrap();
+    assert_eq!(rows, text_rows(&[&["2"]]));
+    exec(&mut sess, "drop table stmt_timeout_t").unwrap();
+}
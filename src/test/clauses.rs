@@ -0,0 +1,102 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{exec, text_rows};
+use crate::access::xact::SessionExt as XACTSessionExt;
+use crate::optimizer::{self, Plan};
+use crate::parser::{self, sem};
+use crate::utils::SessionState;
+
+fn plan(sess: &mut SessionState, query: &str) -> Plan {
+    sess.start_tran_cmd().unwrap();
+    let ast = parser::parse(query).unwrap();
+    let stmt = match sem::kb_analyze(sess, &ast).unwrap() {
+        sem::Stmt::Optimizable(stmt) => stmt,
+        sem::Stmt::Utility(_) => unreachable!(),
+    };
+    let plan = optimizer::planner(sess, &stmt).unwrap().plan_tree;
+    sess.commit_tran_cmd().unwrap();
+    return plan;
+}
+
+// The number of the quals evaluated for every row, None means the table is not scanned.
+fn scan_quals(plan: &Plan) -> Option<usize> {
+    match plan {
+        Plan::SeqScan(scan) => Some(scan.qual.len()),
+        Plan::Result(res) => {
+            let qual = res.resconstantqual.as_ref().unwrap();
+            assert!(matches!(qual, sem::Expr::Const(_)));
+            None
+        }
+        _ => panic!("unexpected plan"),
+    }
+}
+
+fn count(sess: &mut SessionState, cond: &str) -> String {
+    let query = format!("select count(*) from fold_t where {}", cond);
+    let rows = exec(sess, &query).unwrap();
+    return rows[0][0].clone().unwrap();
+}
+
+#[test]
+fn fold_qual() {
+    let mut sess = super::new_session();
+    exec(&mut sess, "create table fold_t(i int)").unwrap();
+    exec(&mut sess, "insert into fold_t values (1), (2), (3)").unwrap();
+    let cases = [
+        ("1 = 1", Some(0), "3"),
+        ("1 = 0", None, "0"),
+        ("not 1 = 1", None, "0"),
+        ("i > 1 and 1 = 1", Some(1), "2"),
+        ("i > 1 and 1 = 0", None, "0"),
+        ("i > 1 or 1 + 1 = 2", Some(0), "3"),
+        ("i > 1 or 1 = 0", Some(1), "2"),
+        ("i > 1 and (i < 3 and 2 > 1)", Some(2), "1"),
+    ];
+    for &(cond, quals, cnt) in &cases {
+        let query = format!("select i from fold_t where {}", cond);
+        assert_eq!(scan_quals(&plan(&mut sess, &query)), quals, "cond={}", cond);
+        assert_eq!(count(&mut sess, cond), cnt, "cond={}", cond);
+    }
+    exec(&mut sess, "drop table fold_t").unwrap();
+}
+
+#[test]
+fn fold_tlist() {
+    let mut sess = super::new_session();
+    let plan = plan(&mut sess, "select 1 + 2 * 3, 7 > 3");
+    match &plan.tlist()[0].expr {
+        sem::Expr::Const(c) => assert_eq!(c.v.get_single_fixedlen::<i32>(), 7),
+        expr => panic!("unexpected expr: {:?}", expr),
+    }
+    match &plan.tlist()[1].expr {
+        sem::Expr::Const(c) => assert!(c.v.get_single_fixedlen::<bool>()),
+        expr => panic!("unexpected expr: {:?}", expr),
+    }
+    let rows = exec(&mut sess, "select 1 + 2 * 3, 7 > 3").unwrap();
+    assert_eq!(rows, text_rows(&[&["7", "t"]]));
+}
+
+#[test]
+fn fold_volatile() {
+    let mut sess = super::new_session();
+    // Pretend int4mi to be volatile, the function is evaluated by the executor then.
+    sess.metaconn
+        .execute("update kb_proc set provolatile = 118 where oid = 181")
+        .unwrap();
+    let plan = plan(&mut sess, "select 7 - 4");
+    let rows = exec(&mut sess, "select 7 - 4");
+    sess.metaconn
+        .execute("update kb_proc set provolatile = 105 where oid = 181")
+        .unwrap();
+    assert!(matches!(plan.tlist()[0].expr, sem::Expr::Func(_)));
+    assert_eq!(rows.unwrap(), text_rows(&[&["3"]]));
+}
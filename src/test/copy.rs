@@ -0,0 +1,181 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{exec, text_rows};
+use crate::protocol::{
+    ERRCODE_BAD_COPY_FILE_FORMAT, ERRCODE_FEATURE_NOT_SUPPORTED, ERRCODE_INVALID_PARAMETER_VALUE,
+};
+use crate::utils::err::errcode;
+use std::io::Write;
+use tempfile::NamedTempFile;
+
+const SELECT_ALL: &str = "select i, j, k from {} order by i";
+
+fn select_all(sess: &mut crate::utils::SessionState, table: &str) -> super::TextRows {
+    return exec(sess, &SELECT_ALL.replace("{}", table)).unwrap();
+}
+
+#[test]
+fn binary_roundtrip() {
+    let mut sess = super::new_session();
+    exec(&mut sess, "create table copy_src(i int, j int, k int)").unwrap();
+    exec(&mut sess, "create table copy_dst(i int, j int, k int)").unwrap();
+    let mut input = NamedTempFile::new().unwrap();
+    for i in 0..3000i32 {
+        if i % 7 == 0 {
+            writeln!(input, "{},,", i).unwrap();
+        } else {
+            writeln!(input, "{},{},{}", i, -i, i * 1_000).unwrap();
+        }
+    }
+    input.flush().unwrap();
+    let copy = format!(
+        "copy copy_src from '{}' with (delimiter ',')",
+        input.path().display()
+    );
+    exec(&mut sess, &copy).unwrap();
+
+    let output = NamedTempFile::new().unwrap();
+    let copy = format!(
+        "copy copy_src to '{}' with (format 'binary')",
+        output.path().display()
+    );
+    exec(&mut sess, &copy).unwrap();
+    let data = std::fs::read(output.path()).unwrap();
+    assert!(data.starts_with(b"PGCOPY\n\xff\r\n\0\0\0\0\0\0\0\0\0"));
+    assert!(data.ends_with(&[0xff, 0xff]));
+
+    let copy = format!(
+        "copy copy_dst from '{}' with (format 'binary', parallel 2)",
+        output.path().display()
+    );
+    exec(&mut sess, &copy).unwrap();
+    let src = select_all(&mut sess, "copy_src");
+    assert_eq!(src.len(), 3000);
+    assert_eq!(src, select_all(&mut sess, "copy_dst"));
+    assert_eq!(
+        src[..2].to_vec(),
+        text_rows(&[&["0", "NULL", "NULL"], &["1", "-1", "1000"]])
+    );
+
+    let copy = format!("copy copy_src to '{}'", output.path().display());
+    let err = exec(&mut sess, &copy).unwrap_err();
+    assert_eq!(errcode(&err), ERRCODE_FEATURE_NOT_SUPPORTED);
+}
+
+// Returns the binary COPY file of the table copy_bad(i int, j int).
+fn binary_file(rows: &[u8], trailer: bool) -> Vec<u8> {
+    let mut data = b"PGCOPY\n\xff\r\n\0".to_vec();
+    data.extend_from_slice(&0i32.to_be_bytes());
+    data.extend_from_slice(&4i32.to_be_bytes());
+    data.extend_from_slice(b"skip");
+    data.extend_from_slice(rows);
+    if trailer {
+        data.extend_from_slice(&(-1i16).to_be_bytes());
+    }
+    return data;
+}
+
+#[test]
+fn binary_malformed() {
+    let mut sess = super::new_session();
+    exec(&mut sess, "create table copy_bad(i int, j int)").unwrap();
+    let row: &[u8] = &[
+        0, 2, 0, 0, 0, 4, 0, 0, 0, 9, 0, 0, 0, 4, 0xff, 0xff, 0xff, 0xfe,
+    ];
+    let mut copy_bad = |data: &[u8]| {
+        let mut input = NamedTempFile::new().unwrap();
+        input.write_all(data).unwrap();
+        input.flush().unwrap();
+        let copy = format!(
+            "copy copy_bad from '{}' with (format 'binary')",
+            input.path().display()
+        );
+        return exec(&mut sess, &copy);
+    };
+    copy_bad(&binary_file(row, true)).unwrap();
+
+    let mut badsig = binary_file(row, true);
+    badsig[0] = b'X';
+    let mut withoids = binary_file(row, true);
+    withoids[12] = 1;
+    let mut extra = binary_file(row, true);
+    extra.push(0);
+    let malformed = [
+        badsig,
+        withoids,
+        extra,
+        binary_file(row, false),
+        binary_file(&row[..row.len() - 1], true),
+        // The field count does not match the table.
+        binary_file(&[0, 1, 0, 0, 0, 4, 0, 0, 0, 9], true),
+        // The field length does not match the type.
+        binary_file(&[0, 2, 0, 0, 0, 2, 0, 9, 0, 0, 0, 4, 0, 0, 0, 1], true),
+        binary_file(&[0, 2, 0xff, 0xff, 0xff, 0xfe], true),
+        b"PGCOPY\n".to_vec(),
+    ];
+    for data in &malformed {
+        let err = copy_bad(data).unwrap_err();
+        assert_eq!(errcode(&err), ERRCODE_BAD_COPY_FILE_FORMAT, "{:?}", data);
+    }
+    let rows = exec(&mut sess, "select i, j from copy_bad").unwrap();
+    assert_eq!(rows, text_rows(&[&["9", "-2"]]));
+}
+
+#[test]
+fn csv() {
+    let mut sess = super::new_session();
+    exec(&mut sess, "create table copy_csv(i int, j int)").unwrap();
+    let mut copy_csv = |data: &[u8], opts: &str| {
+        let mut input = NamedTempFile::new().unwrap();
+        input.write_all(data).unwrap();
+        input.flush().unwrap();
+        let copy = format!(
+            "copy copy_csv from '{}' with (format 'csv'{})",
+            input.path().display(),
+            opts
+        );
+        return exec(&mut sess, &copy);
+    };
+    copy_csv(
+        b"i,j\n\"1\",\"-1\"\n2,-\n\"3\",30\r\n",
+        ", header true, null '-'",
+    )
+    .unwrap();
+    copy_csv(b"4;\n\"5\";6\n", ", delimiter ';'").unwrap();
+    // The quoted empty string is not NULL.
+    assert!(copy_csv(b"7,\"\"\n", "").is_err());
+    let err = copy_csv(b"8,\"9\n", "").unwrap_err();
+    assert_eq!(errcode(&err), ERRCODE_BAD_COPY_FILE_FORMAT);
+    let err = copy_csv(b"", ", delimiter '\"'").unwrap_err();
+    assert_eq!(errcode(&err), ERRCODE_INVALID_PARAMETER_VALUE);
+    let err = copy_csv(b"", ", quote '\"\"'").unwrap_err();
+    assert_eq!(errcode(&err), ERRCODE_FEATURE_NOT_SUPPORTED);
+    let rows = exec(&mut sess, "select i, j from copy_csv order by i").unwrap();
+    assert_eq!(
+        rows,
+        text_rows(&[
+            &["1", "-1"],
+            &["2", "NULL"],
+            &["3", "30"],
+            &["4", "NULL"],
+            &["5", "6"]
+        ])
+    );
+
+    // QUOTE is only available in the CSV format.
+    let err = exec(
+        &mut sess,
+        "copy copy_csv from '/dev/null' with (delimiter ',', quote '\"')",
+    )
+    .unwrap_err();
+    assert_eq!(errcode(&err), ERRCODE_FEATURE_NOT_SUPPORTED);
+}
@@ -0,0 +1,245 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::parallel::insert_files;
+use super::{exec, text_rows};
+use crate::access::cs::TableScan;
+use crate::access::xact::SessionExt as XACTSessionExt;
+use crate::access::{rel, sv};
+use crate::catalog::namespace::SessionExt;
+use crate::commands::verify::verify_table;
+use crate::guc;
+use crate::protocol::ERRCODE_DATA_CORRUPTED;
+use crate::utils::err::errcode;
+use crate::utils::SessionState;
+use std::sync::Arc;
+
+// Scan the files with enable_mmap_scan set to mmap, returns the rows.
+fn scan_files(
+    sess: &mut SessionState,
+    tableid: sv::TableId,
+    rel: &rel::Rel,
+    files: &[sv::FileMeta],
+    mmap: bool,
+) -> Vec<(i32, i32)> {
+    let gucstate = Arc::make_mut(&mut sess.gucstate);
+    guc::set_bool_guc(guc::EnableMmapScan, mmap, gucstate);
+    sess.start_tran_cmd().unwrap();
+    let worker = sess.new_worker();
+    let mut scan = TableScan::with_files(tableid, rel.clone(), files.to_vec(), &worker).unwrap();
+    let mut out = Vec::new();
+    let mut rows = Vec::new();
+    while let Some(rownum) = scan.next(&worker, &mut out).unwrap() {
+        for idx in 0..rownum as isize {
+            let i = out[0].get_fixedlen_at::<i32>(idx);
+            let j = out[1].get_fixedlen_at::<i32>(idx);
+            rows.push((i, j));
+        }
+    }
+    drop(scan);
+    sess.commit_tran_cmd().unwrap();
+    return rows;
+}
+
+// VERIFY TABLE, returns the corruptions found.
+fn verify(sess: &mut SessionState, table: &str) -> Vec<String> {
+    sess.start_tran_cmd().unwrap();
+    let tableoid = sess.relname_get_oid(table).unwrap().unwrap();
+    let corrupted = verify_table(sess, tableoid).unwrap();
+    sess.commit_tran_cmd().unwrap();
+    for err in &corrupted {
+        assert_eq!(errcode(err), ERRCODE_DATA_CORRUPTED);
+    }
+    return corrupted.iter().map(|err| err.to_string()).collect();
+}
+
+#[test]
+fn mmap_scan() {
+    let mut sess = super::new_session();
+    exec(
+        &mut sess,
+        "create table mmap_t(i int, j int) with (data_blk_rows = 4)",
+    )
+    .unwrap();
+    for i in 0..5 {
+        let values: Vec<_> = (i * 10..i * 10 + 10)
+            .map(|v| format!("({}, {})", v, v * 2))
+            .collect();
+        let insert = format!("insert into mmap_t values {}", values.join(", "));
+        exec(&mut sess, &insert).unwrap();
+    }
+    let tableoid = sess.relname_get_oid("mmap_t").unwrap().unwrap();
+    let rel = rel::getrel(&mut sess, tableoid).unwrap();
+    let tableid = sv::TableId {
+        db: sess.reqdb,
+        table: tableoid,
+    };
+    let files = {
+        let svslot = sess.tabsv.read(&tableid, &rel.opt.enable_cs_wal).unwrap();
+        let sv = svslot.v.read().unwrap();
+        sv.as_ref().unwrap().datafiles()
+    };
+    let expected: Vec<_> = (0..50).map(|v| (v, v * 2)).collect();
+    let rows = scan_files(&mut sess, tableid, &rel, &files, false);
+    assert_eq!(rows, expected);
+
+    // There is no compaction yet, the L0 file is not written during the scan, so it is
+    // used as an immutable L1 file.
+    let sealed: Vec<_> = files
+        .iter()
+        .map(|&f| sv::FileMeta { sealed: true, ..f })
+        .collect();
+    assert_eq!(
+        scan_files(&mut sess, tableid, &rel, &sealed, true),
+        expected
+    );
+    assert_eq!(
+        scan_files(&mut sess, tableid, &rel, &sealed, false),
+        expected
+    );
+    // The L0 file is never mapped.
+    assert_eq!(scan_files(&mut sess, tableid, &rel, &files, true), expected);
+
+    // The sealed file is mapped as a whole, the mapping beyond the end of file is refused.
+    let mut toolong = sealed.clone();
+    toolong[0].len += 1;
+    sess.start_tran_cmd().unwrap();
+    let worker = sess.new_worker();
+    let mut scan = TableScan::with_files(tableid, rel.clone(), toolong, &worker).unwrap();
+    let err = scan.next(&worker, &mut Vec::new()).unwrap_err();
+    drop(scan);
+    sess.commit_tran_cmd().unwrap();
+    assert!(err
+        .to_string()
+        .starts_with("MmapFile::new: the file is too short"));
+
+    // The corrupted block is detected in the mapping as well.
+    let datapath = sv::get_datafile_path(tableid, files[0].fileid);
+    let data = std::fs::read(&datapath).unwrap();
+    let mut corrupted = data.clone();
+    corrupted[20] ^= 0xff;
+    std::fs::write(&datapath, &corrupted).unwrap();
+    let gucstate = Arc::make_mut(&mut sess.gucstate);
+    guc::set_bool_guc(guc::EnableMmapScan, true, gucstate);
+    sess.start_tran_cmd().unwrap();
+    let worker = sess.new_worker();
+    let mut scan = TableScan::with_files(tableid, rel.clone(), sealed, &worker).unwrap();
+    let err = scan.next(&worker, &mut Vec::new()).unwrap_err();
+    drop(scan);
+    sess.commit_tran_cmd().unwrap();
+    std::fs::write(&datapath, &data).unwrap();
+    assert!(err
+        .to_string()
+        .starts_with("invalid checksum in data block"));
+}
+
+#[test]
+fn data_block_checksum() {
+    let mut sess = super::new_session();
+    exec(
+        &mut sess,
+        "create table checksum_t(i int, j int) with (data_blk_rows = 4)",
+    )
+    .unwrap();
+    exec(
+        &mut sess,
+        "insert into checksum_t values (1, 2), (3, 4), (5, 6)",
+    )
+    .unwrap();
+    exec(&mut sess, "verify table checksum_t").unwrap();
+
+    let tableoid = sess.relname_get_oid("checksum_t").unwrap().unwrap();
+    let tableid = sv::TableId {
+        db: sess.reqdb,
+        table: tableoid,
+    };
+    let fileid = {
+        let svslot = sess.tabsv.read(&tableid, &false).unwrap();
+        let sv = svslot.v.read().unwrap();
+        sv.as_ref().unwrap().datafiles()[0].fileid
+    };
+    let datapath = sv::get_datafile_path(tableid, fileid);
+    let data = std::fs::read(&datapath).unwrap();
+    let mut corrupted = data.clone();
+    // The first column of the first block.
+    corrupted[16] ^= 0x10;
+    std::fs::write(&datapath, &corrupted).unwrap();
+    let msg = format!("invalid checksum in data block: path={} off=0", datapath);
+    let err = exec(&mut sess, "select sum(i) from checksum_t").unwrap_err();
+    assert_eq!(errcode(&err), ERRCODE_DATA_CORRUPTED);
+    assert!(err.to_string().starts_with(&msg), "{}", err);
+    let corrupted = verify(&mut sess, "checksum_t");
+    assert_eq!(corrupted.len(), 1);
+    assert!(corrupted[0].starts_with(&msg), "{}", corrupted[0]);
+    std::fs::write(&datapath, &data).unwrap();
+    assert!(verify(&mut sess, "checksum_t").is_empty());
+    let rows = exec(&mut sess, "select sum(i) from checksum_t").unwrap();
+    assert_eq!(rows, text_rows(&[&["9"]]));
+}
+
+#[test]
+fn verify_parallel() {
+    let mut sess = super::new_session();
+    exec(
+        &mut sess,
+        "create table verify_parallel(i int, j int) \
+        with (data_blk_rows = 4, mvcc_blk_rows = 4, mvcc_buf_cap = 4)",
+    )
+    .unwrap();
+    insert_files(&mut sess, "verify_parallel", 3, |file| {
+        let rows = file as i32 * 40..file as i32 * 40 + 40;
+        rows.map(|i| (i, i * 2)).collect()
+    });
+    let gucstate = Arc::make_mut(&mut sess.gucstate);
+    guc::set_int_guc(guc::MaxParallelWorkersPerGather, 2, gucstate);
+    assert!(verify(&mut sess, "verify_parallel").is_empty());
+    exec(&mut sess, "verify table verify_parallel").unwrap();
+
+    let tableoid = sess.relname_get_oid("verify_parallel").unwrap().unwrap();
+    let tableid = sv::TableId {
+        db: sess.reqdb,
+        table: tableoid,
+    };
+    let files = {
+        let svslot = sess.tabsv.read(&tableid, &false).unwrap();
+        let sv = svslot.v.read().unwrap();
+        sv.as_ref().unwrap().datafiles()
+    };
+    assert_eq!(files.len(), 3);
+    // The first block of the second data file.
+    let datapath = sv::get_datafile_path(tableid, files[1].fileid);
+    let data = std::fs::read(&datapath).unwrap();
+    let mut corrupted = data.clone();
+    corrupted[16] ^= 0x10;
+    std::fs::write(&datapath, &corrupted).unwrap();
+    // The first page of the third mvcc file, the pages evicted from the small buffer are
+    // on disk.
+    let mvccpath = sv::get_mvccfile_path(tableid, files[2].fileid);
+    let mvcc = std::fs::read(&mvccpath).unwrap();
+    assert!(mvcc.iter().any(|&b| b != 0));
+    let mut corrupted = mvcc.clone();
+    corrupted[16] ^= 0x10;
+    std::fs::write(&mvccpath, &corrupted).unwrap();
+
+    let rets = verify(&mut sess, "verify_parallel");
+    assert_eq!(rets.len(), 2, "{:?}", rets);
+    let msg = format!("invalid checksum in data block: path={} off=0 ", datapath);
+    assert!(rets[0].starts_with(&msg), "{}", rets[0]);
+    let msg = format!("invalid checksum in mvcc page: path={} page=0 ", mvccpath);
+    assert!(rets[1].starts_with(&msg), "{}", rets[1]);
+    // VERIFY reports the corruptions instead of failing, and modifies nothing.
+    exec(&mut sess, "verify table verify_parallel").unwrap();
+    assert_eq!(std::fs::read(&mvccpath).unwrap(), corrupted);
+
+    std::fs::write(&datapath, &data).unwrap();
+    std::fs::write(&mvccpath, &mvcc).unwrap();
+    assert!(verify(&mut sess, "verify_parallel").is_empty());
+}
@@ -0,0 +1,180 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{exec, text_rows, wait_for_waiter};
+use crate::access::lmgr::LockTag;
+use crate::access::rel;
+use crate::catalog::namespace::SessionExt;
+use crate::protocol::{
+    ERRCODE_DEPENDENT_OBJECTS_STILL_EXIST, ERRCODE_DUPLICATE_SCHEMA, ERRCODE_UNDEFINED_COLUMN,
+    ERRCODE_UNDEFINED_OBJECT, ERRCODE_UNDEFINED_SCHEMA, ERRCODE_UNDEFINED_TABLE,
+};
+use crate::utils::err::errcode;
+use crate::utils::SessionState;
+use crate::TYPERELID;
+use std::time::Duration;
+
+fn take_notices(sess: &mut SessionState) -> Vec<String> {
+    sess.notices.drain(..).map(|v| v.msg).collect()
+}
+
+#[test]
+fn drop_table() {
+    let mut sess = super::new_session();
+    exec(&mut sess, "create table drop_t1(i int)").unwrap();
+    exec(&mut sess, "create table drop_t2(i int)").unwrap();
+    exec(&mut sess, "insert into drop_t1 values (1)").unwrap();
+    exec(&mut sess, "drop table drop_t1, public.drop_t2").unwrap();
+    assert_eq!(sess.relname_get_oid("drop_t1").unwrap(), None);
+    assert_eq!(sess.relname_get_oid("drop_t2").unwrap(), None);
+    let err = exec(&mut sess, "select i from drop_t1").unwrap_err();
+    assert_eq!(errcode(&err), ERRCODE_UNDEFINED_TABLE);
+    // The name can be used again.
+    exec(&mut sess, "create table drop_t1(j int)").unwrap();
+    let rows = exec(&mut sess, "select count(*) from drop_t1").unwrap();
+    assert_eq!(rows, text_rows(&[&["0"]]));
+    exec(&mut sess, "drop table drop_t1").unwrap();
+
+    let err = exec(&mut sess, "drop table drop_t1").unwrap_err();
+    assert_eq!(errcode(&err), ERRCODE_UNDEFINED_TABLE);
+    sess.notices.clear();
+    exec(&mut sess, "drop table if exists drop_t1").unwrap();
+    assert_eq!(
+        take_notices(&mut sess),
+        ["table \"drop_t1\" does not exist, skipping"]
+    );
+    // The catalogs are required by the database system.
+    let err = exec(&mut sess, "drop table kb_class").unwrap_err();
+    assert_eq!(errcode(&err), ERRCODE_DEPENDENT_OBJECTS_STILL_EXIST);
+}
+
+#[test]
+fn drop_type() {
+    let mut sess = super::new_session();
+    exec(
+        &mut sess,
+        "create type drop_int (input = int4in, output = int4out)",
+    )
+    .unwrap();
+    exec(&mut sess, "create table drop_typ_t(i int, j drop_int)").unwrap();
+    let err = exec(&mut sess, "drop type drop_int").unwrap_err();
+    assert_eq!(errcode(&err), ERRCODE_DEPENDENT_OBJECTS_STILL_EXIST);
+    assert!(
+        err.to_string()
+            .contains("column j of table drop_typ_t depends on type drop_int"),
+        "err={}",
+        err
+    );
+    let err = exec(&mut sess, "drop type drop_int restrict").unwrap_err();
+    assert_eq!(errcode(&err), ERRCODE_DEPENDENT_OBJECTS_STILL_EXIST);
+
+    sess.notices.clear();
+    exec(&mut sess, "drop type drop_int cascade").unwrap();
+    assert_eq!(
+        take_notices(&mut sess),
+        ["drop cascades to column j of table drop_typ_t"]
+    );
+    assert!(sess.typname_get_type("drop_int").unwrap().is_none());
+    let tableoid = sess.relname_get_oid("drop_typ_t").unwrap().unwrap();
+    let attrs = rel::getrel(&mut sess, tableoid).unwrap().attrs;
+    let dropped: Vec<_> = attrs.iter().map(|v| v.dropped).collect();
+    assert_eq!(dropped, [false, true]);
+    let err = exec(&mut sess, "select j from drop_typ_t").unwrap_err();
+    assert_eq!(errcode(&err), ERRCODE_UNDEFINED_COLUMN);
+    exec(&mut sess, "insert into drop_typ_t values (1)").unwrap();
+    let rows = exec(&mut sess, "select i from drop_typ_t").unwrap();
+    assert_eq!(rows, text_rows(&[&["1"]]));
+
+    let err = exec(&mut sess, "drop type drop_int").unwrap_err();
+    assert_eq!(errcode(&err), ERRCODE_UNDEFINED_OBJECT);
+    exec(&mut sess, "drop type if exists drop_int").unwrap();
+    // int4 is required by the database system even if CASCADE is specified.
+    let err = exec(&mut sess, "drop type int4 cascade").unwrap_err();
+    assert_eq!(errcode(&err), ERRCODE_DEPENDENT_OBJECTS_STILL_EXIST);
+    assert!(sess.typname_get_type("int4").unwrap().is_some());
+}
+
+// The column of the type can not be created while the type is being dropped.
+#[test]
+fn drop_type_concurrently() {
+    let mut sess = super::new_session();
+    exec(
+        &mut sess,
+        "create type drop_conc_int (input = int4in, output = int4out)",
+    )
+    .unwrap();
+    exec(&mut sess, "create table drop_conc_t(i drop_conc_int)").unwrap();
+    let typoid = sess.typname_get_type("drop_conc_int").unwrap().unwrap().id;
+    let tableoid = sess.relname_get_oid("drop_conc_t").unwrap().unwrap();
+    // DROP TYPE holds the type lock while it waits for the table.
+    exec(&mut sess, "begin").unwrap();
+    exec(&mut sess, "insert into drop_conc_t values (1)").unwrap();
+    std::thread::scope(|s| {
+        let dropper = s.spawn(|| {
+            let mut sess = super::new_session();
+            exec(&mut sess, "drop type drop_conc_int cascade").map(|_| ())
+        });
+        let reltag = LockTag::Relation {
+            dboid: Some(sess.reqdb),
+            reloid: tableoid,
+        };
+        assert!(wait_for_waiter(&sess, reltag, Duration::from_secs(10)));
+        let creator = s.spawn(|| {
+            let mut sess = super::new_session();
+            exec(&mut sess, "create table drop_conc_t2(j drop_conc_int)").map(|_| ())
+        });
+        let typtag = LockTag::Object {
+            dboid: sess.reqdb,
+            clsoid: TYPERELID,
+            objoid: typoid,
+        };
+        // The creator may have finished already, the dropper still waits for us anyway.
+        let waited = wait_for_waiter(&sess, typtag, Duration::from_secs(1));
+        exec(&mut sess, "commit").unwrap();
+        dropper.join().unwrap().unwrap();
+        let err = creator.join().unwrap().unwrap_err();
+        assert_eq!(errcode(&err), ERRCODE_UNDEFINED_OBJECT);
+        assert!(waited);
+    });
+    assert!(sess.typname_get_type("drop_conc_int").unwrap().is_none());
+    assert!(sess.relname_get_oid("drop_conc_t2").unwrap().is_none());
+}
+
+#[test]
+fn drop_schema() {
+    let mut sess = super::new_session();
+    exec(&mut sess, "create schema drop_s").unwrap();
+    let err = exec(&mut sess, "create schema drop_s").unwrap_err();
+    assert_eq!(errcode(&err), ERRCODE_DUPLICATE_SCHEMA);
+    exec(&mut sess, "create table drop_s.drop_s_t(i int)").unwrap();
+    exec(&mut sess, "insert into drop_s.drop_s_t values (1), (2)").unwrap();
+    let rows = exec(&mut sess, "select sum(i) from drop_s.drop_s_t").unwrap();
+    assert_eq!(rows, text_rows(&[&["3"]]));
+
+    let err = exec(&mut sess, "drop schema drop_s").unwrap_err();
+    assert_eq!(errcode(&err), ERRCODE_DEPENDENT_OBJECTS_STILL_EXIST);
+    let nsoid = sess.get_namespace_oid("drop_s").unwrap();
+    sess.notices.clear();
+    exec(&mut sess, "drop schema drop_s cascade").unwrap();
+    assert_eq!(take_notices(&mut sess), ["drop cascades to table drop_s_t"]);
+    assert!(sess.get_namespace_oid("drop_s").is_err());
+    let tables = crate::catalog::relname_get_relid(&sess, "drop_s_t", nsoid).unwrap();
+    assert_eq!(tables, None);
+
+    let err = exec(&mut sess, "drop schema drop_s").unwrap_err();
+    assert_eq!(errcode(&err), ERRCODE_UNDEFINED_SCHEMA);
+    exec(&mut sess, "drop schema if exists drop_s").unwrap();
+    let err = exec(&mut sess, "drop schema kb_catalog cascade").unwrap_err();
+    assert_eq!(errcode(&err), ERRCODE_DEPENDENT_OBJECTS_STILL_EXIST);
+    // The empty schema.
+    exec(&mut sess, "create schema drop_s").unwrap();
+    exec(&mut sess, "drop schema drop_s").unwrap();
+}
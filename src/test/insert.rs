@@ -0,0 +1,143 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{exec, text_rows};
+use crate::protocol::{
+    ERRCODE_GROUPING_ERROR, ERRCODE_NOT_NULL_VIOLATION, ERRCODE_UNDEFINED_COLUMN,
+};
+use crate::utils::err::errcode;
+
+#[test]
+fn insert_then_select() {
+    let mut sess = super::new_session();
+    exec(&mut sess, "create table insert_t(i int, j int)").unwrap();
+    exec(&mut sess, "insert into insert_t values (1, 10), (2, -20)").unwrap();
+    let rows = exec(&mut sess, "select * from insert_t").unwrap();
+    assert_eq!(rows, text_rows(&[&["1", "10"], &["2", "-20"]]));
+
+    // The rows inserted by the aborted transaction are invisible.
+    exec(&mut sess, "begin").unwrap();
+    exec(&mut sess, "insert into insert_t values (3, 30)").unwrap();
+    let rows = exec(&mut sess, "select j, i from insert_t").unwrap();
+    assert_eq!(
+        rows,
+        text_rows(&[&["10", "1"], &["-20", "2"], &["30", "3"]])
+    );
+    exec(&mut sess, "abort").unwrap();
+    let rows = exec(&mut sess, "select i from insert_t").unwrap();
+    assert_eq!(rows, text_rows(&[&["1"], &["2"]]));
+
+    assert!(exec(&mut sess, "insert into insert_t values (4)").is_err());
+    exec(&mut sess, "insert into insert_t values (5, 50)").unwrap();
+    let rows = exec(&mut sess, "select i from insert_t").unwrap();
+    assert_eq!(rows, text_rows(&[&["1"], &["2"], &["5"]]));
+}
+
+// The NULL before the last row keeps the offsets of the later rows.
+#[test]
+fn null_in_middle() {
+    let mut sess = super::new_session();
+    exec(&mut sess, "create table insert_n(i int, j int)").unwrap();
+    exec(
+        &mut sess,
+        "insert into insert_n values (1, 10), (2, 20), (3, NULL), (4, 40), (5, 50)",
+    )
+    .unwrap();
+    exec(
+        &mut sess,
+        "insert into insert_n (j, i) values (NULL, 6), (60, NULL)",
+    )
+    .unwrap();
+    let rows = exec(&mut sess, "select i, j from insert_n").unwrap();
+    assert_eq!(
+        rows,
+        text_rows(&[
+            &["1", "10"],
+            &["2", "20"],
+            &["3", "NULL"],
+            &["4", "40"],
+            &["5", "50"],
+            &["6", "NULL"],
+            &["NULL", "60"]
+        ])
+    );
+}
+
+#[test]
+fn not_null_and_default() {
+    let mut sess = super::new_session();
+    exec(
+        &mut sess,
+        "create table insert_d(i int not null, j int default -7, k int)",
+    )
+    .unwrap();
+    let err = exec(&mut sess, "insert into insert_d values (null, 1, 1)").unwrap_err();
+    assert_eq!(errcode(&err), ERRCODE_NOT_NULL_VIOLATION);
+    let err = exec(&mut sess, "insert into insert_d (j) values (1)").unwrap_err();
+    assert_eq!(errcode(&err), ERRCODE_NOT_NULL_VIOLATION);
+
+    exec(&mut sess, "insert into insert_d (i) values (1), (2)").unwrap();
+    exec(&mut sess, "insert into insert_d (k, i) values (30, 3)").unwrap();
+    let rows = exec(&mut sess, "select i, j, k from insert_d order by i").unwrap();
+    assert_eq!(
+        rows,
+        text_rows(&[
+            &["1", "-7", "NULL"],
+            &["2", "-7", "NULL"],
+            &["3", "-7", "30"]
+        ])
+    );
+
+    assert!(exec(&mut sess, "insert into insert_d (i, i) values (1, 1)").is_err());
+    assert!(exec(&mut sess, "insert into insert_d (x) values (1)").is_err());
+    assert!(exec(&mut sess, "create table insert_d2(i int default 'x')").is_err());
+    assert!(exec(&mut sess, "create table insert_d2(i int null not null)").is_err());
+}
+
+#[test]
+fn insert_returning() {
+    let mut sess = super::new_session();
+    exec(
+        &mut sess,
+        "create table insert_r(i int, j int default -7, k int)",
+    )
+    .unwrap();
+    exec(&mut sess, "alter table insert_r drop column k").unwrap();
+    let rows = exec(
+        &mut sess,
+        "insert into insert_r (i) values (1), (2) returning *",
+    )
+    .unwrap();
+    assert_eq!(rows, text_rows(&[&["1", "-7"], &["2", "-7"]]));
+    let rows = exec(
+        &mut sess,
+        "insert into insert_r values (3, null) returning j, i + 1 as k, 'x'",
+    )
+    .unwrap();
+    assert_eq!(rows, text_rows(&[&["NULL", "4", "x"]]));
+    // The INSERT without RETURNING returns no rows.
+    let rows = exec(&mut sess, "insert into insert_r values (4, 4)").unwrap();
+    assert!(rows.is_empty());
+    let rows = exec(&mut sess, "select count(*) from insert_r").unwrap();
+    assert_eq!(rows, text_rows(&[&["4"]]));
+
+    let err = exec(
+        &mut sess,
+        "insert into insert_r values (5, 5) returning count(*)",
+    )
+    .unwrap_err();
+    assert_eq!(errcode(&err), ERRCODE_GROUPING_ERROR);
+    let err = exec(&mut sess, "insert into insert_r values (5, 5) returning k").unwrap_err();
+    assert_eq!(errcode(&err), ERRCODE_UNDEFINED_COLUMN);
+    let rows = exec(&mut sess, "select count(*) from insert_r").unwrap();
+    assert_eq!(rows, text_rows(&[&["4"]]));
+    exec(&mut sess, "drop table insert_r").unwrap();
+}
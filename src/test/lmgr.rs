@@ -0,0 +1,188 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::access::lmgr::{LockMode, LockTag, SessionExt};
+use crate::protocol::ERRCODE_T_R_DEADLOCK_DETECTED;
+use crate::utils::err::errcode;
+use crate::{Oid, INT4OID};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::time::Duration;
+
+fn test_tag(objoid: u32) -> LockTag {
+    return LockTag::Object {
+        dboid: Oid::new(1).unwrap(),
+        clsoid: Oid::new(1).unwrap(),
+        objoid: Oid::new(objoid).unwrap(),
+    };
+}
+
+fn test_reltag(reloid: u32) -> LockTag {
+    return LockTag::Relation {
+        dboid: Oid::new(1),
+        reloid: Oid::new(reloid).unwrap(),
+    };
+}
+
+#[test]
+fn lock_order() {
+    let mut sess = super::new_session();
+    assert_eq!(sess.lmgrs.check_order, cfg!(debug_assertions));
+    sess.lock_acquire(&test_tag(10), LockMode::AccessShare)
+        .unwrap();
+    sess.lock_acquire(&test_reltag(20), LockMode::AccessShare)
+        .unwrap();
+    sess.lock_acquire(&test_reltag(21), LockMode::AccessShare)
+        .unwrap();
+    // The lock held is acquired again in another mode.
+    sess.lock_acquire(&test_tag(10), LockMode::AccessExclusive)
+        .unwrap();
+    sess.lock_release_all();
+    sess.lock_acquire(&test_reltag(20), LockMode::AccessShare)
+        .unwrap();
+    sess.lock_release(&test_reltag(20), LockMode::AccessShare);
+    sess.lock_acquire(&test_tag(10), LockMode::AccessShare)
+        .unwrap();
+    sess.lock_release_all();
+
+    sess.lmgrs.check_order = false;
+    sess.lock_acquire(&test_reltag(20), LockMode::AccessShare)
+        .unwrap();
+    sess.lock_acquire(&test_tag(10), LockMode::AccessShare)
+        .unwrap();
+    sess.lock_release_all();
+}
+
+#[cfg(debug_assertions)]
+#[test]
+#[should_panic(expected = "lock acquired out of order")]
+fn lock_out_of_order() {
+    let mut sess = super::new_session();
+    sess.lock_acquire(&test_reltag(22), LockMode::AccessShare)
+        .unwrap();
+    sess.lock_acquire(&test_tag(11), LockMode::AccessShare)
+        .unwrap();
+}
+
+#[test]
+fn fair_queue() {
+    const READERS: u64 = 4;
+    let tag = test_tag(1);
+    let mut sess = super::new_session();
+    sess.lock_acquire(&tag, LockMode::AccessShare).unwrap();
+    let stop = AtomicBool::new(false);
+    let (granted, grantedr) = mpsc::channel();
+    std::thread::scope(|s| {
+        let stop = &stop;
+        for idx in 0..READERS {
+            s.spawn(move || {
+                let mut sess = super::new_session();
+                std::thread::sleep(Duration::from_millis(idx * 2));
+                while !stop.load(Ordering::Relaxed) {
+                    sess.lock_acquire(&tag, LockMode::AccessShare).unwrap();
+                    std::thread::sleep(Duration::from_millis(5));
+                    sess.lock_release(&tag, LockMode::AccessShare);
+                }
+            });
+        }
+        s.spawn(move || {
+            let mut sess = super::new_session();
+            sess.lock_acquire(&tag, LockMode::AccessExclusive).unwrap();
+            granted.send(()).unwrap();
+            sess.lock_release(&tag, LockMode::AccessExclusive);
+        });
+        // The lock is always held by some readers after we release ours, but the readers
+        // coming after the AccessExclusive request have to wait behind it.
+        std::thread::sleep(Duration::from_millis(50));
+        sess.lock_release(&tag, LockMode::AccessShare);
+        let ret = grantedr.recv_timeout(Duration::from_secs(5));
+        stop.store(true, Ordering::Relaxed);
+        assert!(ret.is_ok());
+    });
+}
+
+#[test]
+fn upgrade_ahead_of_waiter() {
+    let tag = test_tag(2);
+    let mut sess = super::new_session();
+    sess.lock_acquire(&tag, LockMode::AccessShare).unwrap();
+    let (granted, grantedr) = mpsc::channel();
+    std::thread::scope(|s| {
+        s.spawn(move || {
+            let mut sess = super::new_session();
+            sess.lock_acquire(&tag, LockMode::AccessExclusive).unwrap();
+            granted.send(()).unwrap();
+            sess.lock_release(&tag, LockMode::AccessExclusive);
+        });
+        std::thread::sleep(Duration::from_millis(50));
+        // The waiter is waiting for us, so we do not queue behind it.
+        sess.lock_acquire(&tag, LockMode::AccessExclusive).unwrap();
+        assert!(grantedr.try_recv().is_err());
+        sess.lock_release_all();
+        grantedr.recv_timeout(Duration::from_secs(5)).unwrap();
+    });
+}
+
+#[test]
+fn mutual_upgrade() {
+    let tag = test_tag(3);
+    let mut sess = super::new_session();
+    sess.lock_acquire(&tag, LockMode::AccessShare).unwrap();
+    let (granted, grantedr) = mpsc::channel();
+    std::thread::scope(|s| {
+        s.spawn(move || {
+            let mut sess = super::new_session();
+            sess.lock_acquire(&tag, LockMode::AccessShare).unwrap();
+            // Waits for the AccessShare held by us.
+            sess.lock_acquire(&tag, LockMode::AccessExclusive).unwrap();
+            granted.send(()).unwrap();
+            sess.lock_release_all();
+        });
+        std::thread::sleep(Duration::from_millis(50));
+        // The waiter is waiting for us, and we would wait for its AccessShare.
+        let err = sess
+            .lock_acquire(&tag, LockMode::AccessExclusive)
+            .unwrap_err();
+        assert_eq!(errcode(&err), ERRCODE_T_R_DEADLOCK_DETECTED);
+        assert!(grantedr.try_recv().is_err());
+        sess.lock_release_all();
+        grantedr.recv_timeout(Duration::from_secs(5)).unwrap();
+    });
+    // The failed request leaves nothing behind.
+    sess.lock_acquire(&tag, LockMode::AccessExclusive).unwrap();
+    sess.lock_release_all();
+}
+
+#[test]
+fn type_lock() {
+    let mut sess = super::new_session();
+    // Like DROP TYPE.
+    sess.lock_type(INT4OID, LockMode::AccessExclusive).unwrap();
+    let (granted, grantedr) = mpsc::channel();
+    std::thread::scope(|s| {
+        s.spawn(move || {
+            let mut sess = super::new_session();
+            sess.lock_proc(INT4OID, LockMode::AccessExclusive).unwrap();
+            granted.send("proc").unwrap();
+            sess.lock_release_all();
+            sess.lock_type(INT4OID, LockMode::AccessShare).unwrap();
+            granted.send("type").unwrap();
+            sess.lock_release_all();
+        });
+        // The proc with the same oid is another object.
+        let timeout = Duration::from_secs(5);
+        assert_eq!(grantedr.recv_timeout(timeout), Ok("proc"));
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(grantedr.try_recv().is_err());
+        sess.lock_release_all();
+        assert_eq!(grantedr.recv_timeout(timeout), Ok("type"));
+    });
+}
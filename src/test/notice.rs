@@ -0,0 +1,58 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::exec;
+use crate::guc::NoticeLevel;
+use crate::protocol::{ERRCODE_ACTIVE_SQL_TRANSACTION, ERRCODE_NO_ACTIVE_SQL_TRANSACTION};
+use crate::utils::SessionState;
+
+fn take_notices(sess: &mut SessionState) -> Vec<(NoticeLevel, &'static str)> {
+    sess.notices.drain(..).map(|v| (v.level, v.code)).collect()
+}
+
+#[test]
+fn client_min_messages() {
+    let mut sess = super::new_session();
+    exec(&mut sess, "begin").unwrap();
+    exec(&mut sess, "begin").unwrap();
+    exec(&mut sess, "commit").unwrap();
+    exec(&mut sess, "commit").unwrap();
+    exec(&mut sess, "rollback").unwrap();
+    assert_eq!(
+        take_notices(&mut sess),
+        [
+            (NoticeLevel::Warning, ERRCODE_ACTIVE_SQL_TRANSACTION),
+            (NoticeLevel::Warning, ERRCODE_NO_ACTIVE_SQL_TRANSACTION),
+            (NoticeLevel::Warning, ERRCODE_NO_ACTIVE_SQL_TRANSACTION),
+        ]
+    );
+
+    exec(&mut sess, "set client_min_messages to 'ERROR'").unwrap();
+    assert_eq!(sess.gucstate.client_minlvl, NoticeLevel::Error);
+    exec(&mut sess, "commit").unwrap();
+    assert!(take_notices(&mut sess).is_empty());
+    // The invalid value is ignored.
+    exec(&mut sess, "set client_min_messages to 'fatal'").unwrap();
+    assert_eq!(sess.gucstate.client_minlvl, NoticeLevel::Error);
+    exec(&mut sess, "set client_min_messages to 'debug3'").unwrap();
+    exec(&mut sess, "commit").unwrap();
+    assert_eq!(take_notices(&mut sess).len(), 1);
+}
+
+#[test]
+fn log_min_messages() {
+    let mut sess = super::new_session();
+    exec(&mut sess, "set log_min_messages to 'ERROR'").unwrap();
+    assert_eq!(sess.gucstate.loglvl, log::LevelFilter::Error);
+    // The setting is local to the session.
+    let other = super::new_session();
+    assert_ne!(other.gucstate.loglvl, log::LevelFilter::Error);
+}
@@ -0,0 +1,156 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::exec;
+use crate::access::xact::SessionExt as XACTSessionExt;
+use crate::parser::oper::{can_coerce_type, oper};
+use crate::parser::sem::{self, Expr};
+use crate::parser::{self, syn};
+use crate::protocol::{ERRCODE_AMBIGUOUS_FUNCTION, ERRCODE_UNDEFINED_FUNCTION};
+use crate::utils::err::errcode;
+use crate::utils::SessionState;
+use crate::{Oid, OptOid, BOOLOID, FLOAT4OID, FLOAT8OID, INT2OID, INT4OID, INT8OID};
+
+fn resolve(
+    sess: &mut SessionState,
+    name: &str,
+    left: Option<Oid>,
+    right: Oid,
+) -> anyhow::Result<(u32, Oid)> {
+    let op = oper(sess, &vec![syn::StrVal::InPlace(name)], OptOid(left), right)?;
+    let oprcode: u32 = op.oprcode.into();
+    return Ok((oprcode, op.oprresult));
+}
+
+#[test]
+fn oper_resolve() {
+    let mut sess = super::new_session();
+    // The exact matches, int24pl for int2 + int4.
+    assert_eq!(
+        resolve(&mut sess, "+", Some(INT4OID), INT4OID).unwrap(),
+        (177, INT4OID)
+    );
+    assert_eq!(
+        resolve(&mut sess, "+", Some(INT2OID), INT4OID).unwrap(),
+        (178, INT4OID)
+    );
+    assert_eq!(
+        resolve(&mut sess, "-", None, INT4OID).unwrap(),
+        (212, INT4OID)
+    );
+    // int4 is implicitly coerced to float8, float8pl is preferred to float48pl.
+    assert_eq!(
+        resolve(&mut sess, "+", Some(INT4OID), FLOAT8OID).unwrap(),
+        (218, FLOAT8OID)
+    );
+    // float84pl has more exact matches than float8pl.
+    assert_eq!(
+        resolve(&mut sess, "+", Some(INT2OID), FLOAT4OID).unwrap(),
+        (285, FLOAT8OID)
+    );
+    // Both int8 and float8 can be coerced to float8, but float8 can not be coerced to int8.
+    assert!(can_coerce_type(&sess, INT8OID, FLOAT8OID).unwrap());
+    assert!(!can_coerce_type(&sess, FLOAT8OID, INT8OID).unwrap());
+
+    let err = resolve(&mut sess, "+", Some(BOOLOID), INT4OID).unwrap_err();
+    assert_eq!(errcode(&err), ERRCODE_UNDEFINED_FUNCTION);
+    let err = resolve(&mut sess, "+", None, BOOLOID).unwrap_err();
+    assert_eq!(errcode(&err), ERRCODE_UNDEFINED_FUNCTION);
+
+    // Neither of int8 and float4 is preferred.
+    sess.metaconn
+        .execute(
+            "insert into kb_operator values
+            (9001,'###',11,20,20,20,463),
+            (9002,'###',11,700,700,700,204)",
+        )
+        .unwrap();
+    let ret = resolve(&mut sess, "###", Some(INT4OID), INT4OID);
+    sess.metaconn
+        .execute("delete from kb_operator where oprname = '###'")
+        .unwrap();
+    assert_eq!(errcode(&ret.unwrap_err()), ERRCODE_AMBIGUOUS_FUNCTION);
+}
+
+// The first target entry of the select analyzed.
+fn analyze_target(sess: &mut SessionState, query: &str) -> Expr {
+    sess.start_tran_cmd().unwrap();
+    let ast = parser::parse(query).unwrap();
+    let stmt = match sem::kb_analyze(sess, &ast).unwrap() {
+        sem::Stmt::Optimizable(stmt) => stmt,
+        sem::Stmt::Utility(_) => unreachable!(),
+    };
+    sess.commit_tran_cmd().unwrap();
+    return stmt.tlist[0].expr.clone();
+}
+
+// The function and the result type of the operator, and the cast function of each argument,
+// None means the argument is not casted.
+fn op_shape(expr: &Expr) -> (u32, Oid, Vec<Option<u32>>) {
+    let func = match expr {
+        Expr::Func(func) => func,
+        expr => panic!("unexpected expr: {:?}", expr),
+    };
+    let casts = func
+        .args
+        .iter()
+        .map(|arg| match arg {
+            Expr::Func(cast) => {
+                assert!(matches!(cast.args[0], Expr::Var(_) | Expr::Const(_)));
+                Some(cast.funcid.get())
+            }
+            _ => None,
+        })
+        .collect();
+    return (func.funcid.get(), func.funcresulttype, casts);
+}
+
+#[test]
+fn oper_coerce() {
+    let mut sess = super::new_session();
+    exec(&mut sess, "create table oper_t(i int)").unwrap();
+    let expr = analyze_target(&mut sess, "select i + 2.5 from oper_t");
+    // The int4 column is wrapped in i4tod.
+    assert_eq!(op_shape(&expr), (218, FLOAT8OID, vec![Some(316), None]));
+    match &expr {
+        Expr::Func(func) => assert!(matches!(func.args[1], Expr::Const(_))),
+        expr => panic!("unexpected expr: {:?}", expr),
+    }
+    exec(&mut sess, "drop table oper_t").unwrap();
+}
+
+#[test]
+fn oper_promote() {
+    let mut sess = super::new_session();
+    exec(
+        &mut sess,
+        "create table promote_t(s smallint, b int8, i int, f float4)",
+    )
+    .unwrap();
+    let cases = [
+        // int24eq, int84pl and int48pl match exactly, no cast is needed.
+        ("s = 5", (158, BOOLOID, vec![None, None])),
+        ("b + i", (1274, INT8OID, vec![None, None])),
+        ("i + b", (1278, INT8OID, vec![None, None])),
+        // float84pl, int8 is promoted to float8 by i8tod.
+        ("b + f", (285, FLOAT8OID, vec![Some(482), None])),
+        // float8pl, int2 is promoted to float8 by i2tod.
+        ("s + 2.5", (218, FLOAT8OID, vec![Some(235), None])),
+        // float48pl matches exactly, float4 is not promoted.
+        ("f + 2.5", (281, FLOAT8OID, vec![None, None])),
+    ];
+    for (expr, expected) in cases.iter() {
+        let query = format!("select {} from promote_t", expr);
+        let expr = analyze_target(&mut sess, &query);
+        assert_eq!(&op_shape(&expr), expected, "query={}", query);
+    }
+    exec(&mut sess, "drop table promote_t").unwrap();
+}
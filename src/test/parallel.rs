@@ -0,0 +1,311 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{exec, text_rows, Rows};
+use crate::access::xact::SessionExt as XACTSessionExt;
+use crate::access::{rel, sv};
+use crate::catalog::namespace::SessionExt;
+use crate::optimizer::{self, Plan};
+use crate::parser::{self, sem};
+use crate::protocol::{
+    ERRCODE_BAD_COPY_FILE_FORMAT, ERRCODE_INTERNAL_ERROR, ERRCODE_OUT_OF_MEMORY,
+};
+use crate::utils::err::errcode;
+use crate::utils::{wait_workers, SessionState};
+use crate::{executor, guc, kbbail};
+use std::collections::HashSet;
+use std::io::Write;
+use std::sync::Arc;
+use tempfile::NamedTempFile;
+
+fn files_to_scan(sess: &SessionState, tableid: &sv::TableId, rel: &rel::Rel) -> [usize; 3] {
+    let svslot = sess.tabsv.read(tableid, &rel.opt.enable_cs_wal).unwrap();
+    let sv = svslot.v.read().unwrap();
+    return sv.as_ref().unwrap().files_to_scan();
+}
+
+fn scan_parallel(plan: &Plan) -> usize {
+    match plan {
+        Plan::SeqScan(s) => s.parallel,
+        Plan::Agg(a) => scan_parallel(&a.lefttree),
+        Plan::Sort(s) => scan_parallel(&s.lefttree),
+        Plan::Limit(l) => scan_parallel(&l.lefttree),
+        Plan::Result(_) => 0,
+    }
+}
+
+// Returns the parallel degree of the scan, the number of workers used and the rows.
+fn exec_scan(sess: &mut SessionState, query: &str) -> (usize, usize, super::TextRows) {
+    sess.start_tran_cmd().unwrap();
+    let ast = parser::parse(query).unwrap();
+    let stmt = match parser::sem::kb_analyze(sess, &ast).unwrap() {
+        sem::Stmt::Optimizable(stmt) => stmt,
+        sem::Stmt::Utility(_) => panic!("not a query: {}", query),
+    };
+    let plannedstmt = optimizer::planner(sess, &stmt).unwrap();
+    let parallel = scan_parallel(&plannedstmt.plan_tree);
+    let mut rows = Rows {
+        typout: Vec::new(),
+        outstr: Vec::new(),
+        rows: Vec::new(),
+    };
+    let nworkers = executor::exec_select(&plannedstmt, sess, &mut rows).unwrap();
+    sess.commit_tran_cmd().unwrap();
+    return (parallel, nworkers, rows.rows);
+}
+
+// Insert rows(file) into the nfiles L0 files of the table, one file after another.
+pub fn insert_files(
+    sess: &mut SessionState,
+    table: &str,
+    nfiles: usize,
+    rows: impl Fn(usize) -> Vec<(i32, i32)>,
+) {
+    let tableoid = sess.relname_get_oid(table).unwrap().unwrap();
+    let rel = rel::getrel(sess, tableoid).unwrap();
+    let tableid = sv::TableId {
+        db: sess.reqdb,
+        table: tableoid,
+    };
+    // The L0 files in use are skipped by INSERT, so every INSERT writes to a new file.
+    for file in 0..nfiles {
+        let svslot = sess.tabsv.read(&tableid, &rel.opt.enable_cs_wal).unwrap();
+        sess.start_tran_cmd().unwrap();
+        let inuse = sv::start_write(sess, &svslot, file).unwrap();
+        sess.commit_tran_cmd().unwrap();
+        let values: Vec<_> = rows(file)
+            .into_iter()
+            .map(|(i, j)| format!("({}, {})", i, j))
+            .collect();
+        let insert = format!("insert into {} values {}", table, values.join(", "));
+        exec(sess, &insert).unwrap();
+        sv::abort_write(&svslot, &inuse);
+    }
+    assert_eq!(files_to_scan(sess, &tableid, &rel), [nfiles, 0, 0]);
+}
+
+fn set_max_workers(sess: &mut SessionState, val: i32) {
+    let gucstate = Arc::make_mut(&mut sess.gucstate);
+    guc::set_int_guc(guc::MaxParallelWorkersPerGather, val, gucstate);
+}
+
+#[test]
+fn parallel_scan() {
+    let mut sess = super::new_session();
+    exec(&mut sess, "create table parallel_small(i int)").unwrap();
+    exec(&mut sess, "insert into parallel_small values (1), (2)").unwrap();
+    exec(&mut sess, "insert into parallel_small values (3)").unwrap();
+    // The table has only one file, it is scanned by the session itself.
+    set_max_workers(&mut sess, 4);
+    let (parallel, nworkers, rows) = exec_scan(&mut sess, "select sum(i) from parallel_small");
+    assert_eq!((parallel, nworkers), (1, 0));
+    assert_eq!(rows, text_rows(&[&["6"]]));
+
+    exec(&mut sess, "create table parallel_big(i int, j int)").unwrap();
+    insert_files(&mut sess, "parallel_big", 4, |file| {
+        let rows = file as i32 * 1000 + 1..=file as i32 * 1000 + 1000;
+        rows.map(|i| (i, i % 10)).collect()
+    });
+
+    let query = "select count(*), sum(i), count(j) from parallel_big where j < 5";
+    let expected = text_rows(&[&["2000", "3998000", "2000"]]);
+    for (maxworkers, expected_workers) in [(1, 1), (2, 2), (3, 3), (8, 4)] {
+        set_max_workers(&mut sess, maxworkers);
+        let (parallel, nworkers, rows) = exec_scan(&mut sess, query);
+        assert_eq!(parallel, expected_workers);
+        // The scan with parallel 1 is done by the session without workers.
+        let expected_nworkers = if expected_workers == 1 {
+            0
+        } else {
+            expected_workers
+        };
+        assert_eq!(nworkers, expected_nworkers);
+        assert_eq!(rows, expected);
+    }
+
+    // The leader stops reading early, the workers still exit.
+    let (parallel, nworkers, rows) = exec_scan(&mut sess, "select i from parallel_big limit 3");
+    assert_eq!((parallel, nworkers, rows.len()), (4, 4, 3));
+}
+
+#[test]
+fn maintenance_work_mem() {
+    let mut sess = super::new_session();
+    exec(&mut sess, "create table parallel_maint(i int, j int)").unwrap();
+    insert_files(&mut sess, "parallel_maint", 4, |file| {
+        (1..=300).map(|i| (i, file as i32)).collect()
+    });
+    let gucstate = Arc::make_mut(&mut sess.gucstate);
+    guc::set_int_guc(guc::WorkMem, 64, gucstate);
+    guc::set_int_guc(guc::MaintenanceWorkMem, 1024, gucstate);
+    set_max_workers(&mut sess, 4);
+
+    // The maintenance workers share maintenance_work_mem, 256kB each.
+    let budgets = |sess: &mut SessionState, maintenance: bool| {
+        let body = |_, worker: &mut crate::utils::WorkerState| Ok(worker.work_mem);
+        let workers = if maintenance {
+            sess.exec_maintenance(4, |_| (), body)
+        } else {
+            sess.exec(4, |_| (), body)
+        };
+        let mut budgets = Vec::new();
+        for (exit, work_mem) in wait_workers(&workers).unwrap() {
+            sess.exit_worker(exit);
+            budgets.push(work_mem);
+        }
+        return budgets;
+    };
+    sess.start_tran_cmd().unwrap();
+    assert_eq!(budgets(&mut sess, true), vec![256 * 1024; 4]);
+    assert_eq!(budgets(&mut sess, false), vec![16 * 1024; 4]);
+    sess.commit_tran_cmd().unwrap();
+
+    // VERIFY TABLE runs with maintenance_work_mem while the query still exceeds work_mem.
+    let rows = exec(&mut sess, "verify table parallel_maint").unwrap();
+    assert!(rows.is_empty());
+    let query = "select i, count(*) from parallel_maint group by i";
+    sess.start_tran_cmd().unwrap();
+    let err = super::do_exec(&mut sess, query).unwrap_err();
+    sess.abort_cur_tran().unwrap();
+    assert_eq!(errcode(&err), ERRCODE_OUT_OF_MEMORY);
+}
+
+#[test]
+fn work_mem_partition() {
+    let mut sess = super::new_session();
+    exec(&mut sess, "create table parallel_agg(i int, j int)").unwrap();
+    // Every file has the same 300 groups, the hash table of each worker holds all of them.
+    insert_files(&mut sess, "parallel_agg", 4, |file| {
+        (1..=300).map(|i| (i, file as i32)).collect()
+    });
+    let gucstate = Arc::make_mut(&mut sess.gucstate);
+    guc::set_int_guc(guc::WorkMem, 64, gucstate);
+    let query = "select i, count(*), sum(j), min(j), max(j) from parallel_agg group by i";
+
+    // Each of 4 workers can only use 16kB.
+    set_max_workers(&mut sess, 4);
+    sess.start_tran_cmd().unwrap();
+    let err = super::do_exec(&mut sess, query).unwrap_err();
+    sess.abort_cur_tran().unwrap();
+    assert_eq!(errcode(&err), ERRCODE_OUT_OF_MEMORY);
+
+    // The only worker uses the whole work_mem.
+    set_max_workers(&mut sess, 1);
+    let (parallel, nworkers, rows) = exec_scan(&mut sess, query);
+    assert_eq!((parallel, nworkers, rows.len()), (1, 0, 300));
+    assert!(rows.contains(&text_rows(&[&["7", "4", "6", "0", "3"]])[0]));
+
+    // The partial results of workers are combined by the leader.
+    let gucstate = Arc::make_mut(&mut sess.gucstate);
+    guc::set_int_guc(guc::WorkMem, 1024, gucstate);
+    set_max_workers(&mut sess, 4);
+    let (parallel, nworkers, mut parallel_rows) = exec_scan(&mut sess, query);
+    assert_eq!((parallel, nworkers), (4, 4));
+    let mut rows = rows;
+    rows.sort();
+    parallel_rows.sort();
+    assert_eq!(rows, parallel_rows);
+    let query = "select count(*), sum(i), avg(j), min(i), max(j) from parallel_agg";
+    let (_, nworkers, rows) = exec_scan(&mut sess, query);
+    assert_eq!(nworkers, 4);
+    assert_eq!(rows, text_rows(&[&["1200", "180600", "1.5", "1", "3"]]));
+}
+
+#[test]
+fn worker_error() {
+    let mut sess = super::new_session();
+    // Worker 1 fails after worker 3, but the error of worker 1 is always reported.
+    let workers = sess.exec(
+        4,
+        |idx| idx,
+        |idx, _| {
+            match idx {
+                1 => {
+                    std::thread::sleep(std::time::Duration::from_millis(50));
+                    kbbail!(ERRCODE_INTERNAL_ERROR, "worker {} failed", idx);
+                }
+                3 => panic!("worker {} panicked", idx),
+                _ => {}
+            }
+            Ok(idx)
+        },
+    );
+    let err = wait_workers(&workers).err().unwrap();
+    assert_eq!(err.to_string(), "worker 1 failed");
+
+    let workers = sess.exec(
+        2,
+        |idx| idx,
+        |idx, _| -> anyhow::Result<usize> {
+            if idx == 1 {
+                panic!("boom");
+            }
+            Ok(idx)
+        },
+    );
+    let err = wait_workers(&workers).err().unwrap();
+    assert_eq!(errcode(&err), ERRCODE_INTERNAL_ERROR);
+    assert_eq!(err.to_string(), "parallel worker panicked: boom");
+
+    // The pool is still usable after the failed workers.
+    let workers = sess.exec(4, |idx| idx * 10, |arg, _| Ok(arg + 1));
+    let rets: Vec<_> = wait_workers(&workers)
+        .unwrap()
+        .into_iter()
+        .map(|(_, ret)| ret)
+        .collect();
+    assert_eq!(rets, [1, 11, 21, 31]);
+
+    // The error of COPY workers fails the statement instead of losing the rows.
+    exec(&mut sess, "create table worker_err(i int, j int)").unwrap();
+    let mut input = NamedTempFile::new().unwrap();
+    for i in 1..=100 {
+        writeln!(input, "{},{}", i, i).unwrap();
+    }
+    writeln!(input, "x,1").unwrap();
+    input.flush().unwrap();
+    for parallel in [1, 3] {
+        let copy = format!(
+            "copy worker_err from '{}' with (delimiter ',', parallel {})",
+            input.path().display(),
+            parallel
+        );
+        let err = exec(&mut sess, &copy).unwrap_err();
+        assert_eq!(err.to_string(), "invalid digit found in string");
+    }
+    // The error of the leader does not wait for the workers forever.
+    let mut input = NamedTempFile::new().unwrap();
+    writeln!(input, "1,1,1").unwrap();
+    input.flush().unwrap();
+    let copy = format!(
+        "copy worker_err from '{}' with (delimiter ',', parallel 2)",
+        input.path().display()
+    );
+    let err = exec(&mut sess, &copy).unwrap_err();
+    assert_eq!(errcode(&err), ERRCODE_BAD_COPY_FILE_FORMAT);
+    let rows = exec(&mut sess, "select count(*) from worker_err").unwrap();
+    assert_eq!(rows, text_rows(&[&["0"]]));
+}
+
+#[test]
+fn pool_reuse() {
+    let mut sess = super::new_session();
+    let mut threads = HashSet::new();
+    for round in 0..64 {
+        let parallel = [4, 1, 3, 2][round % 4];
+        let workers = sess.exec(parallel, |_| (), |_, _| Ok(std::thread::current().id()));
+        for (_, thdid) in wait_workers(&workers).unwrap() {
+            threads.insert(thdid);
+        }
+    }
+    // All execs run on the threads created by the first one.
+    assert!(threads.len() <= 4);
+}
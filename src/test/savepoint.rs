@@ -0,0 +1,144 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{exec, text_rows};
+use crate::access::clog::XidStatus;
+use crate::access::xact::SessionExt;
+use crate::protocol::{
+    ERRCODE_IN_FAILED_SQL_TRANSACTION, ERRCODE_NO_ACTIVE_SQL_TRANSACTION,
+    ERRCODE_S_E_INVALID_SPECIFICATION,
+};
+use crate::utils::err::errcode;
+
+#[test]
+fn rollback_to_and_release() {
+    let mut sess = super::new_session();
+    exec(&mut sess, "create table sp_t(i int)").unwrap();
+    exec(&mut sess, "begin").unwrap();
+    exec(&mut sess, "insert into sp_t values (1)").unwrap();
+    let topxid = sess.get_xid().unwrap();
+    exec(&mut sess, "savepoint a").unwrap();
+    exec(&mut sess, "insert into sp_t values (2)").unwrap();
+    let subxid = sess.get_xid().unwrap();
+    assert_ne!(topxid, subxid);
+    exec(&mut sess, "savepoint b").unwrap();
+    exec(&mut sess, "insert into sp_t values (3)").unwrap();
+    exec(&mut sess, "rollback to a").unwrap();
+    assert_eq!(XidStatus::Aborted, sess.clog.xid_status(subxid).unwrap());
+    let rows = exec(&mut sess, "select i from sp_t").unwrap();
+    assert_eq!(rows, text_rows(&[&["1"]]));
+
+    // The savepoint is kept after ROLLBACK TO, the later changes are done in a new subxact.
+    exec(&mut sess, "insert into sp_t values (4)").unwrap();
+    let subxid = sess.get_xid().unwrap();
+    exec(&mut sess, "release a").unwrap();
+    assert!(exec(&mut sess, "release a").is_err());
+    assert!(sess.is_aborted());
+    exec(&mut sess, "rollback").unwrap();
+
+    exec(&mut sess, "begin").unwrap();
+    exec(&mut sess, "savepoint a").unwrap();
+    exec(&mut sess, "insert into sp_t values (5)").unwrap();
+    let subxid2 = sess.get_xid().unwrap();
+    exec(&mut sess, "release savepoint a").unwrap();
+    exec(&mut sess, "commit").unwrap();
+    assert_eq!(XidStatus::Aborted, sess.clog.xid_status(subxid).unwrap());
+    assert_eq!(XidStatus::Committed, sess.clog.xid_status(subxid2).unwrap());
+    let rows = exec(&mut sess, "select i from sp_t").unwrap();
+    assert_eq!(rows, text_rows(&[&["5"]]));
+}
+
+#[test]
+fn error_in_savepoint() {
+    let mut sess = super::new_session();
+    exec(&mut sess, "create table sp_e(i int)").unwrap();
+    exec(&mut sess, "begin").unwrap();
+    exec(&mut sess, "insert into sp_e values (1)").unwrap();
+    exec(&mut sess, "savepoint a").unwrap();
+    exec(&mut sess, "insert into sp_e values (2)").unwrap();
+    assert!(exec(&mut sess, "select * from sp_e_missing").is_err());
+    assert!(sess.is_aborted());
+    let err = exec(&mut sess, "savepoint b").unwrap_err();
+    assert_eq!(errcode(&err), ERRCODE_IN_FAILED_SQL_TRANSACTION);
+
+    exec(&mut sess, "rollback to savepoint a").unwrap();
+    assert!(!sess.is_aborted());
+    exec(&mut sess, "insert into sp_e values (4)").unwrap();
+    exec(&mut sess, "commit").unwrap();
+    let rows = exec(&mut sess, "select i from sp_e").unwrap();
+    assert_eq!(rows, text_rows(&[&["1"], &["4"]]));
+
+    // COMMIT of a failed subxact rolls back the whole transaction.
+    exec(&mut sess, "begin").unwrap();
+    exec(&mut sess, "insert into sp_e values (5)").unwrap();
+    exec(&mut sess, "savepoint a").unwrap();
+    assert!(exec(&mut sess, "select * from sp_e_missing").is_err());
+    exec(&mut sess, "commit").unwrap();
+    let rows = exec(&mut sess, "select i from sp_e").unwrap();
+    assert_eq!(rows, text_rows(&[&["1"], &["4"]]));
+}
+
+#[test]
+fn savepoint_names() {
+    let mut sess = super::new_session();
+    let err = exec(&mut sess, "savepoint a").unwrap_err();
+    assert_eq!(errcode(&err), ERRCODE_NO_ACTIVE_SQL_TRANSACTION);
+    let err = exec(&mut sess, "rollback to a").unwrap_err();
+    assert_eq!(errcode(&err), ERRCODE_NO_ACTIVE_SQL_TRANSACTION);
+
+    exec(&mut sess, "create table sp_n(i int)").unwrap();
+    exec(&mut sess, "begin").unwrap();
+    exec(&mut sess, "savepoint a").unwrap();
+    exec(&mut sess, "insert into sp_n values (1)").unwrap();
+    exec(&mut sess, "savepoint a").unwrap();
+    exec(&mut sess, "insert into sp_n values (2)").unwrap();
+    // The newer savepoint shadows the older one of the same name.
+    exec(&mut sess, "rollback to a").unwrap();
+    let rows = exec(&mut sess, "select i from sp_n").unwrap();
+    assert_eq!(rows, text_rows(&[&["1"]]));
+    exec(&mut sess, "release a").unwrap();
+    exec(&mut sess, "rollback to a").unwrap();
+    let rows = exec(&mut sess, "select i from sp_n").unwrap();
+    assert_eq!(rows, text_rows(&[]));
+
+    let err = exec(&mut sess, "release b").unwrap_err();
+    assert_eq!(errcode(&err), ERRCODE_S_E_INVALID_SPECIFICATION);
+    exec(&mut sess, "rollback").unwrap();
+}
+
+#[test]
+fn visibility() {
+    let mut sess = super::new_session();
+    let mut other = super::new_session();
+    exec(&mut sess, "create table sp_v(i int)").unwrap();
+    exec(&mut sess, "begin").unwrap();
+    exec(&mut sess, "savepoint a").unwrap();
+    exec(&mut sess, "insert into sp_v values (1)").unwrap();
+    exec(&mut sess, "release a").unwrap();
+    exec(&mut sess, "savepoint b").unwrap();
+    exec(&mut sess, "insert into sp_v values (2)").unwrap();
+    // The rows of the released subxact are invisible until the top transaction commits.
+    let rows = exec(&mut other, "select i from sp_v").unwrap();
+    assert_eq!(rows, text_rows(&[]));
+    exec(&mut sess, "commit").unwrap();
+    let rows = exec(&mut other, "select i from sp_v").unwrap();
+    assert_eq!(rows, text_rows(&[&["1"], &["2"]]));
+
+    exec(&mut sess, "begin").unwrap();
+    exec(&mut sess, "savepoint a").unwrap();
+    exec(&mut sess, "insert into sp_v values (3)").unwrap();
+    exec(&mut sess, "release a").unwrap();
+    exec(&mut sess, "savepoint b").unwrap();
+    exec(&mut sess, "insert into sp_v values (4)").unwrap();
+    exec(&mut sess, "rollback").unwrap();
+    let rows = exec(&mut other, "select i from sp_v").unwrap();
+    assert_eq!(rows, text_rows(&[&["1"], &["2"]]));
+}
@@ -0,0 +1,87 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{exec, text_rows};
+
+#[test]
+fn order_by_limit() {
+    let mut sess = super::new_session();
+    exec(&mut sess, "create table sort_t(i int, j int)").unwrap();
+    assert_eq!(
+        exec(&mut sess, "select i from sort_t order by i limit 3").unwrap(),
+        text_rows(&[])
+    );
+    exec(
+        &mut sess,
+        "insert into sort_t values (3, 30), (1, 11), (4, 40)",
+    )
+    .unwrap();
+    exec(
+        &mut sess,
+        "insert into sort_t values (1, 10), (5, 50), (2, 20)",
+    )
+    .unwrap();
+
+    let rows = exec(&mut sess, "select i, j from sort_t order by i, j desc").unwrap();
+    assert_eq!(
+        rows,
+        text_rows(&[
+            &["1", "11"],
+            &["1", "10"],
+            &["2", "20"],
+            &["3", "30"],
+            &["4", "40"],
+            &["5", "50"]
+        ])
+    );
+    let rows = exec(
+        &mut sess,
+        "select i + j as k from sort_t order by k desc limit 2",
+    )
+    .unwrap();
+    assert_eq!(rows, text_rows(&[&["55"], &["44"]]));
+    let rows = exec(&mut sess, "select i, j from sort_t order by 2 limit 2").unwrap();
+    assert_eq!(rows, text_rows(&[&["1", "10"], &["1", "11"]]));
+    // ORDER BY a column not in the select list.
+    let rows = exec(
+        &mut sess,
+        "select i from sort_t where i > 1 order by j desc",
+    )
+    .unwrap();
+    assert_eq!(rows, text_rows(&[&["5"], &["4"], &["3"], &["2"]]));
+    let rows = exec(
+        &mut sess,
+        "select i, count(*) from sort_t group by i order by count(*) desc, i limit 2",
+    )
+    .unwrap();
+    assert_eq!(rows, text_rows(&[&["1", "2"], &["2", "1"]]));
+
+    assert_eq!(
+        exec(&mut sess, "select i from sort_t limit 4")
+            .unwrap()
+            .len(),
+        4
+    );
+    assert_eq!(
+        exec(&mut sess, "select i from sort_t limit 100")
+            .unwrap()
+            .len(),
+        6
+    );
+    assert_eq!(
+        exec(&mut sess, "select i from sort_t order by i limit 0").unwrap(),
+        text_rows(&[])
+    );
+
+    assert!(exec(&mut sess, "select i from sort_t limit -1").is_err());
+    assert!(exec(&mut sess, "select i from sort_t order by 3").is_err());
+    assert!(exec(&mut sess, "select i, j as i from sort_t order by i").is_err());
+}
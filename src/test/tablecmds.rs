@@ -0,0 +1,295 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{exec, text_rows};
+use crate::access::rel;
+use crate::access::sv;
+use crate::catalog::namespace::SessionExt;
+use crate::protocol::{
+    ERRCODE_DATA_CORRUPTED, ERRCODE_DUPLICATE_COLUMN, ERRCODE_DUPLICATE_TABLE,
+    ERRCODE_INVALID_PARAMETER_VALUE, ERRCODE_NOT_NULL_VIOLATION, ERRCODE_UNDEFINED_COLUMN,
+    ERRCODE_UNDEFINED_TABLE,
+};
+use crate::utils::err::errcode;
+use crate::utils::{sb, SessionState};
+use crate::{INT2OID, INT4OID};
+use std::collections::HashMap;
+use std::path::Path;
+
+#[test]
+fn create_table() {
+    let mut sess = super::new_session();
+    exec(&mut sess, "create table create_t(i int, j smallint)").unwrap();
+    let tableoid = sess.relname_get_oid("create_t").unwrap().unwrap();
+    let rel = rel::getrel(&mut sess, tableoid).unwrap();
+    let attrs: Vec<_> = rel
+        .attrs
+        .iter()
+        .map(|v| (v.num.get(), v.name.as_str(), v.typ.id))
+        .collect();
+    assert_eq!(attrs, [(1, "i", INT4OID), (2, "j", INT2OID)]);
+
+    let manifest = sv::get_minafest_path(sess.reqdb, tableoid);
+    assert!(Path::new(&manifest).exists());
+    let tableid = sv::TableId {
+        db: sess.reqdb,
+        table: tableoid,
+    };
+    assert!(sess.tabsv.read(&tableid, &rel.opt.enable_cs_wal).is_ok());
+
+    assert!(exec(&mut sess, "create table create_t(i int)").is_err());
+    assert!(exec(&mut sess, "create table create_nons.t(i int)").is_err());
+}
+
+// The non-empty files of the table.
+fn table_files(sess: &mut SessionState, table: &str) -> (sv::TableId, Vec<sv::FileMeta>) {
+    let tableoid = sess.relname_get_oid(table).unwrap().unwrap();
+    let rel = rel::getrel(sess, tableoid).unwrap();
+    let tableid = sv::TableId {
+        db: sess.reqdb,
+        table: tableoid,
+    };
+    let svslot = sess.tabsv.read(&tableid, &rel.opt.enable_cs_wal).unwrap();
+    let sv = svslot.v.read().unwrap();
+    return (tableid, sv.as_ref().unwrap().datafiles());
+}
+
+fn mvccfile_len(sess: &mut SessionState, table: &str) -> u64 {
+    let (tableid, files) = table_files(sess, table);
+    assert_eq!(files.len(), 1);
+    let path = sv::get_mvccfile_path(tableid, files[0].fileid);
+    return std::fs::metadata(path).unwrap().len();
+}
+
+#[test]
+fn add_column() {
+    let mut sess = super::new_session();
+    exec(&mut sess, "create table add_t(i int)").unwrap();
+    exec(&mut sess, "insert into add_t values (1), (2)").unwrap();
+    sess.tabsv.flushall(true).unwrap();
+    let (_, files) = table_files(&mut sess, "add_t");
+    exec(&mut sess, "alter table add_t add column j int").unwrap();
+    exec(&mut sess, "alter table add_t add k int not null default 7").unwrap();
+    // The data files are not rewritten.
+    assert_eq!(table_files(&mut sess, "add_t").1, files);
+
+    exec(&mut sess, "insert into add_t values (3, 30, 300)").unwrap();
+    let rows = exec(&mut sess, "select i, j, k from add_t order by i").unwrap();
+    let expected = [
+        &["1", "NULL", "7"][..],
+        &["2", "NULL", "7"],
+        &["3", "30", "300"],
+    ];
+    assert_eq!(rows, text_rows(&expected));
+    let rows = exec(&mut sess, "select count(*), sum(i) from add_t where k = 7").unwrap();
+    assert_eq!(rows, text_rows(&[&["2", "3"]]));
+    assert!(exec(&mut sess, "verify table add_t").unwrap().is_empty());
+
+    let err = exec(&mut sess, "alter table add_t add column j int").unwrap_err();
+    assert_eq!(errcode(&err), ERRCODE_DUPLICATE_COLUMN);
+    let err = exec(&mut sess, "alter table add_t add column l int not null").unwrap_err();
+    assert_eq!(errcode(&err), ERRCODE_NOT_NULL_VIOLATION);
+    let tableoid = sess.relname_get_oid("add_t").unwrap().unwrap();
+    assert_eq!(rel::getrel(&mut sess, tableoid).unwrap().attrs.len(), 3);
+}
+
+#[test]
+fn rename() {
+    let mut sess = super::new_session();
+    exec(&mut sess, "create table rename_t(i int, j int)").unwrap();
+    exec(&mut sess, "create table rename_other(i int)").unwrap();
+    exec(&mut sess, "insert into rename_t values (1, 10)").unwrap();
+    let tableoid = sess.relname_get_oid("rename_t").unwrap().unwrap();
+
+    exec(&mut sess, "alter table rename_t rename to rename_t2").unwrap();
+    assert_eq!(sess.relname_get_oid("rename_t2").unwrap(), Some(tableoid));
+    let err = exec(&mut sess, "select i from rename_t").unwrap_err();
+    assert_eq!(errcode(&err), ERRCODE_UNDEFINED_TABLE);
+    exec(&mut sess, "alter table rename_t2 rename column j to k").unwrap();
+    exec(&mut sess, "alter table rename_t2 rename i to h").unwrap();
+    let rows = exec(&mut sess, "select h, k from rename_t2").unwrap();
+    assert_eq!(rows, text_rows(&[&["1", "10"]]));
+    let err = exec(&mut sess, "select j from rename_t2").unwrap_err();
+    assert_eq!(errcode(&err), ERRCODE_UNDEFINED_COLUMN);
+
+    let err = exec(&mut sess, "alter table rename_t2 rename to rename_other").unwrap_err();
+    assert_eq!(errcode(&err), ERRCODE_DUPLICATE_TABLE);
+    let err = exec(&mut sess, "alter table rename_t2 rename column h to k").unwrap_err();
+    assert_eq!(errcode(&err), ERRCODE_DUPLICATE_COLUMN);
+    let err = exec(&mut sess, "alter table rename_t2 rename column j to l").unwrap_err();
+    assert_eq!(errcode(&err), ERRCODE_UNDEFINED_COLUMN);
+    // The failed renames change nothing.
+    let attrs = rel::getrel(&mut sess, tableoid).unwrap().attrs;
+    let names: Vec<_> = attrs.iter().map(|v| v.name.as_str()).collect();
+    assert_eq!(names, ["h", "k"]);
+    assert_eq!(sess.relname_get_oid("rename_t2").unwrap(), Some(tableoid));
+}
+
+#[test]
+fn mvcc_blk_rows() {
+    let mut sess = super::new_session();
+    // Only one page is cached, so the pages are stored and loaded again.
+    for blk_rows in [4, 16] {
+        let query = format!(
+            "create table blk_rows{}(i int) with (mvcc_blk_rows = {}, mvcc_buf_cap = 1)",
+            blk_rows, blk_rows
+        );
+        exec(&mut sess, &query).unwrap();
+    }
+    let values: Vec<_> = (1..=40).map(|i| format!("({})", i)).collect();
+    for table in ["blk_rows4", "blk_rows16"] {
+        let insert = format!("insert into {} values {}", table, values.join(", "));
+        exec(&mut sess, &insert).unwrap();
+        let query = format!("select count(*), sum(i) from {}", table);
+        let rows = exec(&mut sess, &query).unwrap();
+        assert_eq!(rows, text_rows(&[&["40", "820"]]));
+    }
+    sess.tabmvcc.flushall(true).unwrap();
+    // A page of n rows takes 16 bytes of header, 16n bytes of xmin/xmax and n/2 bytes of
+    // infomask, so 10 pages of 82 bytes and 3 pages of 280 bytes.
+    assert_eq!(mvccfile_len(&mut sess, "blk_rows4"), 820);
+    assert_eq!(mvccfile_len(&mut sess, "blk_rows16"), 840);
+
+    let query = "create table blk_rows0(i int) with (mvcc_blk_rows = 0)";
+    let err = exec(&mut sess, query).unwrap_err();
+    assert_eq!(errcode(&err), ERRCODE_INVALID_PARAMETER_VALUE);
+    assert!(sess.relname_get_oid("blk_rows0").unwrap().is_none());
+}
+
+#[test]
+fn verify_manifest_files() {
+    let mut sess = super::new_session();
+    exec(&mut sess, "create table verify_t(i int)").unwrap();
+    exec(&mut sess, "insert into verify_t values (1), (2)").unwrap();
+    sess.tabsv.flushall(true).unwrap();
+    let (tableid, files) = table_files(&mut sess, "verify_t");
+    let datapath = sv::get_datafile_path(tableid, files[0].fileid);
+    // Load the manifest just written.
+    let load = |verify_files| {
+        let svdata = sv::SVCommonData::new(sess.pending_fileops, None, verify_files);
+        let tabsv: sv::TabSupVer = sb::new_lru_sb(1, svdata);
+        tabsv.read(&tableid, &false).map(|_| ())
+    };
+    load(true).unwrap();
+
+    let bakpath = format!("{}.bak", datapath);
+    std::fs::rename(&datapath, &bakpath).unwrap();
+    let err = load(true).unwrap_err();
+    std::fs::rename(&bakpath, &datapath).unwrap();
+    assert_eq!(errcode(&err), ERRCODE_DATA_CORRUPTED);
+    let msg = format!(
+        "the file {} referenced by the manifest does not exist",
+        datapath
+    );
+    assert_eq!(err.to_string(), msg);
+
+    let data = std::fs::read(&datapath).unwrap();
+    std::fs::write(&datapath, &data[..data.len() - 1]).unwrap();
+    let err = load(true).unwrap_err();
+    assert!(load(false).is_ok());
+    std::fs::write(&datapath, &data).unwrap();
+    let msg = format!(
+        "the file {} is {} bytes, but the manifest expects {} bytes",
+        datapath,
+        data.len() - 1,
+        data.len()
+    );
+    assert_eq!(err.to_string(), msg);
+}
+
+#[test]
+fn rebuild_manifest() {
+    let mut sess = super::new_session();
+    exec(&mut sess, "create table rebuild_t(i int)").unwrap();
+    exec(&mut sess, "insert into rebuild_t values (1), (2)").unwrap();
+    exec(&mut sess, "insert into rebuild_t values (3)").unwrap();
+    sess.tabsv.flushall(true).unwrap();
+    let (tableid, files) = table_files(&mut sess, "rebuild_t");
+    assert_eq!(files.len(), 1);
+    let manifest = sv::get_minafest_path(tableid.db, tableid.table);
+    std::fs::remove_file(&manifest).unwrap();
+
+    // The torn block at the end of the data file is not a part of the table.
+    let datapath = sv::get_datafile_path(tableid, files[0].fileid);
+    let data = std::fs::read(&datapath).unwrap();
+    let mut torn = data.clone();
+    torn.extend_from_slice(&data[..data.len() / 2]);
+    std::fs::write(&datapath, &torn).unwrap();
+    let rebuilt = sv::rebuild_manifest(tableid, &HashMap::new(), false).unwrap();
+    assert_eq!(rebuilt, files);
+    assert!(!Path::new(&manifest).exists());
+
+    // The wal of the table is preferred.
+    let walfiles = sv::l0files_from_wal(tableid).unwrap();
+    assert_eq!(walfiles.get(&files[0].fileid), Some(&files[0]));
+    let rebuilt = sv::rebuild_manifest(tableid, &walfiles, true).unwrap();
+    assert_eq!(rebuilt, files);
+    let svdata = sv::SVCommonData::new(sess.pending_fileops, None, true);
+    let tabsv: sv::TabSupVer = sb::new_lru_sb(1, svdata);
+    let svslot = tabsv.read(&tableid, &false).unwrap();
+    let sv = svslot.v.read().unwrap();
+    assert_eq!(sv.as_ref().unwrap().datafiles(), files);
+    std::fs::write(&datapath, &data).unwrap();
+    let rows = exec(&mut sess, "select count(*), sum(i) from rebuild_t").unwrap();
+    assert_eq!(rows, text_rows(&[&["3", "6"]]));
+}
+
+#[test]
+fn manifest_journal() {
+    let mut sess = super::new_session();
+    exec(&mut sess, "create table journal_t(i int)").unwrap();
+    let (tableid, _) = table_files(&mut sess, "journal_t");
+    let manifest = sv::get_minafest_path(tableid.db, tableid.table);
+    let journal = sv::get_journal_path(tableid.db, tableid.table);
+    let (mut journaled, mut compacted) = (false, false);
+    for i in 1..=8 {
+        let insert = format!("insert into journal_t values ({})", i);
+        exec(&mut sess, &insert).unwrap();
+        sess.tabsv.flushall(true).unwrap();
+        let (_, files) = table_files(&mut sess, "journal_t");
+        // The journal never outgrows the manifest rewritten from the files.
+        let manifestlen = (sv::INIT_MANIFEST_DAT.len() + files.len() * 16) as u64;
+        match std::fs::metadata(&journal) {
+            Ok(meta) => {
+                journaled = true;
+                assert!(meta.len() <= manifestlen);
+            }
+            Err(_) => {
+                compacted = journaled;
+                assert_eq!(std::fs::metadata(&manifest).unwrap().len(), manifestlen);
+            }
+        }
+        // The manifest with its journal loads the same files as the ones in memory, which
+        // are what a full rewrite writes.
+        let svdata = sv::SVCommonData::new(sess.pending_fileops, None, true);
+        let tabsv: sv::TabSupVer = sb::new_lru_sb(1, svdata);
+        let svslot = tabsv.read(&tableid, &false).unwrap();
+        let sv = svslot.v.read().unwrap();
+        assert_eq!(sv.as_ref().unwrap().datafiles(), files);
+        assert_eq!(files.iter().map(|f| f.rownum).sum::<u32>(), i);
+    }
+    assert!(journaled && compacted);
+
+    // The torn record at the end of the journal is ignored.
+    exec(&mut sess, "insert into journal_t values (9)").unwrap();
+    sess.tabsv.flushall(true).unwrap();
+    let (_, files) = table_files(&mut sess, "journal_t");
+    let mut data = std::fs::read(&journal).unwrap_or_default();
+    let validlen = data.len() as u64;
+    data.extend_from_slice(&[7u8; 13]);
+    std::fs::write(&journal, &data).unwrap();
+    let svdata = sv::SVCommonData::new(sess.pending_fileops, None, true);
+    let tabsv: sv::TabSupVer = sb::new_lru_sb(1, svdata);
+    let svslot = tabsv.read(&tableid, &false).unwrap();
+    let sv = svslot.v.read().unwrap();
+    assert_eq!(sv.as_ref().unwrap().datafiles(), files);
+    assert_eq!(std::fs::metadata(&journal).unwrap().len(), validlen);
+}
@@ -0,0 +1,200 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{exec, text_rows, wait_for_waiter};
+use crate::access::lmgr::{LockMode, LockTag, SessionExt as LMGRSessionExt};
+use crate::catalog::namespace::SessionExt;
+use crate::catalog::{get_type_input_info, get_type_output_info};
+use crate::guc::NoticeLevel;
+use crate::protocol::{
+    ERRCODE_DUPLICATE_OBJECT, ERRCODE_INVALID_OBJECT_DEFINITION, ERRCODE_INVALID_PARAMETER_VALUE,
+    ERRCODE_SYNTAX_ERROR, ERRCODE_UNDEFINED_FUNCTION, ERRCODE_UNDEFINED_OBJECT,
+};
+use crate::utils::err::errcode;
+use crate::{INT4OID, PROCRELID, TYPERELID};
+use std::time::Duration;
+
+#[test]
+fn define_type() {
+    let mut sess = super::new_session();
+    exec(
+        &mut sess,
+        "create type def_int (input = int4in, output = int4out, internallength = 4, \
+         alignment = int4)",
+    )
+    .unwrap();
+    let typ = sess.typname_get_type("def_int").unwrap().unwrap();
+    assert_eq!((typ.len, typ.align), (4, 4));
+    assert!(typ.isdefined);
+    assert_eq!(
+        get_type_input_info(&sess, typ.id).unwrap(),
+        get_type_input_info(&sess, INT4OID).unwrap()
+    );
+    assert_eq!(
+        get_type_output_info(&sess, typ.id).unwrap(),
+        get_type_output_info(&sess, INT4OID).unwrap()
+    );
+    exec(&mut sess, "create table def_typ_t(i def_int)").unwrap();
+    exec(&mut sess, "insert into def_typ_t values (33), (null)").unwrap();
+    let rows = exec(&mut sess, "select i from def_typ_t").unwrap();
+    assert_eq!(rows, text_rows(&[&["33"], &["NULL"]]));
+    assert!(exec(&mut sess, "insert into def_typ_t values ('x')").is_err());
+    let rows = exec(&mut sess, "select count(*) from def_typ_t").unwrap();
+    assert_eq!(rows, text_rows(&[&["2"]]));
+
+    let err = exec(
+        &mut sess,
+        "create type def_int (input = int4in, output = int4out)",
+    )
+    .unwrap_err();
+    assert_eq!(errcode(&err), ERRCODE_DUPLICATE_OBJECT);
+    exec(&mut sess, "drop table def_typ_t").unwrap();
+    exec(&mut sess, "drop type def_int").unwrap();
+}
+
+#[test]
+fn define_type_error() {
+    let mut sess = super::new_session();
+    let cases = [
+        ("(output = int4out)", ERRCODE_INVALID_OBJECT_DEFINITION),
+        ("(input = int4in)", ERRCODE_INVALID_OBJECT_DEFINITION),
+        (
+            "(input = nosuchin, output = int4out)",
+            ERRCODE_UNDEFINED_FUNCTION,
+        ),
+        // int4out takes int4 rather than the text.
+        (
+            "(input = int4out, output = int4out)",
+            ERRCODE_UNDEFINED_FUNCTION,
+        ),
+        // The output function must take the result of the input function.
+        (
+            "(input = int4in, output = int8out)",
+            ERRCODE_UNDEFINED_FUNCTION,
+        ),
+        (
+            "(input = int4in, output = int4out, internallength = 8)",
+            ERRCODE_INVALID_OBJECT_DEFINITION,
+        ),
+        (
+            "(input = int4in, output = int4out, internallength = variable)",
+            ERRCODE_INVALID_OBJECT_DEFINITION,
+        ),
+        (
+            "(input = int4in, output = int4out, alignment = double)",
+            ERRCODE_INVALID_OBJECT_DEFINITION,
+        ),
+        (
+            "(input = int4in, output = int4out, alignment = int3)",
+            ERRCODE_INVALID_PARAMETER_VALUE,
+        ),
+        (
+            "(input = int4in, input = int4in, output = int4out)",
+            ERRCODE_SYNTAX_ERROR,
+        ),
+        ("(input = 1, output = int4out)", ERRCODE_SYNTAX_ERROR),
+    ];
+    for (def, code) in &cases {
+        let query = format!("create type def_err {}", def);
+        let err = exec(&mut sess, &query).unwrap_err();
+        assert_eq!(errcode(&err), *code, "query={} err={}", query, err);
+    }
+    assert!(sess.typname_get_type("def_err").unwrap().is_none());
+
+    // The unknown attribute is ignored with a warning, just as PostgreSQL.
+    sess.notices.clear();
+    exec(
+        &mut sess,
+        "create type def_byval (input = int4in, output = int4out, passedbyvalue = 1)",
+    )
+    .unwrap();
+    let notices: Vec<_> = sess.notices.drain(..).map(|v| (v.level, v.code)).collect();
+    assert_eq!(notices, [(NoticeLevel::Warning, ERRCODE_SYNTAX_ERROR)]);
+    assert!(sess.typname_get_type("def_byval").unwrap().is_some());
+    exec(&mut sess, "drop type def_byval").unwrap();
+}
+
+// ALTER TABLE ADD COLUMN looks up the type under the lock, so it waits for DROP TYPE and then
+// finds the type dropped.
+#[test]
+fn add_column_during_drop_type() {
+    let mut sess = super::new_session();
+    exec(
+        &mut sess,
+        "create type lock_int (input = int4in, output = int4out)",
+    )
+    .unwrap();
+    exec(&mut sess, "create table lock_typ_t(i lock_int)").unwrap();
+    exec(&mut sess, "create table lock_typ_t2(i int)").unwrap();
+    let typoid = sess.typname_get_type("lock_int").unwrap().unwrap().id;
+    let tableoid = sess.relname_get_oid("lock_typ_t").unwrap().unwrap();
+    // DROP TYPE holds the type lock while it waits for the table.
+    exec(&mut sess, "begin").unwrap();
+    exec(&mut sess, "insert into lock_typ_t values (1)").unwrap();
+    std::thread::scope(|s| {
+        let dropper = s.spawn(|| {
+            let mut sess = super::new_session();
+            exec(&mut sess, "drop type lock_int cascade").map(|_| ())
+        });
+        let reltag = LockTag::Relation {
+            dboid: Some(sess.reqdb),
+            reloid: tableoid,
+        };
+        assert!(wait_for_waiter(&sess, reltag, Duration::from_secs(10)));
+        let alterer = s.spawn(|| {
+            let mut sess = super::new_session();
+            exec(&mut sess, "alter table lock_typ_t2 add column j lock_int").map(|_| ())
+        });
+        let typtag = LockTag::Object {
+            dboid: sess.reqdb,
+            clsoid: TYPERELID,
+            objoid: typoid,
+        };
+        let waited = wait_for_waiter(&sess, typtag, Duration::from_secs(1));
+        exec(&mut sess, "commit").unwrap();
+        dropper.join().unwrap().unwrap();
+        let err = alterer.join().unwrap().unwrap_err();
+        assert_eq!(errcode(&err), ERRCODE_UNDEFINED_OBJECT);
+        assert!(waited);
+    });
+    let rows = exec(&mut sess, "select * from lock_typ_t2").unwrap();
+    assert!(rows.is_empty());
+    assert!(exec(&mut sess, "select j from lock_typ_t2").is_err());
+}
+
+// CREATE TYPE locks its functions, so it waits for the session dropping them.
+#[test]
+fn define_type_locks_procs() {
+    let mut sess = super::new_session();
+    let inproc = get_type_input_info(&sess, INT4OID).unwrap();
+    // Like DROP FUNCTION int4in.
+    exec(&mut sess, "begin").unwrap();
+    sess.lock_proc(inproc, LockMode::AccessExclusive).unwrap();
+    std::thread::scope(|s| {
+        let definer = s.spawn(|| {
+            let mut sess = super::new_session();
+            let query = "create type lock_proc_int (input = int4in, output = int4out)";
+            exec(&mut sess, query).map(|_| ())
+        });
+        let proctag = LockTag::Object {
+            dboid: sess.reqdb,
+            clsoid: PROCRELID,
+            objoid: inproc,
+        };
+        let waited = wait_for_waiter(&sess, proctag, Duration::from_secs(1));
+        assert!(sess.typname_get_type("lock_proc_int").unwrap().is_none());
+        exec(&mut sess, "commit").unwrap();
+        definer.join().unwrap().unwrap();
+        assert!(waited);
+    });
+    assert!(sess.typname_get_type("lock_proc_int").unwrap().is_some());
+    exec(&mut sess, "drop type lock_proc_int").unwrap();
+}
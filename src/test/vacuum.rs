@@ -0,0 +1,193 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{exec, text_rows, GLOBAL_STATE};
+use crate::access::autovacuum::{self, AutoVacOpts};
+use crate::access::{rel, sv};
+use crate::catalog::namespace::SessionExt;
+use crate::commands::vacuum::VacuumReclaimer;
+use crate::protocol::ERRCODE_ACTIVE_SQL_TRANSACTION;
+use crate::utils::err::errcode;
+use crate::utils::{SessionState, FROZEN_XID};
+use std::sync::atomic::{AtomicBool, Ordering::Relaxed};
+use std::time::{Duration, Instant};
+
+// The data files of the table and the xmins of all their rows.
+fn files_xmins(sess: &mut SessionState, table: &str) -> (Vec<sv::FileMeta>, Vec<u64>) {
+    let tableoid = sess.relname_get_oid(table).unwrap().unwrap();
+    let rel = rel::getrel(sess, tableoid).unwrap();
+    let tableid = sv::TableId {
+        db: sess.reqdb,
+        table: tableoid,
+    };
+    let files = {
+        let svslot = sess.tabsv.read(&tableid, &rel.opt.enable_cs_wal).unwrap();
+        let sv = svslot.v.read().unwrap();
+        sv.as_ref().unwrap().datafiles()
+    };
+    let mvccslot = sess.tabmvcc.read(&tableid, &rel.opt).unwrap();
+    let mvcc = mvccslot.v.read().unwrap();
+    let mvcc = mvcc.as_ref().unwrap();
+    let mut xmins = Vec::new();
+    for file in &files {
+        mvcc.get_xmin(file.fileid, 0, file.rownum, &mut xmins)
+            .unwrap();
+    }
+    return (files, xmins);
+}
+
+// The transactions of the tests running concurrently hold the horizon back for a while, so
+// VACUUM is retried until the first n rows are frozen.
+fn vacuum_until_frozen(sess: &mut SessionState, stmt: &str, table: &str, n: usize) -> Vec<u64> {
+    for _ in 0..100 {
+        exec(sess, stmt).unwrap();
+        let (_, xmins) = files_xmins(sess, table);
+        if xmins[..n].iter().all(|&xmin| xmin == FROZEN_XID.get()) {
+            return xmins;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+    panic!("the rows are never frozen. stmt={}", stmt);
+}
+
+#[test]
+fn vacuum_freeze() {
+    let mut sess = super::new_session();
+    exec(&mut sess, "create table vacuum_t(i int, j int)").unwrap();
+    exec(&mut sess, "create table vacuum_t2(i int)").unwrap();
+    exec(&mut sess, "insert into vacuum_t values (1, 10), (2, 20)").unwrap();
+    exec(&mut sess, "insert into vacuum_t values (3, 30)").unwrap();
+    let (_, xmins) = files_xmins(&mut sess, "vacuum_t");
+    assert_eq!(xmins.len(), 3);
+    assert!(xmins.iter().all(|&xmin| xmin > FROZEN_XID.get()));
+
+    // The transaction still running holds the horizon, the rows inserted after it are kept.
+    let mut sess2 = super::new_session();
+    exec(&mut sess2, "begin").unwrap();
+    exec(&mut sess2, "insert into vacuum_t2 values (1)").unwrap();
+    exec(&mut sess, "insert into vacuum_t values (4, 40)").unwrap();
+    let (_, before) = files_xmins(&mut sess, "vacuum_t");
+    let fpi = sess.wal.unwrap().stats().fpi;
+    let xmins = vacuum_until_frozen(&mut sess, "vacuum vacuum_t", "vacuum_t", 3);
+    assert_eq!(xmins[3], before[3]);
+    // The frozen page is logged as a whole.
+    assert!(sess.wal.unwrap().stats().fpi > fpi);
+    let rows = exec(&mut sess, "select sum(i), sum(j) from vacuum_t").unwrap();
+    assert_eq!(rows, text_rows(&[&["10", "100"]]));
+
+    exec(&mut sess2, "commit").unwrap();
+    vacuum_until_frozen(&mut sess, "vacuum vacuum_t", "vacuum_t", 4);
+    let rows = exec(&mut sess, "select sum(i), sum(j) from vacuum_t").unwrap();
+    assert_eq!(rows, text_rows(&[&["10", "100"]]));
+
+    exec(&mut sess, "begin").unwrap();
+    let err = exec(&mut sess, "vacuum vacuum_t").unwrap_err();
+    assert_eq!(errcode(&err), ERRCODE_ACTIVE_SQL_TRANSACTION);
+    exec(&mut sess, "abort").unwrap();
+}
+
+#[test]
+fn vacuum_full() {
+    let mut sess = super::new_session();
+    exec(&mut sess, "create table vacuum_full_t(i int, j int)").unwrap();
+    exec(
+        &mut sess,
+        "insert into vacuum_full_t values (1, 10), (2, 20)",
+    )
+    .unwrap();
+    exec(&mut sess, "begin").unwrap();
+    exec(
+        &mut sess,
+        "insert into vacuum_full_t values (3, 30), (4, 40)",
+    )
+    .unwrap();
+    exec(&mut sess, "abort").unwrap();
+    exec(&mut sess, "insert into vacuum_full_t values (5, 50)").unwrap();
+    let (oldfiles, xmins) = files_xmins(&mut sess, "vacuum_full_t");
+    assert_eq!(xmins.len(), 5);
+
+    // The rows of the aborted transaction are removed once it is before the horizon.
+    let stmt = "vacuum full vacuum_full_t";
+    let xmins = vacuum_until_frozen(&mut sess, stmt, "vacuum_full_t", 3);
+    assert_eq!(xmins.len(), 3);
+    let (newfiles, _) = files_xmins(&mut sess, "vacuum_full_t");
+    assert_eq!(newfiles.len(), 1);
+    assert!(oldfiles.iter().all(|f| f.fileid != newfiles[0].fileid));
+    let rows = exec(&mut sess, "select i, j from vacuum_full_t").unwrap();
+    assert_eq!(rows, text_rows(&[&["1", "10"], &["2", "20"], &["5", "50"]]));
+
+    // The new file is written as usual.
+    exec(&mut sess, "insert into vacuum_full_t values (6, 60)").unwrap();
+    let rows = exec(&mut sess, "select sum(i), sum(j) from vacuum_full_t").unwrap();
+    assert_eq!(rows, text_rows(&[&["14", "140"]]));
+    exec(&mut sess, "vacuum full vacuum_full_t").unwrap();
+    let rows = exec(&mut sess, "select sum(i), sum(j) from vacuum_full_t").unwrap();
+    assert_eq!(rows, text_rows(&[&["14", "140"]]));
+}
+
+fn values(n: usize) -> String {
+    let rows: Vec<_> = (0..n).map(|i| format!("({})", i)).collect();
+    return rows.join(",");
+}
+
+#[test]
+fn autovacuum() {
+    let mut sess = super::new_session();
+    exec(&mut sess, "create table autovacuum_t(i int)").unwrap();
+    let tableoid = sess.relname_get_oid("autovacuum_t").unwrap().unwrap();
+    let table = sv::TableId {
+        db: sess.reqdb,
+        table: tableoid,
+    };
+    exec(&mut sess, "insert into autovacuum_t values (1), (2)").unwrap();
+    exec(&mut sess, "begin").unwrap();
+    exec(&mut sess, "savepoint s").unwrap();
+    let insert = format!("insert into autovacuum_t values {}", values(600));
+    exec(&mut sess, &insert).unwrap();
+    exec(&mut sess, "rollback to s").unwrap();
+    exec(&mut sess, "insert into autovacuum_t values (3)").unwrap();
+    exec(&mut sess, "commit").unwrap();
+    exec(&mut sess, "begin").unwrap();
+    exec(&mut sess, &insert).unwrap();
+    exec(&mut sess, "abort").unwrap();
+    let stat = GLOBAL_STATE.tabstats.get(table).unwrap();
+    assert_eq!((stat.live_rows, stat.dead_rows), (3, 1200));
+    let (_, xmins) = files_xmins(&mut sess, "autovacuum_t");
+    assert_eq!(xmins.len(), 1203);
+
+    // The tables of the other tests have fewer dead rows than the threshold.
+    let reclaimer = Box::leak(Box::new(VacuumReclaimer::new(GLOBAL_STATE.clone())));
+    let stop: &'static AtomicBool = Box::leak(Box::new(AtomicBool::new(false)));
+    let opts = AutoVacOpts {
+        naptime: Duration::from_millis(10),
+        vacuum_threshold: 1000,
+        vacuum_scale_factor: 0.2,
+        work_mem: 64 << 20,
+    };
+    let worker = autovacuum::start_autovacuum(
+        GLOBAL_STATE.tabstats,
+        GLOBAL_STATE.xact.unwrap(),
+        reclaimer,
+        opts,
+        stop,
+    );
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while GLOBAL_STATE.tabstats.get(table).unwrap().dead_rows > 0 {
+        assert!(Instant::now() < deadline, "autovacuum is not triggered");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    stop.store(true, Relaxed);
+    worker.join().unwrap();
+    let (files, xmins) = files_xmins(&mut sess, "autovacuum_t");
+    assert_eq!((files.len(), xmins.len()), (1, 3));
+    let rows = exec(&mut sess, "select sum(i) from autovacuum_t").unwrap();
+    assert_eq!(rows, text_rows(&[&["6"]]));
+}
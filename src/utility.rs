@@ -12,16 +12,25 @@ limitations under the License.
 */
 use crate::access::xact::SessionExt as xact_sess_ext;
 use crate::commands::copy::copy_stmt;
+use crate::commands::discard::{self, close_portal_stmt, discard_stmt, SessionReset};
+use crate::commands::dropcmds::remove_objects;
+use crate::commands::explain::explain_stmt;
+use crate::commands::insert::insert_stmt;
 use crate::commands::lockcmds::lock_stmt;
-use crate::commands::tablecmds::create_table;
+use crate::commands::notify::{listen_stmt, notify_stmt, unlisten_stmt};
+use crate::commands::schemacmds::create_schema;
+use crate::commands::tablecmds::{alter_table, create_table};
 use crate::commands::typecmds::define_type;
+use crate::commands::vacuum::vacuum_stmt;
+use crate::commands::verify::verify_stmt;
+use crate::executor::DestReceiver;
 use crate::parser::{sem, syn};
 use crate::{guc, kbanyhow, kbbail, SessionState};
 use std::sync::Arc;
 
 pub struct StrResp {
     pub name: String,
-    pub val: String,
+    pub vals: Vec<String>,
 }
 
 pub struct Response {
@@ -39,7 +48,17 @@ impl Response {
 
     pub fn new_ex(tag: &str, name: String, val: String) -> Self {
         Self {
-            resp: Some(StrResp { name, val }),
+            resp: Some(StrResp {
+                name,
+                vals: vec![val],
+            }),
+            tag: tag.to_string(),
+        }
+    }
+
+    pub fn new_rows(tag: &str, name: String, vals: Vec<String>) -> Self {
+        Self {
+            resp: Some(StrResp { name, vals }),
             tag: tag.to_string(),
         }
     }
@@ -49,6 +68,10 @@ impl Response {
     }
 }
 
+fn null_value() -> anyhow::Error {
+    kbanyhow!(ERRCODE_INVALID_PARAMETER_VALUE, "requires a non-null value")
+}
+
 fn to_i32(val: &syn::Value) -> anyhow::Result<i32> {
     match val {
         syn::Value::Num(v) => match v {
@@ -59,6 +82,7 @@ fn to_i32(val: &syn::Value) -> anyhow::Result<i32> {
             }
         },
         syn::Value::Str(v) => Ok(v.parse::<i32>()?),
+        syn::Value::Null => Err(null_value()),
     }
 }
 
@@ -72,6 +96,7 @@ fn to_bool(val: &syn::Value) -> anyhow::Result<bool> {
             )),
         },
         syn::Value::Str(v) => Ok(v.eq_ignore_ascii_case("on") || v.eq_ignore_ascii_case("true")),
+        syn::Value::Null => Err(null_value()),
     }
 }
 
@@ -85,6 +110,7 @@ fn to_f64(val: &syn::Value) -> anyhow::Result<f64> {
             }
         },
         syn::Value::Str(v) => Ok(v.parse::<f64>()?),
+        syn::Value::Null => Err(null_value()),
     }
 }
 
@@ -101,6 +127,7 @@ fn to_str(val: &syn::Value) -> anyhow::Result<String> {
             }
         },
         syn::Value::Str(v) => v.to_string(),
+        syn::Value::Null => return Err(null_value()),
     })
 }
 
@@ -135,6 +162,30 @@ fn set_guc(stmt: &syn::VariableSetStmt, state: &mut SessionState) -> anyhow::Res
     return Ok(Response::new("SET"));
 }
 
+fn reset_guc(stmt: &syn::VariableResetStmt, state: &mut SessionState) -> anyhow::Result<Response> {
+    let gucname = match stmt {
+        syn::VariableResetStmt::Var(v) => v,
+        syn::VariableResetStmt::All => {
+            discard::reset_session(state, &[SessionReset::ResetAll])?;
+            return Ok(Response::new(SessionReset::ResetAll.tag()));
+        }
+        syn::VariableResetStmt::SessionAuthorization => {
+            let reset = SessionReset::SessionAuthorizationDefault;
+            discard::reset_session(state, &[reset])?;
+            return Ok(Response::new(reset.tag()));
+        }
+    };
+    let gucidx = match guc::get_gucidx(gucname) {
+        Some(v) => v,
+        None => {
+            kbbail!(ERRCODE_UNDEFINED_OBJECT, "unknown guc");
+        }
+    };
+    let reset = state.reset_gucstate.clone();
+    guc::reset_guc(gucidx, &reset, Arc::make_mut(&mut state.gucstate));
+    return Ok(Response::new(SessionReset::ResetAll.tag()));
+}
+
 fn get_guc(stmt: &syn::VariableShowStmt, state: &SessionState) -> anyhow::Result<Response> {
     let gucname = &stmt.name;
     let gucidx = match guc::get_gucidx(gucname) {
@@ -166,13 +217,46 @@ fn tran(stmt: &syn::TranStmt, state: &mut SessionState) -> anyhow::Result<Respon
             state.user_abort_tran_block()?;
             ABORT_TAG
         }
+        syn::TranStmt::Savepoint(name) => {
+            state.require_transblock("SAVEPOINT")?;
+            state.define_savepoint(name)?;
+            "SAVEPOINT"
+        }
+        syn::TranStmt::Release(name) => {
+            state.require_transblock("RELEASE SAVEPOINT")?;
+            state.release_savepoint(name)?;
+            "RELEASE"
+        }
+        syn::TranStmt::RollbackTo(name) => {
+            state.require_transblock("ROLLBACK TO SAVEPOINT")?;
+            state.rollback_to_savepoint(name)?;
+            ABORT_TAG
+        }
     };
     return Ok(Response::new(tag));
 }
 
+// UtilityTupleDescriptor, the name of the only column returned by stmt, None if stmt returns
+// no rows.
+pub fn utility_result_name(stmt: &sem::UtilityStmt) -> Option<String> {
+    match stmt {
+        &sem::UtilityStmt::VariableShow(v) => Some(v.name.to_string()),
+        // Only the shell type returns the DefineType result, see define_type().
+        &sem::UtilityStmt::DefineType(v) if v.definition.is_empty() => {
+            Some("CREATE TYPE".to_string())
+        }
+        sem::UtilityStmt::Explain(_) => Some("QUERY PLAN".to_string()),
+        &sem::UtilityStmt::Verify(_) => Some("corruption".to_string()),
+        _ => None,
+    }
+}
+
+// ProcessUtility, only INSERT RETURNING sends its rows to dest, the other statements return
+// their rows in Response.
 pub fn process_utility(
     stmt: &sem::UtilityStmt,
     state: &mut SessionState,
+    dest: &mut dyn DestReceiver,
 ) -> anyhow::Result<Response> {
     match stmt {
         &sem::UtilityStmt::VariableSet(v) => set_guc(v, state),
@@ -180,7 +264,20 @@ pub fn process_utility(
         &sem::UtilityStmt::DefineType(v) => define_type(v, state),
         &sem::UtilityStmt::Tran(v) => tran(v, state),
         &sem::UtilityStmt::CreateTable(v) => create_table(v, state),
+        &sem::UtilityStmt::AlterTable(v) => alter_table(v, state),
+        &sem::UtilityStmt::CreateSchema(v) => create_schema(v, state),
+        &sem::UtilityStmt::Drop(v) => remove_objects(v, state),
         &sem::UtilityStmt::Lock(v) => lock_stmt(state, v),
         &sem::UtilityStmt::Copy(v) => copy_stmt(state, v),
+        sem::UtilityStmt::Insert(v, returning) => insert_stmt(state, v, returning, dest),
+        &sem::UtilityStmt::Notify(v) => notify_stmt(state, v),
+        &sem::UtilityStmt::Listen(v) => listen_stmt(state, v),
+        &sem::UtilityStmt::Unlisten(v) => unlisten_stmt(state, v),
+        &sem::UtilityStmt::VariableReset(v) => reset_guc(v, state),
+        &sem::UtilityStmt::Discard(v) => discard_stmt(state, v),
+        &sem::UtilityStmt::ClosePortal(v) => close_portal_stmt(v),
+        sem::UtilityStmt::Explain(v) => explain_stmt(state, v),
+        &sem::UtilityStmt::Verify(v) => verify_stmt(state, v),
+        &sem::UtilityStmt::Vacuum(v) => vacuum_stmt(state, v),
     }
 }
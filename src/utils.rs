@@ -15,15 +15,19 @@ use crate::access::clog::WorkerExt as ClogWorkerExt;
 use crate::access::csmvcc::TabMVCC;
 use crate::access::fd::{SessionExt as FDSessionExt, WorkerExt as FDWorkerExt};
 use crate::access::lmgr;
-use crate::access::{ckpt, sv};
+use crate::access::{autovacuum, ckpt, sv};
 use crate::access::{clog, wal, xact};
 use crate::catalog::namespace::SessionStateExt as NameSpaceSessionStateExt;
+use crate::commands::notify;
+use crate::guc::SyncMethod;
+use crate::replication::slot::ReplSlots;
 use crate::Oid;
-use crate::{guc, kbensure, protocol, GlobalState, SockWriter};
+use crate::{guc, kbanyhow, kbensure, protocol, GlobalState, SockWriter};
 use anyhow::anyhow;
 use chrono::offset::Local;
 use chrono::DateTime;
 use crossbeam_channel::{unbounded, Receiver};
+use nix::fcntl::OFlag;
 use nix::libc::off_t;
 use nix::sys::uio::IoVec;
 use nix::unistd::SysconfVar::IOV_MAX;
@@ -32,19 +36,33 @@ use std::fs::File;
 use std::io::Write;
 use std::num::NonZeroU16;
 use std::os::unix::io::RawFd;
+use std::panic::{catch_unwind, AssertUnwindSafe};
 use std::path::Path;
 use std::ptr::NonNull;
-use std::sync::{atomic::AtomicBool, atomic::AtomicU32, atomic::Ordering::Relaxed, Arc};
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering::Relaxed};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tempfile::NamedTempFile;
 use threadpool::ThreadPool;
 
 pub mod adt;
+pub mod auth;
+pub mod crashpoint;
+pub mod encoding;
 pub mod err;
 pub mod fmgr;
+pub mod hba;
+pub mod health;
+pub mod latch;
+pub mod logger;
 pub mod marc;
+pub mod metrics;
+pub mod pglz;
 pub mod sb;
 pub mod ser;
+pub mod shutdown;
+pub mod ssl;
+pub mod statedump;
 
 pub struct WorkerState {
     pub wal: Option<&'static wal::GlobalStateExt>,
@@ -54,7 +72,24 @@ pub struct WorkerState {
     pub sessid: u32,
     pub reqdb: Oid,
     pub termreq: Arc<AtomicBool>,
+    // The deadline of the statement_timeout, copied from the session.
+    pub stmt_deadline: Option<Instant>,
     pub gucstate: Arc<guc::GucState>,
+    pub tabsv: &'static sv::TabSupVer,
+    pub tabmvcc: &'static TabMVCC,
+    // The memory that can be used by the hash tables of the worker, unit: bytes.
+    pub work_mem: usize,
+}
+
+// unit: bytes.
+fn work_mem(gucstate: &guc::GucState) -> usize {
+    guc::get_int(gucstate, guc::WorkMem).max(64) as usize * 1024
+}
+
+// The maintenance operations, such as VERIFY TABLE and the freeze/compaction of vacuum, run
+// rarely and one at a time, so they can use more memory than the queries, unit: bytes.
+pub fn maintenance_work_mem(gucstate: &guc::GucState) -> usize {
+    guc::get_int(gucstate, guc::MaintenanceWorkMem).max(1024) as usize * 1024
 }
 
 pub struct WorkerExit {
@@ -68,16 +103,21 @@ impl WorkerState {
             sessid: session.sessid,
             reqdb: session.reqdb,
             termreq: session.termreq.clone(),
+            stmt_deadline: session.stmt_deadline,
             gucstate: session.gucstate.clone(),
             clog: session.clog,
             xact: xact::WorkerStateExt::new(session),
             wal: session.wal,
+            tabsv: session.tabsv,
+            tabmvcc: session.tabmvcc,
+            work_mem: work_mem(&session.gucstate),
         }
     }
 
     pub fn init_thread_locals(&self) {
         self.resize_clog_l1cache();
         self.resize_fdcache();
+        logger::set_level(self.gucstate.loglvl);
     }
 
     pub fn exit(&self) -> WorkerExit {
@@ -85,6 +125,27 @@ impl WorkerState {
             xact: self.xact.exit(),
         }
     }
+
+    pub fn check_termreq(&self) -> anyhow::Result<()> {
+        return check_termreq(&self.termreq, self.stmt_deadline);
+    }
+}
+
+// ProcessInterrupts, the termreq is set by the CancelRequest, it is cleared when the next
+// command is read, see do_postgres_main(). The statement_timeout is checked along with it, so
+// the parallel workers stop at the deadline too.
+fn check_termreq(termreq: &AtomicBool, stmt_deadline: Option<Instant>) -> anyhow::Result<()> {
+    kbensure!(
+        !termreq.load(Relaxed),
+        ERRCODE_QUERY_CANCELED,
+        "canceling statement due to user request"
+    );
+    kbensure!(
+        stmt_deadline.map_or(true, |v| Instant::now() < v),
+        ERRCODE_QUERY_CANCELED,
+        "canceling statement due to statement timeout"
+    );
+    return Ok(());
 }
 
 pub struct SessionState {
@@ -96,10 +157,14 @@ pub struct SessionState {
     pub db: String,
     pub termreq: Arc<AtomicBool>,
     pub gucstate: Arc<guc::GucState>,
+    // The gucs at the start of the session, restored by RESET.
+    pub reset_gucstate: Arc<guc::GucState>,
     pub metaconn: sqlite::Connection,
     pub xact: xact::SessionStateExt,
     pub wal: Option<&'static wal::GlobalStateExt>,
     pub stmt_startts: KBSystemTime,
+    // The deadline of the statement_timeout, None if it is disabled.
+    pub stmt_deadline: Option<Instant>,
     pub dead: bool,
     pub nsstate: NameSpaceSessionStateExt,
     pub oid_creator: Option<&'static AtomicU32>, // nextoid
@@ -108,6 +173,61 @@ pub struct SessionState {
     pub pending_fileops: &'static ckpt::PendingFileOps,
     pub tabsv: &'static sv::TabSupVer,
     pub tabmvcc: &'static TabMVCC,
+    pub replslots: &'static ReplSlots,
+    pub notify: notify::SessionStateExt,
+    pub tabstat: autovacuum::SessionStateExt,
+    // The notices not sent to the client yet, see SessionState::notice().
+    pub notices: Vec<Notice>,
+}
+
+pub struct Notice {
+    pub level: guc::NoticeLevel,
+    pub code: &'static str,
+    pub msg: String,
+}
+
+// The result of the worker started by SessionState::exec().
+pub struct WorkerRet<Ret> {
+    pub idx: usize,
+    pub exit: WorkerExit,
+    pub ret: anyhow::Result<Ret>,
+}
+
+// Wait for all workers started by SessionState::exec(). The results are in the order of the
+// worker index, and the error of the failed worker with the smallest index is returned, so the
+// statement reports the same error no matter which worker fails first.
+pub fn wait_workers<Ret>(rec: &Receiver<WorkerRet<Ret>>) -> anyhow::Result<Vec<(WorkerExit, Ret)>> {
+    let mut rets: Vec<_> = rec.iter().collect();
+    rets.sort_unstable_by_key(|v| v.idx);
+    let mut oks = Vec::with_capacity(rets.len());
+    for workerret in rets {
+        oks.push((workerret.exit, workerret.ret?));
+    }
+    return Ok(oks);
+}
+
+// The panic of the worker is reported as the error of the worker.
+fn run_worker<Args, Ret>(
+    body: impl FnOnce(Args, &mut WorkerState) -> anyhow::Result<Ret>,
+    args: Args,
+    worker: &mut WorkerState,
+) -> anyhow::Result<Ret> {
+    let payload = match catch_unwind(AssertUnwindSafe(|| body(args, worker))) {
+        Ok(ret) => return ret,
+        Err(payload) => payload,
+    };
+    let msg = if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.as_str()
+    } else {
+        "unknown panic"
+    };
+    return Err(kbanyhow!(
+        ERRCODE_INTERNAL_ERROR,
+        "parallel worker panicked: {}",
+        msg
+    ));
 }
 
 pub struct WorkerExitGuard<'a, T> {
@@ -130,6 +250,7 @@ impl SessionState {
     pub fn init_thread_locals(&self) {
         self.resize_clog_l1cache();
         self.resize_fdcache();
+        logger::set_session(self.sessid, &self.db);
     }
 
     pub fn new(
@@ -138,6 +259,7 @@ impl SessionState {
         db: String,
         termreq: Arc<AtomicBool>,
         metaconn: sqlite::Connection,
+        latch: Arc<latch::Latch>,
         gstate: GlobalState,
     ) -> Self {
         let now = KBSystemTime::now();
@@ -148,12 +270,14 @@ impl SessionState {
             db,
             termreq,
             fmgr_builtins: gstate.fmgr_builtins,
+            reset_gucstate: gstate.gucstate.clone(),
             gucstate: gstate.gucstate,
             metaconn,
             dead: false,
             nsstate: NameSpaceSessionStateExt::default(),
             clog: clog::WorkerStateExt::new(gstate.clog),
             stmt_startts: now.into(),
+            stmt_deadline: None,
             xact: xact::SessionStateExt::new(gstate.xact, now),
             wal: gstate.wal,
             lmgrg: gstate.lmgr,
@@ -162,6 +286,35 @@ impl SessionState {
             pending_fileops: gstate.pending_fileops,
             tabsv: gstate.tabsv,
             tabmvcc: gstate.tabmvcc,
+            replslots: gstate.replslots,
+            notify: notify::SessionStateExt::new(gstate.notify, sessid, latch),
+            tabstat: autovacuum::SessionStateExt::new(gstate.tabstats),
+            notices: Vec::new(),
+        }
+    }
+
+    // ResetAllOptions, the gucs are restored as a whole, then the state derived from them but
+    // kept outside of GucState.
+    pub fn reset_all_gucs(&mut self) {
+        self.gucstate = self.reset_gucstate.clone();
+        if self.gucstate.base_search_path_valid {
+            Arc::make_mut(&mut self.gucstate).base_search_path_valid = false;
+        }
+        logger::set_level(self.gucstate.loglvl);
+        logger::set_appname(guc::get_str(&self.gucstate, guc::ApplicationName));
+    }
+
+    // ereport(level) for the level below ERROR, the notice is sent to the client before the
+    // end of the current command if the level reaches client_min_messages.
+    pub fn notice(&mut self, level: guc::NoticeLevel, code: &'static str, msg: String) {
+        let loglvl = match level {
+            guc::NoticeLevel::Debug => log::Level::Debug,
+            guc::NoticeLevel::Log | guc::NoticeLevel::Notice => log::Level::Info,
+            guc::NoticeLevel::Warning | guc::NoticeLevel::Error => log::Level::Warn,
+        };
+        log::log!(loglvl, "{}. code={}", msg, code);
+        if level >= self.gucstate.client_minlvl {
+            self.notices.push(Notice { level, code, msg });
         }
     }
 
@@ -171,17 +324,31 @@ impl SessionState {
         } else {
             protocol::SEVERITY_ERR
         };
-        crate::on_error(lvl, err, stream);
+        crate::on_error(lvl, err, stream, self.gucstate.client_encoding);
     }
 
+    // The statement_timeout starts along with the statement, not the transaction.
     pub fn update_stmt_startts(&mut self) {
         self.stmt_startts = KBSystemTime::now();
+        let timeout = guc::get_int(&self.gucstate, guc::StatementTimeout);
+        self.stmt_deadline = if timeout > 0 {
+            Some(Instant::now() + Duration::from_millis(timeout as u64))
+        } else {
+            None
+        };
     }
 
     pub fn new_worker(&self) -> WorkerState {
         WorkerState::new(self)
     }
 
+    // The parallel workers share the work_mem of the query.
+    fn new_parallel_worker(&self, parallel: usize, work_mem: usize) -> WorkerState {
+        let mut worker = self.new_worker();
+        worker.work_mem = work_mem / parallel;
+        return worker;
+    }
+
     // Only invokded when commit.
     pub fn exit_worker(&mut self, e: WorkerExit) {
         self.xact.exit_worker(e.xact);
@@ -192,6 +359,8 @@ impl SessionState {
         self.thdpool.as_ref().unwrap()
     }
 
+    // The pool only grows, exec() never queues more jobs than parallel, so the extra idle
+    // threads are harmless and the threads are not recreated for every exec().
     fn resize_pool(&mut self, parallel: usize) {
         match self.thdpool.as_mut() {
             None => {
@@ -199,7 +368,9 @@ impl SessionState {
             }
             Some(p) => {
                 debug_assert_eq!(p.queued_count(), 0);
-                p.set_num_threads(parallel);
+                if p.max_count() < parallel {
+                    p.set_num_threads(parallel);
+                }
             }
         }
     }
@@ -208,40 +379,65 @@ impl SessionState {
         &mut self,
         parallel: usize,
         args_gene: impl Fn(usize) -> Args,
-        body: impl FnOnce(Args, &mut WorkerState) -> Ret + Send + 'static + Clone,
-    ) -> Receiver<(WorkerExit, Ret)> {
+        body: impl FnOnce(Args, &mut WorkerState) -> anyhow::Result<Ret> + Send + 'static + Clone,
+    ) -> Receiver<WorkerRet<Ret>> {
+        let work_mem = work_mem(&self.gucstate);
+        self.exec_with_mem(parallel, work_mem, args_gene, body)
+    }
+
+    // exec() for the maintenance operations, the workers share maintenance_work_mem instead,
+    // the queries running concurrently in other sessions still use their own work_mem.
+    pub fn exec_maintenance<Args: Send + 'static, Ret: Send + 'static>(
+        &mut self,
+        parallel: usize,
+        args_gene: impl Fn(usize) -> Args,
+        body: impl FnOnce(Args, &mut WorkerState) -> anyhow::Result<Ret> + Send + 'static + Clone,
+    ) -> Receiver<WorkerRet<Ret>> {
+        let work_mem = maintenance_work_mem(&self.gucstate);
+        self.exec_with_mem(parallel, work_mem, args_gene, body)
+    }
+
+    fn exec_with_mem<Args: Send + 'static, Ret: Send + 'static>(
+        &mut self,
+        parallel: usize,
+        work_mem: usize,
+        args_gene: impl Fn(usize) -> Args,
+        body: impl FnOnce(Args, &mut WorkerState) -> anyhow::Result<Ret> + Send + 'static + Clone,
+    ) -> Receiver<WorkerRet<Ret>> {
         debug_assert!(parallel > 0);
         self.resize_pool(parallel);
-        let (send, receiver) = unbounded::<(WorkerExit, Ret)>();
+        let (send, receiver) = unbounded::<WorkerRet<Ret>>();
         let lastno = parallel - 1;
         for idx in 0..lastno {
             let args = args_gene(idx);
-            let mut worker = self.new_worker();
+            let mut worker = self.new_parallel_worker(parallel, work_mem);
             let body2 = body.clone();
             let send2 = send.clone();
             self.pool().execute(move || {
                 worker.init_thread_locals();
-                let ret = body2(args, &mut worker);
-                send2.send((worker.exit(), ret)).unwrap();
+                let ret = run_worker(body2, args, &mut worker);
+                let exit = worker.exit();
+                // The receiver may be dropped if the leader has failed.
+                let _ = send2.send(WorkerRet { idx, exit, ret });
             });
         }
         let args = args_gene(lastno);
-        let mut worker = self.new_worker();
+        let mut worker = self.new_parallel_worker(parallel, work_mem);
         self.pool().execute(move || {
             worker.init_thread_locals();
-            let ret = body(args, &mut worker);
-            send.send((worker.exit(), ret)).unwrap();
+            let ret = run_worker(body, args, &mut worker);
+            let exit = worker.exit();
+            let _ = send.send(WorkerRet {
+                idx: lastno,
+                exit,
+                ret,
+            });
         });
         return receiver;
     }
 
     pub fn check_termreq(&self) -> anyhow::Result<()> {
-        kbensure!(
-            !self.termreq.load(Relaxed),
-            ERRCODE_ADMIN_SHUTDOWN,
-            "terminating connection due to administrator command"
-        );
-        return Ok(());
+        return check_termreq(&self.termreq, self.stmt_deadline);
     }
 }
 
@@ -271,6 +467,10 @@ impl<'a, 'b> Drop for ExecSQLOnDrop<'a, 'b> {
 pub type AttrNumber = NonZeroU16;
 pub type Xid = std::num::NonZeroU64;
 
+// FrozenTransactionId, the xmin of the row frozen by VACUUM, which is visible to all snapshots.
+// It is never assigned since the nextxid of initdb is 2.
+pub const FROZEN_XID: Xid = unsafe { Xid::new_unchecked(1) };
+
 pub fn inc_xid(v: Xid) -> Xid {
     Xid::new(v.get() + 1).unwrap()
 }
@@ -283,15 +483,51 @@ pub fn sync_dir<P: AsRef<Path>>(path: P) -> std::io::Result<()> {
     File::open(path)?.sync_data()
 }
 
+// The number of fdatasync() and fsync() called by sync_file().
+pub static NFDATASYNC: AtomicU64 = AtomicU64::new(0);
+pub static NFSYNC: AtomicU64 = AtomicU64::new(0);
+
+// issue_xlog_fsync, the file must be opened with sync_open_flags(method).
+pub fn sync_file(file: &File, method: SyncMethod) -> std::io::Result<()> {
+    match method {
+        SyncMethod::Fdatasync => {
+            NFDATASYNC.fetch_add(1, Relaxed);
+            file.sync_data()
+        }
+        SyncMethod::Fsync => {
+            NFSYNC.fetch_add(1, Relaxed);
+            file.sync_all()
+        }
+        SyncMethod::OpenDsync => Ok(()),
+    }
+}
+
+// get_sync_bit
+pub fn sync_open_flags(method: SyncMethod) -> i32 {
+    match method {
+        SyncMethod::Fdatasync | SyncMethod::Fsync => 0,
+        SyncMethod::OpenDsync => OFlag::O_DSYNC.bits(),
+    }
+}
+
 // Path::new(file).parent().unwrap() must not be empty.
 pub fn persist<P: AsRef<Path>>(file: P, d: &[u8]) -> anyhow::Result<()> {
+    persist_sync(file, d, SyncMethod::Fdatasync)
+}
+
+// The temporary file is not opened with O_DSYNC, so it is synced by fdatasync for open_dsync.
+pub fn persist_sync<P: AsRef<Path>>(file: P, d: &[u8], method: SyncMethod) -> anyhow::Result<()> {
+    let method = match method {
+        SyncMethod::OpenDsync => SyncMethod::Fdatasync,
+        method => method,
+    };
     let path = file.as_ref();
     {
         let mut tempf = NamedTempFile::new_in(".")?;
         tempf.write_all(d)?;
         tempf.flush()?;
         let targetfile = tempf.persist(path)?;
-        targetfile.sync_data()?;
+        sync_file(&targetfile, method)?;
     }
     let dir = path
         .parent()
@@ -8,10 +8,15 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
-use crate::datums::Datums;
-use crate::kbensure;
+use crate::datums::{Datums, Value};
+use crate::guc::ByteaOutput;
 use crate::utils::fmgr::FmgrInfo;
 use crate::utils::WorkerState;
+use crate::{kbbail, kbensure, Oid};
+use crate::{BOOLOID, FLOAT4OID, FLOAT8OID, INT2OID, INT4OID, INT8OID, VARCHAROID};
+use std::cmp::Ordering;
+use std::convert::TryInto;
+use std::fmt::Write;
 use std::mem::{align_of, size_of};
 use std::rc::Rc;
 
@@ -93,7 +98,7 @@ macro_rules! typbinop {
                     ERRCODE_NUMERIC_VALUE_OUT_OF_RANGE,
                     "integer out of range"
                 );
-                retdatum.set_single_fixedlen(retval);
+                retdatum.set_fixedlen_at(idx, retval);
             }
         }
     };
@@ -145,6 +150,95 @@ pub fn int4out(
     return Ok(());
 }
 
+fn typout<T: Copy + ToString>(ret: &mut Rc<Datums>, arg: &Datums) {
+    let retdatum = Rc::make_mut(ret);
+    if arg.is_single() {
+        if arg.is_single_null() {
+            retdatum.set_single_null();
+        } else {
+            retdatum.set_single_varchar(arg.get_single_fixedlen::<T>().to_string().as_bytes());
+        }
+        return;
+    }
+    retdatum.resize_varlen(arg.len());
+    retdatum.set_null_to(arg);
+    for idx in 0..arg.len() as isize {
+        if !arg.is_null_at(idx) {
+            retdatum.set_varchar_at(idx, arg.get_fixedlen_at::<T>(idx).to_string().as_bytes());
+        } else {
+            retdatum.set_empty_at(idx);
+        }
+    }
+    return;
+}
+
+pub fn int8out(
+    _flinfo: &FmgrInfo,
+    ret: &mut Rc<Datums>,
+    args: &[Rc<Datums>],
+    _state: &WorkerState,
+) -> anyhow::Result<()> {
+    typout::<i64>(ret, &args[0]);
+    return Ok(());
+}
+
+// float8out_internal, Rust also outputs the shortest-precise representation.
+pub fn float8out(
+    _flinfo: &FmgrInfo,
+    ret: &mut Rc<Datums>,
+    args: &[Rc<Datums>],
+    _state: &WorkerState,
+) -> anyhow::Result<()> {
+    typout::<f64>(ret, &args[0]);
+    return Ok(());
+}
+
+fn typcast<S: Copy, T: Copy>(ret: &mut Rc<Datums>, arg: &Datums, cast: impl Fn(S) -> T) {
+    let retdatum = Rc::make_mut(ret);
+    if arg.is_single() {
+        if arg.is_single_null() {
+            retdatum.set_single_null();
+        } else {
+            retdatum.set_single_fixedlen(cast(arg.get_single_fixedlen::<S>()));
+        }
+        return;
+    }
+    retdatum.resize_fixedlen(arg.len(), size_of::<T>(), align_of::<T>());
+    retdatum.set_null_to(arg);
+    for idx in 0..arg.len() as isize {
+        if !arg.is_null_at(idx) {
+            retdatum.set_fixedlen_at(idx, cast(arg.get_fixedlen_at::<S>(idx)));
+        }
+    }
+    return;
+}
+
+// The implicit casts between the numeric types in kb_cast, they never fail.
+macro_rules! typcastfn {
+    ($name: ident, $from: ty, $to: ty) => {
+        pub fn $name(
+            _flinfo: &FmgrInfo,
+            ret: &mut Rc<Datums>,
+            args: &[Rc<Datums>],
+            _state: &WorkerState,
+        ) -> anyhow::Result<()> {
+            typcast(ret, &args[0], |v: $from| v as $to);
+            return Ok(());
+        }
+    };
+}
+
+typcastfn!(i2toi4, i16, i32);
+typcastfn!(int28, i16, i64);
+typcastfn!(i2tof, i16, f32);
+typcastfn!(i2tod, i16, f64);
+typcastfn!(int48, i32, i64);
+typcastfn!(i4tof, i32, f32);
+typcastfn!(i4tod, i32, f64);
+typcastfn!(i8tof, i64, f32);
+typcastfn!(i8tod, i64, f64);
+typcastfn!(ftod, f32, f64);
+
 pub fn int4in(
     _flinfo: &FmgrInfo,
     ret: &mut Rc<Datums>,
@@ -210,3 +304,416 @@ pub fn int4mul(
     i32binop!(ret, left, right, overflowing_mul);
     return Ok(());
 }
+
+// Compare left and right, the result is a bool column. A comparison with null yields null.
+pub fn typcmp<T: Copy>(ret: &mut Datums, left: &Datums, right: &Datums, cmp: fn(&T, &T) -> bool) {
+    if left.is_single() && right.is_single() {
+        if left.is_single_null() || right.is_single_null() {
+            ret.set_single_null();
+        } else {
+            let v = cmp(&left.get_single_fixedlen(), &right.get_single_fixedlen());
+            ret.set_single_fixedlen(v);
+        }
+        return;
+    }
+    let rownum = if left.is_single() {
+        right.len()
+    } else {
+        left.len()
+    };
+    ret.set_notnull_all();
+    ret.resize_fixedlen(rownum, size_of::<bool>(), align_of::<bool>());
+    if (left.is_single() && left.is_single_null()) || (right.is_single() && right.is_single_null())
+    {
+        ret.set_null_all();
+        return;
+    }
+    for idx in 0..rownum as isize {
+        let l = if left.is_single() {
+            left.get_single_fixedlen()
+        } else if left.is_null_at(idx) {
+            ret.set_null_at(idx);
+            continue;
+        } else {
+            left.get_fixedlen_at(idx)
+        };
+        let r = if right.is_single() {
+            right.get_single_fixedlen()
+        } else if right.is_null_at(idx) {
+            ret.set_null_at(idx);
+            continue;
+        } else {
+            right.get_fixedlen_at(idx)
+        };
+        ret.set_fixedlen_at(idx, cmp(&l, &r));
+    }
+    return;
+}
+
+macro_rules! i32cmp {
+    ($fname: ident, $cmp: ident) => {
+        pub fn $fname(
+            _flinfo: &FmgrInfo,
+            ret: &mut Rc<Datums>,
+            args: &[Rc<Datums>],
+            _state: &WorkerState,
+        ) -> anyhow::Result<()> {
+            typcmp::<i32>(Rc::make_mut(ret), &args[0], &args[1], i32::$cmp);
+            return Ok(());
+        }
+    };
+}
+
+i32cmp!(int4eq, eq);
+i32cmp!(int4ne, ne);
+i32cmp!(int4lt, lt);
+i32cmp!(int4gt, gt);
+i32cmp!(int4le, le);
+i32cmp!(int4ge, ge);
+
+// The comparison support function of btree, the datums must not be null.
+pub type SortCmp = fn(&Datums, isize, &Datums, isize) -> Ordering;
+
+fn fixedlen_at<T: Copy>(d: &Datums, idx: isize) -> T {
+    if d.is_single() {
+        d.get_single_fixedlen()
+    } else {
+        d.get_fixedlen_at(idx)
+    }
+}
+
+// btint4cmp, btint8cmp, btboolcmp
+fn btcmp<T: Copy + Ord>(a: &Datums, aidx: isize, b: &Datums, bidx: isize) -> Ordering {
+    fixedlen_at::<T>(a, aidx).cmp(&fixedlen_at(b, bidx))
+}
+
+// btfloat8cmp, NaN is equal to NaN and greater than any non-NaN value.
+fn btfloat8cmp(a: &Datums, aidx: isize, b: &Datums, bidx: isize) -> Ordering {
+    let a: f64 = fixedlen_at(a, aidx);
+    let b: f64 = fixedlen_at(b, bidx);
+    match (a.is_nan(), b.is_nan()) {
+        (true, true) => Ordering::Equal,
+        (true, false) => Ordering::Greater,
+        (false, true) => Ordering::Less,
+        (false, false) => a.partial_cmp(&b).unwrap(),
+    }
+}
+
+// bttextcmp, the "C" collation is used.
+fn btvarcharcmp(a: &Datums, aidx: isize, b: &Datums, bidx: isize) -> Ordering {
+    let a = a.try_get_varchar_at(aidx).unwrap();
+    let b = b.try_get_varchar_at(bidx).unwrap();
+    a.as_bytes().cmp(b.as_bytes())
+}
+
+// get_sort_group_operators, None means the type has no ordering operator.
+pub fn get_sort_cmp(typid: Oid) -> Option<SortCmp> {
+    match typid {
+        BOOLOID => Some(btcmp::<bool>),
+        INT4OID => Some(btcmp::<i32>),
+        INT8OID => Some(btcmp::<i64>),
+        FLOAT8OID => Some(btfloat8cmp),
+        VARCHAROID => Some(btvarcharcmp),
+        _ => None,
+    }
+}
+
+// parse_bool_with_len
+pub fn parse_bool(v: &str) -> Option<bool> {
+    match v.trim().to_ascii_lowercase().as_str() {
+        "t" | "tr" | "tru" | "true" | "y" | "ye" | "yes" | "on" | "1" => Some(true),
+        "f" | "fa" | "fal" | "fals" | "false" | "n" | "no" | "of" | "off" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+fn parse_bool_or_err(v: &str) -> anyhow::Result<bool> {
+    match parse_bool(v) {
+        Some(b) => Ok(b),
+        None => kbbail!(
+            ERRCODE_INVALID_TEXT_REPRESENTATION,
+            "invalid input syntax for type boolean: \"{}\"",
+            v
+        ),
+    }
+}
+
+pub fn boolin(
+    _flinfo: &FmgrInfo,
+    ret: &mut Rc<Datums>,
+    args: &[Rc<Datums>],
+    _state: &WorkerState,
+) -> anyhow::Result<()> {
+    let retdatum = Rc::make_mut(ret);
+    let arg = &args[0];
+    if arg.is_single() {
+        if arg.is_single_null() {
+            retdatum.set_single_null();
+        } else {
+            retdatum.set_single_fixedlen(parse_bool_or_err(arg.get_single_varchar())?);
+        }
+        return Ok(());
+    }
+    retdatum.resize_fixedlen(arg.len(), size_of::<bool>(), align_of::<bool>());
+    retdatum.set_null_to(arg);
+    for idx in 0..arg.len() as isize {
+        if !arg.is_null_at(idx) {
+            retdatum.set_fixedlen_at(idx, parse_bool_or_err(arg.get_varchar_at(idx))?);
+        }
+    }
+    return Ok(());
+}
+
+fn bool_text(v: bool) -> &'static [u8] {
+    if v {
+        b"t"
+    } else {
+        b"f"
+    }
+}
+
+pub fn boolout(
+    _flinfo: &FmgrInfo,
+    ret: &mut Rc<Datums>,
+    args: &[Rc<Datums>],
+    _state: &WorkerState,
+) -> anyhow::Result<()> {
+    let retdatum = Rc::make_mut(ret);
+    let arg = &args[0];
+    if arg.is_single() {
+        if arg.is_single_null() {
+            retdatum.set_single_null();
+        } else {
+            retdatum.set_single_varchar(bool_text(arg.get_single_fixedlen()));
+        }
+        return Ok(());
+    }
+    retdatum.resize_varlen(arg.len());
+    retdatum.set_null_to(arg);
+    for idx in 0..arg.len() as isize {
+        if !arg.is_null_at(idx) {
+            retdatum.set_varchar_at(idx, bool_text(arg.get_fixedlen_at(idx)));
+        } else {
+            retdatum.set_empty_at(idx);
+        }
+    }
+    return Ok(());
+}
+
+fn hex_value(c: u8) -> anyhow::Result<u8> {
+    match c {
+        b'0'..=b'9' => Ok(c - b'0'),
+        b'a'..=b'f' => Ok(c - b'a' + 10),
+        b'A'..=b'F' => Ok(c - b'A' + 10),
+        _ => kbbail!(
+            ERRCODE_INVALID_PARAMETER_VALUE,
+            "invalid hexadecimal digit: \"{}\"",
+            c as char
+        ),
+    }
+}
+
+// hex_decode, the whitespaces between the bytes are skipped.
+fn hex_decode(v: &[u8], out: &mut Vec<u8>) -> anyhow::Result<()> {
+    let mut idx = 0;
+    while idx < v.len() {
+        if let b' ' | b'\t' | b'\n' | b'\r' = v[idx] {
+            idx += 1;
+            continue;
+        }
+        kbensure!(
+            idx + 1 < v.len(),
+            ERRCODE_INVALID_PARAMETER_VALUE,
+            "invalid hexadecimal data: odd number of digits"
+        );
+        out.push(hex_value(v[idx])? << 4 | hex_value(v[idx + 1])?);
+        idx += 2;
+    }
+    return Ok(());
+}
+
+fn is_octal(c: u8) -> bool {
+    (b'0'..=b'7').contains(&c)
+}
+
+// byteain of the escape format, \\ is a backslash and \ooo is the byte in octal.
+fn escape_decode(v: &[u8], out: &mut Vec<u8>) -> anyhow::Result<()> {
+    let mut idx = 0;
+    while idx < v.len() {
+        if v[idx] != b'\\' {
+            out.push(v[idx]);
+            idx += 1;
+            continue;
+        }
+        if v.get(idx + 1) == Some(&b'\\') {
+            out.push(b'\\');
+            idx += 2;
+            continue;
+        }
+        match v.get(idx + 1..idx + 4) {
+            Some(&[a, b, c]) if (b'0'..=b'3').contains(&a) && is_octal(b) && is_octal(c) => {
+                out.push((a - b'0') << 6 | (b - b'0') << 3 | (c - b'0'));
+                idx += 4;
+            }
+            _ => kbbail!(
+                ERRCODE_INVALID_TEXT_REPRESENTATION,
+                "invalid input syntax for type bytea"
+            ),
+        }
+    }
+    return Ok(());
+}
+
+fn bytea_decode(v: &str, out: &mut Vec<u8>) -> anyhow::Result<()> {
+    out.clear();
+    match v.as_bytes().strip_prefix(b"\\x") {
+        Some(hex) => hex_decode(hex, out),
+        None => escape_decode(v.as_bytes(), out),
+    }
+}
+
+pub fn byteain(
+    _flinfo: &FmgrInfo,
+    ret: &mut Rc<Datums>,
+    args: &[Rc<Datums>],
+    _state: &WorkerState,
+) -> anyhow::Result<()> {
+    let retdatum = Rc::make_mut(ret);
+    let arg = &args[0];
+    let mut buf = Vec::new();
+    if arg.is_single() {
+        if arg.is_single_null() {
+            retdatum.set_single_null();
+        } else {
+            bytea_decode(arg.get_single_varchar(), &mut buf)?;
+            retdatum.set_single_bytea(&buf);
+        }
+        return Ok(());
+    }
+    retdatum.resize_varlen(arg.len());
+    retdatum.set_null_to(arg);
+    for idx in 0..arg.len() as isize {
+        if !arg.is_null_at(idx) {
+            bytea_decode(arg.get_varchar_at(idx), &mut buf)?;
+            retdatum.set_varchar_at(idx, &buf);
+        } else {
+            retdatum.set_empty_at(idx);
+        }
+    }
+    return Ok(());
+}
+
+fn bytea_text(v: &[u8], format: ByteaOutput, out: &mut String) {
+    out.clear();
+    match format {
+        ByteaOutput::Hex => {
+            out.push_str("\\x");
+            for b in v {
+                write!(out, "{:02x}", b).unwrap();
+            }
+        }
+        ByteaOutput::Escape => {
+            for &b in v {
+                if b == b'\\' {
+                    out.push_str("\\\\");
+                } else if (0x20..=0x7e).contains(&b) {
+                    out.push(b as char);
+                } else {
+                    write!(out, "\\{:03o}", b).unwrap();
+                }
+            }
+        }
+    }
+}
+
+// varcharout, the text of varchar is the value itself.
+pub fn varcharout(
+    _flinfo: &FmgrInfo,
+    ret: &mut Rc<Datums>,
+    args: &[Rc<Datums>],
+    _state: &WorkerState,
+) -> anyhow::Result<()> {
+    *ret = args[0].clone();
+    return Ok(());
+}
+
+// byteaout, the format is bytea_output of the session running the query.
+pub fn byteaout(
+    _flinfo: &FmgrInfo,
+    ret: &mut Rc<Datums>,
+    args: &[Rc<Datums>],
+    state: &WorkerState,
+) -> anyhow::Result<()> {
+    let retdatum = Rc::make_mut(ret);
+    let arg = &args[0];
+    let format = state.gucstate.bytea_output;
+    let mut text = String::new();
+    if arg.is_single() {
+        match arg.try_get_bytea_at(0) {
+            None => retdatum.set_single_null(),
+            Some(v) => {
+                bytea_text(v, format, &mut text);
+                retdatum.set_single_varchar(text.as_bytes());
+            }
+        }
+        return Ok(());
+    }
+    retdatum.resize_varlen(arg.len());
+    retdatum.set_null_to(arg);
+    for idx in 0..arg.len() as isize {
+        match arg.try_get_bytea_at(idx) {
+            None => retdatum.set_empty_at(idx),
+            Some(v) => {
+                bytea_text(v, format, &mut text);
+                retdatum.set_varchar_at(idx, text.as_bytes());
+            }
+        }
+    }
+    return Ok(());
+}
+
+// boolsend, int2send, int4send, int8send, float4send, float8send, byteasend, varcharsend. The
+// integers and floats are in network byte order, the bool is a byte of 0 or 1.
+pub fn binary_send(val: Value<'_>, out: &mut Vec<u8>) {
+    match val {
+        Value::Bool(v) => out.push(v as u8),
+        Value::Int2(v) => out.extend_from_slice(&v.to_be_bytes()),
+        Value::Int4(v) => out.extend_from_slice(&v.to_be_bytes()),
+        Value::Int8(v) => out.extend_from_slice(&v.to_be_bytes()),
+        Value::Float4(v) => out.extend_from_slice(&v.to_be_bytes()),
+        Value::Float8(v) => out.extend_from_slice(&v.to_be_bytes()),
+        Value::Bytea(v) => out.extend_from_slice(v),
+        Value::Varchar(v) => out.extend_from_slice(v.as_bytes()),
+    }
+}
+
+fn binary_bytes<const N: usize>(data: &[u8]) -> anyhow::Result<[u8; N]> {
+    match data.try_into() {
+        Ok(v) => Ok(v),
+        Err(_) => kbbail!(
+            ERRCODE_BAD_COPY_FILE_FORMAT,
+            "incorrect binary data format. len={} expected={}",
+            data.len(),
+            N
+        ),
+    }
+}
+
+// boolrecv, int2recv, int4recv, int8recv, float4recv, float8recv. out must be resized to the
+// layout of typid by the caller.
+pub fn binary_recv(typid: Oid, data: &[u8], out: &mut Datums, idx: isize) -> anyhow::Result<()> {
+    match typid {
+        BOOLOID => out.set_fixedlen_at(idx, binary_bytes::<1>(data)?[0] != 0),
+        INT2OID => out.set_fixedlen_at(idx, i16::from_be_bytes(binary_bytes(data)?)),
+        INT4OID => out.set_fixedlen_at(idx, i32::from_be_bytes(binary_bytes(data)?)),
+        INT8OID => out.set_fixedlen_at(idx, i64::from_be_bytes(binary_bytes(data)?)),
+        FLOAT4OID => out.set_fixedlen_at(idx, f32::from_be_bytes(binary_bytes(data)?)),
+        FLOAT8OID => out.set_fixedlen_at(idx, f64::from_be_bytes(binary_bytes(data)?)),
+        _ => kbbail!(
+            ERRCODE_FEATURE_NOT_SUPPORTED,
+            "no binary input function available for type {}",
+            typid
+        ),
+    }
+    return Ok(());
+}
@@ -0,0 +1,663 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::protocol::{self, MsgType};
+use crate::utils::hba;
+use crate::{catalog, guc, kbanyhow, kbbail, kbensure, SockReader, SockWriter};
+use sha2::{Digest, Sha256};
+use std::io::Write;
+
+// pg_md5_encrypt, the rolpassword of kb_authid is md5_encrypt(password, rolname).
+pub fn md5_encrypt(passwd: &[u8], salt: &[u8]) -> String {
+    let mut ctx = md5::Context::new();
+    ctx.consume(passwd);
+    ctx.consume(salt);
+    return format!("md5{:x}", ctx.compute());
+}
+
+// The response to AuthenticationMD5Password, just as libpq.
+pub fn md5_client_response(user: &str, passwd: &str, salt: &[u8]) -> String {
+    let shadow = md5_encrypt(passwd.as_bytes(), user.as_bytes());
+    return md5_encrypt(&shadow.as_bytes()[3..], salt);
+}
+
+// md5_crypt_verify, the client proves it knows the rolpassword without sending it.
+fn md5_crypt_verify(shadow_pass: &str, client_pass: &str, salt: &[u8]) -> bool {
+    match shadow_pass.strip_prefix("md5") {
+        None => false,
+        Some(v) => md5_encrypt(v.as_bytes(), salt) == client_pass,
+    }
+}
+
+pub const SCRAM_SHA_256_NAME: &str = "SCRAM-SHA-256";
+const SCRAM_KEY_LEN: usize = 32;
+const SCRAM_DEFAULT_ITERATIONS: u32 = 4096;
+
+type ScramKey = [u8; SCRAM_KEY_LEN];
+
+fn sha256(data: &[u8]) -> ScramKey {
+    let mut ret = [0; SCRAM_KEY_LEN];
+    ret.copy_from_slice(&Sha256::digest(data));
+    return ret;
+}
+
+// HMAC-SHA-256 of RFC 2104, the message is the concatenation of parts.
+fn hmac_sha256(key: &[u8], parts: &[&[u8]]) -> ScramKey {
+    const BLOCK_LEN: usize = 64;
+    let mut block = [0u8; BLOCK_LEN];
+    if key.len() > BLOCK_LEN {
+        block[..SCRAM_KEY_LEN].copy_from_slice(&sha256(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let mut inner = Sha256::new();
+    inner.input(block.iter().map(|v| v ^ 0x36).collect::<Vec<u8>>());
+    for part in parts {
+        inner.input(part);
+    }
+    let mut outer = Sha256::new();
+    outer.input(block.iter().map(|v| v ^ 0x5c).collect::<Vec<u8>>());
+    outer.input(inner.result());
+    let mut ret = [0; SCRAM_KEY_LEN];
+    ret.copy_from_slice(&outer.result());
+    return ret;
+}
+
+fn xor_key(l: &ScramKey, r: &ScramKey) -> ScramKey {
+    let mut ret = *l;
+    for (v, r) in ret.iter_mut().zip(r) {
+        *v ^= r;
+    }
+    return ret;
+}
+
+// scram_SaltedPassword, Hi() of RFC 5802. The password is not normalized by SASLprep.
+fn scram_salted_password(password: &str, salt: &[u8], iterations: u32) -> ScramKey {
+    let password = password.as_bytes();
+    let mut u = hmac_sha256(password, &[salt, &1u32.to_be_bytes()]);
+    let mut ret = u;
+    for _ in 1..iterations {
+        u = hmac_sha256(password, &[&u]);
+        ret = xor_key(&ret, &u);
+    }
+    return ret;
+}
+
+// scram_ClientKey
+fn scram_client_key(salted_password: &ScramKey) -> ScramKey {
+    return hmac_sha256(salted_password, &[b"Client Key"]);
+}
+
+// scram_ServerKey
+fn scram_server_key(salted_password: &ScramKey) -> ScramKey {
+    return hmac_sha256(salted_password, &[b"Server Key"]);
+}
+
+const BASE64_CHARS: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// pg_b64_encode
+fn b64_encode(data: &[u8]) -> String {
+    let mut ret = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let mut buf = [0u8; 3];
+        buf[..chunk.len()].copy_from_slice(chunk);
+        let v = (buf[0] as u32) << 16 | (buf[1] as u32) << 8 | buf[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                ret.push(BASE64_CHARS[(v >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                ret.push('=');
+            }
+        }
+    }
+    return ret;
+}
+
+// pg_b64_decode, None if the data is not a valid base64 with padding.
+fn b64_decode(data: &str) -> Option<Vec<u8>> {
+    let data = data.as_bytes();
+    if data.len() % 4 != 0 {
+        return None;
+    }
+    let mut ret = Vec::with_capacity(data.len() / 4 * 3);
+    for (idx, chunk) in data.chunks(4).enumerate() {
+        let last = idx == data.len() / 4 - 1;
+        let pad = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if pad > 2 || (pad > 0 && !last) {
+            return None;
+        }
+        let mut v = 0u32;
+        for &c in &chunk[..4 - pad] {
+            let d = BASE64_CHARS.iter().position(|&b| b == c)?;
+            v = v << 6 | d as u32;
+        }
+        v <<= 6 * pad;
+        let bytes = [(v >> 16) as u8, (v >> 8) as u8, v as u8];
+        ret.extend_from_slice(&bytes[..3 - pad]);
+    }
+    return Some(ret);
+}
+
+// The SCRAM secret stored in kb_authid.rolpassword.
+struct ScramSecret {
+    iterations: u32,
+    salt: Vec<u8>,
+    stored_key: ScramKey,
+    server_key: ScramKey,
+}
+
+// scram_build_secret, SCRAM-SHA-256$<iterations>:<salt>$<StoredKey>:<ServerKey>.
+pub fn scram_build_secret(password: &str, salt: &[u8], iterations: u32) -> String {
+    let salted_password = scram_salted_password(password, salt, iterations);
+    let stored_key = sha256(&scram_client_key(&salted_password));
+    let server_key = scram_server_key(&salted_password);
+    return format!(
+        "{}${}:{}${}:{}",
+        SCRAM_SHA_256_NAME,
+        iterations,
+        b64_encode(salt),
+        b64_encode(&stored_key),
+        b64_encode(&server_key)
+    );
+}
+
+// pg_be_scram_build_secret, the salt is random.
+pub fn scram_encrypt(password: &str) -> String {
+    let salt: [u8; 16] = rand::random();
+    return scram_build_secret(password, &salt, SCRAM_DEFAULT_ITERATIONS);
+}
+
+fn b64_key(data: &str) -> Option<ScramKey> {
+    let data = b64_decode(data)?;
+    if data.len() != SCRAM_KEY_LEN {
+        return None;
+    }
+    let mut ret = [0; SCRAM_KEY_LEN];
+    ret.copy_from_slice(&data);
+    return Some(ret);
+}
+
+// parse_scram_secret, None if the secret is not a SCRAM secret, such as the md5 password.
+fn parse_scram_secret(secret: &str) -> Option<ScramSecret> {
+    let secret = secret.strip_prefix(SCRAM_SHA_256_NAME)?.strip_prefix('$')?;
+    let (salt_part, key_part) = secret.split_once('$')?;
+    let (iterations, salt) = salt_part.split_once(':')?;
+    let (stored_key, server_key) = key_part.split_once(':')?;
+    let iterations = iterations.parse().ok().filter(|&v| v > 0)?;
+    return Some(ScramSecret {
+        iterations,
+        salt: b64_decode(salt)?,
+        stored_key: b64_key(stored_key)?,
+        server_key: b64_key(server_key)?,
+    });
+}
+
+// scram_mock_salt, the exchange with the role having no SCRAM secret goes on as usual and
+// fails at last, so that the client can not probe the roles.
+fn scram_mock_secret() -> ScramSecret {
+    return ScramSecret {
+        iterations: SCRAM_DEFAULT_ITERATIONS,
+        salt: rand::random::<[u8; 16]>().to_vec(),
+        stored_key: rand::random(),
+        server_key: rand::random(),
+    };
+}
+
+fn malformed_scram(detail: &str) -> anyhow::Error {
+    kbanyhow!(
+        ERRCODE_PROTOCOL_VIOLATION,
+        "malformed SCRAM message. detail={}",
+        detail
+    )
+}
+
+// read_attr_value, reads "<attr>=<value>" and the following comma if any.
+fn read_attr_value<'a>(input: &mut &'a str, attr: char) -> anyhow::Result<&'a str> {
+    let rest = input
+        .strip_prefix(attr)
+        .and_then(|v| v.strip_prefix('='))
+        .ok_or_else(|| malformed_scram(&format!("expected attribute \"{}\"", attr)))?;
+    let (value, rest) = rest.split_once(',').unwrap_or((rest, ""));
+    *input = rest;
+    return Ok(value);
+}
+
+fn scram_nonce() -> String {
+    return b64_encode(&rand::random::<[u8; 18]>());
+}
+
+// scram_state, the server side of SCRAM-SHA-256. Only the exchange without channel binding is
+// supported, SCRAM-SHA-256-PLUS is not advertised even over SSL since tls-server-end-point is
+// not implemented.
+struct ScramState<'a> {
+    secret: &'a ScramSecret,
+    gs2_header: String,
+    client_first_bare: String,
+    server_first: String,
+    nonce: String,
+}
+
+impl ScramState<'_> {
+    fn new(secret: &ScramSecret) -> ScramState<'_> {
+        return ScramState {
+            secret,
+            gs2_header: String::new(),
+            client_first_bare: String::new(),
+            server_first: String::new(),
+            nonce: String::new(),
+        };
+    }
+
+    // read_client_first_message and build_server_first_message.
+    fn server_first(&mut self, client_first: &str, server_nonce: &str) -> anyhow::Result<String> {
+        let mut input = client_first;
+        match input.chars().next() {
+            // The client does not support channel binding.
+            Some('n') => input = &input[1..],
+            // The client supports channel binding but thinks that the server does not, this is
+            // right since SCRAM-SHA-256-PLUS is never advertised.
+            Some('y') => input = &input[1..],
+            Some('p') => kbbail!(
+                ERRCODE_PROTOCOL_VIOLATION,
+                "malformed SCRAM message. detail=The client selected SCRAM-SHA-256 without \
+                 channel binding, but the SCRAM message includes channel binding data."
+            ),
+            _ => return Err(malformed_scram("unexpected channel-binding flag")),
+        }
+        input = input
+            .strip_prefix(',')
+            .ok_or_else(|| malformed_scram("comma expected after the channel-binding flag"))?;
+        kbensure!(
+            !input.starts_with("a="),
+            ERRCODE_FEATURE_NOT_SUPPORTED,
+            "client uses authorization identity, but it is not supported"
+        );
+        input = input
+            .strip_prefix(',')
+            .ok_or_else(|| malformed_scram("comma expected after the authorization identity"))?;
+        self.gs2_header = client_first[..client_first.len() - input.len()].to_string();
+        self.client_first_bare = input.to_string();
+        kbensure!(
+            !input.starts_with("m="),
+            ERRCODE_FEATURE_NOT_SUPPORTED,
+            "client requires an unsupported SCRAM extension"
+        );
+        // The username is ignored, the user of the startup packet is used.
+        read_attr_value(&mut input, 'n')?;
+        let client_nonce = read_attr_value(&mut input, 'r')?;
+        if client_nonce.is_empty() || !client_nonce.bytes().all(|c| c.is_ascii_graphic()) {
+            return Err(malformed_scram("invalid nonce"));
+        }
+        self.nonce = format!("{}{}", client_nonce, server_nonce);
+        self.server_first = format!(
+            "r={},s={},i={}",
+            self.nonce,
+            b64_encode(&self.secret.salt),
+            self.secret.iterations
+        );
+        return Ok(self.server_first.clone());
+    }
+
+    // read_client_final_message and verify_client_proof, returns the server-final-message if
+    // the client proof is right.
+    fn verify_client_final(&self, client_final: &str) -> anyhow::Result<Option<String>> {
+        let mut input = client_final;
+        let cbind = read_attr_value(&mut input, 'c')?;
+        if b64_decode(cbind).as_deref() != Some(self.gs2_header.as_bytes()) {
+            kbbail!(
+                ERRCODE_PROTOCOL_VIOLATION,
+                "SCRAM channel binding check failed"
+            );
+        }
+        if read_attr_value(&mut input, 'r')? != self.nonce {
+            return Err(malformed_scram("nonce does not match"));
+        }
+        let proofidx = client_final
+            .rfind(",p=")
+            .ok_or_else(|| malformed_scram("could not find proof"))?;
+        let without_proof = &client_final[..proofidx];
+        let proof = b64_key(&client_final[proofidx + 3..])
+            .ok_or_else(|| malformed_scram("malformed proof in client-final-message"))?;
+        let auth_message = format!(
+            "{},{},{}",
+            self.client_first_bare, self.server_first, without_proof
+        );
+        let signature = hmac_sha256(&self.secret.stored_key, &[auth_message.as_bytes()]);
+        let client_key = xor_key(&proof, &signature);
+        if sha256(&client_key) != self.secret.stored_key {
+            return Ok(None);
+        }
+        let server_signature = hmac_sha256(&self.secret.server_key, &[auth_message.as_bytes()]);
+        return Ok(Some(format!("v={}", b64_encode(&server_signature))));
+    }
+}
+
+// ScramClient is the client side of SCRAM-SHA-256 without channel binding, just as libpq.
+pub struct ScramClient {
+    password: String,
+    nonce: String,
+    client_first_bare: String,
+    server_signature: ScramKey,
+}
+
+impl ScramClient {
+    pub fn new(password: &str) -> ScramClient {
+        // The username is sent empty, the server uses the user of the startup packet.
+        return ScramClient::with_nonce("", password, &scram_nonce());
+    }
+
+    fn with_nonce(user: &str, password: &str, nonce: &str) -> ScramClient {
+        return ScramClient {
+            password: password.to_string(),
+            nonce: nonce.to_string(),
+            client_first_bare: format!("n={},r={}", user, nonce),
+            server_signature: [0; SCRAM_KEY_LEN],
+        };
+    }
+
+    pub fn client_first(&self) -> String {
+        return format!("n,,{}", self.client_first_bare);
+    }
+
+    pub fn client_final(&mut self, server_first: &str) -> anyhow::Result<String> {
+        let mut input = server_first;
+        let nonce = read_attr_value(&mut input, 'r')?;
+        if nonce.len() <= self.nonce.len() || !nonce.starts_with(&self.nonce) {
+            return Err(malformed_scram("invalid SCRAM response (nonce mismatch)"));
+        }
+        let salt = b64_decode(read_attr_value(&mut input, 's')?)
+            .ok_or_else(|| malformed_scram("malformed SCRAM message (invalid salt)"))?;
+        let iterations = read_attr_value(&mut input, 'i')?
+            .parse()
+            .ok()
+            .filter(|&v: &u32| v > 0)
+            .ok_or_else(|| malformed_scram("malformed SCRAM message (invalid iteration count)"))?;
+        // "biws" is the base64 of the gs2 header "n,,".
+        let without_proof = format!("c=biws,r={}", nonce);
+        let auth_message = format!(
+            "{},{},{}",
+            self.client_first_bare, server_first, without_proof
+        );
+        let salted_password = scram_salted_password(&self.password, &salt, iterations);
+        let client_key = scram_client_key(&salted_password);
+        let signature = hmac_sha256(&sha256(&client_key), &[auth_message.as_bytes()]);
+        let proof = xor_key(&client_key, &signature);
+        let server_key = scram_server_key(&salted_password);
+        self.server_signature = hmac_sha256(&server_key, &[auth_message.as_bytes()]);
+        return Ok(format!("{},p={}", without_proof, b64_encode(&proof)));
+    }
+
+    pub fn verify_server_final(&self, server_final: &str) -> anyhow::Result<()> {
+        let mut input = server_final;
+        let signature = read_attr_value(&mut input, 'v')?;
+        kbensure!(
+            b64_key(signature) == Some(self.server_signature),
+            ERRCODE_INVALID_PASSWORD,
+            "incorrect server signature"
+        );
+        return Ok(());
+    }
+}
+
+// Reads the 'p' message of the authentication, what is the name of the expected response.
+fn read_auth_response(sockreader: &mut SockReader, what: &str) -> anyhow::Result<Vec<u8>> {
+    let (msgtype, msgdata) = protocol::read_message(sockreader)?;
+    if msgtype == MsgType::EOF as i8 {
+        // The client such as psql closes the connection to prompt for the password.
+        kbbail!(
+            ERRCODE_CONNECTION_FAILURE,
+            "connection closed by client during authentication"
+        );
+    }
+    kbensure!(
+        msgtype == MsgType::PasswordMessage as i8,
+        ERRCODE_PROTOCOL_VIOLATION,
+        "expected {} response, got message type {}",
+        what,
+        msgtype
+    );
+    return Ok(msgdata);
+}
+
+fn sasl_str(data: &[u8]) -> anyhow::Result<&str> {
+    return std::str::from_utf8(data).map_err(|_| malformed_scram("invalid UTF-8"));
+}
+
+// CheckMD5Auth, returns whether the password is right.
+fn md5_exchange(
+    shadow: Option<&str>,
+    sockreader: &mut SockReader,
+    sockwriter: &mut SockWriter,
+) -> anyhow::Result<bool> {
+    let salt = rand::random();
+    protocol::write_message(sockwriter, &protocol::AuthenticationMD5Password { salt });
+    sockwriter.flush()?;
+    let msgdata = read_auth_response(sockreader, "password")?;
+    let passwd = protocol::PasswordMessage::deserialize(&msgdata)?;
+    kbensure!(
+        !passwd.password.is_empty(),
+        ERRCODE_INVALID_PASSWORD,
+        "empty password returned by client"
+    );
+    return Ok(shadow.map_or(false, |shadow| {
+        md5_crypt_verify(shadow, passwd.password, &salt)
+    }));
+}
+
+// CheckSASLAuth, returns whether the client proof is right. AuthenticationSASLFinal is sent
+// only if it is right.
+fn scram_exchange(
+    secret: &ScramSecret,
+    sockreader: &mut SockReader,
+    sockwriter: &mut SockWriter,
+) -> anyhow::Result<bool> {
+    let mechanisms = [SCRAM_SHA_256_NAME];
+    protocol::write_message(
+        sockwriter,
+        &protocol::AuthenticationSASL {
+            mechanisms: &mechanisms,
+        },
+    );
+    sockwriter.flush()?;
+    let msgdata = read_auth_response(sockreader, "SASL")?;
+    let initial = protocol::SASLInitialResponse::deserialize(&msgdata)?;
+    kbensure!(
+        initial.mechanism == SCRAM_SHA_256_NAME,
+        ERRCODE_PROTOCOL_VIOLATION,
+        "client selected an invalid SASL authentication mechanism"
+    );
+    let client_first = initial
+        .data
+        .ok_or_else(|| malformed_scram("the client-first-message is missing"))?;
+    let mut state = ScramState::new(secret);
+    let server_first = state.server_first(sasl_str(client_first)?, &scram_nonce())?;
+    let msg = protocol::AuthenticationSASLContinue {
+        data: server_first.as_bytes(),
+    };
+    protocol::write_message(sockwriter, &msg);
+    sockwriter.flush()?;
+
+    let msgdata = read_auth_response(sockreader, "SASL")?;
+    let resp = protocol::SASLResponse::deserialize(&msgdata)?;
+    let server_final = match state.verify_client_final(sasl_str(resp.data)?)? {
+        None => return Ok(false),
+        Some(v) => v,
+    };
+    let msg = protocol::AuthenticationSASLFinal {
+        data: server_final.as_bytes(),
+    };
+    protocol::write_message(sockwriter, &msg);
+    return Ok(true);
+}
+
+// ClientAuthentication, the method is chosen by kb_hba.conf or hba_rules, or is auth_method for
+// all connections if there are neither. The SCRAM exchange is used if the role has a SCRAM
+// secret, even if the method is md5, just as the md5 method of pg_hba.conf.
+pub fn client_authentication(
+    gucstate: &guc::GucState,
+    database: &str,
+    user: &str,
+    sockreader: &mut SockReader,
+    sockwriter: &mut SockWriter,
+) -> anyhow::Result<()> {
+    let stream = sockwriter.get_ref();
+    let conn = hba::HbaConn {
+        tls: stream.is_tls(),
+        addr: stream.peer_addr().ok().map(|v| v.ip()),
+        database,
+        user,
+    };
+    let method = hba::hba_method(guc::get_str(gucstate, guc::HbaRules), &conn)?
+        .unwrap_or_else(|| guc::get_str(gucstate, guc::AuthMethod));
+    if method == "trust" {
+        return Ok(());
+    }
+    // The nonexistent role fails in the same way, so that the client can not probe the roles.
+    let shadow = catalog::get_role_password(user)?;
+    let verified = match shadow.as_deref().and_then(parse_scram_secret) {
+        Some(secret) => scram_exchange(&secret, sockreader, sockwriter)?,
+        None if method == "md5" => md5_exchange(shadow.as_deref(), sockreader, sockwriter)?,
+        // The md5 password can not be used by SCRAM.
+        None => {
+            scram_exchange(&scram_mock_secret(), sockreader, sockwriter)?;
+            false
+        }
+    };
+    kbensure!(
+        verified,
+        ERRCODE_INVALID_PASSWORD,
+        "password authentication failed for user \"{}\"",
+        user
+    );
+    return Ok(());
+}
+
+#[cfg(test)]
+mod auth_test {
+    use super::{
+        b64_decode, b64_encode, md5_client_response, md5_crypt_verify, md5_encrypt,
+        parse_scram_secret, scram_build_secret, ScramClient, ScramState,
+    };
+    use crate::protocol::{ERRCODE_FEATURE_NOT_SUPPORTED, ERRCODE_PROTOCOL_VIOLATION};
+    use crate::utils::err::errcode;
+
+    #[test]
+    fn md5() {
+        let shadow = md5_encrypt(b"secret", b"kuiba");
+        assert_eq!(shadow, "md570ec4afda39e87f422d91bdc6761ea87");
+        let salt = [1, 2, 3, 4];
+        let resp = md5_client_response("kuiba", "secret", &salt);
+        assert_eq!(resp, "md52ac69f295f629c7d7af90704d90e45b1");
+        assert!(md5_crypt_verify(&shadow, &resp, &salt));
+        assert!(!md5_crypt_verify(&shadow, &resp, &[4, 3, 2, 1]));
+        assert!(!md5_crypt_verify(
+            &shadow,
+            &md5_client_response("kuiba", "", &salt),
+            &salt
+        ));
+        // The plain text rolpassword is not accepted.
+        assert!(!md5_crypt_verify("secret", &resp, &salt));
+    }
+
+    #[test]
+    fn base64() {
+        for (data, b64) in &[
+            (&b""[..], ""),
+            (b"f", "Zg=="),
+            (b"fo", "Zm8="),
+            (b"foo", "Zm9v"),
+            (b"foob", "Zm9vYg=="),
+            (b"n,,", "biws"),
+        ] {
+            assert_eq!(b64_encode(data), *b64);
+            assert_eq!(b64_decode(b64).as_deref(), Some(*data));
+        }
+        for invalid in &["Zg", "Zg=a", "Z===", "Zg==Zm8=", "Zm9*"] {
+            assert_eq!(b64_decode(invalid), None, "invalid={}", invalid);
+        }
+    }
+
+    // The example of RFC 7677.
+    const CLIENT_FIRST: &str = "n,,n=user,r=rOprNGfwEbeRWgbNEkqO";
+    const SERVER_NONCE: &str = "%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0";
+    const SERVER_FIRST: &str =
+        "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096";
+    const CLIENT_FINAL: &str = "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                                p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=";
+    const SERVER_FINAL: &str = "v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=";
+
+    #[test]
+    fn scram_client() {
+        let mut client = ScramClient::with_nonce("user", "pencil", "rOprNGfwEbeRWgbNEkqO");
+        assert_eq!(client.client_first(), CLIENT_FIRST);
+        assert_eq!(client.client_final(SERVER_FIRST).unwrap(), CLIENT_FINAL);
+        client.verify_server_final(SERVER_FINAL).unwrap();
+        assert!(client.verify_server_final("v=AAAA").is_err());
+        // The server nonce must extend the client nonce.
+        let mut client = ScramClient::with_nonce("user", "pencil", "rOprNGfwEbeRWgbNEkqO");
+        assert!(client
+            .client_final("r=abc,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096")
+            .is_err());
+    }
+
+    #[test]
+    fn scram_server() {
+        let salt = b64_decode("W22ZaJ0SNY7soEsUEjb6gQ==").unwrap();
+        let secret = parse_scram_secret(&scram_build_secret("pencil", &salt, 4096)).unwrap();
+        let mut state = ScramState::new(&secret);
+        assert_eq!(
+            state.server_first(CLIENT_FIRST, SERVER_NONCE).unwrap(),
+            SERVER_FIRST
+        );
+        let server_final = state.verify_client_final(CLIENT_FINAL).unwrap();
+        assert_eq!(server_final.as_deref(), Some(SERVER_FINAL));
+
+        // The wrong password.
+        let secret = parse_scram_secret(&scram_build_secret("pencil2", &salt, 4096)).unwrap();
+        let mut state = ScramState::new(&secret);
+        state.server_first(CLIENT_FIRST, SERVER_NONCE).unwrap();
+        assert_eq!(state.verify_client_final(CLIENT_FINAL).unwrap(), None);
+
+        assert!(parse_scram_secret("md570ec4afda39e87f422d91bdc6761ea87").is_none());
+        assert!(parse_scram_secret("SCRAM-SHA-256$0:W22ZaJ0SNY7soEsUEjb6gQ==$a:b").is_none());
+    }
+
+    #[test]
+    fn scram_channel_binding() {
+        let salt = b64_decode("W22ZaJ0SNY7soEsUEjb6gQ==").unwrap();
+        let secret = parse_scram_secret(&scram_build_secret("pencil", &salt, 4096)).unwrap();
+        // The client supporting channel binding, the gs2 header "y,," is checked in the final
+        // message.
+        let mut state = ScramState::new(&secret);
+        state.server_first("y,,n=,r=abc", "def").unwrap();
+        let err = state
+            .verify_client_final("c=biws,r=abcdef,p=AAAA")
+            .unwrap_err();
+        assert_eq!(errcode(&err), ERRCODE_PROTOCOL_VIOLATION);
+        let err = state
+            .verify_client_final("c=eSws,r=abcdef,p=AAAA")
+            .unwrap_err();
+        assert_eq!(errcode(&err), ERRCODE_PROTOCOL_VIOLATION);
+        assert!(err.to_string().contains("proof"), "err={}", err);
+
+        // The client requiring channel binding is rejected since the server does not support it.
+        let mut state = ScramState::new(&secret);
+        let err = state
+            .server_first("p=tls-server-end-point,,n=,r=abc", "def")
+            .unwrap_err();
+        assert_eq!(errcode(&err), ERRCODE_PROTOCOL_VIOLATION);
+        let err = state.server_first("n,a=user,n=,r=abc", "def").unwrap_err();
+        assert_eq!(errcode(&err), ERRCODE_FEATURE_NOT_SUPPORTED);
+        for invalid in &["x,,n=,r=abc", "n,n=,r=abc", "n,,n=", "n,,n=,r="] {
+            let err = state.server_first(invalid, "def").unwrap_err();
+            assert_eq!(errcode(&err), ERRCODE_PROTOCOL_VIOLATION, "msg={}", invalid);
+        }
+    }
+}
@@ -0,0 +1,81 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+// The points where the recovery tests crash the server, see crash_point in gucdef.yaml.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CrashPoint {
+    // The wal has been written to the wal file but not fsynced yet.
+    WalWrite = 1,
+    // The commit record has been fsynced but the clog has not been updated yet, the checkpoint
+    // is still delayed by the transaction.
+    CommitFsync,
+    // The manifest has been written but the data files have not been fsynced yet.
+    ManifestWrite,
+    // The checkpoint has flushed the buffers but not inserted its record yet.
+    CkptFlush,
+    // The checkpoint record has been fsynced but the control file has not been updated yet.
+    CkptRecord,
+}
+
+const CRASH_POINTS: [(&str, CrashPoint); 5] = [
+    ("wal_write", CrashPoint::WalWrite),
+    ("commit_fsync", CrashPoint::CommitFsync),
+    ("manifest_write", CrashPoint::ManifestWrite),
+    ("ckpt_flush", CrashPoint::CkptFlush),
+    ("ckpt_record", CrashPoint::CkptRecord),
+];
+
+// The crash point armed, 0 means none. It is shared by all threads since the point may be
+// reached by the thread other than the session setting it, such as the shutdown checkpoint.
+static ARMED: AtomicU8 = AtomicU8::new(0);
+
+// Arms the crash point, none disarms it. Returns false if name is not a crash point.
+pub fn arm(name: &str) -> bool {
+    let point = if name == "none" {
+        0
+    } else {
+        match CRASH_POINTS.iter().find(|(n, _)| *n == name) {
+            None => return false,
+            Some(&(_, point)) => point as u8,
+        }
+    };
+    ARMED.store(point, Ordering::Relaxed);
+    return true;
+}
+
+// Kills the server by SIGKILL if point is armed, just as the tests crash the server, so nothing
+// is flushed or cleaned up after it.
+pub fn crash_point(point: CrashPoint) {
+    if ARMED.load(Ordering::Relaxed) != point as u8 {
+        return;
+    }
+    log::error!("crash at the crash point. point={:?}", point);
+    kill(Pid::this(), Signal::SIGKILL).unwrap();
+}
+
+#[cfg(test)]
+mod crashpoint_test {
+    use super::{arm, ARMED};
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn arm_by_name() {
+        assert!(!arm("wal_fsync"));
+        assert_eq!(ARMED.load(Ordering::Relaxed), 0);
+        assert!(arm("ckpt_record"));
+        assert_eq!(ARMED.load(Ordering::Relaxed), 5);
+        assert!(arm("none"));
+        assert_eq!(ARMED.load(Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,317 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// The encoding conversion between the client and the server, the server encoding is always
+// UTF8, see conv.c and mbutils.c.
+use crate::kbbail;
+use std::borrow::Cow;
+use std::str::from_utf8;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Encoding {
+    Utf8,
+    Latin1,
+    Win1252,
+}
+
+// The chars of 0x80..=0x9f in WIN1252, 0 means the byte is undefined, see cp1252.txt.
+const WIN1252_C1: [u16; 32] = [
+    0x20ac, 0, 0x201a, 0x0192, 0x201e, 0x2026, 0x2020, 0x2021, 0x02c6, 0x2030, 0x0160, 0x2039,
+    0x0152, 0, 0x017d, 0, 0, 0x2018, 0x2019, 0x201c, 0x201d, 0x2022, 0x2013, 0x2014, 0x02dc,
+    0x2122, 0x0161, 0x203a, 0x0153, 0, 0x017e, 0x0178,
+];
+
+impl Encoding {
+    // pg_char_to_encoding, the name is case-insensitive and the non-alphanumeric chars are
+    // ignored, so that "iso-8859-1" is LATIN1.
+    pub fn from_name(name: &str) -> Option<Encoding> {
+        match clean_name(name).as_str() {
+            "utf8" | "unicode" => Some(Encoding::Utf8),
+            "latin1" | "iso88591" => Some(Encoding::Latin1),
+            "win1252" | "windows1252" => Some(Encoding::Win1252),
+            _ => None,
+        }
+    }
+
+    // pg_encoding_to_char
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Utf8 => "UTF8",
+            Encoding::Latin1 => "LATIN1",
+            Encoding::Win1252 => "WIN1252",
+        }
+    }
+
+    // Only for the single-byte encodings.
+    fn decode_byte(self, b: u8) -> Option<char> {
+        match self {
+            Encoding::Win1252 if (0x80..=0x9f).contains(&b) => {
+                match WIN1252_C1[(b - 0x80) as usize] {
+                    0 => None,
+                    c => char::from_u32(c as u32),
+                }
+            }
+            Encoding::Latin1 | Encoding::Win1252 => Some(b as char),
+            Encoding::Utf8 => unreachable!(),
+        }
+    }
+
+    // Only for the single-byte encodings.
+    fn encode_char(self, c: char) -> Option<u8> {
+        match self {
+            Encoding::Latin1 if (c as u32) < 0x100 => Some(c as u8),
+            Encoding::Win1252 if (c as u32) < 0x100 && !(0x80..=0x9f).contains(&(c as u32)) => {
+                Some(c as u8)
+            }
+            Encoding::Win1252 => WIN1252_C1
+                .iter()
+                .position(|&v| v != 0 && v as u32 == c as u32)
+                .map(|idx| 0x80 + idx as u8),
+            Encoding::Latin1 => None,
+            Encoding::Utf8 => unreachable!(),
+        }
+    }
+}
+
+// clean_encoding_name
+fn clean_name(name: &str) -> String {
+    name.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+// The encodings known by PostgreSQL but not supported as the client encoding, see
+// pg_enc2name_tbl.
+const UNSUPPORTED_ENCODINGS: [&str; 39] = [
+    "SQL_ASCII",
+    "EUC_JP",
+    "EUC_CN",
+    "EUC_KR",
+    "EUC_TW",
+    "EUC_JIS_2004",
+    "MULE_INTERNAL",
+    "LATIN2",
+    "LATIN3",
+    "LATIN4",
+    "LATIN5",
+    "LATIN6",
+    "LATIN7",
+    "LATIN8",
+    "LATIN9",
+    "LATIN10",
+    "WIN1256",
+    "WIN1258",
+    "WIN866",
+    "WIN874",
+    "KOI8R",
+    "WIN1251",
+    "ISO_8859_5",
+    "ISO_8859_6",
+    "ISO_8859_7",
+    "ISO_8859_8",
+    "WIN1250",
+    "WIN1253",
+    "WIN1254",
+    "WIN1255",
+    "WIN1257",
+    "KOI8U",
+    "SJIS",
+    "BIG5",
+    "GBK",
+    "UHC",
+    "GB18030",
+    "JOHAB",
+    "SHIFT_JIS_2004",
+];
+
+// check_client_encoding, validates the client_encoding of the startup packet. The unknown
+// name is an invalid value, while the encoding known by PostgreSQL but without a conversion
+// to the server encoding is a missing feature.
+pub fn check_client_encoding(name: &str, server_encoding: &str) -> anyhow::Result<Encoding> {
+    if let Some(enc) = Encoding::from_name(name) {
+        return Ok(enc);
+    }
+    let cleaned = clean_name(name);
+    let known = UNSUPPORTED_ENCODINGS
+        .iter()
+        .find(|v| clean_name(v) == cleaned);
+    match known {
+        None => kbbail!(
+            ERRCODE_INVALID_PARAMETER_VALUE,
+            "invalid value for parameter \"client_encoding\": \"{}\". server_encoding={}",
+            name,
+            server_encoding
+        ),
+        Some(known) => kbbail!(
+            ERRCODE_FEATURE_NOT_SUPPORTED,
+            "conversion between {} and {} is not supported. client_encoding={} server_encoding={} supported={},{},{}",
+            known,
+            server_encoding,
+            name,
+            server_encoding,
+            Encoding::Utf8.name(),
+            Encoding::Latin1.name(),
+            Encoding::Win1252.name()
+        ),
+    }
+}
+
+// pg_any_to_server, converts the bytes received from the client to UTF-8.
+pub fn to_server(enc: Encoding, data: &[u8]) -> anyhow::Result<Cow<'_, str>> {
+    if enc == Encoding::Utf8 || data.is_ascii() {
+        match from_utf8(data) {
+            Ok(v) => return Ok(Cow::Borrowed(v)),
+            Err(err) => kbbail!(
+                ERRCODE_CHARACTER_NOT_IN_REPERTOIRE,
+                "invalid byte sequence for encoding \"UTF8\": 0x{:02x}",
+                data[err.valid_up_to()]
+            ),
+        }
+    }
+    let mut out = String::with_capacity(data.len() + data.len() / 2);
+    for &b in data {
+        match enc.decode_byte(b) {
+            Some(c) => out.push(c),
+            None => kbbail!(
+                ERRCODE_CHARACTER_NOT_IN_REPERTOIRE,
+                "invalid byte sequence for encoding \"{}\": 0x{:02x}",
+                enc.name(),
+                b
+            ),
+        }
+    }
+    return Ok(Cow::Owned(out));
+}
+
+// pg_server_to_any, converts the text sent to the client from UTF-8.
+pub fn to_client(enc: Encoding, s: &str) -> anyhow::Result<Cow<'_, [u8]>> {
+    if enc == Encoding::Utf8 || s.is_ascii() {
+        return Ok(Cow::Borrowed(s.as_bytes()));
+    }
+    let mut out = Vec::with_capacity(s.len());
+    for c in s.chars() {
+        match enc.encode_char(c) {
+            Some(b) => out.push(b),
+            None => {
+                let mut buf = [0u8; 4];
+                let seq: Vec<_> = c
+                    .encode_utf8(&mut buf)
+                    .bytes()
+                    .map(|b| format!("0x{:02x}", b))
+                    .collect();
+                kbbail!(
+                    ERRCODE_UNTRANSLATABLE_CHARACTER,
+                    "character with byte sequence {} in encoding \"UTF8\" has no equivalent in encoding \"{}\"",
+                    seq.join(" "),
+                    enc.name()
+                );
+            }
+        }
+    }
+    return Ok(Cow::Owned(out));
+}
+
+// to_client() for the text which can not fail to be sent, such as the error message, the
+// untranslatable char is replaced by '?'.
+pub fn to_client_lossy(enc: Encoding, s: &str) -> Cow<'_, [u8]> {
+    if enc == Encoding::Utf8 || s.is_ascii() {
+        return Cow::Borrowed(s.as_bytes());
+    }
+    return Cow::Owned(
+        s.chars()
+            .map(|c| enc.encode_char(c).unwrap_or(b'?'))
+            .collect(),
+    );
+}
+
+#[cfg(test)]
+mod encoding_test {
+    use super::{check_client_encoding, to_client, to_client_lossy, to_server, Encoding};
+    use crate::protocol::{
+        ERRCODE_CHARACTER_NOT_IN_REPERTOIRE, ERRCODE_FEATURE_NOT_SUPPORTED,
+        ERRCODE_INVALID_PARAMETER_VALUE, ERRCODE_UNTRANSLATABLE_CHARACTER,
+    };
+    use crate::utils::err::errcode;
+
+    #[test]
+    fn from_name() {
+        assert_eq!(Encoding::from_name("utf-8"), Some(Encoding::Utf8));
+        assert_eq!(Encoding::from_name("ISO_8859_1"), Some(Encoding::Latin1));
+        assert_eq!(Encoding::from_name("Windows-1252"), Some(Encoding::Win1252));
+        assert_eq!(Encoding::from_name("KOI8"), None);
+        for enc in [Encoding::Utf8, Encoding::Latin1, Encoding::Win1252] {
+            assert_eq!(Encoding::from_name(enc.name()), Some(enc));
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let latin1: Vec<u8> = (1..=0xffu8).collect();
+        let s = to_server(Encoding::Latin1, &latin1).unwrap();
+        assert_eq!(s.chars().count(), 0xff);
+        assert_eq!(to_client(Encoding::Latin1, &s).unwrap(), latin1);
+
+        let win1252 = b"\x80 caf\xe9 \x9f";
+        let s = to_server(Encoding::Win1252, win1252).unwrap();
+        assert_eq!(s, "€ café Ÿ");
+        assert_eq!(to_client(Encoding::Win1252, &s).unwrap(), &win1252[..]);
+
+        assert_eq!(
+            to_server(Encoding::Utf8, "盏一".as_bytes()).unwrap(),
+            "盏一"
+        );
+        assert_eq!(
+            to_client(Encoding::Utf8, "盏一").unwrap(),
+            "盏一".as_bytes()
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        let err = to_server(Encoding::Win1252, b"a\x81").unwrap_err();
+        assert_eq!(errcode(&err), ERRCODE_CHARACTER_NOT_IN_REPERTOIRE);
+        let err = to_server(Encoding::Utf8, b"a\xe9").unwrap_err();
+        assert_eq!(errcode(&err), ERRCODE_CHARACTER_NOT_IN_REPERTOIRE);
+        let err = to_client(Encoding::Latin1, "café €").unwrap_err();
+        assert_eq!(errcode(&err), ERRCODE_UNTRANSLATABLE_CHARACTER);
+        assert_eq!(
+            err.to_string(),
+            "character with byte sequence 0xe2 0x82 0xac in encoding \"UTF8\" has no equivalent in encoding \"LATIN1\""
+        );
+        assert_eq!(
+            to_client_lossy(Encoding::Latin1, "café €"),
+            &b"caf\xe9 ?"[..]
+        );
+    }
+
+    #[test]
+    fn check() {
+        assert_eq!(
+            check_client_encoding("Latin-1", "UTF8").unwrap(),
+            Encoding::Latin1
+        );
+        let err = check_client_encoding("koi8-r", "UTF8").unwrap_err();
+        assert_eq!(errcode(&err), ERRCODE_FEATURE_NOT_SUPPORTED);
+        assert_eq!(
+            err.to_string(),
+            "conversion between KOI8R and UTF8 is not supported. client_encoding=koi8-r server_encoding=UTF8 supported=UTF8,LATIN1,WIN1252"
+        );
+        let err = check_client_encoding("gb18030", "UTF8").unwrap_err();
+        assert_eq!(errcode(&err), ERRCODE_FEATURE_NOT_SUPPORTED);
+        let err = check_client_encoding("nosuch", "UTF8").unwrap_err();
+        assert_eq!(errcode(&err), ERRCODE_INVALID_PARAMETER_VALUE);
+        assert_eq!(
+            err.to_string(),
+            "invalid value for parameter \"client_encoding\": \"nosuch\". server_encoding=UTF8"
+        );
+    }
+}
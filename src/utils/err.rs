@@ -12,6 +12,8 @@
 pub struct ErrCtx {
     pub code: &'static str,
     pub msg: String,
+    // The cursor position, an index into the query string in characters, starting from 1.
+    pub cursorpos: Option<usize>,
 }
 
 // crate::on_error() has already output `code`,
@@ -30,18 +32,24 @@ pub fn errcode(err: &anyhow::Error) -> &'static str {
     }
 }
 
+pub fn errposition(err: &anyhow::Error) -> Option<usize> {
+    err.downcast_ref::<ErrCtx>().and_then(|v| v.cursorpos)
+}
+
 #[macro_export]
 macro_rules! errctx {
     ($code:ident, $msg:literal $(,)?) => {
         $crate::utils::err::ErrCtx {
             code: $crate::protocol::$code,
             msg: $msg.to_string(),
+            cursorpos: None,
         }
     };
     ($code:ident, $fmt:expr, $($arg:tt)*) => {
         $crate::utils::err::ErrCtx {
             code: $crate::protocol::$code,
             msg: format!($fmt, $($arg)*),
+            cursorpos: None,
         }
     };
 }
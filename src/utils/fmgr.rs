@@ -49,6 +49,29 @@ pub fn get_fmgr_builtins() -> FmgrBuiltinsMap {
     m.insert(Oid::new(181).unwrap(), adt::int4mi);
     m.insert(Oid::new(154).unwrap(), adt::int4div);
     m.insert(Oid::new(141).unwrap(), adt::int4mul);
+    m.insert(Oid::new(65).unwrap(), adt::int4eq);
+    m.insert(Oid::new(144).unwrap(), adt::int4ne);
+    m.insert(Oid::new(66).unwrap(), adt::int4lt);
+    m.insert(Oid::new(147).unwrap(), adt::int4gt);
+    m.insert(Oid::new(149).unwrap(), adt::int4le);
+    m.insert(Oid::new(150).unwrap(), adt::int4ge);
+    m.insert(Oid::new(1242).unwrap(), adt::boolin);
+    m.insert(Oid::new(1243).unwrap(), adt::boolout);
+    m.insert(Oid::new(461).unwrap(), adt::int8out);
+    m.insert(Oid::new(215).unwrap(), adt::float8out);
+    m.insert(Oid::new(1244).unwrap(), adt::byteain);
+    m.insert(Oid::new(31).unwrap(), adt::byteaout);
+    m.insert(Oid::new(1047).unwrap(), adt::varcharout);
+    m.insert(Oid::new(313).unwrap(), adt::i2toi4);
+    m.insert(Oid::new(754).unwrap(), adt::int28);
+    m.insert(Oid::new(236).unwrap(), adt::i2tof);
+    m.insert(Oid::new(235).unwrap(), adt::i2tod);
+    m.insert(Oid::new(481).unwrap(), adt::int48);
+    m.insert(Oid::new(318).unwrap(), adt::i4tof);
+    m.insert(Oid::new(316).unwrap(), adt::i4tod);
+    m.insert(Oid::new(652).unwrap(), adt::i8tof);
+    m.insert(Oid::new(482).unwrap(), adt::i8tod);
+    m.insert(Oid::new(311).unwrap(), adt::ftod);
     m
 }
 
@@ -0,0 +1,489 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::utils::latch::Latch;
+use crate::{kbanyhow, kbbail, kbensure};
+use anyhow::{anyhow, bail};
+use nix::libc::c_int;
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+
+// The host-based authentication file in the data directory, just as pg_hba.conf. Each line is
+// 'type database user address method':
+//   type: host, hostssl or hostnossl.
+//   database and user: all or the names separated by commas.
+//   address: all, an IP address or a CIDR.
+//   method: trust, reject, md5 or scram-sha-256.
+// The text after # is a comment. The first line matching the connection decides the method.
+// The rule 'address user method' of hba_rules is the line 'host all user address method', and
+// hba_rules is only used if there is no kb_hba.conf.
+pub const HBA_FILE: &str = "kb_hba.conf";
+
+const HBA_METHODS: [&str; 4] = ["trust", "reject", "md5", "scram-sha-256"];
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum ConnType {
+    Host,
+    HostSsl,
+    HostNoSsl,
+}
+
+// HbaLine
+#[derive(Debug, PartialEq)]
+pub struct HbaLine {
+    conntype: ConnType,
+    // None matches all databases.
+    databases: Option<Vec<String>>,
+    // None matches all users.
+    users: Option<Vec<String>>,
+    // The network and the length of its mask, None matches all hosts.
+    addr: Option<(IpAddr, u32)>,
+    pub method: &'static str,
+}
+
+// The connection to authenticate.
+pub struct HbaConn<'a> {
+    pub tls: bool,
+    // None if the address of the client is unknown, it only matches the lines for all hosts.
+    pub addr: Option<IpAddr>,
+    pub database: &'a str,
+    pub user: &'a str,
+}
+
+impl HbaConn<'_> {
+    // The connection in the errors of the authentication.
+    pub fn describe(&self) -> String {
+        format!(
+            "host \"{}\", user \"{}\", database \"{}\", {}",
+            self.addr
+                .map_or("UNKNOWN ADDR".to_string(), |v| v.to_string()),
+            self.user,
+            self.database,
+            if self.tls { "SSL on" } else { "SSL off" }
+        )
+    }
+}
+
+// The lines of kb_hba.conf, None if the file does not exist, see hba_method().
+static HBA_LINES: RwLock<Option<Arc<Vec<HbaLine>>>> = RwLock::new(None);
+
+fn addr_bits(addr: &IpAddr) -> u32 {
+    if addr.is_ipv4() {
+        32
+    } else {
+        128
+    }
+}
+
+fn parse_names(field: &str) -> Option<Vec<String>> {
+    if field == "all" {
+        return None;
+    }
+    return Some(field.split(',').map(|v| v.to_string()).collect());
+}
+
+fn parse_addr(field: &str) -> anyhow::Result<Option<(IpAddr, u32)>> {
+    if field == "all" {
+        return Ok(None);
+    }
+    let (addr, bits) = match field.split_once('/') {
+        None => {
+            let addr: IpAddr = field.parse()?;
+            (addr, addr_bits(&addr))
+        }
+        Some((addr, bits)) => (addr.parse()?, bits.parse()?),
+    };
+    if bits > addr_bits(&addr) {
+        bail!("invalid CIDR mask in address \"{}\"", field);
+    }
+    return Ok(Some((addr, bits)));
+}
+
+fn parse_method(field: &str) -> anyhow::Result<&'static str> {
+    return HBA_METHODS
+        .iter()
+        .find(|&&v| v == field)
+        .copied()
+        .ok_or_else(|| anyhow!("invalid authentication method \"{}\"", field));
+}
+
+// parse_hba_line for the rule of hba_rules.
+fn parse_hba_rule(rule: &str) -> anyhow::Result<HbaLine> {
+    let fields: Vec<&str> = rule.split_whitespace().collect();
+    if fields.len() != 3 {
+        bail!("expected 3 fields, got {}", fields.len());
+    }
+    let addr = parse_addr(fields[0])
+        .map_err(|err| anyhow!("invalid IP address \"{}\": {}", fields[0], err))?;
+    return Ok(HbaLine {
+        conntype: ConnType::Host,
+        databases: None,
+        users: parse_names(fields[1]),
+        addr,
+        method: parse_method(fields[2])?,
+    });
+}
+
+fn parse_hba_rules(rules: &str) -> anyhow::Result<Vec<HbaLine>> {
+    let mut lines = Vec::new();
+    for (ruleno, rule) in rules.split(',').enumerate() {
+        let hbaline = parse_hba_rule(rule)
+            .map_err(|err| anyhow!("{}, rule {} of hba_rules", err, ruleno + 1))?;
+        lines.push(hbaline);
+    }
+    return Ok(lines);
+}
+
+// parse_hba_line
+fn parse_hba_line(line: &str) -> anyhow::Result<HbaLine> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() != 5 {
+        bail!("expected 5 fields, got {}", fields.len());
+    }
+    let conntype = match fields[0] {
+        "host" => ConnType::Host,
+        "hostssl" => ConnType::HostSsl,
+        "hostnossl" => ConnType::HostNoSsl,
+        v => bail!("invalid connection type \"{}\"", v),
+    };
+    let addr = parse_addr(fields[3])
+        .map_err(|err| anyhow!("invalid IP address \"{}\": {}", fields[3], err))?;
+    return Ok(HbaLine {
+        conntype,
+        databases: parse_names(fields[1]),
+        users: parse_names(fields[2]),
+        addr,
+        method: parse_method(fields[4])?,
+    });
+}
+
+// tokenize_file and parse_hba_line for every line.
+fn parse_hba(content: &str) -> anyhow::Result<Vec<HbaLine>> {
+    let mut lines = Vec::new();
+    for (lineno, line) in content.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let hbaline = parse_hba_line(line)
+            .map_err(|err| anyhow!("{}, line {} of {}", err, lineno + 1, HBA_FILE))?;
+        lines.push(hbaline);
+    }
+    return Ok(lines);
+}
+
+// check_ip
+fn addr_match(addr: IpAddr, net: IpAddr, bits: u32) -> bool {
+    let hostbits = addr_bits(&net) - bits;
+    let (addr, net) = match (addr, net) {
+        (IpAddr::V4(addr), IpAddr::V4(net)) => (u32::from(addr) as u128, u32::from(net) as u128),
+        (IpAddr::V6(addr), IpAddr::V6(net)) => (u128::from(addr), u128::from(net)),
+        _ => return false,
+    };
+    return (addr ^ net).checked_shr(hostbits).unwrap_or(0) == 0;
+}
+
+fn names_match(names: &Option<Vec<String>>, name: &str) -> bool {
+    return names.as_ref().map_or(true, |v| v.iter().any(|v| v == name));
+}
+
+// check_hba, returns the first line matching the connection.
+fn check_hba<'a>(lines: &'a [HbaLine], conn: &HbaConn<'_>) -> Option<&'a HbaLine> {
+    return lines.iter().find(|line| {
+        let type_ok = match line.conntype {
+            ConnType::Host => true,
+            ConnType::HostSsl => conn.tls,
+            ConnType::HostNoSsl => !conn.tls,
+        };
+        let addr_ok = match (line.addr, conn.addr) {
+            (None, _) => true,
+            (Some((net, bits)), Some(addr)) => addr_match(addr, net, bits),
+            (Some(_), None) => false,
+        };
+        type_ok
+            && addr_ok
+            && names_match(&line.databases, conn.database)
+            && names_match(&line.users, conn.user)
+    });
+}
+
+// Returns the method of the first line matching the connection, source names the lines in the
+// errors. The connection matching no line is rejected, just as the implicit reject line at the
+// end of pg_hba.conf.
+fn match_method(
+    lines: &[HbaLine],
+    conn: &HbaConn<'_>,
+    source: &str,
+) -> anyhow::Result<&'static str> {
+    let line = match check_hba(lines, conn) {
+        Some(v) => v,
+        None => kbbail!(
+            ERRCODE_INVALID_AUTHORIZATION_SPECIFICATION,
+            "no {} entry for {}",
+            source,
+            conn.describe()
+        ),
+    };
+    kbensure!(
+        line.method != "reject",
+        ERRCODE_INVALID_AUTHORIZATION_SPECIFICATION,
+        "{} rejects connection for {}",
+        source,
+        conn.describe()
+    );
+    return Ok(line.method);
+}
+
+// hba_getauthmethod, the method is chosen by kb_hba.conf, or by hba_rules if kb_hba.conf does
+// not exist, None if there are neither. The invalid rules reject all connections rather than
+// falling back to auth_method.
+pub fn hba_method(rules: &str, conn: &HbaConn<'_>) -> anyhow::Result<Option<&'static str>> {
+    if let Some(lines) = HBA_LINES.read().unwrap().clone() {
+        return Ok(Some(match_method(&lines, conn, HBA_FILE)?));
+    }
+    if rules.trim().is_empty() {
+        return Ok(None);
+    }
+    let lines = parse_hba_rules(rules).map_err(|err| {
+        kbanyhow!(
+            ERRCODE_INVALID_AUTHORIZATION_SPECIFICATION,
+            "invalid hba_rules: {}",
+            err
+        )
+    })?;
+    return Ok(Some(match_method(&lines, conn, "hba_rules")?));
+}
+
+// load_hba, the lines are kept unchanged if the file is invalid. The missing file removes the
+// lines.
+pub fn load() -> anyhow::Result<()> {
+    let content = match std::fs::read_to_string(HBA_FILE) {
+        Ok(v) => Some(v),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err.into()),
+    };
+    let lines = match content {
+        None => None,
+        Some(content) => Some(Arc::new(parse_hba(&content)?)),
+    };
+    log::info!(
+        "load kb_hba.conf. lines={:?}",
+        lines.as_ref().map(|v| v.len())
+    );
+    *HBA_LINES.write().unwrap() = lines;
+    return Ok(());
+}
+
+// The latch of the reloader thread, see statedump for why the handler only sets the latch.
+static RELOAD_LATCH: AtomicPtr<Latch> = AtomicPtr::new(std::ptr::null_mut());
+
+extern "C" fn on_sighup(_: c_int) {
+    let latch = RELOAD_LATCH.load(Ordering::Relaxed);
+    if !latch.is_null() {
+        unsafe { &*latch }.set();
+    }
+}
+
+// Loads kb_hba.conf and installs the SIGHUP handler, kb_hba.conf is reloaded by a dedicated
+// thread each time the signal is received.
+pub fn start() -> anyhow::Result<()> {
+    load()?;
+    let latch: &'static Latch = Box::leak(Box::new(Latch::new()?));
+    RELOAD_LATCH.store(latch as *const _ as *mut _, Ordering::Relaxed);
+    thread::spawn(move || loop {
+        latch.wait();
+        latch.reset();
+        if let Err(err) = load() {
+            log::warn!("kb_hba.conf was not reloaded. err={}", err);
+        }
+    });
+    let action = SigAction::new(
+        SigHandler::Handler(on_sighup),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    unsafe { sigaction(Signal::SIGHUP, &action) }?;
+    return Ok(());
+}
+
+#[cfg(test)]
+mod hba_test {
+    use super::{check_hba, parse_hba, parse_hba_rules, HbaConn};
+
+    fn method(
+        lines: &[super::HbaLine],
+        tls: bool,
+        addr: &str,
+        db: &str,
+        user: &str,
+    ) -> &'static str {
+        let conn = HbaConn {
+            tls,
+            addr: addr.parse().ok(),
+            database: db,
+            user,
+        };
+        return check_hba(lines, &conn).map_or("none", |v| v.method);
+    }
+
+    // The rules of hba_rules match all connection types and all databases.
+    fn rule_method(lines: &[super::HbaLine], addr: &str, user: &str) -> &'static str {
+        let ret = method(lines, false, addr, "kuiba", user);
+        assert_eq!(ret, method(lines, true, addr, "other", user));
+        return ret;
+    }
+
+    #[test]
+    fn hba_rules() {
+        let lines = parse_hba_rules(
+            "10.0.0.0/8 all md5, 127.0.0.1 kuiba trust, ::1/128 all scram-sha-256, all admin reject",
+        )
+        .unwrap();
+        assert_eq!(rule_method(&lines, "10.1.2.3", "kuiba"), "md5");
+        assert_eq!(rule_method(&lines, "11.1.2.3", "kuiba"), "none");
+        assert_eq!(rule_method(&lines, "127.0.0.1", "kuiba"), "trust");
+        assert_eq!(rule_method(&lines, "127.0.0.2", "kuiba"), "none");
+        assert_eq!(rule_method(&lines, "127.0.0.1", "other"), "none");
+        assert_eq!(rule_method(&lines, "::1", "other"), "scram-sha-256");
+        assert_eq!(rule_method(&lines, "127.0.0.1", "admin"), "reject");
+        // The client whose address is unknown only matches all hosts.
+        assert_eq!(rule_method(&lines, "unknown", "admin"), "reject");
+        assert_eq!(rule_method(&lines, "unknown", "kuiba"), "none");
+
+        let lines = parse_hba_rules("0.0.0.0/0 all trust").unwrap();
+        assert_eq!(rule_method(&lines, "1.2.3.4", "kuiba"), "trust");
+        assert_eq!(rule_method(&lines, "::1", "kuiba"), "none");
+    }
+
+    #[test]
+    fn invalid_hba_rules() {
+        for (rules, err) in &[
+            ("all all", "expected 3 fields, got 2, rule 1"),
+            ("all all trust x", "expected 3 fields, got 4"),
+            ("all all password", "invalid authentication method"),
+            ("host all trust", "invalid IP address \"host\""),
+            ("10.0.0.0/33 all trust", "invalid CIDR mask"),
+            ("all all trust,", "expected 3 fields, got 0, rule 2"),
+        ] {
+            let msg = parse_hba_rules(rules).unwrap_err().to_string();
+            assert!(msg.contains(err), "rules={:?} msg={}", rules, msg);
+        }
+    }
+
+    #[test]
+    fn cidr() {
+        let lines = parse_hba(
+            "host all all 10.0.0.0/8 md5\n\
+             host all all 127.0.0.1 trust\n\
+             host all all ::1/128 scram-sha-256\n\
+             host all all 192.168.1.0/24 reject\n",
+        )
+        .unwrap();
+        assert_eq!(method(&lines, false, "10.1.2.3", "kuiba", "kuiba"), "md5");
+        assert_eq!(method(&lines, false, "11.1.2.3", "kuiba", "kuiba"), "none");
+        assert_eq!(
+            method(&lines, false, "127.0.0.1", "kuiba", "kuiba"),
+            "trust"
+        );
+        assert_eq!(method(&lines, false, "127.0.0.2", "kuiba", "kuiba"), "none");
+        assert_eq!(
+            method(&lines, false, "::1", "kuiba", "kuiba"),
+            "scram-sha-256"
+        );
+        assert_eq!(method(&lines, false, "::2", "kuiba", "kuiba"), "none");
+        assert_eq!(
+            method(&lines, false, "192.168.1.255", "kuiba", "kuiba"),
+            "reject"
+        );
+        assert_eq!(
+            method(&lines, false, "192.168.2.1", "kuiba", "kuiba"),
+            "none"
+        );
+        // The client whose address is unknown only matches all hosts.
+        assert_eq!(method(&lines, false, "unknown", "kuiba", "kuiba"), "none");
+
+        let lines = parse_hba("host all all 0.0.0.0/0 trust").unwrap();
+        assert_eq!(method(&lines, false, "1.2.3.4", "kuiba", "kuiba"), "trust");
+        assert_eq!(method(&lines, false, "::1", "kuiba", "kuiba"), "none");
+    }
+
+    #[test]
+    fn database_and_user() {
+        let lines = parse_hba(
+            "# comment\n\
+             \n\
+             host kuiba,test all all md5  # trailing comment\n\
+             host all admin,root all scram-sha-256\n\
+             hostssl all all all trust\n",
+        )
+        .unwrap();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(method(&lines, false, "1.2.3.4", "test", "admin"), "md5");
+        assert_eq!(
+            method(&lines, false, "1.2.3.4", "other", "admin"),
+            "scram-sha-256"
+        );
+        assert_eq!(
+            method(&lines, false, "1.2.3.4", "other", "root"),
+            "scram-sha-256"
+        );
+        assert_eq!(method(&lines, false, "1.2.3.4", "other", "kuiba"), "none");
+        assert_eq!(method(&lines, true, "1.2.3.4", "other", "kuiba"), "trust");
+    }
+
+    #[test]
+    fn first_match_wins() {
+        let lines = parse_hba(
+            "host all kuiba 127.0.0.1/32 reject\n\
+             host all all 127.0.0.0/8 trust\n\
+             hostnossl all all all md5\n\
+             host all all all trust\n",
+        )
+        .unwrap();
+        assert_eq!(
+            method(&lines, false, "127.0.0.1", "kuiba", "kuiba"),
+            "reject"
+        );
+        assert_eq!(
+            method(&lines, false, "127.0.0.1", "kuiba", "other"),
+            "trust"
+        );
+        assert_eq!(method(&lines, false, "10.0.0.1", "kuiba", "kuiba"), "md5");
+        assert_eq!(method(&lines, true, "10.0.0.1", "kuiba", "kuiba"), "trust");
+    }
+
+    #[test]
+    fn invalid() {
+        for (content, err) in &[
+            ("host all all md5", "expected 5 fields, got 4, line 1"),
+            ("host all all all trust x", "expected 5 fields, got 6"),
+            (
+                "local all all all trust",
+                "invalid connection type \"local\"",
+            ),
+            ("\nhost all all 10.0.0.0/33 trust", "invalid CIDR mask"),
+            (
+                "host all all 10.0.0/8 trust",
+                "invalid IP address \"10.0.0/8\"",
+            ),
+            ("host all all all password", "invalid authentication method"),
+        ] {
+            let msg = parse_hba(content).unwrap_err().to_string();
+            assert!(msg.contains(err), "content={:?} msg={}", content, msg);
+        }
+        let msg = parse_hba("\nhost all all 10.0.0.0/33 trust")
+            .unwrap_err()
+            .to_string();
+        assert!(msg.ends_with("line 2 of kb_hba.conf"), "msg={}", msg);
+    }
+}
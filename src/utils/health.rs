@@ -0,0 +1,73 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::reject_startup;
+use nix::poll::{poll, PollFd, PollFlags};
+use std::io::Write;
+use std::net::TcpListener;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+
+// Set once the recovery is done and the server is accepting connections.
+static READY: AtomicBool = AtomicBool::new(false);
+
+pub fn set_ready() {
+    READY.store(true, Ordering::Release);
+}
+
+pub fn is_ready() -> bool {
+    READY.load(Ordering::Acquire)
+}
+
+// The listener is bound before the redo, the connections are rejected with "the database
+// system is starting up" until the server is ready, instead of being refused. The thread
+// returns once the server is ready, the listener is then accepted by the postmaster.
+pub fn reject_until_ready(listener: TcpListener) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut fds = [PollFd::new(listener.as_raw_fd(), PollFlags::POLLIN)];
+        loop {
+            let readable = matches!(poll(&mut fds, 100), Ok(n) if n > 0);
+            if is_ready() {
+                return;
+            }
+            if !readable {
+                continue;
+            }
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    thread::spawn(move || reject_startup(stream));
+                }
+                Err(err) => log::warn!("reject startup: accept failed. err={}", err),
+            }
+        }
+    })
+}
+
+// The health check endpoint for the container orchestration, it does not speak the
+// protocol, every connection just gets a line of OK or NOT_READY and is closed.
+pub fn start(port: u16) -> std::io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    log::info!("health check listen. port={}", port);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    log::warn!("health check: accept failed. err={}", err);
+                    continue;
+                }
+            };
+            let resp: &[u8] = if is_ready() { b"OK\n" } else { b"NOT_READY\n" };
+            let _ = stream.write_all(resp);
+        }
+    });
+    return Ok(());
+}
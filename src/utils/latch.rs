@@ -0,0 +1,96 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::unistd::{close, pipe2, read, write};
+use std::os::unix::io::RawFd;
+
+// Just like the self-pipe trick used by the Latch of PostgreSQL, the readfd becomes
+// readable after set() so that the owner can wait for it together with the client socket.
+pub struct Latch {
+    readfd: RawFd,
+    writefd: RawFd,
+}
+
+impl Latch {
+    // InitLatch
+    pub fn new() -> nix::Result<Latch> {
+        let (readfd, writefd) = pipe2(OFlag::O_NONBLOCK | OFlag::O_CLOEXEC)?;
+        Ok(Latch { readfd, writefd })
+    }
+
+    // SetLatch
+    pub fn set(&self) {
+        // The pipe being full means the latch has been set already.
+        let _ = write(self.writefd, &[0]);
+    }
+
+    // ResetLatch
+    pub fn reset(&self) {
+        let mut buf = [0u8; 16];
+        loop {
+            match read(self.readfd, &mut buf) {
+                Ok(n) if n > 0 => continue,
+                Err(nix::Error::Sys(Errno::EINTR)) => continue,
+                _ => return,
+            }
+        }
+    }
+
+    // WaitLatch, returns once the latch is set, the EINTR is retried.
+    pub fn wait(&self) {
+        let mut fds = [PollFd::new(self.readfd, PollFlags::POLLIN)];
+        loop {
+            match poll(&mut fds, -1) {
+                Err(nix::Error::Sys(Errno::EINTR)) => continue,
+                ret => {
+                    ret.unwrap();
+                    return;
+                }
+            }
+        }
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.readfd
+    }
+}
+
+impl Drop for Latch {
+    fn drop(&mut self) {
+        let _ = close(self.readfd);
+        let _ = close(self.writefd);
+    }
+}
+
+#[cfg(test)]
+mod latch_test {
+    use super::Latch;
+    use nix::poll::{poll, PollFd, PollFlags};
+
+    fn is_set(latch: &Latch) -> bool {
+        let mut fds = [PollFd::new(latch.fd(), PollFlags::POLLIN)];
+        poll(&mut fds, 0).unwrap() > 0
+    }
+
+    #[test]
+    fn t() {
+        let latch = Latch::new().unwrap();
+        assert!(!is_set(&latch));
+        for _ in 0..100000 {
+            latch.set();
+        }
+        assert!(is_set(&latch));
+        latch.reset();
+        assert!(!is_set(&latch));
+    }
+}
@@ -0,0 +1,500 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// The log destination, just as the syslogger. The log is written to stderr until start()
+// redirects it to the files under log_directory according to the GUCs loaded from kuiba.conf.
+use crate::guc::{self, GucState};
+use crate::utils::Xid;
+use anyhow::Context;
+use chrono::format::{Item, StrftimeItems};
+use chrono::Local;
+use std::cell::RefCell;
+use std::fmt::Write as FmtWrite;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+struct Dest {
+    prefix: String,
+    // log_format is json, the prefix is not used then.
+    json: bool,
+    // None means stderr.
+    file: Option<RotatingFile>,
+}
+
+struct Logger {
+    dest: Mutex<Dest>,
+}
+
+// The log_min_messages of kuiba.conf, which is used by the thread without its own level.
+static BASE_LEVEL: AtomicUsize = AtomicUsize::new(log::LevelFilter::Trace as usize);
+
+fn level_from_usize(v: usize) -> log::LevelFilter {
+    match v {
+        0 => log::LevelFilter::Off,
+        1 => log::LevelFilter::Error,
+        2 => log::LevelFilter::Warn,
+        3 => log::LevelFilter::Info,
+        4 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref LOGGER: Logger = Logger {
+        dest: Mutex::new(Dest {
+            // The boot_val of log_line_prefix.
+            prefix: "%m - ".to_string(),
+            json: false,
+            file: None,
+        }),
+    };
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        let level = SESSION_CONTEXT.with(|ctx| ctx.borrow().level);
+        metadata.level() <= level.unwrap_or_else(|| level_from_usize(BASE_LEVEL.load(Relaxed)))
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let mut dest = self.dest.lock().unwrap();
+        let line = if dest.json {
+            format_json(record)
+        } else {
+            format_line(&dest.prefix, record)
+        };
+        match dest.file.as_mut() {
+            None => {
+                let _ = io::stderr().write_all(line.as_bytes());
+            }
+            Some(file) => file.write(line.as_bytes()),
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = self.dest.lock().unwrap().file.as_mut() {
+            let _ = file.file.flush();
+        }
+    }
+}
+
+// The fields of log_line_prefix that belong to the session running on the current thread.
+#[derive(Default)]
+struct SessionContext {
+    sessid: Option<u32>,
+    db: String,
+    appname: String,
+    xid: Option<Xid>,
+    // The log_min_messages of the session.
+    level: Option<log::LevelFilter>,
+}
+
+thread_local! {
+    static SESSION_CONTEXT: RefCell<SessionContext> = RefCell::new(SessionContext::default());
+}
+
+pub fn set_session(sessid: u32, db: &str) {
+    SESSION_CONTEXT.with(|ctx| {
+        let mut ctx = ctx.borrow_mut();
+        ctx.sessid = Some(sessid);
+        ctx.db = db.to_string();
+    });
+}
+
+pub fn set_appname(appname: &str) {
+    SESSION_CONTEXT.with(|ctx| ctx.borrow_mut().appname = appname.to_string());
+}
+
+// log::max_level() is the max level of all threads, so that the disabled log is skipped as
+// soon as possible. It is only raised here and never lowered since it is only a hint.
+pub fn set_level(level: log::LevelFilter) {
+    SESSION_CONTEXT.with(|ctx| ctx.borrow_mut().level = Some(level));
+    if level > log::max_level() {
+        log::set_max_level(level);
+    }
+}
+
+pub fn set_xid(xid: Option<Xid>) {
+    SESSION_CONTEXT.with(|ctx| ctx.borrow_mut().xid = xid);
+}
+
+// log_line_prefix, see the short_desc in gucdef.yaml for the escapes. The unknown escape is
+// ignored just as PostgreSQL, the session fields are empty outside of the session.
+fn format_line(prefix: &str, record: &log::Record) -> String {
+    let mut line = String::with_capacity(128);
+    SESSION_CONTEXT.with(|ctx| {
+        let ctx = ctx.borrow();
+        let mut chars = prefix.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                line.push(c);
+                continue;
+            }
+            let _ = match chars.next() {
+                Some('m') => write!(line, "{}", Local::now().format("%Y-%m-%dT%H:%M:%S%.6f%:z")),
+                Some('t') => write!(line, "{}", Local::now().format("%Y-%m-%dT%H:%M:%S%:z")),
+                Some('p') => write!(line, "{}", std::process::id()),
+                Some('c') => match ctx.sessid {
+                    None => Ok(()),
+                    Some(v) => write!(line, "{}", v),
+                },
+                Some('d') => write!(line, "{}", ctx.db),
+                Some('a') => write!(line, "{}", ctx.appname),
+                Some('x') => write!(line, "{}", ctx.xid.map_or(0, |v| v.get())),
+                Some('%') => write!(line, "%"),
+                _ => Ok(()),
+            };
+        }
+    });
+    let _ = writeln!(line, "{} - {}", record.level(), record.args());
+    return line;
+}
+
+// escape_json
+fn escape_json(out: &mut String, val: &str) {
+    out.push('"');
+    for c in val.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c < ' ' => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+// write_jsonlog, one JSON object per line. Just as PostgreSQL, the session fields are omitted
+// outside of the session.
+fn format_json(record: &log::Record) -> String {
+    let mut line = String::with_capacity(256);
+    line.push_str("{\"timestamp\":");
+    let now = Local::now().format("%Y-%m-%dT%H:%M:%S%.6f%:z").to_string();
+    escape_json(&mut line, &now);
+    let _ = write!(line, ",\"pid\":{}", std::process::id());
+    SESSION_CONTEXT.with(|ctx| {
+        let ctx = ctx.borrow();
+        if let Some(sessid) = ctx.sessid {
+            let _ = write!(line, ",\"session_id\":{}", sessid);
+        }
+        if !ctx.db.is_empty() {
+            line.push_str(",\"dbname\":");
+            escape_json(&mut line, &ctx.db);
+        }
+        if let Some(xid) = ctx.xid {
+            let _ = write!(line, ",\"txid\":{}", xid);
+        }
+        if !ctx.appname.is_empty() {
+            line.push_str(",\"application_name\":");
+            escape_json(&mut line, &ctx.appname);
+        }
+    });
+    let _ = write!(line, ",\"error_severity\":\"{}\"", record.level());
+    line.push_str(",\"message\":");
+    escape_json(&mut line, &record.args().to_string());
+    line.push_str("}\n");
+    return line;
+}
+
+pub fn init() {
+    log::set_logger(&*LOGGER).unwrap();
+    log::set_max_level(log::LevelFilter::Trace);
+}
+
+// SysLogger_Start, the log_min_messages still works after the redirection since it only
+// changes log::max_level().
+pub fn start(gucstate: &GucState) -> anyhow::Result<()> {
+    BASE_LEVEL.store(gucstate.loglvl as usize, Relaxed);
+    log::set_max_level(gucstate.loglvl);
+    {
+        let mut dest = LOGGER.dest.lock().unwrap();
+        dest.prefix = guc::get_str(gucstate, guc::LogLinePrefix).to_string();
+        dest.json = guc::get_str(gucstate, guc::LogFormat) == "json";
+    }
+    if guc::get_str(gucstate, guc::LogDestination) != "file" {
+        return Ok(());
+    }
+    let rotation_age = guc::get_int(gucstate, guc::LogRotationAge);
+    let rotation_age = if rotation_age > 0 {
+        Some(Duration::from_secs(rotation_age as u64 * 60))
+    } else {
+        None
+    };
+    let rotation_size = guc::get_int(gucstate, guc::LogRotationSize).max(0) as u64 * 1024;
+    let file = RotatingFile::new(
+        guc::get_str(gucstate, guc::LogDirectory),
+        guc::get_str(gucstate, guc::LogFilename),
+        rotation_age,
+        rotation_size,
+    )?;
+    log::info!("redirect log. path={}", file.path().display());
+    LOGGER.dest.lock().unwrap().file = Some(file);
+    return Ok(());
+}
+
+// The log_filename is a strftime pattern.
+pub fn is_valid_filename(filename: &str) -> bool {
+    !filename.is_empty() && StrftimeItems::new(filename).all(|item| !matches!(item, Item::Error))
+}
+
+pub struct RotatingFile {
+    dir: PathBuf,
+    filename: String,
+    // None disables the time-based rotation.
+    rotation_age: Option<Duration>,
+    // 0 disables the size-based rotation.
+    rotation_size: u64,
+    file: File,
+    // The log_filename expanded at the open time, the path is different from it only if the
+    // file is rotated within the same name.
+    base: PathBuf,
+    path: PathBuf,
+    size: u64,
+    opened: Instant,
+}
+
+fn open_logfile(path: &Path) -> anyhow::Result<(File, u64)> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("could not open log file. path={}", path.display()))?;
+    let size = file.metadata()?.len();
+    return Ok((file, size));
+}
+
+impl RotatingFile {
+    pub fn new(
+        dir: &str,
+        filename: &str,
+        rotation_age: Option<Duration>,
+        rotation_size: u64,
+    ) -> anyhow::Result<RotatingFile> {
+        let dir = PathBuf::from(dir);
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("could not create log directory. dir={}", dir.display()))?;
+        let base = dir.join(Local::now().format(filename).to_string());
+        let (file, size) = open_logfile(&base)?;
+        return Ok(RotatingFile {
+            dir,
+            filename: filename.to_string(),
+            rotation_age,
+            rotation_size,
+            file,
+            path: base.clone(),
+            base,
+            size,
+            opened: Instant::now(),
+        });
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn should_rotate(&self) -> bool {
+        (self.rotation_size > 0 && self.size >= self.rotation_size)
+            || self
+                .rotation_age
+                .map_or(false, |v| self.opened.elapsed() >= v)
+    }
+
+    // logfile_rotate, the file with the same name is appended to, unless the size limit is
+    // reached, in which case a numeric suffix is added to get a new file.
+    fn rotate(&mut self) -> anyhow::Result<()> {
+        let base = self
+            .dir
+            .join(Local::now().format(&self.filename).to_string());
+        let mut path = base.clone();
+        if base == self.base && self.rotation_size > 0 && self.size >= self.rotation_size {
+            let mut seq = 1;
+            loop {
+                path = PathBuf::from(format!("{}.{}", base.display(), seq));
+                if !path.exists() {
+                    break;
+                }
+                seq += 1;
+            }
+        }
+        let (file, size) = open_logfile(&path)?;
+        self.file = file;
+        self.base = base;
+        self.path = path;
+        self.size = size;
+        self.opened = Instant::now();
+        return Ok(());
+    }
+
+    // The error is ignored since there is nowhere to report it, just as write_syslogger_file.
+    pub fn write(&mut self, line: &[u8]) {
+        if self.should_rotate() {
+            if let Err(err) = self.rotate() {
+                // Keep writing to the current file, and retry at the next age.
+                self.opened = Instant::now();
+                let _ = writeln!(self.file, "could not rotate log file. err={:#}", err);
+            }
+        }
+        if self.file.write_all(line).is_ok() {
+            self.size += line.len() as u64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod logger_test {
+    use super::{
+        format_json, format_line, is_valid_filename, set_appname, set_level, set_session, set_xid,
+        RotatingFile, LOGGER,
+    };
+    use crate::utils::Xid;
+    use log::Log;
+    use std::fs;
+    use std::thread;
+    use std::time::Duration;
+
+    fn logfiles(dir: &std::path::Path) -> Vec<String> {
+        let mut files: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|v| v.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        return files;
+    }
+
+    #[test]
+    fn rotate_by_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let logdir = dir.path().join("log");
+        let mut file = RotatingFile::new(logdir.to_str().unwrap(), "kuiba.log", None, 64).unwrap();
+        let line = b"0123456789012345678901234567890\n";
+        for _ in 0..5 {
+            file.write(line);
+        }
+        assert_eq!(file.path(), logdir.join("kuiba.log.2"));
+        assert_eq!(
+            logfiles(&logdir),
+            ["kuiba.log", "kuiba.log.1", "kuiba.log.2"]
+        );
+        for name in &["kuiba.log", "kuiba.log.1"] {
+            assert_eq!(fs::read(logdir.join(name)).unwrap().len(), 64);
+        }
+        assert_eq!(fs::read(logdir.join("kuiba.log.2")).unwrap(), line);
+
+        // The size of the existing file counts.
+        let mut file = RotatingFile::new(logdir.to_str().unwrap(), "kuiba.log", None, 64).unwrap();
+        file.write(line);
+        assert_eq!(file.path(), logdir.join("kuiba.log.3"));
+    }
+
+    #[test]
+    fn rotate_by_age() {
+        let dir = tempfile::tempdir().unwrap();
+        let logdir = dir.path().to_str().unwrap();
+        let age = Some(Duration::from_millis(20));
+        let mut file = RotatingFile::new(logdir, "kuiba-%H%M%S%f.log", age, 0).unwrap();
+        file.write(b"1\n");
+        let first = file.path().to_path_buf();
+        thread::sleep(Duration::from_millis(30));
+        file.write(b"2\n");
+        assert_ne!(file.path(), first);
+        assert_eq!(fs::read(&first).unwrap(), b"1\n");
+        assert_eq!(fs::read(file.path()).unwrap(), b"2\n");
+        assert_eq!(logfiles(dir.path()).len(), 2);
+    }
+
+    #[test]
+    fn filename() {
+        assert!(is_valid_filename("kuiba-%Y-%m-%d_%H%M%S.log"));
+        assert!(!is_valid_filename("kuiba-%Q.log"));
+        assert!(!is_valid_filename(""));
+    }
+
+    #[test]
+    fn line_prefix() {
+        let args = format_args!("hello {}", 1);
+        let record = log::Record::builder()
+            .args(args)
+            .level(log::Level::Warn)
+            .build();
+        let prefix = "[%p] %c %d %x %a %% %q|";
+        assert_eq!(
+            format_line(prefix, &record),
+            format!("[{}]   0  % |WARN - hello 1\n", std::process::id())
+        );
+
+        set_session(7, "kuiba");
+        set_appname("psql");
+        set_xid(Xid::new(42));
+        let line = format_line(prefix, &record);
+        assert_eq!(
+            line,
+            format!(
+                "[{}] 7 kuiba 42 psql % |WARN - hello 1\n",
+                std::process::id()
+            )
+        );
+        set_xid(None);
+        assert!(format_line("%x %m ", &record).starts_with("0 20"));
+    }
+
+    #[test]
+    fn json() {
+        let args = format_args!("say \"hi\"\n\tto\\{}", '\u{1}');
+        let record = log::Record::builder()
+            .args(args)
+            .level(log::Level::Info)
+            .build();
+        let line = format_json(&record);
+        assert!(line.starts_with("{\"timestamp\":\"20"), "{}", line);
+        let fields = format!(
+            ",\"pid\":{},\"error_severity\":\"INFO\",\"message\":\"say \\\"hi\\\"\\n\\tto\\\\\\u0001\"}}\n",
+            std::process::id()
+        );
+        assert!(line.ends_with(&fields), "{}", line);
+
+        set_session(7, "kuiba");
+        set_appname("p\"sql");
+        set_xid(Xid::new(42));
+        let line = format_json(&record);
+        let fields = format!(
+            ",\"pid\":{},\"session_id\":7,\"dbname\":\"kuiba\",\"txid\":42,\"application_name\":\"p\\\"sql\",\"error_severity\":\"INFO\",",
+            std::process::id()
+        );
+        assert!(line.contains(&fields), "{}", line);
+    }
+
+    #[test]
+    fn thread_level() {
+        fn enabled(level: log::Level) -> bool {
+            LOGGER.enabled(&log::Metadata::builder().level(level).build())
+        }
+        assert!(enabled(log::Level::Trace));
+        set_level(log::LevelFilter::Warn);
+        assert!(!enabled(log::Level::Info));
+        assert!(enabled(log::Level::Warn));
+        // The level of other threads is not changed.
+        assert!(thread::spawn(|| enabled(log::Level::Trace)).join().unwrap());
+        set_level(log::LevelFilter::Trace);
+        assert!(enabled(log::Level::Trace));
+    }
+}
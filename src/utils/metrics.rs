@@ -0,0 +1,247 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::utils::sb::{SlotState, Stats};
+use crate::GlobalState;
+use std::fmt::{Display, Write as FmtWrite};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+// Writes a metric in the Prometheus text exposition format, the label of the sample is
+// empty or like `pool="tabsv"`.
+fn write_metric<T: Display>(
+    out: &mut String,
+    name: &str,
+    typ: &str,
+    help: &str,
+    samples: &[(String, T)],
+) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, typ).unwrap();
+    for (label, val) in samples {
+        if label.is_empty() {
+            writeln!(out, "{} {}", name, val).unwrap();
+        } else {
+            writeln!(out, "{}{{{}}} {}", name, label, val).unwrap();
+        }
+    }
+}
+
+fn hit_ratio(stats: &Stats) -> f64 {
+    let total = stats.hits + stats.reads;
+    if total == 0 {
+        return 1.0;
+    }
+    return stats.hits as f64 / total as f64;
+}
+
+fn ndirty<K>(slots: &[SlotState<K>]) -> usize {
+    slots.iter().filter(|s| s.dirty).count()
+}
+
+pub fn render(state: &GlobalState) -> String {
+    let mut out = String::new();
+    let pools = [
+        ("tabsv", state.tabsv.stats(), state.tabsv.dump()),
+        ("tabmvcc", state.tabmvcc.stats(), state.tabmvcc.dump()),
+    ];
+    let label = |pool: &str| format!("pool=\"{}\"", pool);
+    let samples: Vec<_> = pools
+        .iter()
+        .map(|(pool, stats, _)| (label(pool), stats.hits))
+        .collect();
+    write_metric(
+        &mut out,
+        "kuiba_buffer_hits_total",
+        "counter",
+        "Number of buffer reads found in the buffer pool.",
+        &samples,
+    );
+    let samples: Vec<_> = pools
+        .iter()
+        .map(|(pool, stats, _)| (label(pool), stats.reads))
+        .collect();
+    write_metric(
+        &mut out,
+        "kuiba_buffer_reads_total",
+        "counter",
+        "Number of buffer reads loaded from the disk.",
+        &samples,
+    );
+    let samples: Vec<_> = pools
+        .iter()
+        .map(|(pool, stats, _)| (label(pool), hit_ratio(stats)))
+        .collect();
+    write_metric(
+        &mut out,
+        "kuiba_buffer_hit_ratio",
+        "gauge",
+        "Ratio of the buffer reads found in the buffer pool.",
+        &samples,
+    );
+    let samples: Vec<_> = pools
+        .iter()
+        .map(|(pool, _, slots)| (label(pool), slots.len()))
+        .collect();
+    write_metric(
+        &mut out,
+        "kuiba_buffers",
+        "gauge",
+        "Number of the used buffers.",
+        &samples,
+    );
+    let samples: Vec<_> = pools
+        .iter()
+        .map(|(pool, _, slots)| (label(pool), ndirty(slots)))
+        .collect();
+    write_metric(
+        &mut out,
+        "kuiba_buffers_dirty",
+        "gauge",
+        "Number of the dirty buffers.",
+        &samples,
+    );
+
+    if let Some(wal) = state.wal {
+        let lsns = [
+            ("insert", wal.insert_lsn().get()),
+            ("write", wal.write_lsn()),
+            ("flush", wal.flush_lsn()),
+        ];
+        for (name, lsn) in &lsns {
+            write_metric(
+                &mut out,
+                &format!("kuiba_wal_{}_lsn", name),
+                "gauge",
+                &format!("The wal {} position.", name),
+                &[(String::new(), lsn)],
+            );
+        }
+        let stats = wal.stats();
+        let counters = [
+            (
+                "records",
+                "Number of the wal records generated.",
+                stats.records,
+            ),
+            (
+                "fpi",
+                "Number of the wal full page images generated.",
+                stats.fpi,
+            ),
+            (
+                "bytes",
+                "Amount of the wal generated in bytes.",
+                stats.bytes,
+            ),
+            ("sync", "Number of the wal fsyncs.", stats.sync),
+        ];
+        for (name, help, val) in &counters {
+            write_metric(
+                &mut out,
+                &format!("kuiba_wal_{}_total", name),
+                "counter",
+                help,
+                &[(String::new(), val)],
+            );
+        }
+    }
+
+    if let Some(xact) = state.xact {
+        let (ncommit, nabort) = xact.xact_stats();
+        write_metric(
+            &mut out,
+            "kuiba_xact_commits_total",
+            "counter",
+            "Number of the committed transactions.",
+            &[(String::new(), ncommit)],
+        );
+        write_metric(
+            &mut out,
+            "kuiba_xact_aborts_total",
+            "counter",
+            "Number of the aborted transactions.",
+            &[(String::new(), nabort)],
+        );
+    }
+
+    let locks = state.lmgr.dump();
+    let nwaiters: usize = locks.iter().map(|l| l.waiters.len()).sum();
+    write_metric(
+        &mut out,
+        "kuiba_locks",
+        "gauge",
+        "Number of the locks in the lock table.",
+        &[(String::new(), locks.len())],
+    );
+    write_metric(
+        &mut out,
+        "kuiba_lock_waiters",
+        "gauge",
+        "Number of the lock requests waiting.",
+        &[(String::new(), nwaiters)],
+    );
+
+    let nconn = state.cancelmap.lock().unwrap().len();
+    write_metric(
+        &mut out,
+        "kuiba_connections",
+        "gauge",
+        "Number of the active connections.",
+        &[(String::new(), nconn)],
+    );
+    return out;
+}
+
+// Only GET /metrics is served, the headers of the request are ignored.
+fn handle(state: &GlobalState, stream: TcpStream) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut reqline = String::new();
+    reader.read_line(&mut reqline)?;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header == "\r\n" || header == "\n" {
+            break;
+        }
+    }
+    let mut parts = reqline.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render(state)),
+        _ => ("404 Not Found", String::from("not found\n")),
+    };
+    let mut stream = reader.into_inner();
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        CONTENT_TYPE,
+        body.len(),
+        body
+    )?;
+    return Ok(());
+}
+
+// The HTTP endpoint scraped by Prometheus.
+pub fn start(port: u16, state: GlobalState) -> std::io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    log::info!("metrics listen. port={}", port);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let ret = stream.and_then(|stream| handle(&state, stream));
+            if let Err(err) = ret {
+                log::warn!("metrics: serve failed. err={}", err);
+            }
+        }
+    });
+    return Ok(());
+}
@@ -0,0 +1,222 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// pg_lzcompress, the output is in the format of PostgreSQL. A control byte is followed by up to
+// 8 items, the item is a literal byte if its bit in the control byte is 0, otherwise it is a tag
+// of 2 or 3 bytes copying len bytes at off bytes back in the output. The tag is
+// `(off >> 4) & 0xf0 | (len - 3)`, `off & 0xff`, and `len - 18` if (len - 3) is 15.
+
+const MAX_OFFSET: usize = 0x0fff;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 273;
+const HISTORY_SIZE: usize = 8192;
+// PGLZ_strategy_default
+const MIN_INPUT_SIZE: usize = 32;
+const MIN_COMP_RATE: usize = 25;
+const MATCH_SIZE_GOOD: usize = 128;
+
+// The input at pos is in the history only if there are MIN_MATCH bytes since pos.
+struct History {
+    head: Vec<usize>,
+    prev: Vec<usize>,
+}
+
+impl History {
+    fn new(len: usize) -> History {
+        History {
+            head: vec![usize::MAX; HISTORY_SIZE],
+            prev: vec![usize::MAX; len],
+        }
+    }
+
+    fn hash(d: &[u8], pos: usize) -> usize {
+        let v = (d[pos] as usize) << 6 ^ (d[pos + 1] as usize) << 3 ^ d[pos + 2] as usize;
+        v & (HISTORY_SIZE - 1)
+    }
+
+    fn add(&mut self, d: &[u8], pos: usize) {
+        if pos + MIN_MATCH <= d.len() {
+            let h = History::hash(d, pos);
+            self.prev[pos] = self.head[h];
+            self.head[h] = pos;
+        }
+    }
+
+    // pglz_find_match, returns the len and the off of the longest match of the input at pos.
+    fn find_match(&self, d: &[u8], pos: usize) -> (usize, usize) {
+        if pos + MIN_MATCH > d.len() {
+            return (0, 0);
+        }
+        let maxlen = MAX_MATCH.min(d.len() - pos);
+        let mut best = (0, 0);
+        let mut cand = self.head[History::hash(d, pos)];
+        let mut depth = MATCH_SIZE_GOOD;
+        while cand != usize::MAX && pos - cand <= MAX_OFFSET && depth > 0 {
+            let len = d[cand..]
+                .iter()
+                .zip(&d[pos..pos + maxlen])
+                .take_while(|(a, b)| a == b)
+                .count();
+            if len > best.0 {
+                best = (len, pos - cand);
+                if len == maxlen {
+                    break;
+                }
+            }
+            cand = self.prev[cand];
+            depth -= 1;
+        }
+        return best;
+    }
+}
+
+// pglz_compress, returns None if the input is too short, or the output does not save
+// MIN_COMP_RATE percent of the input.
+pub fn compress(d: &[u8]) -> Option<Vec<u8>> {
+    if d.len() < MIN_INPUT_SIZE {
+        return None;
+    }
+    let result_max = d.len() / 100 * (100 - MIN_COMP_RATE);
+    let mut out = Vec::with_capacity(result_max + 4);
+    let mut hist = History::new(d.len());
+    let mut ctrlidx = 0;
+    let mut ctrlbit = 8;
+    let mut pos = 0;
+    while pos < d.len() {
+        if out.len() >= result_max {
+            return None;
+        }
+        if ctrlbit == 8 {
+            ctrlidx = out.len();
+            out.push(0);
+            ctrlbit = 0;
+        }
+        let (len, off) = hist.find_match(d, pos);
+        if len >= MIN_MATCH {
+            out[ctrlidx] |= 1 << ctrlbit;
+            if len > 17 {
+                out.push((off >> 4) as u8 & 0xf0 | 0x0f);
+                out.push(off as u8);
+                out.push((len - 18) as u8);
+            } else {
+                out.push((off >> 4) as u8 & 0xf0 | (len - 3) as u8);
+                out.push(off as u8);
+            }
+            for p in pos..pos + len {
+                hist.add(d, p);
+            }
+            pos += len;
+        } else {
+            out.push(d[pos]);
+            hist.add(d, pos);
+            pos += 1;
+        }
+        ctrlbit += 1;
+    }
+    if out.len() >= result_max {
+        return None;
+    }
+    return Some(out);
+}
+
+// pglz_decompress, rawlen is the length of the input of compress(). Returns None if the
+// input is corrupted.
+pub fn decompress(d: &[u8], rawlen: usize) -> Option<Vec<u8>> {
+    let mut out: Vec<u8> = Vec::with_capacity(rawlen);
+    let mut sp = 0;
+    while sp < d.len() && out.len() < rawlen {
+        let mut ctrl = d[sp];
+        sp += 1;
+        for _ in 0..8 {
+            if sp >= d.len() || out.len() >= rawlen {
+                break;
+            }
+            if ctrl & 1 == 0 {
+                out.push(d[sp]);
+                sp += 1;
+            } else {
+                let tag = d.get(sp..sp + 2)?;
+                sp += 2;
+                let mut len = (tag[0] & 0x0f) as usize + 3;
+                let off = ((tag[0] & 0xf0) as usize) << 4 | tag[1] as usize;
+                if len == 18 {
+                    len += *d.get(sp)? as usize;
+                    sp += 1;
+                }
+                if off == 0 || off > out.len() || out.len() + len > rawlen {
+                    return None;
+                }
+                // The source may overlap the output, so the bytes are copied one by one.
+                let start = out.len() - off;
+                for i in start..start + len {
+                    out.push(out[i]);
+                }
+            }
+            ctrl >>= 1;
+        }
+    }
+    if sp != d.len() || out.len() != rawlen {
+        return None;
+    }
+    return Some(out);
+}
+
+#[cfg(test)]
+mod pglz_test {
+    use super::{compress, decompress};
+
+    fn roundtrip(d: &[u8]) -> usize {
+        let c = compress(d).unwrap();
+        assert_eq!(decompress(&c, d.len()).unwrap(), d);
+        return c.len();
+    }
+
+    #[test]
+    fn compress_decompress() {
+        // A page of xmins, most bytes are zeros.
+        let mut page = vec![0u8; 8192];
+        for (i, v) in page.chunks_mut(8).enumerate().take(300) {
+            v[0] = (i % 7) as u8 + 2;
+            v[1] = 0x12;
+        }
+        assert!(roundtrip(&page) < 8192 / 10);
+        // The match longer than 17 bytes and the match overlapping itself.
+        let d: Vec<u8> = b"abcdefghijklmnopqrstuvwxyz".repeat(20);
+        assert!(roundtrip(&d) < d.len() / 4);
+        assert!(roundtrip(&[7u8; 1000]) < 30);
+
+        // The input that can not be compressed enough.
+        assert!(compress(&[1u8; 31]).is_none());
+        let mut x = 20181218u32;
+        let noise: Vec<u8> = (0..4096)
+            .map(|_| {
+                // xorshift32
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            })
+            .collect();
+        assert!(compress(&noise).is_none());
+    }
+
+    #[test]
+    fn corrupted() {
+        let d = [5u8; 100];
+        let c = compress(&d).unwrap();
+        assert!(decompress(&c, 99).is_none());
+        assert!(decompress(&c, 101).is_none());
+        assert!(decompress(&c[..c.len() - 1], 100).is_none());
+        // The offset points to before the start of the output.
+        assert!(decompress(&[0x01, 0x00, 0x05], 3).is_none());
+        assert!(decompress(&[0x02, 0x41, 0x00, 0x02], 4).is_none());
+    }
+}
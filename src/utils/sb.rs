@@ -12,8 +12,11 @@
 use anyhow::bail;
 use std::collections::HashMap;
 use std::hash::Hash;
-use std::sync::atomic::{AtomicU32, Ordering::Acquire, Ordering::Relaxed, Ordering::Release};
-use std::sync::{RwLock, TryLockError};
+use std::sync::atomic::{
+    AtomicU32, AtomicU64, AtomicUsize, Ordering::Acquire, Ordering::Relaxed, Ordering::Release,
+};
+use std::sync::{Condvar, Mutex, RwLock, TryLockError};
+use std::time::Duration;
 
 pub trait SBK: Eq + Hash + Copy + std::fmt::Debug {}
 
@@ -48,6 +51,14 @@ pub struct SharedBuffer<V: Value, E: EvictPolicy> {
     dat: RwLock<(Map<V, E>, E)>,
     pub valctx: V::CommonData,
     cap: usize,
+    // blks_hit and blks_read of pg_stat_database.
+    hits: AtomicU64,
+    reads: AtomicU64,
+}
+
+pub struct Stats {
+    pub hits: u64,
+    pub reads: u64,
 }
 
 enum TryGetRet<'a, V: Value, E: EvictPolicy> {
@@ -80,13 +91,22 @@ impl<V: Value, E: EvictPolicy> Drop for SharedBuffer<V, E> {
     }
 }
 
-// TODO: Add prometheus metric and bgwriter thread. bgwriter thread will periodly flush dirty slot.
+// TODO: Add bgwriter thread. bgwriter thread will periodly flush dirty slot.
 impl<V: Value, E: EvictPolicy> SharedBuffer<V, E> {
     pub fn new(cap: usize, evict: E, valctx: V::CommonData) -> Self {
         Self {
             dat: RwLock::new((Map::with_capacity(cap), evict)),
             cap,
             valctx,
+            hits: AtomicU64::new(0),
+            reads: AtomicU64::new(0),
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            hits: self.hits.load(Relaxed),
+            reads: self.reads.load(Relaxed),
         }
     }
 
@@ -100,15 +120,12 @@ impl<V: Value, E: EvictPolicy> SharedBuffer<V, E> {
         self.pin_slot(v)
     }
 
-    // Without invoking on_use_slot().
+    // Without invoking on_use_slot(). The slot may be invalid, the key may have been evicted
+    // and loaded again by others, but an invalid slot is never dirty.
     fn find(&self, k: &V::K) -> Option<SlotPinGuard<V, E>> {
         let dat = self.dat.read().unwrap();
         let map = &dat.0;
-        map.get(k).map(|v| {
-            let (pinned_v, valid) = self.pin_slot(v);
-            debug_assert!(valid);
-            SlotPinGuard(pinned_v)
-        })
+        map.get(k).map(|v| SlotPinGuard(self.pin_slot(v).0))
     }
 
     fn get_dirty_keys(&self) -> Vec<V::K> {
@@ -125,6 +142,12 @@ impl<V: Value, E: EvictPolicy> SharedBuffer<V, E> {
         return v;
     }
 
+    // pg_buffercache, the state of every slot, only for diagnosing the buffer pool.
+    pub fn dump(&self) -> Vec<SlotState<V::K>> {
+        let dat = self.dat.read().unwrap();
+        return dat.0.values().map(|slot| slot.dump()).collect();
+    }
+
     fn try_get(&self, k: &V::K) -> TryGetRet<V, E> {
         let dat = self.dat.read().unwrap();
         let partmap = &dat.0;
@@ -147,7 +170,12 @@ impl<V: Value, E: EvictPolicy> SharedBuffer<V, E> {
         return slotref;
     }
 
-    fn try_create(&self, k: &V::K, evict: Option<&Slot<V, E>>) -> (Option<&Slot<V, E>>, bool) {
+    // The pin on evict is released if it is not removed.
+    fn try_create(
+        &self,
+        k: &V::K,
+        evict: Option<SlotPinGuard<V, E>>,
+    ) -> (Option<&Slot<V, E>>, bool) {
         let mut dat = self.dat.write().unwrap();
         if let Some(v) = dat.0.get(k) {
             let ret = self.use_slot(&dat.1, &v);
@@ -158,7 +186,10 @@ impl<V: Value, E: EvictPolicy> SharedBuffer<V, E> {
         }
         if let Some(evict) = evict {
             if evict.canremove() {
-                let evict = dat.0.remove(&evict.k).unwrap();
+                let evictk = evict.k;
+                // The slot is dropped with our pin.
+                std::mem::forget(evict);
+                let evict = dat.0.remove(&evictk).unwrap();
                 dat.1.on_drop_slot(&evict.k, &evict.evict);
                 let retslot = self.create_slot(&mut dat, k);
                 std::mem::drop(dat);
@@ -183,21 +214,15 @@ impl<V: Value, E: EvictPolicy> SharedBuffer<V, E> {
                 TryGetRet::Evict(None, _) => {
                     bail!("no unpinned buffers available. key={:?}", k);
                 }
-                TryGetRet::Evict(Some(s), state) => (Some(s), state),
-                TryGetRet::HasIdleSlot => (None, 0),
+                TryGetRet::Evict(Some(s), state) => Some((SlotPinGuard(s), state)),
+                TryGetRet::HasIdleSlot => None,
             };
-            // evict_slot is pinned.
-            match evict_slot {
-                (Some(evict_slot), state) if dirty(state) => {
-                    let _d = SlotPinGuard(evict_slot);
-                    if !evict_slot.try_flush(&self.valctx)? {
-                        continue;
-                    }
-                    std::mem::forget(_d);
+            if let Some((ref evict_slot, state)) = evict_slot {
+                if dirty(state) && !evict_slot.try_flush(&self.valctx)? {
+                    continue;
                 }
-                _ => {}
-            };
-            if let (Some(s), valid) = self.try_create(k, evict_slot.0) {
+            }
+            if let (Some(s), valid) = self.try_create(k, evict_slot.map(|v| v.0)) {
                 return Ok((s, valid));
             }
         }
@@ -206,11 +231,14 @@ impl<V: Value, E: EvictPolicy> SharedBuffer<V, E> {
     pub fn read(&self, k: &V::K, loadctx: &V::LoadCtx) -> anyhow::Result<SlotPinGuard<V, E>> {
         let (slot, valid) = self.get(k)?;
         if valid {
+            self.hits.fetch_add(1, Relaxed);
             return Ok(SlotPinGuard(slot));
         }
         if !slot.startio(true) {
+            self.hits.fetch_add(1, Relaxed);
             return Ok(SlotPinGuard(slot));
         }
+        self.reads.fetch_add(1, Relaxed);
         match V::load(k, loadctx, &self.valctx) {
             Ok(v) => {
                 slot.setv(v);
@@ -252,6 +280,53 @@ const SLOT_VALID: u32 = 1 << 24;
 const SLOT_IO_INPROGRESS: u32 = 1 << 26;
 const SLOT_IO_ERR: u32 = 1 << 27;
 const SLOT_JUST_DIRTIED: u32 = 1 << 28;
+// Someone waits on io_cv for the io in progress.
+const SLOT_IO_WAITER: u32 = 1 << 29;
+
+// See s_lock.c, spin SPINS_PER_DELAY times before sleeping, the sleep time increases by a
+// random fraction between 1X and 2X, and wraps back to MIN_DELAY when MAX_DELAY is exceeded.
+const SPINS_PER_DELAY: u32 = 100;
+const NUM_DELAYS: u32 = 1000;
+const MIN_DELAY: Duration = Duration::from_millis(1);
+const MAX_DELAY: Duration = Duration::from_secs(1);
+
+// SpinDelayStatus
+struct SpinDelay<'a, K: SBK> {
+    k: &'a K,
+    spins: u32,
+    delays: u32,
+    cur_delay: Duration,
+}
+
+impl<'a, K: SBK> SpinDelay<'a, K> {
+    fn new(k: &'a K) -> Self {
+        Self {
+            k,
+            spins: 0,
+            delays: 0,
+            cur_delay: MIN_DELAY,
+        }
+    }
+
+    // perform_spin_delay
+    fn delay(&mut self) {
+        std::hint::spin_loop();
+        self.spins += 1;
+        if self.spins < SPINS_PER_DELAY {
+            return;
+        }
+        self.spins = 0;
+        self.delays += 1;
+        if self.delays > NUM_DELAYS {
+            panic!("stuck spinlock detected. key={:?}", self.k);
+        }
+        std::thread::sleep(self.cur_delay);
+        self.cur_delay = self.cur_delay.mul_f64(1.0 + rand::random::<f64>());
+        if self.cur_delay > MAX_DELAY {
+            self.cur_delay = MIN_DELAY;
+        }
+    }
+}
 
 fn biton(state: u32, bit: u32) -> bool {
     (state & bit) != 0
@@ -277,6 +352,10 @@ fn io_in_progress(state: u32) -> bool {
     biton(state, SLOT_IO_INPROGRESS)
 }
 
+fn io_waiter(state: u32) -> bool {
+    biton(state, SLOT_IO_WAITER)
+}
+
 fn locked(state: u32) -> bool {
     biton(state, SLOT_LOCKED)
 }
@@ -285,11 +364,32 @@ fn valid(state: u32) -> bool {
     biton(state, SLOT_VALID)
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlotState<K> {
+    pub k: K,
+    pub rc: u32,
+    pub dirty: bool,
+    pub valid: bool,
+    pub io_in_progress: bool,
+    pub io_err: bool,
+}
+
+// The lock order is Slot::v, SharedBuffer::dat, Slot::io_mutex, then the slot state
+// lock(SLOT_LOCKED).
+// - The state lock is a spinlock, nothing is acquired or waited while holding it, so
+//   mark_dirty() can be called with Slot::v held.
+// - dat is never held while acquiring Slot::v, so other keys can be read with Slot::v held.
+//   The evicting thread only try_read() the victim after releasing dat.
+// - do_flush() waits for the io in progress with the Slot::v read lock held, the io is either
+//   an output done with the read lock, or an input of an invalid slot which is never dirty.
 pub struct Slot<V: Value, E: EvictPolicy> {
     pub k: V::K,
     pub v: RwLock<Option<V>>, // Use MaybeUninit when assume_init_ref is stable.
     state: AtomicU32,
     evict: E::Data,
+    // BufferDescriptorGetIOCV, signaled when the io ends if SLOT_IO_WAITER is set.
+    io_mutex: Mutex<()>,
+    io_cv: Condvar,
 }
 
 struct SlotLockGuard<'a, V: Value, E: EvictPolicy> {
@@ -310,6 +410,8 @@ impl<V: Value, E: EvictPolicy> Slot<V, E> {
             v: RwLock::new(None),
             state: AtomicU32::new(REFCOUNT_ONE), // pinned
             evict,
+            io_mutex: Mutex::new(()),
+            io_cv: Condvar::new(),
         }
     }
 
@@ -368,10 +470,11 @@ impl<V: Value, E: EvictPolicy> Slot<V, E> {
     // lock()/unlock() does not use acquire/release semantics,
     // so do not use it for synchronization
     fn lock(&self) -> SlotLockGuard<V, E> {
+        let mut delay = SpinDelay::new(&self.k);
         loop {
             let state = self.state.fetch_or(SLOT_LOCKED, Acquire);
             if locked(state) {
-                std::hint::spin_loop(); // Use a more adaptive approach.
+                delay.delay();
             } else {
                 return SlotLockGuard {
                     slot: self,
@@ -382,9 +485,10 @@ impl<V: Value, E: EvictPolicy> Slot<V, E> {
     }
 
     fn wait(&self) -> u32 {
+        let mut delay = SpinDelay::new(&self.k);
         let mut state = self.get_state();
         while locked(state) {
-            std::hint::spin_loop(); // Use a more adaptive approach.
+            delay.delay();
             state = self.get_state();
         }
         return state;
@@ -452,12 +556,30 @@ impl<V: Value, E: EvictPolicy> Slot<V, E> {
         self.lock().state
     }
 
+    fn dump(&self) -> SlotState<V::K> {
+        let state = self.locked_state();
+        return SlotState {
+            k: self.k,
+            rc: rc(state),
+            dirty: dirty(state),
+            valid: valid(state),
+            io_in_progress: io_in_progress(state),
+            io_err: ioerr(state),
+        };
+    }
+
+    // WaitIO, SLOT_IO_WAITER is set with io_mutex held, so endio() can not notify before we wait.
     fn waitio(&self) {
+        let mut waitguard = self.io_mutex.lock().unwrap();
         loop {
-            if !io_in_progress(self.locked_state()) {
-                return;
+            {
+                let mut guard = self.lock();
+                if !io_in_progress(guard.state) {
+                    return;
+                }
+                guard.state |= SLOT_IO_WAITER;
             }
-            std::thread::yield_now(); // Use Semaphore?
+            waitguard = self.io_cv.wait(waitguard).unwrap();
         }
     }
 
@@ -496,12 +618,20 @@ impl<V: Value, E: EvictPolicy> Slot<V, E> {
     }
 
     fn endio(&self, clear_dirty: bool, set_flag_bits: u32) {
-        let mut guard = self.lock();
-        guard.state &= !(SLOT_IO_INPROGRESS | SLOT_IO_ERR);
-        if clear_dirty && !just_dirtied(guard.state) {
-            guard.state &= !SLOT_DIRTY;
+        let has_waiter = {
+            let mut guard = self.lock();
+            let has_waiter = io_waiter(guard.state);
+            guard.state &= !(SLOT_IO_INPROGRESS | SLOT_IO_ERR | SLOT_IO_WAITER);
+            if clear_dirty && !just_dirtied(guard.state) {
+                guard.state &= !SLOT_DIRTY;
+            }
+            guard.state |= set_flag_bits;
+            has_waiter
+        };
+        if has_waiter {
+            let _waitguard = self.io_mutex.lock().unwrap();
+            self.io_cv.notify_all();
         }
-        guard.state |= set_flag_bits;
         return;
     }
 }
@@ -560,8 +690,341 @@ pub fn new_fifo_sb<V: Value>(cap: usize, valctx: V::CommonData) -> SharedBuffer<
     SharedBuffer::new(cap, FIFOPolicy::new(), valctx)
 }
 
-// TODO: Implement the real LRUPolicy based on the method in slru.rs.
-pub type LRUPolicy = FIFOPolicy;
+// BM_MAX_USAGE_COUNT
+const MAX_USAGE_COUNT: u32 = 5;
+
+// The clock sweep of the buffer manager of PostgreSQL. Each slot has the usage count bumped by
+// every use, the hand sweeps the slots in the order of the map and decrements the count of the
+// unpinned ones, the first unpinned slot whose count is zero is evicted.
+pub struct ClockSweepPolicy {
+    // nextVictimBuffer, the position of the hand in the order of the map. The order changes
+    // when a slot is created or dropped, then the hand just points to some other slot.
+    hand: AtomicUsize,
+}
+
+impl ClockSweepPolicy {
+    fn new() -> Self {
+        Self {
+            hand: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl EvictPolicy for ClockSweepPolicy {
+    type Data = AtomicU32; // usage_count
+
+    fn on_create_slot<K: SBK>(&mut self, _k: &K) -> Self::Data {
+        AtomicU32::new(1)
+    }
+    fn on_use_slot<K: SBK>(&self, _k: &K, s: &Self::Data) {
+        let _ = s.fetch_update(Relaxed, Relaxed, |v| {
+            if v < MAX_USAGE_COUNT {
+                Some(v + 1)
+            } else {
+                None
+            }
+        });
+    }
+    fn on_drop_slot<K: SBK>(&mut self, _k: &K, _s: &Self::Data) {}
+    // StrategyGetBuffer, fails if a whole round finds all slots pinned.
+    fn evict_cand<'a, V: Value>(
+        &self,
+        part: &'a Map<V, Self>,
+        _newk: &V::K,
+    ) -> (Option<&'a Slot<V, Self>>, u32) {
+        let nslots = part.len();
+        if nslots == 0 {
+            return (None, 0);
+        }
+        let start = self.hand.load(Relaxed) % nslots;
+        let mut trycounter = nslots;
+        for (idx, (_, slot)) in part.iter().cycle().skip(start).enumerate() {
+            let lguard = slot.lock();
+            if rc(lguard.state) == 0 {
+                let decremented = slot
+                    .evict
+                    .fetch_update(Relaxed, Relaxed, |v| v.checked_sub(1));
+                if decremented.is_err() {
+                    self.hand.store((start + idx + 1) % nslots, Relaxed);
+                    let state = slot.pin_locked(lguard);
+                    return (Some(slot.as_ref()), state);
+                }
+                trycounter = nslots;
+            } else {
+                trycounter -= 1;
+                if trycounter == 0 {
+                    break;
+                }
+            }
+        }
+        return (None, 0);
+    }
+}
+
+pub type LRUPolicy = ClockSweepPolicy;
 pub fn new_lru_sb<V: Value>(cap: usize, valctx: V::CommonData) -> SharedBuffer<V, LRUPolicy> {
     SharedBuffer::new(cap, LRUPolicy::new(), valctx)
 }
+
+#[cfg(test)]
+mod sb_test {
+    use super::{new_lru_sb, FIFOPolicy, Slot, SlotState, Value};
+    use nix::time::{clock_gettime, ClockId};
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    struct Val;
+
+    impl Value for Val {
+        type LoadCtx = ();
+        type CommonData = ();
+        type K = u32;
+
+        fn load(_k: &u32, _ctx: &(), _dat: &Self::CommonData) -> anyhow::Result<Self> {
+            Ok(Val)
+        }
+
+        fn store(&self, _k: &u32, _dat: &Self::CommonData, _force: bool) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn dump() {
+        let sb = new_lru_sb::<Val>(32, ());
+        let state = |k: u32, rc: u32, dirty: bool| SlotState {
+            k,
+            rc,
+            dirty,
+            valid: true,
+            io_in_progress: false,
+            io_err: false,
+        };
+        let dump = || {
+            let mut slots = sb.dump();
+            slots.sort_by_key(|s| s.k);
+            slots
+        };
+        let pinned = sb.read(&1, &()).unwrap();
+        pinned.mark_dirty();
+        let pinned2 = sb.read(&1, &()).unwrap();
+        sb.read(&2, &()).unwrap();
+        assert_eq!(dump(), [state(1, 2, true), state(2, 0, false)]);
+
+        sb.flushall(true).unwrap();
+        std::mem::drop(pinned2);
+        assert_eq!(dump(), [state(1, 1, false), state(2, 0, false)]);
+        std::mem::drop(pinned);
+        assert_eq!(dump(), [state(1, 0, false), state(2, 0, false)]);
+    }
+
+    #[test]
+    fn clock_sweep() {
+        const CAP: u32 = 4;
+        const HOT: u32 = 0;
+        let sb = new_lru_sb::<Val>(CAP as usize, ());
+        let keys = || {
+            let mut keys: Vec<_> = sb.dump().iter().map(|s| s.k).collect();
+            keys.sort_unstable();
+            keys
+        };
+        for k in 0..CAP {
+            sb.read(&k, &()).unwrap();
+        }
+        // The hot key is the oldest one, it survives since it is used more often than the cold
+        // ones evicted by each other.
+        for cold in CAP..CAP * 10 {
+            sb.read(&HOT, &()).unwrap();
+            sb.read(&HOT, &()).unwrap();
+            sb.read(&cold, &()).unwrap();
+            let keys = keys();
+            assert_eq!(keys.len(), CAP as usize);
+            assert!(keys.contains(&HOT), "cold={} keys={:?}", cold, keys);
+            assert!(keys.contains(&cold), "cold={} keys={:?}", cold, keys);
+        }
+        // The hot key is evicted once it is no longer used.
+        for cold in CAP * 10..CAP * 20 {
+            sb.read(&cold, &()).unwrap();
+        }
+        assert!(!keys().contains(&HOT), "keys={:?}", keys());
+
+        // The pinned slots are never evicted.
+        let pinned: Vec<_> = keys().iter().map(|k| sb.read(k, &()).unwrap()).collect();
+        let err = sb.read(&HOT, &()).err().unwrap();
+        assert!(
+            err.to_string().contains("no unpinned buffers"),
+            "err={}",
+            err
+        );
+        std::mem::drop(pinned);
+        sb.read(&HOT, &()).unwrap();
+        assert!(keys().contains(&HOT));
+    }
+
+    fn thread_cputime() -> Duration {
+        Duration::from(clock_gettime(ClockId::CLOCK_THREAD_CPUTIME_ID).unwrap())
+    }
+
+    #[test]
+    fn lock_contention() {
+        const THREADS: usize = 8;
+        const HOLD: Duration = Duration::from_millis(200);
+        let slot = Slot::<Val, FIFOPolicy>::new(&1, 0);
+        let cputime = std::thread::scope(|s| {
+            let guard = slot.lock();
+            let waiters: Vec<_> = (0..THREADS)
+                .map(|idx| {
+                    let slot = &slot;
+                    s.spawn(move || {
+                        let start = thread_cputime();
+                        if idx % 2 == 0 {
+                            slot.lock();
+                        } else {
+                            slot.pin();
+                        }
+                        thread_cputime() - start
+                    })
+                })
+                .collect();
+            std::thread::sleep(HOLD);
+            std::mem::drop(guard);
+            let cputime: Vec<_> = waiters.into_iter().map(|w| w.join().unwrap()).collect();
+            cputime.into_iter().sum::<Duration>()
+        });
+        assert_eq!(super::rc(slot.locked_state()), THREADS as u32 / 2 + 1);
+        // The waiters sleep instead of spinning during the whole HOLD.
+        assert!(cputime < HOLD / 4, "cputime={:?}", cputime);
+    }
+
+    // The load takes the CommonData long.
+    struct SlowLoad;
+
+    impl Value for SlowLoad {
+        type LoadCtx = ();
+        type CommonData = Duration;
+        type K = u32;
+
+        fn load(_k: &u32, _ctx: &(), dur: &Duration) -> anyhow::Result<Self> {
+            std::thread::sleep(*dur);
+            Ok(SlowLoad)
+        }
+
+        fn store(&self, _k: &u32, _dur: &Duration, _force: bool) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn waitio() {
+        const WAITERS: usize = 4;
+        const LOAD: Duration = Duration::from_millis(300);
+        let sb = new_lru_sb::<SlowLoad>(4, LOAD);
+        let (loaded, waiters) = std::thread::scope(|s| {
+            let loader = s.spawn(|| {
+                sb.read(&1, &()).unwrap();
+                Instant::now()
+            });
+            while !sb.dump().iter().any(|slot| slot.io_in_progress) {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            let waiters: Vec<_> = (0..WAITERS)
+                .map(|_| {
+                    s.spawn(|| {
+                        let start = thread_cputime();
+                        sb.read(&1, &()).unwrap();
+                        (thread_cputime() - start, Instant::now())
+                    })
+                })
+                .collect();
+            let loaded = loader.join().unwrap();
+            let waiters: Vec<_> = waiters.into_iter().map(|w| w.join().unwrap()).collect();
+            (loaded, waiters)
+        });
+        // The waiters sleep until the load ends instead of polling.
+        let cputime: Duration = waiters.iter().map(|w| w.0).sum();
+        assert!(cputime < LOAD / 4, "cputime={:?}", cputime);
+        for (_, woken) in waiters {
+            let latency = woken.saturating_duration_since(loaded);
+            assert!(latency < Duration::from_millis(50), "latency={:?}", latency);
+        }
+    }
+
+    // The counter of each key, only the stored counters are visible to load.
+    struct Counter(u64);
+
+    impl Value for Counter {
+        type LoadCtx = ();
+        type CommonData = &'static Mutex<HashMap<u32, u64>>;
+        type K = u32;
+
+        fn load(k: &u32, _ctx: &(), disk: &Self::CommonData) -> anyhow::Result<Self> {
+            Ok(Counter(disk.lock().unwrap().get(k).copied().unwrap_or(0)))
+        }
+
+        fn store(&self, k: &u32, disk: &Self::CommonData, _force: bool) -> anyhow::Result<()> {
+            disk.lock().unwrap().insert(*k, self.0);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn stress() {
+        const WORKERS: u64 = 4;
+        const KEYS: u64 = 16;
+        const LOOPS: u64 = 20000;
+        let disk: &'static Mutex<HashMap<u32, u64>> =
+            Box::leak(Box::new(Mutex::new(HashMap::new())));
+        // Every worker and the flusher pin at most one slot at a time.
+        let sb = new_lru_sb::<Counter>(WORKERS as usize + 2, disk);
+        let stop = AtomicBool::new(false);
+        let writes = std::thread::scope(|s| {
+            let flusher = s.spawn(|| {
+                let mut force = false;
+                while !stop.load(Ordering::Relaxed) {
+                    sb.flushall(force).unwrap();
+                    force = !force;
+                }
+            });
+            let workers: Vec<_> = (0..WORKERS)
+                .map(|worker| {
+                    let sb = &sb;
+                    s.spawn(move || {
+                        let mut seed = worker + 1;
+                        let mut writes = 0;
+                        for _ in 0..LOOPS {
+                            seed = seed
+                                .wrapping_mul(6364136223846793005)
+                                .wrapping_add(1442695040888963407);
+                            let k = ((seed >> 33) % KEYS) as u32;
+                            let slot = sb.read(&k, &()).unwrap();
+                            if (seed >> 20) % 4 == 0 {
+                                assert!(slot.v.read().unwrap().is_some());
+                                continue;
+                            }
+                            let mut v = slot.v.write().unwrap();
+                            v.as_mut().unwrap().0 += 1;
+                            slot.mark_dirty();
+                            writes += 1;
+                        }
+                        writes
+                    })
+                })
+                .collect();
+            // Stop the flusher even if some worker panicked.
+            let writes: Vec<_> = workers.into_iter().map(|w| w.join()).collect();
+            stop.store(true, Ordering::Relaxed);
+            flusher.join().unwrap();
+            writes
+        });
+        let writes: u64 = writes.into_iter().map(|w| w.unwrap()).sum();
+        sb.flushall(true).unwrap();
+        for slot in sb.dump() {
+            assert_eq!((slot.rc, slot.dirty), (0, false));
+        }
+        // No update is lost by eviction.
+        let stored: u64 = disk.lock().unwrap().values().sum();
+        assert_eq!(stored, writes);
+    }
+}
@@ -145,3 +145,24 @@ pub fn ser_be_i16(out: &mut Vec<u8>, val: i16) {
 pub fn ser_be_i16_at(out: &mut Vec<u8>, idx: usize, val: i16) {
     ser_be_at(out, idx, val);
 }
+
+// The wal records and the control file are in little-endian, so they can be read on any host.
+pub fn ser_le_u16(out: &mut Vec<u8>, val: u16) {
+    ser(out, val.to_le());
+}
+
+pub fn ser_le_u32(out: &mut Vec<u8>, val: u32) {
+    ser(out, val.to_le());
+}
+
+pub fn ser_le_u32_at(out: &mut Vec<u8>, idx: usize, val: u32) {
+    ser_at(out, idx, val.to_le());
+}
+
+pub fn ser_le_u64(out: &mut Vec<u8>, val: u64) {
+    ser(out, val.to_le());
+}
+
+pub fn ser_le_u64_at(out: &mut Vec<u8>, idx: usize, val: u64) {
+    ser_at(out, idx, val.to_le());
+}
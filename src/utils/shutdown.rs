@@ -0,0 +1,110 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::access::ckpt;
+use crate::utils::latch::Latch;
+use crate::GlobalState;
+use nix::errno::Errno;
+use nix::libc::c_int;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::thread;
+use std::time::Duration;
+
+// The latch set by SIGTERM, see statedump for why the handler only sets the latch.
+static SHUTDOWN_LATCH: AtomicPtr<Latch> = AtomicPtr::new(std::ptr::null_mut());
+
+extern "C" fn on_sigterm(_: c_int) {
+    let latch = SHUTDOWN_LATCH.load(Ordering::Relaxed);
+    if !latch.is_null() {
+        unsafe { &*latch }.set();
+    }
+}
+
+// Installs the SIGTERM handler, SIGTERM requests the smart shutdown just as PostgreSQL.
+pub fn install() -> nix::Result<&'static Latch> {
+    let latch: &'static Latch = Box::leak(Box::new(Latch::new()?));
+    SHUTDOWN_LATCH.store(latch as *const _ as *mut _, Ordering::Relaxed);
+    let action = SigAction::new(
+        SigHandler::Handler(on_sigterm),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    unsafe { sigaction(Signal::SIGTERM, &action) }?;
+    return Ok(latch);
+}
+
+// ServerLoop, returns None once the shutdown is requested.
+pub fn accept(listener: &TcpListener, latch: &Latch) -> Option<TcpStream> {
+    let mut fds = [
+        PollFd::new(listener.as_raw_fd(), PollFlags::POLLIN),
+        PollFd::new(latch.fd(), PollFlags::POLLIN),
+    ];
+    loop {
+        match poll(&mut fds, -1) {
+            Err(nix::Error::Sys(Errno::EINTR)) => continue,
+            ret => {
+                ret.unwrap();
+            }
+        }
+        if fds[1].revents().map_or(false, |r| !r.is_empty()) {
+            return None;
+        }
+        match listener.accept() {
+            Ok((stream, _)) => return Some(stream),
+            Err(err) => log::warn!("accept failed. err={}", err),
+        }
+    }
+}
+
+// The smart shutdown: waits for the sessions to exit and then writes the shutdown
+// checkpoint. The standby writes no wal, so there is nothing to checkpoint. The walsenders
+// are not waited for, they send the shutdown checkpoint to the standbys and then are
+// terminated, just as WalSndInitStopping() and WalSndWaitStopping().
+pub fn shutdown(state: &GlobalState) -> anyhow::Result<()> {
+    loop {
+        let nsess = state
+            .cancelmap
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|v| !v.walsender)
+            .count();
+        if nsess == 0 {
+            break;
+        }
+        log::debug!("shutdown: wait for the sessions. n={}", nsess);
+        thread::sleep(Duration::from_millis(100));
+    }
+    if state.wal.is_some() {
+        ckpt::shutdown_ckpt(state)?;
+    }
+    loop {
+        let nsess = {
+            let map = state.cancelmap.lock().unwrap();
+            for sess in map.values() {
+                sess.termreq.store(true, Ordering::Relaxed);
+                if let Some(latch) = &sess.latch {
+                    latch.set();
+                }
+            }
+            map.len()
+        };
+        if nsess == 0 {
+            break;
+        }
+        log::debug!("shutdown: wait for the walsenders. n={}", nsess);
+        thread::sleep(Duration::from_millis(100));
+    }
+    return Ok(());
+}
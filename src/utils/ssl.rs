@@ -0,0 +1,154 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// be-secure.c, the connection is in cleartext until the SSLRequest is accepted, then the
+// messages are read and written through the rustls server session.
+use crate::guc::{self, GucState};
+use anyhow::{anyhow, Context};
+use rustls::{Certificate, PrivateKey, ServerConfig, ServerConnection};
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Arc;
+
+// The reader and the writer of the session share the stream, so the TLS session is in a
+// RefCell, both of them are used in the thread of the session only.
+pub struct KBStream {
+    sock: TcpStream,
+    tls: RefCell<Option<ServerConnection>>,
+}
+
+impl KBStream {
+    pub fn new(sock: TcpStream) -> KBStream {
+        KBStream {
+            sock,
+            tls: RefCell::new(None),
+        }
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.sock.peer_addr()
+    }
+
+    pub fn is_tls(&self) -> bool {
+        self.tls.borrow().is_some()
+    }
+
+    // be_tls_open_server, the handshake is done before return.
+    pub fn start_tls(&self, config: Arc<ServerConfig>) -> anyhow::Result<()> {
+        debug_assert!(!self.is_tls());
+        let mut conn = ServerConnection::new(config)?;
+        let mut sock = &self.sock;
+        while conn.is_handshaking() {
+            let (rdlen, wrlen) = conn.complete_io(&mut sock)?;
+            if rdlen == 0 && wrlen == 0 && conn.wants_read() {
+                return Err(anyhow!("unexpected EOF during the SSL handshake"));
+            }
+        }
+        *self.tls.borrow_mut() = Some(conn);
+        return Ok(());
+    }
+
+    // The plaintext is buffered by the TLS session, the socket may be not readable then.
+    pub fn has_pending(&self) -> bool {
+        match self.tls.borrow().as_ref() {
+            None => false,
+            Some(conn) => !conn.wants_read(),
+        }
+    }
+
+    // be_tls_close, the close_notify alert is sent, the error is ignored just as SSL_shutdown.
+    pub fn close(&self) {
+        if let Some(conn) = self.tls.borrow_mut().as_mut() {
+            conn.send_close_notify();
+            let mut sock = &self.sock;
+            let _ = conn.write_tls(&mut sock);
+        }
+    }
+}
+
+impl AsRawFd for KBStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.sock.as_raw_fd()
+    }
+}
+
+impl Read for &KBStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut sock = &self.sock;
+        match self.tls.borrow_mut().as_mut() {
+            None => sock.read(buf),
+            Some(conn) => rustls::Stream::new(conn, &mut sock).read(buf),
+        }
+    }
+}
+
+impl Write for &KBStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut sock = &self.sock;
+        match self.tls.borrow_mut().as_mut() {
+            None => sock.write(buf),
+            Some(conn) => rustls::Stream::new(conn, &mut sock).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut sock = &self.sock;
+        match self.tls.borrow_mut().as_mut() {
+            None => sock.flush(),
+            Some(conn) => rustls::Stream::new(conn, &mut sock).flush(),
+        }
+    }
+}
+
+fn load_certs(path: &str) -> anyhow::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    if certs.is_empty() {
+        return Err(anyhow!("no certificate found"));
+    }
+    return Ok(certs.into_iter().map(Certificate).collect());
+}
+
+fn load_private_key(path: &str) -> anyhow::Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => {}
+        }
+    }
+    return Err(anyhow!("no private key found"));
+}
+
+// be_tls_init, None means the SSL is disabled and the SSLRequest is declined. The paths are
+// relative to the data directory.
+pub fn tls_init(gucstate: &GucState) -> anyhow::Result<Option<Arc<ServerConfig>>> {
+    let certfile = guc::get_str(gucstate, guc::SslCertFile);
+    let keyfile = guc::get_str(gucstate, guc::SslKeyFile);
+    if certfile.is_empty() && keyfile.is_empty() {
+        return Ok(None);
+    }
+    let certs = load_certs(certfile)
+        .with_context(|| format!("could not load server certificate file \"{}\"", certfile))?;
+    let key = load_private_key(keyfile)
+        .with_context(|| format!("could not load private key file \"{}\"", keyfile))?;
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("could not use the server certificate and private key")?;
+    return Ok(Some(Arc::new(config)));
+}
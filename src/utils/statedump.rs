@@ -0,0 +1,102 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::utils::latch::Latch;
+use crate::utils::sb::SlotState;
+use crate::GlobalState;
+use nix::libc::c_int;
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::thread;
+
+// The latch of the dumper thread, the signal handler only sets it since write(2) is
+// async-signal-safe while logging is not.
+static DUMP_LATCH: AtomicPtr<Latch> = AtomicPtr::new(std::ptr::null_mut());
+
+extern "C" fn on_sigusr1(_: c_int) {
+    let latch = DUMP_LATCH.load(Ordering::Relaxed);
+    if !latch.is_null() {
+        unsafe { &*latch }.set();
+    }
+}
+
+fn dump_slots<K>(name: &str, slots: &[SlotState<K>]) {
+    let valid = slots.iter().filter(|s| s.valid).count();
+    let dirty = slots.iter().filter(|s| s.dirty).count();
+    let pinned = slots.iter().filter(|s| s.rc > 0).count();
+    let io_in_progress = slots.iter().filter(|s| s.io_in_progress).count();
+    let io_err = slots.iter().filter(|s| s.io_err).count();
+    log::info!(
+        "state dump: buffer pool. name={} slots={} valid={} dirty={} pinned={} io_in_progress={} io_err={}",
+        name,
+        slots.len(),
+        valid,
+        dirty,
+        pinned,
+        io_in_progress,
+        io_err
+    );
+}
+
+// Logs a snapshot of the buffer pools, the lock table, the sessions and the wal positions.
+pub fn dump_state(state: &GlobalState) {
+    log::info!("state dump: begin");
+    dump_slots("tabsv", &state.tabsv.dump());
+    dump_slots("tabmvcc", &state.tabmvcc.dump());
+
+    let locks = state.lmgr.dump();
+    log::info!("state dump: locks. n={}", locks.len());
+    for lock in &locks {
+        log::info!(
+            "state dump: lock. tag={:?} granted={:?} waiters={:?}",
+            lock.tag,
+            lock.granted,
+            lock.waiters
+        );
+    }
+
+    let mut sessids: Vec<u32> = state.cancelmap.lock().unwrap().keys().copied().collect();
+    sessids.sort_unstable();
+    log::info!(
+        "state dump: sessions. n={} sessids={:?}",
+        sessids.len(),
+        sessids
+    );
+
+    match state.wal {
+        Some(wal) => log::info!(
+            "state dump: wal. insert_lsn={} flush_lsn={} stats={:?}",
+            wal.insert_lsn(),
+            wal.flush_lsn(),
+            wal.stats()
+        ),
+        None => log::info!("state dump: wal. disabled"),
+    }
+    log::info!("state dump: end");
+}
+
+// Installs the SIGUSR1 handler, the state is dumped to the log by a dedicated thread
+// each time the signal is received.
+pub fn start(state: GlobalState) -> nix::Result<()> {
+    let latch: &'static Latch = Box::leak(Box::new(Latch::new()?));
+    DUMP_LATCH.store(latch as *const _ as *mut _, Ordering::Relaxed);
+    thread::spawn(move || loop {
+        latch.wait();
+        latch.reset();
+        dump_state(&state);
+    });
+    let action = SigAction::new(
+        SigHandler::Handler(on_sigusr1),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    unsafe { sigaction(Signal::SIGUSR1, &action) }?;
+    return Ok(());
+}
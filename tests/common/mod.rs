@@ -0,0 +1,588 @@
+// Copyright 2020 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// The end-to-end test harness, TestServer runs initdb into a temp dir and launches kuiba on an
+// ephemeral port, Client is a minimal client of the PostgreSQL protocol.
+use kuiba::utils::auth::{md5_client_response, ScramClient};
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct TestServer {
+    child: Child,
+    // The datadir and the logs are in the tempdir, which is removed after the server is
+    // killed, see Drop.
+    tempdir: TempDir,
+    pub port: u16,
+    // The password of the superuser kuiba, required by connect() if it is set.
+    pub password: Option<String>,
+}
+
+pub fn pick_port() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().port()
+}
+
+impl TestServer {
+    pub fn start() -> TestServer {
+        let mut server = TestServer::spawn(&[]);
+        server.wait_ready();
+        return server;
+    }
+
+    // The server requiring the md5 authentication, the password of kuiba is password.
+    pub fn start_with_password(password: &str) -> TestServer {
+        return TestServer::start_with_auth(password, "md5");
+    }
+
+    // The server requiring the password, auth is both the auth_method and the
+    // encryption of the password stored by initdb.
+    pub fn start_with_auth(password: &str, auth: &str) -> TestServer {
+        let mut pwfile = tempfile::NamedTempFile::new().unwrap();
+        writeln!(pwfile, "{}", password).unwrap();
+        let pwarg = format!("--pwfile={}", pwfile.path().to_str().unwrap());
+        let autharg = format!("--auth={}", auth);
+        let conf = format!("auth_method: {}", auth);
+        let mut server = TestServer::spawn_with(&[&pwarg, &autharg], &[&conf]);
+        server.password = Some(password.to_string());
+        server.wait_ready();
+        return server;
+    }
+
+    // Starts the server with the extra lines of kuiba.conf, without waiting for it to be ready.
+    pub fn spawn(extra: &[&str]) -> TestServer {
+        TestServer::spawn_with(&[], extra)
+    }
+
+    // spawn() with the extra arguments of initdb.
+    pub fn spawn_with(initdb_args: &[&str], extra: &[&str]) -> TestServer {
+        let tempdir = tempfile::tempdir().unwrap();
+        let datadir = format!("{}/data", tempdir.path().to_str().unwrap());
+        let logpath = tempdir.path().join("initdb.log");
+        let status = Command::new(env!("CARGO_BIN_EXE_initdb"))
+            .args(initdb_args)
+            .arg(&datadir)
+            .stdout(Stdio::null())
+            .stderr(File::create(&logpath).unwrap())
+            .status()
+            .unwrap();
+        assert!(
+            status.success(),
+            "initdb failed. log={}",
+            std::fs::read_to_string(&logpath).unwrap_or_default()
+        );
+
+        let port = pick_port();
+        let mut conf = OpenOptions::new()
+            .append(true)
+            .open(format!("{}/kuiba.conf", datadir))
+            .unwrap();
+        writeln!(conf, "port: {}", port).unwrap();
+        for line in extra {
+            writeln!(conf, "{}", line).unwrap();
+        }
+        let child = TestServer::spawn_kuiba(&tempdir);
+        return TestServer {
+            child,
+            tempdir,
+            port,
+            password: None,
+        };
+    }
+
+    // The log is appended so that it covers all runs of the server.
+    fn spawn_kuiba(tempdir: &TempDir) -> Child {
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(tempdir.path().join("kuiba.log"))
+            .unwrap();
+        Command::new(env!("CARGO_BIN_EXE_kuiba"))
+            .arg("-D")
+            .arg(tempdir.path().join("data"))
+            .stdout(Stdio::null())
+            .stderr(log)
+            .spawn()
+            .unwrap()
+    }
+
+    // Kills the server and starts it again on the same datadir, like a crash.
+    pub fn restart(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        self.launch();
+    }
+
+    // Starts the server again after it exited.
+    pub fn launch(&mut self) {
+        self.child = TestServer::spawn_kuiba(&self.tempdir);
+        self.wait_ready();
+    }
+
+    // The smart shutdown, returns the exit status of the server.
+    pub fn shutdown(&mut self) -> ExitStatus {
+        kill(Pid::from_raw(self.pid() as i32), Signal::SIGTERM).unwrap();
+        return self.wait_exit();
+    }
+
+    // Waits for the server to exit, such as the crash at the crash point.
+    pub fn wait_exit(&mut self) -> ExitStatus {
+        let deadline = Instant::now() + STARTUP_TIMEOUT;
+        loop {
+            if let Some(status) = self.child.try_wait().unwrap() {
+                return status;
+            }
+            assert!(
+                Instant::now() < deadline,
+                "kuiba does not exit. log={}",
+                self.log()
+            );
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    pub fn datadir(&self) -> std::path::PathBuf {
+        self.tempdir.path().join("data")
+    }
+
+    // The server is ready once the connection is established, the port is bound during the
+    // redo but the connection is rejected then.
+    pub fn wait_ready(&mut self) {
+        let deadline = Instant::now() + STARTUP_TIMEOUT;
+        loop {
+            if TcpStream::connect(("127.0.0.1", self.port)).is_ok() {
+                let (client, msgs) = self.connect();
+                if msgs.last().map_or(false, |m| m.typ == b'Z') {
+                    client.terminate();
+                    return;
+                }
+            }
+            if let Some(status) = self.child.try_wait().unwrap() {
+                panic!("kuiba exited. status={} log={}", status, self.log());
+            }
+            assert!(
+                Instant::now() < deadline,
+                "kuiba is not ready. log={}",
+                self.log()
+            );
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    pub fn pid(&self) -> u32 {
+        self.child.id()
+    }
+
+    pub fn log(&self) -> String {
+        std::fs::read_to_string(self.tempdir.path().join("kuiba.log")).unwrap_or_default()
+    }
+
+    pub fn connect(&self) -> (Client, Vec<Message>) {
+        Client::connect_with_password(self.port, "kuiba", "kuiba", self.password.as_deref())
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[derive(Debug)]
+pub struct Message {
+    pub typ: u8,
+    pub body: Vec<u8>,
+}
+
+impl Message {
+    // The null-terminated strings of the body, such as the name and value of ParameterStatus.
+    pub fn cstrs(&self) -> Vec<String> {
+        let mut strs: Vec<_> = self
+            .body
+            .split(|&b| b == 0)
+            .map(|v| String::from_utf8_lossy(v).to_string())
+            .collect();
+        strs.pop();
+        return strs;
+    }
+
+    // The field of ErrorResponse, such as b'C' for the SQLSTATE code.
+    pub fn err_field(&self, field: u8) -> Option<String> {
+        let mut off = 0;
+        while off < self.body.len() && self.body[off] != 0 {
+            let end = off + self.body[off..].iter().position(|&b| b == 0).unwrap();
+            if self.body[off] == field {
+                return Some(String::from_utf8_lossy(&self.body[off + 1..end]).to_string());
+            }
+            off = end + 1;
+        }
+        return None;
+    }
+}
+
+// The stream of Client, in TLS if the SSLRequest is accepted.
+enum ClientStream {
+    Plain(TcpStream),
+    Tls(Box<rustls::StreamOwned<rustls::ClientConnection, TcpStream>>),
+}
+
+impl Read for ClientStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            ClientStream::Plain(v) => v.read(buf),
+            ClientStream::Tls(v) => v.read(buf),
+        }
+    }
+}
+
+impl Write for ClientStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            ClientStream::Plain(v) => v.write(buf),
+            ClientStream::Tls(v) => v.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            ClientStream::Plain(v) => v.flush(),
+            ClientStream::Tls(v) => v.flush(),
+        }
+    }
+}
+
+// The certificates used by the servers with SSL enabled, see ssl_conf().
+pub fn ssl_file(name: &str) -> String {
+    format!("{}/tests/ssl/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+// The kuiba.conf lines enabling SSL, server.crt is signed by root.crt for localhost.
+pub fn ssl_conf() -> Vec<String> {
+    vec![
+        format!("ssl_cert_file: {}", ssl_file("server.crt")),
+        format!("ssl_key_file: {}", ssl_file("server.key")),
+    ]
+}
+
+// Sends SSLRequest, the handshake is done if the server answers 'S', the server certificate is
+// verified with root.crt. Returns the stream and the answer of the server.
+fn ssl_request(mut stream: TcpStream) -> (ClientStream, u8) {
+    let mut req = 8u32.to_be_bytes().to_vec();
+    req.extend_from_slice(&80877103u32.to_be_bytes());
+    stream.write_all(&req).unwrap();
+    let mut answer = [0u8; 1];
+    stream.read_exact(&mut answer).unwrap();
+    if answer[0] != b'S' {
+        return (ClientStream::Plain(stream), answer[0]);
+    }
+    let mut roots = rustls::RootCertStore::empty();
+    let mut rootfile = BufReader::new(File::open(ssl_file("root.crt")).unwrap());
+    for cert in rustls_pemfile::certs(&mut rootfile).unwrap() {
+        roots.add(&rustls::Certificate(cert)).unwrap();
+    }
+    let config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let servername = "localhost".try_into().unwrap();
+    let mut conn = rustls::ClientConnection::new(Arc::new(config), servername).unwrap();
+    while conn.is_handshaking() {
+        conn.complete_io(&mut stream).unwrap();
+    }
+    let tls = rustls::StreamOwned::new(conn, stream);
+    return (ClientStream::Tls(Box::new(tls)), answer[0]);
+}
+
+// Sends CancelRequest on a new connection, the server closes it without any answer.
+pub fn cancel_request(port: u16, sessid: u32, key: u32) {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.set_read_timeout(Some(STARTUP_TIMEOUT)).unwrap();
+    let mut req = 16u32.to_be_bytes().to_vec();
+    req.extend_from_slice(&80877102u32.to_be_bytes());
+    req.extend_from_slice(&sessid.to_be_bytes());
+    req.extend_from_slice(&key.to_be_bytes());
+    stream.write_all(&req).unwrap();
+    let mut answer = Vec::new();
+    stream.read_to_end(&mut answer).unwrap();
+    assert!(answer.is_empty(), "answer={:?}", answer);
+}
+
+pub struct Client {
+    stream: BufReader<ClientStream>,
+    // The answer to SSLRequest, None if it is not sent.
+    pub ssl_answer: Option<u8>,
+}
+
+fn be_u32(d: &[u8]) -> u32 {
+    u32::from_be_bytes([d[0], d[1], d[2], d[3]])
+}
+
+impl Client {
+    // Returns the client and the messages before the first ReadyForQuery, inclusive.
+    pub fn connect(port: u16, user: &str, database: &str) -> (Client, Vec<Message>) {
+        Client::connect_with(port, user, database, &[])
+    }
+
+    // connect() with the extra startup parameters. The messages end with ErrorResponse if the
+    // server rejects the connection.
+    pub fn connect_with(
+        port: u16,
+        user: &str,
+        database: &str,
+        params: &[(&str, &str)],
+    ) -> (Client, Vec<Message>) {
+        Client::startup(port, user, database, params, None, false)
+    }
+
+    // connect() sending SSLRequest first, the connection proceeds in cleartext if the server
+    // declines it.
+    pub fn connect_ssl(port: u16, user: &str, database: &str) -> (Client, Vec<Message>) {
+        Client::startup(port, user, database, &[], None, true)
+    }
+
+    // connect() answering the md5 or SCRAM authentication with password. The messages end with
+    // AuthenticationMD5Password or AuthenticationSASL if password is None and the server
+    // requires it.
+    pub fn connect_with_password(
+        port: u16,
+        user: &str,
+        database: &str,
+        password: Option<&str>,
+    ) -> (Client, Vec<Message>) {
+        Client::startup(port, user, database, &[], password, false)
+    }
+
+    fn startup(
+        port: u16,
+        user: &str,
+        database: &str,
+        params: &[(&str, &str)],
+        password: Option<&str>,
+        ssl: bool,
+    ) -> (Client, Vec<Message>) {
+        let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.set_read_timeout(Some(STARTUP_TIMEOUT)).unwrap();
+        let (stream, ssl_answer) = if ssl {
+            let (stream, answer) = ssl_request(stream);
+            (stream, Some(answer))
+        } else {
+            (ClientStream::Plain(stream), None)
+        };
+        let mut body = Vec::new();
+        body.extend_from_slice(&196608u32.to_be_bytes());
+        let mut strs = vec!["user", user, "database", database];
+        for (name, val) in params {
+            strs.push(name);
+            strs.push(val);
+        }
+        strs.push("");
+        for s in strs {
+            body.extend_from_slice(s.as_bytes());
+            body.push(0);
+        }
+        let mut msg = ((body.len() + 4) as u32).to_be_bytes().to_vec();
+        msg.extend_from_slice(&body);
+        let mut client = Client {
+            stream: BufReader::new(stream),
+            ssl_answer,
+        };
+        client.stream.get_mut().write_all(&msg).unwrap();
+        let mut msgs = Vec::new();
+        let mut scram = password.map(ScramClient::new);
+        loop {
+            let msg = client.read_message();
+            let done = msg.typ == b'Z' || msg.typ == b'E';
+            let code = if msg.typ == b'R' {
+                be_u32(&msg.body[..4])
+            } else {
+                0
+            };
+            // AuthenticationMD5Password and AuthenticationSASL.
+            let asked = code == 5 || code == 10;
+            if let (Some(password), Some(scram)) = (password, &mut scram) {
+                let data = msg.body.get(4..).unwrap_or_default();
+                match code {
+                    5 => {
+                        let mut resp = md5_client_response(user, password, data).into_bytes();
+                        resp.push(0);
+                        client.send(b'p', &resp);
+                    }
+                    10 => {
+                        let mut resp = b"SCRAM-SHA-256\0".to_vec();
+                        let first = scram.client_first();
+                        resp.extend_from_slice(&(first.len() as u32).to_be_bytes());
+                        resp.extend_from_slice(first.as_bytes());
+                        client.send(b'p', &resp);
+                    }
+                    11 => {
+                        let server_first = std::str::from_utf8(data).unwrap();
+                        let resp = scram.client_final(server_first).unwrap();
+                        client.send(b'p', resp.as_bytes());
+                    }
+                    12 => scram
+                        .verify_server_final(std::str::from_utf8(data).unwrap())
+                        .unwrap(),
+                    _ => {}
+                }
+            }
+            msgs.push(msg);
+            if done || (asked && password.is_none()) {
+                return (client, msgs);
+            }
+        }
+    }
+
+    pub fn send(&mut self, typ: u8, body: &[u8]) {
+        let mut msg = vec![typ];
+        msg.extend_from_slice(&((body.len() + 4) as u32).to_be_bytes());
+        msg.extend_from_slice(body);
+        self.stream.get_mut().write_all(&msg).unwrap();
+    }
+
+    pub fn read_message(&mut self) -> Message {
+        let mut hdr = [0u8; 5];
+        self.stream.read_exact(&mut hdr).unwrap();
+        let len = be_u32(&hdr[1..]) as usize;
+        assert!(len >= 4, "invalid message length. hdr={:?}", hdr);
+        let mut body = vec![0u8; len - 4];
+        self.stream.read_exact(&mut body).unwrap();
+        return Message { typ: hdr[0], body };
+    }
+
+    pub fn read_until_ready(&mut self) -> Vec<Message> {
+        let mut msgs = Vec::new();
+        loop {
+            let msg = self.read_message();
+            let ready = msg.typ == b'Z';
+            msgs.push(msg);
+            if ready {
+                return msgs;
+            }
+        }
+    }
+
+    // The simple query protocol, returns the messages until ReadyForQuery, inclusive.
+    pub fn query(&mut self, sql: &str) -> Vec<Message> {
+        self.query_bytes(sql.as_bytes())
+    }
+
+    // query() in the client_encoding other than UTF8.
+    pub fn query_bytes(&mut self, sql: &[u8]) -> Vec<Message> {
+        let mut body = sql.to_vec();
+        body.push(0);
+        self.send(b'Q', &body);
+        return self.read_until_ready();
+    }
+
+    // The messages of the extended query protocol, the responses are read by read_until_ready()
+    // after sync().
+    pub fn parse(&mut self, name: &str, query: &str) {
+        let mut body = Vec::new();
+        for s in &[name, query] {
+            body.extend_from_slice(s.as_bytes());
+            body.push(0);
+        }
+        body.extend_from_slice(&0u16.to_be_bytes());
+        self.send(b'P', &body);
+    }
+
+    pub fn bind(&mut self, portal: &str, stmt: &str, resultformats: &[i16]) {
+        let mut body = Vec::new();
+        for s in &[portal, stmt] {
+            body.extend_from_slice(s.as_bytes());
+            body.push(0);
+        }
+        // No parameter formats and no parameters.
+        body.extend_from_slice(&[0, 0, 0, 0]);
+        body.extend_from_slice(&(resultformats.len() as u16).to_be_bytes());
+        for format in resultformats {
+            body.extend_from_slice(&format.to_be_bytes());
+        }
+        self.send(b'B', &body);
+    }
+
+    // kind is b'S' for the statement and b'P' for the portal.
+    pub fn describe(&mut self, kind: u8, name: &str) {
+        let mut body = vec![kind];
+        body.extend_from_slice(name.as_bytes());
+        body.push(0);
+        self.send(b'D', &body);
+    }
+
+    pub fn execute(&mut self, portal: &str, max_rows: i32) {
+        let mut body = portal.as_bytes().to_vec();
+        body.push(0);
+        body.extend_from_slice(&max_rows.to_be_bytes());
+        self.send(b'E', &body);
+    }
+
+    pub fn close(&mut self, kind: u8, name: &str) {
+        let mut body = vec![kind];
+        body.extend_from_slice(name.as_bytes());
+        body.push(0);
+        self.send(b'C', &body);
+    }
+
+    pub fn sync(&mut self) -> Vec<Message> {
+        self.send(b'S', &[]);
+        return self.read_until_ready();
+    }
+
+    pub fn terminate(mut self) {
+        self.send(b'X', &[]);
+    }
+}
+
+// The text values of the DataRow messages, None means null.
+pub fn data_rows(msgs: &[Message]) -> Vec<Vec<Option<String>>> {
+    return data_row_bytes(msgs)
+        .into_iter()
+        .map(|row| {
+            row.into_iter()
+                .map(|v| v.map(|v| String::from_utf8_lossy(&v).to_string()))
+                .collect()
+        })
+        .collect();
+}
+
+// data_rows() without the UTF-8 decoding.
+pub fn data_row_bytes(msgs: &[Message]) -> Vec<Vec<Option<Vec<u8>>>> {
+    let mut rows = Vec::new();
+    for msg in msgs.iter().filter(|m| m.typ == b'D') {
+        let ncols = u16::from_be_bytes([msg.body[0], msg.body[1]]);
+        let mut off = 2;
+        let mut row = Vec::new();
+        for _ in 0..ncols {
+            let len = be_u32(&msg.body[off..]) as i32;
+            off += 4;
+            if len < 0 {
+                row.push(None);
+                continue;
+            }
+            let end = off + len as usize;
+            row.push(Some(msg.body[off..end].to_vec()));
+            off = end;
+        }
+        rows.push(row);
+    }
+    return rows;
+}
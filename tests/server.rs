@@ -0,0 +1,1669 @@
+// Copyright 2020 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+mod common;
+
+use common::{data_row_bytes, data_rows, pick_port, Client, Message, TestServer};
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use std::convert::TryInto;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::os::unix::process::ExitStatusExt;
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[test]
+fn smoke() {
+    let server = TestServer::start();
+    let (mut client, msgs) = server.connect();
+    let types: Vec<_> = msgs.iter().map(|m| m.typ).collect();
+    assert_eq!(types.first(), Some(&b'R'));
+    assert_eq!(msgs[0].body, [0, 0, 0, 0]);
+    assert_eq!(&types[types.len() - 2..], b"KZ");
+    assert_eq!(msgs.last().unwrap().body, b"I");
+    let params: Vec<_> = msgs
+        .iter()
+        .filter(|m| m.typ == b'S')
+        .map(|m| m.cstrs())
+        .collect();
+    assert!(params.contains(&vec!["client_encoding".to_string(), "UTF8".to_string()]));
+    assert!(params.iter().any(|p| p[0] == "server_version"));
+
+    let msgs = client.query("select 1");
+    let types: Vec<_> = msgs.iter().map(|m| m.typ).collect();
+    assert_eq!(types, b"TDCZ", "log={}", server.log());
+    assert_eq!(data_rows(&msgs), [[Some("1".to_string())]]);
+    assert_eq!(msgs[2].cstrs(), ["SELECT 1"]);
+    client.terminate();
+}
+
+#[test]
+fn select_rows() {
+    let server = TestServer::start();
+    let (mut client, _) = server.connect();
+    let msgs = client.query("select 1 as a, 'abc' as b");
+    let types: Vec<_> = msgs.iter().map(|m| m.typ).collect();
+    assert_eq!(types, b"TDCZ", "log={}", server.log());
+    assert_eq!(
+        fields(&msgs),
+        [("a".to_string(), 23, 0), ("b".to_string(), 1043, 0)]
+    );
+    assert_eq!(
+        data_rows(&msgs),
+        [[Some("1".to_string()), Some("abc".to_string())]]
+    );
+    assert_eq!(msgs[2].cstrs(), ["SELECT 1"]);
+
+    client.query("create table sent(i int, j int)");
+    client.query("insert into sent values (1, null), (2, 3)");
+    let msgs = client.query("select i, j from sent");
+    let types: Vec<_> = msgs.iter().map(|m| m.typ).collect();
+    assert_eq!(types, b"TDDCZ", "log={}", server.log());
+    // NULL is sent as the length -1.
+    let row = &msgs[1].body;
+    assert_eq!(row[row.len() - 4..], (-1i32).to_be_bytes());
+    assert_eq!(
+        data_rows(&msgs),
+        [
+            [Some("1".to_string()), None],
+            [Some("2".to_string()), Some("3".to_string())]
+        ]
+    );
+    assert_eq!(msgs[3].cstrs(), ["SELECT 2"]);
+    client.terminate();
+}
+
+fn errcode(msgs: &[Message]) -> Option<String> {
+    msgs.iter()
+        .find(|m| m.typ == b'E')
+        .and_then(|m| m.err_field(b'C'))
+}
+
+#[test]
+fn client_encoding() {
+    let server = TestServer::start();
+    let (mut client, msgs) = Client::connect_with(
+        server.port,
+        "kuiba",
+        "kuiba",
+        &[("client_encoding", "latin1")],
+    );
+    assert_eq!(msgs.last().unwrap().typ, b'Z', "log={}", server.log());
+    let params: Vec<_> = msgs
+        .iter()
+        .filter(|m| m.typ == b'S')
+        .map(|m| m.cstrs())
+        .collect();
+    assert!(params.contains(&vec!["client_encoding".to_string(), "LATIN1".to_string()]));
+
+    let msgs = client.query_bytes(b"set search_path to 'sch\xe9ma'");
+    assert_eq!(errcode(&msgs), None, "log={}", server.log());
+    let msgs = client.query("show search_path");
+    assert_eq!(data_row_bytes(&msgs), [[Some(b"sch\xe9ma".to_vec())]]);
+    let msgs = client.query("set client_encoding to 'utf-8'");
+    assert_eq!(errcode(&msgs), None);
+    let msgs = client.query("show search_path");
+    assert_eq!(data_rows(&msgs), [[Some("schéma".to_string())]]);
+    let msgs = client.query("show client_encoding");
+    assert_eq!(data_rows(&msgs), [[Some("UTF8".to_string())]]);
+
+    // The euro sign is in WIN1252 but not in LATIN1.
+    client.query("set client_encoding to 'win1252'");
+    let msgs = client.query_bytes(b"set search_path to '\x80'");
+    assert_eq!(errcode(&msgs), None);
+    let msgs = client.query("show search_path");
+    assert_eq!(data_row_bytes(&msgs), [[Some(b"\x80".to_vec())]]);
+    // 0x81 is undefined in WIN1252, the session is still usable after the error.
+    let msgs = client.query_bytes(b"select '\x81'");
+    assert_eq!(errcode(&msgs).as_deref(), Some("22021"));
+    assert_eq!(msgs.last().unwrap().typ, b'Z');
+    client.query("set client_encoding to 'latin1'");
+    let msgs = client.query("show search_path");
+    assert_eq!(errcode(&msgs).as_deref(), Some("22P05"));
+    client.terminate();
+
+    let (_, msgs) = Client::connect_with(
+        server.port,
+        "kuiba",
+        "kuiba",
+        &[("client_encoding", "nosuch")],
+    );
+    assert_eq!(errcode(&msgs).as_deref(), Some("22023"));
+}
+
+#[test]
+fn client_encoding_mismatch() {
+    let server = TestServer::start();
+    let (_, msgs) = Client::connect_with(
+        server.port,
+        "kuiba",
+        "kuiba",
+        &[("client_encoding", "KOI8-R")],
+    );
+    let err = msgs.last().unwrap();
+    assert_eq!(err.typ, b'E');
+    assert_eq!(err.err_field(b'S').as_deref(), Some("FATAL"));
+    assert_eq!(err.err_field(b'C').as_deref(), Some("0A000"));
+    let errmsg = err.err_field(b'M').unwrap();
+    assert!(
+        errmsg.contains("conversion between KOI8R and UTF8 is not supported"),
+        "{}",
+        errmsg
+    );
+    assert!(errmsg.contains("client_encoding=KOI8-R"), "{}", errmsg);
+    assert!(errmsg.contains("server_encoding=UTF8"), "{}", errmsg);
+
+    // No client_encoding is sent, just as pgbench, the server encoding is used.
+    let (mut client, msgs) = Client::connect_with(server.port, "kuiba", "kuiba", &[]);
+    assert_eq!(msgs.last().unwrap().typ, b'Z');
+    let params: Vec<_> = msgs
+        .iter()
+        .filter(|m| m.typ == b'S')
+        .map(|m| m.cstrs())
+        .collect();
+    assert!(params.contains(&vec!["client_encoding".to_string(), "UTF8".to_string()]));
+    client.query("set search_path to 'schéma'");
+    let msgs = client.query("show search_path");
+    assert_eq!(data_rows(&msgs), [[Some("schéma".to_string())]]);
+    client.terminate();
+}
+
+#[test]
+fn min_messages() {
+    let server = TestServer::start();
+    let (mut client, _) = server.connect();
+    client.query("begin");
+    let msgs = client.query("begin");
+    let types: Vec<_> = msgs.iter().map(|m| m.typ).collect();
+    assert_eq!(types, b"NCZ");
+    assert_eq!(msgs[0].err_field(b'S').as_deref(), Some("WARNING"));
+    assert_eq!(msgs[0].err_field(b'C').as_deref(), Some("25001"));
+    client.query("commit");
+    client.query("set client_min_messages to 'error'");
+    let msgs = client.query("commit");
+    let types: Vec<_> = msgs.iter().map(|m| m.typ).collect();
+    assert_eq!(types, b"CZ");
+
+    // log_min_messages only affects the session which sets it.
+    client.query("set log_min_messages to 'ERROR'");
+    client.query("select 1234567");
+    let (mut other, _) = server.connect();
+    other.query("select 7654321");
+    let log = server.log();
+    assert!(!log.contains("select 1234567"), "{}", log);
+    assert!(log.contains("select 7654321"), "{}", log);
+    let msgs = client.query("show log_min_messages");
+    assert_eq!(data_rows(&msgs), [[Some("ERROR".to_string())]]);
+    client.terminate();
+    other.terminate();
+}
+
+#[test]
+fn state_dump() {
+    let server = TestServer::start();
+    let (mut client, _) = server.connect();
+    client.query("create table dumped(i int)");
+    client.query("begin");
+    let msgs = client.query("lock table dumped");
+    assert_eq!(errcode(&msgs), None);
+    let pid = Pid::from_raw(server.pid() as i32);
+    kill(pid, Signal::SIGUSR1).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while !server.log().contains("state dump: end") {
+        assert!(Instant::now() < deadline, "{}", server.log());
+        thread::sleep(Duration::from_millis(50));
+    }
+    let log = server.log();
+    for section in &[
+        "state dump: buffer pool. name=tabsv",
+        "state dump: buffer pool. name=tabmvcc",
+        "state dump: locks. n=",
+        "granted=[(\"AccessExclusiveLock\", 1)]",
+        "state dump: sessions. n=1",
+        "state dump: wal. insert_lsn=",
+    ] {
+        assert!(log.contains(section), "{} {}", section, log);
+    }
+    // The server is still serving after the dump.
+    let msgs = client.query("commit");
+    assert_eq!(errcode(&msgs), None);
+    client.terminate();
+}
+
+// Returns None if the health check endpoint is not listening yet.
+fn health_check(port: u16) -> Option<String> {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).ok()?;
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).unwrap();
+    return Some(resp);
+}
+
+#[test]
+fn health_check_ready() {
+    let health_port = pick_port();
+    // The restore_command is tried at the end of the local wal, which holds the redo.
+    let mut server = TestServer::spawn(&[
+        &format!("health_port: {}", health_port),
+        "restore_command: 'sleep 3; false'",
+    ]);
+    let deadline = Instant::now() + Duration::from_secs(30);
+    let resp = loop {
+        if let Some(resp) = health_check(health_port) {
+            break resp;
+        }
+        assert!(Instant::now() < deadline, "{}", server.log());
+        thread::sleep(Duration::from_millis(50));
+    };
+    assert_eq!(resp, "NOT_READY\n");
+    server.wait_ready();
+    while health_check(health_port).as_deref() != Some("OK\n") {
+        assert!(Instant::now() < deadline, "{}", server.log());
+        thread::sleep(Duration::from_millis(50));
+    }
+    let (client, _) = server.connect();
+    client.terminate();
+}
+
+#[test]
+fn reject_during_recovery() {
+    // The restore_command is tried at the end of the local wal, which holds the redo.
+    let mut server = TestServer::spawn(&["restore_command: 'sleep 3; false'"]);
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while TcpStream::connect(("127.0.0.1", server.port)).is_err() {
+        assert!(Instant::now() < deadline, "{}", server.log());
+        thread::sleep(Duration::from_millis(50));
+    }
+    let (_, msgs) = server.connect();
+    let err = msgs.last().unwrap();
+    assert_eq!(err.typ, b'E');
+    assert_eq!(err.err_field(b'S').as_deref(), Some("FATAL"));
+    assert_eq!(err.err_field(b'C').as_deref(), Some("57P03"));
+    let errmsg = err.err_field(b'M').unwrap();
+    assert!(
+        errmsg.starts_with("the database system is starting up"),
+        "{}",
+        errmsg
+    );
+    server.wait_ready();
+    let (client, msgs) = server.connect();
+    assert_eq!(errcode(&msgs), None);
+    client.terminate();
+}
+
+// Returns the body of GET path on the port.
+// The standby never writes wal, the writes are rejected before the xid or the oid is assigned,
+// and the session is still usable after that.
+#[test]
+fn standby_read_only() {
+    let mut server = TestServer::start();
+    let (mut client, _) = server.connect();
+    client.query("create table standby_t(i int)");
+    client.query("insert into standby_t values (1), (2)");
+    client.terminate();
+    let status = server.shutdown();
+    assert!(status.success(), "{} {}", status, server.log());
+    let mut conf = std::fs::OpenOptions::new()
+        .append(true)
+        .open(server.datadir().join("kuiba.conf"))
+        .unwrap();
+    writeln!(conf, "standby_mode: true").unwrap();
+    server.launch();
+
+    let (mut client, _) = server.connect();
+    for query in &[
+        "insert into standby_t values (3)",
+        "create table standby_w(i int)",
+        "drop table standby_t",
+        "vacuum standby_t",
+    ] {
+        let msgs = client.query(query);
+        assert_eq!(errcode(&msgs).as_deref(), Some("25006"), "query={}", query);
+    }
+    client.query("begin");
+    let msgs = client.query("insert into standby_t values (3)");
+    assert_eq!(errcode(&msgs).as_deref(), Some("25006"));
+    client.query("rollback");
+    let msgs = client.query("select i from standby_t");
+    assert_eq!(data_rows(&msgs), int_rows(&[&[Some(1)], &[Some(2)]]));
+    client.terminate();
+}
+
+fn http_get(port: u16, path: &str) -> (String, String) {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).unwrap();
+    let (head, body) = resp.split_at(resp.find("\r\n\r\n").unwrap() + 4);
+    return (head.to_string(), body.to_string());
+}
+
+// Returns the value of the sample of the metric, name includes the labels.
+fn metric(body: &str, name: &str) -> Option<f64> {
+    body.lines()
+        .find(|l| l.starts_with(name) && l[name.len()..].starts_with(' '))
+        .map(|l| l[name.len() + 1..].parse().unwrap())
+}
+
+#[test]
+fn metrics() {
+    let metrics_port = pick_port();
+    let mut server = TestServer::spawn(&[&format!("metrics_port: {}", metrics_port)]);
+    server.wait_ready();
+    let (mut client, _) = server.connect();
+    client.query("create table scraped(i int)");
+    client.query("insert into scraped values(1)");
+    client.query("begin");
+    client.query("rollback");
+
+    let (head, body) = http_get(metrics_port, "/metrics");
+    assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+    assert!(
+        head.contains("Content-Type: text/plain; version=0.0.4\r\n"),
+        "{}",
+        head
+    );
+    for line in body.lines() {
+        if line.starts_with('#') {
+            let mut parts = line.splitn(4, ' ');
+            assert!(
+                matches!(parts.nth(1), Some("HELP") | Some("TYPE")),
+                "{}",
+                line
+            );
+        } else {
+            let (name, val) = line.split_at(line.rfind(' ').unwrap());
+            assert!(name.starts_with("kuiba_"), "{}", line);
+            val.trim().parse::<f64>().unwrap();
+        }
+    }
+    assert!(
+        body.contains("# TYPE kuiba_xact_commits_total counter\n"),
+        "{}",
+        body
+    );
+    assert!(
+        metric(&body, "kuiba_xact_commits_total").unwrap() >= 2.0,
+        "{}",
+        body
+    );
+    assert!(
+        metric(&body, "kuiba_xact_aborts_total").unwrap() >= 1.0,
+        "{}",
+        body
+    );
+    // CREATE TABLE and the commits are logged, the commits are synced.
+    let records = metric(&body, "kuiba_wal_records_total").unwrap();
+    assert!(records >= 3.0, "{}", body);
+    let bytes = metric(&body, "kuiba_wal_bytes_total").unwrap();
+    assert!(bytes > records, "{}", body);
+    assert!(
+        metric(&body, "kuiba_wal_sync_total").unwrap() >= 1.0,
+        "{}",
+        body
+    );
+    assert!(metric(&body, "kuiba_wal_fpi_total").is_some(), "{}", body);
+    assert_eq!(metric(&body, "kuiba_connections"), Some(1.0), "{}", body);
+    let ratio = metric(&body, "kuiba_buffer_hit_ratio{pool=\"tabsv\"}").unwrap();
+    assert!((0.0..=1.0).contains(&ratio), "{}", body);
+    for name in &[
+        "kuiba_buffers_dirty{pool=\"tabsv\"}",
+        "kuiba_wal_write_lsn",
+        "kuiba_wal_flush_lsn",
+        "kuiba_locks",
+    ] {
+        assert!(metric(&body, name).is_some(), "{} {}", name, body);
+    }
+    let (head, _) = http_get(metrics_port, "/");
+    assert!(head.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", head);
+    client.terminate();
+}
+
+#[test]
+fn autoprewarm() {
+    let metrics_port = pick_port();
+    let mut server = TestServer::spawn(&[
+        "autoprewarm: true",
+        "autoprewarm_interval: 1",
+        &format!("metrics_port: {}", metrics_port),
+    ]);
+    server.wait_ready();
+    let (mut client, _) = server.connect();
+    // Only CREATE TABLE can be redone after the restart, so the table is left empty.
+    client.query("create table warm(i int)");
+    client.query("select * from warm");
+    client.terminate();
+
+    let prewarm_file = server.datadir().join("global/kb_prewarm");
+    let deadline = Instant::now() + Duration::from_secs(30);
+    loop {
+        let d = std::fs::read_to_string(&prewarm_file).unwrap_or_default();
+        if d.contains("tabsv ") && d.contains("tabmvcc ") {
+            break;
+        }
+        assert!(Instant::now() < deadline, "{:?} {}", d, server.log());
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    server.restart();
+    assert!(
+        server.log().contains("prewarm: loaded."),
+        "{}",
+        server.log()
+    );
+    let reads = |body: &str| metric(body, "kuiba_buffer_reads_total{pool=\"tabsv\"}").unwrap();
+    let hits = |body: &str| metric(body, "kuiba_buffer_hits_total{pool=\"tabsv\"}").unwrap();
+    let (_, before) = http_get(metrics_port, "/metrics");
+    assert!(reads(&before) > 0.0, "{}", before);
+    let (mut client, _) = server.connect();
+    let msgs = client.query("select * from warm");
+    assert!(data_rows(&msgs).is_empty());
+    client.terminate();
+    let (_, after) = http_get(metrics_port, "/metrics");
+    assert_eq!(reads(&after), reads(&before), "{} {}", before, after);
+    assert!(hits(&after) > hits(&before), "{} {}", before, after);
+}
+
+fn controldata(server: &TestServer) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_kb_controldata"))
+        .current_dir(server.datadir())
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    return String::from_utf8(output.stdout).unwrap();
+}
+
+#[test]
+fn shutdown_checkpoint() {
+    let mut server = TestServer::start();
+    let (mut client, _) = server.connect();
+    client.query("create table persisted(i int)");
+    client.query("insert into persisted values(1)");
+    client.terminate();
+    assert!(controldata(&server).contains("Database cluster state: in production\n"));
+
+    // The session is drained before the shutdown checkpoint.
+    let (idle, _) = server.connect();
+    let terminator = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(500));
+        idle.terminate();
+    });
+    let status = server.shutdown();
+    terminator.join().unwrap();
+    assert!(status.success(), "{} {}", status, server.log());
+    let log = server.log();
+    assert!(log.contains("shutdown checkpoint."), "{}", log);
+    assert!(log.contains("database system is shut down"), "{}", log);
+    let ctl = controldata(&server);
+    assert!(
+        ctl.contains("Database cluster state: shut down\n"),
+        "{}",
+        ctl
+    );
+
+    server.launch();
+    let log = server.log();
+    let log = &log[log.rfind("start redo.").unwrap()..];
+    assert!(
+        log.contains("skip redo after the clean shutdown."),
+        "{}",
+        log
+    );
+    assert!(!log.contains("end redo because of failed read"), "{}", log);
+    assert!(controldata(&server).contains("Database cluster state: in production\n"));
+    let (mut client, _) = server.connect();
+    let msgs = client.query("select * from persisted");
+    assert_eq!(data_rows(&msgs), vec![vec![Some("1".to_string())]]);
+    client.terminate();
+}
+
+// The start lsn of the wal files, in the ascending order.
+fn wal_files(server: &TestServer) -> Vec<u64> {
+    let mut lsns: Vec<_> = std::fs::read_dir(server.datadir().join("kb_wal"))
+        .unwrap()
+        .filter_map(|v| {
+            let name = v.unwrap().file_name().into_string().unwrap();
+            let lsn = name.strip_suffix(".wal")?.get(8..)?;
+            Some(u64::from_str_radix(lsn, 16).unwrap())
+        })
+        .collect();
+    lsns.sort_unstable();
+    return lsns;
+}
+
+fn replication_cmd(server: &TestServer, cmd: &str) {
+    let (mut client, msgs) =
+        Client::connect_with(server.port, "kuiba", "kuiba", &[("replication", "true")]);
+    assert_eq!(errcode(&msgs), None, "{}", server.log());
+    let msgs = client.query(cmd);
+    assert_eq!(errcode(&msgs), None, "cmd={} {}", cmd, server.log());
+    client.terminate();
+}
+
+// The restart_lsn persisted in kb_replslot/name.
+fn slot_lsn(server: &TestServer, name: &str) -> u64 {
+    let slotfile = server.datadir().join("kb_replslot").join(name);
+    let data = std::fs::read(&slotfile).unwrap();
+    return u64::from_le_bytes(data[..8].try_into().unwrap());
+}
+
+// The smart shutdown does not wait for the replication connections, the streaming walsender
+// sends the shutdown checkpoint before it is terminated.
+#[test]
+fn shutdown_walsender() {
+    let mut server = TestServer::start();
+    let replconn = || {
+        let (client, msgs) =
+            Client::connect_with(server.port, "kuiba", "kuiba", &[("replication", "true")]);
+        assert_eq!(errcode(&msgs), None, "{}", server.log());
+        client
+    };
+    let mut idle = replconn();
+    let mut streaming = replconn();
+    let msgs = streaming.query("IDENTIFY_SYSTEM");
+    let xlogpos = data_rows(&msgs)[0][2].clone().unwrap();
+    streaming.send(b'Q', format!("START_REPLICATION {}\0", xlogpos).as_bytes());
+    assert_eq!(streaming.read_message().typ, b'W');
+    let status = server.shutdown();
+    assert!(status.success(), "{} {}", status, server.log());
+
+    // XLogData is 'w', the start, the walend, the sendtime and the wal.
+    let mut walend = 0;
+    let err = loop {
+        let msg = streaming.read_message();
+        if msg.typ != b'd' {
+            break msg;
+        }
+        if msg.body[0] == b'w' {
+            walend = u64::from_be_bytes(msg.body[9..17].try_into().unwrap());
+        }
+    };
+    assert_eq!(err.err_field(b'C').as_deref(), Some("57P01"));
+    let ctl = controldata(&server);
+    let prefix = "Latest checkpoint location: ";
+    let ckpt = ctl.lines().find_map(|l| l.strip_prefix(prefix)).unwrap();
+    assert!(walend > ckpt.parse().unwrap(), "walend={} {}", walend, ctl);
+    let err = idle.read_message();
+    assert_eq!(err.err_field(b'C').as_deref(), Some("57P01"));
+}
+
+// The shutdown checkpoint removes the wal files before its redo, except those still needed by
+// the replication slot.
+#[test]
+fn slot_keeps_wal() {
+    let mut server = TestServer::spawn(&["wal_file_max_size: 4096"]);
+    server.wait_ready();
+    replication_cmd(&server, "CREATE_REPLICATION_SLOT slot_keeps_wal PHYSICAL");
+    let slotlsn = slot_lsn(&server, "slot_keeps_wal");
+    let (mut client, _) = server.connect();
+    client.query("create table slot_t(i int)");
+    for i in 0..64 {
+        client.query(&format!("insert into slot_t values ({})", i));
+    }
+    client.terminate();
+    let status = server.shutdown();
+    assert!(status.success(), "{} {}", status, server.log());
+    let files = wal_files(&server);
+    assert!(files[0] <= slotlsn, "{:?} {}", files, slotlsn);
+    assert!(files.len() > 2, "{:?}", files);
+
+    server.launch();
+    replication_cmd(&server, "DROP_REPLICATION_SLOT slot_keeps_wal");
+    let status = server.shutdown();
+    assert!(status.success(), "{} {}", status, server.log());
+    let files = wal_files(&server);
+    assert!(files[0] > slotlsn, "{:?} {}", files, slotlsn);
+    server.launch();
+    let (mut client, _) = server.connect();
+    let msgs = client.query("select count(*) from slot_t");
+    assert_eq!(data_rows(&msgs), [[Some("64".to_string())]]);
+    client.terminate();
+}
+
+fn waldump(server: &TestServer, args: &[&str]) -> (bool, String, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_kb_waldump"))
+        .arg("-D")
+        .arg(server.datadir())
+        .args(args)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    return (output.status.success(), stdout, stderr);
+}
+
+#[test]
+fn kb_waldump() {
+    let mut server = TestServer::start();
+    // The wal before the shutdown checkpoint is removed unless the slot keeps it.
+    replication_cmd(&server, "CREATE_REPLICATION_SLOT kb_waldump PHYSICAL");
+    let slotlsn = slot_lsn(&server, "kb_waldump");
+    let redo = &slotlsn.to_string();
+    let (mut client, _) = server.connect();
+    client.query("create table dumped(i int)");
+    client.terminate();
+    let status = server.shutdown();
+    assert!(status.success(), "{} {}", status, server.log());
+
+    // The dump stops at the end of wal instead of failing.
+    let (ok, out, err) = waldump(&server, &["--start", redo]);
+    assert!(ok, "{} {}", out, err);
+    assert!(err.starts_with("kb_waldump: end of wal at "), "{}", err);
+    let lines: Vec<_> = out.lines().collect();
+    let start = format!(" lsn: {:X}/{:X} ", slotlsn >> 32, slotlsn as u32);
+    assert!(lines[0].contains(&start), "{}", out);
+    assert!(lines.iter().any(|l| l.contains("desc: CREATE_TABLE db=")));
+    assert!(lines.iter().any(|l| l.contains("desc: COMMIT ")));
+    let last = lines.last().unwrap();
+    assert!(last.contains("desc: CHECKPOINT_SHUTDOWN "), "{}", out);
+    let lsn = |line: &str| {
+        line.split(" lsn: ")
+            .nth(1)
+            .unwrap()
+            .split(' ')
+            .next()
+            .unwrap()
+            .to_string()
+    };
+
+    let (ok, xact, _) = waldump(&server, &["--start", redo, "-r", "transaction"]);
+    assert!(ok);
+    assert!(!xact.is_empty());
+    assert!(
+        xact.lines().all(|l| l.starts_with("rmgr: Transaction ")),
+        "{}",
+        xact
+    );
+    let (ok, two, _) = waldump(&server, &["-s", redo, "-r", "sv", "-r", "Transaction"]);
+    assert!(ok);
+    assert!(two.lines().count() > xact.lines().count(), "{}", two);
+
+    // The end is exclusive, and it is an error if the records before the end can not be read.
+    let (ok, before, err) = waldump(&server, &["-s", redo, "-e", &lsn(last)]);
+    assert!(ok, "{}", err);
+    assert_eq!(before.lines().count(), lines.len() - 1);
+    let (ok, _, err) = waldump(&server, &["-s", redo, "-e", "FF/0"]);
+    assert!(!ok);
+    assert!(err.starts_with("kb_waldump: end of wal at "), "{}", err);
+}
+
+fn show(client: &mut Client, name: &str) -> String {
+    let msgs = client.query(&format!("show {}", name));
+    return data_rows(&msgs)[0][0].clone().unwrap();
+}
+
+fn tags(msgs: &[Message]) -> Vec<String> {
+    msgs.iter()
+        .filter(|m| m.typ == b'C')
+        .map(|m| m.cstrs()[0].clone())
+        .collect()
+}
+
+#[test]
+fn pooler_reset() {
+    let server = TestServer::start();
+    let (mut client, _) = Client::connect_with(
+        server.port,
+        "kuiba",
+        "kuiba",
+        &[("application_name", "pool")],
+    );
+    let (mut other, _) = server.connect();
+    let dirty = [
+        "set application_name to 'app'",
+        "set bytea_output to 'escape'",
+        "set client_min_messages to 'error'",
+        "listen pooled",
+    ];
+    let pristine = [
+        ("application_name", "pool"),
+        ("bytea_output", "hex"),
+        ("client_min_messages", "notice"),
+    ];
+    for sql in &dirty {
+        client.query(sql);
+    }
+    let msgs = client.query("RESET ALL; SET SESSION AUTHORIZATION DEFAULT; UNLISTEN *; CLOSE ALL;");
+    assert_eq!(errcode(&msgs), None);
+    assert_eq!(
+        tags(&msgs),
+        ["RESET", "SET", "UNLISTEN", "CLOSE CURSOR ALL"]
+    );
+    for (name, val) in &pristine {
+        assert_eq!(show(&mut client, name), *val);
+    }
+    // The notification of the channel unlistened is not delivered, the notification of the
+    // channel listened later is delivered after it if any.
+    other.query("notify pooled");
+    client.query("listen control");
+    other.query("notify control");
+    loop {
+        let msg = client.read_message();
+        if msg.typ != b'A' {
+            continue;
+        }
+        // The body is the sessid, then the channel and the payload.
+        let channel = msg.body[4..].split(|&b| b == 0).next().unwrap();
+        assert_eq!(channel, b"control");
+        break;
+    }
+
+    for sql in &dirty {
+        client.query(sql);
+    }
+    let msgs = client.query("discard all");
+    assert_eq!(tags(&msgs), ["DISCARD ALL"]);
+    for (name, val) in &pristine {
+        assert_eq!(show(&mut client, name), *val);
+    }
+    client.query("set bytea_output to 'escape'");
+    let msgs = client.query("reset bytea_output");
+    assert_eq!(tags(&msgs), ["RESET"]);
+    assert_eq!(show(&mut client, "bytea_output"), "hex");
+
+    client.query("begin");
+    let msgs = client.query("discard all");
+    assert_eq!(errcode(&msgs).as_deref(), Some("25001"));
+    client.query("rollback");
+    let msgs = client.query("close hidva");
+    assert_eq!(errcode(&msgs).as_deref(), Some("34000"));
+    client.terminate();
+    other.terminate();
+}
+
+fn param_status(msgs: &[Message]) -> Vec<Vec<String>> {
+    msgs.iter()
+        .filter(|m| m.typ == b'S')
+        .map(|m| m.cstrs())
+        .collect()
+}
+
+#[test]
+fn report_changed_gucs() {
+    let server = TestServer::start();
+    let (mut client, _) = server.connect();
+    let msgs = client.query("set application_name to 'app'");
+    assert_eq!(types(&msgs), b"CSZ");
+    assert_eq!(param_status(&msgs), [["application_name", "app"]]);
+    // Neither the GUC without GUC_REPORT nor the unchanged value is reported.
+    let msgs = client.query("set bytea_output to 'escape'");
+    assert_eq!(types(&msgs), b"CZ");
+    let msgs = client.query("set application_name to 'app'");
+    assert_eq!(types(&msgs), b"CZ");
+
+    let msgs = client.query("set client_encoding to 'latin1'");
+    assert_eq!(param_status(&msgs), [["client_encoding", "LATIN1"]]);
+    assert_eq!(msgs.last().unwrap().typ, b'Z');
+
+    let msgs = client.query("reset all");
+    let mut params = param_status(&msgs);
+    params.sort();
+    assert_eq!(
+        params,
+        [["application_name", ""], ["client_encoding", "UTF8"]]
+    );
+    assert_eq!(msgs.last().unwrap().typ, b'Z');
+    client.terminate();
+}
+
+// The names, the type oids and the format codes of the fields in RowDescription.
+fn fields(msgs: &[Message]) -> Vec<(String, u32, u16)> {
+    let desc = msgs.iter().find(|m| m.typ == b'T').unwrap();
+    let nfields = i16::from_be_bytes([desc.body[0], desc.body[1]]);
+    let mut body = &desc.body[2..];
+    let mut fields = Vec::new();
+    for _ in 0..nfields {
+        let end = body.iter().position(|&b| b == 0).unwrap();
+        let name = String::from_utf8(body[..end].to_vec()).unwrap();
+        // The table oid, the attnum, the type oid, the typlen, the typmod and the format.
+        let attrs = &body[end + 1..];
+        let typoid = u32::from_be_bytes([attrs[6], attrs[7], attrs[8], attrs[9]]);
+        let format = u16::from_be_bytes([attrs[16], attrs[17]]);
+        fields.push((name, typoid, format));
+        body = &body[end + 1 + 18..];
+    }
+    return fields;
+}
+
+fn field_names(msgs: &[Message]) -> Vec<String> {
+    fields(msgs).into_iter().map(|(name, _, _)| name).collect()
+}
+
+fn int_rows(rows: &[&[Option<i32>]]) -> Vec<Vec<Option<String>>> {
+    rows.iter()
+        .map(|row| row.iter().map(|v| v.map(|v| v.to_string())).collect())
+        .collect()
+}
+
+#[test]
+fn drop_column() {
+    let server = TestServer::start();
+    let (mut client, _) = server.connect();
+    client.query("create table drop_t(i int, j int not null, k int)");
+    client.query("insert into drop_t values (1, 10, 100), (2, 20, 200)");
+    let msgs = client.query("alter table drop_t drop column j");
+    assert_eq!(tags(&msgs), ["ALTER TABLE"], "log={}", server.log());
+    // The NOT NULL of the dropped column is gone, the new rows store NULL for it.
+    let msgs = client.query("insert into drop_t values (3, 300)");
+    assert_eq!(tags(&msgs), ["INSERT 0 1"]);
+    let msgs = client.query("select * from drop_t order by i");
+    assert_eq!(field_names(&msgs), ["i", "k"]);
+    let expected = [
+        &[Some(1), Some(100)][..],
+        &[Some(2), Some(200)],
+        &[Some(3), Some(300)],
+    ];
+    assert_eq!(data_rows(&msgs), int_rows(&expected));
+    let msgs = client.query("select j from drop_t");
+    assert_eq!(errcode(&msgs).as_deref(), Some("42703"));
+    let msgs = client.query("alter table drop_t drop column j");
+    assert_eq!(errcode(&msgs).as_deref(), Some("42703"));
+
+    // The name of the dropped column can be taken by a new column.
+    client.query("alter table drop_t add column j int default 7");
+    let msgs = client.query("select * from drop_t where k > 100 order by i");
+    assert_eq!(field_names(&msgs), ["i", "k", "j"]);
+    let expected = [
+        &[Some(2), Some(200), Some(7)][..],
+        &[Some(3), Some(300), Some(7)],
+    ];
+    assert_eq!(data_rows(&msgs), int_rows(&expected));
+    client.terminate();
+}
+
+#[test]
+fn create_type() {
+    let server = TestServer::start();
+    let (mut client, _) = server.connect();
+    let msgs = client.query("create type myint (input = int4in, output = int4out)");
+    assert_eq!(tags(&msgs), ["CREATE TYPE"], "log={}", server.log());
+    client.query("create table type_t(i myint)");
+    let msgs = client.query("insert into type_t values (1), (null)");
+    assert_eq!(tags(&msgs), ["INSERT 0 2"]);
+    let msgs = client.query("select i from type_t");
+    assert_eq!(data_rows(&msgs), int_rows(&[&[Some(1)], &[None]]));
+    let msgs = client.query("create type myint (input = int4in, output = int4out)");
+    assert_eq!(errcode(&msgs).as_deref(), Some("42710"));
+    client.terminate();
+}
+
+#[test]
+fn tablespace() {
+    let spcdir = tempfile::tempdir().unwrap();
+    let spcarg = format!("--tablespace=fast={}", spcdir.path().to_str().unwrap());
+    let mut server = TestServer::spawn_with(&[&spcarg], &[]);
+    server.wait_ready();
+    let (mut client, _) = server.connect();
+    let msgs = client.query("create table spc_t(i int) with (tablespace = fast)");
+    assert_eq!(tags(&msgs), ["CREATE TABLE"], "log={}", server.log());
+    let msgs = client.query("create table default_t(i int) with (tablespace = kb_default)");
+    assert_eq!(tags(&msgs), ["CREATE TABLE"]);
+    let msgs = client.query("create table nospc_t(i int) with (tablespace = slow)");
+    assert_eq!(errcode(&msgs).as_deref(), Some("42704"));
+    client.terminate();
+
+    // The files of spc_t are under the directory of the tablespace, base links to it.
+    let tables: Vec<_> = std::fs::read_dir(spcdir.path().join("2"))
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .collect();
+    assert_eq!(tables.len(), 1);
+    let table = &tables[0];
+    let link = server
+        .datadir()
+        .join("base/2")
+        .join(table.file_name().unwrap());
+    let check_storage = || {
+        assert!(table.join("manifest").is_file());
+        assert!(link.symlink_metadata().unwrap().file_type().is_symlink());
+        assert_eq!(link.canonicalize().unwrap(), table.canonicalize().unwrap());
+    };
+    check_storage();
+
+    // The redo of CREATE TABLE creates the storage in the tablespace again.
+    std::fs::remove_file(&link).unwrap();
+    std::fs::remove_dir_all(table).unwrap();
+    server.restart();
+    check_storage();
+
+    let (mut client, _) = server.connect();
+    client.query("insert into spc_t values (1), (2)");
+    client.terminate();
+    let status = server.shutdown();
+    assert!(status.success(), "{} {}", status, server.log());
+    server.launch();
+    let (mut client, _) = server.connect();
+    let msgs = client.query("select i from spc_t");
+    assert_eq!(data_rows(&msgs), int_rows(&[&[Some(1)], &[Some(2)]]));
+    client.terminate();
+    let datafiles = std::fs::read_dir(table)
+        .unwrap()
+        .filter(|e| e.as_ref().unwrap().path().extension() == Some("d".as_ref()))
+        .count();
+    assert!(datafiles > 0);
+}
+
+// BackendKeyData, returns the session id and the secret key.
+fn backend_key(msgs: &[Message]) -> (u32, u32) {
+    let msg = msgs.iter().find(|m| m.typ == b'K').unwrap();
+    let d = &msg.body;
+    (
+        u32::from_be_bytes([d[0], d[1], d[2], d[3]]),
+        u32::from_be_bytes([d[4], d[5], d[6], d[7]]),
+    )
+}
+
+#[test]
+fn cancel_request() {
+    let server = TestServer::start();
+    let (mut client1, msgs1) = server.connect();
+    let (mut client2, msgs2) = server.connect();
+    let (sessid1, key1) = backend_key(&msgs1);
+    let (sessid2, key2) = backend_key(&msgs2);
+    assert_ne!(sessid1, sessid2);
+    assert_ne!(key1, key2);
+
+    // Neither the wrong key nor the idle session is cancelled, the session goes on.
+    common::cancel_request(server.port, sessid1, key1.wrapping_add(1));
+    common::cancel_request(server.port, sessid1 + 1000, key1);
+    common::cancel_request(server.port, sessid2, key2);
+    client1.query("create table cancel_t(i int)");
+    let msgs = client2.query("insert into cancel_t values (1), (2)");
+    assert_eq!(tags(&msgs), ["INSERT 0 2"], "log={}", server.log());
+    let msgs = client1.query("select i from cancel_t");
+    assert_eq!(data_rows(&msgs), int_rows(&[&[Some(1)], &[Some(2)]]));
+    let log = server.log();
+    assert!(
+        log.contains("execute cancel request. done=unexpected key"),
+        "{}",
+        log
+    );
+    assert!(log.contains("done=cannot find the backend"), "{}", log);
+    client1.terminate();
+    client2.terminate();
+}
+
+fn types(msgs: &[Message]) -> Vec<u8> {
+    msgs.iter().map(|m| m.typ).collect()
+}
+
+#[test]
+fn extended_query() {
+    let server = TestServer::start();
+    let (mut client, _) = server.connect();
+    client.query("create table ext_t(i int, j int)");
+    client.query("insert into ext_t values (1, 10), (2, 20), (3, NULL)");
+
+    // The unnamed statement and portal.
+    client.parse("", "select i, j from ext_t order by i");
+    client.bind("", "", &[]);
+    client.describe(b'P', "");
+    client.execute("", 0);
+    let msgs = client.sync();
+    assert_eq!(types(&msgs), b"12TDDDCZ", "log={}", server.log());
+    assert_eq!(field_names(&msgs), ["i", "j"]);
+    let expected = [
+        &[Some(1), Some(10)][..],
+        &[Some(2), Some(20)],
+        &[Some(3), None],
+    ];
+    assert_eq!(data_rows(&msgs), int_rows(&expected));
+    assert_eq!(tags(&msgs), ["SELECT 3"]);
+    assert_eq!(msgs.last().unwrap().body, b"I");
+
+    // The named statement, the binary result and the portal fetched by max_rows.
+    client.parse("s1", "select i from ext_t where i > 1 order by i");
+    client.describe(b'S', "s1");
+    client.bind("p1", "s1", &[1]);
+    client.execute("p1", 1);
+    let msgs = client.sync();
+    assert_eq!(types(&msgs), b"1tT2DsZ");
+    assert_eq!(msgs[1].body, [0, 0]);
+    assert_eq!(data_row_bytes(&msgs), [[Some(2i32.to_be_bytes().to_vec())]]);
+    // The portal is dropped at the end of the transaction.
+    client.execute("p1", 1);
+    let msgs = client.sync();
+    assert_eq!(errcode(&msgs).as_deref(), Some("34000"));
+    assert_eq!(types(&msgs), b"EZ");
+
+    client.query("begin");
+    client.bind("p1", "s1", &[]);
+    client.execute("p1", 1);
+    client.execute("p1", 1);
+    client.execute("p1", 1);
+    let msgs = client.sync();
+    assert_eq!(types(&msgs), b"2DsDCCZ");
+    assert_eq!(tags(&msgs), ["SELECT 1", "SELECT 0"]);
+    assert_eq!(msgs.last().unwrap().body, b"T");
+    client.query("commit");
+
+    // The statement without rows, the empty query and Close.
+    client.parse("", "insert into ext_t values (4, 40)");
+    client.describe(b'S', "");
+    client.bind("", "", &[]);
+    client.execute("", 0);
+    client.parse("empty", "");
+    client.bind("", "empty", &[]);
+    client.execute("", 0);
+    client.close(b'S', "empty");
+    client.close(b'P', "nonexistent");
+    let msgs = client.sync();
+    assert_eq!(types(&msgs), b"1tn2C12I33Z");
+    assert_eq!(tags(&msgs), ["INSERT 0 1"]);
+
+    // The messages after an error are skipped until Sync.
+    client.parse("", "selec 1");
+    client.bind("", "", &[]);
+    client.execute("", 0);
+    let msgs = client.sync();
+    assert_eq!(types(&msgs), b"EZ");
+    assert_eq!(errcode(&msgs).as_deref(), Some("42601"));
+    client.parse("s1", "select 1");
+    let msgs = client.sync();
+    assert_eq!(errcode(&msgs).as_deref(), Some("42P05"));
+    client.bind("", "nonexistent", &[]);
+    let msgs = client.sync();
+    assert_eq!(errcode(&msgs).as_deref(), Some("26000"));
+
+    // The error in a transaction block aborts it.
+    client.query("begin");
+    client.parse("", "select * from nonexistent");
+    client.describe(b'S', "");
+    let msgs = client.sync();
+    assert_eq!(types(&msgs), b"1tEZ");
+    assert_eq!(msgs.last().unwrap().body, b"E");
+    client.query("rollback");
+    let msgs = client.query("select count(*) from ext_t");
+    assert_eq!(data_rows(&msgs), [[Some("4".to_string())]]);
+    client.terminate();
+}
+
+#[test]
+fn insert_returning() {
+    let server = TestServer::start();
+    let (mut client, _) = server.connect();
+    client.query("create table ret_t(i int, j int default 7)");
+    let msgs = client.query("insert into ret_t (i) values (1), (2) returning *");
+    assert_eq!(types(&msgs), b"TDDCZ", "log={}", server.log());
+    assert_eq!(field_names(&msgs), ["i", "j"]);
+    assert_eq!(
+        data_rows(&msgs),
+        int_rows(&[&[Some(1), Some(7)], &[Some(2), Some(7)]])
+    );
+    assert_eq!(tags(&msgs), ["INSERT 0 2"]);
+
+    // The portal of INSERT RETURNING is described by its returning list, and all rows are
+    // inserted even if only some of them are fetched.
+    client.parse(
+        "",
+        "insert into ret_t values (3, null), (4, 40) returning j, i",
+    );
+    client.bind("", "", &[0, 1]);
+    client.describe(b'P', "");
+    client.execute("", 1);
+    let msgs = client.sync();
+    assert_eq!(types(&msgs), b"12TDsZ", "log={}", server.log());
+    assert_eq!(field_names(&msgs), ["j", "i"]);
+    assert_eq!(
+        data_row_bytes(&msgs),
+        [[None, Some(3i32.to_be_bytes().to_vec())]]
+    );
+    let msgs = client.query("select count(*) from ret_t");
+    assert_eq!(data_rows(&msgs), [[Some("4".to_string())]]);
+
+    client.parse("", "insert into ret_t values (5, 50) returning i");
+    client.bind("", "", &[]);
+    client.execute("", 0);
+    let msgs = client.sync();
+    assert_eq!(types(&msgs), b"12DCZ");
+    assert_eq!(tags(&msgs), ["INSERT 0 1"]);
+    client.terminate();
+}
+
+#[test]
+fn mixed_result_formats() {
+    let server = TestServer::start();
+    let (mut client, _) = server.connect();
+    client.query("create table fmt_t(i int, b bool)");
+    client.query("insert into fmt_t values (7, 't'), (NULL, 'f')");
+
+    client.parse("", "select i, i, b, b, 'abc' from fmt_t order by i");
+    client.bind("", "", &[1, 0, 1, 0, 1]);
+    client.describe(b'P', "");
+    client.execute("", 0);
+    let msgs = client.sync();
+    assert_eq!(types(&msgs), b"12TDDCZ", "log={}", server.log());
+    let formats: Vec<_> = fields(&msgs).into_iter().map(|(_, _, f)| f).collect();
+    assert_eq!(formats, [1, 0, 1, 0, 1]);
+    let bytes = |v: &[u8]| Some(v.to_vec());
+    assert_eq!(
+        data_row_bytes(&msgs),
+        [
+            [
+                bytes(&7i32.to_be_bytes()),
+                bytes(b"7"),
+                bytes(&[1]),
+                bytes(b"t"),
+                bytes(b"abc")
+            ],
+            [None, None, bytes(&[0]), bytes(b"f"), bytes(b"abc")]
+        ]
+    );
+
+    // The single format code applies to all columns.
+    client.parse("", "select i, b from fmt_t where i = 7");
+    client.bind("", "", &[1]);
+    client.describe(b'P', "");
+    client.execute("", 0);
+    let msgs = client.sync();
+    let formats: Vec<_> = fields(&msgs).into_iter().map(|(_, _, f)| f).collect();
+    assert_eq!(formats, [1, 1]);
+    assert_eq!(
+        data_row_bytes(&msgs),
+        [[bytes(&7i32.to_be_bytes()), bytes(&[1])]]
+    );
+
+    // The count of the format codes must be 0, 1 or the count of the columns.
+    client.parse("", "select i, i, b from fmt_t");
+    client.bind("", "", &[1, 0]);
+    client.describe(b'P', "");
+    let msgs = client.sync();
+    assert_eq!(types(&msgs), b"12EZ");
+    assert_eq!(errcode(&msgs).as_deref(), Some("08P01"));
+    client.bind("", "", &[2]);
+    let msgs = client.sync();
+    assert_eq!(errcode(&msgs).as_deref(), Some("22023"));
+    client.terminate();
+}
+
+#[test]
+fn md5_auth() {
+    let server = TestServer::start_with_password("secret");
+    let (mut client, msgs) = server.connect();
+    assert_eq!(types(&msgs)[..2], *b"RR", "log={}", server.log());
+    // The code of AuthenticationMD5Password followed by the salt.
+    assert_eq!(msgs[0].body.len(), 8);
+    assert_eq!(msgs[1].body, [0, 0, 0, 0]);
+    assert_eq!(msgs.last().unwrap().typ, b'Z');
+    let msgs = client.query("select 1");
+    assert_eq!(data_rows(&msgs), [[Some("1".to_string())]]);
+    client.terminate();
+
+    let port = server.port;
+    let (_, msgs) = Client::connect_with_password(port, "kuiba", "kuiba", Some("wrong"));
+    assert_eq!(errcode(&msgs).as_deref(), Some("28P01"));
+    // The nonexistent role fails in the same way as the wrong password.
+    let (_, msgs) = Client::connect_with_password(port, "nobody", "kuiba", Some("secret"));
+    assert_eq!(errcode(&msgs).as_deref(), Some("28P01"));
+    let msg = msgs.last().unwrap();
+    let errmsg = msg.err_field(b'M').unwrap();
+    assert!(errmsg.starts_with("password authentication failed for user \"nobody\""));
+
+    let (mut client, msgs) = Client::connect_with_password(port, "kuiba", "kuiba", None);
+    assert_eq!(types(&msgs), b"R");
+    client.send(b'p', b"\0");
+    let msg = client.read_message();
+    assert_eq!(msg.typ, b'E');
+    assert_eq!(msg.err_field(b'C').as_deref(), Some("28P01"));
+    let errmsg = msg.err_field(b'M').unwrap();
+    assert!(errmsg.starts_with("empty password returned by client"));
+}
+
+#[test]
+fn scram_auth() {
+    let server = TestServer::start_with_auth("secret", "scram-sha-256");
+    let (mut client, msgs) = server.connect();
+    // AuthenticationSASL, AuthenticationSASLContinue, AuthenticationSASLFinal and
+    // AuthenticationOk.
+    assert_eq!(types(&msgs)[..4], *b"RRRR", "log={}", server.log());
+    assert_eq!(msgs[0].body, b"\0\0\0\x0aSCRAM-SHA-256\0\0");
+    assert_eq!(msgs[3].body, [0, 0, 0, 0]);
+    assert_eq!(msgs.last().unwrap().typ, b'Z');
+    let msgs = client.query("select 1");
+    assert_eq!(data_rows(&msgs), [[Some("1".to_string())]]);
+    client.terminate();
+
+    let port = server.port;
+    let (_, msgs) = Client::connect_with_password(port, "kuiba", "kuiba", Some("wrong"));
+    assert_eq!(errcode(&msgs).as_deref(), Some("28P01"));
+    // The nonexistent role goes through the exchange and fails in the same way.
+    let (_, msgs) = Client::connect_with_password(port, "nobody", "kuiba", Some("secret"));
+    assert_eq!(types(&msgs), b"RRE");
+    assert_eq!(errcode(&msgs).as_deref(), Some("28P01"));
+
+    // The client requiring channel binding is rejected.
+    let (mut client, msgs) = Client::connect_with_password(port, "kuiba", "kuiba", None);
+    assert_eq!(types(&msgs), b"R");
+    let first = b"p=tls-server-end-point,,n=,r=abc";
+    let mut resp = b"SCRAM-SHA-256\0".to_vec();
+    resp.extend_from_slice(&(first.len() as u32).to_be_bytes());
+    resp.extend_from_slice(first);
+    client.send(b'p', &resp);
+    let msg = client.read_message();
+    assert_eq!(msg.typ, b'E');
+    assert_eq!(msg.err_field(b'C').as_deref(), Some("08P01"));
+}
+
+#[test]
+fn scram_secret_with_md5() {
+    // The SCRAM secret is used by the SCRAM exchange even if auth_method is md5.
+    let mut pwfile = tempfile::NamedTempFile::new().unwrap();
+    writeln!(pwfile, "secret").unwrap();
+    let pwarg = format!("--pwfile={}", pwfile.path().to_str().unwrap());
+    let initdb_args = [pwarg.as_str(), "--auth=scram-sha-256"];
+    let mut server = TestServer::spawn_with(&initdb_args, &["auth_method: md5"]);
+    server.password = Some("secret".to_string());
+    server.wait_ready();
+    let port = server.port;
+    let (client, msgs) = Client::connect_with_password(port, "kuiba", "kuiba", Some("secret"));
+    assert_eq!(types(&msgs)[..4], *b"RRRR", "log={}", server.log());
+    assert_eq!(msgs[0].body[..4], [0, 0, 0, 10]);
+    client.terminate();
+}
+
+fn wait_log(server: &TestServer, pattern: &str, n: usize) {
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while server.log().matches(pattern).count() < n {
+        assert!(Instant::now() < deadline, "{}", server.log());
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+fn assert_hba_rejected(port: u16, user: &str, database: &str, errmsg: &str) {
+    let (_, msgs) = Client::connect(port, user, database);
+    let err = msgs.last().unwrap();
+    assert_eq!(err.typ, b'E');
+    assert_eq!(err.err_field(b'S').as_deref(), Some("FATAL"));
+    assert_eq!(err.err_field(b'C').as_deref(), Some("28000"));
+    let msg = err.err_field(b'M').unwrap();
+    assert!(msg.starts_with(errmsg), "{}", msg);
+}
+
+#[test]
+fn hba_rules() {
+    let rules = "hba_rules: '10.0.0.0/8 all reject, 127.0.0.0/8 kuiba trust, all admin reject'";
+    let mut server = TestServer::spawn(&[rules]);
+    server.wait_ready();
+    let port = server.port;
+    let (mut client, msgs) = Client::connect(port, "kuiba", "kuiba");
+    assert_eq!(errcode(&msgs), None, "log={}", server.log());
+    let msgs = client.query("select 1");
+    assert_eq!(data_rows(&msgs), [[Some("1".to_string())]]);
+    client.terminate();
+    assert_hba_rejected(
+        port,
+        "admin",
+        "kuiba",
+        "hba_rules rejects connection for host",
+    );
+    assert_hba_rejected(port, "nobody", "kuiba", "no hba_rules entry for host");
+}
+
+#[test]
+fn hba() {
+    // hba_rules is used if there is no kb_hba.conf.
+    let mut server = TestServer::spawn(&["hba_rules: 'all kuiba trust, all nobody trust'"]);
+    server.wait_ready();
+    let port = server.port;
+    assert_hba_rejected(port, "admin", "kuiba", "no hba_rules entry for host");
+    let hbapath = server.datadir().join("kb_hba.conf");
+    let hba = "host all admin all reject\n\
+               host kuiba kuiba,other 127.0.0.0/8 trust\n";
+    std::fs::write(&hbapath, hba).unwrap();
+    kill(Pid::from_raw(server.pid() as i32), Signal::SIGHUP).unwrap();
+    wait_log(&server, "load kb_hba.conf. lines=Some(2)", 1);
+    let check = |server: &TestServer| {
+        let (client, msgs) = server.connect();
+        assert_eq!(errcode(&msgs), None, "log={}", server.log());
+        client.terminate();
+        assert_hba_rejected(
+            port,
+            "admin",
+            "kuiba",
+            "kb_hba.conf rejects connection for host",
+        );
+        assert_hba_rejected(port, "nobody", "kuiba", "no kb_hba.conf entry for host");
+        assert_hba_rejected(port, "kuiba", "postgres", "no kb_hba.conf entry for host");
+    };
+    check(&server);
+
+    // The invalid kb_hba.conf is not reloaded, the lines loaded before are kept.
+    std::fs::write(&hbapath, "host all all all password\n").unwrap();
+    kill(Pid::from_raw(server.pid() as i32), Signal::SIGHUP).unwrap();
+    wait_log(&server, "kb_hba.conf was not reloaded", 1);
+    check(&server);
+
+    // kb_hba.conf is loaded at startup too.
+    std::fs::write(&hbapath, hba).unwrap();
+    server.shutdown();
+    server.launch();
+    wait_log(&server, "load kb_hba.conf. lines=Some(2)", 2);
+    check(&server);
+
+    // hba_rules is used again once kb_hba.conf is removed.
+    std::fs::remove_file(&hbapath).unwrap();
+    kill(Pid::from_raw(server.pid() as i32), Signal::SIGHUP).unwrap();
+    wait_log(&server, "load kb_hba.conf. lines=None", 2);
+    assert_hba_rejected(port, "admin", "kuiba", "no hba_rules entry for host");
+}
+
+#[test]
+fn ssl_declined() {
+    let server = TestServer::start();
+    let (mut client, msgs) = Client::connect_ssl(server.port, "kuiba", "kuiba");
+    assert_eq!(client.ssl_answer, Some(b'N'));
+    assert_eq!(msgs.last().unwrap().typ, b'Z', "log={}", server.log());
+    let msgs = client.query("select 1");
+    assert_eq!(data_rows(&msgs), [[Some("1".to_string())]]);
+    client.terminate();
+}
+
+#[test]
+fn ssl() {
+    let conf = common::ssl_conf();
+    let conf: Vec<_> = conf.iter().map(|v| v.as_str()).collect();
+    let mut server = TestServer::spawn(&conf);
+    // The connection without SSLRequest proceeds in cleartext.
+    server.wait_ready();
+    let (mut client, msgs) = Client::connect_ssl(server.port, "kuiba", "kuiba");
+    assert_eq!(client.ssl_answer, Some(b'S'));
+    assert_eq!(msgs.last().unwrap().typ, b'Z', "log={}", server.log());
+    let msgs = client.query("select 1");
+    assert_eq!(data_rows(&msgs), [[Some("1".to_string())]]);
+    let msgs = client.query("show ssl_cert_file");
+    assert_eq!(data_rows(&msgs), [[Some(common::ssl_file("server.crt"))]]);
+    client.terminate();
+
+    // The startup packet sent along with SSLRequest is not encrypted.
+    let mut stream = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+    let mut req = 8u32.to_be_bytes().to_vec();
+    req.extend_from_slice(&80877103u32.to_be_bytes());
+    req.extend_from_slice(&8u32.to_be_bytes());
+    req.extend_from_slice(&196608u32.to_be_bytes());
+    stream.write_all(&req).unwrap();
+    let mut resp = Vec::new();
+    stream.read_to_end(&mut resp).unwrap();
+    assert_eq!(resp[0], b'E');
+    assert!(String::from_utf8_lossy(&resp).contains("08P01"));
+}
+
+#[test]
+fn savepoint() {
+    let server = TestServer::start();
+    let (mut client, _) = server.connect();
+    client.query("create table sp_s(i int)");
+    client.query("begin");
+    client.query("insert into sp_s values (1)");
+    let msgs = client.query("savepoint a");
+    assert_eq!(tags(&msgs), ["SAVEPOINT"]);
+    client.query("select * from sp_s_missing");
+    let msgs = client.query("insert into sp_s values (2)");
+    assert_eq!(errcode(&msgs).as_deref(), Some("25P02"));
+    assert_eq!(msgs.last().unwrap().body, b"E");
+
+    // ROLLBACK TO is allowed in the failed transaction and brings it back.
+    let msgs = client.query("rollback to savepoint a");
+    assert_eq!(tags(&msgs), ["ROLLBACK"]);
+    assert_eq!(msgs.last().unwrap().body, b"T");
+    client.query("insert into sp_s values (3)");
+    let msgs = client.query("release a");
+    assert_eq!(tags(&msgs), ["RELEASE"]);
+    client.query("commit");
+    let msgs = client.query("select i from sp_s");
+    assert_eq!(
+        data_rows(&msgs),
+        [[Some("1".to_string())], [Some("3".to_string())]]
+    );
+    client.terminate();
+}
+
+#[test]
+fn idle_in_transaction_timeout() {
+    let server = TestServer::start();
+    let (mut client, _) = server.connect();
+    client.query("create table idle_t(i int)");
+    client.query("set idle_in_transaction_session_timeout to 300");
+    // Idle outside a transaction block is not limited.
+    thread::sleep(Duration::from_millis(600));
+    let msgs = client.query("select 1");
+    assert_eq!(data_rows(&msgs), [[Some("1".to_string())]]);
+
+    let (mut other, _) = server.connect();
+    other.query("begin");
+    thread::sleep(Duration::from_millis(600));
+    let msgs = other.query("select 2");
+    assert_eq!(data_rows(&msgs), [[Some("2".to_string())]]);
+    other.query("commit");
+
+    client.query("begin");
+    client.query("savepoint a");
+    let msgs = client.query("lock table idle_t");
+    assert_eq!(errcode(&msgs), None);
+    let waitts = Instant::now();
+    let err = client.read_message();
+    assert!(waitts.elapsed() >= Duration::from_millis(250));
+    assert_eq!(err.typ, b'E');
+    assert_eq!(err.err_field(b'S').as_deref(), Some("FATAL"));
+    assert_eq!(err.err_field(b'C').as_deref(), Some("25P03"));
+
+    // The lock is released with the whole transaction.
+    let msgs = other.query("drop table idle_t");
+    assert_eq!(tags(&msgs), ["DROP TABLE"]);
+    other.terminate();
+}
+
+// The rows inserted after the last checkpoint are redone after the crash, the mvcc pages are
+// initialized, changed and logged as the full page images by the freeze.
+#[test]
+fn redo_insert() {
+    let mut server = TestServer::start();
+    let (mut client, _) = server.connect();
+    client.query("create table redo_t(i int) with (mvcc_blk_rows = 4)");
+    client.query("insert into redo_t values (1), (2)");
+    client.query("insert into redo_t values (3)");
+    let msgs = client.query("vacuum redo_t");
+    assert_eq!(errcode(&msgs), None);
+    client.query("insert into redo_t values (4), (5)");
+    client.terminate();
+    let xids = wal_xids(&server, "BUF_INIT");
+    assert_eq!(xids.len(), 2, "{:?}", xids);
+    assert!(!wal_xids(&server, "BUF_SET_PAGE_XMIN").is_empty());
+    assert!(!wal_xids(&server, "BUF_FPI").is_empty());
+    server.restart();
+
+    let (mut client, _) = server.connect();
+    let msgs = client.query("select i from redo_t");
+    assert_eq!(
+        data_rows(&msgs),
+        int_rows(&[&[Some(1)], &[Some(2)], &[Some(3)], &[Some(4)], &[Some(5)]])
+    );
+    let msgs = client.query("verify table redo_t");
+    assert_eq!(errcode(&msgs), None);
+    assert!(data_rows(&msgs).is_empty(), "{:?}", data_rows(&msgs));
+    client.query("insert into redo_t values (6)");
+    let msgs = client.query("select count(*) from redo_t");
+    assert_eq!(data_rows(&msgs), [[Some("6".to_string())]]);
+    client.terminate();
+}
+
+// The xids of the records whose desc starts with desc, from the redo of the latest checkpoint
+// to the end of wal.
+fn wal_xids(server: &TestServer, desc: &str) -> Vec<u64> {
+    let ctl = controldata(server);
+    let prefix = "Latest checkpoint's REDO location: ";
+    let redo = ctl.lines().find_map(|l| l.strip_prefix(prefix)).unwrap();
+    let (ok, out, err) = waldump(server, &["--start", redo]);
+    assert!(ok, "{} {}", out, err);
+    let pattern = format!(" desc: {}", desc);
+    out.lines()
+        .filter(|l| l.contains(&pattern))
+        .map(|l| {
+            let xid = l.split(" xid: ").nth(1).unwrap().split(' ').next();
+            xid.unwrap().parse().unwrap()
+        })
+        .collect()
+}
+
+// The rows 1 and 2 are checkpointed by the clean shutdown before the crash. After that, the row
+// 3 is committed and the row 4 is rolled back, so the redo starts before them.
+fn start_crash_server() -> TestServer {
+    let mut server = TestServer::start();
+    let (mut client, _) = server.connect();
+    client.query("create table crash_t(i int)");
+    client.query("insert into crash_t values (1), (2)");
+    client.terminate();
+    let status = server.shutdown();
+    assert!(status.success(), "{} {}", status, server.log());
+    server.launch();
+    let (mut client, _) = server.connect();
+    client.query("insert into crash_t values (3)");
+    client.query("begin");
+    client.query("insert into crash_t values (4)");
+    client.query("rollback");
+    client.terminate();
+    return server;
+}
+
+fn wait_crash(server: &mut TestServer) {
+    let status = server.wait_exit();
+    assert_eq!(status.signal(), Some(Signal::SIGKILL as i32), "{}", status);
+    let log = server.log();
+    assert!(log.contains("crash at the crash point."), "{}", log);
+}
+
+// Starts the crashed server again, the rows of crash_t are read, verified and vacuumed, only
+// the committed rows are visible. Returns the nextxid after the redo.
+fn recover_crash(server: &mut TestServer, committed: &[i32]) -> u64 {
+    server.launch();
+    let log = server.log();
+    let prefix = "End of redo. nextxid: ";
+    let nextxid = &log[log.rfind(prefix).unwrap() + prefix.len()..];
+    let nextxid = nextxid.split(',').next().unwrap().parse().unwrap();
+    let (mut client, _) = server.connect();
+    assert_eq!(show(&mut client, "crash_point"), "none");
+    let rows: Vec<_> = committed
+        .iter()
+        .map(|&v| vec![Some(v.to_string())])
+        .collect();
+    let msgs = client.query("select i from crash_t order by i");
+    assert_eq!(data_rows(&msgs), rows);
+    let msgs = client.query("verify table crash_t");
+    assert_eq!(errcode(&msgs), None);
+    assert!(data_rows(&msgs).is_empty(), "{:?}", data_rows(&msgs));
+    let msgs = client.query("vacuum crash_t");
+    assert_eq!(errcode(&msgs), None);
+    let msgs = client.query("select i from crash_t order by i");
+    assert_eq!(data_rows(&msgs), rows);
+    client.terminate();
+    return nextxid;
+}
+
+// The transaction crashed after its commit record is written is committed by the redo even
+// though it is never acknowledged, while the transaction in progress at the crash is aborted.
+fn crash_at_commit(point: &str) {
+    let mut server = start_crash_server();
+    let (mut client, _) = server.connect();
+    client.query("create table crash_c(i int)");
+    let (mut inprogress, _) = server.connect();
+    inprogress.query("begin");
+    let msgs = inprogress.query("insert into crash_t values (6)");
+    assert_eq!(errcode(&msgs), None);
+    let msgs = client.query(&format!("set crash_point to '{}'", point));
+    assert_eq!(errcode(&msgs), None);
+    client.send(b'Q', b"insert into crash_t values (5)\0");
+    wait_crash(&mut server);
+
+    // The rows 3 and 5, and crash_c.
+    let committed = wal_xids(&server, "COMMIT");
+    assert_eq!(committed.len(), 3, "{:?}", committed);
+    assert_eq!(wal_xids(&server, "CREATE_TABLE"), &committed[1..2]);
+    assert_eq!(wal_xids(&server, "ABORT").len(), 1);
+    let nextxid = recover_crash(&mut server, &[1, 2, 3, 5]);
+    assert!(nextxid > committed[2], "{} {:?}", nextxid, committed);
+
+    // The DDL crashed at the commit is redone as well.
+    let (mut client, _) = server.connect();
+    client.query(&format!("set crash_point to '{}'", point));
+    client.send(b'Q', b"create table crash_w(i int)\0");
+    wait_crash(&mut server);
+    let created = wal_xids(&server, "CREATE_TABLE");
+    assert_eq!(created.len(), 2, "{:?}", created);
+    assert_eq!(wal_xids(&server, "COMMIT").last(), created.last());
+    let nextxid = recover_crash(&mut server, &[1, 2, 3, 5]);
+    assert!(nextxid > created[1], "{} {:?}", nextxid, created);
+    let (mut client, _) = server.connect();
+    let msgs = client.query("select count(*) from crash_w");
+    assert_eq!(data_rows(&msgs), [[Some("0".to_string())]]);
+    client.terminate();
+}
+
+#[test]
+fn crash_wal_write() {
+    crash_at_commit("wal_write");
+}
+
+#[test]
+fn crash_commit_fsync() {
+    crash_at_commit("commit_fsync");
+}
+
+// The checkpoint crashed before the control file is updated is ignored, the redo starts from
+// the previous checkpoint again.
+fn crash_at_ckpt(point: &str) -> TestServer {
+    let mut server = start_crash_server();
+    let ctl = controldata(&server);
+    let (mut client, _) = server.connect();
+    // The invalid crash point is ignored just as the other invalid values.
+    client.query("set crash_point to 'ckpt'");
+    assert_eq!(show(&mut client, "crash_point"), "none");
+    client.query("create table crash_c(i int)");
+    client.query(&format!("set crash_point to '{}'", point));
+    client.terminate();
+    server.shutdown();
+    wait_crash(&mut server);
+
+    let prefix = "Latest checkpoint location: ";
+    let ckpt = |ctl: &str| {
+        ctl.lines()
+            .find_map(|l| l.strip_prefix(prefix))
+            .unwrap()
+            .to_string()
+    };
+    let crashed = controldata(&server);
+    assert_eq!(ckpt(&crashed), ckpt(&ctl));
+    assert!(crashed.contains("Database cluster state: in production\n"));
+    // The row 3 and crash_c.
+    let created = wal_xids(&server, "CREATE_TABLE");
+    assert_eq!(created.len(), 1, "{:?}", created);
+    assert_eq!(&wal_xids(&server, "COMMIT")[1..], created);
+    recover_crash(&mut server, &[1, 2, 3]);
+    let log = server.log();
+    let log = &log[log.rfind("start redo.").unwrap()..];
+    assert!(
+        !log.contains("skip redo after the clean shutdown."),
+        "{}",
+        log
+    );
+    return server;
+}
+
+#[test]
+fn crash_ckpt_flush() {
+    let server = crash_at_ckpt("ckpt_flush");
+    // The checkpoint record of the clean shutdown, which is the redo of itself.
+    assert_eq!(wal_xids(&server, "CHECKPOINT_SHUTDOWN").len(), 1);
+}
+
+#[test]
+fn crash_ckpt_record() {
+    let server = crash_at_ckpt("ckpt_record");
+    assert_eq!(wal_xids(&server, "CHECKPOINT_SHUTDOWN").len(), 2);
+}